@@ -0,0 +1,48 @@
+//! Password hashing and JWT costs. Argon2 dominates login and registration,
+//! so a change to its parameters shows up here first.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use example_axum_api::auth::{
+    jwt::{JwtConfig, create_access_token, verify_token},
+    util::{hash_password, passwords_match},
+};
+
+fn passwords(c: &mut Criterion) {
+    let hash = hash_password("123456".to_string()).unwrap();
+
+    let mut group = c.benchmark_group("password");
+    group.sample_size(20);
+    group.bench_function("hash_password", |b| {
+        b.iter(|| hash_password(black_box("123456".to_string())).unwrap())
+    });
+    group.bench_function("passwords_match", |b| {
+        b.iter(|| passwords_match(black_box(&hash), black_box("123456")).unwrap())
+    });
+    group.finish();
+}
+
+fn tokens(c: &mut Criterion) {
+    let config = JwtConfig::new("bench-signing-key".to_string());
+    let token = create_access_token(&config, "bench-user", "bench@mail.com").unwrap();
+
+    let mut group = c.benchmark_group("jwt");
+    group.bench_function("create_access_token", |b| {
+        b.iter(|| {
+            create_access_token(
+                &config,
+                black_box("bench-user"),
+                black_box("bench@mail.com"),
+            )
+            .unwrap()
+        })
+    });
+    group.bench_function("verify_token", |b| {
+        b.iter(|| verify_token(&config, black_box(&token)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, passwords, tokens);
+criterion_main!(benches);
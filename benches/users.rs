@@ -0,0 +1,62 @@
+//! The `get_users` listing against a real database, configured like the
+//! tests through `test.toml` (or the file named by `BENCH_CONFIG`). Skipped
+//! when the database can't be reached.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use example_axum_api::{
+    auth::user::{UserFilter, get_users},
+    config::{connection::connect, settings::Settings},
+    pagination::Pagination,
+};
+use tokio::runtime::Runtime;
+
+fn users(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let config = std::env::var("BENCH_CONFIG").unwrap_or_else(|_| "test.toml".to_string());
+    let pool = rt.block_on(async {
+        let settings = Settings::load(&config).await.ok()?;
+        connect(&settings.database).await.ok()
+    });
+    let Some(pool) = pool else {
+        eprintln!("Skipping get_users benchmarks: no database from {}", config);
+        return;
+    };
+
+    let first_page = Pagination::default();
+    let cursor_page = Pagination {
+        cursor: Some("m".to_string()),
+        ..Pagination::default()
+    };
+
+    let everyone = UserFilter::default();
+    let by_name = UserFilter::by_name("jo");
+
+    let mut group = c.benchmark_group("get_users");
+    group.bench_function("first_page", |b| {
+        b.to_async(&rt).iter(|| async {
+            get_users(black_box(&first_page), &everyone, &pool)
+                .await
+                .unwrap()
+        })
+    });
+    group.bench_function("name_filter", |b| {
+        b.to_async(&rt).iter(|| async {
+            get_users(black_box(&first_page), &by_name, &pool)
+                .await
+                .unwrap()
+        })
+    });
+    group.bench_function("cursor", |b| {
+        b.to_async(&rt).iter(|| async {
+            get_users(black_box(&cursor_page), &everyone, &pool)
+                .await
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, users);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Compiles build information into the binary for `GET /version`:
+/// `GIT_SHA` (or "unknown" outside a git checkout) and `BUILD_TIMESTAMP` in
+/// seconds since the epoch, taken from `SOURCE_DATE_EPOCH` when set so
+/// reproducible builds stay reproducible.
+///
+/// Also generates the gRPC server from `proto/api.proto`. The schema is
+/// parsed by protox, so no `protoc` install is needed.
+fn main() {
+    let descriptors =
+        protox::compile(["proto/api.proto"], ["proto"]).expect("Invalid proto/api.proto");
+    tonic_prost_build::configure()
+        .compile_fds(descriptors)
+        .expect("Failed to generate gRPC code");
+
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .filter(|sha| !sha.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default()
+        });
+
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-changed=build.rs");
+}
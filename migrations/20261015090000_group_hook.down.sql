@@ -0,0 +1 @@
+drop table group_hooks;
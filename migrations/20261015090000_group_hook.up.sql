@@ -0,0 +1,9 @@
+create table group_hooks(
+    hook_id varchar(50) primary key,
+    group_id varchar(50) not null references groups(group_id) on delete cascade,
+    name varchar(50) not null,
+    token varchar(100) not null unique,
+    created_by varchar(50) not null,
+    revoked_at timestamp null default null,
+    created_at timestamp not null default current_timestamp
+);
@@ -0,0 +1,8 @@
+create table group_webhooks(
+    webhook_id varchar(50) primary key,
+    group_id varchar(50) not null references groups(group_id) on delete cascade,
+    url text not null,
+    secret varchar(100) not null,
+    created_by varchar(50) not null,
+    created_at timestamp not null default current_timestamp
+);
@@ -0,0 +1,10 @@
+create table attachments(
+    attachment_id varchar(50) primary key,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    storage_key text not null,
+    content_type varchar(100) not null,
+    size bigint not null,
+    created_at timestamp not null default current_timestamp
+);
+create index idx_attachments_user_id on attachments(user_id);
+alter table users add column avatar_id varchar(50) null references attachments(attachment_id) on delete set null;
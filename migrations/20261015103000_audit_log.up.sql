@@ -0,0 +1,22 @@
+create table audit_log(
+    audit_id bigserial primary key,
+    actor_id varchar(50) not null,
+    action varchar(50) not null,
+    target text not null,
+    status smallint not null,
+    ip varchar(64) null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_audit_log_actor on audit_log(actor_id, created_at);
+create index idx_audit_log_action on audit_log(action, created_at);
+
+-- Append-only: rows can be inserted but never changed or removed
+create function audit_log_append_only() returns trigger as $$
+begin
+    raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_log_append_only
+    before update or delete on audit_log
+    for each row execute function audit_log_append_only();
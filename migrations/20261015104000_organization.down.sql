@@ -0,0 +1,4 @@
+alter table groups drop column org_id;
+drop table organization_invitations;
+drop table organization_members;
+drop table organizations;
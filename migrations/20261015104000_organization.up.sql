@@ -0,0 +1,25 @@
+create table organizations(
+    org_id varchar(50) primary key,
+    name varchar(50) not null unique,
+    created_by varchar(50) not null,
+    created_at timestamp not null default current_timestamp
+);
+create table organization_members(
+    org_id varchar(50) not null references organizations(org_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    role varchar(20) not null default 'member',
+    created_at timestamp not null default current_timestamp,
+    primary key (org_id, user_id)
+);
+create index idx_organization_members_user_id on organization_members(user_id);
+create table organization_invitations(
+    invitation_id varchar(50) primary key,
+    org_id varchar(50) not null references organizations(org_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    invited_by varchar(50) not null,
+    created_at timestamp not null default current_timestamp,
+    unique (org_id, user_id)
+);
+create index idx_organization_invitations_user_id on organization_invitations(user_id);
+alter table groups add column org_id varchar(50) null references organizations(org_id) on delete cascade;
+create index idx_groups_org_id on groups(org_id);
@@ -0,0 +1,2 @@
+drop table otp_codes;
+alter table users drop column phone;
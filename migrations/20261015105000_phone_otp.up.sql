@@ -0,0 +1,8 @@
+alter table users add column phone varchar(20) null unique;
+create table otp_codes(
+    phone varchar(20) primary key,
+    code_hash varchar(64) not null,
+    attempts integer not null default 0,
+    expires_at timestamp not null,
+    created_at timestamp not null default current_timestamp
+);
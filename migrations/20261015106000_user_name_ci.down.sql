@@ -0,0 +1 @@
+drop index users_user_name_lower_key;
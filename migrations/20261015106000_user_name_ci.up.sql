@@ -0,0 +1,25 @@
+-- Names that differ only by case would make the unique index below fail.
+-- Before it is built, the oldest account keeps its name and every other one
+-- gets a suffix from its user_id, reported with a notice so those users can
+-- be told their new name. See "Upgrading" in the Readme.
+do $$
+declare
+    renamed record;
+begin
+    for renamed in
+        update users set user_name = left(users.user_name, 21) || '_' || left(md5(users.user_id), 8)
+        from (
+            select user_id, user_name as old_name,
+                row_number() over (partition by lower(user_name) order by created_at, user_id) as position
+            from users
+        ) duplicates
+        where users.user_id = duplicates.user_id and duplicates.position > 1
+        returning users.user_id, duplicates.old_name, users.user_name
+    loop
+        raise notice 'user_name % of user % is taken regardless of case, renamed to %',
+            renamed.old_name, renamed.user_id, renamed.user_name;
+    end loop;
+end;
+$$;
+
+create unique index users_user_name_lower_key on users (lower(user_name));
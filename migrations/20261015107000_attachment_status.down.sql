@@ -0,0 +1 @@
+alter table attachments drop column status;
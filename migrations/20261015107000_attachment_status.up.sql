@@ -0,0 +1 @@
+alter table attachments add column status varchar(20) not null default 'clean';
@@ -0,0 +1,2 @@
+drop index idx_attachments_group_id;
+alter table attachments drop column group_id;
@@ -0,0 +1,2 @@
+alter table attachments add column group_id varchar(50) null references groups(group_id) on delete set null;
+create index idx_attachments_group_id on attachments(group_id, created_at);
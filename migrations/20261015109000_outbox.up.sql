@@ -0,0 +1,5 @@
+create table outbox(
+    outbox_id bigserial primary key,
+    event jsonb not null,
+    created_at timestamp not null default current_timestamp
+);
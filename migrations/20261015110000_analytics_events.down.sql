@@ -0,0 +1 @@
+drop table analytics_events;
@@ -0,0 +1,10 @@
+create table analytics_events(
+    event_id bigserial primary key,
+    user_id varchar(50) not null,
+    name varchar(64) not null,
+    properties jsonb not null default '{}',
+    occurred_at timestamptz not null,
+    received_at timestamptz not null default current_timestamp
+);
+create index idx_analytics_events_name on analytics_events(name, occurred_at);
+create index idx_analytics_events_user on analytics_events(user_id, occurred_at);
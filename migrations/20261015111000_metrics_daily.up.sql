@@ -0,0 +1,8 @@
+create table metrics_daily(
+    day date primary key,
+    active_users integer not null,
+    monthly_active_users integer not null,
+    new_users integer not null,
+    new_groups integer not null,
+    computed_at timestamptz not null default current_timestamp
+);
@@ -0,0 +1,15 @@
+create table reports(
+    report_id varchar(50) primary key,
+    reporter_id varchar(50) not null,
+    target_type varchar(20) not null,
+    user_id varchar(50) not null,
+    group_id varchar(50) null references groups(group_id) on delete set null,
+    message text null,
+    reason varchar(30) not null,
+    details text null,
+    status varchar(20) not null default 'open',
+    created_at timestamptz not null default current_timestamp,
+    updated_at timestamptz null
+);
+create index idx_reports_status on reports(status, created_at);
+create index idx_reports_user on reports(user_id);
@@ -0,0 +1,11 @@
+create table user_sanctions(
+    sanction_id varchar(50) primary key,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    kind varchar(20) not null,
+    group_id varchar(50) null references groups(group_id) on delete cascade,
+    report_id varchar(50) null references reports(report_id) on delete set null,
+    created_by varchar(50) not null,
+    expires_at timestamptz null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_user_sanctions_user on user_sanctions(user_id, kind);
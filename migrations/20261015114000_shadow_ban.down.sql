@@ -0,0 +1,2 @@
+drop index idx_users_shadow_banned;
+alter table users drop column shadow_banned;
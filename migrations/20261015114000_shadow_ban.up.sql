@@ -0,0 +1,2 @@
+alter table users add column shadow_banned boolean not null default false;
+create index idx_users_shadow_banned on users(user_id) where shadow_banned;
@@ -0,0 +1 @@
+drop table group_word_filters;
@@ -0,0 +1,11 @@
+create table group_word_filters(
+    filter_id varchar(50) primary key,
+    group_id varchar(50) not null references groups(group_id) on delete cascade,
+    pattern varchar(200) not null,
+    kind varchar(10) not null,
+    action varchar(10) not null,
+    notify boolean not null default false,
+    created_by varchar(50) not null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_group_word_filters_group on group_word_filters(group_id, created_at);
@@ -0,0 +1,2 @@
+drop table event_rsvps;
+drop table group_events;
@@ -0,0 +1,21 @@
+create table group_events(
+    event_id varchar(50) primary key,
+    group_id varchar(50) not null references groups(group_id) on delete cascade,
+    title varchar(100) not null,
+    description text null,
+    starts_at timestamptz not null,
+    ends_at timestamptz not null,
+    created_by varchar(50) not null,
+    reminded_at timestamptz null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_group_events_group on group_events(group_id, starts_at);
+create index idx_group_events_reminder on group_events(starts_at) where reminded_at is null;
+
+create table event_rsvps(
+    event_id varchar(50) not null references group_events(event_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    status varchar(10) not null,
+    updated_at timestamptz not null default current_timestamp,
+    primary key (event_id, user_id)
+);
@@ -0,0 +1,4 @@
+drop table bot_tokens;
+drop index idx_users_bot_owner;
+alter table users drop column bot_owner_id;
+alter table users drop column is_bot;
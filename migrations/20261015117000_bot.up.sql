@@ -0,0 +1,13 @@
+alter table users add column is_bot boolean not null default false;
+alter table users add column bot_owner_id varchar(50) null references users(user_id) on delete cascade;
+create index idx_users_bot_owner on users(bot_owner_id) where is_bot;
+create table bot_tokens(
+    token_id varchar(50) primary key,
+    bot_id varchar(50) not null references users(user_id) on delete cascade,
+    token_hash varchar(64) not null unique,
+    scopes text[] not null,
+    created_at timestamptz not null default current_timestamp,
+    last_used_at timestamptz null,
+    revoked_at timestamptz null
+);
+create index idx_bot_tokens_bot on bot_tokens(bot_id);
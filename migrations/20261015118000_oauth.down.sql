@@ -0,0 +1,4 @@
+drop table oauth_tokens;
+drop table oauth_codes;
+drop table oauth_consents;
+drop table oauth_clients;
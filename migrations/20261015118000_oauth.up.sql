@@ -0,0 +1,35 @@
+create table oauth_clients(
+    client_id varchar(50) primary key,
+    owner_id varchar(50) not null references users(user_id) on delete cascade,
+    name varchar(100) not null,
+    redirect_uris text[] not null,
+    secret_hash varchar(64) null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_oauth_clients_owner on oauth_clients(owner_id);
+create table oauth_consents(
+    client_id varchar(50) not null references oauth_clients(client_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    scopes text[] not null,
+    updated_at timestamptz not null default current_timestamp,
+    primary key (client_id, user_id)
+);
+create index idx_oauth_consents_user on oauth_consents(user_id);
+create table oauth_codes(
+    code_hash varchar(64) primary key,
+    client_id varchar(50) not null references oauth_clients(client_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    redirect_uri text not null,
+    scopes text[] not null,
+    code_challenge varchar(128) not null,
+    expires_at timestamptz not null
+);
+create table oauth_tokens(
+    token_hash varchar(64) primary key,
+    client_id varchar(50) not null references oauth_clients(client_id) on delete cascade,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    scopes text[] not null,
+    expires_at timestamptz not null,
+    created_at timestamptz not null default current_timestamp
+);
+create index idx_oauth_tokens_grant on oauth_tokens(client_id, user_id);
@@ -0,0 +1,2 @@
+drop table saml_identities;
+drop table saml_requests;
@@ -0,0 +1,13 @@
+create table saml_requests(
+    request_id varchar(64) primary key,
+    relay_state text null,
+    expires_at timestamptz not null
+);
+create table saml_identities(
+    idp_entity_id varchar(255) not null,
+    name_id varchar(255) not null,
+    user_id varchar(50) not null references users(user_id) on delete cascade,
+    created_at timestamptz not null default current_timestamp,
+    primary key (idp_entity_id, name_id)
+);
+create index idx_saml_identities_user on saml_identities(user_id);
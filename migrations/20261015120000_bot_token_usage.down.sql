@@ -0,0 +1 @@
+drop table bot_token_usage;
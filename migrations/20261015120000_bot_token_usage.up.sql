@@ -0,0 +1,6 @@
+create table bot_token_usage(
+    token_id varchar(50) not null references bot_tokens(token_id) on delete cascade,
+    day date not null,
+    requests bigint not null default 0,
+    primary key (token_id, day)
+);
@@ -0,0 +1,4 @@
+drop index idx_groups_created_at;
+drop trigger groups_updated_at on groups;
+drop trigger users_updated_at on users;
+drop function set_updated_at();
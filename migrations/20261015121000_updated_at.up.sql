@@ -0,0 +1,19 @@
+-- Stamps updated_at on any change to a row, whichever code path makes it
+create function set_updated_at() returns trigger as $$
+begin
+    new.updated_at = current_timestamp;
+    return new;
+end;
+$$ language plpgsql;
+
+create trigger users_updated_at
+    before update on users
+    for each row when (old.* is distinct from new.*)
+    execute function set_updated_at();
+
+create trigger groups_updated_at
+    before update on groups
+    for each row when (old.* is distinct from new.*)
+    execute function set_updated_at();
+
+create index idx_groups_created_at on groups(created_at);
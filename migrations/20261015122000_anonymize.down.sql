@@ -0,0 +1,8 @@
+create or replace function audit_log_append_only() returns trigger as $$
+begin
+    raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
+
+drop table anonymize_jobs;
+alter table users drop column anonymized_at;
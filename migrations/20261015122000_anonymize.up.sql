@@ -0,0 +1,25 @@
+alter table users add column anonymized_at timestamptz null;
+
+create table anonymize_jobs(
+    job_id varchar(50) primary key,
+    requested_by varchar(50) not null,
+    status varchar(20) not null default 'running',
+    total integer not null,
+    processed integer not null default 0,
+    error text null,
+    created_at timestamptz not null default current_timestamp,
+    finished_at timestamptz null
+);
+
+-- Still append-only, except that an entry's client address may be erased
+create or replace function audit_log_append_only() returns trigger as $$
+begin
+    if tg_op = 'UPDATE' and new.ip is null
+        and (new.audit_id, new.actor_id, new.action, new.target, new.status, new.created_at)
+            is not distinct from
+            (old.audit_id, old.actor_id, old.action, old.target, old.status, old.created_at) then
+        return new;
+    end if;
+    raise exception 'audit_log is append-only';
+end;
+$$ language plpgsql;
@@ -0,0 +1,6 @@
+create table revoked_tokens(
+    token_hash varchar(64) primary key,
+    expires_at timestamptz not null
+);
+
+create index revoked_tokens_expires_at on revoked_tokens(expires_at);
@@ -0,0 +1,2 @@
+drop function create_message_partitions(date, int);
+drop table messages;
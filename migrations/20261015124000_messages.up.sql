@@ -0,0 +1,49 @@
+-- Partitioned by month of sent_at, so old months can be detached or dropped
+-- whole. The primary key has to include the partition key.
+create table messages(
+    message_id bigserial,
+    sender_id varchar(50) not null references users(user_id) on delete cascade,
+    receiver_id varchar(50) not null references users(user_id) on delete cascade,
+    body text not null,
+    sent_at timestamptz not null,
+    -- From a shadow banned sender, never delivered
+    hidden boolean not null default false,
+    primary key (message_id, sent_at)
+) partition by range (sent_at);
+
+create index messages_conversation on messages(sender_id, receiver_id, message_id);
+
+-- Catches rows no monthly partition covers yet, so an insert never fails
+create table messages_default partition of messages default;
+
+-- Creates the monthly partitions messages_YYYY_MM (UTC) from the month of
+-- from_day through `months` months after it, moving in any rows the default
+-- partition caught for them. Returns how many it created; run by
+-- message::partition::spawn_partitions.
+create function create_message_partitions(from_day date, months int) returns int as $$
+declare
+    month_start timestamptz;
+    month_end timestamptz;
+    partition_name text;
+    created int := 0;
+begin
+    for i in 0..months loop
+        month_start := (date_trunc('month', from_day) + make_interval(months => i))::timestamp at time zone 'utc';
+        month_end := ((date_trunc('month', from_day) + make_interval(months => i + 1))::timestamp) at time zone 'utc';
+        partition_name := 'messages_' || to_char(month_start at time zone 'utc', 'YYYY_MM');
+        continue when to_regclass(partition_name) is not null;
+
+        execute format('create table %I (like messages including defaults)', partition_name);
+        execute format(
+            'with moved as (delete from messages_default where sent_at >= %L and sent_at < %L returning *) '
+            'insert into %I select * from moved',
+            month_start, month_end, partition_name);
+        execute format('alter table messages attach partition %I for values from (%L) to (%L)',
+            partition_name, month_start, month_end);
+        created := created + 1;
+    end loop;
+    return created;
+end;
+$$ language plpgsql;
+
+select create_message_partitions(current_date, 3);
@@ -0,0 +1 @@
+alter table groups drop column created_by;
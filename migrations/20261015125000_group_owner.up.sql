@@ -0,0 +1,3 @@
+-- Who created the group, like group_webhooks.created_by. Null for groups from
+-- before it was recorded, which only admins can then manage.
+alter table groups add column created_by varchar(50) null;
@@ -0,0 +1 @@
+alter table oauth_codes drop column redirect_uri_explicit;
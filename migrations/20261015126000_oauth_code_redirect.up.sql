@@ -0,0 +1,3 @@
+-- Whether the authorization request named its redirect_uri, in which case the
+-- token request must send the same one (RFC 6749 4.1.3)
+alter table oauth_codes add column redirect_uri_explicit boolean not null default false;
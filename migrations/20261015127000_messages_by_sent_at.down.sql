@@ -0,0 +1,2 @@
+drop index messages_conversation;
+create index messages_conversation on messages(sender_id, receiver_id, message_id);
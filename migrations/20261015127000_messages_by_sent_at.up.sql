@@ -0,0 +1,4 @@
+-- History pages by (sent_at, message_id), so a page bounded by sent_at only
+-- reads the monthly partitions it falls in
+drop index messages_conversation;
+create index messages_conversation on messages(sender_id, receiver_id, sent_at, message_id);
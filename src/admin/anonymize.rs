@@ -0,0 +1,444 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, QueryBuilder, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::{ADMIN_ROLE, UserFilter},
+        util::{MetaResponse, StatusCodeExt},
+    },
+    storage::handler::delete_objects,
+    validation::ValidatedJson,
+};
+
+/// Users one job may cover
+pub const MAX_USERS: usize = 10_000;
+/// Users scrubbed per transaction
+const BATCH_USERS: usize = 100;
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "validate_selection"))]
+pub struct BulkAnonymizeRequest {
+    pub user_ids: Option<Vec<String>>,
+    /// Same fields as the user listing's filters
+    pub filter: Option<UserFilter>,
+}
+
+fn validate_selection(req: &BulkAnonymizeRequest) -> Result<(), ValidationError> {
+    let selects = |filter: &UserFilter| {
+        let set = |value: &Option<String>| value.as_deref().is_some_and(|value| !value.is_empty());
+        set(&filter.user_name)
+            || set(&filter.email)
+            || set(&filter.role)
+            || filter.created_after.is_some()
+            || filter.created_before.is_some()
+    };
+    match (&req.user_ids, &req.filter) {
+        (Some(ids), None) if !ids.is_empty() && ids.len() <= MAX_USERS => Ok(()),
+        (None, Some(filter)) if selects(filter) => Ok(()),
+        _ => Err(ValidationError::new("selection").with_message(
+            format!(
+                "give either 1 to {} user_ids or a filter with at least one field",
+                MAX_USERS
+            )
+            .into(),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymizeJob {
+    pub job_id: String,
+    /// `running`, `done` or `failed`
+    pub status: String,
+    /// Users the job covers; admins and users already anonymized are left out
+    pub total: i32,
+    pub processed: i32,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnonymizeJobResponse {
+    pub meta: MetaResponse,
+    pub data: AnonymizeJob,
+}
+
+impl IntoResponse for AnonymizeJobResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.meta.code as u16).unwrap_or(StatusCode::OK);
+        (status, Json(self)).into_response()
+    }
+}
+
+fn job_from_row(row: PgRow) -> AnonymizeJob {
+    AnonymizeJob {
+        job_id: row.get("job_id"),
+        status: row.get("status"),
+        total: row.get("total"),
+        processed: row.get("processed"),
+        error: row.get("error"),
+        created_at: row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        finished_at: row
+            .get::<Option<DateTime<Utc>>, _>("finished_at")
+            .map(|time| time.to_rfc3339()),
+    }
+}
+
+const JOB_COLUMNS: &str = "job_id, status, total, processed, error, created_at, finished_at";
+
+/// Ids of the users `req` selects, leaving out admins and users anonymized
+/// before. `None` when there are more than `MAX_USERS`.
+#[tracing::instrument(name = "db.users.select_anonymizable", skip_all)]
+async fn select_users(
+    pool: &Pool<Postgres>,
+    req: &BulkAnonymizeRequest,
+) -> Result<Option<Vec<String>>, Error> {
+    let mut query = QueryBuilder::new("select user_id from users where anonymized_at is null");
+    query.push(" and role <> ").push_bind(ADMIN_ROLE);
+    if let Some(ids) = &req.user_ids {
+        query.push(" and user_id = any(").push_bind(ids).push(")");
+    }
+    if let Some(filter) = &req.filter {
+        filter.push_conditions(&mut query);
+    }
+    query
+        .push(" order by user_id limit ")
+        .push_bind(MAX_USERS as i64 + 1);
+    let ids: Vec<String> = query.build_query_scalar().fetch_all(pool).await?;
+    Ok((ids.len() <= MAX_USERS).then_some(ids))
+}
+
+#[tracing::instrument(name = "db.anonymize_jobs.create", skip(pool))]
+async fn create_job(
+    pool: &Pool<Postgres>,
+    requested_by: &str,
+    total: usize,
+) -> Result<AnonymizeJob, Error> {
+    let sql = format!(
+        "insert into anonymize_jobs (job_id, requested_by, total) values ($1, $2, $3) returning {}",
+        JOB_COLUMNS
+    );
+    sqlx::query(&sql)
+        .bind(Uuid::new_v4().to_string())
+        .bind(requested_by)
+        .bind(total as i32)
+        .map(job_from_row)
+        .fetch_one(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.anonymize_jobs.get", skip(pool))]
+pub async fn get_job(pool: &Pool<Postgres>, job_id: &str) -> Result<Option<AnonymizeJob>, Error> {
+    let sql = format!(
+        "select {} from anonymize_jobs where job_id = $1",
+        JOB_COLUMNS
+    );
+    sqlx::query(&sql)
+        .bind(job_id)
+        .map(job_from_row)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Scrubs one batch of users and counts it on the job, in one transaction.
+/// Names and emails become placeholders derived from the user id, the phone
+/// number, password, avatar, SSO identities, OAuth tokens and the private
+/// messages they sent go, bot tokens are revoked and the client address is
+/// erased from their audit entries.
+/// Returns the storage keys of the deleted avatars.
+#[tracing::instrument(name = "db.users.anonymize", skip(pool, user_ids), fields(users = user_ids.len()))]
+pub async fn anonymize(
+    pool: &Pool<Postgres>,
+    job_id: &str,
+    user_ids: &[String],
+) -> Result<Vec<String>, Error> {
+    let mut tx = pool.begin().await?;
+    let avatars: Vec<String> = sqlx::query_scalar(
+        "delete from attachments where attachment_id in \
+         (select avatar_id from users where user_id = any($1)) returning storage_key",
+    )
+    .bind(user_ids)
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query(
+        "update users set user_name = 'deleted_' || md5(user_id), \
+         email = md5(user_id) || '@anon.invalid', phone = null, password = '', \
+         anonymized_at = current_timestamp where user_id = any($1)",
+    )
+    .bind(user_ids)
+    .execute(&mut *tx)
+    .await?;
+    for sql in [
+        "delete from saml_identities where user_id = any($1)",
+        "delete from oauth_tokens where user_id = any($1)",
+        "delete from messages where sender_id = any($1)",
+        "update bot_tokens set revoked_at = current_timestamp where bot_id = any($1) and revoked_at is null",
+        "update audit_log set ip = null where actor_id = any($1) and ip is not null",
+    ] {
+        sqlx::query(sql).bind(user_ids).execute(&mut *tx).await?;
+    }
+    sqlx::query("update anonymize_jobs set processed = processed + $2 where job_id = $1")
+        .bind(job_id)
+        .bind(user_ids.len() as i32)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(avatars)
+}
+
+#[tracing::instrument(name = "db.anonymize_jobs.finish", skip(pool))]
+async fn finish(pool: &Pool<Postgres>, job_id: &str, error: Option<String>) -> Result<(), Error> {
+    sqlx::query(
+        "update anonymize_jobs set status = $2, error = $3, finished_at = current_timestamp \
+         where job_id = $1",
+    )
+    .bind(job_id)
+    .bind(if error.is_some() { "failed" } else { "done" })
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Works through `user_ids` batch by batch. A failed batch stops the job;
+/// the batches before it stay anonymized.
+async fn run(state: Arc<AppState>, job_id: String, user_ids: Vec<String>) {
+    let mut error = None;
+    for batch in user_ids.chunks(BATCH_USERS) {
+        match anonymize(&state.pool, &job_id, batch).await {
+            Ok(avatars) => {
+                delete_objects(&state, avatars);
+                for user_id in batch {
+                    state.user_cache.invalidate(user_id).await;
+                }
+            }
+            Err(e) => {
+                tracing::error!(job_id, error = %e, "Anonymize batch failed");
+                error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    if let Err(e) = finish(&state.pool, &job_id, error).await {
+        tracing::error!(job_id, error = %e, "Failed to finish anonymize job");
+    }
+}
+
+/// Starts anonymizing the selected users in the background and answers
+/// `202` with the job to poll
+pub async fn bulk_anonymize_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(req): ValidatedJson<BulkAnonymizeRequest>,
+) -> Result<AnonymizeJobResponse, MetaResponse> {
+    let storage_error = |e: Error| MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    };
+    let user_ids = select_users(&state.pool, &req)
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("The filter matches more than {} users", MAX_USERS),
+        })?;
+    let job = create_job(&state.pool, &user.user_id, user_ids.len())
+        .await
+        .map_err(storage_error)?;
+    tracing::info!(
+        job_id = job.job_id,
+        users = user_ids.len(),
+        "Anonymize job started"
+    );
+    tokio::spawn(run(state.clone(), job.job_id.clone(), user_ids));
+    Ok(AnonymizeJobResponse {
+        meta: MetaResponse {
+            code: StatusCode::ACCEPTED.to_i32(),
+            message: "Accepted".to_string(),
+        },
+        data: job,
+    })
+}
+
+pub async fn anonymize_job_handler(
+    State(state): State<Arc<AppState>>,
+    Path(job_id): Path<String>,
+) -> Result<AnonymizeJobResponse, MetaResponse> {
+    let job = get_job(&state.pool, &job_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Job not found".to_string(),
+        })?;
+    Ok(AnonymizeJobResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: job,
+    })
+}
+
+#[cfg(test)]
+mod tests_anonymize {
+    use std::time::Duration;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        admin::anonymize::AnonymizeJobResponse,
+        audit::handler::record,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::hash_password,
+        },
+        routes::routes,
+        websocket::chat::send_to_user,
+    };
+
+    #[tokio::test]
+    async fn test_bulk_anonymize() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let hash = hash_password("123456".to_string()).unwrap();
+        let admin = add(
+            &state.pool,
+            NewUser::new(
+                "erasure_admin".to_string(),
+                "erasure_admin@mail.com".to_string(),
+                hash.clone(),
+            ),
+        )
+        .await
+        .unwrap();
+        set_role(&admin.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &admin.user_id, &admin.email).unwrap()
+        );
+        let mut users = Vec::new();
+        for name in ["erase_one", "erase_two", "keep_three"] {
+            let user = add(
+                &state.pool,
+                NewUser::new(name.to_string(), format!("{}@mail.com", name), hash.clone()),
+            )
+            .await
+            .unwrap();
+            users.push(user);
+        }
+        let ids: Vec<String> = users.iter().map(|user| user.user_id.clone()).collect();
+        send_to_user(&state.chat, &users[0], &users[2], "my address is ...").await;
+        send_to_user(&state.chat, &users[2], &users[0], "got it").await;
+        record(
+            &state.pool,
+            &ids[0],
+            "auth.login",
+            "/login",
+            200,
+            Some("10.0.0.1"),
+        )
+        .await
+        .unwrap();
+
+        server
+            .post("/api/v1/admin/users/bulk-anonymize")
+            .add_header("Authorization", &token)
+            .json(&json!({"filter": {}}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The admin is left out even when selected
+        let response = server
+            .post("/api/v1/admin/users/bulk-anonymize")
+            .add_header("Authorization", &token)
+            .json(&json!({"user_ids": [&ids[0], &ids[1], &admin.user_id]}))
+            .await;
+        response.assert_status(StatusCode::ACCEPTED);
+        let job = response.json::<AnonymizeJobResponse>().data;
+        assert_eq!(job.total, 2);
+
+        let path = format!("/api/v1/admin/users/bulk-anonymize/{}", job.job_id);
+        let mut job = job;
+        for _ in 0..50 {
+            job = server
+                .get(&path)
+                .add_header("Authorization", &token)
+                .await
+                .json::<AnonymizeJobResponse>()
+                .data;
+            if job.status != "running" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!((job.status.as_str(), job.processed), ("done", 2));
+
+        let names: Vec<String> = sqlx::query_scalar(
+            "select user_name from users where user_id = any($1) order by user_name",
+        )
+        .bind(&ids)
+        .fetch_all(&*state.pool)
+        .await
+        .unwrap();
+        assert!(names[0].starts_with("deleted_") && names[1].starts_with("deleted_"));
+        assert_eq!(names[2], "keep_three");
+        let ip: Option<String> = sqlx::query_scalar("select ip from audit_log where actor_id = $1")
+            .bind(&ids[0])
+            .fetch_one(&*state.pool)
+            .await
+            .unwrap();
+        assert_eq!(ip, None);
+        // What they wrote goes, replies to them stay
+        let bodies: Vec<String> = sqlx::query_scalar(
+            "select body from messages where sender_id = any($1) or receiver_id = any($1)",
+        )
+        .bind(&ids)
+        .fetch_all(&*state.pool)
+        .await
+        .unwrap();
+        assert_eq!(bodies, ["got it"]);
+        // Nothing else about an audit entry can change
+        assert!(
+            sqlx::query("update audit_log set target = 'x' where actor_id = $1")
+                .bind(&ids[0])
+                .execute(&*state.pool)
+                .await
+                .is_err()
+        );
+
+        server
+            .post("/api/v1/auth/login")
+            .form(&json!({"user_name": "erase_one", "password": "123456"}))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server
+            .get("/api/v1/admin/users/bulk-anonymize/unknown")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
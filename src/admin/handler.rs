@@ -0,0 +1,452 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::util::{MetaResponse, StatusCodeExt},
+    config::{
+        flavor::load_config,
+        logger::{self, LEVELS},
+        runtime::{self, RuntimeSettings},
+        settings::Settings,
+    },
+    metrics::{Channel, QueryStats, RequestStats, query_stats},
+    validation::ValidatedJson,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelStats {
+    /// Open private chat channels (one per connected user)
+    pub private_channels: usize,
+    /// Messages queued across private channels, not yet read by every receiver
+    pub private_backlog: usize,
+    /// Private messages delivered but not saved to the history
+    pub unstored_messages: u64,
+    pub group_backlog: usize,
+    pub event_backlog: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+    /// Share of `max` connections checked out, 0.0 to 1.0
+    pub utilization: f64,
+    /// How long the last periodic probe waited for a connection
+    pub acquire_wait_ms: f64,
+}
+
+impl PoolStats {
+    fn of(pool: &Pool<Postgres>, acquire_wait_ms: f64) -> Self {
+        let size = pool.size();
+        let idle = pool.num_idle();
+        let max = pool.options().get_max_connections();
+        let in_use = size.saturating_sub(idle as u32);
+        Self {
+            size,
+            idle,
+            max,
+            utilization: if max > 0 {
+                in_use as f64 / max as f64
+            } else {
+                0.0
+            },
+            acquire_wait_ms,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatabaseStats {
+    pub primary: PoolStats,
+    pub replica: Option<PoolStats>,
+    /// Durations of each query function since startup
+    pub queries: BTreeMap<String, QueryStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub connections: BTreeMap<Channel, i64>,
+    pub channels: ChannelStats,
+    pub requests: RequestStats,
+    pub database: DatabaseStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub meta: MetaResponse,
+    pub data: Stats,
+}
+
+impl IntoResponse for StatsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+pub async fn stats_handler(State(state): State<Arc<AppState>>) -> StatsResponse {
+    let (private_channels, private_backlog) = {
+        let connections = state.chat.connections.read().await;
+        (
+            connections.len(),
+            connections.values().map(|tx| tx.len()).sum(),
+        )
+    };
+
+    StatsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: Stats {
+            connections: Channel::ALL
+                .into_iter()
+                .map(|channel| (channel, state.metrics.connections(channel)))
+                .collect(),
+            channels: ChannelStats {
+                private_channels,
+                private_backlog,
+                unstored_messages: state.chat.unstored(),
+                group_backlog: state.group.tx.len(),
+                event_backlog: state.events.backlog(),
+            },
+            requests: state.metrics.requests(),
+            database: DatabaseStats {
+                primary: PoolStats::of(&state.pool, state.metrics.acquire_wait_ms(false)),
+                replica: state
+                    .replica
+                    .as_deref()
+                    .map(|replica| PoolStats::of(replica, state.metrics.acquire_wait_ms(true))),
+                queries: query_stats(),
+            },
+        },
+    }
+}
+
+/// Settings as the server runs with them right now
+#[derive(Serialize)]
+pub struct EffectiveConfig {
+    /// Config file of the flavor, e.g. `dev.toml`
+    pub file: String,
+    /// The file with `APP_` overrides, secrets from the backend and defaults
+    /// for what it leaves out, then runtime changes (reloads, log level, IP
+    /// lists) on top. Secrets read `[redacted]`, or `""` when unset.
+    pub settings: Settings,
+}
+
+#[derive(Serialize)]
+pub struct ConfigResponse {
+    pub meta: MetaResponse,
+    pub data: EffectiveConfig,
+}
+
+impl IntoResponse for ConfigResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+pub async fn config_handler(State(state): State<Arc<AppState>>) -> ConfigResponse {
+    let mut settings = (*state.settings).clone();
+    state.runtime.load().overlay(&mut settings);
+    settings.ip_filter.lists = state.ip_filter.lists();
+    ConfigResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: EffectiveConfig {
+            file: load_config().unwrap_or_default(),
+            settings,
+        },
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuntimeResponse {
+    pub meta: MetaResponse,
+    pub data: RuntimeSettings,
+}
+
+impl IntoResponse for RuntimeResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// Re-reads the config file now instead of waiting for the file watcher
+pub async fn reload_config_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<RuntimeResponse, MetaResponse> {
+    let runtime = runtime::reload(&state)
+        .await
+        .map_err(|message| MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message,
+        })?;
+    Ok(RuntimeResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: (*runtime).clone(),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct LogLevelRequest {
+    /// `EnvFilter` directives; the configured `logging.level` when missing
+    pub level: Option<String>,
+    /// Module path inside this crate to level, e.g. `websocket::chat` to `debug`
+    #[serde(default)]
+    #[validate(custom(function = "validate_modules"))]
+    pub modules: BTreeMap<String, String>,
+}
+
+fn validate_modules(modules: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    let is_path = |module: &str| {
+        module.split("::").all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+        })
+    };
+    for (module, level) in modules {
+        if !is_path(module) || !LEVELS.contains(&level.as_str()) {
+            return Err(ValidationError::new("modules").with_message(
+                "must map module paths to trace, debug, info, warn, error or off".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevel {
+    /// Directives of the active filter
+    pub level: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogLevelResponse {
+    pub meta: MetaResponse,
+    pub data: LogLevel,
+}
+
+impl IntoResponse for LogLevelResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+fn log_level_response(level: String) -> LogLevelResponse {
+    LogLevelResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: LogLevel { level },
+    }
+}
+
+pub async fn log_level_handler(State(state): State<Arc<AppState>>) -> LogLevelResponse {
+    log_level_response(state.runtime.load().log_level.clone())
+}
+
+/// Swaps the log filter without a restart. Lasts until the next config
+/// reload, which goes back to `logging.level`.
+pub async fn set_log_level_handler(
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(req): ValidatedJson<LogLevelRequest>,
+) -> Result<LogLevelResponse, MetaResponse> {
+    if logger::pinned_by_env() {
+        return Err(MetaResponse {
+            code: StatusCode::CONFLICT.to_i32(),
+            message: "The log level is set by RUST_LOG".to_string(),
+        });
+    }
+    let level = req
+        .level
+        .unwrap_or_else(|| state.settings.logging.level.clone());
+    let level = logger::directives(&level, &req.modules);
+    logger::set_level(&level).map_err(|message| MetaResponse {
+        code: StatusCode::UNPROCESSABLE_ENTITY.to_i32(),
+        message,
+    })?;
+    let mut runtime = (**state.runtime.load()).clone();
+    runtime.log_level = level.clone();
+    state.runtime.store(Arc::new(runtime));
+    tracing::info!(level = %level, "Log level changed");
+    Ok(log_level_response(level))
+}
+
+#[cfg(test)]
+mod tests_admin {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        admin::handler::{LogLevelResponse, RuntimeResponse},
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::{hash_password, random_name},
+        },
+        metrics::Channel,
+        routes::routes,
+    };
+
+    async fn token(state: &AppState, admin: bool) -> String {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name, email, hash))
+            .await
+            .unwrap();
+        if admin {
+            set_role(&user.user_id, ADMIN_ROLE, &state.pool)
+                .await
+                .unwrap();
+        }
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = token(&state, true).await;
+
+        let _connection = state.metrics.connection(Channel::GroupChat);
+        server.get("/healthz").await.assert_status_ok();
+
+        let response = server
+            .get("/api/v1/admin/stats")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["data"]["connections"]["group_chat"], 1);
+        assert_eq!(body["data"]["connections"]["private_chat"], 0);
+        assert!(body["data"]["requests"]["total"].as_u64().unwrap() >= 1);
+        assert!(body["data"]["database"]["primary"]["max"].as_u64().unwrap() > 0);
+        assert!(body["data"]["database"]["replica"].is_null());
+        assert!(body["data"]["database"]["primary"]["acquire_wait_ms"].is_number());
+        assert!(body["data"]["database"]["queries"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_stats_requires_admin() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = token(&state, false).await;
+
+        let response = server
+            .get("/api/v1/admin/stats")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+
+        let response = server.get("/api/v1/admin/stats").await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = token(&state, true).await;
+
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.features.registration = false;
+        state.runtime.store(Arc::new(runtime));
+
+        let response = server
+            .post("/api/v1/admin/config/reload")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<RuntimeResponse>();
+        assert!(body.data.features.registration);
+        assert!(state.runtime.load().features.registration);
+    }
+
+    #[tokio::test]
+    async fn test_effective_config() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.features.registration = false;
+        state.runtime.store(Arc::new(runtime));
+
+        let response = server
+            .get("/api/v1/admin/config")
+            .add_header("Authorization", token(&state, true).await)
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        let settings = &body["data"]["settings"];
+        assert_eq!(settings["jwt"]["key"], "[redacted]");
+        assert_eq!(settings["database"]["password"], "[redacted]");
+        assert_eq!(settings["features"]["registration"], false);
+        assert_eq!(settings["database"]["name"], state.settings.database.name);
+
+        server
+            .get("/api/v1/admin/config")
+            .add_header("Authorization", token(&state, false).await)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_log_level() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = token(&state, true).await;
+
+        let response = server
+            .put("/api/v1/admin/log-level")
+            .add_header("Authorization", &token)
+            .json(&json!({"level": "info", "modules": {"websocket::chat": "debug"}}))
+            .await;
+        response.assert_status_ok();
+        let level = "info,example_axum_api::websocket::chat=debug";
+        assert_eq!(response.json::<LogLevelResponse>().data.level, level);
+        assert_eq!(state.runtime.load().log_level, level);
+        let response = server
+            .get("/api/v1/admin/log-level")
+            .add_header("Authorization", &token)
+            .await;
+        assert_eq!(response.json::<LogLevelResponse>().data.level, level);
+
+        server
+            .put("/api/v1/admin/log-level")
+            .add_header("Authorization", &token)
+            .json(&json!({"modules": {"websocket::chat": "loud"}}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .put("/api/v1/admin/log-level")
+            .add_header("Authorization", &token)
+            .json(&json!({"level": "info,[=bad"}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(state.runtime.load().log_level, level);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod anonymize;
+pub mod handler;
@@ -0,0 +1,280 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use chrono::{Days, NaiveDate, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use tokio::task::JoinHandle;
+
+use crate::{
+    app_state::AppState,
+    auth::util::{MetaResponse, StatusCodeExt},
+};
+
+/// Days returned when the query names no range
+pub const DEFAULT_DAYS: u64 = 30;
+
+/// Longest range one request may ask for
+pub const MAX_DAYS: u64 = 366;
+
+/// Engagement of one UTC day, see `rollup`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyMetrics {
+    /// `YYYY-MM-DD`
+    pub day: String,
+    /// Users active on the day
+    pub active_users: i64,
+    /// Users active in the 30 days ending with the day
+    pub monthly_active_users: i64,
+    pub new_users: i64,
+    pub new_groups: i64,
+    /// RFC 3339, when the row was last recomputed
+    pub computed_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetricsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<DailyMetrics>,
+}
+
+impl IntoResponse for MetricsResponse {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetricsQuery {
+    /// `YYYY-MM-DD`, inclusive
+    pub from: Option<String>,
+    /// `YYYY-MM-DD`, inclusive; today when missing
+    pub to: Option<String>,
+}
+
+/// Recomputes `metrics_daily` for every day from `from` to `to`. A user is
+/// active on a day when they sent an analytics event or a private message,
+/// made an audited call or registered that day; audit entries of the API
+/// itself don't count. Group messages aren't stored, so they aren't counted.
+#[tracing::instrument(name = "db.metrics_daily.rollup", skip(pool))]
+pub async fn rollup(pool: &Pool<Postgres>, from: NaiveDate, to: NaiveDate) -> Result<u64, Error> {
+    let sql = "with activity as ( \
+            select user_id, (occurred_at at time zone 'utc')::date as day from analytics_events \
+            where occurred_at >= ($1::date - 29)::timestamp at time zone 'utc' \
+              and occurred_at < ($2::date + 1)::timestamp at time zone 'utc' \
+            union \
+            select actor_id, (created_at at time zone 'utc')::date from audit_log \
+            where actor_id not in ('anonymous', 'system') \
+              and created_at >= ($1::date - 29)::timestamp at time zone 'utc' \
+              and created_at < ($2::date + 1)::timestamp at time zone 'utc' \
+            union \
+            select sender_id, (sent_at at time zone 'utc')::date from messages \
+            where sent_at >= ($1::date - 29)::timestamp at time zone 'utc' \
+              and sent_at < ($2::date + 1)::timestamp at time zone 'utc' \
+            union \
+            select user_id, created_at::date from users \
+            where created_at >= $1::date - 29 and created_at < $2::date + 1 \
+        ) \
+        insert into metrics_daily (day, active_users, monthly_active_users, new_users, new_groups, computed_at) \
+        select d.day, \
+            (select count(distinct user_id) from activity a where a.day = d.day), \
+            (select count(distinct user_id) from activity a where a.day between d.day - 29 and d.day), \
+            (select count(*) from users where created_at >= d.day and created_at < d.day + 1), \
+            (select count(*) from groups where created_at >= d.day and created_at < d.day + 1), \
+            current_timestamp \
+        from (select generate_series($1::date, $2::date, interval '1 day')::date as day) d \
+        on conflict (day) do update set \
+            active_users = excluded.active_users, \
+            monthly_active_users = excluded.monthly_active_users, \
+            new_users = excluded.new_users, \
+            new_groups = excluded.new_groups, \
+            computed_at = excluded.computed_at";
+    let result = sqlx::query(sql).bind(from).bind(to).execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(name = "db.metrics_daily.get", skip(pool))]
+pub async fn get_range(
+    pool: &Pool<Postgres>,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<DailyMetrics>, Error> {
+    let sql = "select to_char(day, 'YYYY-MM-DD') as day, active_users, monthly_active_users, \
+               new_users, new_groups, computed_at from metrics_daily \
+               where day between $1 and $2 order by day";
+    sqlx::query(sql)
+        .bind(from)
+        .bind(to)
+        .map(|row: PgRow| DailyMetrics {
+            day: row.get("day"),
+            active_users: row.get::<i32, _>("active_users").into(),
+            monthly_active_users: row.get::<i32, _>("monthly_active_users").into(),
+            new_users: row.get::<i32, _>("new_users").into(),
+            new_groups: row.get::<i32, _>("new_groups").into(),
+            computed_at: row
+                .get::<chrono::DateTime<Utc>, _>("computed_at")
+                .to_rfc3339(),
+        })
+        .fetch_all(pool)
+        .await
+}
+
+/// Every `analytics.rollup_secs`, recomputes the last `analytics.rollup_days`
+/// days, so today's row fills in as the day goes and yesterday's is final
+pub fn spawn_rollup(state: Arc<AppState>) -> JoinHandle<()> {
+    let settings = state.settings.analytics.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.rollup_secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let to = Utc::now().date_naive();
+            let from = to - Days::new(settings.rollup_days.saturating_sub(1));
+            if let Err(e) = rollup(&state.pool, from, to).await {
+                tracing::error!(error = %e, "Failed to compute daily metrics");
+            }
+        }
+    })
+}
+
+fn parse_day(value: Option<&str>, field: &str) -> Result<Option<NaiveDate>, MetaResponse> {
+    value
+        .map(|value| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: format!("{} must be a date (YYYY-MM-DD)", field),
+            })
+        })
+        .transpose()
+}
+
+pub async fn metrics_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<MetricsQuery>,
+) -> Result<MetricsResponse, MetaResponse> {
+    let to = parse_day(params.to.as_deref(), "to")?.unwrap_or_else(|| Utc::now().date_naive());
+    let from = parse_day(params.from.as_deref(), "from")?
+        .unwrap_or_else(|| to - Days::new(DEFAULT_DAYS - 1));
+    if from > to {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "from must not be after to".to_string(),
+        });
+    }
+    if (to - from).num_days() >= MAX_DAYS as i64 {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("At most {} days per request", MAX_DAYS),
+        });
+    }
+
+    let data = get_range(&state.pool, from, to)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    Ok(MetricsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests_daily {
+    use chrono::NaiveDate;
+    use http::StatusCode;
+
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        analytics::daily::{MetricsResponse, rollup},
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::hash_password,
+        },
+        routes::routes,
+    };
+
+    #[tokio::test]
+    async fn test_rollup_and_range() {
+        let state = AppState::isolated().await;
+        let hash = hash_password("123456".to_string()).unwrap();
+        let admin = add(
+            &state.pool,
+            NewUser::new("metrics-admin".into(), "admin@mail.com".into(), hash),
+        )
+        .await
+        .unwrap();
+        set_role(&admin.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+
+        sqlx::raw_sql(
+            "insert into analytics_events (user_id, name, occurred_at) values \
+                ('u1', 'screen.view', '2026-09-20T10:00:00Z'), \
+                ('u1', 'screen.view', '2026-10-01T10:00:00Z'), \
+                ('u2', 'screen.view', '2026-10-01T23:30:00Z'), \
+                ('u2', 'screen.view', '2026-10-02T00:30:00+07:00'); \
+             insert into groups (group_id, name, created_at) values \
+                ('g1', 'metrics-g1', '2026-10-02 08:00:00'), \
+                ('g2', 'metrics-g2', '2026-10-02 09:00:00');",
+        )
+        .execute(&*state.pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "insert into messages (sender_id, receiver_id, body, sent_at) \
+             values ($1, $1, 'note to self', '2026-10-02T12:00:00Z')",
+        )
+        .bind(&admin.user_id)
+        .execute(&*state.pool)
+        .await
+        .unwrap();
+
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap();
+        assert_eq!(rollup(&state.pool, day(1), day(2)).await.unwrap(), 2);
+        // Recomputing replaces rows instead of adding to them
+        assert_eq!(rollup(&state.pool, day(1), day(2)).await.unwrap(), 2);
+
+        let token = create_access_token(&state.jwt_config, &admin.user_id, &admin.email).unwrap();
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let response = server
+            .get("/api/v1/admin/metrics")
+            .add_query_param("from", "2026-10-01")
+            .add_query_param("to", "2026-10-02")
+            .add_header("Authorization", format!("Bearer {}", token))
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<MetricsResponse>();
+        assert_eq!(body.data.len(), 2);
+        let (first, second) = (&body.data[0], &body.data[1]);
+        assert_eq!(first.day, "2026-10-01");
+        // u2's second event is 2026-10-01T17:30Z
+        assert_eq!((first.active_users, first.monthly_active_users), (2, 2));
+        assert_eq!(second.day, "2026-10-02");
+        // The admin sent a message
+        assert_eq!((second.active_users, second.monthly_active_users), (1, 3));
+        assert_eq!((second.new_users, second.new_groups), (0, 2));
+
+        for (from, to) in [("2026-10-02", "2026-10-01"), ("yesterday", "2026-10-01")] {
+            server
+                .get("/api/v1/admin/metrics")
+                .add_query_param("from", from)
+                .add_query_param("to", to)
+                .add_header("Authorization", format!("Bearer {}", token))
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres};
+use tokio::{sync::Mutex, task::JoinHandle};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::DomainEvent,
+    validation::ValidatedJson,
+};
+
+/// Events accepted in one request
+pub const MAX_BATCH: u64 = 100;
+
+/// Serialized size allowed for one event's `properties`, so a full batch
+/// stays under the default body limit
+pub const MAX_PROPERTIES_BYTES: usize = 2048;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyticsSink {
+    /// Rows in `analytics_events`
+    #[default]
+    Database,
+    /// One `analytics.events` domain event per flush, for `[streaming]` or webhooks
+    Stream,
+}
+
+/// Settings from the `[analytics]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AnalyticsSettings {
+    pub sink: AnalyticsSink,
+    /// How often buffered events are written out
+    pub flush_ms: u64,
+    /// Events held between flushes; requests beyond it get a 503
+    pub max_buffered: usize,
+    /// How often `metrics_daily` is recomputed, see `daily::spawn_rollup`
+    pub rollup_secs: u64,
+    /// Days recomputed each time, counting today
+    pub rollup_days: u64,
+}
+
+impl Default for AnalyticsSettings {
+    fn default() -> Self {
+        Self {
+            sink: AnalyticsSink::Database,
+            flush_ms: 2000,
+            max_buffered: 10_000,
+            rollup_secs: 3600,
+            rollup_days: 2,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct AnalyticsEvent {
+    /// e.g. `screen.view` or `feature.search_used`
+    #[validate(custom(function = "validate_event_name"))]
+    pub name: String,
+    /// RFC 3339, when the client saw it; the time it was received otherwise
+    #[serde(default)]
+    #[validate(custom(function = "validate_timestamp"))]
+    pub occurred_at: Option<String>,
+    #[serde(default)]
+    #[validate(custom(function = "validate_properties"))]
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct AnalyticsBatch {
+    #[validate(length(min = 1, max = "MAX_BATCH"), nested)]
+    pub events: Vec<AnalyticsEvent>,
+}
+
+/// An accepted event, as written to the database or the event stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalyticsRecord {
+    pub user_id: String,
+    pub name: String,
+    pub properties: serde_json::Value,
+    /// RFC 3339
+    pub occurred_at: String,
+    /// RFC 3339
+    pub received_at: String,
+}
+
+fn validate_event_name(name: &str) -> Result<(), ValidationError> {
+    let valid = (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'.');
+    if !valid {
+        return Err(ValidationError::new("event_name")
+            .with_message("must be 1 to 64 lowercase letters, digits, '_' or '.'".into()));
+    }
+    Ok(())
+}
+
+fn validate_timestamp(value: &str) -> Result<(), ValidationError> {
+    if DateTime::parse_from_rfc3339(value).is_err() {
+        return Err(
+            ValidationError::new("timestamp").with_message("must be an RFC 3339 timestamp".into())
+        );
+    }
+    Ok(())
+}
+
+fn validate_properties(
+    properties: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), ValidationError> {
+    let size = serde_json::to_vec(properties).map_or(usize::MAX, |json| json.len());
+    if size > MAX_PROPERTIES_BYTES {
+        return Err(ValidationError::new("properties_size").with_message(
+            format!("must be at most {} bytes of JSON", MAX_PROPERTIES_BYTES).into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Events accepted but not written out yet. Bounded, so a slow database
+/// pushes back on clients instead of growing memory.
+pub struct AnalyticsBuffer {
+    events: Mutex<Vec<AnalyticsRecord>>,
+    capacity: usize,
+}
+
+impl AnalyticsBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    /// Buffers all of `records`, or none of them when they don't fit
+    pub async fn push(&self, records: Vec<AnalyticsRecord>) -> bool {
+        let mut events = self.events.lock().await;
+        if events.len() + records.len() > self.capacity {
+            return false;
+        }
+        events.extend(records);
+        true
+    }
+
+    pub async fn take(&self) -> Vec<AnalyticsRecord> {
+        std::mem::take(&mut *self.events.lock().await)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.events.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+#[tracing::instrument(name = "db.analytics.insert", skip_all, fields(count = records.len()))]
+pub async fn insert(pool: &Pool<Postgres>, records: &[AnalyticsRecord]) -> Result<u64, Error> {
+    let mut user_ids = Vec::with_capacity(records.len());
+    let mut names = Vec::with_capacity(records.len());
+    let mut properties = Vec::with_capacity(records.len());
+    let mut occurred = Vec::with_capacity(records.len());
+    let mut received = Vec::with_capacity(records.len());
+    for record in records {
+        user_ids.push(record.user_id.as_str());
+        names.push(record.name.as_str());
+        properties.push(record.properties.to_string());
+        occurred.push(record.occurred_at.as_str());
+        received.push(record.received_at.as_str());
+    }
+    let sql = "insert into analytics_events (user_id, name, properties, occurred_at, received_at) \
+               select user_id, name, properties::jsonb, occurred_at::timestamptz, received_at::timestamptz \
+               from unnest($1::text[], $2::text[], $3::text[], $4::text[], $5::text[]) \
+               as t(user_id, name, properties, occurred_at, received_at)";
+    let result = sqlx::query(sql)
+        .bind(&user_ids)
+        .bind(&names)
+        .bind(&properties)
+        .bind(&occurred)
+        .bind(&received)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Writes out everything buffered so far to the configured sink, returning
+/// how many events went. Events that fail to insert are logged and dropped:
+/// analytics is best effort, and retrying could hold up the next batches.
+pub async fn flush(state: &AppState) -> usize {
+    let records = state.analytics.take().await;
+    if records.is_empty() {
+        return 0;
+    }
+    let count = records.len();
+    match state.settings.analytics.sink {
+        AnalyticsSink::Database => {
+            if let Err(e) = insert(&state.pool, &records).await {
+                tracing::error!(count, error = %e, "Failed to store analytics events");
+                return 0;
+            }
+        }
+        AnalyticsSink::Stream => state
+            .events
+            .publish(DomainEvent::AnalyticsEvents { events: records }),
+    }
+    count
+}
+
+/// Flushes the buffer every `analytics.flush_ms`
+pub fn spawn_flusher(state: Arc<AppState>) -> JoinHandle<()> {
+    let period = Duration::from_millis(state.settings.analytics.flush_ms);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            flush(&state).await;
+        }
+    })
+}
+
+pub async fn ingest_handler(
+    AuthUser(claims): AuthUser,
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(batch): ValidatedJson<AnalyticsBatch>,
+) -> MetaResponse {
+    let received_at = Utc::now().to_rfc3339();
+    let records = batch
+        .events
+        .into_iter()
+        .map(|event| AnalyticsRecord {
+            user_id: claims.user_id.clone(),
+            name: event.name,
+            properties: serde_json::Value::Object(event.properties),
+            occurred_at: event
+                .occurred_at
+                .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+                .map_or_else(|| received_at.clone(), |time| time.to_utc().to_rfc3339()),
+            received_at: received_at.clone(),
+        })
+        .collect();
+
+    if !state.analytics.push(records).await {
+        return MetaResponse {
+            code: StatusCode::SERVICE_UNAVAILABLE.to_i32(),
+            message: "Too many analytics events buffered, retry later".to_string(),
+        };
+    }
+    MetaResponse {
+        code: StatusCode::ACCEPTED.to_i32(),
+        message: "Accepted".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests_analytics {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+    use sqlx::Row;
+
+    use crate::{
+        AppState,
+        analytics::handler::{AnalyticsBuffer, AnalyticsSink, flush},
+        auth::jwt::create_access_token,
+        error::ErrorResponse,
+        event_bus::DomainEvent,
+        routes::routes,
+    };
+
+    const USER_ID: &str = "analytics-user";
+
+    fn bearer(state: &AppState) -> String {
+        let token = create_access_token(&state.jwt_config, USER_ID, "analytics@mail.com").unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[tokio::test]
+    async fn test_ingest_and_flush() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+
+        let body = json!({"events": [
+            {"name": "screen.view", "properties": {"screen": "home"}},
+            {"name": "feature.search_used", "occurred_at": "2026-10-01T08:00:00+07:00"},
+        ]});
+        let response = server
+            .post("/api/v1/analytics/events")
+            .add_header("Authorization", bearer(&state))
+            .json(&body)
+            .await;
+        response.assert_status(StatusCode::ACCEPTED);
+        assert_eq!(state.analytics.len().await, 2);
+
+        assert_eq!(flush(&state).await, 2);
+        assert!(state.analytics.is_empty().await);
+        let rows = sqlx::query(
+            "select user_id, name, properties::text as properties, \
+             to_char(occurred_at at time zone 'utc', 'YYYY-MM-DD HH24:MI') as occurred_at \
+             from analytics_events order by name",
+        )
+        .fetch_all(&*state.pool)
+        .await
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<String, _>("name"), "feature.search_used");
+        assert_eq!(rows[0].get::<String, _>("user_id"), USER_ID);
+        assert_eq!(rows[0].get::<String, _>("occurred_at"), "2026-10-01 01:00");
+        assert_eq!(
+            rows[1].get::<String, _>("properties"),
+            r#"{"screen": "home"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_batch() {
+        let state = Arc::new(AppState::fake().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+
+        let large = "x".repeat(5000);
+        for body in [
+            json!({"events": []}),
+            json!({"events": [{"name": "Screen View"}]}),
+            json!({"events": [{"name": "screen.view", "occurred_at": "yesterday"}]}),
+            json!({"events": [{"name": "screen.view", "properties": {"note": large}}]}),
+        ] {
+            let response = server
+                .post("/api/v1/analytics/events")
+                .add_header("Authorization", bearer(&state))
+                .json(&body)
+                .await;
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(response.json::<ErrorResponse>().meta.errors.len(), 1);
+        }
+        assert!(state.analytics.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn test_full_buffer_and_stream_sink() {
+        let mut state = AppState::fake().await;
+        let mut settings = (*state.settings).clone();
+        settings.analytics.sink = AnalyticsSink::Stream;
+        state.settings = Arc::new(settings);
+        state.analytics = Arc::new(AnalyticsBuffer::new(2));
+        let state = Arc::new(state);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let mut rx = state.events.subscribe();
+
+        let batch = json!({"events": [{"name": "a"}, {"name": "b"}]});
+        for expected in [StatusCode::ACCEPTED, StatusCode::SERVICE_UNAVAILABLE] {
+            server
+                .post("/api/v1/analytics/events")
+                .add_header("Authorization", bearer(&state))
+                .json(&batch)
+                .await
+                .assert_status(expected);
+        }
+
+        assert_eq!(flush(&state).await, 2);
+        match rx.recv().await.unwrap() {
+            DomainEvent::AnalyticsEvents { events } => {
+                let names: Vec<_> = events.iter().map(|event| event.name.as_str()).collect();
+                assert_eq!(names, ["a", "b"]);
+                assert!(events.iter().all(|event| event.user_id == USER_ID));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
@@ -1,27 +1,404 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use arc_swap::ArcSwap;
 use sqlx::{Pool, Postgres};
 
 use crate::{
-    auth::jwt::JwtConfig,
+    analytics::handler::AnalyticsBuffer,
+    auth::{
+        jwt::JwtConfig,
+        repository::{PgUserRepository, UserRepository},
+        revocation::{PgRevokedTokens, RevokedTokens},
+    },
+    cache::UserCache,
+    config::{
+        connection::connect_replica,
+        runtime::{Runtime, RuntimeSettings},
+        settings::Settings,
+    },
+    event_bus::EventBus,
+    group::repository::{GroupRepository, PgGroupRepository},
+    health::handler::ProbeState,
+    ip_filter::IpFilter,
+    mail::mailer::{Mailer, build_mailer},
+    message::repository::PgMessageRepository,
+    metrics::Metrics,
+    moderation::shadow_ban::ShadowBans,
+    rate_limit::RateLimiter,
+    sms::sender::{SmsSender, build_sms_sender},
+    storage::{
+        backend::{Storage, build_storage},
+        scan::{UploadScanner, build_scanner},
+    },
     websocket::{chat::PrivateChatState, group::GroupState},
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: Arc<Pool<Postgres>>,
+    /// Read-only replica, when `[database.replica]` is configured
+    pub replica: Option<Arc<Pool<Postgres>>>,
+    pub users: Arc<dyn UserRepository>,
+    pub groups: Arc<dyn GroupRepository>,
+    /// Checked by `auth_middleware` after the token verifies
+    pub revoked_tokens: Arc<dyn RevokedTokens>,
     pub chat: Arc<PrivateChatState>,
     pub group: Arc<GroupState>,
+    /// Shared with `chat` and `group`, which enforce it
+    pub shadow_bans: Arc<ShadowBans>,
     pub jwt_config: Arc<JwtConfig>,
+    pub hook_limiter: Arc<RateLimiter>,
+    pub events: Arc<EventBus>,
+    pub probe: Arc<ProbeState>,
+    pub settings: Arc<Settings>,
+    pub user_cache: Arc<UserCache>,
+    pub mailer: Arc<dyn Mailer>,
+    pub sms: Arc<dyn SmsSender>,
+    pub storage: Arc<dyn Storage>,
+    /// Malware scanner uploads go through, when `[scan]` configures one
+    pub scanner: Option<Arc<dyn UploadScanner>>,
+    pub metrics: Arc<Metrics>,
+    /// Client analytics events waiting for the next flush
+    pub analytics: Arc<AnalyticsBuffer>,
+    pub ip_filter: Arc<IpFilter>,
+    /// Settings that can change without a restart, see `config::runtime`
+    pub runtime: Runtime,
 }
 
 impl AppState {
-    pub fn new(pool: Pool<Postgres>, secret: String) -> Self {
+    pub fn new(pool: Pool<Postgres>, settings: Settings) -> Self {
+        let replica = settings.database.replica.as_ref().and_then(|replica| {
+            connect_replica(&settings.database, replica)
+                .inspect_err(|e| tracing::error!(error = %e, "Invalid replica configuration"))
+                .ok()
+        });
+        let shadow_bans = Arc::new(ShadowBans::default());
         Self {
+            users: Arc::new(PgUserRepository::new(pool.clone(), replica.clone())),
+            groups: Arc::new(PgGroupRepository::new(pool.clone(), replica.clone())),
+            revoked_tokens: Arc::new(PgRevokedTokens::new(pool.clone())),
+            chat: Arc::new(PrivateChatState::new(
+                shadow_bans.clone(),
+                Arc::new(PgMessageRepository::new(pool.clone())),
+            )),
             pool: Arc::new(pool),
-            chat: Arc::new(PrivateChatState::new()),
-            group: Arc::new(GroupState::new()),
-            jwt_config: Arc::new(JwtConfig::new(secret)),
+            replica: replica.map(Arc::new),
+            group: Arc::new(GroupState::new(shadow_bans.clone())),
+            shadow_bans,
+            jwt_config: Arc::new(JwtConfig::new(settings.jwt.key.clone())),
+            hook_limiter: Arc::new(RateLimiter::new(
+                settings.rate_limits.hook_limit,
+                Duration::from_secs(settings.rate_limits.hook_window_secs),
+            )),
+            events: Arc::new(EventBus::new()),
+            probe: Arc::new(ProbeState::new()),
+            user_cache: Arc::new(UserCache::new(&settings.cache)),
+            mailer: build_mailer(&settings.mail),
+            sms: build_sms_sender(&settings.sms),
+            storage: build_storage(&settings.storage),
+            scanner: build_scanner(&settings.scan),
+            metrics: Arc::new(Metrics::new()),
+            analytics: Arc::new(AnalyticsBuffer::new(settings.analytics.max_buffered)),
+            ip_filter: Arc::new(IpFilter::new(&settings.ip_filter)),
+            runtime: Arc::new(ArcSwap::from_pointee(RuntimeSettings::from(&settings))),
+            settings: Arc::new(settings),
         }
     }
 }
+
+/// Config of the test flavor, resolved like the server's own; `TEST_CONFIG`
+/// points it elsewhere
+#[cfg(test)]
+pub fn test_config() -> String {
+    use crate::config::flavor::{TEST, config_file};
+    std::env::var("TEST_CONFIG").unwrap_or_else(|_| config_file(TEST))
+}
+
+/// Schema the test fixture builds from `migrations/`, so tests never touch
+/// the tables of the configured database's `public` schema
+#[cfg(test)]
+pub const TEST_SCHEMA: &str = "api_test";
+
+/// Users every test can log in as
+#[cfg(test)]
+pub const FIXTURE_USERS: &[(&str, &str, &str)] = &[("Jordan", "jordan@mail.com", "123456")];
+
+#[cfg(test)]
+static TEST_SCHEMA_READY: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
+#[cfg(test)]
+impl AppState {
+    /// State on the shared test schema, rebuilt from the migrations and
+    /// seeded with `FIXTURE_USERS` the first time a test asks for it
+    pub async fn test() -> Self {
+        let settings = Settings::load(&test_config())
+            .await
+            .expect("Invalid test configuration");
+        TEST_SCHEMA_READY
+            .get_or_init(|| build_test_schema(&settings.database, TEST_SCHEMA))
+            .await;
+        Self::on_schema(settings, TEST_SCHEMA).await
+    }
+
+    /// State on a schema of its own, dropped together with the returned
+    /// guard, so nothing the test writes is seen by or left for other tests
+    pub async fn isolated() -> IsolatedState {
+        let settings = Settings::load(&test_config())
+            .await
+            .expect("Invalid test configuration");
+        let schema = format!("{}_{}", TEST_SCHEMA, uuid::Uuid::new_v4().simple());
+        build_test_schema(&settings.database, &schema).await;
+        let db = settings.database.clone();
+        IsolatedState {
+            state: Arc::new(Self::on_schema(settings, &schema).await),
+            schema,
+            db,
+        }
+    }
+
+    /// State backed by in-memory users, groups, revocations and chat
+    /// history. The pool is lazy and never connects unless a handler
+    /// bypasses the repositories.
+    pub async fn fake() -> Self {
+        use crate::{
+            auth::{repository::MemoryUserRepository, revocation::MemoryRevokedTokens},
+            group::repository::MemoryGroupRepository,
+            message::repository::MemoryMessageRepository,
+        };
+
+        let mut settings = Settings::load(&test_config())
+            .await
+            .expect("Invalid test configuration");
+        settings.database.replica = None;
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy_with(test_connect_options(&settings.database, TEST_SCHEMA));
+        let state = Self::new(pool, settings);
+        Self {
+            users: Arc::new(MemoryUserRepository::default()),
+            groups: Arc::new(MemoryGroupRepository::default()),
+            revoked_tokens: Arc::new(MemoryRevokedTokens::default()),
+            chat: Arc::new(PrivateChatState::new(
+                state.shadow_bans.clone(),
+                Arc::new(MemoryMessageRepository::default()),
+            )),
+            ..state
+        }
+    }
+
+    async fn on_schema(settings: Settings, schema: &str) -> Self {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(settings.database.max_connection)
+            .connect_with(test_connect_options(&settings.database, schema))
+            .await
+            .expect("Failed to connect to test database");
+        Self::new(pool, settings)
+    }
+}
+
+/// `Arc<AppState>` from `AppState::isolated()`; its schema is dropped when
+/// this goes out of scope, including when the test panics
+#[cfg(test)]
+pub struct IsolatedState {
+    state: Arc<AppState>,
+    schema: String,
+    db: crate::config::settings::DbSettings,
+}
+
+#[cfg(test)]
+impl std::ops::Deref for IsolatedState {
+    type Target = Arc<AppState>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+impl Drop for IsolatedState {
+    fn drop(&mut self) {
+        use sqlx::Connection;
+
+        // Drop can't await, and the test's runtime may be single threaded,
+        // so the cleanup gets a thread and runtime of its own
+        let options = test_connect_options(&self.db, "public");
+        let sql = format!("drop schema if exists {} cascade", self.schema);
+        let cleanup = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let mut conn = sqlx::postgres::PgConnection::connect_with(&options)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                sqlx::raw_sql(&sql)
+                    .execute(&mut conn)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                conn.close().await.ok();
+                Ok::<_, std::io::Error>(())
+            })
+        });
+        if let Ok(Err(e)) = cleanup.join() {
+            tracing::warn!(error = %e, schema = %self.schema, "Failed to drop test schema");
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_connect_options(
+    db: &crate::config::settings::DbSettings,
+    schema: &str,
+) -> sqlx::postgres::PgConnectOptions {
+    crate::config::connection::connect_options(db, &db.url())
+        .expect("Invalid database url")
+        .options([("search_path", schema)])
+}
+
+/// Drops and recreates `schema`, applies every `*.up.sql` in order and adds
+/// the fixture users
+#[cfg(test)]
+async fn build_test_schema(db: &crate::config::settings::DbSettings, schema: &str) {
+    use sqlx::Connection;
+
+    use crate::auth::user::{NewUser, add};
+
+    let mut conn = sqlx::postgres::PgConnection::connect_with(&test_connect_options(db, schema))
+        .await
+        .expect("Failed to connect to test database");
+    sqlx::raw_sql(&format!(
+        "drop schema if exists {schema} cascade; create schema {schema};"
+    ))
+    .execute(&mut conn)
+    .await
+    .expect("Failed to create test schema");
+
+    let mut migrations: Vec<_> = std::fs::read_dir("migrations")
+        .expect("Missing migrations directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.to_string_lossy().ends_with(".up.sql"))
+        .collect();
+    migrations.sort();
+    for path in migrations {
+        let sql = std::fs::read_to_string(&path).expect("Unreadable migration");
+        sqlx::raw_sql(&sql)
+            .execute(&mut conn)
+            .await
+            .unwrap_or_else(|e| panic!("Migration {} failed: {}", path.display(), e));
+    }
+    conn.close().await.ok();
+
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(test_connect_options(db, schema))
+        .await
+        .expect("Failed to connect to test database");
+    for (user_name, email, password) in FIXTURE_USERS {
+        add(
+            &pool,
+            NewUser::new(
+                user_name.to_string(),
+                email.to_string(),
+                password.to_string(),
+            ),
+        )
+        .await
+        .expect("Failed to seed fixture user");
+    }
+    pool.close().await;
+}
+
+#[cfg(test)]
+mod tests_app_state {
+    use sqlx::{Error, Row};
+
+    use crate::{
+        app_state::{AppState, FIXTURE_USERS, test_config},
+        auth::user::{NewUser, add},
+        config::{
+            connection::{connect, is_unavailable, read_with_fallback},
+            settings::{ReplicaSettings, Settings},
+        },
+    };
+
+    async fn state_with_replica(port: u16) -> AppState {
+        let mut settings = Settings::load(&test_config()).await.unwrap();
+        let pool = connect(&settings.database).await.unwrap();
+        settings.database.replica = Some(ReplicaSettings {
+            host: settings.database.host.clone(),
+            port,
+            max_connection: Some(2),
+            acquire_timeout: 1,
+        });
+        AppState::new(pool, settings)
+    }
+
+    async fn select_one(state: &AppState) -> Result<i32, Error> {
+        read_with_fallback(&state.pool, state.replica.as_deref(), |pool| async move {
+            sqlx::query("select 1 as one")
+                .fetch_one(&pool)
+                .await
+                .map(|row| row.get("one"))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_read_from_replica() {
+        let primary_port = Settings::load(&test_config()).await.unwrap().database.port;
+        let state = state_with_replica(primary_port).await;
+        assert!(state.replica.is_some());
+        assert_eq!(select_one(&state).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_falls_back_to_primary() {
+        let state = state_with_replica(1).await;
+        assert_eq!(select_one(&state).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_isolated_schemas() {
+        let count = |state: &AppState| {
+            let pool = state.pool.clone();
+            async move {
+                sqlx::query_scalar::<_, i64>("select count(*) from users")
+                    .fetch_one(pool.as_ref())
+                    .await
+                    .unwrap()
+            }
+        };
+        let first = AppState::isolated().await;
+        let second = AppState::isolated().await;
+        let schema = first.schema.clone();
+
+        add(
+            &first.pool,
+            NewUser::new(
+                "isolated".to_string(),
+                "isolated@mail.com".to_string(),
+                "123456".to_string(),
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(count(&first).await, FIXTURE_USERS.len() as i64 + 1);
+        assert_eq!(count(&second).await, FIXTURE_USERS.len() as i64);
+
+        let pool = second.pool.clone();
+        drop(first);
+        let exists: bool = sqlx::query_scalar(
+            "select exists(select 1 from information_schema.schemata where schema_name = $1)",
+        )
+        .bind(&schema)
+        .fetch_one(pool.as_ref())
+        .await
+        .unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        assert!(is_unavailable(&Error::PoolTimedOut));
+        assert!(!is_unavailable(&Error::RowNotFound));
+    }
+}
@@ -0,0 +1,381 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{MatchedPath, OriginalUri, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        jwt::Claims,
+        util::{MetaResponse, StatusCodeExt, client_ip},
+    },
+};
+
+const PAGE_SIZE: i64 = 50;
+
+/// State of `audit_middleware`: the action recorded for the routes it wraps.
+/// Without one, the action is derived from the admin route, e.g. `admin.stats`.
+#[derive(Clone)]
+pub struct Audit {
+    state: Arc<AppState>,
+    action: Option<&'static str>,
+}
+
+impl Audit {
+    pub fn action(state: Arc<AppState>, action: &'static str) -> Self {
+        Self {
+            state,
+            action: Some(action),
+        }
+    }
+
+    pub fn admin(state: Arc<AppState>) -> Self {
+        Self {
+            state,
+            action: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub actor_id: String,
+    pub action: String,
+    pub target: String,
+    pub status: i16,
+    pub ip: Option<String>,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditResponse {
+    pub meta: MetaResponse,
+    pub page: i64,
+    pub data: Vec<AuditEntry>,
+}
+
+impl IntoResponse for AuditResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub actor_id: Option<String>,
+    pub action: Option<String>,
+    /// RFC 3339, inclusive
+    pub from: Option<String>,
+    /// RFC 3339, exclusive
+    pub to: Option<String>,
+    #[serde(default)]
+    pub page: i64,
+}
+
+#[tracing::instrument(name = "db.audit_log.record", skip(pool))]
+pub async fn record(
+    pool: &Pool<Postgres>,
+    actor_id: &str,
+    action: &str,
+    target: &str,
+    status: u16,
+    ip: Option<&str>,
+) -> Result<(), Error> {
+    let sql =
+        "insert into audit_log (actor_id, action, target, status, ip) values ($1, $2, $3, $4, $5)";
+    sqlx::query(sql)
+        .bind(actor_id)
+        .bind(action)
+        .bind(target)
+        .bind(status as i16)
+        .bind(ip)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Newest first; `$5` is the page size, null for every match
+const SEARCH_SQL: &str = "select audit_id, actor_id, action, target, status, ip, created_at \
+    from audit_log \
+    where ($1::text is null or actor_id = $1) \
+    and ($2::text is null or action = $2) \
+    and ($3::timestamptz is null or created_at >= $3) \
+    and ($4::timestamptz is null or created_at < $4) \
+    order by created_at desc, audit_id desc limit $5 offset $6";
+
+fn entry(data: PgRow) -> AuditEntry {
+    AuditEntry {
+        audit_id: data.get("audit_id"),
+        actor_id: data.get("actor_id"),
+        action: data.get("action"),
+        target: data.get("target"),
+        status: data.get("status"),
+        ip: data.get("ip"),
+        created_at: data.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+    }
+}
+
+#[tracing::instrument(name = "db.audit_log.search", skip(pool))]
+pub async fn search(
+    pool: &Pool<Postgres>,
+    actor_id: Option<&str>,
+    action: Option<&str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    page: i64,
+) -> Result<Vec<AuditEntry>, Error> {
+    let offset = if page > 0 { (page - 1) * PAGE_SIZE } else { 0 };
+    sqlx::query(SEARCH_SQL)
+        .bind(actor_id)
+        .bind(action)
+        .bind(from)
+        .bind(to)
+        .bind(PAGE_SIZE)
+        .bind(offset)
+        .map(entry)
+        .fetch_all(pool)
+        .await
+}
+
+/// Every entry `search` matches, as rows are read instead of a page at a time
+pub fn export<'a>(
+    pool: &'a Pool<Postgres>,
+    actor_id: Option<&'a str>,
+    action: Option<&'a str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> BoxStream<'a, Result<AuditEntry, Error>> {
+    sqlx::query(SEARCH_SQL)
+        .bind(actor_id)
+        .bind(action)
+        .bind(from)
+        .bind(to)
+        .bind(None::<i64>)
+        .bind(0_i64)
+        .map(entry)
+        .fetch(pool)
+}
+
+/// Records the caller, action, path and response status of every request it
+/// wraps, whether or not it succeeded. Must run after `auth_middleware`.
+pub async fn audit_middleware(State(audit): State<Audit>, req: Request, next: Next) -> Response {
+    let actor_id = req
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| claims.user_id.clone())
+        .unwrap_or_else(|| "anonymous".to_string());
+    let action = match audit.action {
+        Some(action) => action.to_string(),
+        None => {
+            let route = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str())
+                .unwrap_or_else(|| req.uri().path());
+            let route = route.split_once("/admin/").map_or(route, |(_, rest)| rest);
+            format!("admin.{}", route.replace('/', "."))
+        }
+    };
+    // Nested routers strip their prefix from the URI
+    let target = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri().path(), |uri| uri.path())
+        .to_string();
+    let ip = client_ip(req.headers());
+
+    let response = next.run(req).await;
+    if let Err(e) = record(
+        &audit.state.pool,
+        &actor_id,
+        &action,
+        &target,
+        response.status().as_u16(),
+        ip.as_deref(),
+    )
+    .await
+    {
+        tracing::error!(action, actor_id, error = %e, "Failed to write audit log");
+    }
+    response
+}
+
+pub fn parse_time(value: Option<&str>, field: &str) -> Result<Option<DateTime<Utc>>, MetaResponse> {
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| MetaResponse {
+                    code: StatusCode::BAD_REQUEST.to_i32(),
+                    message: format!("{} must be an RFC 3339 timestamp", field),
+                })
+        })
+        .transpose()
+}
+
+pub async fn audit_log_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuditQuery>,
+) -> Result<AuditResponse, MetaResponse> {
+    let from = parse_time(params.from.as_deref(), "from")?;
+    let to = parse_time(params.to.as_deref(), "to")?;
+    let data = search(
+        &state.pool,
+        params.actor_id.as_deref(),
+        params.action.as_deref(),
+        from,
+        to,
+        params.page,
+    )
+    .await
+    .map_err(|e| MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    })?;
+    Ok(AuditResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        page: params.page.max(1),
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests_audit {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        audit::handler::record,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::{hash_password, random_name},
+        },
+        routes::routes,
+    };
+
+    async fn user(state: &AppState, admin: bool) -> (User, String) {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name, email, hash))
+            .await
+            .unwrap();
+        if admin {
+            set_role(&user.user_id, ADMIN_ROLE, &state.pool)
+                .await
+                .unwrap();
+        }
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    #[tokio::test]
+    async fn test_delete_account_audited() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (_, admin_token) = user(&state, true).await;
+        let (deleted, token) = user(&state, false).await;
+
+        server
+            .delete("/api/v1/auth/delete-account")
+            .add_header("Authorization", &token)
+            .add_header("x-forwarded-for", "203.0.113.7")
+            .await
+            .assert_status_ok();
+
+        let response = server
+            .get("/api/v1/admin/audit")
+            .add_query_param("actor_id", &deleted.user_id)
+            .add_query_param("action", "account.delete")
+            .add_query_param("from", "2020-01-01T00:00:00Z")
+            .add_header("Authorization", &admin_token)
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        let entries = body["data"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["target"], "/api/v1/auth/delete-account");
+        assert_eq!(entries[0]["status"], 200);
+        assert_eq!(entries[0]["ip"], "203.0.113.7");
+
+        let response = server
+            .get("/api/v1/admin/audit")
+            .add_query_param("actor_id", &deleted.user_id)
+            .add_query_param("to", "2020-01-01T00:00:00Z")
+            .add_header("Authorization", &admin_token)
+            .await;
+        assert!(
+            response.json::<serde_json::Value>()["data"]
+                .as_array()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denied_admin_call_audited() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (_, admin_token) = user(&state, true).await;
+        let (caller, token) = user(&state, false).await;
+
+        server
+            .get("/api/v1/admin/stats")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let response = server
+            .get("/api/v1/admin/audit")
+            .add_query_param("actor_id", &caller.user_id)
+            .add_header("Authorization", &admin_token)
+            .await;
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["data"][0]["action"], "admin.stats");
+        assert_eq!(body["data"][0]["status"], 403);
+
+        let response = server
+            .get("/api/v1/admin/audit")
+            .add_query_param("from", "yesterday")
+            .add_header("Authorization", &admin_token)
+            .await;
+        response.assert_status_bad_request();
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_append_only() {
+        let state = AppState::test().await;
+        let actor_id = random_name();
+        record(&state.pool, &actor_id, "test", "/", 200, None)
+            .await
+            .unwrap();
+
+        let update = sqlx::query("update audit_log set action = 'changed' where actor_id = $1")
+            .bind(&actor_id)
+            .execute(state.pool.as_ref())
+            .await;
+        assert!(update.is_err());
+        let delete = sqlx::query("delete from audit_log where actor_id = $1")
+            .bind(&actor_id)
+            .execute(state.pool.as_ref())
+            .await;
+        assert!(delete.is_err());
+    }
+}
@@ -3,7 +3,10 @@ use axum::{
     http::{StatusCode, request::Parts},
 };
 
-use crate::auth::jwt::Claims;
+use crate::auth::{
+    jwt::Claims,
+    util::{MetaResponse, StatusCodeExt},
+};
 
 pub struct AuthUser(pub Claims);
 
@@ -11,7 +14,7 @@ impl<S> FromRequestParts<S> for AuthUser
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = MetaResponse;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         // Allows extracting authenticated user in handler parameters
@@ -21,6 +24,9 @@ where
             .get::<Claims>()
             .cloned()
             .map(AuthUser)
-            .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized"))
+            .ok_or(MetaResponse {
+                code: StatusCode::UNAUTHORIZED.to_i32(),
+                message: "Unauthorized".to_string(),
+            })
     }
 }
@@ -2,30 +2,52 @@ use crate::{
     AppState,
     auth::{
         extractors::AuthUser,
-        jwt::{create_access_token, create_refresh_token, verify_token},
-        user::{
-            NewUser, User, UserResponse, add, delete_user, get_by_user_name, get_users,
-            update_password,
-        },
-        util::{MetaResponse, StatusCodeExt, passwords_match},
+        jwt::Claims,
+        middleware::request_token,
+        service::{AuthError, AuthService, Session},
+        user::{NewUser, User, UserFilter, UserResponse},
+        util::{MetaResponse, StatusCodeExt, client_ip},
     },
+    bot::handler::BOT_TOKEN_PREFIX,
+    extract::JsonOrForm,
+    http_cache::last_modified,
+    mail::{mailer::Email, template::MailTemplate},
+    normalize::de_name,
+    oauth::handler::OAUTH_TOKEN_PREFIX,
+    pagination::Pagination,
+    storage::handler::{delete_objects, get_keys_by_user},
+    validation::Validated,
 };
 use axum::{
-    Form,
+    Extension,
     extract::{Query, State},
-    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Json, Response},
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
-#[derive(serde::Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AuthResponse {
     pub meta: MetaResponse,
     pub data: Option<User>,
     pub access_token: Option<String>,
     pub refresh_token: Option<String>,
 }
+impl From<Session> for AuthResponse {
+    fn from(session: Session) -> Self {
+        Self {
+            meta: MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: String::from("Success"),
+            },
+            data: session.user,
+            access_token: session.access_token,
+            refresh_token: session.refresh_token,
+        }
+    }
+}
+
 impl IntoResponse for AuthResponse {
     fn into_response(self) -> Response {
         let status = StatusCode::from_u16(self.meta.code as u16).unwrap_or(StatusCode::OK);
@@ -34,14 +56,6 @@ impl IntoResponse for AuthResponse {
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct GetUsersQuery {
-    #[serde(default)]
-    pub page: i32,
-    #[serde(default)]
-    pub user_name: Option<String>,
-}
-
 #[derive(Debug, Serialize)]
 pub struct UsersResponse {
     pub meta: MetaResponse,
@@ -50,90 +64,79 @@ pub struct UsersResponse {
 impl IntoResponse for UsersResponse {
     fn into_response(self) -> Response {
         let status = StatusCode::from_u16(self.meta.code as u16).unwrap_or(StatusCode::OK);
-        (status, Json(self)).into_response()
+        let last_modified = last_modified(
+            self.data
+                .data
+                .iter()
+                .map(|user| user.updated_at.as_deref().or(user.created_at.as_deref())),
+        );
+        let mut response = (status, Json(self)).into_response();
+        if let Some(value) = last_modified {
+            response.headers_mut().insert(header::LAST_MODIFIED, value);
+        }
+        response
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginParam {
+    #[serde(deserialize_with = "de_name")]
     pub user_name: String,
     pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshParam {
+    pub refresh_token: String,
+}
+
 pub async fn register_handler(
     State(state): State<Arc<AppState>>,
-    Form(req): Form<NewUser>,
+    Validated(req): Validated<NewUser>,
 ) -> Result<AuthResponse, MetaResponse> {
-    let sql = "select user_name from users where user_name = $1";
-    let existing = sqlx::query(sql)
-        .bind(req.user_name.clone())
-        .fetch_optional(state.pool.as_ref())
-        .await;
-
-    if let Ok(Some(_)) = existing {
-        MetaResponse {
-            code: StatusCode::BAD_REQUEST.to_i32(),
-            message: "User name already registered".to_string(),
-        };
-    }
-
-    let result = add(&state.pool, req).await.map_err(|e| MetaResponse {
-        code: StatusCode::BAD_REQUEST.to_i32(),
-        message: format!("Failed to register: {}", e.to_string()),
-    })?;
-
-    let access_token = create_access_token(&state.jwt_config, &result.user_id, &result.email).ok();
-    let refresh_token =
-        create_refresh_token(&state.jwt_config, &result.user_id, &result.email).ok();
-
-    Ok(AuthResponse {
-        meta: MetaResponse {
-            code: StatusCode::OK.to_i32(),
-            message: String::from("Success"),
-        },
-        data: Some(result),
-        access_token: access_token,
-        refresh_token: refresh_token,
-    })
+    let session = AuthService::new(&state).register(req).await?;
+    Ok(session.into())
 }
 
 pub async fn login_handler(
     State(state): State<Arc<AppState>>,
-    Form(req): Form<LoginParam>,
+    headers: HeaderMap,
+    JsonOrForm(req): JsonOrForm<LoginParam>,
 ) -> Result<AuthResponse, MetaResponse> {
-    let result = get_by_user_name(req.user_name, &state.pool)
-        .await
-        .map_err(|_| MetaResponse {
-            code: StatusCode::NOT_FOUND.to_i32(),
-            message: "Invalid user name or password".to_string(),
-        })?;
-
-    let is_err = passwords_match(&req.password, &result.password);
-    if let Err(_) = is_err {
-        MetaResponse {
-            code: StatusCode::NOT_FOUND.to_i32(),
-            message: "Invalid user name or password".to_string(),
-        };
+    let session = AuthService::new(&state)
+        .login(&req.user_name, &req.password)
+        .await?;
+
+    if let Some(user) = &session.user
+        && state.runtime.load().login_alerts
+    {
+        send_login_alert(&state, &user.email, &user.user_name, &headers);
     }
+    Ok(session.into())
+}
 
-    let access_token = create_access_token(&state.jwt_config, &result.user_id, &result.email).ok();
-    let refresh_token =
-        create_refresh_token(&state.jwt_config, &result.user_id, &result.email).ok();
-
-    let data = User {
-        user_id: result.user_id,
-        user_name: result.user_name,
-        email: result.email,
+/// Emails the user about the sign-in without holding up the response
+fn send_login_alert(state: &AppState, to: &str, user_name: &str, headers: &HeaderMap) {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("unknown")
     };
-    Ok(AuthResponse {
-        meta: MetaResponse {
-            code: StatusCode::OK.to_i32(),
-            message: String::from("Success"),
+    let email = Email::from_template(
+        to,
+        &MailTemplate::NewDeviceAlert {
+            user_name: user_name.to_string(),
+            device: header("user-agent").to_string(),
+            ip: client_ip(headers).unwrap_or_else(|| "unknown".to_string()),
         },
-        data: Some(data),
-        access_token: access_token,
-        refresh_token: refresh_token,
-    })
+    );
+    let mailer = state.mailer.clone();
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(email).await {
+            tracing::warn!(error = %e, "Failed to send login alert");
+        }
+    });
 }
 
 pub async fn refresh_token_handler(
@@ -141,51 +144,75 @@ pub async fn refresh_token_handler(
     headers: HeaderMap,
 ) -> Result<AuthResponse, MetaResponse> {
     let refresh_token = match headers.get("refresh-token") {
-        Some(token) => match token.to_str() {
-            Ok(v) => Ok(v.to_string()),
-            Err(_) => Err(MetaResponse {
+        Some(token) => token.to_str().map_err(|_| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "Invalid refresh token header format".to_string(),
+        })?,
+        None => return Err(AuthError::InvalidRefreshToken.into()),
+    };
+
+    let session = AuthService::new(&state).refresh(refresh_token).await?;
+    Ok(session.into())
+}
+
+/// `refresh_token_handler` with the token in the body
+pub async fn refresh_handler(
+    State(state): State<Arc<AppState>>,
+    JsonOrForm(req): JsonOrForm<RefreshParam>,
+) -> Result<AuthResponse, MetaResponse> {
+    let session = AuthService::new(&state).refresh(&req.refresh_token).await?;
+    Ok(session.into())
+}
+
+/// Revokes the bearer token, and the refresh token of a `refresh-token`
+/// header when there is one
+pub async fn logout_handler(
+    Extension(claims): Extension<Claims>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> MetaResponse {
+    let refresh_token = match headers.get("refresh-token").map(|value| value.to_str()) {
+        Some(Ok(token)) => Some(token),
+        Some(Err(_)) => {
+            return MetaResponse {
                 code: StatusCode::BAD_REQUEST.to_i32(),
                 message: "Invalid refresh token header format".to_string(),
-            }),
-        },
-        None => Err(MetaResponse {
-            code: StatusCode::BAD_REQUEST.to_i32(),
-            message: "Invalid or expired refresh token".to_string(),
-        }),
-    }?;
-
-    let mut header_map = HeaderMap::new();
-    let header_value = HeaderValue::from_str(&refresh_token).expect("Invalid header value");
-    header_map.insert(HeaderName::from_static("refresh-token"), header_value);
-
-    match verify_token(&state.jwt_config, &refresh_token) {
-        Ok(claims) => {
-            let access_token =
-                create_access_token(&state.jwt_config, &claims.user_id, &claims.email).ok();
-            Ok(AuthResponse {
-                meta: MetaResponse {
-                    code: StatusCode::OK.to_i32(),
-                    message: "Success".to_string(),
-                },
-                data: None,
-                access_token: access_token,
-                refresh_token: Some(refresh_token.to_string()),
-            })
+            };
         }
-        Err(_) => Err(MetaResponse {
+        None => None,
+    };
+    // No bearer token with `X-Debug-User`
+    let access_token = request_token(&headers);
+    // Opaque tokens aren't JWTs, writing them to `revoked_tokens` wouldn't stop them
+    if access_token.as_deref().is_some_and(|token| {
+        token.starts_with(BOT_TOKEN_PREFIX) || token.starts_with(OAUTH_TOKEN_PREFIX)
+    }) {
+        return MetaResponse {
             code: StatusCode::BAD_REQUEST.to_i32(),
-            message: "Invalid or expired refresh token".to_string(),
-        }),
+            message: "Bot and OAuth tokens can't be logged out, revoke them instead".to_string(),
+        };
+    }
+    let access = access_token.as_deref().map(|token| (token, &claims));
+    match AuthService::new(&state)
+        .logout(access, refresh_token, &claims.user_id)
+        .await
+    {
+        Ok(()) => MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: String::from("Success"),
+        },
+        Err(e) => e.into(),
     }
 }
 
 pub async fn get_users_handler(
     State(state): State<Arc<AppState>>,
-    Query(params): Query<GetUsersQuery>,
+    pagination: Pagination,
+    Query(filter): Query<UserFilter>,
 ) -> Result<UsersResponse, MetaResponse> {
-    let page = params.page;
-    let user_name = params.user_name.unwrap_or_default();
-    let result = get_users(page, &user_name, &state.pool)
+    let result = state
+        .users
+        .get_users(&pagination, &filter)
         .await
         .map_err(|e| MetaResponse {
             code: StatusCode::BAD_REQUEST.to_i32(),
@@ -209,18 +236,17 @@ pub struct UpdatePasswordParam {
 pub async fn update_password_handler(
     AuthUser(user): AuthUser,
     State(state): State<Arc<AppState>>,
-    Form(req): Form<UpdatePasswordParam>,
+    JsonOrForm(req): JsonOrForm<UpdatePasswordParam>,
 ) -> MetaResponse {
-    let result = update_password(&user.user_id, &req.password, &state.pool).await;
-    match result {
-        Ok(_) => MetaResponse {
+    match AuthService::new(&state)
+        .update_password(&user.user_id, &req.password)
+        .await
+    {
+        Ok(()) => MetaResponse {
             code: StatusCode::OK.to_i32(),
             message: String::from("Success"),
         },
-        Err(e) => MetaResponse {
-            code: StatusCode::BAD_REQUEST.to_i32(),
-            message: e.to_string(),
-        },
+        Err(e) => e.into(),
     }
 }
 
@@ -228,16 +254,19 @@ pub async fn delete_user_handler(
     AuthUser(user): AuthUser,
     State(state): State<Arc<AppState>>,
 ) -> MetaResponse {
-    let result = delete_user(&user.user_id, &state.pool).await;
-    match result {
-        Ok(_) => MetaResponse {
-            code: StatusCode::OK.to_i32(),
-            message: String::from("Success"),
-        },
-        Err(e) => MetaResponse {
-            code: StatusCode::BAD_REQUEST.to_i32(),
-            message: e.to_string(),
-        },
+    // Uploads go with the account; their rows cascade, the objects are removed here
+    let keys = get_keys_by_user(&state.pool, &user.user_id)
+        .await
+        .unwrap_or_default();
+    match AuthService::new(&state).delete_account(&user.user_id).await {
+        Ok(()) => {
+            delete_objects(&state, keys);
+            MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: String::from("Success"),
+            }
+        }
+        Err(e) => e.into(),
     }
 }
 
@@ -252,12 +281,14 @@ mod tests_user {
     use crate::{
         AppState,
         auth::{
-            handler::{LoginParam, NewUser, UpdatePasswordParam},
+            handler::{LoginParam, NewUser, RefreshParam, UpdatePasswordParam},
             util::random_name,
         },
+        error::ErrorResponse,
+        mail::mailer::{Email, Mailer},
         routes::routes,
     };
-    use std::{sync::Arc, usize};
+    use std::sync::Arc;
 
     pub async fn get_access_token(
         app: &Router,
@@ -309,23 +340,42 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_register_user() {
-        let state = Arc::new(AppState::test().await);
+        let state = AppState::isolated().await;
 
-        let app = routes(state);
+        let app = routes(state.clone());
 
         let server = TestServer::new(app).unwrap();
 
         let user_name = random_name().to_string();
         let email = format!("{}.example.@mail.com", user_name.clone());
         let body = NewUser {
-            user_name: user_name,
-            email: email,
+            user_name,
+            email,
             password: "123456".to_string(),
+            phone: None,
         };
         let response = server.post("/api/auth/register").form(&body).await;
         response.assert_status_ok();
     }
 
+    #[tokio::test]
+    async fn test_register_invalid_fields() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = NewUser {
+            user_name: "Jo".to_string(),
+            email: "not-an-email".to_string(),
+            password: "123456".to_string(),
+            phone: None,
+        };
+        let response = server.post("/api/auth/register").form(&body).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let body = response.json::<ErrorResponse>();
+        let fields: Vec<_> = body.meta.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["email", "user_name"]);
+    }
+
     #[tokio::test]
     async fn test_register_duplicate_username() {
         let state = Arc::new(AppState::test().await);
@@ -336,15 +386,90 @@ mod tests_user {
         let user_name = "Jordan".to_string();
         let email = format!("{}.example.@mail.com", user_name.clone());
         let body = NewUser {
-            user_name: user_name,
-            email: email,
+            user_name,
+            email,
             password: "123456".to_string(),
+            phone: None,
         };
 
         let response = server.post("/api/auth/register").form(&body).await;
         response.assert_status_bad_request();
     }
 
+    #[tokio::test]
+    async fn test_register_name_case_and_reserved() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = NewUser::new(
+            "JORDAN".to_string(),
+            "jordan.upper@mail.com".to_string(),
+            "123456".to_string(),
+        );
+        let response = server.post("/api/auth/register").form(&body).await;
+        response.assert_status_bad_request();
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "User name already registered"
+        );
+
+        let body = NewUser::new(
+            "Support".to_string(),
+            "support@mail.com".to_string(),
+            "123456".to_string(),
+        );
+        let response = server.post("/api/auth/register").form(&body).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let error = response.json::<ErrorResponse>();
+        assert_eq!(error.meta.errors[0].field, "user_name");
+        assert_eq!(error.meta.errors[0].code, "reserved");
+
+        let body = LoginParam {
+            user_name: "jordan".to_string(),
+            password: "123456".to_string(),
+        };
+        let response = server.post("/api/auth/login").form(&body).await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.json::<serde_json::Value>()["data"]["user_name"],
+            "Jordan"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_memory_repository() {
+        let state = Arc::new(AppState::fake().await);
+        let app = routes(state);
+        let server = TestServer::new(app.clone()).unwrap();
+
+        let body = NewUser {
+            user_name: "Jordan".to_string(),
+            email: "jordan@mail.com".to_string(),
+            password: "123456".to_string(),
+            phone: None,
+        };
+        server
+            .post("/api/auth/register")
+            .form(&body)
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/auth/register")
+            .form(&body)
+            .await
+            .assert_status_bad_request();
+
+        let (token, _) = get_access_token(&app, "Jordan", "123456").await.unwrap();
+        let response = server
+            .get("/api/users?user_name=Jor")
+            .add_header("Authorization", format!("Bearer {}", token))
+            .await;
+        response.assert_status_ok();
+        let users = response.json::<serde_json::Value>()["data"]["data"].clone();
+        assert_eq!(users.as_array().unwrap().len(), 1);
+        assert_eq!(users[0]["user_name"], "Jordan");
+    }
+
     #[tokio::test]
     async fn test_login_user() {
         let state = Arc::new(AppState::test().await);
@@ -361,6 +486,92 @@ mod tests_user {
         response.assert_status_ok();
     }
 
+    #[tokio::test]
+    async fn test_json_bodies() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = LoginParam {
+            user_name: "Jordan".to_string(),
+            password: "123456".to_string(),
+        };
+        let response = server.post("/api/auth/login").json(&body).await;
+        response.assert_status_ok();
+
+        let user_name = random_name();
+        let body = NewUser {
+            user_name: user_name.clone(),
+            email: format!("{}@mail.com", user_name),
+            password: "123456".to_string(),
+            phone: None,
+        };
+        let response = server.post("/api/auth/register").json(&body).await;
+        response.assert_status_ok();
+
+        let body = NewUser {
+            email: "not-an-email".to_string(),
+            ..body
+        };
+        let response = server.post("/api/auth/register").json(&body).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_registration_disabled() {
+        let state = AppState::test().await;
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.features.registration = false;
+        state.runtime.store(Arc::new(runtime));
+        let server = TestServer::new(routes(Arc::new(state))).unwrap();
+
+        let user_name = random_name();
+        let body = NewUser {
+            user_name: user_name.clone(),
+            email: format!("{}@mail.com", user_name),
+            password: "123456".to_string(),
+            phone: None,
+        };
+        let response = server.post("/api/auth/register").form(&body).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+
+    struct ChannelMailer(tokio::sync::mpsc::UnboundedSender<Email>);
+
+    #[async_trait::async_trait]
+    impl Mailer for ChannelMailer {
+        async fn send(&self, email: Email) -> Result<(), String> {
+            self.0.send(email).map_err(|e| e.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_alert() {
+        let mut state = AppState::test().await;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.login_alerts = true;
+        state.runtime.store(Arc::new(runtime));
+        state.mailer = Arc::new(ChannelMailer(tx));
+
+        let server = TestServer::new(routes(Arc::new(state))).unwrap();
+        let body = LoginParam {
+            user_name: "Jordan".to_string(),
+            password: "123456".to_string(),
+        };
+        let response = server
+            .post("/api/auth/login")
+            .add_header("user-agent", "test-client/1.0")
+            .add_header("x-forwarded-for", "203.0.113.7, 10.0.0.1")
+            .form(&body)
+            .await;
+        response.assert_status_ok();
+
+        let email = rx.recv().await.unwrap();
+        assert_eq!(email.subject, "New sign-in to your account");
+        assert!(email.body.contains("Device: test-client/1.0"));
+        assert!(email.body.contains("IP address: 203.0.113.7\n"));
+    }
+
     #[tokio::test]
     async fn test_refresh_token() {
         let state = Arc::new(AppState::test().await);
@@ -382,9 +593,94 @@ mod tests_user {
 
         let response = server
             .post("/api/auth/refresh-token")
-            .add_header("refresh-token", format!("{}", refresh))
+            .add_header("refresh-token", refresh)
+            .await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let state = Arc::new(AppState::test().await);
+        let app = routes(state);
+        let server = TestServer::new(app.clone()).unwrap();
+
+        let (access, refresh) = get_access_token(&app, "Jordan", "123456").await.unwrap();
+
+        let response = server
+            .post("/api/auth/refresh")
+            .json(&RefreshParam {
+                refresh_token: refresh,
+            })
             .await;
         response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        let new_access = body["access_token"].as_str().unwrap();
+        let new_refresh = body["refresh_token"].as_str().unwrap();
+
+        // The new pair works, each for its own purpose
+        server
+            .get("/api/users")
+            .add_header("Authorization", format!("Bearer {}", new_access))
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/auth/refresh")
+            .form(&RefreshParam {
+                refresh_token: new_refresh.to_string(),
+            })
+            .await
+            .assert_status_ok();
+
+        // An access token is no refresh token, and the other way around
+        let response = server
+            .post("/api/auth/refresh")
+            .json(&RefreshParam {
+                refresh_token: access,
+            })
+            .await;
+        response.assert_status_bad_request();
+        server
+            .get("/api/users")
+            .add_header("Authorization", format!("Bearer {}", new_refresh))
+            .await
+            .assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn test_logout() {
+        let state = AppState::isolated().await;
+        let app = routes((*state).clone());
+        let server = TestServer::new(app.clone()).unwrap();
+
+        let (access, refresh) = get_access_token(&app, "Jordan", "123456").await.unwrap();
+        let (other, _) = get_access_token(&app, "Jordan", "123456").await.unwrap();
+
+        let response = server
+            .post("/api/auth/logout")
+            .add_header("Authorization", format!("Bearer {}", access))
+            .add_header("refresh-token", refresh.clone())
+            .await;
+        response.assert_status_ok();
+
+        server
+            .get("/api/users")
+            .add_header("Authorization", format!("Bearer {}", access))
+            .await
+            .assert_status_unauthorized();
+        server
+            .post("/api/auth/refresh")
+            .json(&RefreshParam {
+                refresh_token: refresh,
+            })
+            .await
+            .assert_status_bad_request();
+
+        // Other sessions of the user are left alone
+        server
+            .get("/api/users")
+            .add_header("Authorization", format!("Bearer {}", other))
+            .await
+            .assert_status_ok();
     }
 
     #[tokio::test]
@@ -469,8 +765,9 @@ mod tests_user {
         let password = "123456".to_string();
         let body = NewUser {
             user_name: user_name.clone(),
-            email: email,
+            email,
             password: password.clone(),
+            phone: None,
         };
 
         let response = server.post("/api/auth/register").form(&body).await;
@@ -505,8 +802,9 @@ mod tests_user {
         let password = "123456".to_string();
         let body = NewUser {
             user_name: user_name.clone(),
-            email: email,
+            email,
             password: password.clone(),
+            phone: None,
         };
 
         let response = server.post("/api/auth/register").form(&body).await;
@@ -516,7 +814,7 @@ mod tests_user {
             .await
             .unwrap();
 
-        let param = UpdatePasswordParam { password: password };
+        let param = UpdatePasswordParam { password };
         let response = server
             .put("/api/auth/update-password")
             .add_header("Authorization", format!("Bearer {}", token))
@@ -537,8 +835,9 @@ mod tests_user {
         let password = "123456".to_string();
         let body = NewUser {
             user_name: user_name.clone(),
-            email: email,
+            email,
             password: password.clone(),
+            phone: None,
         };
 
         let response = server.post("/api/auth/register").form(&body).await;
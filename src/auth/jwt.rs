@@ -1,19 +1,11 @@
-use crate::config::connection::Configure;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode, errors::ErrorKind,
+};
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
-#[derive(Clone)]
-pub struct Secret;
-impl Secret {
-    pub fn new(env: &str) -> String {
-        let configure = Configure::build(env).expect("Failed to load environment");
-        let secret_key = configure
-            .get_string("jwt.key")
-            .expect("Failed to get jwt secret key");
-        secret_key
-    }
-}
+use crate::auth::scope::Scope;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
@@ -22,6 +14,36 @@ pub struct Claims {
     pub iat: usize, // Issued at (unnix timestamp)
     pub user_id: String,
     pub email: String,
+    /// Organization the token is scoped to, set by `create_org_access_token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    /// What a bot token may do, unset for users who may do everything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
+    /// Unset on tokens issued before the claim existed, which are refused
+    /// both as access and as refresh tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<TokenType>,
+    /// Random, so two tokens issued in the same second can be revoked apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+/// Keeps a refresh token from being used as an access token and the other
+/// way around
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
+
+impl Claims {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&scope))
+    }
 }
 
 #[derive(Clone)]
@@ -46,24 +68,58 @@ pub fn create_access_token(
     user_id: &str,
     email: &str,
 ) -> Result<String, jsonwebtoken::errors::Error> {
+    access_token(config, user_id, email, None)
+}
+
+/// Access token for the organization routes of `org_id`. The caller must
+/// have checked the membership.
+pub fn create_org_access_token(
+    config: &JwtConfig,
+    user_id: &str,
+    email: &str,
+    org_id: &str,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    access_token(config, user_id, email, Some(org_id))
+}
+
+fn access_token(
+    config: &JwtConfig,
+    user_id: &str,
+    email: &str,
+    org_id: Option<&str>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = access_claims(config, user_id, email, org_id);
+
+    encode(
+        &Header::default(), // Use default algoritme (HS256)
+        &claims,            // Token payload
+        &EncodingKey::from_secret(config.secret.as_bytes()), // Secret key
+    )
+}
+
+/// Claims an access token issued now would carry
+pub fn access_claims(
+    config: &JwtConfig,
+    user_id: &str,
+    email: &str,
+    org_id: Option<&str>,
+) -> Claims {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs() as usize;
 
-    let claims = Claims {
+    Claims {
         sub: user_id.to_string(),
         exp: now + config.access_token_expiry,
         iat: now,
         user_id: user_id.to_string(),
         email: email.to_string(),
-    };
-
-    encode(
-        &Header::default(), // Use default algoritme (HS256)
-        &claims,            // Token payload
-        &EncodingKey::from_secret(config.secret.as_bytes()), // Secret key
-    )
+        org_id: org_id.map(str::to_string),
+        scopes: None,
+        token_type: Some(TokenType::Access),
+        jti: Some(Uuid::new_v4().to_string()),
+    }
 }
 
 pub fn create_refresh_token(
@@ -82,6 +138,10 @@ pub fn create_refresh_token(
         iat: now,
         user_id: user_id.to_string(),
         email: email.to_string(),
+        org_id: None,
+        scopes: None,
+        token_type: Some(TokenType::Refresh),
+        jti: Some(Uuid::new_v4().to_string()),
     };
 
     encode(
@@ -103,3 +163,70 @@ pub fn verify_token(
 
     Ok(token_data.claims)
 }
+
+/// `verify_token` for the routes: refresh tokens are refused
+pub fn verify_access_token(
+    config: &JwtConfig,
+    token: &str,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    verify_typed(config, token, TokenType::Access)
+}
+
+/// `verify_token` for `/auth/refresh`: access tokens are refused
+pub fn verify_refresh_token(
+    config: &JwtConfig,
+    token: &str,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    verify_typed(config, token, TokenType::Refresh)
+}
+
+fn verify_typed(
+    config: &JwtConfig,
+    token: &str,
+    token_type: TokenType,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let claims = verify_token(config, token)?;
+    if claims.token_type != Some(token_type) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests_jwt {
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    use crate::auth::jwt::{
+        JwtConfig, create_access_token, create_refresh_token, verify_access_token,
+        verify_refresh_token, verify_token,
+    };
+
+    #[test]
+    fn test_token_types() {
+        let config = JwtConfig::new("secret".to_string());
+        let access = create_access_token(&config, "user-1", "jordan@mail.com").unwrap();
+        let refresh = create_refresh_token(&config, "user-1", "jordan@mail.com").unwrap();
+        assert!(verify_access_token(&config, &access).is_ok());
+        assert!(verify_refresh_token(&config, &access).is_err());
+        assert!(verify_refresh_token(&config, &refresh).is_ok());
+        assert!(verify_access_token(&config, &refresh).is_err());
+
+        // Issued before `token_type` existed: it could be either, so neither
+        let legacy = encode(
+            &Header::default(),
+            &json!({
+                "sub": "user-1",
+                "exp": u32::MAX,
+                "iat": 0,
+                "user_id": "user-1",
+                "email": "jordan@mail.com",
+            }),
+            &EncodingKey::from_secret(config.secret.as_bytes()),
+        )
+        .unwrap();
+        assert!(verify_token(&config, &legacy).is_ok());
+        assert!(verify_access_token(&config, &legacy).is_err());
+        assert!(verify_refresh_token(&config, &legacy).is_err());
+    }
+}
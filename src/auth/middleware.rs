@@ -2,43 +2,112 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Request, State},
-    http::{StatusCode, header},
+    http::{HeaderMap, Method, StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Response},
 };
 
-use crate::{app_state::AppState, auth::jwt::verify_token};
+use crate::{
+    app_state::AppState,
+    auth::{
+        jwt::{Claims, access_claims, verify_access_token},
+        scope::{Scope, required_scopes},
+        util::{MetaResponse, StatusCodeExt},
+    },
+    bot::{
+        handler::{self as bot, BOT_TOKEN_PREFIX},
+        usage,
+    },
+    oauth::handler::{self as oauth, OAUTH_TOKEN_PREFIX},
+    websocket::protocol::bearer_token,
+};
+
+/// Names a user to act as without a token, when `jwt.debug_user` is on
+pub const DEBUG_USER_HEADER: &str = "x-debug-user";
 
 pub async fn auth_middleware(
     State(state): State<Arc<AppState>>,
     mut req: Request,
     next: Next,
 ) -> Result<Response, Response> {
-    // Extract token from Authorization header
-    let token = req
+    // Dev flavor only: `X-Debug-User` stands in for a token
+    let debug_user = req
         .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|auth_header| auth_header.to_str().ok())
-        .and_then(|auth_value| {
-            if auth_value.starts_with("Bearer ") {
-                Some(auth_value[7..].to_string())
-            } else {
-                None
-            }
-        });
+        .get(DEBUG_USER_HEADER)
+        .filter(|_| state.settings.jwt.debug_user)
+        .and_then(|value| value.to_str().ok())
+        .map(|user_id| user_id.to_string());
+    if let Some(user_id) = debug_user {
+        let user = state
+            .user_cache
+            .get_user(&user_id, &state.pool)
+            .await
+            .ok_or_else(|| {
+                MetaResponse {
+                    code: StatusCode::UNAUTHORIZED.to_i32(),
+                    message: "Unknown X-Debug-User".to_string(),
+                }
+                .into_response()
+            })?;
+        let claims = access_claims(&state.jwt_config, &user.user_id, &user.email, None);
+        tracing::Span::current().record("user_id", &claims.user_id);
+        req.extensions_mut().insert(claims);
+        return Ok(next.run(req).await);
+    }
 
     // Return error if no token
-    let token = token.ok_or_else(|| {
-        (
-            StatusCode::UNAUTHORIZED,
-            "Missing or invalid Authorization header",
-        )
-            .into_response()
+    let token = request_token(req.headers()).ok_or_else(|| {
+        MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Missing or invalid Authorization header".to_string(),
+        }
+        .into_response()
     })?;
 
+    // Bot and OAuth tokens are opaque and only open the routes their scopes reach
+    let found = if token.starts_with(BOT_TOKEN_PREFIX) {
+        Some(bot::authenticate(&state.pool, &token).await)
+    } else if token.starts_with(OAUTH_TOKEN_PREFIX) {
+        Some(oauth::authenticate(&state.pool, &token).await)
+    } else {
+        None
+    };
+    if let Some(found) = found {
+        let claims = scoped_claims(&state, req.method(), req.uri().path(), found)
+            .map_err(IntoResponse::into_response)?;
+        if token.starts_with(BOT_TOKEN_PREFIX) {
+            monthly_quota(&state, &token).await?;
+        }
+        tracing::Span::current().record("user_id", &claims.user_id);
+        req.extensions_mut().insert(claims);
+        return Ok(next.run(req).await);
+    }
+
     // Veirify token
-    let claims = verify_token(&state.jwt_config, &token)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or expired token").into_response())?;
+    let claims = verify_access_token(&state.jwt_config, &token).map_err(|_| {
+        MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Invalid or expired token".to_string(),
+        }
+        .into_response()
+    })?;
+    let revoked = state.revoked_tokens.is_revoked(&token).await.map_err(|e| {
+        MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        }
+        .into_response()
+    })?;
+    if revoked {
+        return Err(MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Token has been revoked".to_string(),
+        }
+        .into_response());
+    }
+
+    // Attach the caller to the request span
+    tracing::Span::current().record("user_id", &claims.user_id);
 
     // Add claims to request extensions
     req.extensions_mut().insert(claims);
@@ -46,3 +115,173 @@ pub async fn auth_middleware(
     // Continue to handler
     Ok(next.run(req).await)
 }
+
+/// Token from the Authorization header, or a bearer.<token> subprotocol
+pub fn request_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|auth_header| auth_header.to_str().ok())
+        .and_then(|auth_value| auth_value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+        .or_else(|| bearer_token(headers))
+}
+
+/// Claims of a live scoped token, refused on routes its scopes don't reach
+fn scoped_claims(
+    state: &AppState,
+    method: &Method,
+    path: &str,
+    found: Result<Option<(String, String, Vec<Scope>)>, sqlx::Error>,
+) -> Result<Claims, MetaResponse> {
+    let (user_id, email, scopes) = found
+        .map_err(|e| MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Invalid or expired token".to_string(),
+        })?;
+    let required = required_scopes(method, path).ok_or_else(|| MetaResponse {
+        code: StatusCode::FORBIDDEN.to_i32(),
+        message: "Scoped tokens can't use this endpoint".to_string(),
+    })?;
+    if let Some(missing) = required.iter().find(|scope| !scopes.contains(scope)) {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: format!("Token lacks the {} scope", missing.as_str()),
+        });
+    }
+    let mut claims = access_claims(&state.jwt_config, &user_id, &email, None);
+    claims.scopes = Some(scopes);
+    Ok(claims)
+}
+
+/// Counts a bot token's request, `429` once its monthly quota is used up
+async fn monthly_quota(state: &AppState, token: &str) -> Result<(), Response> {
+    let quota = state.runtime.load().rate_limits.bot_token_monthly_quota;
+    match usage::consume(&state.pool, token, quota).await {
+        Ok(Some(quota)) if !quota.allowed => Err((
+            quota.headers(),
+            MetaResponse {
+                code: StatusCode::TOO_MANY_REQUESTS.to_i32(),
+                message: "Monthly quota exceeded".to_string(),
+            },
+        )
+            .into_response()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        }
+        .into_response()),
+    }
+}
+
+/// Lets only users with the admin role through. Must run after `auth_middleware`.
+pub async fn admin_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, MetaResponse> {
+    let user_id = req
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| claims.user_id.clone())
+        .ok_or(MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Unauthorized".to_string(),
+        })?;
+
+    match state.users.is_admin(&user_id).await {
+        Ok(true) => Ok(next.run(req).await),
+        Ok(false) => Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Admin role required".to_string(),
+        }),
+        Err(e) => Err(MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests_auth_middleware {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+
+    use crate::{
+        app_state::AppState,
+        auth::{
+            middleware::DEBUG_USER_HEADER,
+            user::{NewUser, add},
+            util::random_name,
+        },
+        bot::handler::BOT_TOKEN_PREFIX,
+        config::settings::Settings,
+        oauth::handler::OAUTH_TOKEN_PREFIX,
+        routes::routes,
+    };
+
+    async fn server(debug_user: bool) -> (TestServer, String) {
+        let mut state = AppState::test().await;
+        let mut settings = Settings::clone(&state.settings);
+        settings.jwt.debug_user = debug_user;
+        state.settings = Arc::new(settings);
+
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name, email, "123456".to_string()),
+        )
+        .await
+        .unwrap();
+        let server = TestServer::new(routes(Arc::new(state))).unwrap();
+        (server, user.user_id)
+    }
+
+    #[tokio::test]
+    async fn test_debug_user_header() {
+        let (server, user_id) = server(true).await;
+
+        let response = server
+            .get("/api/v1/users")
+            .add_header(DEBUG_USER_HEADER, user_id)
+            .await;
+        response.assert_status_ok();
+
+        let response = server
+            .get("/api/v1/users")
+            .add_header(DEBUG_USER_HEADER, "unknown")
+            .await;
+        response.assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn test_debug_user_header_ignored_when_disabled() {
+        let (server, user_id) = server(false).await;
+
+        let response = server
+            .get("/api/v1/users")
+            .add_header(DEBUG_USER_HEADER, user_id)
+            .await;
+        response.assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn test_logout_refuses_opaque_tokens() {
+        let (server, user_id) = server(true).await;
+
+        for prefix in [BOT_TOKEN_PREFIX, OAUTH_TOKEN_PREFIX] {
+            let response = server
+                .post("/api/v1/auth/logout")
+                .add_header(DEBUG_USER_HEADER, &user_id)
+                .add_header("Authorization", format!("Bearer {}abc", prefix))
+                .await;
+            response.assert_status_bad_request();
+        }
+    }
+}
@@ -2,5 +2,10 @@ pub mod extractors;
 pub mod handler;
 pub mod jwt;
 pub mod middleware;
+pub mod otp;
+pub mod repository;
+pub mod revocation;
+pub mod scope;
+pub mod service;
 pub mod user;
 pub mod util;
@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Error, Pool, Postgres, Row};
+use validator::Validate;
+
+use crate::{
+    AppState,
+    auth::{
+        handler::AuthResponse,
+        service::{AuthError, AuthService},
+        user::validate_phone,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    sms::sender::Sms,
+    validation::Validated,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `[otp]`, one-time codes sent by SMS to sign in with a phone number
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OtpSettings {
+    /// How long a code can be used
+    pub ttl_secs: u64,
+    /// Wrong guesses before the code is thrown away
+    pub max_attempts: i32,
+    /// Requests within this long of the last code don't send a new one
+    pub resend_secs: u64,
+}
+
+impl Default for OtpSettings {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 300,
+            max_attempts: 5,
+            resend_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct OtpRequest {
+    #[validate(custom(function = "validate_phone"))]
+    pub phone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct OtpVerify {
+    #[validate(custom(function = "validate_phone"))]
+    pub phone: String,
+    pub code: String,
+}
+
+fn generate_code() -> String {
+    format!("{:06}", rand::rng().random_range(0..1_000_000))
+}
+
+/// Codes are stored keyed with the JWT secret, six digits being too few to
+/// survive a plain hash
+fn code_hash(secret: &str, phone: &str, code: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(phone.as_bytes());
+    mac.update(b":");
+    mac.update(code.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Stores a new code for the number, replacing the previous one unless that
+/// was issued less than `resend_secs` ago. False when nothing was stored.
+#[tracing::instrument(name = "db.otp_codes.issue", skip(pool, hash, settings))]
+pub async fn issue(
+    pool: &Pool<Postgres>,
+    phone: &str,
+    hash: &str,
+    settings: &OtpSettings,
+) -> Result<bool, Error> {
+    let result = sqlx::query(
+        "insert into otp_codes(phone, code_hash, expires_at) \
+         values($1, $2, current_timestamp + make_interval(secs => $3)) \
+         on conflict (phone) do update set code_hash = excluded.code_hash, attempts = 0, \
+         expires_at = excluded.expires_at, created_at = current_timestamp \
+         where otp_codes.created_at <= current_timestamp - make_interval(secs => $4)",
+    )
+    .bind(phone)
+    .bind(hash)
+    .bind(settings.ttl_secs as f64)
+    .bind(settings.resend_secs as f64)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Whether `hash` is the live code of the number. A match uses the code up,
+/// a miss counts against `max_attempts`.
+#[tracing::instrument(name = "db.otp_codes.verify", skip(pool, hash))]
+pub async fn verify(
+    pool: &Pool<Postgres>,
+    phone: &str,
+    hash: &str,
+    max_attempts: i32,
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query(
+        "select code_hash, attempts, expires_at > current_timestamp as live \
+         from otp_codes where phone = $1 for update",
+    )
+    .bind(phone)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(row) = row else {
+        return Ok(false);
+    };
+
+    let matched = row.get::<String, _>("code_hash") == hash;
+    let attempts = row.get::<i32, _>("attempts") + 1;
+    if matched || !row.get::<bool, _>("live") || attempts >= max_attempts {
+        sqlx::query("delete from otp_codes where phone = $1")
+            .bind(phone)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query("update otp_codes set attempts = $2 where phone = $1")
+            .bind(phone)
+            .bind(attempts)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(matched && row.get::<bool, _>("live"))
+}
+
+/// Texts a sign-in code to the number. The answer is the same whether or
+/// not anyone registered it, and the SMS goes out after the response.
+pub async fn request_otp_handler(
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<OtpRequest>,
+) -> MetaResponse {
+    if state.users.get_by_phone(&req.phone).await.is_ok() {
+        let code = generate_code();
+        let hash = code_hash(&state.jwt_config.secret, &req.phone, &code);
+        match issue(&state.pool, &req.phone, &hash, &state.settings.otp).await {
+            Ok(true) => {
+                let sms = Sms {
+                    to: req.phone,
+                    body: format!(
+                        "Your sign-in code is {}. It expires in {} minutes.",
+                        code,
+                        state.settings.otp.ttl_secs.div_ceil(60)
+                    ),
+                };
+                let sender = state.sms.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sender.send(sms).await {
+                        tracing::warn!(error = %e, "Failed to send sign-in code");
+                    }
+                });
+            }
+            Ok(false) => {}
+            Err(e) => tracing::error!(error = %e, "Failed to store sign-in code"),
+        }
+    }
+    MetaResponse {
+        code: StatusCode::OK.to_i32(),
+        message: String::from("Success"),
+    }
+}
+
+pub async fn verify_otp_handler(
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<OtpVerify>,
+) -> Result<AuthResponse, MetaResponse> {
+    let hash = code_hash(&state.jwt_config.secret, &req.phone, req.code.trim());
+    let valid = verify(
+        &state.pool,
+        &req.phone,
+        &hash,
+        state.settings.otp.max_attempts,
+    )
+    .await
+    .map_err(|e| AuthError::Storage(e.to_string()))?;
+    if !valid {
+        return Err(AuthError::InvalidOtp.into());
+    }
+    let session = AuthService::new(&state).phone_login(&req.phone).await?;
+    Ok(session.into())
+}
+
+#[cfg(test)]
+mod tests_otp {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+    use crate::{
+        AppState,
+        auth::{
+            otp::{OtpRequest, OtpVerify},
+            user::{NewUser, add},
+            util::random_name,
+        },
+        error::ErrorResponse,
+        routes::routes,
+        sms::sender::{Sms, SmsSender},
+    };
+
+    struct ChannelSms(UnboundedSender<Sms>);
+
+    #[async_trait::async_trait]
+    impl SmsSender for ChannelSms {
+        async fn send(&self, sms: Sms) -> Result<(), String> {
+            self.0.send(sms).map_err(|e| e.to_string())
+        }
+    }
+
+    fn random_phone() -> String {
+        format!("+62{}", rand::random_range(8_000_000_000u64..9_000_000_000))
+    }
+
+    async fn setup() -> (TestServer, Arc<AppState>, UnboundedReceiver<Sms>, String) {
+        let mut state = AppState::test().await;
+        let (tx, rx) = unbounded_channel();
+        state.sms = Arc::new(ChannelSms(tx));
+        let state = Arc::new(state);
+
+        let user_name = random_name();
+        let phone = random_phone();
+        add(
+            &state.pool,
+            NewUser {
+                phone: Some(phone.clone()),
+                ..NewUser::new(
+                    user_name.clone(),
+                    format!("{}@mail.com", user_name),
+                    "123456".to_string(),
+                )
+            },
+        )
+        .await
+        .unwrap();
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        (server, state, rx, phone)
+    }
+
+    async fn request_code(
+        server: &TestServer,
+        rx: &mut UnboundedReceiver<Sms>,
+        phone: &str,
+    ) -> String {
+        server
+            .post("/api/v1/auth/otp/request")
+            .json(&OtpRequest {
+                phone: phone.to_string(),
+            })
+            .await
+            .assert_status_ok();
+        let sms = rx.recv().await.unwrap();
+        assert_eq!(sms.to, phone);
+        sms.body
+            .split_whitespace()
+            .find(|word| word.len() == 7 && word.ends_with('.'))
+            .unwrap()
+            .trim_end_matches('.')
+            .to_string()
+    }
+
+    fn verify_body(phone: &str, code: &str) -> OtpVerify {
+        OtpVerify {
+            phone: phone.to_string(),
+            code: code.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_otp_login() {
+        let (server, _state, mut rx, phone) = setup().await;
+        let code = request_code(&server, &mut rx, &phone).await;
+
+        let response = server
+            .post("/api/v1/auth/otp/verify")
+            .json(&verify_body(&phone, &code))
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert!(body["access_token"].is_string());
+        assert!(body["refresh_token"].is_string());
+
+        // Codes are single use
+        let response = server
+            .post("/api/v1/auth/otp/verify")
+            .json(&verify_body(&phone, &code))
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Invalid or expired code"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_otp_attempts_and_expiry() {
+        let (server, state, mut rx, phone) = setup().await;
+        let code = request_code(&server, &mut rx, &phone).await;
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        for _ in 0..state.settings.otp.max_attempts {
+            server
+                .post("/api/v1/auth/otp/verify")
+                .json(&verify_body(&phone, wrong))
+                .await
+                .assert_status(StatusCode::BAD_REQUEST);
+        }
+        server
+            .post("/api/v1/auth/otp/verify")
+            .json(&verify_body(&phone, &code))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // A used up code can be replaced right away, a live one cannot
+        let code = request_code(&server, &mut rx, &phone).await;
+        server
+            .post("/api/v1/auth/otp/request")
+            .json(&OtpRequest {
+                phone: phone.clone(),
+            })
+            .await
+            .assert_status_ok();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+
+        sqlx::query("update otp_codes set expires_at = current_timestamp where phone = $1")
+            .bind(&phone)
+            .execute(&*state.pool)
+            .await
+            .unwrap();
+        server
+            .post("/api/v1/auth/otp/verify")
+            .json(&verify_body(&phone, &code))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_otp_unknown_and_invalid_phone() {
+        let (server, _state, mut rx, _phone) = setup().await;
+        server
+            .post("/api/v1/auth/otp/request")
+            .json(&OtpRequest {
+                phone: random_phone(),
+            })
+            .await
+            .assert_status_ok();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+
+        let response = server
+            .post("/api/v1/auth/otp/request")
+            .json(&OtpRequest {
+                phone: "08123456789".to_string(),
+            })
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.errors[0].code,
+            "phone"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use sqlx::{Error, Pool, Postgres};
+
+use crate::{
+    auth::user::{
+        NewUser, User, UserFilter, UserInfo, UserResponse, delete_user, get_by_phone,
+        get_by_user_name, get_users, is_admin, name_taken, register, update_password,
+    },
+    config::connection::read_with_fallback,
+    pagination::Pagination,
+};
+
+/// User storage used by the handlers. `PgUserRepository` is the real one;
+/// tests can swap in `MemoryUserRepository` through `AppState::users`.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Stores a sign-up; the Postgres repository queues `user.registered`
+    /// in the outbox with it
+    async fn add(&self, new_user: NewUser) -> Result<User, Error>;
+    async fn name_taken(&self, user_name: &str) -> Result<bool, Error>;
+    /// `Error::RowNotFound` when there is no such user
+    async fn get_by_user_name(&self, user_name: &str) -> Result<UserInfo, Error>;
+    /// `Error::RowNotFound` when no user registered the number
+    async fn get_by_phone(&self, phone: &str) -> Result<User, Error>;
+    async fn get_users(
+        &self,
+        pagination: &Pagination,
+        filter: &UserFilter,
+    ) -> Result<UserResponse, Error>;
+    async fn update_password(&self, user_id: &str, password: &str) -> Result<bool, Error>;
+    async fn delete(&self, user_id: &str) -> Result<bool, Error>;
+    async fn is_admin(&self, user_id: &str) -> Result<bool, Error>;
+}
+
+/// Writes go to the primary, listings to the replica when there is one
+pub struct PgUserRepository {
+    pool: Pool<Postgres>,
+    replica: Option<Pool<Postgres>>,
+}
+
+impl PgUserRepository {
+    pub fn new(pool: Pool<Postgres>, replica: Option<Pool<Postgres>>) -> Self {
+        Self { pool, replica }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn add(&self, new_user: NewUser) -> Result<User, Error> {
+        register(&self.pool, new_user).await
+    }
+
+    async fn name_taken(&self, user_name: &str) -> Result<bool, Error> {
+        name_taken(user_name, &self.pool).await
+    }
+
+    async fn get_by_user_name(&self, user_name: &str) -> Result<UserInfo, Error> {
+        get_by_user_name(user_name.to_string(), &self.pool).await
+    }
+
+    async fn get_by_phone(&self, phone: &str) -> Result<User, Error> {
+        get_by_phone(phone, &self.pool).await
+    }
+
+    async fn get_users(
+        &self,
+        pagination: &Pagination,
+        filter: &UserFilter,
+    ) -> Result<UserResponse, Error> {
+        read_with_fallback(&self.pool, self.replica.as_ref(), |pool| async move {
+            get_users(pagination, filter, &pool).await
+        })
+        .await
+    }
+
+    async fn update_password(&self, user_id: &str, password: &str) -> Result<bool, Error> {
+        update_password(user_id, password, &self.pool).await
+    }
+
+    async fn delete(&self, user_id: &str) -> Result<bool, Error> {
+        delete_user(user_id, &self.pool).await
+    }
+
+    async fn is_admin(&self, user_id: &str) -> Result<bool, Error> {
+        is_admin(user_id, &self.pool).await
+    }
+}
+
+#[cfg(test)]
+pub use fake::MemoryUserRepository;
+
+#[cfg(test)]
+mod fake {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use sqlx::Error;
+    use uuid::Uuid;
+
+    use crate::{
+        auth::{
+            repository::UserRepository,
+            user::{NewUser, User, UserFilter, UserInfo, UserResponse},
+            util::{hash_password, passwords_match},
+        },
+        pagination::Pagination,
+    };
+
+    /// In-memory users for handler tests that don't need Postgres. Nobody
+    /// is an admin, and the registration date filters are not applied.
+    #[derive(Default)]
+    pub struct MemoryUserRepository {
+        users: Mutex<Vec<UserInfo>>,
+        /// Phone number to user id
+        phones: Mutex<HashMap<String, String>>,
+    }
+
+    #[async_trait]
+    impl UserRepository for MemoryUserRepository {
+        async fn add(&self, new_user: NewUser) -> Result<User, Error> {
+            let mut users = self.users.lock().unwrap();
+            if users
+                .iter()
+                .any(|user| user.user_name.to_lowercase() == new_user.user_name.to_lowercase())
+            {
+                return Err(Error::Protocol("duplicate user_name".to_string()));
+            }
+            let user = UserInfo {
+                user_id: Uuid::new_v4().to_string(),
+                user_name: new_user.user_name,
+                email: new_user.email,
+                password: hash_password(new_user.password).unwrap(),
+            };
+            users.push(user.clone());
+            if let Some(phone) = new_user.phone {
+                self.phones
+                    .lock()
+                    .unwrap()
+                    .insert(phone, user.user_id.clone());
+            }
+            Ok(User {
+                user_id: user.user_id,
+                user_name: user.user_name,
+                email: user.email,
+                is_bot: false,
+                ..Default::default()
+            })
+        }
+
+        async fn name_taken(&self, user_name: &str) -> Result<bool, Error> {
+            let users = self.users.lock().unwrap();
+            Ok(users
+                .iter()
+                .any(|user| user.user_name.to_lowercase() == user_name.to_lowercase()))
+        }
+
+        async fn get_by_user_name(&self, user_name: &str) -> Result<UserInfo, Error> {
+            let users = self.users.lock().unwrap();
+            users
+                .iter()
+                .find(|user| user.user_name.to_lowercase() == user_name.to_lowercase())
+                .cloned()
+                .ok_or(Error::RowNotFound)
+        }
+
+        async fn get_by_phone(&self, phone: &str) -> Result<User, Error> {
+            let user_id = self
+                .phones
+                .lock()
+                .unwrap()
+                .get(phone)
+                .cloned()
+                .ok_or(Error::RowNotFound)?;
+            let users = self.users.lock().unwrap();
+            users
+                .iter()
+                .find(|user| user.user_id == user_id)
+                .map(|user| User {
+                    user_id: user.user_id.clone(),
+                    user_name: user.user_name.clone(),
+                    email: user.email.clone(),
+                    is_bot: false,
+                    ..Default::default()
+                })
+                .ok_or(Error::RowNotFound)
+        }
+
+        async fn get_users(
+            &self,
+            pagination: &Pagination,
+            filter: &UserFilter,
+        ) -> Result<UserResponse, Error> {
+            let user_name = filter.user_name.as_deref().unwrap_or_default();
+            let email = filter.email.as_deref().unwrap_or_default().to_lowercase();
+            let role = filter.role.as_deref().unwrap_or_default();
+            let matching: Vec<User> = self
+                .users
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|user| user.user_name.contains(user_name))
+                .filter(|user| user.email.to_lowercase().contains(&email))
+                .filter(|_| role.is_empty() || role == "user")
+                .map(|user| User {
+                    user_id: user.user_id.clone(),
+                    user_name: user.user_name.clone(),
+                    email: user.email.clone(),
+                    is_bot: false,
+                    ..Default::default()
+                })
+                .collect();
+            let users = pagination.slice(matching, |user| user.user_name.as_str());
+            Ok(UserResponse {
+                page: pagination.page,
+                per_page: pagination.per_page,
+                next_cursor: pagination.next_cursor(
+                    users.len(),
+                    users.last().map(|user| user.user_name.as_str()),
+                ),
+                data: users,
+            })
+        }
+
+        async fn update_password(&self, user_id: &str, password: &str) -> Result<bool, Error> {
+            let mut users = self.users.lock().unwrap();
+            let user = users
+                .iter_mut()
+                .find(|user| user.user_id == user_id)
+                .ok_or(Error::RowNotFound)?;
+            if passwords_match(&user.password, password).unwrap_or(false) {
+                return Err(Error::Configuration(
+                    "New password cannot be the same as the current password".into(),
+                ));
+            }
+            user.password = hash_password(password.to_string()).unwrap();
+            Ok(false)
+        }
+
+        async fn delete(&self, user_id: &str) -> Result<bool, Error> {
+            self.users
+                .lock()
+                .unwrap()
+                .retain(|user| user.user_id != user_id);
+            Ok(true)
+        }
+
+        async fn is_admin(&self, _user_id: &str) -> Result<bool, Error> {
+            Ok(false)
+        }
+    }
+}
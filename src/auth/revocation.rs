@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{Error, Pool, Postgres};
+
+use crate::auth::jwt::Claims;
+
+/// JWTs refused before they expire, see `/auth/logout`. `PgRevokedTokens` is
+/// the real store; tests can swap in `MemoryRevokedTokens` through
+/// `AppState::revoked_tokens`.
+#[async_trait]
+pub trait RevokedTokens: Send + Sync {
+    /// Refuses `token` from now on; `false` when it already was. Its row
+    /// can go once the token expires, see `retention.expired_revocation_days`.
+    async fn revoke(&self, token: &str, expires_at: DateTime<Utc>) -> Result<bool, Error>;
+    async fn is_revoked(&self, token: &str) -> Result<bool, Error>;
+}
+
+/// Tokens are stored hashed, like bot tokens
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// When the token of `claims` expires
+pub fn expires_at(claims: &Claims) -> DateTime<Utc> {
+    DateTime::from_timestamp(claims.exp as i64, 0).unwrap_or_else(Utc::now)
+}
+
+pub struct PgRevokedTokens {
+    pool: Pool<Postgres>,
+}
+
+impl PgRevokedTokens {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl RevokedTokens for PgRevokedTokens {
+    #[tracing::instrument(name = "db.revoked_tokens.revoke", skip_all)]
+    async fn revoke(&self, token: &str, expires_at: DateTime<Utc>) -> Result<bool, Error> {
+        let result = sqlx::query(
+            "insert into revoked_tokens (token_hash, expires_at) values ($1, $2) \
+             on conflict (token_hash) do nothing",
+        )
+        .bind(token_hash(token))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[tracing::instrument(name = "db.revoked_tokens.is_revoked", skip_all)]
+    async fn is_revoked(&self, token: &str) -> Result<bool, Error> {
+        sqlx::query_scalar("select exists(select 1 from revoked_tokens where token_hash = $1)")
+            .bind(token_hash(token))
+            .fetch_one(&self.pool)
+            .await
+    }
+}
+
+#[cfg(test)]
+pub use fake::MemoryRevokedTokens;
+
+#[cfg(test)]
+mod fake {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use sqlx::Error;
+
+    use crate::auth::revocation::{RevokedTokens, token_hash};
+
+    /// In-memory revocations for handler tests that don't need Postgres
+    #[derive(Default)]
+    pub struct MemoryRevokedTokens {
+        tokens: Mutex<HashMap<String, DateTime<Utc>>>,
+    }
+
+    #[async_trait]
+    impl RevokedTokens for MemoryRevokedTokens {
+        async fn revoke(&self, token: &str, expires_at: DateTime<Utc>) -> Result<bool, Error> {
+            let mut tokens = self.tokens.lock().unwrap();
+            Ok(tokens.insert(token_hash(token), expires_at).is_none())
+        }
+
+        async fn is_revoked(&self, token: &str) -> Result<bool, Error> {
+            Ok(self.tokens.lock().unwrap().contains_key(&token_hash(token)))
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+
+/// What a bot or OAuth token may do. User tokens may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// `GET /api/v1/oauth/userinfo`
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Receive messages: `/ws` and `/api/v1/events`
+    #[serde(rename = "messages:read")]
+    MessagesRead,
+    /// Send messages: `POST /api/v1/messages` and `POST /api/v1/groups/{group_id}/messages`
+    #[serde(rename = "messages:write")]
+    MessagesWrite,
+    /// `GET /api/v1/groups`
+    #[serde(rename = "groups:read")]
+    GroupsRead,
+    /// Join organization groups: list and accept invitations
+    #[serde(rename = "groups:join")]
+    GroupsJoin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProfileRead => "profile:read",
+            Scope::MessagesRead => "messages:read",
+            Scope::MessagesWrite => "messages:write",
+            Scope::GroupsRead => "groups:read",
+            Scope::GroupsJoin => "groups:join",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "profile:read" => Some(Scope::ProfileRead),
+            "messages:read" => Some(Scope::MessagesRead),
+            "messages:write" => Some(Scope::MessagesWrite),
+            "groups:read" => Some(Scope::GroupsRead),
+            "groups:join" => Some(Scope::GroupsJoin),
+            _ => None,
+        }
+    }
+}
+
+/// Scopes as stored, skipping any that are no longer known
+pub fn parse_all(values: &[String]) -> Vec<Scope> {
+    values
+        .iter()
+        .filter_map(|value| Scope::parse(value))
+        .collect()
+}
+
+/// Scopes as stored
+pub fn names(scopes: &[Scope]) -> Vec<&'static str> {
+    scopes.iter().map(Scope::as_str).collect()
+}
+
+/// Scopes a scoped token needs for a request, `None` where such tokens
+/// aren't let in. `path` is the one `auth_middleware` sees, without the
+/// `/api/v1` prefix. `/chat` and `/group-chat` both deliver and accept messages.
+pub fn required_scopes(method: &Method, path: &str) -> Option<&'static [Scope]> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["oauth", "userinfo"]) => Some(&[Scope::ProfileRead]),
+        (&Method::GET, ["ws"] | ["events"] | ["chats", _, "messages"]) => {
+            Some(&[Scope::MessagesRead])
+        }
+        (&Method::GET, ["chat"] | ["group-chat"]) => {
+            Some(&[Scope::MessagesRead, Scope::MessagesWrite])
+        }
+        (&Method::POST, ["messages"] | ["groups", _, "messages"]) => Some(&[Scope::MessagesWrite]),
+        (&Method::GET, ["groups"]) => Some(&[Scope::GroupsRead]),
+        (&Method::GET, ["invitations"]) | (&Method::POST, ["invitations", _, "accept"]) => {
+            Some(&[Scope::GroupsJoin])
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,423 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        jwt::{Claims, JwtConfig, create_access_token, create_refresh_token, verify_refresh_token},
+        repository::UserRepository,
+        revocation::{RevokedTokens, expires_at},
+        user::{NewUser, User},
+        util::{MetaResponse, StatusCodeExt, passwords_match_async},
+    },
+    cache::UserCache,
+    config::runtime::Runtime,
+};
+
+#[derive(Debug)]
+pub enum AuthError {
+    RegistrationDisabled,
+    NameTaken,
+    PhoneTaken,
+    /// The address is on one of `email_policy.blocked_domains`
+    EmailBlocked,
+    /// Unknown user name or wrong password, deliberately not told apart
+    InvalidCredentials,
+    InvalidRefreshToken,
+    /// Wrong, expired or used up SMS code, or a number nobody registered
+    InvalidOtp,
+    Storage(String),
+}
+
+impl From<AuthError> for MetaResponse {
+    fn from(error: AuthError) -> Self {
+        let (code, message) = match error {
+            AuthError::RegistrationDisabled => (
+                StatusCode::FORBIDDEN,
+                "Registration is disabled".to_string(),
+            ),
+            AuthError::NameTaken => (
+                StatusCode::BAD_REQUEST,
+                "User name already registered".to_string(),
+            ),
+            AuthError::PhoneTaken => (
+                StatusCode::BAD_REQUEST,
+                "Phone number already registered".to_string(),
+            ),
+            AuthError::EmailBlocked => (
+                StatusCode::BAD_REQUEST,
+                "Email domain is not allowed".to_string(),
+            ),
+            AuthError::InvalidCredentials => (
+                StatusCode::NOT_FOUND,
+                "Invalid user name or password".to_string(),
+            ),
+            AuthError::InvalidRefreshToken => (
+                StatusCode::BAD_REQUEST,
+                "Invalid or expired refresh token".to_string(),
+            ),
+            AuthError::InvalidOtp => (
+                StatusCode::BAD_REQUEST,
+                "Invalid or expired code".to_string(),
+            ),
+            AuthError::Storage(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        MetaResponse {
+            code: code.to_i32(),
+            message,
+        }
+    }
+}
+
+/// Tokens handed out after registering, logging in or refreshing
+#[derive(Debug)]
+pub struct Session {
+    pub user: Option<User>,
+    pub access_token: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+/// Account rules: who may register, how credentials are checked and which
+/// tokens are issued. Handlers only translate HTTP to and from these calls.
+pub struct AuthService {
+    users: Arc<dyn UserRepository>,
+    revoked_tokens: Arc<dyn RevokedTokens>,
+    jwt_config: Arc<JwtConfig>,
+    user_cache: Arc<UserCache>,
+    runtime: Runtime,
+}
+
+impl AuthService {
+    pub fn new(state: &AppState) -> Self {
+        Self {
+            users: state.users.clone(),
+            revoked_tokens: state.revoked_tokens.clone(),
+            jwt_config: state.jwt_config.clone(),
+            user_cache: state.user_cache.clone(),
+            runtime: state.runtime.clone(),
+        }
+    }
+
+    pub async fn register(&self, mut new_user: NewUser) -> Result<Session, AuthError> {
+        let runtime = self.runtime.load_full();
+        if !runtime.features.registration {
+            return Err(AuthError::RegistrationDisabled);
+        }
+        new_user.email = runtime.email_policy.normalize(&new_user.email);
+        if runtime.email_policy.blocks(&new_user.email) {
+            return Err(AuthError::EmailBlocked);
+        }
+        if let Ok(true) = self.users.name_taken(&new_user.user_name).await {
+            return Err(AuthError::NameTaken);
+        }
+        if let Some(phone) = &new_user.phone
+            && self.users.get_by_phone(phone).await.is_ok()
+        {
+            return Err(AuthError::PhoneTaken);
+        }
+
+        let user = self
+            .users
+            .add(new_user)
+            .await
+            .map_err(|e| AuthError::Storage(format!("Failed to register: {}", e)))?;
+        Ok(self.session(user))
+    }
+
+    pub async fn login(&self, user_name: &str, password: &str) -> Result<Session, AuthError> {
+        let user = self
+            .users
+            .get_by_user_name(user_name)
+            .await
+            .map_err(|_| AuthError::InvalidCredentials)?;
+        if !passwords_match_async(user.password.clone(), password.to_string())
+            .await
+            .unwrap_or(false)
+        {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        Ok(self.session(User {
+            user_id: user.user_id,
+            user_name: user.user_name,
+            email: user.email,
+            is_bot: false,
+            ..Default::default()
+        }))
+    }
+
+    /// Session for the owner of a number whose SMS code has been verified
+    pub async fn phone_login(&self, phone: &str) -> Result<Session, AuthError> {
+        let user = self
+            .users
+            .get_by_phone(phone)
+            .await
+            .map_err(|_| AuthError::InvalidOtp)?;
+        Ok(self.session(user))
+    }
+
+    /// Session for a user an identity provider vouched for, see `saml`
+    pub fn sso_login(&self, user: User) -> Session {
+        self.session(user)
+    }
+
+    /// New access and refresh token for a valid refresh token. The old one
+    /// is revoked, so a refresh token works once; presenting it again is
+    /// refused.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<Session, AuthError> {
+        let claims = self.refresh_claims(refresh_token).await?;
+        let rotated = self
+            .revoked_tokens
+            .revoke(refresh_token, expires_at(&claims))
+            .await
+            .map_err(|e| AuthError::Storage(e.to_string()))?;
+        if !rotated {
+            return Err(AuthError::InvalidRefreshToken);
+        }
+        Ok(Session {
+            user: None,
+            access_token: create_access_token(&self.jwt_config, &claims.user_id, &claims.email)
+                .ok(),
+            refresh_token: create_refresh_token(&self.jwt_config, &claims.user_id, &claims.email)
+                .ok(),
+        })
+    }
+
+    /// Revokes the access token `claims` came from and, when given, a
+    /// refresh token of the same user
+    pub async fn logout(
+        &self,
+        access: Option<(&str, &Claims)>,
+        refresh_token: Option<&str>,
+        user_id: &str,
+    ) -> Result<(), AuthError> {
+        let refresh = match refresh_token {
+            Some(token) => {
+                let claims = self.refresh_claims(token).await?;
+                if claims.user_id != user_id {
+                    return Err(AuthError::InvalidRefreshToken);
+                }
+                Some((token, claims))
+            }
+            None => None,
+        };
+        let tokens = access
+            .map(|(token, claims)| (token, expires_at(claims)))
+            .into_iter()
+            .chain(
+                refresh
+                    .as_ref()
+                    .map(|(token, claims)| (*token, expires_at(claims))),
+            );
+        for (token, until) in tokens {
+            self.revoked_tokens
+                .revoke(token, until)
+                .await
+                .map_err(|e| AuthError::Storage(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn refresh_claims(&self, refresh_token: &str) -> Result<Claims, AuthError> {
+        let claims = verify_refresh_token(&self.jwt_config, refresh_token)
+            .map_err(|_| AuthError::InvalidRefreshToken)?;
+        match self.revoked_tokens.is_revoked(refresh_token).await {
+            Ok(false) => Ok(claims),
+            Ok(true) => Err(AuthError::InvalidRefreshToken),
+            Err(e) => Err(AuthError::Storage(e.to_string())),
+        }
+    }
+
+    pub async fn update_password(&self, user_id: &str, password: &str) -> Result<(), AuthError> {
+        let result = self.users.update_password(user_id, password).await;
+        self.user_cache.invalidate(user_id).await;
+        result
+            .map(|_| ())
+            .map_err(|e| AuthError::Storage(e.to_string()))
+    }
+
+    pub async fn delete_account(&self, user_id: &str) -> Result<(), AuthError> {
+        let result = self.users.delete(user_id).await;
+        self.user_cache.invalidate(user_id).await;
+        result
+            .map(|_| ())
+            .map_err(|e| AuthError::Storage(e.to_string()))
+    }
+
+    fn session(&self, user: User) -> Session {
+        Session {
+            access_token: create_access_token(&self.jwt_config, &user.user_id, &user.email).ok(),
+            refresh_token: create_refresh_token(&self.jwt_config, &user.user_id, &user.email).ok(),
+            user: Some(user),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_auth_service {
+    use std::sync::Arc;
+
+    use crate::{
+        app_state::AppState,
+        auth::{
+            jwt::verify_access_token,
+            service::{AuthError, AuthService},
+            user::{EmailPolicy, NewUser},
+        },
+    };
+
+    fn jordan() -> NewUser {
+        NewUser::new(
+            "Jordan".to_string(),
+            "jordan@mail.com".to_string(),
+            "123456".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let state = AppState::fake().await;
+        let service = AuthService::new(&state);
+
+        let session = service.register(jordan()).await.unwrap();
+        assert!(session.access_token.is_some());
+        assert!(matches!(
+            service.register(jordan()).await,
+            Err(AuthError::NameTaken)
+        ));
+
+        assert!(service.login("Jordan", "123456").await.is_ok());
+        assert!(matches!(
+            service.login("Jordan", "wrong").await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            service.login("nobody", "123456").await,
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let state = AppState::fake().await;
+        let service = AuthService::new(&state);
+        let session = service.register(jordan()).await.unwrap();
+
+        let refreshed = service
+            .refresh(session.refresh_token.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert!(refreshed.access_token.is_some());
+
+        // The presented token was rotated out, the new one works once
+        assert!(matches!(
+            service
+                .refresh(session.refresh_token.as_deref().unwrap())
+                .await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+        let again = refreshed.refresh_token.as_deref().unwrap();
+        assert!(service.refresh(again).await.is_ok());
+        assert!(matches!(
+            service.refresh(again).await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+        assert!(matches!(
+            service.refresh("not-a-token").await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_logout() {
+        let state = AppState::fake().await;
+        let service = AuthService::new(&state);
+        let session = service.register(jordan()).await.unwrap();
+        let user_id = session.user.unwrap().user_id;
+        let access = session.access_token.unwrap();
+        let refresh = session.refresh_token.unwrap();
+        let claims = verify_access_token(&state.jwt_config, &access).unwrap();
+
+        // Someone else's refresh token is refused, and nothing is revoked
+        assert!(matches!(
+            service
+                .logout(Some((&access, &claims)), Some(&refresh), "someone-else")
+                .await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+        assert!(!state.revoked_tokens.is_revoked(&access).await.unwrap());
+
+        service
+            .logout(Some((&access, &claims)), Some(&refresh), &user_id)
+            .await
+            .unwrap();
+        assert!(state.revoked_tokens.is_revoked(&access).await.unwrap());
+        assert!(matches!(
+            service.refresh(&refresh).await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_phone_login() {
+        let state = AppState::fake().await;
+        let service = AuthService::new(&state);
+        let phone = Some("+628123456789".to_string());
+        service
+            .register(NewUser {
+                phone: phone.clone(),
+                ..jordan()
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            service
+                .register(NewUser {
+                    user_name: "Jordan2".to_string(),
+                    phone,
+                    ..jordan()
+                })
+                .await,
+            Err(AuthError::PhoneTaken)
+        ));
+
+        let session = service.phone_login("+628123456789").await.unwrap();
+        assert_eq!(session.user.unwrap().user_name, "Jordan");
+        assert!(matches!(
+            service.phone_login("+628000000000").await,
+            Err(AuthError::InvalidOtp)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_register_email_policy() {
+        let state = AppState::fake().await;
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.email_policy = EmailPolicy {
+            canonical_gmail: true,
+            blocked_domains: vec!["mailinator.com".to_string()],
+        };
+        state.runtime.store(Arc::new(runtime));
+        let service = AuthService::new(&state);
+
+        let session = service
+            .register(NewUser {
+                email: "J.Ordan+test@Gmail.com".to_string(),
+                ..jordan()
+            })
+            .await
+            .unwrap();
+        assert_eq!(session.user.unwrap().email, "jordan@gmail.com");
+        assert!(matches!(
+            service
+                .register(NewUser {
+                    user_name: "Jordan2".to_string(),
+                    email: "jordan@mailinator.com".to_string(),
+                    ..jordan()
+                })
+                .await,
+            Err(AuthError::EmailBlocked)
+        ));
+    }
+}
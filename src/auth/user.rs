@@ -1,52 +1,166 @@
-use std::borrow::Cow;
-
-use crate::auth::util::{MsgError, hash_password, passwords_match};
+use async_graphql::SimpleObject;
+
+use crate::{
+    auth::util::{MsgError, hash_password_async, passwords_match_async, utc_rfc3339},
+    event_bus::DomainEvent,
+    normalize::{de_name, de_text},
+    outbox::enqueue,
+    pagination::Pagination,
+};
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Json, Response},
 };
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use sqlx::{Error, Pool, Postgres, QueryBuilder, Row, postgres::PgRow};
 use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
+/// Names nobody can register, whatever their case
+pub const RESERVED_NAMES: &[&str] = &[
+    "abuse",
+    "admin",
+    "administrator",
+    "anonymous",
+    "api",
+    "everyone",
+    "help",
+    "hostmaster",
+    "moderator",
+    "noreply",
+    "null",
+    "postmaster",
+    "root",
+    "security",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+    "webmaster",
+];
+
+/// Registration form. Whether the name is taken is checked by the handler.
 #[derive(Debug, Serialize, Deserialize, Validate, Clone)]
-#[validate(context = UserContext,
-schema(
-    function="unique_name",
-    skip_on_field_errors=false,
-    code="username",
-    use_context,
-))]
 pub struct NewUser {
-    #[validate(length(min = 6, max = 30, code = "username"))]
+    #[validate(
+        length(
+            min = 6,
+            max = 30,
+            code = "username",
+            message = "must be between 6 and 30 characters"
+        ),
+        custom(function = "validate_not_reserved")
+    )]
+    #[serde(deserialize_with = "de_name")]
     pub user_name: String,
-    #[validate(email)]
+    #[serde(deserialize_with = "de_text")]
+    #[validate(email(message = "must be a valid email address"))]
     pub email: String,
     pub password: String,
+    /// Lets the user sign in with a code sent by SMS, see `auth::otp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(custom(function = "validate_phone"))]
+    pub phone: Option<String>,
 }
 
 impl NewUser {
     pub fn new(user_name: String, email: String, password: String) -> NewUser {
         NewUser {
-            user_name: user_name,
-            email: email,
-            password: password,
+            user_name,
+            email,
+            password,
+            phone: None,
+        }
+    }
+}
+
+/// `[email_policy]`, how addresses are stored and which are refused at
+/// registration. Re-applied when the config file changes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailPolicy {
+    /// Store Gmail addresses without the dots and `+tag` of the local part,
+    /// so `J.Doe+news@googlemail.com` and `jdoe@gmail.com` are one address
+    pub canonical_gmail: bool,
+    /// Domains nobody can register with, subdomains included
+    pub blocked_domains: Vec<String>,
+}
+
+impl EmailPolicy {
+    /// The address as stored: trimmed, lowercased and, with
+    /// `canonical_gmail`, rewritten to the plain Gmail address
+    pub fn normalize(&self, email: &str) -> String {
+        let email = email.trim().to_lowercase();
+        match email.rsplit_once('@') {
+            Some((local, "gmail.com" | "googlemail.com")) if self.canonical_gmail => {
+                let local = local.split('+').next().unwrap_or_default().replace('.', "");
+                format!("{}@gmail.com", local)
+            }
+            _ => email,
         }
     }
+
+    /// Whether a normalized address is on one of `blocked_domains`
+    pub fn blocks(&self, email: &str) -> bool {
+        let Some((_, domain)) = email.rsplit_once('@') else {
+            return false;
+        };
+        self.blocked_domains.iter().any(|blocked| {
+            let blocked = blocked.trim().trim_start_matches('.').to_lowercase();
+            domain == blocked
+                || domain
+                    .strip_suffix(&blocked)
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+pub fn validate_not_reserved(user_name: &str) -> Result<(), ValidationError> {
+    let user_name = user_name.to_lowercase();
+    if RESERVED_NAMES.contains(&user_name.as_str()) {
+        return Err(ValidationError::new("reserved").with_message("is reserved".into()));
+    }
+    Ok(())
+}
+
+/// E.164: a `+`, the country code and the subscriber number, 8 to 15 digits
+pub fn validate_phone(phone: &str) -> Result<(), ValidationError> {
+    let digits = phone.strip_prefix('+').unwrap_or_default();
+    if (8..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Ok(());
+    }
+    Err(ValidationError::new("phone")
+        .with_message("must be a phone number in international format, e.g. +628123456789".into()))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserResponse {
-    pub page: i32,
+    pub page: u32,
+    pub per_page: u32,
+    /// Pass as `cursor` to fetch the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
     pub data: Vec<User>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, SimpleObject)]
 pub struct User {
     pub user_id: String,
     pub user_name: String,
     pub email: String,
+    /// Bot account, see `bot::handler`
+    #[serde(default)]
+    pub is_bot: bool,
+    /// RFC 3339. Absent where the user comes from the cache, e.g. chat payloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// Last change to the account row, absent until the first one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
 }
 
 impl IntoResponse for UserResponse {
@@ -71,47 +185,82 @@ impl IntoResponse for UserInfo {
     }
 }
 
-pub struct UserContext {
-    pub user_name: String,
+#[tracing::instrument(name = "db.users.add", skip_all)]
+pub async fn add(pg: &Pool<Postgres>, new_user: NewUser) -> Result<User, Error> {
+    insert(pg, new_user, false).await
 }
 
-fn unique_name(user: &NewUser, context: &UserContext) -> Result<(), ValidationError> {
-    if user.user_name == context.user_name {
-        return Err(
-            ValidationError::new("username").with_message(Cow::from(format!(
-                "cannot register using user name {}, user name already exists",
-                user.user_name,
-            ))),
-        );
-    }
-
-    Ok(())
+/// `add` for sign-ups: `user.registered` is queued in the outbox by the same
+/// transaction, so the user is never stored without it being announced
+#[tracing::instrument(name = "db.users.register", skip_all)]
+pub async fn register(pg: &Pool<Postgres>, new_user: NewUser) -> Result<User, Error> {
+    insert(pg, new_user, true).await
 }
 
-pub async fn add(pg: &Pool<Postgres>, new_user: NewUser) -> Result<User, Error> {
+async fn insert(pg: &Pool<Postgres>, new_user: NewUser, announce: bool) -> Result<User, Error> {
+    // Hash before taking a connection, it's the slow part
+    let hash = hash_password_async(new_user.password.clone())
+        .await
+        .map_err(|e| Error::Encode(e.to_string().into()))?;
+
     let mut tx = pg.begin().await?;
 
-    let script = "insert into users(user_id, user_name, email, password) values($1, $2, $3, $4)";
+    let script = "insert into users(user_id, user_name, email, password, phone) \
+         values($1, $2, $3, $4, $5) returning created_at";
     let uid = Uuid::new_v4();
 
-    let hash = hash_password(new_user.password.clone()).unwrap();
-
-    sqlx::query(script)
+    let created_at: NaiveDateTime = sqlx::query_scalar(script)
         .bind(uid.to_string().clone())
         .bind(new_user.user_name.clone())
         .bind(new_user.email.clone())
         .bind(hash)
-        .execute(&mut *tx)
+        .bind(new_user.phone)
+        .fetch_one(&mut *tx)
         .await?;
 
-    tx.commit().await?;
-    Ok(User {
+    let user = User {
         user_id: uid.to_string(),
         user_name: new_user.user_name,
         email: new_user.email,
-    })
+        is_bot: false,
+        created_at: Some(utc_rfc3339(created_at)),
+        updated_at: None,
+    };
+    if announce {
+        enqueue(&mut tx, &DomainEvent::UserRegistered { user: user.clone() }).await?;
+    }
+    tx.commit().await?;
+    Ok(user)
+}
+
+#[tracing::instrument(name = "db.users.name_taken", skip(pool))]
+pub async fn name_taken(user_name: &str, pool: &Pool<Postgres>) -> Result<bool, Error> {
+    let existing: Option<String> =
+        sqlx::query_scalar("select user_name from users where lower(user_name) = lower($1)")
+            .bind(user_name)
+            .fetch_optional(pool)
+            .await?;
+    Ok(existing.is_some())
+}
+
+/// `Error::RowNotFound` when no user registered the number
+#[tracing::instrument(name = "db.users.get_by_phone", skip(pool))]
+pub async fn get_by_phone(phone: &str, pool: &Pool<Postgres>) -> Result<User, Error> {
+    sqlx::query("select user_id, user_name, email, is_bot from users where phone = $1")
+        .bind(phone)
+        .map(|data: PgRow| User {
+            user_id: data.get("user_id"),
+            user_name: data.get("user_name"),
+            email: data.get("email"),
+            is_bot: data.get("is_bot"),
+            ..Default::default()
+        })
+        .fetch_optional(pool)
+        .await?
+        .ok_or(Error::RowNotFound)
 }
 
+#[tracing::instrument(name = "db.users.get_by_user_id", skip(pool))]
 pub async fn get_by_user_id(user_id: String, pool: &Pool<Postgres>) -> Result<NewUser, Error> {
     let result = sqlx::query("select user_name, email, password from users where user_id = $1")
         .bind(user_id.to_string())
@@ -119,6 +268,7 @@ pub async fn get_by_user_id(user_id: String, pool: &Pool<Postgres>) -> Result<Ne
             user_name: data.get("user_name"),
             email: data.get("email"),
             password: data.get("password"),
+            phone: None,
         })
         .fetch_optional(pool)
         .await?;
@@ -138,18 +288,21 @@ async fn new_password(
         .await
         .map_err(|e| MsgError(format!("Failed to get user: {}", e)))?;
 
-    let match_password = passwords_match(&user.password, new_pwd)
+    let match_password = passwords_match_async(user.password, new_pwd.to_string())
+        .await
         .map_err(|e| MsgError(format!("Failed to compare passwords: {}", e)))?;
     if match_password {
-        let msg = format!("New password cannot be the same as the current password");
+        let msg = "New password cannot be the same as the current password".to_string();
         return Err(MsgError(msg));
     }
 
-    let pwd = hash_password(new_pwd.to_string())
+    let pwd = hash_password_async(new_pwd.to_string())
+        .await
         .map_err(|e| MsgError(format!("Failed to hash password: {}", e)))?;
     Ok((pwd, match_password))
 }
 
+#[tracing::instrument(name = "db.users.update_password", skip(new_pwd, pool))]
 pub async fn update_password(
     user_id: &str,
     new_pwd: &str,
@@ -171,43 +324,111 @@ pub async fn update_password(
     Ok(pwd.1)
 }
 
+/// Optional filters of the user listing; unset or empty ones match everyone
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct UserFilter {
+    /// Part of the user name
+    pub user_name: Option<String>,
+    /// Part of the email, case-insensitive
+    pub email: Option<String>,
+    pub role: Option<String>,
+    /// Registered at or after
+    pub created_after: Option<DateTime<Utc>>,
+    /// Registered before
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl UserFilter {
+    pub fn by_name(user_name: &str) -> Self {
+        Self {
+            user_name: Some(user_name.to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Appends one `and ...` condition per filter that is set
+    pub fn push_conditions(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        if let Some(user_name) = non_empty(&self.user_name) {
+            query
+                .push(" and user_name like ")
+                .push_bind(format!("%{}%", user_name));
+        }
+        if let Some(email) = non_empty(&self.email) {
+            query
+                .push(" and email ilike ")
+                .push_bind(format!("%{}%", email));
+        }
+        if let Some(role) = non_empty(&self.role) {
+            query.push(" and role = ").push_bind(role.to_string());
+        }
+        if let Some(after) = self.created_after {
+            query
+                .push(" and created_at >= ")
+                .push_bind(after.naive_utc());
+        }
+        if let Some(before) = self.created_before {
+            query
+                .push(" and created_at < ")
+                .push_bind(before.naive_utc());
+        }
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+#[tracing::instrument(name = "db.users.get_users", skip(pool))]
 pub async fn get_users(
-    page: i32,
-    user_name: &str,
+    pagination: &Pagination,
+    filter: &UserFilter,
     pool: &Pool<Postgres>,
 ) -> Result<UserResponse, Error> {
-    let mut sql = String::from("select user_id, user_name, email from users");
-    let offset = if page > 0 { (page - 1) * 10 } else { 0 };
-    let users = if !user_name.is_empty() {
-        sql.push_str(" where user_name like $1 order by user_name desc limit 10 offset $2");
-        let result = sqlx::query(&sql)
-            .bind(format!("%{}%", user_name))
-            .bind(offset)
-            .map(|data: PgRow| User {
-                user_id: data.get("user_id"),
-                user_name: data.get("user_name"),
-                email: data.get("email"),
-            })
-            .fetch_all(pool)
-            .await?;
-
-        result
-    } else {
-        sql.push_str(" order by user_name desc limit 10 offset $1");
-        let result = sqlx::query(&sql)
-            .bind(offset)
-            .map(|data: PgRow| User {
-                user_id: data.get("user_id"),
-                user_name: data.get("user_name"),
-                email: data.get("email"),
-            })
-            .fetch_all(pool)
-            .await?;
-        result
-    };
-    Ok(UserResponse { page, data: users })
+    let mut query = QueryBuilder::new(
+        "select user_id, user_name, email, is_bot, created_at, updated_at from users where true",
+    );
+    filter.push_conditions(&mut query);
+    if let Some(cursor) = pagination.cursor.as_deref() {
+        query
+            .push(" and user_name ")
+            .push(pagination.sort.after())
+            .push(" ")
+            .push_bind(cursor);
+    }
+    query
+        .push(" order by user_name ")
+        .push(pagination.sort.sql())
+        .push(" limit ")
+        .push_bind(pagination.limit())
+        .push(" offset ")
+        .push_bind(pagination.offset());
+
+    let users = query
+        .build()
+        .map(|data: PgRow| User {
+            user_id: data.get("user_id"),
+            user_name: data.get("user_name"),
+            email: data.get("email"),
+            is_bot: data.get("is_bot"),
+            created_at: Some(utc_rfc3339(data.get("created_at"))),
+            updated_at: data
+                .get::<Option<NaiveDateTime>, _>("updated_at")
+                .map(utc_rfc3339),
+        })
+        .fetch_all(pool)
+        .await?;
+    Ok(UserResponse {
+        page: pagination.page,
+        per_page: pagination.per_page,
+        next_cursor: pagination.next_cursor(
+            users.len(),
+            users.last().map(|user| user.user_name.as_str()),
+        ),
+        data: users,
+    })
 }
 
+#[tracing::instrument(name = "db.users.delete", skip(pool))]
 pub async fn delete_user(user_id: &str, pool: &Pool<Postgres>) -> Result<bool, Error> {
     let sql = "delete from users where user_id = $1";
     let mut tx = pool.begin().await?;
@@ -217,18 +438,53 @@ pub async fn delete_user(user_id: &str, pool: &Pool<Postgres>) -> Result<bool, E
     Ok(true)
 }
 
+/// Value of `users.role` that grants access to `/admin` endpoints
+pub const ADMIN_ROLE: &str = "admin";
+
+#[tracing::instrument(name = "db.users.is_admin", skip(pool))]
+pub async fn is_admin(user_id: &str, pool: &Pool<Postgres>) -> Result<bool, Error> {
+    let role: Option<String> = sqlx::query_scalar("select role from users where user_id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(role.as_deref() == Some(ADMIN_ROLE))
+}
+
+/// Addresses of every admin, who also act as moderators
+#[tracing::instrument(name = "db.users.admin_emails", skip(pool))]
+pub async fn admin_emails(pool: &Pool<Postgres>) -> Result<Vec<String>, Error> {
+    sqlx::query_scalar("select email from users where role = $1")
+        .bind(ADMIN_ROLE)
+        .fetch_all(pool)
+        .await
+}
+
+#[cfg(test)]
+#[tracing::instrument(name = "db.users.set_role", skip(pool))]
+pub async fn set_role(user_id: &str, role: &str, pool: &Pool<Postgres>) -> Result<bool, Error> {
+    let result = sqlx::query("update users set role = $1 where user_id = $2")
+        .bind(role)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Names match whatever their case, as registration keeps them unique that way
+#[tracing::instrument(name = "db.users.get_by_user_name", skip(pool))]
 pub async fn get_by_user_name(user_name: String, pool: &Pool<Postgres>) -> Result<UserInfo, Error> {
-    let result =
-        sqlx::query("select user_id, user_name, email, password from users where user_name = $1")
-            .bind(user_name.to_string())
-            .map(|data: PgRow| UserInfo {
-                user_id: data.get("user_id"),
-                user_name: data.get("user_name"),
-                email: data.get("email"),
-                password: data.get("password"),
-            })
-            .fetch_optional(pool)
-            .await?;
+    let result = sqlx::query(
+        "select user_id, user_name, email, password from users where lower(user_name) = lower($1)",
+    )
+    .bind(user_name.to_string())
+    .map(|data: PgRow| UserInfo {
+        user_id: data.get("user_id"),
+        user_name: data.get("user_name"),
+        email: data.get("email"),
+        password: data.get("password"),
+    })
+    .fetch_optional(pool)
+    .await?;
 
     match result {
         Some(user) => Ok(user),
@@ -238,16 +494,21 @@ pub async fn get_by_user_name(user_name: String, pool: &Pool<Postgres>) -> Resul
 
 #[cfg(test)]
 mod tests_user {
-    use crate::auth::user::{NewUser, add, delete_user, get_users, update_password};
+    use crate::app_state::{AppState, test_config};
+    use crate::auth::user::{
+        EmailPolicy, NewUser, UserFilter, add, delete_user, get_users, set_role, update_password,
+    };
     use crate::auth::util::{hash_password, random_name};
     use crate::config::connection::ConnectionBuilder;
+    use crate::pagination::{Pagination, Sort};
 
+    use chrono::{Duration, Utc};
     use sqlx::Error;
 
     #[tokio::test]
     async fn test_add_user() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
 
         let password = "12345".to_string();
         let hash_password = hash_password(password).unwrap();
@@ -264,8 +525,8 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_add_user_duplicate_user_name() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
 
         let password = "12345".to_string();
         let hash_password = hash_password(password).unwrap();
@@ -285,8 +546,8 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_update_password() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
         let password = "123456".to_string();
         let hash = hash_password(password).unwrap();
         let user_name = random_name().to_string();
@@ -308,8 +569,8 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_update_password_with_matching_password() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
 
         let password = "123456".to_string();
         let hash = hash_password(password).unwrap();
@@ -331,9 +592,9 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_get_users() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
-        let result = get_users(0, "", &pool).await;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let result = get_users(&Pagination::default(), &UserFilter::default(), &pool).await;
         assert!(result.is_ok());
         pool.close().await;
         Ok(())
@@ -341,18 +602,42 @@ mod tests_user {
 
     #[tokio::test]
     async fn test_get_users_with_name() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
-        let result = get_users(0, "J", &pool).await;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let result = get_users(&Pagination::default(), &UserFilter::by_name("J"), &pool).await;
         assert!(result.is_ok());
         pool.close().await;
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_users_after_cursor() -> Result<(), Error> {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let pagination = Pagination {
+            per_page: 5,
+            cursor: Some("J".to_string()),
+            sort: Sort::Asc,
+            ..Pagination::default()
+        };
+        let result = get_users(&pagination, &UserFilter::default(), &pool).await?;
+        assert!(result.data.len() <= 5);
+        assert!(result.data.iter().all(|user| user.user_name.as_str() > "J"));
+        assert!(result.data.is_sorted_by(|a, b| a.user_name <= b.user_name));
+        if result.data.len() == 5 {
+            assert_eq!(
+                result.next_cursor.as_ref(),
+                result.data.last().map(|user| &user.user_name)
+            );
+        }
+        pool.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_delete_user() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
 
         let password = "123456".to_string();
         let hash = hash_password(password).unwrap();
@@ -370,4 +655,62 @@ mod tests_user {
         pool.close().await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_get_users_filtered() -> Result<(), Error> {
+        let state = AppState::isolated().await;
+        let user_name = random_name();
+        let email = format!("{}@Filter.example", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name, email, "123456".to_string()),
+        )
+        .await?;
+        set_role(&user.user_id, "admin", &state.pool).await?;
+
+        let mut filter = UserFilter {
+            email: Some("@filter.EXAMPLE".to_string()),
+            role: Some("admin".to_string()),
+            created_after: Some(Utc::now() - Duration::days(1)),
+            created_before: Some(Utc::now() + Duration::days(1)),
+            ..UserFilter::default()
+        };
+        let result = get_users(&Pagination::default(), &filter, &state.pool).await?;
+        let ids: Vec<_> = result.data.iter().map(|user| &user.user_id).collect();
+        assert_eq!(ids, vec![&user.user_id]);
+
+        filter.role = Some("user".to_string());
+        let result = get_users(&Pagination::default(), &filter, &state.pool).await?;
+        assert!(result.data.is_empty());
+
+        filter.role = None;
+        filter.created_after = None;
+        filter.created_before = Some(Utc::now() - Duration::days(1));
+        let result = get_users(&Pagination::default(), &filter, &state.pool).await?;
+        assert!(result.data.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_policy() {
+        let mut policy = EmailPolicy {
+            canonical_gmail: false,
+            blocked_domains: vec!["Mailinator.com".to_string(), ".yopmail.com".to_string()],
+        };
+        assert_eq!(
+            policy.normalize("  J.Doe+news@GoogleMail.com "),
+            "j.doe+news@googlemail.com"
+        );
+        policy.canonical_gmail = true;
+        assert_eq!(
+            policy.normalize("  J.Doe+news@GoogleMail.com "),
+            "jdoe@gmail.com"
+        );
+        assert_eq!(policy.normalize("J.Doe+x@mail.com"), "j.doe+x@mail.com");
+
+        assert!(policy.blocks("jdoe@mailinator.com"));
+        assert!(policy.blocks("jdoe@eu.yopmail.com"));
+        assert!(!policy.blocks("jdoe@notmailinator.com"));
+        assert!(!policy.blocks("jdoe@mail.com"));
+    }
 }
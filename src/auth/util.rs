@@ -1,18 +1,25 @@
 use argon2::{
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version,
     password_hash::{Error, PasswordHasher, SaltString, rand_core::OsRng},
 };
 use axum::response::IntoResponse;
+use chrono::NaiveDateTime;
 use http::StatusCode;
-use rand::{self, Rng};
 use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+use crate::error::ErrorResponse;
 use std::{
     error::Error as fmt_error,
     fmt::{self, Display},
-    sync::Arc,
+    sync::OnceLock,
 };
 
-use crate::{app_state::AppState, auth::jwt::Secret, config::connection::ConnectionBuilder};
+/// `timestamp` columns hold UTC; responses carry them as RFC 3339
+pub fn utc_rfc3339(time: NaiveDateTime) -> String {
+    time.and_utc().to_rfc3339()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MetaResponse {
     pub code: i32,
@@ -21,14 +28,20 @@ pub struct MetaResponse {
 
 impl IntoResponse for MetaResponse {
     fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::from_u16(self.code as u16).unwrap(),
-            self.message,
-        )
-            .into_response()
+        ErrorResponse::from(self).into_response()
     }
 }
 
+/// Client address from `X-Forwarded-For`; the first hop is the client when behind a proxy
+pub fn client_ip(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+}
+
 pub trait StatusCodeExt {
     fn to_i32(&self) -> i32;
 }
@@ -39,17 +52,74 @@ impl StatusCodeExt for StatusCode {
     }
 }
 
+/// `[password]`, Argon2id cost of new hashes. Existing hashes keep
+/// verifying after a change since they carry their own parameters.
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[serde(default)]
+#[validate(schema(function = "validate_argon2"))]
+pub struct PasswordSettings {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordSettings {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordSettings {
+    fn params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+
+    /// Makes these the parameters of every hash created from now on. Only
+    /// the first call has an effect.
+    pub fn install(&self) {
+        if let Ok(params) = self.params() {
+            let _ = ARGON2_PARAMS.set(params);
+        }
+    }
+}
+
+fn validate_argon2(settings: &PasswordSettings) -> Result<(), ValidationError> {
+    settings
+        .params()
+        .map(|_| ())
+        .map_err(|e| ValidationError::new("argon2").with_message(e.to_string().into()))
+}
+
+/// Set by `PasswordSettings::install` at startup, Argon2 defaults otherwise
+static ARGON2_PARAMS: OnceLock<Params> = OnceLock::new();
+
+fn argon2() -> Argon2<'static> {
+    let params = ARGON2_PARAMS.get().cloned().unwrap_or_default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
 pub fn hash_password(pwd: String) -> Result<String, Error> {
     let number: &[u8] = pwd.as_bytes();
     let salt = SaltString::generate(OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2.hash_password(&number, &salt)?;
+    let password_hash = argon2().hash_password(number, &salt)?;
 
     Ok(password_hash.to_string())
 }
 
+/// `hash_password` on the blocking pool, so a burst of sign-ups doesn't
+/// stall the runtime's worker threads
+pub async fn hash_password_async(pwd: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || hash_password(pwd))
+        .await
+        .map_err(|_| Error::Crypto)?
+}
+
 pub fn parse_password(parse_pwd: &str) -> Result<PasswordHash<'_>, Error> {
-    let parse_hash = PasswordHash::new(&parse_pwd)?;
+    let parse_hash = PasswordHash::new(parse_pwd)?;
     if parse_hash.hash.is_none() {
         return Err(Error::Password);
     }
@@ -75,12 +145,22 @@ pub fn passwords_match(pwd: &str, new_pwd: &str) -> Result<bool, MsgError> {
     let parse_pwd = parse_password(pwd)
         .map_err(|e| MsgError(format!("Failed to parse password hash: {}", e)))?;
 
-    Ok(Argon2::default()
+    Ok(argon2()
         .verify_password(new_pwd.as_bytes(), &parse_pwd)
         .is_ok())
 }
 
+/// `passwords_match` on the blocking pool, see `hash_password_async`
+pub async fn passwords_match_async(pwd: String, new_pwd: String) -> Result<bool, MsgError> {
+    tokio::task::spawn_blocking(move || passwords_match(&pwd, &new_pwd))
+        .await
+        .map_err(|e| MsgError(format!("Password check failed: {}", e)))?
+}
+
+#[cfg(test)]
 pub fn random_name() -> String {
+    use rand::Rng;
+
     let mut rng = rand::rng();
     let chars: Vec<char> = "abcdefghijklmnopqrstuvwxyz".chars().collect();
 
@@ -104,32 +184,17 @@ pub fn random_name() -> String {
         .collect()
 }
 
-impl AppState {
-    pub async fn test() -> Self {
-        let env_dev = String::from("dev.toml");
-        let builder = ConnectionBuilder(env_dev.clone());
-        let pool = ConnectionBuilder::new(&builder)
-            .await
-            .expect("Failed to connect to database");
-        let secret_key = Secret::new(&env_dev);
-        let state = Arc::new(AppState::new(pool, secret_key));
-
-        Self {
-            pool: state.pool.clone(),
-            chat: state.chat.clone(),
-            group: state.group.clone(),
-            jwt_config: state.jwt_config.clone(),
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests_util_password {
-    use crate::auth::util::{hash_password, parse_password, passwords_match, random_name};
+    use crate::auth::util::{
+        PasswordSettings, hash_password, hash_password_async, parse_password, passwords_match,
+        passwords_match_async, random_name,
+    };
     use argon2::{
         Argon2,
         password_hash::{PasswordHash, PasswordVerifier},
     };
+    use validator::Validate;
 
     #[test]
     fn test_hashing_password() {
@@ -177,7 +242,7 @@ mod tests_util_password {
         let hash = hash_password(pwd).unwrap();
         let new_pwd = "12345".to_string();
         let result = passwords_match(&hash, &new_pwd).unwrap();
-        assert_eq!(result, true);
+        assert!(result);
     }
 
     #[test]
@@ -186,7 +251,32 @@ mod tests_util_password {
         let hash = hash_password(pwd).unwrap();
         let new_pwd = "1234".to_string();
         let result = passwords_match(&hash, &new_pwd).unwrap();
-        assert_eq!(result, false);
+        assert!(!result);
+    }
+
+    #[tokio::test]
+    async fn test_async_hashing() {
+        let hash = hash_password_async("12345".to_string()).await.unwrap();
+        assert!(
+            passwords_match_async(hash.clone(), "12345".to_string())
+                .await
+                .unwrap()
+        );
+        assert!(
+            !passwords_match_async(hash, "1234".to_string())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_password_settings_validation() {
+        assert!(PasswordSettings::default().validate().is_ok());
+        let settings = PasswordSettings {
+            memory_kib: 1,
+            ..PasswordSettings::default()
+        };
+        assert!(settings.validate().is_err());
     }
 
     #[test]
@@ -0,0 +1,101 @@
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+};
+use tower_http::limit::RequestBodyLimitLayer;
+
+use crate::auth::util::{MetaResponse, StatusCodeExt};
+
+/// Login, register and password forms
+pub const AUTH_BODY_LIMIT: usize = 16 * 1024;
+/// Everything else. Upload routes should pass their own, larger limit.
+pub const DEFAULT_BODY_LIMIT: usize = 256 * 1024;
+
+/// Caps request bodies on every route of `router` at `limit` bytes.
+///
+/// Both the `Content-Length` check and the extractor limit are set, so the
+/// limit can be raised above axum's 2MB default as well as lowered. Oversized
+/// requests get a 413 `MetaResponse`.
+pub fn with_body_limit<S>(router: Router<S>, limit: usize) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router
+        .layer(DefaultBodyLimit::max(limit))
+        .layer(RequestBodyLimitLayer::new(limit))
+        .layer(middleware::map_response(
+            move |response: Response| async move {
+                if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+                    return response;
+                }
+                MetaResponse {
+                    code: StatusCode::PAYLOAD_TOO_LARGE.to_i32(),
+                    message: format!("Request body exceeds {} bytes", limit),
+                }
+                .into_response()
+            },
+        ))
+}
+
+#[cfg(test)]
+mod tests_body_limit {
+    use std::sync::Arc;
+
+    use axum::{Router, body::Bytes, routing::post};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        body_limit::{AUTH_BODY_LIMIT, with_body_limit},
+        error::ErrorResponse,
+        routes::routes,
+    };
+
+    fn app(limit: usize) -> Router {
+        with_body_limit(
+            Router::new().route("/echo", post(|body: Bytes| async move { body })),
+            limit,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_body_within_limit() {
+        let server = TestServer::new(app(16)).unwrap();
+        let response = server.post("/echo").text("small").await;
+        response.assert_status_ok();
+        response.assert_text("small");
+    }
+
+    #[tokio::test]
+    async fn test_body_over_limit() {
+        let server = TestServer::new(app(16)).unwrap();
+        let response = server.post("/echo").text("x".repeat(32)).await;
+        response.assert_status(axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.json::<ErrorResponse>();
+        assert_eq!(body.meta.message, "Request body exceeds 16 bytes");
+    }
+
+    #[tokio::test]
+    async fn test_limit_above_default() {
+        let limit = 3 * 1024 * 1024;
+        let server = TestServer::new(app(limit)).unwrap();
+        let response = server.post("/echo").text("x".repeat(limit - 1)).await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_auth_form_over_limit() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let user_name = "x".repeat(AUTH_BODY_LIMIT);
+        let response = server
+            .post("/api/auth/register")
+            .form(&[("user_name", user_name.as_str()), ("password", "123456")])
+            .await;
+        response.assert_status(axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,515 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Error, PgExecutor, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        scope::{Scope, names, parse_all},
+        user::{User, name_taken, validate_not_reserved},
+        util::{MetaResponse, StatusCodeExt},
+    },
+    validation::Validated,
+};
+
+/// Bot tokens start with this, which tells them apart from JWTs
+pub const BOT_TOKEN_PREFIX: &str = "bot_";
+/// Bots one user may own
+pub const MAX_BOTS: i64 = 10;
+/// Stored in place of a password hash, so no password ever matches
+const NO_PASSWORD: &str = "!";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotToken {
+    pub token_id: String,
+    pub bot_id: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<String>,
+    /// Only returned when the token is issued, it is stored hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+fn token_from_row(row: PgRow) -> BotToken {
+    BotToken {
+        token_id: row.get("token_id"),
+        bot_id: row.get("bot_id"),
+        scopes: parse_all(&row.get::<Vec<String>, _>("scopes")),
+        created_at: row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        last_used_at: row
+            .get::<Option<DateTime<Utc>>, _>("last_used_at")
+            .map(|time| time.to_rfc3339()),
+        token: None,
+    }
+}
+
+pub(crate) fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bot {
+    pub bot: User,
+    pub tokens: Vec<BotToken>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NewBot {
+    #[validate(
+        length(min = 6, max = 30, message = "must be between 6 and 30 characters"),
+        custom(function = "validate_not_reserved")
+    )]
+    pub user_name: String,
+    #[validate(length(min = 1, message = "must name at least one scope"))]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NewBotToken {
+    #[validate(length(min = 1, message = "must name at least one scope"))]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotResponse {
+    pub meta: MetaResponse,
+    pub data: Bot,
+}
+
+impl IntoResponse for BotResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Bot>,
+}
+
+impl IntoResponse for BotsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotTokenResponse {
+    pub meta: MetaResponse,
+    pub data: BotToken,
+}
+
+impl IntoResponse for BotTokenResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// Stores a new token for the bot and returns it with the plain token set
+#[tracing::instrument(name = "db.bot_tokens.issue", skip(executor))]
+pub async fn issue(
+    executor: impl PgExecutor<'_>,
+    bot_id: &str,
+    scopes: &[Scope],
+) -> Result<BotToken, Error> {
+    let token_id = Uuid::new_v4().to_string();
+    let token = format!(
+        "{}{}{}",
+        BOT_TOKEN_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let created_at: DateTime<Utc> = sqlx::query_scalar(
+        "insert into bot_tokens (token_id, bot_id, token_hash, scopes) \
+         values ($1, $2, $3, $4) returning created_at",
+    )
+    .bind(&token_id)
+    .bind(bot_id)
+    .bind(token_hash(&token))
+    .bind(names(scopes))
+    .fetch_one(executor)
+    .await?;
+    Ok(BotToken {
+        token_id,
+        bot_id: bot_id.to_string(),
+        scopes: scopes.to_vec(),
+        created_at: created_at.to_rfc3339(),
+        last_used_at: None,
+        token: Some(token),
+    })
+}
+
+/// Creates the bot user and its first token
+#[tracing::instrument(name = "db.users.add_bot", skip(pool))]
+pub async fn create(
+    pool: &Pool<Postgres>,
+    owner_id: &str,
+    user_name: &str,
+    scopes: &[Scope],
+) -> Result<Bot, Error> {
+    let mut tx = pool.begin().await?;
+    let bot_id = Uuid::new_v4().to_string();
+    // Bots have no mailbox, the address only fills the column
+    let email = format!("{}@bot.invalid", bot_id);
+    let sql = "insert into users (user_id, user_name, email, password, is_bot, bot_owner_id) \
+               values ($1, $2, $3, $4, true, $5)";
+    sqlx::query(sql)
+        .bind(&bot_id)
+        .bind(user_name)
+        .bind(&email)
+        .bind(NO_PASSWORD)
+        .bind(owner_id)
+        .execute(&mut *tx)
+        .await?;
+    let token = issue(&mut *tx, &bot_id, scopes).await?;
+    tx.commit().await?;
+    Ok(Bot {
+        bot: User {
+            user_id: bot_id,
+            user_name: user_name.to_string(),
+            email,
+            is_bot: true,
+            ..Default::default()
+        },
+        tokens: vec![token],
+    })
+}
+
+#[tracing::instrument(name = "db.users.bots", skip(pool))]
+pub async fn owned(pool: &Pool<Postgres>, owner_id: &str) -> Result<Vec<User>, Error> {
+    sqlx::query(
+        "select user_id, user_name, email from users \
+         where is_bot and bot_owner_id = $1 order by user_name",
+    )
+    .bind(owner_id)
+    .map(|row: PgRow| User {
+        user_id: row.get("user_id"),
+        user_name: row.get("user_name"),
+        email: row.get("email"),
+        is_bot: true,
+        ..Default::default()
+    })
+    .fetch_all(pool)
+    .await
+}
+
+/// Tokens of the bot that haven't been revoked
+#[tracing::instrument(name = "db.bot_tokens.list", skip(pool))]
+pub async fn tokens(pool: &Pool<Postgres>, bot_id: &str) -> Result<Vec<BotToken>, Error> {
+    sqlx::query(
+        "select token_id, bot_id, scopes, created_at, last_used_at from bot_tokens \
+         where bot_id = $1 and revoked_at is null order by created_at",
+    )
+    .bind(bot_id)
+    .map(token_from_row)
+    .fetch_all(pool)
+    .await
+}
+
+/// `false` when there is no such live token
+#[tracing::instrument(name = "db.bot_tokens.revoke", skip(pool))]
+pub async fn revoke(pool: &Pool<Postgres>, bot_id: &str, token_id: &str) -> Result<bool, Error> {
+    let result = sqlx::query(
+        "update bot_tokens set revoked_at = current_timestamp \
+         where bot_id = $1 and token_id = $2 and revoked_at is null",
+    )
+    .bind(bot_id)
+    .bind(token_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// The bot a live token belongs to: its id, email and the token's scopes
+#[tracing::instrument(name = "db.bot_tokens.authenticate", skip_all)]
+pub async fn authenticate(
+    pool: &Pool<Postgres>,
+    token: &str,
+) -> Result<Option<(String, String, Vec<Scope>)>, Error> {
+    sqlx::query(
+        "update bot_tokens t set last_used_at = current_timestamp from users u \
+         where u.user_id = t.bot_id and t.token_hash = $1 and t.revoked_at is null \
+         returning t.bot_id, u.email, t.scopes",
+    )
+    .bind(token_hash(token))
+    .map(|row: PgRow| {
+        (
+            row.get("bot_id"),
+            row.get("email"),
+            parse_all(&row.get::<Vec<String>, _>("scopes")),
+        )
+    })
+    .fetch_optional(pool)
+    .await
+}
+
+fn bad_request(e: Error) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+/// The caller's bot, 404 for anyone else's
+async fn owned_bot(state: &AppState, owner_id: &str, bot_id: &str) -> Result<User, MetaResponse> {
+    owned(&state.pool, owner_id)
+        .await
+        .map_err(bad_request)?
+        .into_iter()
+        .find(|bot| bot.user_id == bot_id)
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Bot not found".to_string(),
+        })
+}
+
+fn success() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::OK.to_i32(),
+        message: "Success".to_string(),
+    }
+}
+
+pub async fn create_bot_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<NewBot>,
+) -> Result<BotResponse, MetaResponse> {
+    let count = owned(&state.pool, &user.user_id)
+        .await
+        .map_err(bad_request)?
+        .len();
+    if count as i64 >= MAX_BOTS {
+        return Err(MetaResponse {
+            code: StatusCode::CONFLICT.to_i32(),
+            message: format!("At most {} bots per user", MAX_BOTS),
+        });
+    }
+    if name_taken(&req.user_name, &state.pool)
+        .await
+        .map_err(bad_request)?
+    {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "User name already registered".to_string(),
+        });
+    }
+    let bot = create(&state.pool, &user.user_id, &req.user_name, &req.scopes)
+        .await
+        .map_err(bad_request)?;
+    Ok(BotResponse {
+        meta: success(),
+        data: bot,
+    })
+}
+
+pub async fn bots_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<BotsResponse, MetaResponse> {
+    let mut data = Vec::new();
+    for bot in owned(&state.pool, &user.user_id)
+        .await
+        .map_err(bad_request)?
+    {
+        let tokens = tokens(&state.pool, &bot.user_id)
+            .await
+            .map_err(bad_request)?;
+        data.push(Bot { bot, tokens });
+    }
+    Ok(BotsResponse {
+        meta: success(),
+        data,
+    })
+}
+
+pub async fn create_bot_token_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(bot_id): Path<String>,
+    Validated(req): Validated<NewBotToken>,
+) -> Result<BotTokenResponse, MetaResponse> {
+    owned_bot(&state, &user.user_id, &bot_id).await?;
+    let token = issue(&*state.pool, &bot_id, &req.scopes)
+        .await
+        .map_err(bad_request)?;
+    Ok(BotTokenResponse {
+        meta: success(),
+        data: token,
+    })
+}
+
+pub async fn revoke_bot_token_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path((bot_id, token_id)): Path<(String, String)>,
+) -> MetaResponse {
+    if let Err(e) = owned_bot(&state, &user.user_id, &bot_id).await {
+        return e;
+    }
+    match revoke(&state.pool, &bot_id, &token_id).await {
+        Ok(true) => success(),
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Token not found".to_string(),
+        },
+        Err(e) => bad_request(e),
+    }
+}
+
+#[cfg(test)]
+mod tests_bot {
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::hash_password,
+        },
+        bot::handler::{BotResponse, BotTokenResponse, BotsResponse},
+        routes::routes,
+        websocket::event::ServerEvent,
+    };
+
+    #[tokio::test]
+    async fn test_bot_tokens() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let hash = hash_password("123456".to_string()).unwrap();
+        let owner = add(
+            &state.pool,
+            NewUser::new(
+                "botmaker".to_string(),
+                "botmaker@mail.com".to_string(),
+                hash,
+            ),
+        )
+        .await
+        .unwrap();
+        let owner_token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &owner.user_id, &owner.email).unwrap()
+        );
+        let group = state
+            .groups
+            .create("automation", "", None, &owner.user_id)
+            .await
+            .unwrap();
+
+        let bot = server
+            .post("/api/v1/bots")
+            .add_header("Authorization", &owner_token)
+            .json(&json!({"user_name": "deploybot", "scopes": ["messages:write"]}))
+            .await
+            .json::<BotResponse>()
+            .data;
+        assert!(bot.bot.is_bot);
+        let token = format!("Bearer {}", bot.tokens[0].token.clone().unwrap());
+
+        // Outside its scopes and off the bot routes a token opens nothing
+        server
+            .get("/api/v1/groups")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .get("/api/v1/users")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post("/api/v1/bots")
+            .add_header("Authorization", &token)
+            .json(&json!({"user_name": "childbot", "scopes": ["messages:write"]}))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // Logging out doesn't apply, the token keeps working below
+        let response = server
+            .post("/api/v1/auth/logout")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        // No password opens the account
+        let body = [("user_name", "deploybot"), ("password", "!")];
+        let response = server.post("/api/v1/auth/login").form(&body).await;
+        assert_ne!(response.status_code(), StatusCode::OK);
+
+        let mut group_rx = state.group.tx.subscribe();
+        server
+            .post(&format!("/api/v1/groups/{}/messages", group.group_id))
+            .add_header("Authorization", &token)
+            .json(&json!({"message": "Deployed v1.2.3"}))
+            .await
+            .assert_status_ok();
+        assert!(group_rx.recv().await.unwrap().contains("Deployed v1.2.3"));
+
+        let mut owner_rx = state.chat.subscribe(&owner.user_id).await;
+        server
+            .post("/api/v1/messages")
+            .add_header("Authorization", &token)
+            .json(&json!({"receiver_id": owner.user_id, "message": "Done"}))
+            .await
+            .assert_status_ok();
+        match serde_json::from_str(&owner_rx.recv().await.unwrap()).unwrap() {
+            ServerEvent::ChatMessage(chat) => assert!(chat.sender_user.is_bot),
+            _ => panic!("expected chat message"),
+        }
+
+        let reader = server
+            .post(&format!("/api/v1/bots/{}/tokens", bot.bot.user_id))
+            .add_header("Authorization", &owner_token)
+            .json(&json!({"scopes": ["groups:read"]}))
+            .await
+            .json::<BotTokenResponse>()
+            .data;
+        let reader_token = format!("Bearer {}", reader.token.unwrap());
+        server
+            .get("/api/v1/groups")
+            .add_header("Authorization", &reader_token)
+            .await
+            .assert_status_ok();
+
+        let bots = server
+            .get("/api/v1/bots")
+            .add_header("Authorization", &owner_token)
+            .await
+            .json::<BotsResponse>()
+            .data;
+        assert_eq!(bots.len(), 1);
+        assert_eq!(bots[0].tokens.len(), 2);
+        assert!(bots[0].tokens.iter().all(|token| token.token.is_none()));
+
+        server
+            .delete(&format!(
+                "/api/v1/bots/{}/tokens/{}",
+                bot.bot.user_id, reader.token_id
+            ))
+            .add_header("Authorization", &owner_token)
+            .await
+            .assert_status_ok();
+        server
+            .get("/api/v1/groups")
+            .add_header("Authorization", &reader_token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+}
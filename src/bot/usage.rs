@@ -0,0 +1,285 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    bot::handler::token_hash,
+    rate_limit::Quota,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub day: String,
+    pub requests: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyUsage {
+    pub token_id: String,
+    /// Calendar month (UTC) the quota counts, e.g. `2026-10`
+    pub month: String,
+    pub quota: u32,
+    pub used: i64,
+    pub remaining: i64,
+    pub resets_at: String,
+    /// Days of the month with at least one request, oldest first
+    pub days: Vec<DailyUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyUsageResponse {
+    pub meta: MetaResponse,
+    pub data: KeyUsage,
+}
+
+impl IntoResponse for KeyUsageResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+fn month_start(today: NaiveDate) -> NaiveDate {
+    today.with_day(1).unwrap_or(today)
+}
+
+/// Midnight UTC on the first of the month after `now`
+fn next_month(now: DateTime<Utc>) -> DateTime<Utc> {
+    let start = month_start(now.date_naive());
+    let next = start.checked_add_months(Months::new(1)).unwrap_or(start);
+    next.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+}
+
+/// Counts a request against the token's monthly quota. `None` for a token
+/// that doesn't exist; requests over the quota are refused and not counted.
+#[tracing::instrument(name = "db.bot_token_usage.consume", skip_all)]
+pub async fn consume(
+    pool: &Pool<Postgres>,
+    token: &str,
+    quota: u32,
+) -> Result<Option<Quota>, Error> {
+    let now = Utc::now();
+    let found = sqlx::query(
+        "select t.token_id, coalesce(sum(u.requests), 0)::bigint as used \
+         from bot_tokens t left join bot_token_usage u on u.token_id = t.token_id and u.day >= $2 \
+         where t.token_hash = $1 and t.revoked_at is null group by t.token_id",
+    )
+    .bind(token_hash(token))
+    .bind(month_start(now.date_naive()))
+    .map(|row: PgRow| (row.get::<String, _>("token_id"), row.get::<i64, _>("used")))
+    .fetch_optional(pool)
+    .await?;
+    let Some((token_id, used)) = found else {
+        return Ok(None);
+    };
+
+    let allowed = used < quota as i64;
+    if allowed {
+        sqlx::query(
+            "insert into bot_token_usage(token_id, day, requests) values ($1, $2, 1) \
+             on conflict (token_id, day) do update set requests = bot_token_usage.requests + 1",
+        )
+        .bind(&token_id)
+        .bind(now.date_naive())
+        .execute(pool)
+        .await?;
+    }
+    let used = used + allowed as i64;
+    Ok(Some(Quota {
+        limit: quota,
+        remaining: (quota as i64 - used).max(0) as u32,
+        reset: (next_month(now) - now).to_std().unwrap_or(Duration::ZERO),
+        allowed,
+    }))
+}
+
+/// This month's daily counts of a token of one of `owner_id`'s bots
+#[tracing::instrument(name = "db.bot_token_usage.daily", skip(pool))]
+pub async fn daily(
+    pool: &Pool<Postgres>,
+    owner_id: &str,
+    token_id: &str,
+) -> Result<Option<Vec<DailyUsage>>, Error> {
+    let owned = sqlx::query(
+        "select 1 from bot_tokens t join users u on u.user_id = t.bot_id \
+         where t.token_id = $1 and u.bot_owner_id = $2",
+    )
+    .bind(token_id)
+    .bind(owner_id)
+    .fetch_optional(pool)
+    .await?;
+    if owned.is_none() {
+        return Ok(None);
+    }
+    sqlx::query(
+        "select day, requests from bot_token_usage \
+         where token_id = $1 and day >= $2 order by day",
+    )
+    .bind(token_id)
+    .bind(month_start(Utc::now().date_naive()))
+    .map(|row: PgRow| DailyUsage {
+        day: row.get::<NaiveDate, _>("day").to_string(),
+        requests: row.get("requests"),
+    })
+    .fetch_all(pool)
+    .await
+    .map(Some)
+}
+
+/// Usage of one of the caller's bot tokens against the monthly quota
+pub async fn key_usage_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(token_id): Path<String>,
+) -> Result<KeyUsageResponse, MetaResponse> {
+    let days = daily(&state.pool, &user.user_id, &token_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Token not found".to_string(),
+        })?;
+    let now = Utc::now();
+    let quota = state.runtime.load().rate_limits.bot_token_monthly_quota;
+    let used = days.iter().map(|day| day.requests).sum::<i64>();
+    Ok(KeyUsageResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: KeyUsage {
+            token_id,
+            month: now.format("%Y-%m").to_string(),
+            quota,
+            used,
+            remaining: (quota as i64 - used).max(0),
+            resets_at: next_month(now).to_rfc3339(),
+            days,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests_usage {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use chrono::{TimeZone, Utc};
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::hash_password,
+        },
+        bot::{
+            handler::BotResponse,
+            usage::{KeyUsageResponse, next_month},
+        },
+        routes::routes,
+    };
+
+    #[test]
+    fn test_next_month() {
+        let now = Utc.with_ymd_and_hms(2026, 12, 31, 23, 59, 0).unwrap();
+        let next = Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(next_month(now), next);
+    }
+
+    #[tokio::test]
+    async fn test_monthly_quota() {
+        let state = AppState::isolated().await;
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.rate_limits.bot_token_monthly_quota = 2;
+        state.runtime.store(Arc::new(runtime));
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let hash = hash_password("123456".to_string()).unwrap();
+        let owner = add(
+            &state.pool,
+            NewUser::new(
+                "quotaowner".to_string(),
+                "quotaowner@mail.com".to_string(),
+                hash,
+            ),
+        )
+        .await
+        .unwrap();
+        let owner_token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &owner.user_id, &owner.email).unwrap()
+        );
+        let bot = server
+            .post("/api/v1/bots")
+            .add_header("Authorization", &owner_token)
+            .json(&json!({"user_name": "quotabot", "scopes": ["groups:read"]}))
+            .await
+            .json::<BotResponse>()
+            .data;
+        let token_id = &bot.tokens[0].token_id;
+        let token = format!("Bearer {}", bot.tokens[0].token.clone().unwrap());
+
+        for _ in 0..2 {
+            server
+                .get("/api/v1/groups")
+                .add_header("Authorization", &token)
+                .await
+                .assert_status_ok();
+        }
+        let response = server
+            .get("/api/v1/groups")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header("x-ratelimit-remaining"), "0");
+        assert!(response.maybe_header("retry-after").is_some());
+
+        let usage = server
+            .get(&format!("/api/v1/keys/{}/usage", token_id))
+            .add_header("Authorization", &owner_token)
+            .await
+            .json::<KeyUsageResponse>()
+            .data;
+        assert_eq!((usage.quota, usage.used, usage.remaining), (2, 2, 0));
+        assert_eq!(usage.days.len(), 1);
+        assert_eq!(usage.days[0].requests, 2);
+
+        // Only the bot's owner sees its usage
+        let other = add(
+            &state.pool,
+            NewUser::new(
+                "quotaother".to_string(),
+                "quotaother@mail.com".to_string(),
+                hash_password("123456".to_string()).unwrap(),
+            ),
+        )
+        .await
+        .unwrap();
+        let other_token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &other.user_id, &other.email).unwrap()
+        );
+        server
+            .get(&format!("/api/v1/keys/{}/usage", token_id))
+            .add_header("Authorization", &other_token)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{auth::user::User, websocket::handler::validate_user};
+
+/// Settings from the `[cache]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub max_capacity: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 300,
+            max_capacity: 10_000,
+        }
+    }
+}
+
+/// In-process cache of user lookups by id, in front of `validate_user`.
+/// Only found users are cached, so a newly registered user is seen at once;
+/// callers changing or deleting a user must `invalidate` it.
+pub struct UserCache {
+    users: Option<Cache<String, User>>,
+}
+
+impl UserCache {
+    pub fn new(settings: &CacheSettings) -> Self {
+        let users = settings.enabled.then(|| {
+            Cache::builder()
+                .max_capacity(settings.max_capacity)
+                .time_to_live(Duration::from_secs(settings.ttl_secs))
+                .build()
+        });
+        Self { users }
+    }
+
+    pub async fn get_user(&self, user_id: &str, pool: &Pool<Postgres>) -> Option<User> {
+        let Some(users) = &self.users else {
+            return validate_user(user_id, pool).await;
+        };
+        if let Some(user) = users.get(user_id).await {
+            return Some(user);
+        }
+        let user = validate_user(user_id, pool).await?;
+        users.insert(user_id.to_string(), user.clone()).await;
+        Some(user)
+    }
+
+    pub async fn invalidate(&self, user_id: &str) {
+        if let Some(users) = &self.users {
+            users.invalidate(user_id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_cache {
+    use crate::{
+        AppState,
+        auth::{
+            user::{NewUser, add, delete_user},
+            util::{hash_password, random_name},
+        },
+        cache::{CacheSettings, UserCache},
+    };
+
+    #[tokio::test]
+    async fn test_cached_user_until_invalidated() {
+        let state = AppState::test().await;
+        let cache = UserCache::new(&CacheSettings::default());
+
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name, email, hash))
+            .await
+            .unwrap();
+
+        let cached = cache.get_user(&user.user_id, &state.pool).await.unwrap();
+        assert_eq!(cached.user_id, user.user_id);
+
+        // Served from the cache while the row is gone...
+        delete_user(&user.user_id, &state.pool).await.unwrap();
+        assert!(cache.get_user(&user.user_id, &state.pool).await.is_some());
+
+        // ...and reloaded once invalidated
+        cache.invalidate(&user.user_id).await;
+        assert!(cache.get_user(&user.user_id, &state.pool).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache() {
+        let state = AppState::test().await;
+        let cache = UserCache::new(&CacheSettings {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(cache.get_user("unknown", &state.pool).await.is_none());
+    }
+}
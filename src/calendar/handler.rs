@@ -0,0 +1,626 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::DomainEvent,
+    organization::handler::member_role,
+    pagination::Pagination,
+    validation::Validated,
+    websocket::group::{GroupMessage, serde_msg},
+};
+
+/// `name` of the group chat messages the calendar posts
+pub const CALENDAR_SENDER: &str = "Calendar";
+
+/// Settings from the `[calendar]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CalendarSettings {
+    /// How long before an event starts its reminder is posted
+    pub reminder_mins: i32,
+    /// How often due reminders are looked for
+    pub interval_secs: u64,
+}
+
+impl Default for CalendarSettings {
+    fn default() -> Self {
+        Self {
+            reminder_mins: 15,
+            interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RsvpStatus {
+    Going,
+    Maybe,
+    No,
+}
+
+impl RsvpStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RsvpStatus::Going => "going",
+            RsvpStatus::Maybe => "maybe",
+            RsvpStatus::No => "no",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "going" => Some(RsvpStatus::Going),
+            "maybe" => Some(RsvpStatus::Maybe),
+            "no" => Some(RsvpStatus::No),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RsvpCounts {
+    pub going: i64,
+    pub maybe: i64,
+    pub no: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarEvent {
+    pub event_id: String,
+    pub group_id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// RFC 3339
+    pub starts_at: String,
+    /// RFC 3339
+    pub ends_at: String,
+    pub created_by: String,
+    pub rsvps: RsvpCounts,
+    /// The caller's answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rsvp: Option<RsvpStatus>,
+}
+
+/// Columns of `event_from_row`; `$1` is the user whose answer is returned
+const EVENT_COLUMNS: &str = "e.event_id, e.group_id, e.title, e.description, \
+    e.starts_at, e.ends_at, e.created_by, \
+    (select count(*) from event_rsvps r where r.event_id = e.event_id and r.status = 'going') as going, \
+    (select count(*) from event_rsvps r where r.event_id = e.event_id and r.status = 'maybe') as maybe, \
+    (select count(*) from event_rsvps r where r.event_id = e.event_id and r.status = 'no') as no, \
+    (select status from event_rsvps r where r.event_id = e.event_id and r.user_id = $1) as rsvp";
+
+fn event_from_row(row: PgRow) -> CalendarEvent {
+    CalendarEvent {
+        event_id: row.get("event_id"),
+        group_id: row.get("group_id"),
+        title: row.get("title"),
+        description: row.get("description"),
+        starts_at: row.get::<DateTime<Utc>, _>("starts_at").to_rfc3339(),
+        ends_at: row.get::<DateTime<Utc>, _>("ends_at").to_rfc3339(),
+        created_by: row.get("created_by"),
+        rsvps: RsvpCounts {
+            going: row.get("going"),
+            maybe: row.get("maybe"),
+            no: row.get("no"),
+        },
+        rsvp: row
+            .get::<Option<&str>, _>("rsvp")
+            .and_then(RsvpStatus::parse),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "validate_event"))]
+pub struct NewCalendarEvent {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub title: String,
+    #[validate(length(max = 2000, message = "must be at most 2000 characters"))]
+    pub description: Option<String>,
+    /// RFC 3339
+    pub starts_at: String,
+    /// RFC 3339
+    pub ends_at: String,
+}
+
+impl NewCalendarEvent {
+    fn times(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let parse = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|time| time.with_timezone(&Utc))
+        };
+        Some((parse(&self.starts_at)?, parse(&self.ends_at)?))
+    }
+}
+
+fn validate_event(event: &NewCalendarEvent) -> Result<(), ValidationError> {
+    let Some((starts_at, ends_at)) = event.times() else {
+        return Err(ValidationError::new("event_timestamps")
+            .with_message("starts_at and ends_at must be RFC 3339 timestamps".into()));
+    };
+    if starts_at <= Utc::now() {
+        return Err(ValidationError::new("event_in_past")
+            .with_message("starts_at must be in the future".into()));
+    }
+    if ends_at <= starts_at {
+        return Err(ValidationError::new("event_ends_before_start")
+            .with_message("ends_at must be after starts_at".into()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct RsvpParam {
+    pub status: RsvpStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CalendarEventResponse {
+    pub meta: MetaResponse,
+    pub data: CalendarEvent,
+}
+
+impl IntoResponse for CalendarEventResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CalendarEventsResponse {
+    pub meta: MetaResponse,
+    pub page: u32,
+    pub data: Vec<CalendarEvent>,
+}
+
+impl IntoResponse for CalendarEventsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[tracing::instrument(name = "db.group_events.create", skip(pool, event))]
+pub async fn create(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    created_by: &str,
+    event: &NewCalendarEvent,
+    starts_at: DateTime<Utc>,
+    ends_at: DateTime<Utc>,
+) -> Result<CalendarEvent, Error> {
+    let event_id = Uuid::new_v4().to_string();
+    let sql = "insert into group_events \
+               (event_id, group_id, title, description, starts_at, ends_at, created_by) \
+               values ($1, $2, $3, $4, $5, $6, $7)";
+    sqlx::query(sql)
+        .bind(&event_id)
+        .bind(group_id)
+        .bind(&event.title)
+        .bind(&event.description)
+        .bind(starts_at)
+        .bind(ends_at)
+        .bind(created_by)
+        .execute(pool)
+        .await?;
+    Ok(CalendarEvent {
+        event_id,
+        group_id: group_id.to_string(),
+        title: event.title.clone(),
+        description: event.description.clone(),
+        starts_at: starts_at.to_rfc3339(),
+        ends_at: ends_at.to_rfc3339(),
+        created_by: created_by.to_string(),
+        rsvps: RsvpCounts::default(),
+        rsvp: None,
+    })
+}
+
+#[tracing::instrument(name = "db.group_events.get", skip(pool))]
+pub async fn get(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    event_id: &str,
+    viewer: &str,
+) -> Result<Option<CalendarEvent>, Error> {
+    let sql = format!(
+        "select {} from group_events e where e.group_id = $2 and e.event_id = $3",
+        EVENT_COLUMNS
+    );
+    sqlx::query(&sql)
+        .bind(viewer)
+        .bind(group_id)
+        .bind(event_id)
+        .map(event_from_row)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Events of the group that haven't ended, soonest first
+#[tracing::instrument(name = "db.group_events.upcoming", skip(pool))]
+pub async fn upcoming(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    viewer: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<CalendarEvent>, Error> {
+    let sql = format!(
+        "select {} from group_events e \
+         where e.group_id = $2 and e.ends_at > current_timestamp \
+         order by e.starts_at, e.event_id limit $3 offset $4",
+        EVENT_COLUMNS
+    );
+    sqlx::query(&sql)
+        .bind(viewer)
+        .bind(group_id)
+        .bind(limit)
+        .bind(offset)
+        .map(event_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+/// Records the user's answer, replacing an earlier one
+#[tracing::instrument(name = "db.event_rsvps.set", skip(pool))]
+pub async fn rsvp(
+    pool: &Pool<Postgres>,
+    event_id: &str,
+    user_id: &str,
+    status: RsvpStatus,
+) -> Result<(), Error> {
+    let sql = "insert into event_rsvps (event_id, user_id, status) values ($1, $2, $3) \
+               on conflict (event_id, user_id) do update \
+               set status = excluded.status, updated_at = current_timestamp";
+    sqlx::query(sql)
+        .bind(event_id)
+        .bind(user_id)
+        .bind(status.as_str())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Marks the events starting within `reminder_mins` as reminded and returns
+/// them, so each reminder goes out once even with several instances running
+#[tracing::instrument(name = "db.group_events.due_reminders", skip(pool))]
+pub async fn due_reminders(
+    pool: &Pool<Postgres>,
+    reminder_mins: i32,
+) -> Result<Vec<CalendarEvent>, Error> {
+    let sql = format!(
+        "update group_events e set reminded_at = current_timestamp \
+         where e.reminded_at is null and e.starts_at > current_timestamp \
+         and e.starts_at <= current_timestamp + make_interval(mins => $2) \
+         returning {}",
+        EVENT_COLUMNS
+    );
+    sqlx::query(&sql)
+        .bind("")
+        .bind(reminder_mins)
+        .map(event_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+fn reminder(event: &CalendarEvent) -> GroupMessage {
+    let starts_at = DateTime::parse_from_rfc3339(&event.starts_at)
+        .map(|time| time.with_timezone(&Utc).format("%H:%M UTC").to_string())
+        .unwrap_or_else(|_| event.starts_at.clone());
+    GroupMessage {
+        id: event.group_id.clone(),
+        name: CALENDAR_SENDER.to_string(),
+        message: format!(
+            "Reminder: \"{}\" starts at {} ({} going, {} maybe)",
+            event.title, starts_at, event.rsvps.going, event.rsvps.maybe
+        ),
+    }
+}
+
+/// Posts a reminder in the group chat of every event about to start
+pub async fn remind(state: &AppState) -> Result<usize, Error> {
+    let events = due_reminders(&state.pool, state.settings.calendar.reminder_mins).await?;
+    for event in &events {
+        let message = reminder(event);
+        let _ = state.group.tx.send(serde_msg(&message));
+        state.events.publish(DomainEvent::GroupMessageCreated {
+            group_id: event.group_id.clone(),
+            message,
+        });
+    }
+    Ok(events.len())
+}
+
+/// Looks for due reminders every `calendar.interval_secs`
+pub fn spawn_reminders(state: Arc<AppState>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(state.settings.calendar.interval_secs);
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = remind(&state).await {
+                tracing::error!(error = %e, "Failed to send event reminders");
+            }
+        }
+    })
+}
+
+fn bad_request(e: Error) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+/// The calendar is open to whoever may join the group's chat
+async fn check_group(state: &AppState, user_id: &str, group_id: &str) -> Result<(), MetaResponse> {
+    let group = state
+        .groups
+        .get_by_id(group_id)
+        .await
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Group not found".to_string(),
+        })?;
+    if let Some(org_id) = &group.org_id
+        && member_role(&state.pool, org_id, user_id)
+            .await
+            .map_err(bad_request)?
+            .is_none()
+    {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Not a member of this organization".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub async fn create_event_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    Validated(req): Validated<NewCalendarEvent>,
+) -> Result<CalendarEventResponse, MetaResponse> {
+    check_group(&state, &user.user_id, &group_id).await?;
+    // Checked by validation
+    let (starts_at, ends_at) = req.times().unwrap_or_default();
+    let event = create(
+        &state.pool,
+        &group_id,
+        &user.user_id,
+        &req,
+        starts_at,
+        ends_at,
+    )
+    .await
+    .map_err(bad_request)?;
+    Ok(CalendarEventResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: event,
+    })
+}
+
+pub async fn calendar_events_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    pagination: Pagination,
+) -> Result<CalendarEventsResponse, MetaResponse> {
+    check_group(&state, &user.user_id, &group_id).await?;
+    let data = upcoming(
+        &state.pool,
+        &group_id,
+        &user.user_id,
+        pagination.limit(),
+        pagination.offset(),
+    )
+    .await
+    .map_err(bad_request)?;
+    Ok(CalendarEventsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        page: pagination.page,
+        data,
+    })
+}
+
+pub async fn rsvp_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path((group_id, event_id)): Path<(String, String)>,
+    Validated(req): Validated<RsvpParam>,
+) -> Result<CalendarEventResponse, MetaResponse> {
+    check_group(&state, &user.user_id, &group_id).await?;
+    let not_found = || MetaResponse {
+        code: StatusCode::NOT_FOUND.to_i32(),
+        message: "Event not found".to_string(),
+    };
+    get(&state.pool, &group_id, &event_id, &user.user_id)
+        .await
+        .map_err(bad_request)?
+        .ok_or_else(not_found)?;
+    rsvp(&state.pool, &event_id, &user.user_id, req.status)
+        .await
+        .map_err(bad_request)?;
+    let event = get(&state.pool, &group_id, &event_id, &user.user_id)
+        .await
+        .map_err(bad_request)?
+        .ok_or_else(not_found)?;
+    Ok(CalendarEventResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: event,
+    })
+}
+
+#[cfg(test)]
+mod tests_calendar {
+    use axum_test::TestServer;
+    use chrono::{Duration, Utc};
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, User, add},
+            util::hash_password,
+        },
+        calendar::handler::{
+            CalendarEventResponse, CalendarEventsResponse, RsvpCounts, RsvpStatus, remind,
+        },
+        routes::routes,
+    };
+
+    async fn user(state: &AppState, user_name: &str) -> (User, String) {
+        let hash = hash_password("123456".to_string()).unwrap();
+        let email = format!("{}@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name.to_string(), email, hash),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    #[tokio::test]
+    async fn test_calendar() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let (owner, owner_token) = user(&state, "organizer").await;
+        let (_, token) = user(&state, "attendee").await;
+        let group = state
+            .groups
+            .create("book club", "", None, &owner.user_id)
+            .await
+            .unwrap();
+        let path = format!("/api/v1/groups/{}/events", group.group_id);
+        let soon = Utc::now() + Duration::minutes(5);
+        let later = Utc::now() + Duration::days(2);
+
+        server
+            .post(&path)
+            .add_header("Authorization", &owner_token)
+            .json(&json!({
+                "title": "Backwards",
+                "starts_at": later.to_rfc3339(),
+                "ends_at": soon.to_rfc3339(),
+            }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .post("/api/v1/groups/missing/events")
+            .add_header("Authorization", &owner_token)
+            .json(&json!({
+                "title": "Nowhere",
+                "starts_at": soon.to_rfc3339(),
+                "ends_at": later.to_rfc3339(),
+            }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        for (title, starts_at) in [("Next month's book", later), ("Chapter one", soon)] {
+            server
+                .post(&path)
+                .add_header("Authorization", &owner_token)
+                .json(&json!({
+                    "title": title,
+                    "starts_at": starts_at.to_rfc3339(),
+                    "ends_at": (starts_at + Duration::hours(1)).to_rfc3339(),
+                }))
+                .await
+                .assert_status_ok();
+        }
+
+        let events = server
+            .get(&path)
+            .add_header("Authorization", &token)
+            .await
+            .json::<CalendarEventsResponse>()
+            .data;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].title, "Chapter one");
+        let event_id = events[0].event_id.clone();
+
+        let rsvp_path = format!("{}/{}/rsvp", path, event_id);
+        server
+            .put(&rsvp_path)
+            .add_header("Authorization", &token)
+            .json(&json!({"status": "perhaps"}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        server
+            .put(&rsvp_path)
+            .add_header("Authorization", &owner_token)
+            .json(&json!({"status": "going"}))
+            .await
+            .assert_status_ok();
+        server
+            .put(&rsvp_path)
+            .add_header("Authorization", &token)
+            .json(&json!({"status": "no"}))
+            .await
+            .assert_status_ok();
+        // Changing an answer replaces it
+        let event = server
+            .put(&rsvp_path)
+            .add_header("Authorization", &token)
+            .json(&json!({"status": "maybe"}))
+            .await
+            .json::<CalendarEventResponse>()
+            .data;
+        assert_eq!(event.rsvp, Some(RsvpStatus::Maybe));
+        assert_eq!(
+            event.rsvps,
+            RsvpCounts {
+                going: 1,
+                maybe: 1,
+                no: 0
+            }
+        );
+        server
+            .put(&format!("{}/missing/rsvp", path))
+            .add_header("Authorization", &token)
+            .json(&json!({"status": "going"}))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        // Only the event starting soon is reminded of, and only once
+        let mut rx = state.group.tx.subscribe();
+        assert_eq!(remind(&state).await.unwrap(), 1);
+        let message = rx.recv().await.unwrap();
+        assert!(message.contains("Chapter one"));
+        assert!(message.contains("1 going, 1 maybe"));
+        assert_eq!(remind(&state).await.unwrap(), 0);
+    }
+}
@@ -1,144 +1,361 @@
-use config::{Config, ConfigError, File, FileFormat};
-use sqlx::{Error, Pool, Postgres, postgres::PgPoolOptions};
-use std::{result::Result::Ok, time::Duration};
+use config::{Config, ConfigError, Environment, File, FileFormat};
+use sqlx::{
+    ConnectOptions, Error, Pool, Postgres,
+    postgres::{PgConnectOptions, PgPoolOptions},
+};
+use std::{fmt, future::Future, path::Path, result::Result::Ok, time::Duration};
 
-use crate::{
-    auth::util::MsgError,
-    config::logger::{LogMsg, Logger},
+use crate::config::{
+    flavor::layers,
+    settings::{DbSettings, ReplicaSettings},
 };
 
 #[derive(Debug)]
-pub struct DB {
-    pub user: String,
-    pub password: String,
-    pub host: String,
-    pub port: i64,
-    pub name: String,
-    pub max_connection: i64,
-    pub min_connection: i64,
-    pub acquired_timout: i64,
-    pub idle_timout: i64,
-}
+pub struct Configure;
 
-#[derive(Debug)]
-pub struct TCP {
-    pub ip: String,
-    pub port: i32,
+/// Variables prefixed with `APP_` override values from the file, with `__`
+/// between nested keys: `APP_DATABASE__PASSWORD` sets `database.password`.
+/// List values (the `cors` and `ip_filter` lists) are comma separated.
+pub fn environment() -> Environment {
+    Environment::with_prefix("APP")
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+        .list_separator(",")
+        .with_list_parse_key("cors.allowed_origins")
+        .with_list_parse_key("cors.allowed_methods")
+        .with_list_parse_key("cors.allowed_headers")
+        .with_list_parse_key("ip_filter.deny")
+        .with_list_parse_key("ip_filter.admin_allow")
 }
 
-#[derive(Debug)]
-pub struct Configure;
+/// Format of a config file, by its extension
+pub fn file_format(name: &str) -> Result<FileFormat, ConfigError> {
+    match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => Ok(FileFormat::Toml),
+        Some("yaml" | "yml") => Ok(FileFormat::Yaml),
+        Some("json") => Ok(FileFormat::Json),
+        _ => Err(ConfigError::Message(format!(
+            "{}: expected a .toml, .yaml, .yml or .json file",
+            name
+        ))),
+    }
+}
 
 impl Configure {
     pub fn build(name: &str) -> Result<Config, ConfigError> {
-        let builder = Config::builder()
-            .add_source(File::new(name, FileFormat::Toml))
-            .build();
+        Self::build_with(name, environment())
+    }
+
+    /// Merges the [`layers`] of `name`, then `env` on top
+    pub fn build_with(name: &str, env: Environment) -> Result<Config, ConfigError> {
+        let builder = layers(name)
+            .iter()
+            .try_fold(Config::builder(), |builder, layer| {
+                Ok(builder.add_source(File::new(layer, file_format(layer)?)))
+            })
+            .and_then(|builder| builder.add_source(env).build());
 
         match builder {
             Ok(build) => Ok(build),
             Err(error) => {
-                Logger::init();
-                let log = Logger;
-                let msg = format!("Failed to execute environment : {:?}", error);
-                log.err(&msg);
+                tracing::error!(error = ?error, "Failed to execute environment");
                 panic!("Failed to execute environment : {:?}", error)
             }
         }
     }
 }
 
+/// Upper bound for the delay between two connection attempts
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The database stayed unreachable for every configured attempt
 #[derive(Debug)]
-pub struct ConnectionBuilder(pub String);
+pub struct ConnectError {
+    pub attempts: u32,
+    pub source: Error,
+}
 
-impl ConnectionBuilder {
-    pub async fn new(&self) -> Result<Pool<Postgres>, Error> {
-        let con = Configure::build(&self.0).unwrap();
-
-        let db: DB = DB {
-            user: con.get_string("database.user").unwrap(),
-            name: con.get_string("database.name").unwrap(),
-            host: con.get_string("database.host").unwrap(),
-            port: con.get_int("database.port").unwrap(),
-            password: con.get_string("database.password").unwrap(),
-            max_connection: con.get_int("database.max_connection").unwrap(),
-            min_connection: con.get_int("database.min_connection").unwrap(),
-            acquired_timout: con.get_int("database.acquire_timeout").unwrap(),
-            idle_timout: con.get_int("database.idle_timeout").unwrap(),
-        };
-
-        let url = format!(
-            "postgres://{}:{}@{}:{}/{}",
-            db.user, db.password, db.host, db.port, db.name
-        );
-        let result = PgPoolOptions::new()
-            .max_connections(db.max_connection as u32)
-            .min_connections(db.min_connection as u32)
-            .acquire_timeout(Duration::from_secs(db.acquired_timout as u64))
-            .idle_timeout(Duration::from_secs(db.idle_timout as u64))
-            .connect(&url)
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to connect into database after {} attempt(s) : {}",
+            self.attempts, self.source
+        )
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Opens the Postgres pool described by `[database]`, retrying with
+/// exponential backoff and jitter while the database is unreachable.
+pub async fn connect(db: &DbSettings) -> Result<Pool<Postgres>, ConnectError> {
+    let options = connect_options(db, &db.url()).map_err(|source| ConnectError {
+        attempts: 0,
+        source,
+    })?;
+    let mut backoff = Duration::from_millis(db.connect_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        let result = pool_options(db)
+            .max_connections(db.max_connection)
+            .min_connections(db.min_connection)
+            .acquire_timeout(Duration::from_secs(db.acquire_timeout))
+            .idle_timeout(Duration::from_secs(db.idle_timeout))
+            .connect_with(options.clone())
             .await;
 
         match result {
-            Ok(v) => Ok(v),
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt < db.connect_attempts => {
+                let delay = jitter(backoff);
+                tracing::warn!(
+                    attempt,
+                    error = %e,
+                    retry_in_ms = delay.as_millis() as u64,
+                    "Database unavailable, retrying"
+                );
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
+            }
             Err(e) => {
-                Logger::init();
-                let log = Logger;
-                let msg = format!("Failed to connect into database : {:?}", e);
-                log.err(&msg);
-                panic!("Failed to connect into database : {}", e)
+                tracing::error!(attempt, error = ?e, "Failed to connect into database");
+                return Err(ConnectError {
+                    attempts: attempt,
+                    source: e,
+                });
             }
         }
     }
+}
 
-    pub fn listen_on(&self) -> Result<TCP, MsgError> {
-        let result = Configure::build(&self.0);
-        match result {
-            Ok(con) => Ok(TCP {
-                ip: con.get_string("tcp.ip").unwrap(),
-                port: con.get_int("tcp.port").unwrap() as i32,
-            }),
-            Err(e) => {
-                Logger::init();
-                let log = Logger;
-                let msg = format!("Failed to execute environment : {:?}", e);
-                log.err(&msg);
-                panic!("Failed to execute environment : {:?}", e)
+/// Creates the replica pool without connecting, so a replica that is down at
+/// startup doesn't block the server; reads fall back to the primary until it's up.
+pub fn connect_replica(
+    db: &DbSettings,
+    replica: &ReplicaSettings,
+) -> Result<Pool<Postgres>, Error> {
+    let options = connect_options(db, &db.replica_url(replica))?;
+    Ok(pool_options(db)
+        .max_connections(replica.max_connection.unwrap_or(db.max_connection))
+        .acquire_timeout(Duration::from_secs(replica.acquire_timeout))
+        .idle_timeout(Duration::from_secs(db.idle_timeout))
+        .connect_lazy_with(options))
+}
+
+/// Connection settings for `url` that warn about statements slower than
+/// `slow_query_ms`
+pub fn connect_options(db: &DbSettings, url: &str) -> Result<PgConnectOptions, Error> {
+    let (level, threshold) = db.slow_query();
+    Ok(url
+        .parse::<PgConnectOptions>()?
+        .log_slow_statements(level, threshold))
+}
+
+/// Pool settings that warn about acquires slower than `slow_query_ms`
+fn pool_options(db: &DbSettings) -> PgPoolOptions {
+    let (level, threshold) = db.slow_query();
+    PgPoolOptions::new()
+        .acquire_slow_level(level)
+        .acquire_slow_threshold(threshold)
+}
+
+/// Runs a read-only query on `replica`, or on `primary` when there is no
+/// replica or it can't be reached.
+pub async fn read_with_fallback<T, F, Fut>(
+    primary: &Pool<Postgres>,
+    replica: Option<&Pool<Postgres>>,
+    query: F,
+) -> Result<T, Error>
+where
+    F: Fn(Pool<Postgres>) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    if let Some(replica) = replica {
+        match query(replica.clone()).await {
+            Err(e) if is_unavailable(&e) => {
+                tracing::warn!(error = %e, "Replica unavailable, reading from primary");
             }
+            result => return result,
         }
     }
+    query(primary.clone()).await
+}
+
+/// True for errors meaning the server couldn't be reached, as opposed to a
+/// failing query
+pub fn is_unavailable(error: &Error) -> bool {
+    match error {
+        Error::Io(_)
+        | Error::Tls(_)
+        | Error::PoolTimedOut
+        | Error::PoolClosed
+        | Error::WorkerCrashed => true,
+        // connection_exception (08xxx) and operator_intervention (57P0x)
+        Error::Database(e) => e
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code.starts_with("57P0")),
+        _ => false,
+    }
+}
+
+/// Random delay between half and all of `backoff`, so restarting replicas
+/// don't retry in lockstep
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(rand::random::<f64>())
+}
+
+/// Loads the settings file (with its layers) and connects, for tests that
+/// only need a pool
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ConnectionBuilder(pub String);
+
+#[cfg(test)]
+impl ConnectionBuilder {
+    pub async fn connect(&self) -> Result<Pool<Postgres>, Error> {
+        let settings = crate::config::settings::Settings::load(&self.0)
+            .await
+            .unwrap_or_else(|e| panic!("Invalid configuration : {}", e));
+        connect(&settings.database).await.map_err(|e| e.source)
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::config::connection::{Configure, ConnectionBuilder, DB};
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::{
+        app_state::test_config,
+        config::{
+            connection::{Configure, ConnectionBuilder, connect, environment, file_format, jitter},
+            flavor::layers,
+            settings::Settings,
+        },
+    };
     use sqlx::Error;
 
-    #[test]
-    fn test_environment() {
-        let con = Configure::build("dev.toml").unwrap();
-
-        let db: DB = DB {
-            user: con.get_string("database.user").unwrap(),
-            name: con.get_string("database.name").unwrap(),
-            host: con.get_string("database.host").unwrap(),
-            port: con.get_int("database.port").unwrap(),
-            password: con.get_string("database.password").unwrap(),
-            max_connection: con.get_int("database.max_connection").unwrap(),
-            min_connection: con.get_int("database.min_connection").unwrap(),
-            acquired_timout: con.get_int("database.acquire_timeout").unwrap(),
-            idle_timout: con.get_int("database.idle_timeout").unwrap(),
-        };
+    #[tokio::test]
+    async fn test_environment() {
+        let db = Settings::load(&test_config()).await.unwrap().database;
 
         assert_eq!(db.user, "postgres");
         assert_eq!(db.name, "roger_db");
         assert_eq!(db.host, "localhost");
         assert_eq!(db.port, 5432);
         assert_eq!(db.max_connection, 10);
-        assert_eq!(db.min_connection, 5);
-        assert_eq!(db.acquired_timout, 5);
-        assert_eq!(db.idle_timout, 60);
+        assert_eq!(db.min_connection, 0);
+        assert_eq!(db.acquire_timeout, 5);
+        assert_eq!(db.idle_timeout, 60);
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries_then_fails() {
+        let mut db = Settings::load(&test_config()).await.unwrap().database;
+        db.port = 1;
+        db.acquire_timeout = 1;
+        db.connect_attempts = 3;
+        db.connect_backoff_ms = 10;
+
+        let error = connect(&db).await.unwrap_err();
+        assert_eq!(error.attempts, 3);
+        assert!(error.to_string().contains("after 3 attempt(s)"));
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let backoff = Duration::from_millis(1000);
+        for _ in 0..100 {
+            let delay = jitter(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+
+    #[test]
+    fn test_environment_override() {
+        let vars = HashMap::from([
+            ("APP_DATABASE__PASSWORD".to_string(), "from-env".to_string()),
+            ("APP_TCP__PORT".to_string(), "8080".to_string()),
+            (
+                "APP_CORS__ALLOWED_ORIGINS".to_string(),
+                "https://a.example.com,https://b.example.com".to_string(),
+            ),
+            ("OTHER_JWT__KEY".to_string(), "ignored".to_string()),
+        ]);
+        let con = Configure::build_with(&test_config(), environment().source(Some(vars))).unwrap();
+
+        assert_eq!(con.get_string("database.password").unwrap(), "from-env");
+        assert_eq!(con.get_int("tcp.port").unwrap(), 8080);
+        assert_eq!(con.get_string("database.name").unwrap(), "roger_db");
+        assert_eq!(
+            con.get::<Vec<String>>("cors.allowed_origins").unwrap(),
+            vec!["https://a.example.com", "https://b.example.com"]
+        );
+        assert_ne!(con.get_string("jwt.key").unwrap(), "ignored");
+    }
+
+    #[test]
+    fn test_file_formats() {
+        let dir = std::env::temp_dir().join(format!("config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("app.yaml", "name: yaml\ntcp:\n  port: 3001\n"),
+            ("app.yml", "name: yml\ntcp:\n  port: 3002\n"),
+            ("app.json", r#"{"name": "json", "tcp": {"port": 3003}}"#),
+        ];
+        for (i, (file, contents)) in files.iter().enumerate() {
+            let path = dir.join(file);
+            std::fs::write(&path, contents).unwrap();
+            let con = Configure::build_with(path.to_str().unwrap(), environment()).unwrap();
+            assert_eq!(con.get_string("name").unwrap(), file[4..]);
+            assert_eq!(con.get_int("tcp.port").unwrap(), 3001 + i as i64);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(file_format("dev.toml").is_ok());
+        assert!(file_format("dev.ini").is_err());
+        assert!(file_format("dev").is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let dir = std::env::temp_dir().join(format!("layers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("app.toml");
+        let config = config.to_str().unwrap();
+        std::fs::write(config, "name = \"app\"\n[tcp]\nport = 3001\n").unwrap();
+        assert_eq!(layers(config), vec![config]);
+
+        let files = [
+            (
+                "base.toml",
+                "name = \"base\"\n[tcp]\nip = \"0.0.0.0\"\nport = 3000\n",
+            ),
+            ("app.local.yaml", "tcp:\n  port: 3002\n"),
+        ];
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        let con = Configure::build_with(config, environment()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(con.get_string("name").unwrap(), "app");
+        assert_eq!(con.get_string("tcp.ip").unwrap(), "0.0.0.0");
+        assert_eq!(con.get_int("tcp.port").unwrap(), 3002);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a .toml, .yaml, .yml or .json file")]
+    fn test_unknown_format() {
+        let _ = Configure::build("dev.ini");
     }
 
     #[test]
@@ -149,8 +366,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_pool_connection() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
 
         pool.close().await;
         Ok(())
@@ -160,13 +377,13 @@ mod tests {
     #[should_panic(expected = "Failed to execute environment : configuration file")]
     async fn test_pool_connection_failed() {
         let builder = ConnectionBuilder(String::from("de.toml"));
-        let _ = ConnectionBuilder::new(&builder).await;
+        let _ = builder.connect().await;
     }
 
     #[tokio::test]
     async fn test_pool_connection_helper() -> Result<(), Error> {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder).await?;
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
         pool.close().await;
         Ok(())
     }
@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::runtime::Runtime;
+
+/// Settings from the `[cors]` section of the config file. Missing keys fall
+/// back to the defaults below; an empty origin list allows no cross-origin
+/// requests.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "DELETE", "OPTIONS"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: [header::CONTENT_TYPE, header::AUTHORIZATION, header::ACCEPT]
+                .map(|name| name.to_string())
+                .to_vec(),
+            max_age: None,
+        }
+    }
+}
+
+impl CorsSettings {
+    /// Builds the layer. `"*"` in the origin list allows any origin, in which
+    /// case credentials are not allowed (browsers reject that combination).
+    /// Otherwise origins are checked against `runtime`, so a config reload
+    /// changes them without rebuilding the layer.
+    pub fn layer(&self, runtime: Runtime) -> CorsLayer {
+        let methods: Vec<Method> = self
+            .allowed_methods
+            .iter()
+            .filter_map(|method| parse_or_warn(method, "method"))
+            .collect();
+        let headers: Vec<HeaderName> = self
+            .allowed_headers
+            .iter()
+            .filter_map(|name| parse_or_warn(name, "header"))
+            .collect();
+
+        let mut cors = CorsLayer::new()
+            .allow_methods(methods)
+            .allow_headers(headers);
+
+        if self.allowed_origins.iter().any(|origin| origin == "*") {
+            cors = cors.allow_origin(AllowOrigin::any());
+        } else {
+            for origin in &self.allowed_origins {
+                parse_or_warn::<HeaderValue>(origin, "origin");
+            }
+            cors = cors
+                .allow_origin(AllowOrigin::predicate(move |origin, _| {
+                    runtime.load().allows_origin(origin)
+                }))
+                .allow_credentials(true);
+        }
+
+        if let Some(secs) = self.max_age {
+            cors = cors.max_age(Duration::from_secs(secs));
+        }
+        cors
+    }
+}
+
+fn parse_or_warn<T: std::str::FromStr>(value: &str, kind: &str) -> Option<T> {
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        tracing::warn!(value, "Ignoring invalid CORS {}", kind);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests_cors {
+    use axum::{
+        Router,
+        http::{HeaderValue, Method, header},
+        routing::get,
+    };
+    use axum_test::TestServer;
+
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+
+    use crate::config::{
+        cors::CorsSettings,
+        runtime::{Runtime, RuntimeSettings},
+    };
+
+    fn runtime(settings: &CorsSettings) -> Runtime {
+        Arc::new(ArcSwap::from_pointee(RuntimeSettings {
+            allowed_origins: settings.allowed_origins.clone(),
+            ..Default::default()
+        }))
+    }
+
+    fn server_with(settings: CorsSettings, runtime: Runtime) -> TestServer {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(settings.layer(runtime));
+        TestServer::new(app).unwrap()
+    }
+
+    fn server(settings: CorsSettings) -> TestServer {
+        let runtime = runtime(&settings);
+        server_with(settings, runtime)
+    }
+
+    #[tokio::test]
+    async fn test_allowed_origin() {
+        let server = server(CorsSettings {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            max_age: Some(600),
+            ..Default::default()
+        });
+
+        let response = server
+            .method(Method::OPTIONS, "/")
+            .add_header(header::ORIGIN, "https://app.example.com")
+            .add_header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .await;
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            HeaderValue::from_static("https://app.example.com")
+        );
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_MAX_AGE),
+            HeaderValue::from_static("600")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_origin() {
+        let server = server(CorsSettings {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        });
+
+        let response = server
+            .get("/")
+            .add_header(header::ORIGIN, "https://evil.example.com")
+            .await;
+        assert!(
+            response
+                .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_origin() {
+        let server = server(CorsSettings {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "NOT A METHOD".to_string()],
+            ..Default::default()
+        });
+
+        let response = server
+            .get("/")
+            .add_header(header::ORIGIN, "https://any.example.com")
+            .await;
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            HeaderValue::from_static("*")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_origins() {
+        let settings = CorsSettings {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        };
+        let runtime = runtime(&settings);
+        let server = server_with(settings, runtime.clone());
+
+        runtime.store(Arc::new(RuntimeSettings {
+            allowed_origins: vec!["https://new.example.com".to_string()],
+            ..Default::default()
+        }));
+
+        let response = server
+            .get("/")
+            .add_header(header::ORIGIN, "https://new.example.com")
+            .await;
+        assert_eq!(
+            response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            HeaderValue::from_static("https://new.example.com")
+        );
+        let response = server
+            .get("/")
+            .add_header(header::ORIGIN, "https://app.example.com")
+            .await;
+        assert!(
+            response
+                .maybe_header(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+    }
+}
@@ -1,17 +1,67 @@
-fn flavor(environmet: &str) -> &str {
-    match environmet {
-        "dev" => "Development",
-        "prod" => "Prodcution",
-        _ => "Unknown",
-    }
-}
+use std::path::Path;
+
+/// Flavor used when `FLAVOR` is not set
+pub const DEV: &str = "dev";
+
+/// Flavor of the test suite
+pub const TEST: &str = "test";
 
+/// Layer every flavor starts from, when it exists
+const BASE: &str = "base";
+
+/// Extensions tried, in order, for a flavor's config file
+const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// Config file of the `FLAVOR` (dev by default), see [`config_file`]
 pub fn load_config() -> Result<String, Box<dyn std::error::Error>> {
-    let environmet = std::env::var("FLAVOR").unwrap_or_else(|_| "dev".to_string());
-    let flavor = flavor(&environmet);
+    let environmet = std::env::var("FLAVOR").unwrap_or_else(|_| DEV.to_string());
+    Ok(config_file(&environmet))
+}
 
-    println!("🚀 Server running on {} mode", flavor);
-    let config = format!("{}.toml", environmet);
+/// The first of `<flavor>.toml`, `.yaml`, `.yml` and `.json` that exists, or
+/// the `.toml` name when none does so the error names the usual file.
+pub fn config_file(flavor: &str) -> String {
+    find(Path::new(flavor)).unwrap_or_else(|| format!("{}.toml", flavor))
+}
+
+/// Files merged into the settings of `config`, lowest priority first: `base`
+/// next to it, `config` itself, then its `<flavor>.local` overrides. The
+/// base and local layers are optional and may use any supported format.
+pub fn layers(config: &str) -> Vec<String> {
+    let path = Path::new(config);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let base = find(&dir.join(BASE)).filter(|base| Path::new(base) != path);
+    let local = find(&dir.join(format!("{}.local", stem)));
+    base.into_iter()
+        .chain(std::iter::once(config.to_string()))
+        .chain(local)
+        .collect()
+}
+
+fn find(stem: &Path) -> Option<String> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| format!("{}.{}", stem.display(), ext))
+        .find(|name| Path::new(name).is_file())
+}
+
+/// Reads `.env` from the working directory, if there is one, into the
+/// environment. Variables already set win, so the file only fills the gaps;
+/// it is the place for `APP_` secrets that shouldn't sit in the config file.
+pub fn load_dotenv() {
+    // Runs before logging is set up, hence eprintln
+    if let Err(e) = dotenvy::dotenv()
+        && !e.not_found()
+    {
+        eprintln!("Ignoring .env: {}", e);
+    }
+}
 
-    Ok(config)
+/// True for the config file of the dev flavor, e.g. `dev.toml` or `dev.yaml`
+pub fn is_dev(config: &str) -> bool {
+    Path::new(config).file_stem().and_then(|stem| stem.to_str()) == Some(DEV)
 }
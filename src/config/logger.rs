@@ -1,34 +1,319 @@
-use log::error;
-use log4rs;
+use std::{collections::BTreeMap, fmt, sync::OnceLock};
 
-pub struct Logger;
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    fmt::{FmtContext, FormatEvent, FormatFields, format::Writer},
+    layer::Context,
+    registry::LookupSpan,
+    reload,
+};
 
-impl Logger {
-    pub fn init() {
-        if let Err(e) = log4rs::init_file("log4rs.yaml", Default::default()) {
-            eprintln!("Failed to initialize logger : {}", e);
-        };
+const DEFAULT_LEVEL: &str = "info,sqlx=warn";
+/// Levels a per-module override may name
+pub const LEVELS: [&str; 6] = ["trace", "debug", "info", "warn", "error", "off"];
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "String", rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Self {
+        match format.to_lowercase().as_str() {
+            "json" => LogFormat::Json,
+            _ => LogFormat::Pretty,
+        }
+    }
+}
+
+impl From<String> for LogFormat {
+    fn from(format: String) -> Self {
+        LogFormat::parse(&format)
+    }
+}
+
+/// Settings from the `[logging]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    /// `EnvFilter` directives, e.g. `info,sqlx=warn,example_axum_api::websocket=debug`
+    pub level: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            level: DEFAULT_LEVEL.to_string(),
+        }
+    }
+}
+
+impl LogSettings {
+    /// `RUST_LOG` takes precedence over the configured level
+    pub fn filter(&self) -> EnvFilter {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&self.level))
+    }
+}
+
+/// Set by `Telemetry::init` so the level can be changed while running
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+pub fn install_reload(handle: reload::Handle<EnvFilter, Registry>) {
+    let _ = FILTER.set(handle);
+}
+
+/// Whether `RUST_LOG` is set, which pins the level for the life of the process
+pub fn pinned_by_env() -> bool {
+    std::env::var_os(EnvFilter::DEFAULT_ENV).is_some()
+}
+
+/// `level` followed by one directive per override. Override keys are module
+/// paths inside this crate, e.g. `websocket::chat`.
+pub fn directives(level: &str, modules: &BTreeMap<String, String>) -> String {
+    let mut directives = level.to_string();
+    for (module, module_level) in modules {
+        if !directives.is_empty() {
+            directives.push(',');
+        }
+        directives.push_str(&format!(
+            "{}::{}={}",
+            env!("CARGO_CRATE_NAME"),
+            module,
+            module_level
+        ));
     }
+    directives
 }
 
-pub trait LogMsg {
-    fn err(&self, msg: &str);
+/// Replaces the active `EnvFilter` with `level`. Does nothing when `RUST_LOG`
+/// is set, since it takes precedence over the config file.
+pub fn set_level(level: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+    if pinned_by_env() {
+        return Ok(());
+    }
+    match FILTER.get() {
+        Some(handle) => handle.reload(filter).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
 }
 
-impl LogMsg for Logger {
-    fn err(&self, msg: &str) {
-        error!("{}", msg);
+/// Span fields repeated on every JSON line logged inside the span
+const REQUEST_FIELDS: [&str; 2] = ["request_id", "user_id"];
+
+/// `request_id` and `user_id` of a span, kept as the span records them
+#[derive(Default)]
+struct RequestFields(Map<String, Value>);
+
+impl Visit for RequestFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if REQUEST_FIELDS.contains(&field.name()) && !value.is_empty() {
+            self.0.insert(field.name().to_string(), value.into());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// Remembers the request fields of each span so `JsonLines` can find them
+/// for events logged further down, e.g. inside a query span
+pub struct RequestFieldsLayer;
+
+impl<S> Layer<S> for RequestFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = RequestFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(fields) = span.extensions_mut().get_mut::<RequestFields>()
+        {
+            values.record(fields);
+        }
+    }
+}
+
+/// An event's own fields, typed where JSON has a matching type
+struct EventFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for EventFields<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// One flat JSON object per event: `timestamp`, `level`, `target`, the
+/// event's fields (`message` among them) and the `request_id`/`user_id` of
+/// the request it happened in. Needs `RequestFieldsLayer` on the registry.
+pub struct JsonLines;
+
+impl<S, N> FormatEvent<S, N> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = Map::new();
+        event.record(&mut EventFields(&mut line));
+        for span in ctx.event_scope().into_iter().flatten() {
+            if let Some(fields) = span.extensions().get::<RequestFields>() {
+                for (name, value) in &fields.0 {
+                    line.entry(name.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        let metadata = event.metadata();
+        line.insert(
+            "timestamp".to_string(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        line.insert("level".to_string(), metadata.level().as_str().into());
+        line.insert("target".to_string(), metadata.target().into());
+        writeln!(writer, "{}", Value::Object(line))
     }
 }
 
 #[cfg(test)]
 mod tests_logger {
-    use crate::config::logger::{LogMsg, Logger};
+    use std::{
+        collections::BTreeMap,
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::Value;
+    use tracing_subscriber::{fmt, layer::SubscriberExt};
+
+    use crate::{
+        app_state::test_config,
+        config::{
+            logger::{JsonLines, LogFormat, LogSettings, RequestFieldsLayer, directives},
+            settings::Settings,
+        },
+    };
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::parse("json"), LogFormat::Json);
+        assert_eq!(LogFormat::parse("JSON"), LogFormat::Json);
+        assert_eq!(LogFormat::parse("pretty"), LogFormat::Pretty);
+        assert_eq!(LogFormat::parse("other"), LogFormat::Pretty);
+    }
+
+    #[tokio::test]
+    async fn test_log_settings() {
+        let settings = Settings::load(&test_config()).await.unwrap();
+        assert!(!settings.logging.level.is_empty());
+        assert_eq!(LogSettings::default().format, LogFormat::Pretty);
+    }
 
     #[test]
-    fn test_log_error() {
-        Logger::init();
-        let logger = Logger;
-        logger.err("Error message");
+    fn test_directives() {
+        let modules = BTreeMap::from([("websocket::chat".to_string(), "debug".to_string())]);
+        assert_eq!(
+            directives("info,sqlx=warn", &modules),
+            "info,sqlx=warn,example_axum_api::websocket::chat=debug"
+        );
+        assert_eq!(
+            directives("", &modules),
+            "example_axum_api::websocket::chat=debug"
+        );
+        assert_eq!(directives("warn", &BTreeMap::new()), "warn");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(RequestFieldsLayer)
+            .with(
+                fmt::layer()
+                    .event_format(JsonLines)
+                    .with_writer(move || writer.clone()),
+            );
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!(
+                "http_request",
+                request_id = %"req-1",
+                user_id = tracing::field::Empty,
+            );
+            let _request = request.enter();
+            request.record("user_id", "user-1");
+            let _query = tracing::info_span!("db.users.find").entered();
+            tracing::warn!(rows = 2, "Slow query");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], module_path!());
+        assert_eq!(line["message"], "Slow query");
+        assert_eq!(line["rows"], 2);
+        assert_eq!(line["request_id"], "req-1");
+        assert_eq!(line["user_id"], "user-1");
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
     }
 }
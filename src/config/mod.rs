@@ -1,3 +1,8 @@
 pub mod connection;
+pub mod cors;
 pub mod flavor;
 pub mod logger;
+pub mod runtime;
+pub mod secrets;
+pub mod settings;
+pub mod telemetry;
@@ -0,0 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use axum::http::HeaderValue;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_state::AppState,
+    auth::user::EmailPolicy,
+    config::{
+        flavor::{layers, load_config},
+        logger,
+        settings::Settings,
+    },
+    rate_limit::RateLimitSettings,
+};
+
+/// How often the config file is checked for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `[features]`, switches that can be flipped without a restart
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FeatureFlags {
+    /// New accounts can be created through `/auth/register`
+    pub registration: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        Self { registration: true }
+    }
+}
+
+/// The part of `Settings` that is re-applied when the config file changes.
+/// Everything else (database, listeners, storage, ...) still needs a restart.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuntimeSettings {
+    /// `cors.allowed_origins`. Switching to or from `"*"` needs a restart.
+    pub allowed_origins: Vec<String>,
+    pub rate_limits: RateLimitSettings,
+    pub features: FeatureFlags,
+    /// `mail.login_alerts`
+    pub login_alerts: bool,
+    /// `logging.level`
+    pub log_level: String,
+    pub email_policy: EmailPolicy,
+}
+
+/// Shared, atomically replaced snapshot read on every request that needs it
+pub type Runtime = Arc<ArcSwap<RuntimeSettings>>;
+
+impl From<&Settings> for RuntimeSettings {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            allowed_origins: settings.cors.allowed_origins.clone(),
+            rate_limits: settings.rate_limits.clone(),
+            features: settings.features.clone(),
+            login_alerts: settings.mail.login_alerts,
+            log_level: settings.logging.level.clone(),
+            email_policy: settings.email_policy.clone(),
+        }
+    }
+}
+
+impl RuntimeSettings {
+    /// Puts the values applied since `settings` was loaded back into it
+    pub fn overlay(&self, settings: &mut Settings) {
+        settings.cors.allowed_origins = self.allowed_origins.clone();
+        settings.rate_limits = self.rate_limits.clone();
+        settings.features = self.features.clone();
+        settings.mail.login_alerts = self.login_alerts;
+        settings.logging.level = self.log_level.clone();
+        settings.email_policy = self.email_policy.clone();
+    }
+
+    pub fn allows_origin(&self, origin: &HeaderValue) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    }
+}
+
+/// Swaps in the runtime part of `settings` and pushes it to the components
+/// that keep their own copy (hook rate limiter, log filter, IP lists).
+pub fn apply(state: &AppState, settings: &Settings) -> Arc<RuntimeSettings> {
+    let runtime = Arc::new(RuntimeSettings::from(settings));
+    state.hook_limiter.configure(
+        runtime.rate_limits.hook_limit,
+        Duration::from_secs(runtime.rate_limits.hook_window_secs),
+    );
+    if let Err(e) = logger::set_level(&runtime.log_level) {
+        tracing::warn!(error = %e, "Keeping the current log level");
+    }
+    state.ip_filter.replace(settings.ip_filter.lists.clone());
+    state.runtime.store(runtime.clone());
+    runtime
+}
+
+/// Re-reads the active config file (and `APP_` overrides) and applies it.
+/// An invalid file is rejected as a whole, leaving the running values in place.
+pub async fn reload(state: &AppState) -> Result<Arc<RuntimeSettings>, String> {
+    let flavor = load_config().map_err(|e| e.to_string())?;
+    let settings = Settings::load(&flavor).await.map_err(|e| e.to_string())?;
+    let runtime = apply(state, &settings);
+    tracing::info!(?runtime, "Configuration reloaded from {}", flavor);
+    Ok(runtime)
+}
+
+/// Polls the modification times of the layers of `path` and reloads when
+/// one changes, or when a base or local layer appears or goes away
+pub fn spawn_watcher(state: Arc<AppState>, path: String) {
+    tokio::spawn(async move {
+        let modified = |path: String| async move {
+            let mut times = Vec::new();
+            for layer in layers(&path) {
+                let time = tokio::fs::metadata(&layer)
+                    .await
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+                times.push((layer, time));
+            }
+            times
+        };
+        let mut last = modified(path.clone()).await;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = modified(path.clone()).await;
+            if current.iter().any(|(_, time)| time.is_none()) || current == last {
+                continue;
+            }
+            last = current;
+            if let Err(e) = reload(&state).await {
+                tracing::error!(error = %e, "Ignoring invalid configuration change in {}", path);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests_runtime {
+    use std::sync::Arc;
+
+    use crate::{
+        app_state::{AppState, test_config},
+        config::{runtime::apply, settings::Settings},
+    };
+
+    #[tokio::test]
+    async fn test_apply() {
+        let state = Arc::new(AppState::test().await);
+        let mut settings = Settings::load(&test_config()).await.unwrap();
+        settings.cors.allowed_origins = vec!["https://new.example.com".to_string()];
+        settings.features.registration = false;
+        settings.rate_limits.hook_limit = 1;
+        settings.ip_filter.lists.deny = vec!["203.0.113.0/24".parse().unwrap()];
+
+        apply(&state, &settings);
+
+        let runtime = state.runtime.load();
+        assert!(runtime.allows_origin(&"https://new.example.com".parse().unwrap()));
+        assert!(!runtime.features.registration);
+        assert!(state.hook_limiter.check("hook").await);
+        assert!(!state.hook_limiter.check("hook").await);
+        assert!(state.ip_filter.is_denied("203.0.113.7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,222 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+/// Name of the database password in the secrets source
+pub const DB_PASSWORD: &str = "db_password";
+/// Name of the JWT signing key in the secrets source
+pub const JWT_KEY: &str = "jwt_key";
+/// Name of the key file links are signed with in the secrets source
+pub const STORAGE_SIGNING_KEY: &str = "storage_signing_key";
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretBackend {
+    /// Secrets come from the config file / `APP_` variables
+    #[default]
+    None,
+    /// One file per secret, e.g. docker secrets mounted at `/run/secrets`
+    File,
+    /// HashiCorp Vault KV v2
+    Vault,
+}
+
+/// Settings from the `[secrets]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SecretsSettings {
+    pub backend: SecretBackend,
+    /// `file`: directory holding the secret files
+    pub dir: String,
+    /// `vault`: server address, e.g. `https://vault:8200`
+    pub address: Option<String>,
+    /// `vault`: KV v2 data path, e.g. `secret/data/example-axum-api`
+    pub path: Option<String>,
+    /// `vault`: file with the token, `VAULT_TOKEN` is used when unset
+    pub token_file: Option<String>,
+}
+
+impl Default for SecretsSettings {
+    fn default() -> Self {
+        Self {
+            backend: SecretBackend::None,
+            dir: "/run/secrets".to_string(),
+            address: None,
+            path: None,
+            token_file: None,
+        }
+    }
+}
+
+impl SecretsSettings {
+    /// Fetches the named secrets from the configured backend. Names the
+    /// backend doesn't have are left out of the result.
+    pub async fn fetch(&self, names: &[&str]) -> Result<HashMap<String, String>, String> {
+        match self.backend {
+            SecretBackend::None => Ok(HashMap::new()),
+            SecretBackend::File => Ok(read_files(Path::new(&self.dir), names)),
+            SecretBackend::Vault => self.read_vault(names).await,
+        }
+    }
+
+    async fn read_vault(&self, names: &[&str]) -> Result<HashMap<String, String>, String> {
+        let address = self
+            .address
+            .as_deref()
+            .ok_or("secrets.address is required for the vault backend")?;
+        let path = self
+            .path
+            .as_deref()
+            .ok_or("secrets.path is required for the vault backend")?;
+        let token = match &self.token_file {
+            Some(file) => std::fs::read_to_string(file)
+                .map_err(|e| format!("Failed to read vault token file {}: {}", file, e))?,
+            None => std::env::var("VAULT_TOKEN")
+                .map_err(|_| "VAULT_TOKEN or secrets.token_file is required".to_string())?,
+        };
+
+        let url = format!(
+            "{}/v1/{}",
+            address.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let response = client
+            .get(&url)
+            .header("X-Vault-Token", token.trim())
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach vault: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Vault responded {} for {}",
+                response.status(),
+                path
+            ));
+        }
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| format!("Invalid vault response: {}", e))?;
+
+        // KV v2 nests the secret under data.data
+        let data = &body["data"]["data"];
+        Ok(names
+            .iter()
+            .filter_map(|name| {
+                data[*name]
+                    .as_str()
+                    .map(|value| (name.to_string(), value.to_string()))
+            })
+            .collect())
+    }
+}
+
+/// Shown instead of a secret when settings are serialized
+pub const REDACTED: &str = "[redacted]";
+
+/// `serialize_with` for a secret setting: tells whether it is set, never what
+/// it is
+pub fn redact<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if value.is_empty() { "" } else { REDACTED })
+}
+
+/// `redact` for an optional secret
+pub fn redact_option<S: Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn read_files(dir: &Path, names: &[&str]) -> HashMap<String, String> {
+    names
+        .iter()
+        .filter_map(|name| {
+            std::fs::read_to_string(dir.join(name))
+                .ok()
+                .map(|value| (name.to_string(), value.trim_end().to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests_secrets {
+    use axum::{Json, Router, http::HeaderMap, routing::get};
+    use axum_test::TestServer;
+    use serde_json::json;
+
+    use crate::config::secrets::{DB_PASSWORD, JWT_KEY, SecretBackend, SecretsSettings};
+
+    #[tokio::test]
+    async fn test_no_backend() {
+        let secrets = SecretsSettings::default();
+        assert!(secrets.fetch(&[JWT_KEY]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_backend() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(JWT_KEY), "from-file-secret\n").unwrap();
+
+        let secrets = SecretsSettings {
+            backend: SecretBackend::File,
+            dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let values = secrets.fetch(&[JWT_KEY, DB_PASSWORD]).await.unwrap();
+        assert_eq!(values.get(JWT_KEY).unwrap(), "from-file-secret");
+        assert!(!values.contains_key(DB_PASSWORD));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vault_backend() {
+        let app = Router::new().route(
+            "/v1/secret/data/app",
+            get(|headers: HeaderMap| async move {
+                assert_eq!(headers["x-vault-token"], "root-token");
+                Json(json!({ "data": { "data": { "db_password": "from-vault" } } }))
+            }),
+        );
+        let server = TestServer::builder()
+            .http_transport()
+            .build(app)
+            .expect("Failed start server");
+        let address = server.server_address().unwrap();
+
+        let token_file = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&token_file, "root-token\n").unwrap();
+
+        let secrets = SecretsSettings {
+            backend: SecretBackend::Vault,
+            address: Some(address.to_string()),
+            path: Some("secret/data/app".to_string()),
+            token_file: Some(token_file.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let values = secrets.fetch(&[DB_PASSWORD, JWT_KEY]).await.unwrap();
+        assert_eq!(values.get(DB_PASSWORD).unwrap(), "from-vault");
+        assert!(!values.contains_key(JWT_KEY));
+
+        std::fs::remove_file(token_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vault_missing_address() {
+        let secrets = SecretsSettings {
+            backend: SecretBackend::Vault,
+            ..Default::default()
+        };
+        assert!(secrets.fetch(&[JWT_KEY]).await.is_err());
+    }
+}
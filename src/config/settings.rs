@@ -0,0 +1,393 @@
+use std::{fmt, net::IpAddr, time::Duration};
+
+use config::ConfigError;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+use crate::{
+    analytics::handler::AnalyticsSettings,
+    auth::{otp::OtpSettings, user::EmailPolicy, util::PasswordSettings},
+    cache::CacheSettings,
+    calendar::handler::CalendarSettings,
+    config::{
+        connection::Configure,
+        cors::CorsSettings,
+        flavor::is_dev,
+        logger::LogSettings,
+        runtime::FeatureFlags,
+        secrets::{DB_PASSWORD, JWT_KEY, STORAGE_SIGNING_KEY, SecretsSettings, redact},
+    },
+    grpc::handler::GrpcSettings,
+    http_cache::HttpCacheSettings,
+    ip_filter::IpFilterSettings,
+    mail::mailer::MailSettings,
+    message::partition::MessageSettings,
+    outbox::OutboxSettings,
+    rate_limit::RateLimitSettings,
+    retention::RetentionSettings,
+    saml::handler::SamlSettings,
+    sms::sender::SmsSettings,
+    storage::{backend::StorageSettings, scan::ScanSettings},
+    streaming::publisher::StreamingSettings,
+    webhooks::delivery::WebhookSettings,
+    websocket::{compression::CompressionSettings, socketio::SocketIoSettings},
+};
+
+/// Typed view of the config file (plus `APP_` overrides), validated once at startup
+#[derive(Clone, Deserialize, Serialize, Validate)]
+pub struct Settings {
+    pub name: String,
+    #[validate(nested)]
+    pub database: DbSettings,
+    #[validate(nested)]
+    pub tcp: TcpSettings,
+    #[validate(nested)]
+    pub jwt: JwtSettings,
+    #[serde(default)]
+    pub cors: CorsSettings,
+    #[serde(default)]
+    pub logging: LogSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
+    #[serde(default)]
+    pub secrets: SecretsSettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub mail: MailSettings,
+    #[serde(default)]
+    #[validate(nested)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    #[validate(nested)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub ip_filter: IpFilterSettings,
+    #[serde(default)]
+    #[validate(nested)]
+    pub rate_limits: RateLimitSettings,
+    #[serde(default)]
+    pub features: FeatureFlags,
+    #[serde(default)]
+    #[validate(nested)]
+    pub password: PasswordSettings,
+    #[serde(default)]
+    pub grpc: GrpcSettings,
+    #[serde(default)]
+    pub sms: SmsSettings,
+    #[serde(default)]
+    pub otp: OtpSettings,
+    #[serde(default)]
+    pub email_policy: EmailPolicy,
+    #[serde(default)]
+    pub scan: ScanSettings,
+    #[serde(default)]
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub streaming: StreamingSettings,
+    #[serde(default)]
+    pub analytics: AnalyticsSettings,
+    #[serde(default)]
+    pub retention: RetentionSettings,
+    #[serde(default)]
+    pub calendar: CalendarSettings,
+    #[serde(default)]
+    #[validate(nested)]
+    pub saml: SamlSettings,
+    #[serde(default)]
+    #[validate(nested)]
+    pub ws_compression: CompressionSettings,
+    #[serde(default)]
+    pub socketio: SocketIoSettings,
+    #[serde(default)]
+    pub http_cache: HttpCacheSettings,
+    #[serde(default)]
+    pub messages: MessageSettings,
+}
+
+#[derive(Clone, Deserialize, Serialize, Validate)]
+#[validate(schema(function = "validate_pool_size", skip_on_field_errors = false))]
+pub struct DbSettings {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub host: String,
+    #[validate(range(min = 1, message = "must be a valid port"))]
+    pub port: u16,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub user: String,
+    /// Can be left out of the file when `[secrets]` provides `db_password`
+    #[serde(default, serialize_with = "redact")]
+    pub password: String,
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub name: String,
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub max_connection: u32,
+    pub min_connection: u32,
+    /// Seconds
+    pub acquire_timeout: u64,
+    /// Seconds
+    pub idle_timeout: u64,
+    /// Connection attempts at startup before giving up
+    #[serde(default = "default_connect_attempts")]
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub connect_attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled on each attempt
+    #[serde(default = "default_connect_backoff_ms")]
+    pub connect_backoff_ms: u64,
+    /// Statements and connection acquires slower than this many milliseconds
+    /// are logged as warnings; 0 turns the warnings off
+    #[serde(default = "default_slow_query_ms")]
+    pub slow_query_ms: u64,
+    /// Optional read-only replica for read-heavy queries
+    #[validate(nested)]
+    pub replica: Option<ReplicaSettings>,
+}
+
+/// `[database.replica]`. Credentials and database name are shared with the primary.
+#[derive(Clone, Deserialize, Serialize, Validate)]
+pub struct ReplicaSettings {
+    #[validate(length(min = 1, message = "must not be empty"))]
+    pub host: String,
+    #[validate(range(min = 1, message = "must be a valid port"))]
+    pub port: u16,
+    /// Defaults to the primary's `max_connection`
+    pub max_connection: Option<u32>,
+    /// Seconds to wait for a replica connection before falling back, default 2
+    #[serde(default = "default_replica_acquire_timeout")]
+    pub acquire_timeout: u64,
+}
+
+fn default_connect_attempts() -> u32 {
+    5
+}
+
+fn default_connect_backoff_ms() -> u64 {
+    500
+}
+
+fn default_slow_query_ms() -> u64 {
+    1000
+}
+
+fn default_replica_acquire_timeout() -> u64 {
+    2
+}
+
+impl DbSettings {
+    pub fn url(&self) -> String {
+        self.url_for(&self.host, self.port)
+    }
+
+    /// Level and threshold of the slow statement and acquire warnings
+    pub fn slow_query(&self) -> (LevelFilter, Duration) {
+        match self.slow_query_ms {
+            0 => (LevelFilter::Off, Duration::MAX),
+            ms => (LevelFilter::Warn, Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn replica_url(&self, replica: &ReplicaSettings) -> String {
+        self.url_for(&replica.host, replica.port)
+    }
+
+    fn url_for(&self, host: &str, port: u16) -> String {
+        format!(
+            "postgres://{}:{}@{}:{}/{}",
+            self.user, self.password, host, port, self.name
+        )
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Validate)]
+pub struct TcpSettings {
+    #[validate(custom(function = "validate_ip"))]
+    pub ip: String,
+    #[validate(range(min = 1, message = "must be a valid port"))]
+    pub port: u16,
+}
+
+#[derive(Clone, Deserialize, Serialize, Validate)]
+pub struct JwtSettings {
+    /// Can be left out of the file when `[secrets]` provides `jwt_key`
+    #[serde(default, serialize_with = "redact")]
+    #[validate(length(min = 32, message = "must be at least 32 characters"))]
+    pub key: String,
+    /// Accept `X-Debug-User: <user_id>` in place of a token. Refused by
+    /// `Settings::load` outside the dev flavor.
+    #[serde(default)]
+    pub debug_user: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TelemetrySettings {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: Option<String>,
+}
+
+fn validate_pool_size(db: &DbSettings) -> Result<(), ValidationError> {
+    if db.min_connection > db.max_connection {
+        return Err(ValidationError::new("pool_size")
+            .with_message("min_connection must not exceed max_connection".into()));
+    }
+    Ok(())
+}
+
+fn validate_ip(ip: &str) -> Result<(), ValidationError> {
+    ip.parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|_| ValidationError::new("ip").with_message("must be an IP address".into()))
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    /// The file is missing, malformed, or a value has the wrong type
+    Load(ConfigError),
+    /// The secrets backend could not be read
+    Secrets(String),
+    /// Every invalid field as `section.field: reason`
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Load(e) => write!(f, "{}", e),
+            SettingsError::Secrets(e) => write!(f, "secrets: {}", e),
+            SettingsError::Invalid(errors) => write!(f, "{}", errors.join("\n")),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl Settings {
+    /// Reads the file, replaces the DB password, JWT key and file link key
+    /// with values from the secrets backend when it has them, then validates.
+    pub async fn load(env: &str) -> Result<Self, SettingsError> {
+        let mut settings: Settings = Configure::build(env)
+            .and_then(|con| con.try_deserialize())
+            .map_err(SettingsError::Load)?;
+
+        let mut secrets = settings
+            .secrets
+            .fetch(&[DB_PASSWORD, JWT_KEY, STORAGE_SIGNING_KEY])
+            .await
+            .map_err(SettingsError::Secrets)?;
+        if let Some(password) = secrets.remove(DB_PASSWORD) {
+            settings.database.password = password;
+        }
+        if let Some(key) = secrets.remove(JWT_KEY) {
+            settings.jwt.key = key;
+        }
+        if let Some(key) = secrets.remove(STORAGE_SIGNING_KEY) {
+            settings.storage.signing_key = key;
+        }
+
+        settings
+            .validate()
+            .map_err(|errors| SettingsError::Invalid(flatten(&errors, "")))?;
+        settings.check_flavor(env)?;
+        Ok(settings)
+    }
+
+    /// Rejects development shortcuts in any flavor but dev
+    fn check_flavor(&self, env: &str) -> Result<(), SettingsError> {
+        if self.jwt.debug_user && !is_dev(env) {
+            return Err(SettingsError::Invalid(vec![format!(
+                "jwt.debug_user: only allowed in dev.toml, not {}",
+                env
+            )]));
+        }
+        Ok(())
+    }
+}
+
+fn flatten(errors: &ValidationErrors, prefix: &str) -> Vec<String> {
+    let mut messages = Vec::new();
+    for (field, kind) in errors.errors() {
+        let path = match (prefix.is_empty(), field.as_ref()) {
+            (true, _) => field.to_string(),
+            (false, "__all__") => prefix.to_string(),
+            (false, _) => format!("{}.{}", prefix, field),
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                for error in errors {
+                    let reason = error.message.clone().unwrap_or_else(|| error.code.clone());
+                    messages.push(format!("{}: {}", path, reason));
+                }
+            }
+            ValidationErrorsKind::Struct(nested) => messages.extend(flatten(nested, &path)),
+            ValidationErrorsKind::List(items) => {
+                for (index, nested) in items {
+                    messages.extend(flatten(nested, &format!("{}[{}]", path, index)));
+                }
+            }
+        }
+    }
+    messages.sort();
+    messages
+}
+
+#[cfg(test)]
+mod tests_settings {
+    use validator::Validate;
+
+    use crate::{
+        app_state::test_config,
+        config::settings::{Settings, SettingsError, flatten},
+    };
+
+    #[tokio::test]
+    async fn test_load_settings() {
+        let settings = Settings::load(&test_config()).await.unwrap();
+        assert_eq!(settings.database.name, "roger_db");
+        assert_eq!(settings.database.port, 5432);
+        assert_eq!(settings.tcp.port, 3000);
+        assert!(settings.database.url().starts_with("postgres://"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_settings() {
+        let mut settings = Settings::load(&test_config()).await.unwrap();
+        settings.database.host = String::new();
+        settings.database.min_connection = settings.database.max_connection + 1;
+        settings.tcp.ip = "localhost:3000".to_string();
+        settings.jwt.key = "short".to_string();
+
+        let errors = flatten(&settings.validate().unwrap_err(), "");
+        assert_eq!(
+            errors,
+            vec![
+                "database.host: must not be empty",
+                "database: min_connection must not exceed max_connection",
+                "jwt.key: must be at least 32 characters",
+                "tcp.ip: must be an IP address",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_user_only_in_dev() {
+        let mut settings = Settings::load("test.toml").await.unwrap();
+        settings.jwt.debug_user = true;
+
+        assert!(settings.check_flavor("dev.toml").is_ok());
+        assert_eq!(
+            settings.check_flavor("test.toml").unwrap_err().to_string(),
+            "jwt.debug_user: only allowed in dev.toml, not test.toml"
+        );
+    }
+
+    #[test]
+    fn test_settings_error_display() {
+        let error = SettingsError::Invalid(vec![
+            "database.host: must not be empty".to_string(),
+            "jwt.key: must be at least 32 characters".to_string(),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "database.host: must not be empty\njwt.key: must be at least 32 characters"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderMap, Request, Response},
+};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use std::time::Duration;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt};
+
+use crate::{
+    config::{
+        logger::{self, JsonLines, LogFormat, RequestFieldsLayer},
+        settings::Settings,
+    },
+    error::REQUEST_ID_HEADER,
+    metrics::QueryTimer,
+};
+
+/// Owns the OTLP tracer provider so pending spans can be flushed on shutdown
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Installs the global tracing subscriber. Logs are written to stdout in the
+    /// `logging.format` style, and spans are exported over OTLP/HTTP when
+    /// `telemetry.otlp_endpoint` is set in the config file.
+    pub fn init(settings: &Settings) -> Self {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let logging = &settings.logging;
+        let service_name = settings
+            .telemetry
+            .service_name
+            .clone()
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+        let provider = settings
+            .telemetry
+            .otlp_endpoint
+            .as_deref()
+            .and_then(|endpoint| build_provider(endpoint, service_name));
+
+        let otel_layer = provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("http")));
+        let (json_layer, pretty_layer) = match logging.format {
+            LogFormat::Json => (
+                Some(RequestFieldsLayer.and_then(fmt::layer().event_format(JsonLines))),
+                None,
+            ),
+            LogFormat::Pretty => (None, Some(fmt::layer())),
+        };
+        let (filter, filter_handle) = reload::Layer::new(logging.filter());
+        logger::install_reload(filter_handle);
+        let _ = tracing_subscriber::registry()
+            .with(filter)
+            .with(json_layer)
+            .with(pretty_layer)
+            .with(otel_layer)
+            .with(QueryTimer)
+            .try_init();
+
+        if let Some(provider) = &provider {
+            global::set_tracer_provider(provider.clone());
+        }
+        Self { provider }
+    }
+
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+fn build_provider(endpoint: &str, service_name: String) -> Option<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build();
+
+    match exporter {
+        Ok(exporter) => Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(service_name).build())
+                .build(),
+        ),
+        Err(e) => {
+            eprintln!("Failed to create OTLP exporter : {}", e);
+            None
+        }
+    }
+}
+
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Request span for `TraceLayer`, continuing the caller's trace from `traceparent`.
+/// `user_id` is recorded by the auth middleware and `http.status_code` on response.
+pub fn make_span<B>(req: &Request<B>) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or_else(|| req.uri().path());
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = %format!("{} {}", req.method(), route),
+        http.method = %req.method(),
+        http.route = %route,
+        request_id = %request_id,
+        http.status_code = tracing::field::Empty,
+        user_id = tracing::field::Empty,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let _ = span.set_parent(parent);
+    span
+}
+
+pub fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    let status = response.status().as_u16();
+    span.record("http.status_code", status);
+    tracing::info!(
+        status,
+        latency_ms = latency.as_millis() as u64,
+        "Request completed"
+    );
+}
+
+#[cfg(test)]
+mod tests_telemetry {
+    use axum::http::{HeaderMap, HeaderValue};
+    use opentelemetry::propagation::Extractor;
+
+    use crate::config::telemetry::HeaderExtractor;
+
+    #[test]
+    fn test_header_extractor() {
+        let mut headers = HeaderMap::new();
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        headers.insert("traceparent", HeaderValue::from_static(traceparent));
+
+        let extractor = HeaderExtractor(&headers);
+        assert_eq!(extractor.get("traceparent"), Some(traceparent));
+        assert_eq!(extractor.get("tracestate"), None);
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{any::Any, backtrace::Backtrace};
+
+use axum::{
+    Json,
+    body::{Body, to_bytes},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::util::{MetaResponse, StatusCodeExt},
+    i18n::error_code,
+};
+
+/// Bodies of rejected responses larger than this are replaced by the status reason
+const MAX_ERROR_BODY: usize = 16 * 1024;
+
+/// Set on every request by `SetRequestIdLayer` and echoed on the response
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// JSON body of every error response: `{"meta": {"code", "error", "message", "errors"}}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub meta: ErrorMeta,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorMeta {
+    pub code: i32,
+    /// Stable machine-readable code, see `i18n::error_code`
+    #[serde(default)]
+    pub error: String,
+    /// In the language asked for with `Accept-Language` when there is a translation
+    pub message: String,
+    pub errors: Vec<ErrorDetail>,
+    /// Set on 500s from a panicking handler, to find the matching log line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// A single problem with the request, e.g. one invalid form field
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub field: String,
+    /// Name of the failed rule, e.g. `length` or `email`
+    #[serde(default)]
+    pub code: String,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            meta: ErrorMeta {
+                code: status.as_u16() as i32,
+                error: error_code(status, &message),
+                message,
+                errors: Vec::new(),
+                request_id: None,
+            },
+        }
+    }
+}
+
+impl From<MetaResponse> for ErrorResponse {
+    fn from(meta: MetaResponse) -> Self {
+        let status = StatusCode::from_u16(meta.code as u16).unwrap_or(StatusCode::BAD_REQUEST);
+        Self {
+            meta: ErrorMeta {
+                code: meta.code,
+                error: error_code(status, &meta.message),
+                message: meta.message,
+                errors: Vec::new(),
+                request_id: None,
+            },
+        }
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.meta.code as u16)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Rewrites non-JSON error responses (extractor rejections, unmatched routes,
+/// layers that answer with plain text) into an `ErrorResponse`, using the
+/// original body as the message.
+pub async fn json_errors(response: Response) -> Response {
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || is_json(&response) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let message = match to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+        _ => status.canonical_reason().unwrap_or("Error").to_string(),
+    };
+
+    let mut response = ErrorResponse::new(status, message).into_response();
+    for (name, value) in parts.headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+    response
+}
+
+/// Router fallback for paths no route matches
+pub async fn not_found_handler() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::NOT_FOUND.to_i32(),
+        message: "Route not found".to_string(),
+    }
+}
+
+/// Fallback for routes that exist but don't accept the request method
+pub async fn method_not_allowed_handler() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::METHOD_NOT_ALLOWED.to_i32(),
+        message: "Method not allowed".to_string(),
+    }
+}
+
+/// Logs every panic with its backtrace. Handler panics happen inside the
+/// request span, so the log line carries the request id.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        tracing::error!(panic = %info, %backtrace, "Panic");
+    }));
+}
+
+/// Marks the response of a panicking handler for `panic_request_id`
+#[derive(Debug, Clone, Copy)]
+struct Panicked;
+
+/// Handler for `CatchPanicLayer`: a JSON 500 instead of a dropped connection.
+/// The panic itself is logged by the hook from `install_panic_hook`.
+pub fn panic_response(_: Box<dyn Any + Send + 'static>) -> Response {
+    let mut response =
+        ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+            .into_response();
+    response.extensions_mut().insert(Panicked);
+    response
+}
+
+/// Adds the `x-request-id` of the request to the body built by `panic_response`
+pub async fn panic_request_id(headers: HeaderMap, response: Response) -> Response {
+    if response.extensions().get::<Panicked>().is_none() {
+        return response;
+    }
+    let mut body = ErrorResponse::new(response.status(), "Internal server error");
+    body.meta.request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    body.into_response()
+}
+
+fn is_json(response: &Response<Body>) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+#[cfg(test)]
+mod tests_error {
+    use axum::{
+        Json, Router,
+        http::StatusCode,
+        middleware,
+        routing::{get, post},
+    };
+    use axum_test::TestServer;
+    use serde::Deserialize;
+    use tower_http::{
+        catch_panic::CatchPanicLayer,
+        request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    };
+
+    use crate::{
+        auth::util::{MetaResponse, StatusCodeExt},
+        error::{ErrorResponse, json_errors, panic_request_id, panic_response},
+    };
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Payload {
+        name: String,
+    }
+
+    fn server() -> TestServer {
+        let app = Router::new()
+            .route(
+                "/meta",
+                get(|| async {
+                    MetaResponse {
+                        code: StatusCode::BAD_REQUEST.to_i32(),
+                        message: "Bad input".to_string(),
+                    }
+                }),
+            )
+            .route(
+                "/text",
+                get(|| async { (StatusCode::UNAUTHORIZED, "Missing token") }),
+            )
+            .route("/json", post(|Json(_): Json<Payload>| async { "ok" }))
+            .route(
+                "/panic",
+                get(|| async {
+                    panic!("handler failed");
+                    #[allow(unreachable_code)]
+                    "ok"
+                }),
+            )
+            .layer(CatchPanicLayer::custom(panic_response))
+            .layer(middleware::map_response(panic_request_id))
+            .layer(middleware::map_response(json_errors))
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+        TestServer::new(app).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_meta_response_is_json() {
+        let response = server().get("/meta").await;
+        response.assert_status_bad_request();
+        let body = response.json::<ErrorResponse>();
+        assert_eq!(body.meta.code, 400);
+        assert_eq!(body.meta.message, "Bad input");
+        assert!(body.meta.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plain_text_rejection_is_json() {
+        let response = server().get("/text").await;
+        response.assert_status_unauthorized();
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Missing token"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extractor_rejection_is_json() {
+        let response = server()
+            .post("/json")
+            .json(&serde_json::json!({ "other": 1 }))
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let body = response.json::<ErrorResponse>();
+        assert_eq!(body.meta.code, 422);
+        assert!(body.meta.message.contains("name"));
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_route_is_json() {
+        let response = server().get("/missing").await;
+        response.assert_status_not_found();
+        assert_eq!(response.json::<ErrorResponse>().meta.message, "Not Found");
+    }
+
+    #[tokio::test]
+    async fn test_panic_is_json_with_request_id() {
+        let response = server().get("/panic").await;
+        response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.json::<ErrorResponse>();
+        assert_eq!(body.meta.code, 500);
+        assert_eq!(body.meta.message, "Internal server error");
+        let request_id = body.meta.request_id.unwrap();
+        assert!(!request_id.is_empty());
+        assert_eq!(response.header("x-request-id"), request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_panic_keeps_caller_request_id() {
+        let response = server()
+            .get("/panic")
+            .add_header("x-request-id", "abc-123")
+            .await;
+        response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.request_id.as_deref(),
+            Some("abc-123")
+        );
+        assert_eq!(response.header("x-request-id"), "abc-123");
+    }
+
+    #[tokio::test]
+    async fn test_error_without_panic_has_no_request_id() {
+        let response = server().get("/text").await;
+        assert!(response.json::<ErrorResponse>().meta.request_id.is_none());
+        assert!(response.maybe_header("x-request-id").is_some());
+    }
+}
@@ -0,0 +1,132 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+/// Listings bigger than this are passed through without an ETag
+const MAX_ETAG_BODY: usize = 1024 * 1024;
+
+/// Weak ETag of a response body: the first 16 bytes of its SHA-256
+pub fn weak_etag(body: &[u8]) -> String {
+    format!("W/\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+/// True when `If-None-Match` lists `etag` or is `*`, using the weak comparison
+pub fn matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Tags successful `GET` responses with a weak ETag computed from the body and
+/// answers `304 Not Modified` without a body when the client already has it.
+///
+/// The handler still runs, so this saves bandwidth rather than database work.
+pub async fn etag(req: Request, next: Next) -> Response {
+    let headers = req.headers().clone();
+    let response = next.run(req).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match to_bytes(body, MAX_ETAG_BODY).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to buffer response for ETag");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = weak_etag(&bytes);
+    let Ok(value) = HeaderValue::from_str(&etag) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    if matches(&headers, &etag) {
+        let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+        not_modified.headers_mut().insert(header::ETAG, value);
+        return not_modified;
+    }
+    parts.headers.insert(header::ETAG, value);
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests_etag {
+    use axum::{
+        Router,
+        http::{HeaderMap, HeaderValue, StatusCode, header},
+        middleware,
+        routing::get,
+    };
+    use axum_test::TestServer;
+
+    use crate::etag::{etag, matches, weak_etag};
+
+    fn server() -> TestServer {
+        let app = Router::new()
+            .route("/list", get(|| async { "[1,2,3]" }))
+            .route(
+                "/missing",
+                get(|| async { (StatusCode::NOT_FOUND, "Not here") }),
+            )
+            .layer(middleware::from_fn(etag));
+        TestServer::new(app).unwrap()
+    }
+
+    #[test]
+    fn test_matches() {
+        let tag = weak_etag(b"body");
+        let mut headers = HeaderMap::new();
+        assert!(!matches(&headers, &tag));
+
+        let strong = tag.trim_start_matches("W/").to_string();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", {}", strong)).unwrap(),
+        );
+        assert!(matches(&headers, &tag));
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(matches(&headers, &tag));
+    }
+
+    #[tokio::test]
+    async fn test_etag_and_not_modified() {
+        let server = server();
+        let response = server.get("/list").await;
+        response.assert_status_ok();
+        let tag = response.header(header::ETAG);
+        assert!(tag.to_str().unwrap().starts_with("W/\""));
+
+        let response = server
+            .get("/list")
+            .add_header(header::IF_NONE_MATCH, tag.clone())
+            .await;
+        response.assert_status(StatusCode::NOT_MODIFIED);
+        assert!(response.as_bytes().is_empty());
+        assert_eq!(response.header(header::ETAG), tag);
+
+        let response = server
+            .get("/list")
+            .add_header(header::IF_NONE_MATCH, "W/\"stale\"")
+            .await;
+        response.assert_status_ok();
+        response.assert_text("[1,2,3]");
+    }
+
+    #[tokio::test]
+    async fn test_errors_have_no_etag() {
+        let response = server().get("/missing").await;
+        response.assert_status_not_found();
+        assert!(response.maybe_header(header::ETAG).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    analytics::handler::AnalyticsRecord,
+    auth::user::User,
+    group::handler::Group,
+    report::handler::Report,
+    websocket::{chat::ChatMessage, group::GroupMessage},
+};
+
+/// Internal events published by handlers and consumed by background subscribers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "event", content = "data")]
+pub enum DomainEvent {
+    #[serde(rename = "message.created")]
+    GroupMessageCreated {
+        group_id: String,
+        message: GroupMessage,
+    },
+    #[serde(rename = "member.joined")]
+    MemberJoined { group_id: String, user: User },
+    #[serde(rename = "user.registered")]
+    UserRegistered { user: User },
+    #[serde(rename = "group.created")]
+    GroupCreated { group: Group, created_by: String },
+    /// A private chat message
+    #[serde(rename = "message.sent")]
+    MessageSent { message: ChatMessage },
+    /// Client analytics events, one per flush when `analytics.sink` is `stream`
+    #[serde(rename = "analytics.events")]
+    AnalyticsEvents { events: Vec<AnalyticsRecord> },
+    /// For moderators; not sent to group webhooks even when it names a group
+    #[serde(rename = "report.created")]
+    ReportCreated { report: Report },
+}
+
+impl DomainEvent {
+    /// The wire name, as in the serialized `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            DomainEvent::GroupMessageCreated { .. } => "message.created",
+            DomainEvent::MemberJoined { .. } => "member.joined",
+            DomainEvent::UserRegistered { .. } => "user.registered",
+            DomainEvent::GroupCreated { .. } => "group.created",
+            DomainEvent::MessageSent { .. } => "message.sent",
+            DomainEvent::AnalyticsEvents { .. } => "analytics.events",
+            DomainEvent::ReportCreated { .. } => "report.created",
+        }
+    }
+
+    pub fn group_id(&self) -> Option<&str> {
+        match self {
+            DomainEvent::GroupMessageCreated { group_id, .. } => Some(group_id),
+            DomainEvent::MemberJoined { group_id, .. } => Some(group_id),
+            DomainEvent::GroupCreated { group, .. } => Some(&group.group_id),
+            DomainEvent::UserRegistered { .. }
+            | DomainEvent::MessageSent { .. }
+            | DomainEvent::AnalyticsEvents { .. }
+            | DomainEvent::ReportCreated { .. } => None,
+        }
+    }
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<DomainEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _rx) = broadcast::channel(1024);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: DomainEvent) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DomainEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Events not yet received by the slowest subscriber
+    pub fn backlog(&self) -> usize {
+        self.tx.len()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests_event_bus {
+    use crate::{
+        auth::user::User,
+        event_bus::{DomainEvent, EventBus},
+        websocket::group::GroupMessage,
+    };
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe();
+        bus.publish(DomainEvent::GroupMessageCreated {
+            group_id: "g1".to_string(),
+            message: GroupMessage {
+                id: "u1".to_string(),
+                name: "Jordan".to_string(),
+                message: "Hello".to_string(),
+            },
+        });
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.group_id(), Some("g1"));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], event.name());
+        assert_eq!(json["event"], "message.created");
+        assert_eq!(json["data"]["message"]["message"], "Hello");
+    }
+
+    #[test]
+    fn test_event_names() {
+        let user = User {
+            user_id: "u1".to_string(),
+            user_name: "Jordan".to_string(),
+            email: "jordan@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        };
+        let event = DomainEvent::UserRegistered { user };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "user.registered");
+        assert_eq!(event.name(), "user.registered");
+        assert_eq!(event.group_id(), None);
+    }
+}
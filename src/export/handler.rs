@@ -0,0 +1,254 @@
+use std::{future::Future, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::NaiveDateTime;
+use futures::{
+    SinkExt, StreamExt,
+    channel::mpsc::{self, Sender},
+    stream::BoxStream,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, QueryBuilder, Row, postgres::PgRow};
+use tracing::Instrument;
+
+use crate::{
+    app_state::AppState,
+    audit::handler::{self as audit, AuditQuery, parse_time},
+    auth::{user::UserFilter, util::MetaResponse},
+};
+
+/// Chunks serialized ahead of a slow client before reading more rows pauses
+const BUFFERED_CHUNKS: usize = 8;
+/// Lines are sent in chunks of about this size rather than one per row
+const CHUNK_BYTES: usize = 16 * 1024;
+
+type Chunk = Result<Bytes, Error>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserExport {
+    pub user_id: String,
+    pub user_name: String,
+    pub email: String,
+    pub role: String,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+/// Newline-delimited JSON download fed by `export`, which runs on its own
+/// task and writes into the body through a small bounded channel. Only a few
+/// chunks are ever held in memory, and a client that disconnects drops the
+/// receiver, which ends the task and its query.
+fn ndjson<F, Fut>(name: &str, export: F) -> Response
+where
+    F: FnOnce(Sender<Chunk>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(BUFFERED_CHUNKS);
+    tokio::spawn(export(tx).in_current_span());
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.ndjson\"", name),
+            ),
+        ],
+        Body::from_stream(rx),
+    )
+        .into_response()
+}
+
+/// Writes one JSON line per row. The status line has already gone out, so a
+/// failing query aborts the body and the client sees a truncated download.
+async fn pipe<T: Serialize>(mut rows: BoxStream<'_, Result<T, Error>>, mut tx: Sender<Chunk>) {
+    let mut chunk = BytesMut::with_capacity(CHUNK_BYTES);
+    while let Some(row) = rows.next().await {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                tracing::error!(error = %e, "Export failed");
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        if serde_json::to_writer((&mut chunk).writer(), &row).is_err() {
+            continue;
+        }
+        chunk.put_u8(b'\n');
+        if chunk.len() >= CHUNK_BYTES && tx.send(Ok(chunk.split().freeze())).await.is_err() {
+            tracing::debug!("Export client went away");
+            return;
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = tx.send(Ok(chunk.freeze())).await;
+    }
+}
+
+/// Every user matching the `/users` filters, oldest first
+pub async fn export_users_handler(
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<UserFilter>,
+) -> Response {
+    let pool = state.pool.clone();
+    ndjson("users", move |tx| async move {
+        let mut query = QueryBuilder::new(
+            "select user_id, user_name, email, role, created_at from users where true",
+        );
+        filter.push_conditions(&mut query);
+        query.push(" order by created_at, user_id");
+        let rows = query
+            .build()
+            .map(|data: PgRow| UserExport {
+                user_id: data.get("user_id"),
+                user_name: data.get("user_name"),
+                email: data.get("email"),
+                role: data.get("role"),
+                created_at: data
+                    .get::<NaiveDateTime, _>("created_at")
+                    .and_utc()
+                    .to_rfc3339(),
+            })
+            .fetch(&*pool);
+        pipe(rows, tx)
+            .instrument(tracing::info_span!("db.users.export"))
+            .await;
+    })
+}
+
+/// Every audit entry matching the `/admin/audit` filters, newest first.
+/// `page` is ignored.
+pub async fn export_audit_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuditQuery>,
+) -> Result<Response, MetaResponse> {
+    let from = parse_time(params.from.as_deref(), "from")?;
+    let to = parse_time(params.to.as_deref(), "to")?;
+    let pool = state.pool.clone();
+    Ok(ndjson("audit", move |tx| async move {
+        let rows = audit::export(
+            &pool,
+            params.actor_id.as_deref(),
+            params.action.as_deref(),
+            from,
+            to,
+        );
+        pipe(rows, tx)
+            .instrument(tracing::info_span!("db.audit_log.export"))
+            .await;
+    }))
+}
+
+#[cfg(test)]
+mod tests_export {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::random_name,
+        },
+        export::handler::{CHUNK_BYTES, UserExport},
+        routes::routes,
+    };
+
+    async fn admin(state: &AppState) -> (User, String) {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name, email, "123456".to_string()),
+        )
+        .await
+        .unwrap();
+        set_role(&user.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    fn lines<T: serde::de::DeserializeOwned>(text: &str) -> Vec<T> {
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_export_users() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (_, token) = admin(&state).await;
+
+        // Enough rows to span several chunks
+        sqlx::query(
+            "insert into users (user_id, user_name, email, password) \
+            select 'export-' || n, 'export_' || n, 'export' || n || '@mail.com', 'x' \
+            from generate_series(1, 500) n",
+        )
+        .execute(&*state.pool)
+        .await
+        .unwrap();
+
+        let response = server
+            .get("/api/v1/admin/export/users")
+            .add_query_param("user_name", "export_")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "application/x-ndjson");
+        assert!(response.as_bytes().len() > 2 * CHUNK_BYTES);
+        let users: Vec<UserExport> = lines(&response.text());
+        assert_eq!(users.len(), 500);
+        assert!(users.iter().all(|user| user.role == "user"));
+
+        let response = server
+            .get("/api/v1/admin/export/users")
+            .add_query_param("role", ADMIN_ROLE)
+            .add_header("Authorization", &token)
+            .await;
+        assert_eq!(lines::<UserExport>(&response.text()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_export_audit() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (admin, token) = admin(&state).await;
+
+        server
+            .get("/api/v1/admin/stats")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status_ok();
+
+        let response = server
+            .get("/api/v1/admin/export/audit")
+            .add_query_param("actor_id", &admin.user_id)
+            .add_query_param("action", "admin.stats")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let entries: Vec<serde_json::Value> = lines(&response.text());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["status"], 200);
+
+        let response = server
+            .get("/api/v1/admin/export/audit")
+            .add_query_param("from", "yesterday")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+}
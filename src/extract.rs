@@ -0,0 +1,103 @@
+use axum::{
+    Form, Json,
+    extract::{FromRequest, Request},
+    http::{HeaderMap, header},
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::ErrorResponse;
+
+/// Request body sent either as JSON or as a URL-encoded form, picked from
+/// `Content-Type`. Anything that isn't JSON is read as a form, so other
+/// content types still get the form rejection (415).
+pub struct JsonOrForm<T>(pub T);
+
+pub fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim())
+        .is_some_and(|mime| {
+            let mime = mime.to_ascii_lowercase();
+            mime == "application/json"
+                || (mime.starts_with("application/") && mime.ends_with("+json"))
+        })
+}
+
+impl<T, S> FromRequest<S> for JsonOrForm<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if is_json(req.headers()) {
+            let Json(value) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(|e| ErrorResponse::new(e.status(), e.body_text()))?;
+            return Ok(JsonOrForm(value));
+        }
+        let Form(value) = Form::<T>::from_request(req, state)
+            .await
+            .map_err(|e| ErrorResponse::new(e.status(), e.body_text()))?;
+        Ok(JsonOrForm(value))
+    }
+}
+
+#[cfg(test)]
+mod tests_extract {
+    use axum::{Router, http::StatusCode, routing::post};
+    use axum_test::TestServer;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{error::ErrorResponse, extract::JsonOrForm};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Login {
+        user_name: String,
+    }
+
+    fn server() -> TestServer {
+        let app = Router::new().route(
+            "/login",
+            post(|JsonOrForm(login): JsonOrForm<Login>| async move { login.user_name }),
+        );
+        TestServer::new(app).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_and_form() {
+        let server = server();
+        let body = Login {
+            user_name: "Jordan".to_string(),
+        };
+        server
+            .post("/login")
+            .json(&body)
+            .await
+            .assert_text("Jordan");
+        server
+            .post("/login")
+            .form(&body)
+            .await
+            .assert_text("Jordan");
+        server
+            .post("/login")
+            .text(r#"{"user_name":"Jordan"}"#)
+            .content_type("application/json; charset=utf-8")
+            .await
+            .assert_text("Jordan");
+    }
+
+    #[tokio::test]
+    async fn test_other_content_type_rejected() {
+        let response = server()
+            .post("/login")
+            .text("user_name=Jordan")
+            .content_type("text/plain")
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(response.json::<ErrorResponse>().meta.code, 415);
+    }
+}
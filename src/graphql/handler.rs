@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use async_graphql::{
+    Context, Data, EmptyMutation, Error, Object, Result, Schema, Subscription,
+    futures_util::{Stream, StreamExt},
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::{
+    Extension,
+    extract::{State, WebSocketUpgrade},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    AppState,
+    auth::{
+        extractors::AuthUser,
+        jwt::Claims,
+        user::{User, UserFilter},
+    },
+    group::handler::{Group, GroupFilter},
+    metrics::Channel,
+    pagination::Pagination,
+    websocket::{chat::ChatMessage, event::ServerEvent, group::GroupMessage, sse::event_stream},
+};
+
+pub type ApiSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+pub fn build_schema(state: Arc<AppState>) -> ApiSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(state)
+        .finish()
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn me(&self, ctx: &Context<'_>) -> Result<User> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let claims = ctx.data::<Claims>()?;
+        state
+            .user_cache
+            .get_user(&claims.user_id, &state.pool)
+            .await
+            .ok_or_else(|| Error::new("User not found"))
+    }
+
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 1)] page: i32,
+        user_name: Option<String>,
+    ) -> Result<Vec<User>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let filter = UserFilter {
+            user_name,
+            ..UserFilter::default()
+        };
+        let result = state
+            .users
+            .get_users(&Pagination::page(page), &filter)
+            .await?;
+        Ok(result.data)
+    }
+
+    async fn groups(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 1)] page: i32,
+    ) -> Result<Vec<Group>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        Ok(state
+            .groups
+            .get_all(None, &GroupFilter::default(), &Pagination::page(page))
+            .await?)
+    }
+
+    async fn group(&self, ctx: &Context<'_>, group_id: String) -> Result<Option<Group>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        Ok(state.groups.get_by_id(&group_id).await)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Private messages sent to or by the authenticated user
+    async fn chat_messages(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = ChatMessage>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let claims = ctx.data::<Claims>()?;
+        let events = event_stream(state, &claims.user_id).await;
+        Ok(events.filter_map(|msg| async move {
+            match serde_json::from_str::<ServerEvent>(&msg) {
+                Ok(ServerEvent::ChatMessage(chat)) => Some(chat),
+                _ => None,
+            }
+        }))
+    }
+
+    /// Messages broadcast over group chat
+    async fn group_messages(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = GroupMessage>> {
+        let state = ctx.data::<Arc<AppState>>()?;
+        let claims = ctx.data::<Claims>()?;
+        let events = event_stream(state, &claims.user_id).await;
+        Ok(events.filter_map(|msg| async move {
+            match serde_json::from_str::<ServerEvent>(&msg) {
+                Ok(ServerEvent::GroupMessage(group)) => Some(group),
+                _ => None,
+            }
+        }))
+    }
+}
+
+pub async fn graphql_handler(
+    AuthUser(user): AuthUser,
+    Extension(schema): Extension<ApiSchema>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(req.into_inner().data(user)).await.into()
+}
+
+pub async fn graphql_ws_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Extension(schema): Extension<ApiSchema>,
+    protocol: GraphQLProtocol,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.protocols(async_graphql::http::ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| async move {
+            let _connection = state.metrics.connection(Channel::GraphqlWs);
+            let mut data = Data::default();
+            data.insert(user);
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .with_data(data)
+                .serve()
+                .await
+        })
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests_graphql {
+    use std::sync::Arc;
+
+    use async_graphql::{Request, futures_util::StreamExt};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::{Claims, TokenType},
+            user::{NewUser, add},
+            util::{hash_password, random_name},
+        },
+        graphql::handler::build_schema,
+        routes::routes,
+        websocket::chat::send_to_user,
+    };
+
+    fn claims(user_id: &str) -> Claims {
+        Claims {
+            sub: user_id.to_string(),
+            exp: 0,
+            iat: 0,
+            user_id: user_id.to_string(),
+            email: format!("{}@mail.com", user_id),
+            org_id: None,
+            scopes: None,
+            token_type: Some(TokenType::Access),
+            jti: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_me_and_groups() {
+        let state = Arc::new(AppState::test().await);
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name.clone(), email, hash))
+            .await
+            .unwrap();
+
+        let schema = build_schema(state);
+        let request = Request::new("{ me { userId userName } groups(page: 1) { groupId name } }")
+            .data(claims(&user.user_id));
+        let response = schema.execute(request).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        let json = response.data.into_json().unwrap();
+        assert_eq!(json["me"]["userId"], user.user_id);
+        assert_eq!(json["me"]["userName"], user_name);
+        assert!(json["groups"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_chat_messages() {
+        let state = Arc::new(AppState::test().await);
+        let schema = build_schema(state.clone());
+
+        let request = Request::new("subscription { chatMessages { message } }")
+            .data(claims("graphql-receiver"));
+        let mut stream = schema.execute_stream(request);
+
+        let sender = crate::auth::user::User {
+            user_id: "graphql-sender".to_string(),
+            user_name: "sender".to_string(),
+            email: "sender@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        };
+        let receiver = crate::auth::user::User {
+            user_id: "graphql-receiver".to_string(),
+            user_name: "receiver".to_string(),
+            email: "receiver@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        };
+
+        // Poll once so the subscription registers before the message is sent
+        let next = tokio::spawn(async move { stream.next().await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        send_to_user(&state.chat, &sender, &receiver, "Hello").await;
+
+        let response = next.await.unwrap().unwrap();
+        let json = response.data.into_json().unwrap();
+        assert_eq!(json["chatMessages"]["message"], "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_graphql_unauthorized() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server
+            .post("/graphql")
+            .json(&serde_json::json!({ "query": "{ me { userId } }" }))
+            .await;
+        response.assert_status_unauthorized();
+    }
+}
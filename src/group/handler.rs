@@ -1,24 +1,70 @@
 use std::sync::Arc;
 
+use async_graphql::SimpleObject;
+
 use axum::{
-    Form,
-    extract::{Path, State},
+    extract::{Query, State},
     response::{IntoResponse, Json},
 };
-use http::StatusCode;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use http::{StatusCode, header};
 use serde::{Deserialize, Serialize};
 use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use validator::Validate;
 
 use crate::{
     app_state::AppState,
-    auth::util::{MetaResponse, StatusCodeExt},
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt, utc_rfc3339},
+    },
+    event_bus::DomainEvent,
+    group::service::{GroupPage, GroupService},
+    http_cache::last_modified,
+    normalize::{de_name, de_text_opt},
+    outbox::enqueue,
+    pagination::Pagination,
+    validation::Validated,
 };
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
+#[derive(Debug, Serialize, Clone, Deserialize, SimpleObject)]
 pub struct Group {
     pub group_id: String,
     pub name: String,
     pub description: Option<String>,
+    /// Set for groups that belong to an organization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    /// RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// Last change to the group row, absent until the first one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// Optional filters of the group listing
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct GroupFilter {
+    /// Created at or after
+    pub created_after: Option<DateTime<Utc>>,
+    /// Created before
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+const GROUP_COLUMNS: &str = "group_id, name, description, org_id, created_at, updated_at";
+
+fn group_from_row(data: PgRow) -> Group {
+    Group {
+        group_id: data.get("group_id"),
+        name: data.get("name"),
+        description: data.get("description"),
+        org_id: data.get("org_id"),
+        created_at: Some(utc_rfc3339(data.get("created_at"))),
+        updated_at: data
+            .get::<Option<NaiveDateTime>, _>("updated_at")
+            .map(utc_rfc3339),
+    }
 }
 
 impl IntoResponse for Group {
@@ -34,6 +80,18 @@ pub struct GroupResponse {
     pub data: Group,
 }
 
+impl From<Group> for GroupResponse {
+    fn from(group: Group) -> Self {
+        Self {
+            meta: MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: "Success".to_string(),
+            },
+            data: group,
+        }
+    }
+}
+
 impl IntoResponse for GroupResponse {
     fn into_response(self) -> axum::response::Response {
         let status = StatusCode::OK;
@@ -44,23 +102,75 @@ impl IntoResponse for GroupResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GroupsResponse {
     pub meta: MetaResponse,
+    /// Pass as `cursor` to fetch the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
     pub data: Vec<Group>,
 }
 
+impl From<GroupPage> for GroupsResponse {
+    fn from(page: GroupPage) -> Self {
+        Self {
+            meta: MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: "Success".to_string(),
+            },
+            next_cursor: page.next_cursor,
+            data: page.groups,
+        }
+    }
+}
+
 impl IntoResponse for GroupsResponse {
     fn into_response(self) -> axum::response::Response {
         let status = StatusCode::OK;
-        (status, Json(self)).into_response()
+        let last_modified = last_modified(
+            self.data
+                .iter()
+                .map(|group| group.updated_at.as_deref().or(group.created_at.as_deref())),
+        );
+        let mut response = (status, Json(self)).into_response();
+        if let Some(value) = last_modified {
+            response.headers_mut().insert(header::LAST_MODIFIED, value);
+        }
+        response
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct GroupParam {
+    #[serde(deserialize_with = "de_name")]
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
     pub name: String,
+    #[serde(default, deserialize_with = "de_text_opt")]
+    #[validate(length(max = 500, message = "must be at most 500 characters"))]
     pub description: Option<String>,
 }
 
 pub async fn create(pool: &Pool<Postgres>, name: &str, desc: &str) -> Result<Group, Error> {
+    insert(pool, name, desc, None, None).await
+}
+
+/// Creates a group on behalf of `created_by`, inside `org_id` when set, and
+/// queues `group.created` in the outbox by the same transaction
+pub async fn create_by(
+    pool: &Pool<Postgres>,
+    name: &str,
+    desc: &str,
+    org_id: Option<&str>,
+    created_by: &str,
+) -> Result<Group, Error> {
+    insert(pool, name, desc, org_id, Some(created_by)).await
+}
+
+#[tracing::instrument(name = "db.groups.create", skip(pool))]
+async fn insert(
+    pool: &Pool<Postgres>,
+    name: &str,
+    desc: &str,
+    org_id: Option<&str>,
+    created_by: Option<&str>,
+) -> Result<Group, Error> {
     let mut tx = pool.begin().await?;
     let group_id = uuid::Uuid::new_v4().to_string();
     let description = if !desc.is_empty() {
@@ -69,94 +179,110 @@ pub async fn create(pool: &Pool<Postgres>, name: &str, desc: &str) -> Result<Gro
         "".to_string()
     };
 
-    let sql = "insert into groups (group_id, name, description) values ($1, $2, $3)";
-    sqlx::query(sql)
+    let sql = "insert into groups (group_id, name, description, org_id, created_by) \
+               values ($1, $2, $3, $4, $5) returning created_at";
+    let created_at: NaiveDateTime = sqlx::query_scalar(sql)
         .bind(group_id.clone())
         .bind(name)
         .bind(description.clone())
-        .execute(&mut *tx)
+        .bind(org_id)
+        .bind(created_by)
+        .fetch_one(&mut *tx)
         .await?;
 
-    tx.commit().await?;
-    Ok(Group {
-        group_id: group_id,
+    let group = Group {
+        group_id,
         name: name.to_string(),
         description: Some(description),
-    })
+        org_id: org_id.map(str::to_string),
+        created_at: Some(utc_rfc3339(created_at)),
+        updated_at: None,
+    };
+    if let Some(created_by) = created_by {
+        let event = DomainEvent::GroupCreated {
+            group: group.clone(),
+            created_by: created_by.to_string(),
+        };
+        enqueue(&mut tx, &event).await?;
+    }
+    tx.commit().await?;
+    Ok(group)
 }
 
-pub async fn get_by_id(pool: &Pool<Postgres>, group_id: &str) -> Option<Group> {
-    let sql = "select group_id, name, description from groups where group_id = $1";
-    let result = sqlx::query(sql)
+/// Who created the group; `None` for groups from before that was recorded
+#[tracing::instrument(name = "db.groups.owner", skip(pool))]
+pub async fn owner(pool: &Pool<Postgres>, group_id: &str) -> Result<Option<String>, Error> {
+    sqlx::query_scalar("select created_by from groups where group_id = $1")
         .bind(group_id)
-        .map(|data: PgRow| Group {
-            group_id: data.get("group_id"),
-            name: data.get("name"),
-            description: data.get("description"),
-        })
         .fetch_optional(pool)
         .await
-        .unwrap_or_default();
+        .map(Option::flatten)
+}
 
-    result
+#[tracing::instrument(name = "db.groups.get_by_id", skip(pool))]
+pub async fn get_by_id(pool: &Pool<Postgres>, group_id: &str) -> Option<Group> {
+    let sql = format!("select {} from groups where group_id = $1", GROUP_COLUMNS);
+    sqlx::query(&sql)
+        .bind(group_id)
+        .map(group_from_row)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_default()
 }
 
-pub async fn get_all(pool: &Pool<Postgres>, page: i32) -> Result<Vec<Group>, Error> {
-    let sql =
-        "select group_id, name, description from groups order by name desc limit 10 offset $1";
-    let offset = if page > 0 { (page - 1) * 10 } else { 0 };
+/// Groups outside any organization, or those of `org_id`
+#[tracing::instrument(name = "db.groups.get_all", skip(pool))]
+pub async fn get_all(
+    pool: &Pool<Postgres>,
+    org_id: Option<&str>,
+    filter: &GroupFilter,
+    pagination: &Pagination,
+) -> Result<Vec<Group>, Error> {
+    let sql = format!(
+        "select {} from groups \
+        where org_id is not distinct from $4 \
+        and ($1::text is null or name {} $1) \
+        and ($5::timestamp is null or created_at >= $5) \
+        and ($6::timestamp is null or created_at < $6) \
+        order by name {} limit $2 offset $3",
+        GROUP_COLUMNS,
+        pagination.sort.after(),
+        pagination.sort.sql()
+    );
 
-    let groups = sqlx::query(sql)
-        .bind(offset)
-        .map(|data: PgRow| Group {
-            group_id: data.get("group_id"),
-            name: data.get("name"),
-            description: data.get("description"),
-        })
+    let groups = sqlx::query(&sql)
+        .bind(pagination.cursor.as_deref())
+        .bind(pagination.limit())
+        .bind(pagination.offset())
+        .bind(org_id)
+        .bind(filter.created_after.map(|after| after.naive_utc()))
+        .bind(filter.created_before.map(|before| before.naive_utc()))
+        .map(group_from_row)
         .fetch_all(pool)
         .await?;
     Ok(groups)
 }
 
 pub async fn create_group_handler(
+    AuthUser(user): AuthUser,
     State(state): State<Arc<AppState>>,
-    Form(req): Form<GroupParam>,
+    Validated(req): Validated<GroupParam>,
 ) -> Result<GroupResponse, MetaResponse> {
-    let result = create(
-        &state.pool,
-        &req.name,
-        req.description.as_deref().unwrap_or(""),
-    )
-    .await
-    .map_err(|e| MetaResponse {
-        code: StatusCode::BAD_REQUEST.to_i32(),
-        message: e.to_string(),
-    })?;
-    Ok(GroupResponse {
-        meta: MetaResponse {
-            code: StatusCode::OK.to_i32(),
-            message: "Success".to_string(),
-        },
-        data: result,
-    })
+    let group = GroupService::new(&state)
+        .create(&user.user_id, req, None)
+        .await?;
+    Ok(group.into())
 }
 
 pub async fn groups_handler(
     State(state): State<Arc<AppState>>,
-    Path(page): Path<i32>,
+    Query(filter): Query<GroupFilter>,
+    pagination: Pagination,
 ) -> Result<GroupsResponse, MetaResponse> {
-    let result = get_all(&state.pool, page).await.map_err(|e| MetaResponse {
-        code: StatusCode::BAD_REQUEST.to_i32(),
-        message: e.to_string(),
-    })?;
-    println!("{:?}", result);
-    Ok(GroupsResponse {
-        meta: MetaResponse {
-            code: StatusCode::OK.to_i32(),
-            message: "Success".to_string(),
-        },
-        data: result,
-    })
+    let page = GroupService::new(&state)
+        .list(None, &filter, &pagination)
+        .await?;
+    Ok(page.into())
 }
 
 #[cfg(test)]
@@ -164,58 +290,169 @@ mod tests_group {
     use std::sync::Arc;
 
     use axum::{
-        Router,
+        Extension, Router,
         routing::{get, post},
     };
-    use axum_test::TestServer;
+    use axum_test::{TestResponse, TestServer};
+    use chrono::{Duration, SecondsFormat, Utc};
     use http::StatusCode;
 
     use crate::{
         app_state::AppState,
-        auth::{jwt::Secret, util::random_name},
-        config::connection::ConnectionBuilder,
-        group::handler::{GroupParam, create_group_handler, groups_handler},
+        auth::{
+            jwt::{Claims, TokenType},
+            util::random_name,
+        },
+        event_bus::DomainEvent,
+        group::handler::{
+            GroupParam, GroupsResponse, create, create_group_handler, get_by_id, groups_handler,
+        },
+        outbox::relay_batch,
     };
 
     #[tokio::test]
     async fn test_create_new() {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder)
-            .await
-            .expect("Failed to connect database");
-
-        let secret_key = Secret::new("dev.toml");
-        let state = Arc::new(AppState::new(pool, secret_key));
+        let state = AppState::isolated().await;
+        let mut events = state.events.subscribe();
 
+        // Stands in for auth_middleware
+        let claims = Claims {
+            sub: "user-1".to_string(),
+            exp: 0,
+            iat: 0,
+            user_id: "user-1".to_string(),
+            email: "jordan@mail.com".to_string(),
+            org_id: None,
+            scopes: None,
+            token_type: Some(TokenType::Access),
+            jti: None,
+        };
         let app = Router::new()
             .route("/api/groups", post(create_group_handler))
-            .with_state(state);
+            .layer(Extension(claims))
+            .with_state(state.clone());
         let name = random_name();
         let body = GroupParam {
-            name: name,
+            name: name.clone(),
             description: Some("".to_string()),
         };
         let server = TestServer::new(app).expect("Failed start server");
         let response = server.post("/api/groups").form(&body).await;
         assert_eq!(response.status_code(), StatusCode::OK);
+
+        // The event reaches the bus through the outbox
+        assert!(events.try_recv().is_err());
+        relay_batch(&state.pool, &state.events, 10).await.unwrap();
+        match events.recv().await.unwrap() {
+            DomainEvent::GroupCreated { group, created_by } => {
+                assert_eq!(group.name, name);
+                assert_eq!(created_by, "user-1");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
     }
 
     #[tokio::test]
     async fn test_get_all() {
-        let builder = ConnectionBuilder(String::from("dev.toml"));
-        let pool = ConnectionBuilder::new(&builder)
-            .await
-            .expect("Failed to connect database");
-
-        let secret_key = Secret::new("dev.toml");
-        let state = Arc::new(AppState::new(pool, secret_key));
+        let state = Arc::new(AppState::test().await);
 
         let app = Router::new()
-            .route("/api/groups/{page}", get(groups_handler))
+            .route("/api/groups", get(groups_handler))
             .with_state(state);
 
         let server = TestServer::new(app).expect("Failed start server");
-        let response = server.get("/api/groups/1").await;
+        let response = server.get("/api/groups?page=1&per_page=5").await;
         assert_eq!(response.status_code(), StatusCode::OK);
+        assert!(response.json::<GroupsResponse>().data.len() <= 5);
+
+        let response = server.get("/api/groups?per_page=0").await;
+        assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_with_memory_repository() {
+        let state = Arc::new(AppState::fake().await);
+        let claims = Claims {
+            sub: "user-1".to_string(),
+            exp: 0,
+            iat: 0,
+            user_id: "user-1".to_string(),
+            email: "jordan@mail.com".to_string(),
+            org_id: None,
+            scopes: None,
+            token_type: Some(TokenType::Access),
+            jti: None,
+        };
+        let app = Router::new()
+            .route(
+                "/api/groups",
+                post(create_group_handler).get(groups_handler),
+            )
+            .layer(Extension(claims))
+            .with_state(state);
+        let server = TestServer::new(app).expect("Failed start server");
+
+        for name in ["alpha", "beta", "gamma"] {
+            let body = GroupParam {
+                name: name.to_string(),
+                description: None,
+            };
+            server
+                .post("/api/groups")
+                .form(&body)
+                .await
+                .assert_status_ok();
+        }
+
+        let response = server.get("/api/groups?per_page=2&sort=asc").await;
+        let body = response.json::<GroupsResponse>();
+        let names: Vec<_> = body.data.iter().map(|group| group.name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "beta"]);
+        assert_eq!(body.next_cursor.as_deref(), Some("beta"));
+
+        let response = server.get("/api/groups?cursor=beta&sort=asc").await;
+        let body = response.json::<GroupsResponse>();
+        assert_eq!(body.data.len(), 1);
+        assert_eq!(body.data[0].name, "gamma");
+    }
+
+    #[tokio::test]
+    async fn test_timestamps() {
+        let state = AppState::isolated().await;
+        let group = create(&state.pool, "stamped", "").await.unwrap();
+        assert!(group.created_at.is_some());
+        assert!(group.updated_at.is_none());
+
+        // Any update stamps updated_at, through the trigger
+        sqlx::query("update groups set description = 'changed' where group_id = $1")
+            .bind(&group.group_id)
+            .execute(&*state.pool)
+            .await
+            .unwrap();
+        let group = get_by_id(&state.pool, &group.group_id).await.unwrap();
+        assert!(group.updated_at.is_some());
+
+        let app = Router::new()
+            .route("/api/groups", get(groups_handler))
+            .with_state(state.clone());
+        let server = TestServer::new(app).expect("Failed start server");
+        let hour_ago = (Utc::now() - Duration::hours(1)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let names = |response: TestResponse| -> Vec<String> {
+            let body = response.json::<GroupsResponse>();
+            body.data.into_iter().map(|group| group.name).collect()
+        };
+
+        let response = server
+            .get(&format!("/api/groups?created_after={}", hour_ago))
+            .await;
+        assert_eq!(names(response), vec!["stamped"]);
+        let response = server
+            .get(&format!("/api/groups?created_before={}", hour_ago))
+            .await;
+        assert!(names(response).is_empty());
+        server
+            .get("/api/groups?created_after=yesterday")
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
     }
 }
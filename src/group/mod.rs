@@ -1 +1,3 @@
 pub mod handler;
+pub mod repository;
+pub mod service;
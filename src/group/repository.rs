@@ -0,0 +1,150 @@
+use async_trait::async_trait;
+use sqlx::{Error, Pool, Postgres};
+
+use crate::{
+    config::connection::read_with_fallback,
+    group::handler::{Group, GroupFilter, create_by, get_all, get_by_id},
+    pagination::Pagination,
+};
+
+/// Group storage used by the handlers. `PgGroupRepository` is the real one;
+/// tests can swap in `MemoryGroupRepository` through `AppState::groups`.
+#[async_trait]
+pub trait GroupRepository: Send + Sync {
+    /// Creates a global group, or one inside `org_id`. The Postgres
+    /// repository queues `group.created` in the outbox with it.
+    async fn create(
+        &self,
+        name: &str,
+        description: &str,
+        org_id: Option<&str>,
+        created_by: &str,
+    ) -> Result<Group, Error>;
+    async fn get_by_id(&self, group_id: &str) -> Option<Group>;
+    /// Groups outside any organization, or those of `org_id`
+    async fn get_all(
+        &self,
+        org_id: Option<&str>,
+        filter: &GroupFilter,
+        pagination: &Pagination,
+    ) -> Result<Vec<Group>, Error>;
+}
+
+/// Writes go to the primary, listings to the replica when there is one
+pub struct PgGroupRepository {
+    pool: Pool<Postgres>,
+    replica: Option<Pool<Postgres>>,
+}
+
+impl PgGroupRepository {
+    pub fn new(pool: Pool<Postgres>, replica: Option<Pool<Postgres>>) -> Self {
+        Self { pool, replica }
+    }
+}
+
+#[async_trait]
+impl GroupRepository for PgGroupRepository {
+    async fn create(
+        &self,
+        name: &str,
+        description: &str,
+        org_id: Option<&str>,
+        created_by: &str,
+    ) -> Result<Group, Error> {
+        create_by(&self.pool, name, description, org_id, created_by).await
+    }
+
+    async fn get_by_id(&self, group_id: &str) -> Option<Group> {
+        get_by_id(&self.pool, group_id).await
+    }
+
+    async fn get_all(
+        &self,
+        org_id: Option<&str>,
+        filter: &GroupFilter,
+        pagination: &Pagination,
+    ) -> Result<Vec<Group>, Error> {
+        read_with_fallback(&self.pool, self.replica.as_ref(), |pool| async move {
+            get_all(&pool, org_id, filter, pagination).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+pub use fake::MemoryGroupRepository;
+
+#[cfg(test)]
+mod fake {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use sqlx::Error;
+    use uuid::Uuid;
+
+    use crate::{
+        group::{
+            handler::{Group, GroupFilter},
+            repository::GroupRepository,
+        },
+        pagination::Pagination,
+    };
+
+    /// In-memory groups for handler tests that don't need Postgres
+    #[derive(Default)]
+    pub struct MemoryGroupRepository {
+        groups: Mutex<Vec<Group>>,
+    }
+
+    #[async_trait]
+    impl GroupRepository for MemoryGroupRepository {
+        async fn create(
+            &self,
+            name: &str,
+            description: &str,
+            org_id: Option<&str>,
+            _created_by: &str,
+        ) -> Result<Group, Error> {
+            let mut groups = self.groups.lock().unwrap();
+            if groups.iter().any(|group| group.name == name) {
+                return Err(Error::Protocol("duplicate group name".to_string()));
+            }
+            let group = Group {
+                group_id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                org_id: org_id.map(str::to_string),
+                created_at: None,
+                updated_at: None,
+            };
+            groups.push(group.clone());
+            Ok(group)
+        }
+
+        async fn get_by_id(&self, group_id: &str) -> Option<Group> {
+            let groups = self.groups.lock().unwrap();
+            groups
+                .iter()
+                .find(|group| group.group_id == group_id)
+                .cloned()
+        }
+
+        async fn get_all(
+            &self,
+            org_id: Option<&str>,
+            // Memory groups carry no timestamps to filter on
+            _filter: &GroupFilter,
+            pagination: &Pagination,
+        ) -> Result<Vec<Group>, Error> {
+            let matching: Vec<Group> = self
+                .groups
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|group| group.org_id.as_deref() == org_id)
+                .cloned()
+                .collect();
+            Ok(pagination.slice(matching, |group| group.name.as_str()))
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use axum::http::StatusCode;
+
+use crate::{
+    app_state::AppState,
+    auth::util::{MetaResponse, StatusCodeExt},
+    group::{
+        handler::{Group, GroupFilter, GroupParam},
+        repository::GroupRepository,
+    },
+    pagination::Pagination,
+};
+
+#[derive(Debug)]
+pub enum GroupError {
+    Storage(String),
+}
+
+impl From<GroupError> for MetaResponse {
+    fn from(error: GroupError) -> Self {
+        match error {
+            GroupError::Storage(message) => MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message,
+            },
+        }
+    }
+}
+
+/// One page of a group listing
+#[derive(Debug)]
+pub struct GroupPage {
+    pub groups: Vec<Group>,
+    pub next_cursor: Option<String>,
+}
+
+/// Group rules shared by the global and organization endpoints
+pub struct GroupService {
+    groups: Arc<dyn GroupRepository>,
+}
+
+impl GroupService {
+    pub fn new(state: &AppState) -> Self {
+        Self {
+            groups: state.groups.clone(),
+        }
+    }
+
+    /// Creates the group (inside `org_id` when set); the repository
+    /// announces it through the outbox
+    pub async fn create(
+        &self,
+        created_by: &str,
+        param: GroupParam,
+        org_id: Option<&str>,
+    ) -> Result<Group, GroupError> {
+        let group = self
+            .groups
+            .create(
+                &param.name,
+                param.description.as_deref().unwrap_or(""),
+                org_id,
+                created_by,
+            )
+            .await
+            .map_err(|e| GroupError::Storage(e.to_string()))?;
+        Ok(group)
+    }
+
+    pub async fn list(
+        &self,
+        org_id: Option<&str>,
+        filter: &GroupFilter,
+        pagination: &Pagination,
+    ) -> Result<GroupPage, GroupError> {
+        let groups = self
+            .groups
+            .get_all(org_id, filter, pagination)
+            .await
+            .map_err(|e| GroupError::Storage(e.to_string()))?;
+        tracing::debug!(
+            page = pagination.page,
+            count = groups.len(),
+            "Fetched groups"
+        );
+        Ok(GroupPage {
+            next_cursor: pagination
+                .next_cursor(groups.len(), groups.last().map(|group| group.name.as_str())),
+            groups,
+        })
+    }
+}
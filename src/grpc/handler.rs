@@ -0,0 +1,544 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tonic::{
+    Request, Response, Status,
+    service::Interceptor,
+    transport::{Server, server::TcpIncoming},
+};
+use validator::Validate;
+
+use crate::{
+    AppState,
+    auth::{
+        jwt::{Claims, JwtConfig, verify_access_token},
+        user::{User, UserFilter},
+    },
+    group::{
+        handler::{Group, GroupFilter, GroupParam},
+        service::{GroupError, GroupService},
+    },
+    grpc::proto::{
+        self,
+        groups_server::{Groups, GroupsServer},
+        message::Kind,
+        messages_server::{Messages, MessagesServer},
+        users_server::{Users, UsersServer},
+    },
+    message::handler::{SendError, send_group, send_private},
+    metrics::Channel,
+    pagination::Pagination,
+    websocket::{chat::ChatMessage, event::ServerEvent, group::GroupMessage, sse::event_stream},
+};
+
+/// Settings from the `[grpc]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GrpcSettings {
+    pub enabled: bool,
+    /// Served on the same `tcp.ip` as REST
+    pub port: u16,
+}
+
+impl Default for GrpcSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 50051,
+        }
+    }
+}
+
+/// Checks the bearer token in the `authorization` metadata and hands its
+/// claims to the services in the request extensions, like `auth_middleware`.
+/// Interceptors can't wait on the revocation store, so the token goes along
+/// too and `GrpcApi::authenticated` checks it.
+#[derive(Clone)]
+pub struct Authenticate {
+    jwt_config: Arc<JwtConfig>,
+}
+
+/// The bearer token the request's `Claims` came from
+#[derive(Clone)]
+struct BearerToken(String);
+
+impl Interceptor for Authenticate {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .ok_or_else(|| Status::unauthenticated("Missing or invalid authorization metadata"))?;
+        let claims = verify_access_token(&self.jwt_config, &token)
+            .map_err(|_| Status::unauthenticated("Invalid or expired token"))?;
+        request.extensions_mut().insert(claims);
+        request.extensions_mut().insert(BearerToken(token));
+        Ok(request)
+    }
+}
+
+/// The Users, Groups and Messages services, on top of the same repositories,
+/// services and chat channels as the REST and WebSocket handlers
+#[derive(Clone)]
+pub struct GrpcApi {
+    state: Arc<AppState>,
+}
+
+impl GrpcApi {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+
+    /// The caller's claims, unless the token was revoked by `/auth/logout`
+    async fn authenticated<'a, T>(&self, request: &'a Request<T>) -> Result<&'a Claims, Status> {
+        let claims = claims(request)?;
+        let token = request
+            .extensions()
+            .get::<BearerToken>()
+            .ok_or_else(|| Status::unauthenticated("Missing credentials"))?;
+        match self.state.revoked_tokens.is_revoked(&token.0).await {
+            Ok(false) => Ok(claims),
+            Ok(true) => Err(Status::unauthenticated("Token has been revoked")),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    /// The caller, who must still exist
+    async fn caller<T>(&self, request: &Request<T>) -> Result<User, Status> {
+        let claims = self.authenticated(request).await?;
+        self.state
+            .user_cache
+            .get_user(&claims.user_id, &self.state.pool)
+            .await
+            .ok_or_else(|| Status::unauthenticated("Unknown user"))
+    }
+}
+
+fn claims<T>(request: &Request<T>) -> Result<&Claims, Status> {
+    request
+        .extensions()
+        .get::<Claims>()
+        .ok_or_else(|| Status::unauthenticated("Missing credentials"))
+}
+
+/// Proto3 has no optional strings, an empty one means unset
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+/// Same defaults and bounds as the `page`, `per_page` and `cursor` query
+/// parameters, with 0 meaning unset
+fn pagination(page: u32, per_page: u32, cursor: String) -> Result<Pagination, Status> {
+    let default = Pagination::default();
+    let pagination = Pagination {
+        page: if page == 0 { default.page } else { page },
+        per_page: if per_page == 0 {
+            default.per_page
+        } else {
+            per_page
+        },
+        cursor: non_empty(cursor),
+        ..default
+    };
+    pagination
+        .validate()
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    Ok(pagination)
+}
+
+impl From<GroupError> for Status {
+    fn from(error: GroupError) -> Self {
+        match error {
+            GroupError::Storage(message) => Status::invalid_argument(message),
+        }
+    }
+}
+
+impl From<SendError> for Status {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::GroupNotFound => Status::not_found("Unknown group_id"),
+            SendError::ReceiverNotFound => Status::not_found("Unknown receiver_id"),
+            SendError::NotMember => Status::permission_denied("Not a member of this organization"),
+            SendError::Muted => Status::permission_denied("You are muted"),
+            SendError::Banned => Status::permission_denied("You are banned from this group"),
+            SendError::Blocked => {
+                Status::invalid_argument("Your message was blocked by the group's word filter")
+            }
+            SendError::Storage(message) => Status::internal(message),
+        }
+    }
+}
+
+impl From<User> for proto::User {
+    fn from(user: User) -> Self {
+        Self {
+            user_id: user.user_id,
+            user_name: user.user_name,
+            email: user.email,
+            is_bot: user.is_bot,
+        }
+    }
+}
+
+impl From<Group> for proto::Group {
+    fn from(group: Group) -> Self {
+        Self {
+            group_id: group.group_id,
+            name: group.name,
+            description: group.description.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ChatMessage> for proto::ChatMessage {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            sender: Some(message.sender_user.into()),
+            receiver: Some(message.receiver_user.into()),
+            message: message.message,
+            timestamp: message.timestamp,
+        }
+    }
+}
+
+impl From<GroupMessage> for proto::GroupMessage {
+    fn from(message: GroupMessage) -> Self {
+        Self {
+            id: message.id,
+            name: message.name,
+            message: message.message,
+        }
+    }
+}
+
+/// A `ServerEvent` payload from the chat channels, when it is a message
+fn message(json: &str) -> Option<proto::Message> {
+    let kind = match serde_json::from_str::<ServerEvent>(json).ok()? {
+        ServerEvent::ChatMessage(chat) => Kind::Chat(chat.into()),
+        ServerEvent::GroupMessage(group) => Kind::Group(group.into()),
+        _ => return None,
+    };
+    Some(proto::Message { kind: Some(kind) })
+}
+
+#[tonic::async_trait]
+impl Users for GrpcApi {
+    async fn get_me(
+        &self,
+        request: Request<proto::GetMeRequest>,
+    ) -> Result<Response<proto::User>, Status> {
+        let user = self.caller(&request).await?;
+        Ok(Response::new(user.into()))
+    }
+
+    async fn list_users(
+        &self,
+        request: Request<proto::ListUsersRequest>,
+    ) -> Result<Response<proto::ListUsersResponse>, Status> {
+        self.authenticated(&request).await?;
+        let req = request.into_inner();
+        let pagination = pagination(req.page, req.per_page, req.cursor)?;
+        let filter = UserFilter {
+            user_name: non_empty(req.user_name),
+            email: non_empty(req.email),
+            role: non_empty(req.role),
+            ..UserFilter::default()
+        };
+        let result = self
+            .state
+            .users
+            .get_users(&pagination, &filter)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(proto::ListUsersResponse {
+            users: result.data.into_iter().map(Into::into).collect(),
+            next_cursor: result.next_cursor.unwrap_or_default(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Groups for GrpcApi {
+    async fn create_group(
+        &self,
+        request: Request<proto::CreateGroupRequest>,
+    ) -> Result<Response<proto::Group>, Status> {
+        let created_by = self.authenticated(&request).await?.user_id.clone();
+        let req = request.into_inner();
+        let param = GroupParam {
+            name: req.name,
+            description: non_empty(req.description),
+        };
+        param
+            .validate()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let group = GroupService::new(&self.state)
+            .create(&created_by, param, None)
+            .await?;
+        Ok(Response::new(group.into()))
+    }
+
+    async fn list_groups(
+        &self,
+        request: Request<proto::ListGroupsRequest>,
+    ) -> Result<Response<proto::ListGroupsResponse>, Status> {
+        self.authenticated(&request).await?;
+        let req = request.into_inner();
+        let pagination = pagination(req.page, req.per_page, req.cursor)?;
+        let page = GroupService::new(&self.state)
+            .list(None, &GroupFilter::default(), &pagination)
+            .await?;
+        Ok(Response::new(proto::ListGroupsResponse {
+            groups: page.groups.into_iter().map(Into::into).collect(),
+            next_cursor: page.next_cursor.unwrap_or_default(),
+        }))
+    }
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<proto::Message, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Messages for GrpcApi {
+    type SubscribeStream = MessageStream;
+
+    async fn send_private_message(
+        &self,
+        request: Request<proto::SendPrivateMessageRequest>,
+    ) -> Result<Response<proto::ChatMessage>, Status> {
+        let sender = self.caller(&request).await?;
+        let req = request.into_inner();
+        let message = send_private(&self.state, &sender, &req.receiver_id, &req.message).await?;
+        Ok(Response::new(message.into()))
+    }
+
+    /// Sent as typed: slash commands belong to a `/group-chat` connection
+    async fn send_group_message(
+        &self,
+        request: Request<proto::SendGroupMessageRequest>,
+    ) -> Result<Response<proto::GroupMessage>, Status> {
+        let user = self.caller(&request).await?;
+        let req = request.into_inner();
+        let message = send_group(&self.state, &user, &req.group_id, &req.message).await?;
+        Ok(Response::new(message.into()))
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let user_id = self.authenticated(&request).await?.user_id.clone();
+        let connection = self.state.metrics.connection(Channel::Grpc);
+        let events = event_stream(&self.state, &user_id).await;
+        let stream = events.filter_map(move |msg| {
+            let _ = &connection;
+            async move { message(&msg).map(Ok) }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serves the gRPC API on `listener` until `shutdown` resolves
+pub async fn serve(
+    state: Arc<AppState>,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let api = GrpcApi::new(state.clone());
+    let auth = Authenticate {
+        jwt_config: state.jwt_config.clone(),
+    };
+    Server::builder()
+        .trace_fn(|request| tracing::info_span!("grpc", path = %request.uri().path()))
+        .add_service(UsersServer::with_interceptor(api.clone(), auth.clone()))
+        .add_service(GroupsServer::with_interceptor(api.clone(), auth.clone()))
+        .add_service(MessagesServer::with_interceptor(api, auth))
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod tests_grpc {
+    use std::{sync::Arc, time::Duration};
+
+    use axum_test::TestServer;
+    use futures::StreamExt;
+    use tokio::net::TcpListener;
+    use tonic::{Code, Request, transport::Channel};
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, User, add},
+            util::random_name,
+        },
+        grpc::{
+            handler::serve,
+            proto::{
+                GetMeRequest, ListUsersRequest, SendPrivateMessageRequest, SubscribeRequest,
+                message::Kind, messages_client::MessagesClient, users_client::UsersClient,
+            },
+        },
+        routes::routes,
+    };
+
+    async fn server(state: Arc<AppState>) -> Channel {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(state, listener, std::future::pending()));
+        Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    async fn user(state: &AppState) -> (User, String) {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name, email, "123456".to_string()),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    fn authorized<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", token.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let state = Arc::new(AppState::test().await);
+        let mut client = UsersClient::new(server(state.clone()).await);
+        let (user, token) = user(&state).await;
+
+        let status = client.get_me(GetMeRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let me = client
+            .get_me(authorized(GetMeRequest {}, &token))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(me.user_id, user.user_id);
+
+        let users = client
+            .list_users(authorized(
+                ListUsersRequest {
+                    user_name: user.user_name.clone(),
+                    ..Default::default()
+                },
+                &token,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(users.users.len(), 1);
+        assert_eq!(users.users[0].user_name, user.user_name);
+
+        let status = client
+            .list_users(authorized(
+                ListUsersRequest {
+                    per_page: 1000,
+                    ..Default::default()
+                },
+                &token,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_private_message_delivery() {
+        let state = Arc::new(AppState::test().await);
+        let channel = server(state.clone()).await;
+        let (sender, sender_token) = user(&state).await;
+        let (receiver, receiver_token) = user(&state).await;
+
+        let mut client = MessagesClient::new(channel);
+        let mut messages = client
+            .subscribe(authorized(SubscribeRequest {}, &receiver_token))
+            .await
+            .unwrap()
+            .into_inner();
+
+        client
+            .send_private_message(authorized(
+                SendPrivateMessageRequest {
+                    receiver_id: receiver.user_id.clone(),
+                    message: "hello over grpc".to_string(),
+                },
+                &sender_token,
+            ))
+            .await
+            .unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("no message delivered")
+            .unwrap()
+            .unwrap();
+        match message.kind {
+            Some(Kind::Chat(chat)) => {
+                assert_eq!(chat.message, "hello over grpc");
+                assert_eq!(chat.sender.unwrap().user_id, sender.user_id);
+            }
+            other => panic!("expected a chat message, got {:?}", other),
+        }
+
+        let status = client
+            .send_private_message(authorized(
+                SendPrivateMessageRequest {
+                    receiver_id: "nobody".to_string(),
+                    message: "hello".to_string(),
+                },
+                &sender_token,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_grpc_access() {
+        let state = Arc::new(AppState::test().await);
+        let mut client = UsersClient::new(server(state.clone()).await);
+        let (_, token) = user(&state).await;
+        client
+            .get_me(authorized(GetMeRequest {}, &token))
+            .await
+            .unwrap();
+
+        let http = TestServer::new(routes(state.clone())).unwrap();
+        http.post("/api/auth/logout")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status_ok();
+
+        let status = client
+            .get_me(authorized(GetMeRequest {}, &token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert_eq!(status.message(), "Token has been revoked");
+        let status = client
+            .list_users(authorized(ListUsersRequest::default(), &token))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
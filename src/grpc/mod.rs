@@ -0,0 +1,7 @@
+pub mod handler;
+
+/// Messages and services generated from `proto/api.proto`
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("api.v1");
+}
@@ -0,0 +1,361 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{app_state::AppState, config::flavor::load_config};
+
+/// Tables created by `migrations/`, readiness fails until all of them exist
+pub const REQUIRED_TABLES: &[&str] = &[
+    "users",
+    "groups",
+    "group_hooks",
+    "group_webhooks",
+    "attachments",
+    "audit_log",
+    "organizations",
+    "organization_members",
+    "organization_invitations",
+    "otp_codes",
+    "outbox",
+    "analytics_events",
+    "metrics_daily",
+    "reports",
+    "user_sanctions",
+    "group_word_filters",
+    "group_events",
+    "event_rsvps",
+    "bot_tokens",
+    "bot_token_usage",
+    "oauth_clients",
+    "oauth_consents",
+    "oauth_codes",
+    "oauth_tokens",
+    "saml_requests",
+    "saml_identities",
+    "anonymize_jobs",
+];
+
+/// How long readiness reports false before the server stops accepting connections
+pub const SHUTDOWN_DRAIN: Duration = Duration::from_secs(5);
+
+pub struct ProbeState {
+    pub shutting_down: AtomicBool,
+}
+
+impl ProbeState {
+    pub fn new() -> Self {
+        Self {
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for ProbeState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessCheck {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IntoResponse for ReadinessResponse {
+    fn into_response(self) -> Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatabaseHealth {
+    pub status: String,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub status: String,
+    pub database: DatabaseHealth,
+}
+
+impl IntoResponse for HealthResponse {
+    fn into_response(self) -> Response {
+        let status = if self.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+pub async fn check_database(pool: &Pool<Postgres>) -> DatabaseHealth {
+    let start = Instant::now();
+    let result = sqlx::query("select 1").execute(pool).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    match result {
+        Ok(_) => DatabaseHealth {
+            status: "ok".to_string(),
+            latency_ms,
+            error: None,
+        },
+        Err(e) => DatabaseHealth {
+            status: "unavailable".to_string(),
+            latency_ms,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+pub async fn missing_tables(pool: &Pool<Postgres>) -> Result<Vec<String>, sqlx::Error> {
+    let mut missing = Vec::new();
+    for table in REQUIRED_TABLES {
+        let exists: bool = sqlx::query_scalar("select to_regclass($1) is not null")
+            .bind(*table)
+            .fetch_one(pool)
+            .await?;
+        if !exists {
+            missing.push(table.to_string());
+        }
+    }
+    Ok(missing)
+}
+
+fn check(name: &str, error: Option<String>) -> ReadinessCheck {
+    ReadinessCheck {
+        name: name.to_string(),
+        ok: error.is_none(),
+        error,
+    }
+}
+
+/// Liveness: the process is up and serving requests
+pub async fn livez_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness: dependencies are usable and the server is not draining
+pub async fn readyz_handler(State(state): State<Arc<AppState>>) -> ReadinessResponse {
+    let mut checks = Vec::new();
+
+    let shutdown_error = state
+        .probe
+        .is_shutting_down()
+        .then(|| "Server is shutting down".to_string());
+    checks.push(check("shutdown", shutdown_error));
+
+    let database = check_database(&state.pool).await;
+    checks.push(check("database", database.error));
+
+    let migrations_error = match missing_tables(&state.pool).await {
+        Ok(missing) if missing.is_empty() => None,
+        Ok(missing) => Some(format!("Missing tables: {}", missing.join(", "))),
+        Err(e) => Some(e.to_string()),
+    };
+    checks.push(check("migrations", migrations_error));
+
+    let config_error = state
+        .jwt_config
+        .secret
+        .is_empty()
+        .then(|| "jwt.key is empty".to_string());
+    checks.push(check("config", config_error));
+
+    ReadinessResponse {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM after flipping readiness off and waiting
+/// `SHUTDOWN_DRAIN`, so load balancers stop routing before connections close
+pub async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutdown signal received, draining connections");
+    state.probe.start_shutdown();
+    tokio::time::sleep(SHUTDOWN_DRAIN).await;
+}
+
+/// What is deployed: compiled in by `build.rs`, plus the config file in use
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionResponse {
+    pub version: String,
+    pub git_sha: String,
+    pub build_timestamp: String,
+    pub flavor: String,
+}
+
+pub async fn version_handler() -> Json<VersionResponse> {
+    let build_timestamp = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default();
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("GIT_SHA").to_string(),
+        build_timestamp,
+        flavor: load_config().unwrap_or_default(),
+    })
+}
+
+pub async fn healthz_handler(State(state): State<Arc<AppState>>) -> HealthResponse {
+    let database = check_database(&state.pool).await;
+    HealthResponse {
+        status: database.status.clone(),
+        database,
+    }
+}
+
+#[cfg(test)]
+mod tests_health {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+
+    use crate::{
+        app_state::AppState,
+        health::handler::{HealthResponse, ReadinessResponse, VersionResponse},
+        routes::routes,
+    };
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/healthz").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let body = response.json::<HealthResponse>();
+        assert_eq!(body.status, "ok");
+        assert_eq!(body.database.status, "ok");
+        assert!(body.database.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_healthz_database_down() {
+        let state = Arc::new(AppState::test().await);
+        state.pool.close().await;
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/healthz").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.json::<HealthResponse>();
+        assert_eq!(body.status, "unavailable");
+        assert!(body.database.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_livez() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/livez").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/readyz").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        let body = response.json::<ReadinessResponse>();
+        assert!(body.ready);
+        assert!(body.checks.iter().all(|c| c.ok));
+    }
+
+    #[tokio::test]
+    async fn test_readyz_shutting_down() {
+        let state = Arc::new(AppState::test().await);
+        state.probe.start_shutdown();
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/readyz").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.json::<ReadinessResponse>();
+        assert!(!body.ready);
+        let shutdown = body.checks.iter().find(|c| c.name == "shutdown").unwrap();
+        assert!(!shutdown.ok);
+
+        let response = server.get("/livez").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let response = server.get("/version").await;
+        response.assert_status_ok();
+        let body = response.json::<VersionResponse>();
+        assert_eq!(body.version, env!("CARGO_PKG_VERSION"));
+        assert!(!body.git_sha.is_empty());
+        assert!(chrono::DateTime::parse_from_rfc3339(&body.build_timestamp).is_ok());
+        assert!(body.flavor.ends_with(".toml"));
+    }
+}
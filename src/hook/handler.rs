@@ -0,0 +1,421 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::is_admin,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    extract::JsonOrForm,
+    group::handler::owner,
+    rate_limit::Throttled,
+    websocket::group::{GroupMessage, serde_msg},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupHook {
+    pub hook_id: String,
+    pub group_id: String,
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupHookResponse {
+    pub meta: MetaResponse,
+    pub data: GroupHook,
+}
+
+impl IntoResponse for GroupHookResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupHookParam {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HookPayload {
+    pub text: String,
+    pub username: Option<String>,
+}
+
+fn hook_url(token: &str) -> String {
+    format!("/hooks/{}", token)
+}
+
+pub async fn create(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    name: &str,
+    created_by: &str,
+) -> Result<GroupHook, Error> {
+    let mut tx = pool.begin().await?;
+    let hook_id = Uuid::new_v4().to_string();
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    let sql = "insert into group_hooks (hook_id, group_id, name, token, created_by) values ($1, $2, $3, $4, $5)";
+    sqlx::query(sql)
+        .bind(&hook_id)
+        .bind(group_id)
+        .bind(name)
+        .bind(&token)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(GroupHook {
+        hook_id,
+        group_id: group_id.to_string(),
+        name: name.to_string(),
+        url: hook_url(&token),
+    })
+}
+
+pub async fn get_by_token(pool: &Pool<Postgres>, token: &str) -> Option<GroupHook> {
+    let sql = "select hook_id, group_id, name, token from group_hooks where token = $1 and revoked_at is null";
+    sqlx::query(sql)
+        .bind(token)
+        .map(|data: PgRow| GroupHook {
+            hook_id: data.get("hook_id"),
+            group_id: data.get("group_id"),
+            name: data.get("name"),
+            url: hook_url(data.get("token")),
+        })
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_default()
+}
+
+/// Revokes a hook, only its creator is allowed to. Returns false when nothing matched.
+pub async fn revoke(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    hook_id: &str,
+    user_id: &str,
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+    let sql = "update group_hooks set revoked_at = current_timestamp where hook_id = $1 and group_id = $2 and created_by = $3 and revoked_at is null";
+    let result = sqlx::query(sql)
+        .bind(hook_id)
+        .bind(group_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Creates an incoming hook that posts into the group. Only the group's
+/// creator or an admin may, as the hook speaks in the group for whoever
+/// holds its URL.
+pub async fn create_hook_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    JsonOrForm(req): JsonOrForm<GroupHookParam>,
+) -> Result<GroupHookResponse, MetaResponse> {
+    if state.groups.get_by_id(&group_id).await.is_none() {
+        return Err(MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Group not found".to_string(),
+        });
+    }
+    let storage_error = |e: Error| MetaResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+        message: e.to_string(),
+    };
+    let owned =
+        owner(&state.pool, &group_id).await.map_err(storage_error)? == Some(user.user_id.clone());
+    if !owned
+        && !is_admin(&user.user_id, &state.pool)
+            .await
+            .map_err(storage_error)?
+    {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Only the group's creator or an admin can add hooks".to_string(),
+        });
+    }
+
+    let result = create(&state.pool, &group_id, &req.name, &user.user_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    Ok(GroupHookResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: result,
+    })
+}
+
+pub async fn revoke_hook_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path((group_id, hook_id)): Path<(String, String)>,
+) -> MetaResponse {
+    match revoke(&state.pool, &group_id, &hook_id, &user.user_id).await {
+        Ok(true) => MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Hook not found".to_string(),
+        },
+        Err(e) => MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Public endpoint called by external systems to post into a group chat
+pub async fn incoming_hook_handler(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    Json(req): Json<HookPayload>,
+) -> Response {
+    let hook = match get_by_token(&state.pool, &token).await {
+        Some(hook) => hook,
+        None => {
+            return MetaResponse {
+                code: StatusCode::NOT_FOUND.to_i32(),
+                message: "Hook not found".to_string(),
+            }
+            .into_response();
+        }
+    };
+
+    let quota = state.hook_limiter.hit(&hook.hook_id).await;
+    if !quota.allowed {
+        return Throttled(quota).into_response();
+    }
+
+    if req.text.trim().is_empty() {
+        return MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "Text cannot be empty".to_string(),
+        }
+        .into_response();
+    }
+
+    let group_msg = GroupMessage {
+        id: hook.hook_id,
+        name: req.username.unwrap_or(hook.name),
+        message: req.text,
+    };
+    let _ = state.group.tx.send(serde_msg(&group_msg));
+
+    (
+        quota.headers(),
+        MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests_hook {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        Router,
+        routing::{delete, post},
+    };
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        app_state::AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::random_name,
+        },
+        error::ErrorResponse,
+        group::handler::{GroupResponse, create},
+        hook::handler::{self, incoming_hook_handler},
+        routes::routes,
+        websocket::event::ServerEvent,
+    };
+
+    fn app(state: Arc<AppState>) -> Router {
+        Router::new()
+            .route("/hooks/{token}", post(incoming_hook_handler))
+            .route(
+                "/api/groups/{group_id}/hooks/{hook_id}",
+                delete(handler::revoke_hook_handler),
+            )
+            .with_state(state)
+    }
+
+    #[tokio::test]
+    async fn test_incoming_hook_posts_to_group() {
+        let state = Arc::new(AppState::test().await);
+        let group = create(&state.pool, &random_name(), "").await.unwrap();
+        let hook = handler::create(&state.pool, &group.group_id, "CI", "user-1")
+            .await
+            .unwrap();
+
+        let mut rx = state.group.tx.subscribe();
+        let server = TestServer::new(app(state)).unwrap();
+        let response = server
+            .post(&hook.url)
+            .json(&json!({ "text": "Build passed" }))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+
+        let msg = rx.recv().await.unwrap();
+        match serde_json::from_str::<ServerEvent>(&msg).unwrap() {
+            ServerEvent::GroupMessage(group_msg) => {
+                assert_eq!(group_msg.id, hook.hook_id);
+                assert_eq!(group_msg.name, "CI");
+                assert_eq!(group_msg.message, "Build passed");
+            }
+            _ => panic!("expected group message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_hook_rejected() {
+        let state = Arc::new(AppState::test().await);
+        let group = create(&state.pool, &random_name(), "").await.unwrap();
+        let hook = handler::create(&state.pool, &group.group_id, "CI", "user-1")
+            .await
+            .unwrap();
+
+        let revoked = handler::revoke(&state.pool, &group.group_id, &hook.hook_id, "user-2")
+            .await
+            .unwrap();
+        assert!(!revoked);
+        let revoked = handler::revoke(&state.pool, &group.group_id, &hook.hook_id, "user-1")
+            .await
+            .unwrap();
+        assert!(revoked);
+
+        let server = TestServer::new(app(state)).unwrap();
+        let response = server.post(&hook.url).json(&json!({ "text": "Hi" })).await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_hook_headers() {
+        let state = Arc::new(AppState::test().await);
+        state.hook_limiter.configure(1, Duration::from_secs(60));
+        let group = create(&state.pool, &random_name(), "").await.unwrap();
+        let hook = handler::create(&state.pool, &group.group_id, "CI", "user-1")
+            .await
+            .unwrap();
+
+        let server = TestServer::new(app(state)).unwrap();
+        server
+            .post(&hook.url)
+            .json(&json!({ "text": "One" }))
+            .await
+            .assert_status_ok();
+        let response = server.post(&hook.url).json(&json!({ "text": "Two" })).await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header("x-ratelimit-limit"), "1");
+        assert_eq!(response.header("x-ratelimit-remaining"), "0");
+        assert_eq!(response.header("retry-after"), "60");
+    }
+
+    #[tokio::test]
+    async fn test_create_hook_handler() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let token = |user: &User| {
+            format!(
+                "Bearer {}",
+                create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap()
+            )
+        };
+        let mut users = Vec::new();
+        for name in ["hook_owner", "hook_other", "hook_admin"] {
+            let user = add(
+                &state.pool,
+                NewUser::new(
+                    name.to_string(),
+                    format!("{}@mail.com", name),
+                    "123456".to_string(),
+                ),
+            )
+            .await
+            .unwrap();
+            users.push(user);
+        }
+        set_role(&users[2].user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+
+        let group = server
+            .post("/api/v1/groups")
+            .add_header("Authorization", token(&users[0]))
+            .form(&[("name", random_name().as_str()), ("description", "")])
+            .await
+            .json::<GroupResponse>()
+            .data;
+        let path = format!("/api/v1/groups/{}/hooks", group.group_id);
+
+        let response = server
+            .post(&path)
+            .add_header("Authorization", token(&users[1]))
+            .form(&[("name", "CI")])
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Only the group's creator or an admin can add hooks"
+        );
+        for user in [&users[0], &users[2]] {
+            server
+                .post(&path)
+                .add_header("Authorization", token(user))
+                .form(&[("name", "CI")])
+                .await
+                .assert_status_ok();
+        }
+        server
+            .post("/api/v1/groups/unknown/hooks")
+            .add_header("Authorization", token(&users[2]))
+            .form(&[("name", "CI")])
+            .await
+            .assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_hook() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(app(state)).unwrap();
+        let response = server
+            .post("/hooks/unknown")
+            .json(&json!({ "text": "Hi" }))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::app_state::AppState;
+
+/// Names the cached collection so a CDN can purge it by key
+pub const SURROGATE_KEY: &str = "surrogate-key";
+
+/// `[http_cache]`. Freshness of the user and group listings, which read the
+/// same for every caller.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpCacheSettings {
+    /// Seconds a browser may reuse a listing without asking again; 0 makes
+    /// it revalidate every time
+    pub max_age_secs: u64,
+    /// Seconds a shared cache (CDN) may keep a listing. 0 keeps them out of
+    /// shared caches: listings are requested with a token, and a CDN would
+    /// hand its copy to anyone.
+    pub shared_max_age_secs: u64,
+}
+
+impl Default for HttpCacheSettings {
+    fn default() -> Self {
+        Self {
+            max_age_secs: 60,
+            shared_max_age_secs: 0,
+        }
+    }
+}
+
+impl HttpCacheSettings {
+    pub fn cache_control(&self) -> String {
+        match (self.max_age_secs, self.shared_max_age_secs) {
+            (0, 0) => "private, no-cache".to_string(),
+            (max_age, 0) => format!("private, max-age={}", max_age),
+            (max_age, shared) => format!("public, max-age={}, s-maxage={}", max_age, shared),
+        }
+    }
+}
+
+/// State of `cache_control`: the collection the routes it wraps list
+#[derive(Clone)]
+pub struct Cacheable {
+    state: Arc<AppState>,
+    key: &'static str,
+}
+
+impl Cacheable {
+    pub fn listing(state: Arc<AppState>, key: &'static str) -> Self {
+        Self { state, key }
+    }
+}
+
+/// Adds `Cache-Control` and a `Surrogate-Key` to successful `GET` responses,
+/// including the `304` of `etag`, which must sit inside this layer.
+///
+/// `Last-Modified` comes from the handler, see `last_modified`. It tells
+/// caches how old a listing is, but revalidation stays with the ETag: a
+/// removed row changes a listing without anything in it getting newer.
+pub async fn cache_control(
+    State(cacheable): State<Cacheable>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let get = req.method() == Method::GET;
+    let mut response = next.run(req).await;
+    if !get || !matches!(response.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+        return response;
+    }
+
+    let settings = &cacheable.state.settings.http_cache;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&settings.cache_control()) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    headers.insert(SURROGATE_KEY, HeaderValue::from_static(cacheable.key));
+    response
+}
+
+/// `Last-Modified` of a listing: the latest change (or creation) among its
+/// rows, as RFC 3339 strings. `None` for an empty listing.
+pub fn last_modified<'a>(
+    changes: impl IntoIterator<Item = Option<&'a str>>,
+) -> Option<HeaderValue> {
+    changes
+        .into_iter()
+        .flatten()
+        .filter_map(|at| DateTime::parse_from_rfc3339(at).ok())
+        .map(|at| at.with_timezone(&Utc))
+        .max()
+        .and_then(|at| HeaderValue::from_str(&http_date(at)).ok())
+}
+
+/// IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests_http_cache {
+    use axum::http::{StatusCode, header};
+    use axum_test::TestServer;
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        AppState,
+        auth::{jwt::create_access_token, util::random_name},
+        http_cache::{HttpCacheSettings, SURROGATE_KEY, http_date, last_modified},
+        routes::routes,
+    };
+
+    #[test]
+    fn test_cache_control() {
+        let mut settings = HttpCacheSettings::default();
+        assert_eq!(settings.cache_control(), "private, max-age=60");
+        settings.shared_max_age_secs = 300;
+        assert_eq!(settings.cache_control(), "public, max-age=60, s-maxage=300");
+        settings.max_age_secs = 0;
+        settings.shared_max_age_secs = 0;
+        assert_eq!(settings.cache_control(), "private, no-cache");
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let at = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        assert_eq!(http_date(at), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        let value = last_modified([
+            Some("1994-11-06T08:49:37+00:00"),
+            None,
+            Some("1994-11-06T10:49:37+02:00"),
+            Some("not a date"),
+            Some("1994-11-05T08:49:37Z"),
+        ])
+        .unwrap();
+        assert_eq!(value, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(last_modified([None, Some("not a date")]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_listing_headers() {
+        let state = AppState::isolated().await;
+        let user = state.users.get_by_user_name("Jordan").await.unwrap();
+        let token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap()
+        );
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+
+        let response = server
+            .get("/api/v1/groups")
+            .add_header(header::AUTHORIZATION, &token)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(
+            response.header(header::CACHE_CONTROL),
+            "private, max-age=60"
+        );
+        assert_eq!(response.header(SURROGATE_KEY), "groups");
+
+        let users = server
+            .get("/api/v1/users")
+            .add_header(header::AUTHORIZATION, &token)
+            .await;
+        users.assert_status_ok();
+        assert_eq!(users.header(SURROGATE_KEY), "users");
+        assert!(users.maybe_header(header::LAST_MODIFIED).is_some());
+
+        // The 304 of a revalidation keeps the caching headers
+        let not_modified = server
+            .get("/api/v1/users")
+            .add_header(header::AUTHORIZATION, &token)
+            .add_header(header::IF_NONE_MATCH, users.header(header::ETAG))
+            .await;
+        not_modified.assert_status(StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            not_modified.header(header::CACHE_CONTROL),
+            "private, max-age=60"
+        );
+
+        let created = server
+            .post("/api/v1/groups")
+            .add_header(header::AUTHORIZATION, &token)
+            .form(&[("name", random_name().as_str()), ("description", "")])
+            .await;
+        created.assert_status_ok();
+        assert!(created.maybe_header(header::CACHE_CONTROL).is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::ErrorResponse, extract::is_json};
+
+/// Language of the messages in the code, and the fallback for everything else
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Message catalogs by language: error code to message template, where each
+/// `{}` stands for a value filled in by the code. A new language is one more
+/// file in `locales/` with the same keys and a line here.
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.json")),
+    ("id", include_str!("../locales/id.json")),
+];
+
+/// Error bodies larger than this are passed through untranslated
+const MAX_ERROR_BODY: usize = 16 * 1024;
+
+static CATALOGS: OnceLock<HashMap<&'static str, HashMap<String, String>>> = OnceLock::new();
+
+fn catalogs() -> &'static HashMap<&'static str, HashMap<String, String>> {
+    CATALOGS.get_or_init(|| {
+        LOCALES
+            .iter()
+            .map(|(language, json)| {
+                let catalog = serde_json::from_str(json)
+                    .unwrap_or_else(|e| panic!("Invalid locales/{}.json: {}", language, e));
+                (*language, catalog)
+            })
+            .collect()
+    })
+}
+
+/// Splits `message` along the literal parts of `template`, returning the
+/// values that stand in for its `{}`s
+fn matches(template: &str, message: &str) -> Option<Vec<String>> {
+    let mut literals = template.split("{}");
+    let mut rest = message.strip_prefix(literals.next()?)?;
+    let literals: Vec<_> = literals.collect();
+    let mut values = Vec::with_capacity(literals.len());
+    for (i, literal) in literals.iter().enumerate() {
+        let end = if i + 1 == literals.len() {
+            rest.strip_suffix(literal)?.len()
+        } else {
+            rest.find(literal)?
+        };
+        if end == 0 {
+            return None;
+        }
+        values.push(rest[..end].to_string());
+        rest = &rest[end + literal.len()..];
+    }
+    rest.is_empty().then_some(values)
+}
+
+fn fill(template: &str, values: &[String]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    for (i, literal) in template.split("{}").enumerate() {
+        if i > 0 {
+            filled.push_str(values.get(i - 1).map_or("{}", String::as_str));
+        }
+        filled.push_str(literal);
+    }
+    filled
+}
+
+/// The catalog entry an English message was built from, and the values
+/// filled into it. The template with the most literal text wins, so
+/// "must be at least 3 characters" is `min_length` rather than `min`.
+fn lookup(message: &str) -> Option<(&'static str, Vec<String>)> {
+    catalogs()
+        .get(DEFAULT_LANGUAGE)?
+        .iter()
+        .filter_map(|(code, template)| {
+            let values = matches(template, message)?;
+            Some((template.len() - 2 * values.len(), code.as_str(), values))
+        })
+        .max_by_key(|(literal, _, _)| *literal)
+        .map(|(_, code, values)| (code, values))
+}
+
+/// Machine-readable code of an error message: its catalog key, or the status
+/// (`unprocessable_entity`) for messages not in the catalog
+pub fn error_code(status: StatusCode, message: &str) -> String {
+    match lookup(message) {
+        Some((code, _)) => code.to_string(),
+        None => status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase()
+            .replace([' ', '-'], "_"),
+    }
+}
+
+/// `message` in `language`, or unchanged when it isn't in the catalog
+pub fn translate(message: &str, language: &str) -> String {
+    let Some((code, values)) = lookup(message) else {
+        return message.to_string();
+    };
+    match catalogs()
+        .get(language)
+        .and_then(|catalog| catalog.get(code))
+    {
+        Some(template) => fill(template, &values),
+        None => message.to_string(),
+    }
+}
+
+/// Best supported language of an `Accept-Language` header, by quality and
+/// then order. Region subtags are ignored: `id-ID` is served `id`.
+pub fn negotiate(headers: &HeaderMap) -> &'static str {
+    let Some(accept) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return DEFAULT_LANGUAGE;
+    };
+    let mut ranges: Vec<(f32, &str)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && quality > 0.0).then_some((quality, tag))
+        })
+        .collect();
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges
+        .into_iter()
+        .find_map(|(_, tag)| {
+            let primary = tag.split('-').next()?.to_ascii_lowercase();
+            if primary == "*" {
+                return Some(DEFAULT_LANGUAGE);
+            }
+            LOCALES
+                .iter()
+                .map(|(language, _)| *language)
+                .find(|language| *language == primary)
+        })
+        .unwrap_or(DEFAULT_LANGUAGE)
+}
+
+/// Translates the messages of JSON error responses into the language asked
+/// for in `Accept-Language`. Must run outside `json_errors` so every error
+/// is JSON by then.
+pub async fn localize(req: Request, next: Next) -> Response {
+    let language = negotiate(req.headers());
+    let response = next.run(req).await;
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || !is_json(response.headers()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-language"));
+    if language == DEFAULT_LANGUAGE {
+        return Response::from_parts(parts, body);
+    }
+    let Ok(bytes) = to_bytes(body, MAX_ERROR_BODY).await else {
+        return ErrorResponse::new(status, translate("Internal server error", language))
+            .into_response();
+    };
+    let Ok(mut error) = serde_json::from_slice::<ErrorResponse>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    error.meta.message = translate(&error.meta.message, language);
+    for detail in error.meta.errors.iter_mut() {
+        detail.message = translate(&detail.message, language);
+    }
+    let mut response = error.into_response();
+    for (name, value) in parts.headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(language));
+    response
+}
+
+#[cfg(test)]
+mod tests_i18n {
+    use std::sync::Arc;
+
+    use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        error::ErrorResponse,
+        i18n::{LOCALES, catalogs, error_code, negotiate, translate},
+        routes::routes,
+    };
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_catalogs_complete() {
+        let english = &catalogs()["en"];
+        for (language, _) in LOCALES {
+            let catalog = &catalogs()[language];
+            for (code, template) in english {
+                let translated = catalog
+                    .get(code)
+                    .unwrap_or_else(|| panic!("{} is missing {}", language, code));
+                assert_eq!(
+                    translated.matches("{}").count(),
+                    template.matches("{}").count(),
+                    "{} {}",
+                    language,
+                    code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&HeaderMap::new()), "en");
+        assert_eq!(negotiate(&accept("id-ID,id;q=0.9,en;q=0.8")), "id");
+        assert_eq!(
+            negotiate(&accept("fr-CH, fr;q=0.9, id;q=0.5, en;q=0.7")),
+            "en"
+        );
+        assert_eq!(negotiate(&accept("en;q=0.2, ID")), "id");
+        assert_eq!(negotiate(&accept("id;q=0, fr")), "en");
+        assert_eq!(negotiate(&accept("*")), "en");
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            translate("Invalid user name or password", "id"),
+            "Nama pengguna atau kata sandi salah"
+        );
+        assert_eq!(
+            translate("Request body exceeds 16384 bytes", "id"),
+            "Isi permintaan melebihi 16384 byte"
+        );
+        assert_eq!(
+            translate("must be between 6 and 30 characters", "id"),
+            "harus antara 6 dan 30 karakter"
+        );
+        assert_eq!(translate("must be at least 1", "id"), "minimal 1");
+        assert_eq!(translate("Something new", "id"), "Something new");
+        assert_eq!(translate("Group not found", "fr"), "Group not found");
+
+        assert_eq!(
+            error_code(StatusCode::BAD_REQUEST, "must be at least 3 characters"),
+            "min_length"
+        );
+        assert_eq!(
+            error_code(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Failed to parse the request body"
+            ),
+            "unprocessable_entity"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_localized_errors() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = [("user_name", "Jordan"), ("password", "wrong")];
+        let response = server
+            .post("/api/v1/auth/login")
+            .add_header("accept-language", "id-ID,id;q=0.9")
+            .form(&body)
+            .await;
+        response.assert_status_not_found();
+        assert_eq!(response.header("content-language"), "id");
+        let error = response.json::<ErrorResponse>();
+        assert_eq!(error.meta.error, "invalid_credentials");
+        assert_eq!(error.meta.message, "Nama pengguna atau kata sandi salah");
+
+        let body = [("user_name", "Jo"), ("email", "nope"), ("password", "1")];
+        let response = server
+            .post("/api/v1/auth/register")
+            .add_header("accept-language", "id")
+            .form(&body)
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let error = response.json::<ErrorResponse>();
+        assert_eq!(error.meta.error, "validation_failed");
+        assert_eq!(error.meta.message, "Validasi gagal");
+        assert_eq!(error.meta.errors[0].code, "email");
+        assert_eq!(
+            error.meta.errors[0].message,
+            "harus berupa alamat email yang valid"
+        );
+
+        let response = server.get("/missing").await;
+        assert!(response.maybe_header("content-language").is_none());
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Route not found"
+        );
+    }
+}
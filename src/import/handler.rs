@@ -0,0 +1,376 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use futures::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, QueryBuilder};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        user::NewUser,
+        util::{MetaResponse, StatusCodeExt, hash_password_async},
+    },
+    validation::invalid,
+};
+
+/// Imports carry far more than a form, up to `MAX_ROWS` rows
+pub const IMPORT_BODY_LIMIT: usize = 4 * 1024 * 1024;
+pub const MAX_ROWS: usize = 10_000;
+/// Rows per insert statement, at four parameters each
+const BATCH_ROWS: usize = 1_000;
+/// Passwords hashed at once on the blocking pool
+const HASH_CONCURRENCY: usize = 8;
+
+/// Why one row of an import was left out. `row` counts data rows from 1,
+/// not including a CSV header.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RowError {
+    pub row: usize,
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportResponse {
+    pub meta: MetaResponse,
+    pub data: ImportReport,
+}
+
+impl IntoResponse for ImportResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    fn of(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim();
+        match mime {
+            "text/csv" => Some(Self::Csv),
+            "application/x-ndjson" => Some(Self::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+fn row_error(row: usize, field: &str, message: impl Into<String>) -> RowError {
+    RowError {
+        row,
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+/// Every data row, numbered from 1, or why it couldn't be read
+fn parse(format: Format, body: &[u8]) -> Vec<(usize, Result<NewUser, RowError>)> {
+    match format {
+        Format::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body)
+            .deserialize::<NewUser>()
+            .enumerate()
+            .map(|(i, row)| (i + 1, row.map_err(|e| row_error(i + 1, "", e.to_string()))))
+            .collect(),
+        Format::Ndjson => body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                let row = serde_json::from_slice::<NewUser>(line)
+                    .map_err(|e| row_error(i + 1, "", e.to_string()));
+                (i + 1, row)
+            })
+            .collect(),
+    }
+}
+
+/// Which of the lowercased `names` are registered, in lowercase
+#[tracing::instrument(name = "db.users.taken_names", skip_all)]
+async fn taken_names(names: Vec<String>, pool: &Pool<Postgres>) -> Result<Vec<String>, Error> {
+    sqlx::query_scalar("select lower(user_name) from users where lower(user_name) = any($1)")
+        .bind(names)
+        .fetch_all(pool)
+        .await
+}
+
+/// Inserts `users`, whose passwords are already hashed, in batches on one
+/// transaction. Returns the names actually inserted: a name registered since
+/// it was checked is skipped rather than failing the whole import.
+#[tracing::instrument(name = "db.users.import", skip_all, fields(rows = users.len()))]
+pub async fn insert_users(users: &[NewUser], pool: &Pool<Postgres>) -> Result<Vec<String>, Error> {
+    let mut tx = pool.begin().await?;
+    let mut inserted = Vec::with_capacity(users.len());
+    for batch in users.chunks(BATCH_ROWS) {
+        let mut query =
+            QueryBuilder::new("insert into users (user_id, user_name, email, password) ");
+        query.push_values(batch, |mut row, user| {
+            row.push_bind(Uuid::new_v4().to_string())
+                .push_bind(&user.user_name)
+                .push_bind(&user.email)
+                .push_bind(&user.password);
+        });
+        query.push(" on conflict ((lower(user_name))) do nothing returning user_name");
+        inserted.extend(
+            query
+                .build_query_scalar::<String>()
+                .fetch_all(&mut *tx)
+                .await?,
+        );
+    }
+    tx.commit().await?;
+    Ok(inserted)
+}
+
+/// Registers every valid row of a CSV (`text/csv`, with a
+/// `user_name,email,password` header) or NDJSON (`application/x-ndjson`)
+/// body. Invalid rows, names already registered and repeated names are left
+/// out and reported; the valid rows go in together or not at all. Imported
+/// users don't raise `user.registered`.
+pub async fn import_users_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<ImportResponse, MetaResponse> {
+    let format = Format::of(&headers).ok_or_else(|| MetaResponse {
+        code: StatusCode::UNSUPPORTED_MEDIA_TYPE.to_i32(),
+        message: "Expected text/csv or application/x-ndjson".to_string(),
+    })?;
+    let rows = parse(format, &body);
+    if rows.len() > MAX_ROWS {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("At most {} rows per import", MAX_ROWS),
+        });
+    }
+
+    let policy = state.runtime.load().email_policy.clone();
+    let mut errors = Vec::new();
+    let mut seen = HashMap::new();
+    let mut valid = Vec::new();
+    for (row, user) in rows {
+        let mut user = match user {
+            Ok(user) => user,
+            Err(error) => {
+                errors.push(error);
+                continue;
+            }
+        };
+        if let Err(e) = user.validate() {
+            errors.extend(
+                invalid(e)
+                    .meta
+                    .errors
+                    .into_iter()
+                    .map(|detail| row_error(row, &detail.field, detail.message)),
+            );
+            continue;
+        }
+        user.email = policy.normalize(&user.email);
+        if policy.blocks(&user.email) {
+            errors.push(row_error(row, "email", "domain is not allowed"));
+            continue;
+        }
+        let key = user.user_name.to_lowercase();
+        if let Some(first) = seen.get(&key) {
+            errors.push(row_error(
+                row,
+                "user_name",
+                format!("repeats row {}", first),
+            ));
+            continue;
+        }
+        seen.insert(key, row);
+        valid.push((row, user));
+    }
+
+    let storage_error = |e: Error| MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: format!("Failed to import: {}", e),
+    };
+    let names = valid
+        .iter()
+        .map(|(_, user)| user.user_name.to_lowercase())
+        .collect();
+    let taken = taken_names(names, &state.pool)
+        .await
+        .map_err(storage_error)?;
+    valid.retain(|(row, user)| {
+        let free = !taken.contains(&user.user_name.to_lowercase());
+        if !free {
+            errors.push(row_error(*row, "user_name", "already registered"));
+        }
+        free
+    });
+
+    let hashed: Vec<_> = stream::iter(valid)
+        .map(|(row, user)| async move {
+            let hash = hash_password_async(user.password.clone()).await;
+            (row, user, hash)
+        })
+        .buffered(HASH_CONCURRENCY)
+        .collect()
+        .await;
+    let mut users = Vec::with_capacity(hashed.len());
+    let mut rows = Vec::with_capacity(hashed.len());
+    for (row, user, hash) in hashed {
+        match hash {
+            Ok(password) => {
+                rows.push(row);
+                users.push(NewUser { password, ..user });
+            }
+            Err(e) => errors.push(row_error(row, "password", e.to_string())),
+        }
+    }
+
+    let inserted = insert_users(&users, &state.pool)
+        .await
+        .map_err(storage_error)?;
+    for (row, user) in rows.into_iter().zip(&users) {
+        if !inserted.contains(&user.user_name) {
+            errors.push(row_error(row, "user_name", "already registered"));
+        }
+    }
+    errors.sort_by_key(|error| error.row);
+    tracing::info!(
+        imported = inserted.len(),
+        rejected = errors.len(),
+        "Imported users"
+    );
+
+    Ok(ImportResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: ImportReport {
+            imported: inserted.len(),
+            errors,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests_import {
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::random_name,
+        },
+        import::handler::ImportResponse,
+        routes::routes,
+    };
+
+    async fn admin(state: &AppState) -> (String, String) {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name.clone(), email, "123456".to_string()),
+        )
+        .await
+        .unwrap();
+        set_role(&user.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user_name, format!("Bearer {}", token))
+    }
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (existing, token) = admin(&state).await;
+
+        let csv = format!(
+            "user_name,email,password\n\
+            importa,importa@mail.com,secret1\n\
+            importb, importb@mail.com ,secret2\n\
+            importc,not-an-email,secret3\n\
+            IMPORTA,other@mail.com,secret4\n\
+            {},taken@mail.com,secret5\n\
+            importd,importd@mail.com\n",
+            existing.to_uppercase()
+        );
+        let response = server
+            .post("/api/v1/admin/users/import")
+            .content_type("text/csv")
+            .bytes(csv.into())
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let report = response.json::<ImportResponse>().data;
+        assert_eq!(report.imported, 2);
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|error| (error.row, error.field.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(3, "email"), (4, "user_name"), (5, "user_name"), (6, "")]
+        );
+
+        let body = [("user_name", "importb"), ("password", "secret2")];
+        server
+            .post("/api/v1/auth/login")
+            .form(&body)
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_import_ndjson() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (_, token) = admin(&state).await;
+
+        let ndjson = "{\"user_name\":\"importe\",\"email\":\"importe@mail.com\",\"password\":\"secret\"}\n\
+            \n\
+            {\"user_name\":\"importf\"\n";
+        let response = server
+            .post("/api/v1/admin/users/import")
+            .content_type("application/x-ndjson")
+            .bytes(ndjson.into())
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let report = response.json::<ImportResponse>().data;
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].row, 2);
+
+        let response = server
+            .post("/api/v1/admin/users/import")
+            .content_type("application/json")
+            .bytes("[]".into())
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_state::AppState,
+    auth::util::{MetaResponse, StatusCodeExt, client_ip},
+    config::{flavor::load_config, settings::Settings},
+};
+
+/// The lists that can be replaced at runtime
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpLists {
+    /// Requests from these networks are rejected everywhere
+    pub deny: Vec<IpNet>,
+    /// When not empty, admin routes only accept these networks
+    pub admin_allow: Vec<IpNet>,
+}
+
+/// Settings from the `[ip_filter]` section of the config file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IpFilterSettings {
+    #[serde(flatten)]
+    pub lists: IpLists,
+    /// Take the client address from `X-Forwarded-For`. Only enable behind a
+    /// proxy that sets it, otherwise clients can pick their own address.
+    pub trust_forwarded_for: bool,
+}
+
+pub struct IpFilter {
+    lists: RwLock<IpLists>,
+    trust_forwarded_for: bool,
+}
+
+impl IpFilter {
+    pub fn new(settings: &IpFilterSettings) -> Self {
+        Self {
+            lists: RwLock::new(settings.lists.clone()),
+            trust_forwarded_for: settings.trust_forwarded_for,
+        }
+    }
+
+    pub fn lists(&self) -> IpLists {
+        self.lists.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, lists: IpLists) {
+        *self.lists.write().unwrap() = lists;
+    }
+
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        self.lists
+            .read()
+            .unwrap()
+            .deny
+            .iter()
+            .any(|net| net.contains(&ip))
+    }
+
+    /// An unknown address only passes while the allowlist is empty
+    pub fn is_admin_allowed(&self, ip: Option<IpAddr>) -> bool {
+        let lists = self.lists.read().unwrap();
+        lists.admin_allow.is_empty()
+            || ip.is_some_and(|ip| lists.admin_allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    fn client(&self, req: &Request) -> Option<IpAddr> {
+        if self.trust_forwarded_for
+            && let Some(ip) = client_ip(req.headers()).and_then(|ip| ip.parse().ok())
+        {
+            return Some(ip);
+        }
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+}
+
+fn forbidden() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::FORBIDDEN.to_i32(),
+        message: "Forbidden".to_string(),
+    }
+}
+
+/// Rejects requests from denied networks
+pub async fn deny_list(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, MetaResponse> {
+    match state.ip_filter.client(&req) {
+        Some(ip) if state.ip_filter.is_denied(ip) => {
+            tracing::warn!(%ip, "Request from denied address");
+            Err(forbidden())
+        }
+        _ => Ok(next.run(req).await),
+    }
+}
+
+/// Rejects admin requests from outside the allowlist
+pub async fn admin_allow_list(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, MetaResponse> {
+    let ip = state.ip_filter.client(&req);
+    if !state.ip_filter.is_admin_allowed(ip) {
+        tracing::warn!(ip = ?ip, "Admin request from address outside the allowlist");
+        return Err(forbidden());
+    }
+    Ok(next.run(req).await)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IpListsResponse {
+    pub meta: MetaResponse,
+    pub data: IpLists,
+}
+
+impl IntoResponse for IpListsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+fn success(data: IpLists) -> IpListsResponse {
+    IpListsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    }
+}
+
+pub async fn ip_lists_handler(State(state): State<Arc<AppState>>) -> IpListsResponse {
+    success(state.ip_filter.lists())
+}
+
+/// Replaces both lists until the next reload or restart
+pub async fn replace_ip_lists_handler(
+    State(state): State<Arc<AppState>>,
+    Json(lists): Json<IpLists>,
+) -> IpListsResponse {
+    state.ip_filter.replace(lists.clone());
+    tracing::info!(?lists, "IP lists replaced");
+    success(lists)
+}
+
+/// Re-reads `[ip_filter]` from the config file
+pub async fn reload_ip_lists_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<IpListsResponse, MetaResponse> {
+    let error = |message: String| MetaResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+        message,
+    };
+    let flavor = load_config().map_err(|e| error(e.to_string()))?;
+    let settings = Settings::load(&flavor)
+        .await
+        .map_err(|e| error(e.to_string()))?;
+    let lists = settings.ip_filter.lists;
+    state.ip_filter.replace(lists.clone());
+    tracing::info!(?lists, "IP lists reloaded from {}", flavor);
+    Ok(success(lists))
+}
+
+#[cfg(test)]
+mod tests_ip_filter {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::{hash_password, random_name},
+        },
+        ip_filter::{IpFilter, IpFilterSettings, IpLists},
+        routes::routes,
+    };
+
+    fn filter(deny: &[&str], admin_allow: &[&str]) -> IpFilter {
+        IpFilter::new(&IpFilterSettings {
+            lists: IpLists {
+                deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+                admin_allow: admin_allow.iter().map(|net| net.parse().unwrap()).collect(),
+            },
+            trust_forwarded_for: true,
+        })
+    }
+
+    #[test]
+    fn test_lists() {
+        let filter = filter(&["10.0.0.0/8", "2001:db8::/32"], &["192.168.1.0/24"]);
+        assert!(filter.is_denied("10.1.2.3".parse().unwrap()));
+        assert!(filter.is_denied("2001:db8::1".parse().unwrap()));
+        assert!(!filter.is_denied("192.168.1.5".parse().unwrap()));
+
+        assert!(filter.is_admin_allowed(Some("192.168.1.5".parse().unwrap())));
+        assert!(!filter.is_admin_allowed(Some("172.16.0.1".parse().unwrap())));
+        assert!(!filter.is_admin_allowed(None));
+
+        let open = self::filter(&[], &[]);
+        assert!(open.is_admin_allowed(None));
+    }
+
+    async fn admin_token(state: &AppState) -> String {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name, email, hash))
+            .await
+            .unwrap();
+        set_role(&user.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[tokio::test]
+    async fn test_middleware_and_runtime_update() {
+        let mut state = AppState::test().await;
+        state.ip_filter = Arc::new(filter(&["203.0.113.0/24"], &["127.0.0.1/32"]));
+        let state = Arc::new(state);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = admin_token(&state).await;
+
+        let response = server
+            .get("/healthz")
+            .add_header("x-forwarded-for", "203.0.113.9")
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        server
+            .get("/healthz")
+            .add_header("x-forwarded-for", "198.51.100.1")
+            .await
+            .assert_status_ok();
+
+        // Admin routes only from the allowlist
+        server
+            .get("/api/v1/admin/ip-lists")
+            .add_header("Authorization", &token)
+            .add_header("x-forwarded-for", "198.51.100.1")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let response = server
+            .put("/api/v1/admin/ip-lists")
+            .add_header("Authorization", &token)
+            .add_header("x-forwarded-for", "127.0.0.1")
+            .json(&json!({ "deny": ["198.51.100.0/24"], "admin_allow": [] }))
+            .await;
+        response.assert_status_ok();
+
+        server
+            .get("/healthz")
+            .add_header("x-forwarded-for", "198.51.100.1")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .get("/healthz")
+            .add_header("x-forwarded-for", "203.0.113.9")
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_reload_from_config() {
+        let mut state = AppState::test().await;
+        state.ip_filter = Arc::new(filter(&["198.51.100.0/24"], &[]));
+        let state = Arc::new(state);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = admin_token(&state).await;
+
+        let response = server
+            .post("/api/v1/admin/ip-lists/reload")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(state.ip_filter.lists(), state.settings.ip_filter.lists);
+    }
+}
@@ -0,0 +1,47 @@
+pub mod admin;
+pub mod analytics;
+pub mod app_state;
+pub mod audit;
+pub mod auth;
+pub mod body_limit;
+pub mod bot;
+pub mod cache;
+pub mod calendar;
+pub mod config;
+pub mod error;
+pub mod etag;
+pub mod event_bus;
+pub mod export;
+pub mod extract;
+pub mod graphql;
+pub mod group;
+pub mod grpc;
+pub mod health;
+pub mod hook;
+pub mod http_cache;
+pub mod i18n;
+pub mod import;
+pub mod ip_filter;
+pub mod mail;
+pub mod message;
+pub mod metrics;
+pub mod moderation;
+pub mod normalize;
+pub mod oauth;
+pub mod organization;
+pub mod outbox;
+pub mod pagination;
+pub mod rate_limit;
+pub mod report;
+pub mod retention;
+pub mod routes;
+pub mod saml;
+pub mod seed;
+pub mod sms;
+pub mod storage;
+pub mod streaming;
+pub mod validation;
+pub mod webhooks;
+pub mod websocket;
+
+pub use crate::app_state::AppState;
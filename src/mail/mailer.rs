@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    transport::smtp::authentication::Credentials,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::secrets::redact_option, mail::template::MailTemplate};
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailProvider {
+    /// Writes messages to the log instead of sending them
+    #[default]
+    Log,
+    Smtp,
+}
+
+/// Settings from the `[mail]` section of the config file
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MailSettings {
+    pub provider: MailProvider,
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    #[serde(serialize_with = "redact_option")]
+    pub smtp_password: Option<String>,
+    /// Email users when their account is signed in to
+    pub login_alerts: bool,
+}
+
+impl Default for MailSettings {
+    fn default() -> Self {
+        Self {
+            provider: MailProvider::Log,
+            from: "Example API <noreply@example.com>".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password: None,
+            login_alerts: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn from_template(to: &str, template: &MailTemplate) -> Self {
+        let (subject, body) = template.render();
+        Self {
+            to: to.to_string(),
+            subject,
+            body,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), String>;
+}
+
+/// Dev mailer: logs each message instead of delivering it
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        tracing::info!(to = %email.to, subject = %email.subject, body = %email.body, "Mail (not sent)");
+        Ok(())
+    }
+}
+
+pub struct SmtpMailer {
+    from: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    /// STARTTLS relay on `smtp_host:smtp_port`, authenticated when a username is set
+    pub fn new(settings: &MailSettings) -> Result<Self, String> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.smtp_host)
+            .map_err(|e| e.to_string())?
+            .port(settings.smtp_port);
+        if let Some(username) = &settings.smtp_username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                settings.smtp_password.clone().unwrap_or_default(),
+            ));
+        }
+        Ok(Self {
+            from: settings.from.clone(),
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let message = Message::builder()
+            .from(
+                self.from
+                    .parse()
+                    .map_err(|e| format!("Invalid from address: {}", e))?,
+            )
+            .to(email
+                .to
+                .parse()
+                .map_err(|e| format!("Invalid recipient: {}", e))?)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Builds the configured mailer. A broken SMTP setup is logged and replaced by
+/// the log mailer so the API still starts.
+pub fn build_mailer(settings: &MailSettings) -> Arc<dyn Mailer> {
+    match settings.provider {
+        MailProvider::Log => Arc::new(LogMailer),
+        MailProvider::Smtp => match SmtpMailer::new(settings) {
+            Ok(mailer) => Arc::new(mailer),
+            Err(e) => {
+                tracing::error!(error = %e, "Invalid SMTP settings, falling back to log mailer");
+                Arc::new(LogMailer)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests_mailer {
+    use crate::mail::{
+        mailer::{Email, LogMailer, MailProvider, MailSettings, Mailer, SmtpMailer, build_mailer},
+        template::MailTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_log_mailer() {
+        let email = Email::from_template(
+            "jordan@example.com",
+            &MailTemplate::PasswordReset {
+                user_name: "Jordan".to_string(),
+                link: "https://example.com/reset?token=abc".to_string(),
+            },
+        );
+        assert_eq!(email.subject, "Reset your password");
+        assert!(LogMailer.send(email).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_smtp_rejects_invalid_recipient() {
+        let mailer = SmtpMailer::new(&MailSettings {
+            provider: MailProvider::Smtp,
+            ..Default::default()
+        })
+        .unwrap();
+        let email = Email {
+            to: "not an address".to_string(),
+            subject: "Hi".to_string(),
+            body: "Hello".to_string(),
+        };
+        let error = mailer.send(email).await.unwrap_err();
+        assert!(error.starts_with("Invalid recipient"));
+    }
+
+    #[tokio::test]
+    async fn test_build_default_mailer() {
+        let mailer = build_mailer(&MailSettings::default());
+        let email = Email {
+            to: "jordan@example.com".to_string(),
+            subject: "Hi".to_string(),
+            body: "Hello".to_string(),
+        };
+        assert!(mailer.send(email).await.is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+/// Messages the API sends. `render` returns the subject and plain-text body.
+#[derive(Debug, Clone)]
+pub enum MailTemplate {
+    /// Sent by the email verification flow
+    #[allow(dead_code)]
+    Verification { user_name: String, link: String },
+    /// Sent by the password reset flow
+    #[allow(dead_code)]
+    PasswordReset { user_name: String, link: String },
+    NewDeviceAlert {
+        user_name: String,
+        device: String,
+        ip: String,
+    },
+    /// Sent to moderators when a user or message is reported
+    ReportFiled {
+        report_id: String,
+        target: String,
+        reason: String,
+    },
+}
+
+impl MailTemplate {
+    pub fn render(&self) -> (String, String) {
+        match self {
+            MailTemplate::Verification { user_name, link } => (
+                "Verify your email address".to_string(),
+                format!(
+                    "Hi {},\n\nPlease confirm your email address by opening the link below:\n\n{}\n\nIf you didn't create an account, you can ignore this email.\n",
+                    user_name, link
+                ),
+            ),
+            MailTemplate::PasswordReset { user_name, link } => (
+                "Reset your password".to_string(),
+                format!(
+                    "Hi {},\n\nWe received a request to reset your password. Open the link below to choose a new one:\n\n{}\n\nIf you didn't ask for this, you can ignore this email.\n",
+                    user_name, link
+                ),
+            ),
+            MailTemplate::NewDeviceAlert {
+                user_name,
+                device,
+                ip,
+            } => (
+                "New sign-in to your account".to_string(),
+                format!(
+                    "Hi {},\n\nYour account was just signed in to from:\n\nDevice: {}\nIP address: {}\n\nIf this wasn't you, change your password right away.\n",
+                    user_name, device, ip
+                ),
+            ),
+            MailTemplate::ReportFiled {
+                report_id,
+                target,
+                reason,
+            } => (
+                format!("New report: {}", reason),
+                format!(
+                    "A {} was reported for {}.\n\nReport: {}\n\nReview it in the moderation queue.\n",
+                    target, reason, report_id
+                ),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_template {
+    use crate::mail::template::MailTemplate;
+
+    #[test]
+    fn test_render_verification() {
+        let (subject, body) = MailTemplate::Verification {
+            user_name: "Jordan".to_string(),
+            link: "https://example.com/verify?token=abc".to_string(),
+        }
+        .render();
+        assert_eq!(subject, "Verify your email address");
+        assert!(body.starts_with("Hi Jordan,"));
+        assert!(body.contains("https://example.com/verify?token=abc"));
+    }
+
+    #[test]
+    fn test_render_new_device_alert() {
+        let (subject, body) = MailTemplate::NewDeviceAlert {
+            user_name: "Jordan".to_string(),
+            device: "curl/8.0".to_string(),
+            ip: "10.0.0.1".to_string(),
+        }
+        .render();
+        assert_eq!(subject, "New sign-in to your account");
+        assert!(body.contains("Device: curl/8.0"));
+        assert!(body.contains("IP address: 10.0.0.1"));
+    }
+}
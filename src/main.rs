@@ -1,49 +1,120 @@
-mod app_state;
-mod auth;
-mod config;
-mod group;
-mod routes;
-mod websocket;
-
 use std::sync::Arc;
 
-use crate::auth::jwt::Secret;
-use crate::{
-    app_state::AppState,
-    config::{connection::ConnectionBuilder, flavor::load_config},
+use example_axum_api::{
+    AppState, analytics, calendar, config,
+    config::{
+        connection::connect,
+        flavor::{load_config, load_dotenv},
+        settings::Settings,
+        telemetry::Telemetry,
+    },
+    error, grpc,
+    health::handler::shutdown_signal,
+    message, metrics, outbox, retention,
     routes::routes,
+    seed, streaming, webhooks,
 };
 
-use axum::http::{HeaderValue, Method, header};
-use tower_http::cors::CorsLayer;
-
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    load_dotenv();
     let flavor = load_config().expect("Failed to load configuration");
-    let builder = ConnectionBuilder(flavor.clone());
-    let pool = ConnectionBuilder::new(&builder)
-        .await
-        .expect("Failed to connect to database");
-    let tcp = ConnectionBuilder::listen_on(&builder).expect("Failed to execute environment");
+    let settings = match Settings::load(&flavor).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration in {}:\n{}", flavor, e);
+            std::process::exit(1);
+        }
+    };
+    let telemetry = Telemetry::init(&settings);
+    settings.password.install();
+    error::install_panic_hook();
+    let pool = match connect(&settings.database).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            tracing::error!("{}", e);
+            telemetry.shutdown();
+            std::process::exit(1);
+        }
+    };
+
+    // `cargo run -- seed --users 100 --groups 10` fills the database and exits
+    if args.first().is_some_and(|command| command == "seed") {
+        let code = match seed::SeedOptions::parse(&args[1..]) {
+            Ok(options) => match seed::run(&pool, &options).await {
+                Ok(_) => 0,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to seed database");
+                    1
+                }
+            },
+            Err(e) => {
+                eprintln!("{}\nUsage: seed [--users N] [--groups N]", e);
+                2
+            }
+        };
+        telemetry.shutdown();
+        std::process::exit(code);
+    }
+
+    let tcp = settings.tcp.clone();
 
-    let secret_key = Secret::new(&flavor);
-    let state = Arc::new(AppState::new(pool, secret_key));
+    let state = Arc::new(AppState::new(pool, settings));
+    if let Err(e) = state.shadow_bans.load(&state.pool).await {
+        tracing::error!(error = %e, "Failed to load shadow bans");
+    }
+    if state.settings.jwt.debug_user {
+        tracing::warn!("X-Debug-User is accepted in place of tokens; development only");
+    }
+    webhooks::delivery::spawn_dispatcher(state.clone());
+    outbox::spawn_relay(state.clone());
+    analytics::handler::spawn_flusher(state.clone());
+    analytics::daily::spawn_rollup(state.clone());
+    retention::spawn_retention(state.clone());
+    message::partition::spawn_partitions(state.clone());
+    calendar::handler::spawn_reminders(state.clone());
+    if let Some(publisher) = streaming::publisher::build_publisher(&state.settings.streaming).await
+    {
+        streaming::publisher::spawn_streamer(state.clone(), publisher);
+    }
+    metrics::spawn_pool_probe(state.clone());
+    config::runtime::spawn_watcher(state.clone(), flavor.clone());
+    let cors = state.settings.cors.layer(state.runtime.clone());
 
-    let cors = CorsLayer::new()
-        .allow_methods([
-            Method::GET,
-            Method::POST,
-            Method::PUT,
-            Method::DELETE,
-            Method::OPTIONS,
-        ])
-        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, header::ACCEPT])
-        .allow_credentials(true);
+    let app = routes(state.clone()).layer(cors);
 
-    let app = routes(state).layer(cors);
+    if state.settings.grpc.enabled {
+        let addr = format!("{}:{}", tcp.ip, state.settings.grpc.port);
+        let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+        tracing::info!("gRPC API on {}", addr);
+        let shutdown = shutdown_signal(state.clone());
+        let grpc_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::handler::serve(grpc_state, listener, shutdown).await {
+                tracing::error!(error = %e, "gRPC server failed");
+            }
+        });
+    }
 
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", tcp.ip, tcp.port))
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    tracing::info!(
+        "🚀 Server running on {}:{} in {} mode ({})",
+        tcp.ip,
+        tcp.port,
+        state.settings.name,
+        flavor
+    );
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(state.clone()))
+    .await
+    .unwrap();
+    // Whatever arrived since the last flush
+    analytics::handler::flush(&state).await;
+    telemetry.shutdown();
 }
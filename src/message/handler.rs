@@ -0,0 +1,385 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::User,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::DomainEvent,
+    message::repository::StoredMessage,
+    moderation::{
+        handler::{is_banned, mute},
+        word_filter::{Filtered, report_match},
+    },
+    organization::handler::member_role,
+    pagination::Pagination,
+    validation::Validated,
+    websocket::{
+        chat::{ChatMessage, send_to_user},
+        group::{GroupMessage, serde_msg},
+    },
+};
+
+/// Why a message sent outside a WebSocket connection was refused
+#[derive(Debug)]
+pub enum SendError {
+    GroupNotFound,
+    ReceiverNotFound,
+    NotMember,
+    Muted,
+    Banned,
+    Blocked,
+    Storage(String),
+}
+
+impl From<sqlx::Error> for SendError {
+    fn from(error: sqlx::Error) -> Self {
+        SendError::Storage(error.to_string())
+    }
+}
+
+impl From<SendError> for MetaResponse {
+    fn from(error: SendError) -> Self {
+        let (status, message) = match error {
+            SendError::GroupNotFound => (StatusCode::NOT_FOUND, "Group not found".to_string()),
+            SendError::ReceiverNotFound => (StatusCode::NOT_FOUND, "User not found".to_string()),
+            SendError::NotMember => (
+                StatusCode::FORBIDDEN,
+                "Not a member of this organization".to_string(),
+            ),
+            SendError::Muted => (StatusCode::FORBIDDEN, "You are muted".to_string()),
+            SendError::Banned => (
+                StatusCode::FORBIDDEN,
+                "You are banned from this group".to_string(),
+            ),
+            SendError::Blocked => (
+                StatusCode::BAD_REQUEST,
+                "Your message was blocked by the group's word filter".to_string(),
+            ),
+            SendError::Storage(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        MetaResponse {
+            code: status.to_i32(),
+            message,
+        }
+    }
+}
+
+async fn check_muted(state: &AppState, user_id: &str) -> Result<(), SendError> {
+    match mute(&state.pool, user_id).await?.is_active() {
+        true => Err(SendError::Muted),
+        false => Ok(()),
+    }
+}
+
+/// Posts to a group chat with the checks of `/group-chat`: organization
+/// membership, mutes, bans and the word filter. A shadow banned sender gets
+/// the message back as if it had been delivered.
+pub async fn send_group(
+    state: &Arc<AppState>,
+    sender: &User,
+    group_id: &str,
+    text: &str,
+) -> Result<GroupMessage, SendError> {
+    let group = state
+        .groups
+        .get_by_id(group_id)
+        .await
+        .ok_or(SendError::GroupNotFound)?;
+    if let Some(org_id) = &group.org_id
+        && member_role(&state.pool, org_id, &sender.user_id)
+            .await?
+            .is_none()
+    {
+        return Err(SendError::NotMember);
+    }
+    check_muted(state, &sender.user_id).await?;
+    if is_banned(&state.pool, &sender.user_id, group_id).await? {
+        return Err(SendError::Banned);
+    }
+    let word_filters = &state.group.word_filters;
+    word_filters.load(&state.pool, group_id).await?;
+    let filtered = word_filters.get(group_id).map(|filter| filter.apply(text));
+    let text = match filtered {
+        Some(Filtered::Blocked { notify }) => {
+            report_match(state, &sender.user_id, group_id, text, notify);
+            return Err(SendError::Blocked);
+        }
+        Some(Filtered::Masked { message, notify }) => {
+            report_match(state, &sender.user_id, group_id, text, notify);
+            message
+        }
+        Some(Filtered::Pass) | None => text.to_string(),
+    };
+    let message = GroupMessage {
+        id: sender.user_id.clone(),
+        name: sender.user_name.clone(),
+        message: text,
+    };
+    if state.shadow_bans.contains(&message.id) {
+        return Ok(message);
+    }
+    let _ = state.group.tx.send(serde_msg(&message));
+    state.events.publish(DomainEvent::GroupMessageCreated {
+        group_id: group_id.to_string(),
+        message: message.clone(),
+    });
+    Ok(message)
+}
+
+/// Sends a private message like `/chat` does
+pub async fn send_private(
+    state: &Arc<AppState>,
+    sender: &User,
+    receiver_id: &str,
+    text: &str,
+) -> Result<ChatMessage, SendError> {
+    let receiver = state
+        .user_cache
+        .get_user(receiver_id, &state.pool)
+        .await
+        .ok_or(SendError::ReceiverNotFound)?;
+    check_muted(state, &sender.user_id).await?;
+    let message = send_to_user(&state.chat, sender, &receiver, text).await;
+    if !state.shadow_bans.contains(&sender.user_id) {
+        state.events.publish(DomainEvent::MessageSent {
+            message: message.clone(),
+        });
+    }
+    Ok(message)
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NewGroupMessage {
+    #[validate(length(min = 1, max = 2000, message = "must be between 1 and 2000 characters"))]
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NewPrivateMessage {
+    pub receiver_id: String,
+    #[validate(length(min = 1, max = 2000, message = "must be between 1 and 2000 characters"))]
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupMessageResponse {
+    pub meta: MetaResponse,
+    pub data: GroupMessage,
+}
+
+impl IntoResponse for GroupMessageResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMessageResponse {
+    pub meta: MetaResponse,
+    pub data: ChatMessage,
+}
+
+impl IntoResponse for ChatMessageResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatHistoryResponse {
+    pub meta: MetaResponse,
+    /// Pass as `cursor` to fetch the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    pub data: Vec<StoredMessage>,
+}
+
+impl IntoResponse for ChatHistoryResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+async fn sender(state: &AppState, user_id: &str) -> Result<User, MetaResponse> {
+    state
+        .user_cache
+        .get_user(user_id, &state.pool)
+        .await
+        .ok_or(MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Unauthorized".to_string(),
+        })
+}
+
+pub async fn send_group_message_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    Validated(req): Validated<NewGroupMessage>,
+) -> Result<GroupMessageResponse, MetaResponse> {
+    let sender = sender(&state, &user.user_id).await?;
+    let message = send_group(&state, &sender, &group_id, &req.message).await?;
+    Ok(GroupMessageResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: message,
+    })
+}
+
+pub async fn send_private_message_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<NewPrivateMessage>,
+) -> Result<ChatMessageResponse, MetaResponse> {
+    let sender = sender(&state, &user.user_id).await?;
+    let message = send_private(&state, &sender, &req.receiver_id, &req.message).await?;
+    Ok(ChatMessageResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: message,
+    })
+}
+
+/// The caller's conversation with `peer_id`, newest first by default
+pub async fn chat_history_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(peer_id): Path<String>,
+    pagination: Pagination,
+) -> Result<ChatHistoryResponse, MetaResponse> {
+    let cursor = match pagination.cursor.as_deref().map(str::parse::<i64>) {
+        None => None,
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(_)) => {
+            return Err(MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Invalid cursor".to_string(),
+            });
+        }
+    };
+    if state
+        .user_cache
+        .get_user(&peer_id, &state.pool)
+        .await
+        .is_none()
+    {
+        return Err(SendError::ReceiverNotFound.into());
+    }
+    let messages = state
+        .chat
+        .messages
+        .conversation(&user.user_id, &peer_id, cursor, &pagination)
+        .await
+        .map_err(SendError::from)?;
+    let last_id = messages
+        .last()
+        .map(|message| message.message_id.to_string());
+    Ok(ChatHistoryResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        next_cursor: pagination.next_cursor(messages.len(), last_id.as_deref()),
+        data: messages,
+    })
+}
+
+#[cfg(test)]
+mod tests_message {
+    use axum::http::{StatusCode, header};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::random_name,
+        },
+        message::handler::ChatHistoryResponse,
+        routes::routes,
+        websocket::chat::send_to_user,
+    };
+
+    #[tokio::test]
+    async fn test_chat_history() {
+        let state = AppState::isolated().await;
+        let jordan = state.users.get_by_user_name("Jordan").await.unwrap();
+        let jordan = state
+            .user_cache
+            .get_user(&jordan.user_id, &state.pool)
+            .await
+            .unwrap();
+        let user_name = random_name();
+        let email = format!("{}@mail.com", user_name);
+        let peer = add(
+            &state.pool,
+            NewUser::new(user_name, email, "123456".to_string()),
+        )
+        .await
+        .unwrap();
+        let token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &jordan.user_id, &jordan.email).unwrap()
+        );
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+
+        for text in ["one", "two", "three"] {
+            send_to_user(&state.chat, &jordan, &peer, text).await;
+        }
+        // Jordan never sees what a shadow banned peer sent
+        state.shadow_bans.set(&peer.user_id, true);
+        send_to_user(&state.chat, &peer, &jordan, "cheap pills").await;
+
+        let path = format!("/api/v1/chats/{}/messages", peer.user_id);
+        let response = server
+            .get(&format!("{}?limit=2", path))
+            .add_header(header::AUTHORIZATION, &token)
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<ChatHistoryResponse>();
+        let texts: Vec<_> = body.data.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(texts, ["three", "two"]);
+
+        let response = server
+            .get(&format!(
+                "{}?limit=2&cursor={}",
+                path,
+                body.next_cursor.unwrap()
+            ))
+            .add_header(header::AUTHORIZATION, &token)
+            .await;
+        let body = response.json::<ChatHistoryResponse>();
+        assert_eq!(body.data.len(), 1);
+        assert_eq!(body.data[0].message, "one");
+        assert!(body.next_cursor.is_none());
+
+        server
+            .get(&format!("{}?cursor=abc", path))
+            .add_header(header::AUTHORIZATION, &token)
+            .await
+            .assert_status_bad_request();
+        server
+            .get("/api/v1/chats/missing/messages")
+            .add_header(header::AUTHORIZATION, &token)
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        server.get(&path).await.assert_status_unauthorized();
+    }
+}
@@ -0,0 +1,3 @@
+pub mod handler;
+pub mod partition;
+pub mod repository;
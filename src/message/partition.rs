@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres};
+use tokio::task::JoinHandle;
+
+use crate::app_state::AppState;
+
+/// Settings from the `[messages]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MessageSettings {
+    /// Monthly partitions of `messages` kept ready after the current one
+    pub partition_months_ahead: u32,
+    /// How often missing partitions are created
+    pub partition_check_secs: u64,
+}
+
+impl Default for MessageSettings {
+    fn default() -> Self {
+        Self {
+            partition_months_ahead: 3,
+            partition_check_secs: 86_400,
+        }
+    }
+}
+
+/// Creates the missing monthly partitions of `messages` from the month of
+/// `from` through `months` months after it, see the `create_message_partitions`
+/// function of the messages migration. Returns how many were created.
+#[tracing::instrument(name = "db.messages.create_partitions", skip(pool))]
+pub async fn create_partitions(
+    pool: &Pool<Postgres>,
+    from: NaiveDate,
+    months: u32,
+) -> Result<i32, Error> {
+    sqlx::query_scalar("select create_message_partitions($1, $2)")
+        .bind(from)
+        .bind(months as i32)
+        .fetch_one(pool)
+        .await
+}
+
+/// Every `messages.partition_check_secs`, makes sure the partitions of this
+/// month and the next `messages.partition_months_ahead` exist, so messages
+/// don't pile up in `messages_default`
+pub fn spawn_partitions(state: Arc<AppState>) -> JoinHandle<()> {
+    let settings = state.settings.messages.clone();
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(settings.partition_check_secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let today = Utc::now().date_naive();
+            match create_partitions(&state.pool, today, settings.partition_months_ahead).await {
+                Ok(0) => {}
+                Ok(created) => tracing::info!(created, "Created message partitions"),
+                Err(e) => tracing::error!(error = %e, "Failed to create message partitions"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_partition {
+    use chrono::NaiveDate;
+
+    use crate::{AppState, message::partition::create_partitions};
+
+    #[tokio::test]
+    async fn test_create_partitions() {
+        let state = AppState::isolated().await;
+        let user = state.users.get_by_user_name("Jordan").await.unwrap();
+        let insert = |sent_at: &'static str| {
+            sqlx::query(
+                "insert into messages (sender_id, receiver_id, body, sent_at) \
+                 values ($1, $1, 'note', $2::timestamptz)",
+            )
+            .bind(user.user_id.clone())
+            .bind(sent_at)
+            .execute(&*state.pool)
+        };
+        let partition = || {
+            sqlx::query_scalar::<_, String>(
+                "select tableoid::regclass::text from messages where sent_at = '2031-05-10T08:00:00Z'",
+            )
+            .fetch_one(&*state.pool)
+        };
+
+        // Past the partitions the migration made, the default one takes it
+        insert("2031-05-10T08:00:00Z").await.unwrap();
+        assert_eq!(partition().await.unwrap(), "messages_default");
+
+        let day = NaiveDate::from_ymd_opt(2031, 4, 20).unwrap();
+        assert_eq!(create_partitions(&state.pool, day, 2).await.unwrap(), 3);
+        assert_eq!(create_partitions(&state.pool, day, 2).await.unwrap(), 0);
+        // Moved out of the default partition, and new rows go straight in
+        assert_eq!(partition().await.unwrap(), "messages_2031_05");
+        insert("2031-06-30T23:59:59Z").await.unwrap();
+        let count: i64 = sqlx::query_scalar("select count(*) from messages_2031_06")
+            .fetch_one(&*state.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,494 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+
+use crate::{
+    pagination::{Pagination, Sort},
+    websocket::chat::ChatMessage,
+};
+
+/// A private message as kept in the history
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoredMessage {
+    /// Pass as `cursor` to page from this message on
+    pub message_id: i64,
+    pub sender_id: String,
+    pub receiver_id: String,
+    pub message: String,
+    pub sent_at: String,
+}
+
+fn message_from_row(row: PgRow) -> StoredMessage {
+    StoredMessage {
+        message_id: row.get("message_id"),
+        sender_id: row.get("sender_id"),
+        receiver_id: row.get("receiver_id"),
+        message: row.get("body"),
+        sent_at: row.get::<DateTime<Utc>, _>("sent_at").to_rfc3339(),
+    }
+}
+
+/// Private chat history. `PgMessageRepository` is the real one; tests can
+/// swap in `MemoryMessageRepository` through `PrivateChatState::new`.
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    /// Stores a message as sent. A `hidden` one comes from a shadow banned
+    /// sender and never reached the receiver. Returns its `message_id`.
+    async fn add(&self, message: &ChatMessage, hidden: bool) -> Result<i64, Error>;
+    /// Messages between `user_id` and `peer_id` in the order they were sent,
+    /// newest first unless `pagination.sort` is `asc`. Hidden ones are only
+    /// seen by their sender. The cursor is a `message_id`; an unknown one
+    /// gives an empty page.
+    async fn conversation(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+        cursor: Option<i64>,
+        pagination: &Pagination,
+    ) -> Result<Vec<StoredMessage>, Error>;
+}
+
+pub struct PgMessageRepository {
+    pool: Pool<Postgres>,
+}
+
+impl PgMessageRepository {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl MessageRepository for PgMessageRepository {
+    async fn add(&self, message: &ChatMessage, hidden: bool) -> Result<i64, Error> {
+        insert(&self.pool, message, hidden).await
+    }
+
+    async fn conversation(
+        &self,
+        user_id: &str,
+        peer_id: &str,
+        cursor: Option<i64>,
+        pagination: &Pagination,
+    ) -> Result<Vec<StoredMessage>, Error> {
+        conversation(&self.pool, user_id, peer_id, cursor, pagination).await
+    }
+}
+
+#[tracing::instrument(name = "db.messages.insert", skip_all)]
+pub async fn insert(
+    pool: &Pool<Postgres>,
+    message: &ChatMessage,
+    hidden: bool,
+) -> Result<i64, Error> {
+    let sent_at = DateTime::<Utc>::from_timestamp(message.timestamp as i64, 0).unwrap_or_default();
+    sqlx::query_scalar(
+        "insert into messages (sender_id, receiver_id, body, sent_at, hidden) \
+         values ($1, $2, $3, $4, $5) returning message_id",
+    )
+    .bind(&message.sender_user.user_id)
+    .bind(&message.receiver_user.user_id)
+    .bind(&message.message)
+    .bind(sent_at)
+    .bind(hidden)
+    .fetch_one(pool)
+    .await
+}
+
+/// The history query, paged by `(sent_at, message_id)`. With a cursor, `$3`
+/// and `$4` are its `sent_at` and `message_id`; the plain bound on `sent_at`
+/// is what lets Postgres skip the monthly partitions past it, which it
+/// doesn't do for the row comparison alone.
+fn conversation_sql(cursor: bool, sort: Sort) -> String {
+    let after = if cursor {
+        format!(
+            "and sent_at {after}= $3 and (sent_at, message_id) {after} ($3, $4) ",
+            after = sort.after()
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "select message_id, sender_id, receiver_id, body, sent_at from messages \
+         where ((sender_id = $1 and receiver_id = $2) or (sender_id = $2 and receiver_id = $1)) \
+         and (not hidden or sender_id = $1) \
+         {after}order by sent_at {sort}, message_id {sort} limit $5 offset $6",
+        sort = sort.sql()
+    )
+}
+
+#[tracing::instrument(name = "db.messages.conversation", skip(pool, pagination))]
+pub async fn conversation(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    peer_id: &str,
+    cursor: Option<i64>,
+    pagination: &Pagination,
+) -> Result<Vec<StoredMessage>, Error> {
+    let sent_at: Option<DateTime<Utc>> = match cursor {
+        Some(cursor) => {
+            let sent_at = sqlx::query_scalar("select sent_at from messages where message_id = $1")
+                .bind(cursor)
+                .fetch_optional(pool)
+                .await?;
+            // Nothing comes after a message that isn't there
+            if sent_at.is_none() {
+                return Ok(Vec::new());
+            }
+            sent_at
+        }
+        None => None,
+    };
+    sqlx::query(&conversation_sql(cursor.is_some(), pagination.sort))
+        .bind(user_id)
+        .bind(peer_id)
+        .bind(sent_at)
+        .bind(cursor)
+        .bind(pagination.limit())
+        .bind(pagination.offset())
+        .map(message_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+#[cfg(test)]
+pub use fake::MemoryMessageRepository;
+
+#[cfg(test)]
+mod fake {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use chrono::DateTime;
+    use sqlx::Error;
+
+    use crate::{
+        message::repository::{MessageRepository, StoredMessage},
+        pagination::{Pagination, Sort},
+        websocket::chat::ChatMessage,
+    };
+
+    /// In-memory history for tests that don't need Postgres
+    #[derive(Default)]
+    pub struct MemoryMessageRepository {
+        messages: Mutex<Vec<(ChatMessage, bool)>>,
+    }
+
+    #[async_trait]
+    impl MessageRepository for MemoryMessageRepository {
+        async fn add(&self, message: &ChatMessage, hidden: bool) -> Result<i64, Error> {
+            let mut messages = self.messages.lock().unwrap();
+            messages.push((message.clone(), hidden));
+            Ok(messages.len() as i64)
+        }
+
+        async fn conversation(
+            &self,
+            user_id: &str,
+            peer_id: &str,
+            cursor: Option<i64>,
+            pagination: &Pagination,
+        ) -> Result<Vec<StoredMessage>, Error> {
+            let messages = self.messages.lock().unwrap();
+            let mut found: Vec<StoredMessage> = messages
+                .iter()
+                .zip(1..)
+                .filter(|((message, hidden), _)| {
+                    let (sender, receiver) = (
+                        message.sender_user.user_id.as_str(),
+                        message.receiver_user.user_id.as_str(),
+                    );
+                    ((sender, receiver) == (user_id, peer_id)
+                        || (sender, receiver) == (peer_id, user_id))
+                        && (!hidden || sender == user_id)
+                })
+                .map(|((message, _), message_id)| StoredMessage {
+                    message_id,
+                    sender_id: message.sender_user.user_id.clone(),
+                    receiver_id: message.receiver_user.user_id.clone(),
+                    message: message.message.clone(),
+                    sent_at: DateTime::from_timestamp(message.timestamp as i64, 0)
+                        .unwrap_or_default()
+                        .to_rfc3339(),
+                })
+                .collect();
+            found.sort_by_key(|message| (message.sent_at.clone(), message.message_id));
+            if let Some(cursor) = cursor {
+                let Some((message, _)) = usize::try_from(cursor - 1)
+                    .ok()
+                    .and_then(|index| messages.get(index))
+                else {
+                    return Ok(Vec::new());
+                };
+                let key = (
+                    DateTime::from_timestamp(message.timestamp as i64, 0)
+                        .unwrap_or_default()
+                        .to_rfc3339(),
+                    cursor,
+                );
+                found.retain(|message| {
+                    let position = (message.sent_at.clone(), message.message_id);
+                    match pagination.sort {
+                        Sort::Asc => position > key,
+                        Sort::Desc => position < key,
+                    }
+                });
+            }
+            if pagination.sort == Sort::Desc {
+                found.reverse();
+            }
+            Ok(found
+                .into_iter()
+                .skip(pagination.offset() as usize)
+                .take(pagination.per_page as usize)
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_message_repository {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use sqlx::{Error, Pool, Postgres};
+
+    use crate::{
+        app_state::test_config,
+        auth::{
+            user::{NewUser, User, add},
+            util::random_name,
+        },
+        config::connection::ConnectionBuilder,
+        message::repository::{
+            MessageRepository, PgMessageRepository, StoredMessage, conversation, conversation_sql,
+        },
+        moderation::shadow_ban::ShadowBans,
+        pagination::{Pagination, Sort},
+        websocket::{
+            chat::{ChatMessage, PrivateChatState, send_to_user},
+            event::ServerEvent,
+        },
+    };
+
+    async fn user(pool: &Pool<Postgres>) -> Result<User, Error> {
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        add(pool, NewUser::new(user_name, email, "123456".to_string())).await
+    }
+
+    async fn stored(pool: &Pool<Postgres>, sender_id: &str) -> Result<Vec<(String, bool)>, Error> {
+        sqlx::query_as("select body, hidden from messages where sender_id = $1 order by message_id")
+            .bind(sender_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_send_to_user_stores_message() -> Result<(), Error> {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let sender = user(&pool).await?;
+        let receiver = user(&pool).await?;
+
+        let shadow_bans = Arc::new(ShadowBans::default());
+        let chat = PrivateChatState::new(
+            shadow_bans.clone(),
+            Arc::new(PgMessageRepository::new(pool.clone())),
+        );
+        let mut receiver_rx = chat.subscribe(&receiver.user_id).await;
+
+        // Stored whether or not the receiver is connected
+        send_to_user(&chat, &sender, &receiver, "Hello").await;
+        assert!(receiver_rx.recv().await.unwrap().contains("Hello"));
+        drop(receiver_rx);
+        chat.unsubscribe(&receiver.user_id).await;
+        send_to_user(&chat, &sender, &receiver, "Still there?").await;
+
+        // A shadow banned sender's message is kept, hidden
+        shadow_bans.set(&sender.user_id, true);
+        send_to_user(&chat, &sender, &receiver, "cheap pills").await;
+
+        assert_eq!(
+            stored(&pool, &sender.user_id).await?,
+            [
+                ("Hello".to_string(), false),
+                ("Still there?".to_string(), false),
+                ("cheap pills".to_string(), true),
+            ]
+        );
+        pool.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conversation() -> Result<(), Error> {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let alice = user(&pool).await?;
+        let bob = user(&pool).await?;
+        let carol = user(&pool).await?;
+
+        let chat = PrivateChatState::new(
+            Arc::new(ShadowBans::default()),
+            Arc::new(PgMessageRepository::new(pool.clone())),
+        );
+        for text in ["one", "two", "three"] {
+            send_to_user(&chat, &alice, &bob, text).await;
+        }
+        send_to_user(&chat, &bob, &alice, "four").await;
+        send_to_user(&chat, &alice, &carol, "elsewhere").await;
+
+        let texts = |messages: Vec<StoredMessage>| {
+            messages
+                .into_iter()
+                .map(|message| message.message)
+                .collect::<Vec<_>>()
+        };
+        let first = Pagination {
+            per_page: 3,
+            ..Pagination::default()
+        };
+        let page = conversation(&pool, &bob.user_id, &alice.user_id, None, &first).await?;
+        assert_eq!(page[0].sender_id, bob.user_id);
+        let cursor = page.last().map(|message| message.message_id);
+        assert_eq!(texts(page), ["four", "three", "two"]);
+
+        let rest = conversation(&pool, &bob.user_id, &alice.user_id, cursor, &first).await?;
+        assert_eq!(texts(rest), ["one"]);
+
+        let oldest = Pagination {
+            per_page: 2,
+            page: 2,
+            sort: Sort::Asc,
+            ..Pagination::default()
+        };
+        let page = conversation(&pool, &alice.user_id, &bob.user_id, None, &oldest).await?;
+        assert_eq!(texts(page), ["three", "four"]);
+        pool.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conversation_by_sent_at() -> Result<(), Error> {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let alice = user(&pool).await?;
+        let bob = user(&pool).await?;
+        let insert = |body: &'static str, sent_at: &'static str| {
+            sqlx::query_scalar::<_, i64>(
+                "insert into messages (sender_id, receiver_id, body, sent_at) \
+                 values ($1, $2, $3, $4::timestamptz) returning message_id",
+            )
+            .bind(alice.user_id.clone())
+            .bind(bob.user_id.clone())
+            .bind(body)
+            .bind(sent_at)
+            .fetch_one(&pool)
+        };
+        // Stored late, like a message replayed from an outbox
+        insert("new", "2020-03-01T10:00:00Z").await?;
+        let old = insert("old", "2020-01-15T10:00:00Z").await?;
+        insert("older", "2020-01-01T10:00:00Z").await?;
+
+        let texts = |messages: Vec<StoredMessage>| {
+            messages
+                .into_iter()
+                .map(|message| message.message)
+                .collect::<Vec<_>>()
+        };
+        let page = conversation(
+            &pool,
+            &bob.user_id,
+            &alice.user_id,
+            None,
+            &Pagination::default(),
+        )
+        .await?;
+        assert_eq!(texts(page), ["new", "old", "older"]);
+        let page = conversation(
+            &pool,
+            &bob.user_id,
+            &alice.user_id,
+            Some(old),
+            &Pagination::default(),
+        )
+        .await?;
+        assert_eq!(texts(page), ["older"]);
+        let page = conversation(
+            &pool,
+            &bob.user_id,
+            &alice.user_id,
+            Some(-1),
+            &Pagination::default(),
+        )
+        .await?;
+        assert!(page.is_empty());
+
+        // Paging back from January leaves out the monthly partitions after it
+        let plan: Vec<String> = sqlx::query_scalar(&format!(
+            "explain (costs off) {}",
+            conversation_sql(true, Sort::Desc)
+        ))
+        .bind(&bob.user_id)
+        .bind(&alice.user_id)
+        .bind("2020-01-15T10:00:00Z".parse::<DateTime<Utc>>().unwrap())
+        .bind(old)
+        .bind(10_i64)
+        .bind(0_i64)
+        .fetch_all(&pool)
+        .await?;
+        let scanned: Vec<&String> = plan
+            .iter()
+            .filter(|line| line.contains("on messages_2"))
+            .collect();
+        assert!(scanned.is_empty(), "{:?}", plan);
+        assert!(plan.iter().any(|line| line.contains("messages_default")));
+        pool.close().await;
+        Ok(())
+    }
+
+    /// A history that can't be written to
+    struct Unavailable;
+
+    #[async_trait]
+    impl MessageRepository for Unavailable {
+        async fn add(&self, _: &ChatMessage, _: bool) -> Result<i64, Error> {
+            Err(Error::PoolTimedOut)
+        }
+
+        async fn conversation(
+            &self,
+            _: &str,
+            _: &str,
+            _: Option<i64>,
+            _: &Pagination,
+        ) -> Result<Vec<StoredMessage>, Error> {
+            Err(Error::PoolTimedOut)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_to_user_unstored() -> Result<(), Error> {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await?;
+        let sender = user(&pool).await?;
+        let receiver = user(&pool).await?;
+
+        let chat = PrivateChatState::new(Arc::new(ShadowBans::default()), Arc::new(Unavailable));
+        let mut sender_rx = chat.subscribe(&sender.user_id).await;
+        let mut receiver_rx = chat.subscribe(&receiver.user_id).await;
+
+        // Still delivered, the sender hears it wasn't kept
+        send_to_user(&chat, &sender, &receiver, "Hello").await;
+        assert!(receiver_rx.recv().await.unwrap().contains("Hello"));
+        assert!(sender_rx.recv().await.unwrap().contains("Hello"));
+        let notice = serde_json::from_str(&sender_rx.recv().await.unwrap()).unwrap();
+        assert!(matches!(notice, ServerEvent::Notice { .. }));
+        assert_eq!(chat.unstored(), 1);
+        pool.close().await;
+        Ok(())
+    }
+}
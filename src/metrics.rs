@@ -0,0 +1,387 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tracing::{Subscriber, span};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+use crate::app_state::AppState;
+
+/// Seconds covered by the request rate
+const RATE_WINDOW: usize = 60;
+
+/// Upper bounds of the query duration buckets in milliseconds; slower
+/// queries land in one more, unbounded bucket
+pub const QUERY_BUCKETS_MS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+
+/// How often `spawn_pool_probe` times a connection acquire
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// `/ws`
+    Echo,
+    /// `/chat`
+    PrivateChat,
+    /// `/group-chat`
+    GroupChat,
+    /// `/api/v1/events`
+    Sse,
+    /// `/graphql/ws`
+    GraphqlWs,
+    /// gRPC `Messages.Subscribe`
+    Grpc,
+    /// `/socket.io/`
+    SocketIo,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 7] = [
+        Channel::Echo,
+        Channel::PrivateChat,
+        Channel::GroupChat,
+        Channel::Sse,
+        Channel::GraphqlWs,
+        Channel::Grpc,
+        Channel::SocketIo,
+    ];
+}
+
+/// In-process counters for HTTP traffic and live connections
+pub struct Metrics {
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    /// Requests per second over the last `RATE_WINDOW` seconds, as `(second, count)`
+    recent: Mutex<[(u64, u64); RATE_WINDOW]>,
+    connections: [AtomicI64; Channel::ALL.len()],
+    /// Last probed acquire wait in microseconds, primary then replica
+    acquire_wait: [AtomicU64; 2],
+}
+
+/// Decrements the channel's connection count when dropped
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    channel: Channel,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.connections[self.channel as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestStats {
+    pub total: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub last_minute: u64,
+    pub per_second: f64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+            recent: Mutex::new([(0, 0); RATE_WINDOW]),
+            connections: Default::default(),
+            acquire_wait: Default::default(),
+        }
+    }
+
+    pub fn record_request(&self, status: u16) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        match status {
+            400..=499 => self.client_errors.fetch_add(1, Ordering::Relaxed),
+            500..=599 => self.server_errors.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+
+        let now = now_secs();
+        let mut recent = self.recent.lock().unwrap();
+        let slot = &mut recent[now as usize % RATE_WINDOW];
+        if slot.0 != now {
+            *slot = (now, 0);
+        }
+        slot.1 += 1;
+    }
+
+    pub fn requests(&self) -> RequestStats {
+        let since = now_secs().saturating_sub(RATE_WINDOW as u64);
+        let last_minute = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(second, _)| *second > since)
+            .map(|(_, count)| count)
+            .sum();
+        RequestStats {
+            total: self.requests.load(Ordering::Relaxed),
+            client_errors: self.client_errors.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+            last_minute,
+            per_second: last_minute as f64 / RATE_WINDOW as f64,
+        }
+    }
+
+    /// Counts a live connection until the guard is dropped
+    pub fn connection(self: &Arc<Self>, channel: Channel) -> ConnectionGuard {
+        self.connections[channel as usize].fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: self.clone(),
+            channel,
+        }
+    }
+
+    pub fn connections(&self, channel: Channel) -> i64 {
+        self.connections[channel as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_acquire_wait(&self, replica: bool, wait: Duration) {
+        self.acquire_wait[replica as usize].store(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Time the last probe waited for a connection, in milliseconds
+    pub fn acquire_wait_ms(&self, replica: bool) -> f64 {
+        self.acquire_wait[replica as usize].load(Ordering::Relaxed) as f64 / 1000.0
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bucket {
+    /// Upper bound in milliseconds, absent for the last bucket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryStats {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; QUERY_BUCKETS_MS.len() + 1],
+    count: u64,
+    sum_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = QUERY_BUCKETS_MS
+            .iter()
+            .position(|le| ms < *le)
+            .unwrap_or(QUERY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        let us = elapsed.as_micros() as u64;
+        self.sum_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    fn stats(&self) -> QueryStats {
+        QueryStats {
+            count: self.count,
+            mean_ms: if self.count > 0 {
+                self.sum_us as f64 / self.count as f64 / 1000.0
+            } else {
+                0.0
+            },
+            max_ms: self.max_us as f64 / 1000.0,
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(i, count)| Bucket {
+                    le_ms: QUERY_BUCKETS_MS.get(i).copied(),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Durations of the `db.*` spans by span name. Process wide, since the
+/// tracing subscriber is installed before any `AppState` exists.
+static QUERIES: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+pub fn query_stats() -> BTreeMap<String, QueryStats> {
+    QUERIES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, histogram)| (name.to_string(), histogram.stats()))
+        .collect()
+}
+
+struct Started(Instant);
+
+/// Times every `db.*` span, i.e. the query functions carrying
+/// `#[tracing::instrument(name = "db....")]`, into `query_stats`. Spans the
+/// log level filters out are not timed.
+pub struct QueryTimer;
+
+impl<S> Layer<S> for QueryTimer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name().starts_with("db.")
+            && let Some(span) = ctx.span(id)
+        {
+            span.extensions_mut().insert(Started(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        if let Some(Started(started)) = span.extensions().get::<Started>() {
+            QUERIES
+                .lock()
+                .unwrap()
+                .entry(span.name())
+                .or_default()
+                .record(started.elapsed());
+        }
+    }
+}
+
+/// Times a connection acquire on each pool every `PROBE_INTERVAL`, which
+/// shows how long requests currently queue for a connection
+pub fn spawn_pool_probe(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+            state
+                .metrics
+                .record_acquire_wait(false, time_acquire(&state.pool).await);
+            if let Some(replica) = &state.replica {
+                state
+                    .metrics
+                    .record_acquire_wait(true, time_acquire(replica).await);
+            }
+        }
+    });
+}
+
+/// Until a connection is handed out, or the pool gives up
+async fn time_acquire(pool: &Pool<Postgres>) -> Duration {
+    let started = Instant::now();
+    let _ = pool.acquire().await;
+    started.elapsed()
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records the status of every response
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(req).await;
+    state.metrics.record_request(response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod tests_metrics {
+    use std::sync::Arc;
+
+    use std::time::Duration;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::metrics::{Channel, Histogram, Metrics, QueryTimer, query_stats};
+
+    #[test]
+    fn test_record_requests() {
+        let metrics = Metrics::new();
+        for status in [200, 201, 404, 500] {
+            metrics.record_request(status);
+        }
+        let stats = metrics.requests();
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.client_errors, 1);
+        assert_eq!(stats.server_errors, 1);
+        assert_eq!(stats.last_minute, 4);
+    }
+
+    #[test]
+    fn test_connection_guard() {
+        let metrics = Arc::new(Metrics::new());
+        let first = metrics.connection(Channel::GroupChat);
+        let second = metrics.connection(Channel::GroupChat);
+        assert_eq!(metrics.connections(Channel::GroupChat), 2);
+        assert_eq!(metrics.connections(Channel::PrivateChat), 0);
+
+        drop(first);
+        drop(second);
+        assert_eq!(metrics.connections(Channel::GroupChat), 0);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        for ms in [0, 3, 3, 7000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let stats = histogram.stats();
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.max_ms, 7000.0);
+        assert_eq!(stats.buckets[0].count, 1);
+        assert_eq!(stats.buckets[1].count, 2);
+        assert_eq!(stats.buckets.last().unwrap().le_ms, None);
+        assert_eq!(stats.buckets.last().unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_query_timer() {
+        let subscriber = tracing_subscriber::registry().with(QueryTimer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("db.tests.timed").in_scope(|| {});
+            tracing::info_span!("http_request").in_scope(|| {});
+        });
+
+        let stats = query_stats();
+        assert_eq!(stats["db.tests.timed"].count, 1);
+        assert!(!stats.contains_key("http_request"));
+    }
+}
@@ -0,0 +1,747 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, PgExecutor, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    audit::handler::record,
+    auth::{
+        extractors::AuthUser,
+        user::User,
+        util::{MetaResponse, StatusCodeExt, client_ip},
+    },
+    group::handler::Group,
+    pagination::Pagination,
+    report::handler::{Report, ReportStatus, against_user, get, queue, set_status},
+    validation::Validated,
+    websocket::event::ServerEvent,
+};
+
+/// Other reports against the same user shown with a report
+const HISTORY: i64 = 10;
+
+/// Longest timed mute, a year
+const MAX_MUTE_MINUTES: u32 = 525_600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanctionKind {
+    /// May not send chat messages anywhere
+    Mute,
+    /// May not join one group's chat
+    GroupBan,
+}
+
+impl SanctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SanctionKind::Mute => "mute",
+            SanctionKind::GroupBan => "group_ban",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "group_ban" => SanctionKind::GroupBan,
+            _ => SanctionKind::Mute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    pub sanction_id: String,
+    pub user_id: String,
+    pub kind: SanctionKind,
+    /// The group of a ban
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_id: Option<String>,
+    /// The moderator who imposed it
+    pub created_by: String,
+    /// RFC 3339; absent when it doesn't expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+fn sanction_from_row(row: PgRow) -> Sanction {
+    Sanction {
+        sanction_id: row.get("sanction_id"),
+        user_id: row.get("user_id"),
+        kind: SanctionKind::parse(row.get("kind")),
+        group_id: row.get("group_id"),
+        report_id: row.get("report_id"),
+        created_by: row.get("created_by"),
+        expires_at: row
+            .get::<Option<DateTime<Utc>>, _>("expires_at")
+            .map(|at| at.to_rfc3339()),
+        created_at: row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+    }
+}
+
+/// Whether a user may send chat messages, from their active mutes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Mute {
+    #[default]
+    None,
+    Until(DateTime<Utc>),
+    Indefinite,
+}
+
+impl Mute {
+    pub fn is_active(&self) -> bool {
+        match self {
+            Mute::None => false,
+            Mute::Until(until) => *until > Utc::now(),
+            Mute::Indefinite => true,
+        }
+    }
+
+    /// The longer of the two
+    pub fn extend(self, other: Mute) -> Mute {
+        match (self, other) {
+            (Mute::Indefinite, _) | (_, Mute::Indefinite) => Mute::Indefinite,
+            (Mute::Until(a), Mute::Until(b)) => Mute::Until(a.max(b)),
+            (Mute::None, mute) | (mute, Mute::None) => mute,
+        }
+    }
+
+    fn expiring(expires_at: Option<DateTime<Utc>>) -> Mute {
+        expires_at.map_or(Mute::Indefinite, Mute::Until)
+    }
+}
+
+impl From<&Sanction> for Mute {
+    fn from(sanction: &Sanction) -> Self {
+        if sanction.kind != SanctionKind::Mute {
+            return Mute::None;
+        }
+        Mute::expiring(
+            sanction
+                .expires_at
+                .as_deref()
+                .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+                .map(|at| at.with_timezone(&Utc)),
+        )
+    }
+}
+
+#[tracing::instrument(name = "db.user_sanctions.add", skip(executor))]
+pub async fn add(
+    executor: impl PgExecutor<'_>,
+    user_id: &str,
+    kind: SanctionKind,
+    group_id: Option<&str>,
+    report_id: Option<&str>,
+    created_by: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Sanction, Error> {
+    let sql = "insert into user_sanctions \
+               (sanction_id, user_id, kind, group_id, report_id, created_by, expires_at) \
+               values ($1, $2, $3, $4, $5, $6, $7) returning *";
+    sqlx::query(sql)
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(kind.as_str())
+        .bind(group_id)
+        .bind(report_id)
+        .bind(created_by)
+        .bind(expires_at)
+        .map(sanction_from_row)
+        .fetch_one(executor)
+        .await
+}
+
+/// Sanctions of the user that haven't expired
+#[tracing::instrument(name = "db.user_sanctions.active", skip(pool))]
+pub async fn active(pool: &Pool<Postgres>, user_id: &str) -> Result<Vec<Sanction>, Error> {
+    let sql = "select * from user_sanctions where user_id = $1 \
+               and (expires_at is null or expires_at > current_timestamp) \
+               order by created_at";
+    sqlx::query(sql)
+        .bind(user_id)
+        .map(sanction_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.user_sanctions.mute", skip(pool))]
+pub async fn mute(pool: &Pool<Postgres>, user_id: &str) -> Result<Mute, Error> {
+    let sql = "select expires_at from user_sanctions where user_id = $1 and kind = 'mute' \
+               and (expires_at is null or expires_at > current_timestamp)";
+    let mutes: Vec<Option<DateTime<Utc>>> = sqlx::query_scalar(sql)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(mutes
+        .into_iter()
+        .map(Mute::expiring)
+        .fold(Mute::None, Mute::extend))
+}
+
+#[tracing::instrument(name = "db.user_sanctions.is_banned", skip(pool))]
+pub async fn is_banned(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    group_id: &str,
+) -> Result<bool, Error> {
+    let sql = "select exists(select 1 from user_sanctions \
+               where user_id = $1 and kind = 'group_ban' and group_id = $2 \
+               and (expires_at is null or expires_at > current_timestamp))";
+    sqlx::query_scalar(sql)
+        .bind(user_id)
+        .bind(group_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Pushes `event` to every open private chat and SSE connection of the user
+pub async fn push(state: &AppState, user_id: &str, event: &ServerEvent) {
+    if let Some(tx) = state.chat.connections.read().await.get(user_id) {
+        let _ = tx.send(event.to_json());
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueQuery {
+    /// `open` when missing
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueResponse {
+    pub meta: MetaResponse,
+    pub page: u32,
+    pub data: Vec<Report>,
+}
+
+impl IntoResponse for QueueResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// A report with what a moderator needs to decide on it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportContext {
+    pub report: Report,
+    /// Absent once the account is deleted
+    pub reported_user: Option<User>,
+    pub reporter: Option<User>,
+    pub group: Option<Group>,
+    /// The latest other reports against the same user
+    pub previous_reports: Vec<Report>,
+    /// Active sanctions of the reported user
+    pub sanctions: Vec<Sanction>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportContextResponse {
+    pub meta: MetaResponse,
+    pub data: ReportContext,
+}
+
+impl IntoResponse for ReportContextResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Take the report off the open queue
+    Review,
+    /// Mute the reported user and resolve the report
+    Mute,
+    /// Ban the reported user from the report's group and resolve the report
+    Ban,
+    /// Close the report without action
+    Dismiss,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Review => "review",
+            ModerationAction::Mute => "mute",
+            ModerationAction::Ban => "ban",
+            ModerationAction::Dismiss => "dismiss",
+        }
+    }
+
+    /// Where the report ends up
+    fn status(&self) -> ReportStatus {
+        match self {
+            ModerationAction::Review => ReportStatus::Reviewing,
+            ModerationAction::Mute | ModerationAction::Ban => ReportStatus::Resolved,
+            ModerationAction::Dismiss => ReportStatus::Dismissed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct ModerationRequest {
+    pub action: ModerationAction,
+    /// How long a mute or ban lasts; indefinite when missing
+    #[validate(range(
+        min = 1,
+        max = "MAX_MUTE_MINUTES",
+        message = "must be between 1 and 525600"
+    ))]
+    pub minutes: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub report: Report,
+    /// Imposed by a mute or ban
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sanction: Option<Sanction>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerationResponse {
+    pub meta: MetaResponse,
+    pub data: ModerationResult,
+}
+
+impl IntoResponse for ModerationResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+fn db_error(e: Error) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+async fn find_report(state: &AppState, report_id: &str) -> Result<Report, MetaResponse> {
+    get(&state.pool, report_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Report not found".to_string(),
+        })
+}
+
+pub async fn queue_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<QueueQuery>,
+    pagination: Pagination,
+) -> Result<QueueResponse, MetaResponse> {
+    let status = match params.status.as_deref() {
+        None => ReportStatus::Open,
+        Some(status) => ReportStatus::parse(status).ok_or_else(|| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "status must be one of open, reviewing, resolved, dismissed".to_string(),
+        })?,
+    };
+    let data = queue(&state.pool, status, pagination.limit(), pagination.offset())
+        .await
+        .map_err(db_error)?;
+    Ok(QueueResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        page: pagination.page,
+        data,
+    })
+}
+
+pub async fn report_context_handler(
+    State(state): State<Arc<AppState>>,
+    Path(report_id): Path<String>,
+) -> Result<ReportContextResponse, MetaResponse> {
+    let report = find_report(&state, &report_id).await?;
+    let reported_user = state
+        .user_cache
+        .get_user(&report.user_id, &state.pool)
+        .await;
+    let reporter = state
+        .user_cache
+        .get_user(&report.reporter_id, &state.pool)
+        .await;
+    let group = match &report.group_id {
+        Some(group_id) => state.groups.get_by_id(group_id).await,
+        None => None,
+    };
+    let previous_reports = against_user(&state.pool, &report.user_id, &report_id, HISTORY)
+        .await
+        .map_err(db_error)?;
+    let sanctions = active(&state.pool, &report.user_id)
+        .await
+        .map_err(db_error)?;
+    Ok(ReportContextResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: ReportContext {
+            report,
+            reported_user,
+            reporter,
+            group,
+            previous_reports,
+            sanctions,
+        },
+    })
+}
+
+/// Applies a moderator's decision on a report. The sanction and the report's
+/// new status are stored together; the action is then recorded in the audit
+/// log as `moderation.<action>`, the sanctioned user's connections are told
+/// (which mutes or disconnects them) and the reporter hears the outcome.
+pub async fn action_handler(
+    AuthUser(claims): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(report_id): Path<String>,
+    headers: HeaderMap,
+    Validated(req): Validated<ModerationRequest>,
+) -> Result<ModerationResponse, MetaResponse> {
+    let report = find_report(&state, &report_id).await?;
+    let status = req.action.status();
+    if !report.status.next().contains(&status) {
+        return Err(MetaResponse {
+            code: StatusCode::CONFLICT.to_i32(),
+            message: format!("Report is already {}", report.status.as_str()),
+        });
+    }
+    let kind = match req.action {
+        ModerationAction::Mute => Some(SanctionKind::Mute),
+        ModerationAction::Ban => Some(SanctionKind::GroupBan),
+        ModerationAction::Review | ModerationAction::Dismiss => None,
+    };
+    if kind == Some(SanctionKind::GroupBan) && report.group_id.is_none() {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "Report has no group to ban from".to_string(),
+        });
+    }
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    let sanction = match kind {
+        Some(kind) => Some(
+            add(
+                &mut *tx,
+                &report.user_id,
+                kind,
+                report.group_id.as_deref(),
+                Some(&report.report_id),
+                &claims.user_id,
+                req.minutes
+                    .map(|minutes| Utc::now() + Duration::minutes(minutes.into())),
+            )
+            .await
+            .map_err(db_error)?,
+        ),
+        None => None,
+    };
+    // Another moderator may have closed it meanwhile
+    let report = set_status(&mut *tx, &report_id, status)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::CONFLICT.to_i32(),
+            message: "Report was updated by someone else, reload it".to_string(),
+        })?;
+    tx.commit().await.map_err(db_error)?;
+
+    let action = format!("moderation.{}", req.action.as_str());
+    let target = format!("report={} user={}", report.report_id, report.user_id);
+    if let Err(e) = record(
+        &state.pool,
+        &claims.user_id,
+        &action,
+        &target,
+        StatusCode::OK.as_u16(),
+        client_ip(&headers).as_deref(),
+    )
+    .await
+    {
+        tracing::error!(action, error = %e, "Failed to write audit log");
+    }
+
+    if let Some(sanction) = &sanction {
+        let _ = state.group.sanctions.send(sanction.clone());
+        push(
+            &state,
+            &sanction.user_id,
+            &ServerEvent::Sanction(sanction.clone()),
+        )
+        .await;
+    }
+    push(
+        &state,
+        &report.reporter_id,
+        &ServerEvent::ReportUpdated {
+            report_id: report.report_id.clone(),
+            status: report.status,
+        },
+    )
+    .await;
+
+    Ok(ModerationResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: ModerationResult { report, sanction },
+    })
+}
+
+#[cfg(test)]
+mod tests_moderation {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::hash_password,
+        },
+        moderation::handler::{
+            ModerationResponse, Mute, QueueResponse, ReportContextResponse, SanctionKind, mute,
+        },
+        report::handler::{NewReport, Report, ReportReason, ReportStatus, ReportTarget, create},
+        routes::routes,
+        websocket::event::ServerEvent,
+    };
+
+    async fn user(state: &AppState, user_name: &str) -> (User, String) {
+        let hash = hash_password("123456".to_string()).unwrap();
+        let email = format!("{}@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name.to_string(), email, hash),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    async fn moderator(state: &AppState) -> String {
+        let (admin, token) = user(state, "moderator").await;
+        set_role(&admin.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        token
+    }
+
+    async fn report(
+        state: &AppState,
+        reporter: &User,
+        author: &User,
+        group_id: Option<&str>,
+    ) -> Report {
+        let report = NewReport {
+            target: ReportTarget::Message,
+            user_id: author.user_id.clone(),
+            group_id: group_id.map(str::to_string),
+            message: Some("you are all idiots".to_string()),
+            reason: ReportReason::Harassment,
+            details: None,
+        };
+        create(&state.pool, &reporter.user_id, report)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mute_extend() {
+        let soon = chrono::Utc::now() + chrono::Duration::minutes(5);
+        let later = soon + chrono::Duration::minutes(5);
+        assert_eq!(
+            Mute::Until(soon).extend(Mute::Until(later)),
+            Mute::Until(later)
+        );
+        assert_eq!(Mute::None.extend(Mute::Until(soon)), Mute::Until(soon));
+        assert_eq!(
+            Mute::Until(later).extend(Mute::Indefinite),
+            Mute::Indefinite
+        );
+        assert!(!Mute::Until(chrono::Utc::now()).is_active());
+    }
+
+    #[tokio::test]
+    async fn test_mute_from_queue() {
+        let isolated = AppState::isolated().await;
+        let state: Arc<AppState> = (*isolated).clone();
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let token = moderator(&state).await;
+        let (reporter, _) = user(&state, "reporter").await;
+        let (author, _) = user(&state, "author").await;
+        let report = report(&state, &reporter, &author, None).await;
+        let mut author_rx = state.chat.subscribe(&author.user_id).await;
+        let mut reporter_rx = state.chat.subscribe(&reporter.user_id).await;
+
+        let response = server
+            .get("/api/v1/admin/moderation/reports")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let queue = response.json::<QueueResponse>().data;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].report_id, report.report_id);
+
+        let path = format!("/api/v1/admin/moderation/reports/{}", report.report_id);
+        let response = server.get(&path).add_header("Authorization", &token).await;
+        response.assert_status_ok();
+        let context = response.json::<ReportContextResponse>().data;
+        assert_eq!(context.reported_user.unwrap().user_id, author.user_id);
+        assert_eq!(context.reporter.unwrap().user_id, reporter.user_id);
+        assert!(context.sanctions.is_empty());
+
+        let actions = format!("{}/actions", path);
+        let response = server
+            .post(&actions)
+            .add_header("Authorization", &token)
+            .json(&json!({"action": "mute", "minutes": 30}))
+            .await;
+        response.assert_status_ok();
+        let result = response.json::<ModerationResponse>().data;
+        assert_eq!(result.report.status, ReportStatus::Resolved);
+        let sanction = result.sanction.unwrap();
+        assert_eq!(sanction.kind, SanctionKind::Mute);
+        assert!(sanction.expires_at.is_some());
+        assert!(
+            mute(&state.pool, &author.user_id)
+                .await
+                .unwrap()
+                .is_active()
+        );
+
+        let pushed = serde_json::from_str(&author_rx.recv().await.unwrap()).unwrap();
+        assert!(
+            matches!(pushed, ServerEvent::Sanction(s) if s.sanction_id == sanction.sanction_id)
+        );
+        let pushed = serde_json::from_str(&reporter_rx.recv().await.unwrap()).unwrap();
+        assert!(matches!(
+            pushed,
+            ServerEvent::ReportUpdated {
+                status: ReportStatus::Resolved,
+                ..
+            }
+        ));
+
+        let audited: Vec<(String, String)> =
+            sqlx::query_as("select action, target from audit_log where action like 'moderation.%'")
+                .fetch_all(&*state.pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            audited,
+            [(
+                "moderation.mute".to_string(),
+                format!("report={} user={}", report.report_id, author.user_id)
+            )]
+        );
+
+        // Resolved is final
+        server
+            .post(&actions)
+            .add_header("Authorization", &token)
+            .json(&json!({"action": "dismiss"}))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        let response = server
+            .get("/api/v1/admin/moderation/reports")
+            .add_header("Authorization", &token)
+            .await;
+        assert!(response.json::<QueueResponse>().data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ban_disconnects_group_chat() {
+        let isolated = AppState::isolated().await;
+        let state: Arc<AppState> = (*isolated).clone();
+        let server = TestServer::builder()
+            .http_transport()
+            .build(routes(state.clone()))
+            .unwrap();
+        let token = moderator(&state).await;
+        let (reporter, _) = user(&state, "reporter").await;
+        let (author, author_token) = user(&state, "author").await;
+        let group = state
+            .groups
+            .create("moderated", "", None, &reporter.user_id)
+            .await
+            .unwrap();
+
+        // A private chat report has no group to ban from
+        let private = report(&state, &reporter, &author, None).await;
+        server
+            .post(&format!(
+                "/api/v1/admin/moderation/reports/{}/actions",
+                private.report_id
+            ))
+            .add_header("Authorization", &token)
+            .json(&json!({"action": "ban"}))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        let mut ws = server
+            .get_websocket("/group-chat")
+            .add_header("Authorization", &author_token)
+            .add_header("group_id", &group.group_id)
+            .await
+            .into_websocket()
+            .await;
+
+        let report = report(&state, &reporter, &author, Some(&group.group_id)).await;
+        server
+            .post(&format!(
+                "/api/v1/admin/moderation/reports/{}/actions",
+                report.report_id
+            ))
+            .add_header("Authorization", &token)
+            .json(&json!({"action": "ban"}))
+            .await
+            .assert_status_ok();
+
+        loop {
+            match serde_json::from_str(&ws.receive_text().await).unwrap() {
+                ServerEvent::Sanction(sanction) => {
+                    assert_eq!(sanction.kind, SanctionKind::GroupBan);
+                    assert_eq!(sanction.group_id, Some(group.group_id.clone()));
+                    break;
+                }
+                // The welcome message
+                _ => continue,
+            }
+        }
+
+        server
+            .get_websocket("/group-chat")
+            .add_header("Authorization", &author_token)
+            .add_header("group_id", &group.group_id)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
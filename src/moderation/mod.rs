@@ -0,0 +1,3 @@
+pub mod handler;
+pub mod shadow_ban;
+pub mod word_filter;
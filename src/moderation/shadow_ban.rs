@@ -0,0 +1,172 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use axum::extract::{Path, State};
+use http::StatusCode;
+use sqlx::{Error, Pool, Postgres};
+
+use crate::{
+    app_state::AppState,
+    auth::util::{MetaResponse, StatusCodeExt},
+};
+
+/// Users whose messages are echoed back to them but delivered to no one
+/// else. Kept in memory because every chat message is checked against it;
+/// `users.shadow_banned` is the source it is loaded from at startup.
+#[derive(Debug, Default)]
+pub struct ShadowBans(RwLock<HashSet<String>>);
+
+impl ShadowBans {
+    pub fn contains(&self, user_id: &str) -> bool {
+        self.0.read().unwrap().contains(user_id)
+    }
+
+    pub fn set(&self, user_id: &str, banned: bool) {
+        let mut users = self.0.write().unwrap();
+        match banned {
+            true => users.insert(user_id.to_string()),
+            false => users.remove(user_id),
+        };
+    }
+
+    /// Replaces the set with the flagged users
+    pub async fn load(&self, pool: &Pool<Postgres>) -> Result<usize, Error> {
+        let users: HashSet<String> =
+            sqlx::query_scalar("select user_id from users where shadow_banned")
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .collect();
+        let count = users.len();
+        *self.0.write().unwrap() = users;
+        Ok(count)
+    }
+}
+
+/// `false` when there is no such user
+#[tracing::instrument(name = "db.users.set_shadow_ban", skip(pool))]
+pub async fn set_shadow_ban(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    banned: bool,
+) -> Result<bool, Error> {
+    let result = sqlx::query("update users set shadow_banned = $2 where user_id = $1")
+        .bind(user_id)
+        .bind(banned)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn update(state: &AppState, user_id: &str, banned: bool) -> MetaResponse {
+    match set_shadow_ban(&state.pool, user_id, banned).await {
+        Ok(true) => {
+            state.shadow_bans.set(user_id, banned);
+            MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: String::from("Success"),
+            }
+        }
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "User not found".to_string(),
+        },
+        Err(e) => MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        },
+    }
+}
+
+pub async fn shadow_ban_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> MetaResponse {
+    update(&state, &user_id, true).await
+}
+
+pub async fn lift_shadow_ban_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> MetaResponse {
+    update(&state, &user_id, false).await
+}
+
+#[cfg(test)]
+mod tests_shadow_ban {
+    use axum_test::TestServer;
+    use http::StatusCode;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::hash_password,
+        },
+        routes::routes,
+        websocket::chat::send_to_user,
+    };
+
+    async fn user(state: &AppState, user_name: &str) -> User {
+        let hash = hash_password("123456".to_string()).unwrap();
+        let email = format!("{}@mail.com", user_name);
+        add(
+            &state.pool,
+            NewUser::new(user_name.to_string(), email, hash),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shadow_banned_messages_stay_with_sender() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let admin = user(&state, "moderator").await;
+        set_role(&admin.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let token = create_access_token(&state.jwt_config, &admin.user_id, &admin.email).unwrap();
+        let spammer = user(&state, "spammer").await;
+        let receiver = user(&state, "receiver").await;
+        let mut spammer_rx = state.chat.subscribe(&spammer.user_id).await;
+        let mut receiver_rx = state.chat.subscribe(&receiver.user_id).await;
+
+        let path = format!(
+            "/api/v1/admin/moderation/users/{}/shadow-ban",
+            spammer.user_id
+        );
+        server
+            .put(&path)
+            .add_header("Authorization", format!("Bearer {}", token))
+            .await
+            .assert_status_ok();
+        assert!(state.shadow_bans.contains(&spammer.user_id));
+
+        send_to_user(&state.chat, &spammer, &receiver, "cheap pills").await;
+        assert!(spammer_rx.recv().await.unwrap().contains("cheap pills"));
+        assert!(receiver_rx.try_recv().is_err());
+
+        // The flag outlives the process
+        state.shadow_bans.set(&spammer.user_id, false);
+        assert_eq!(state.shadow_bans.load(&state.pool).await.unwrap(), 1);
+        assert!(state.shadow_bans.contains(&spammer.user_id));
+
+        server
+            .delete(&path)
+            .add_header("Authorization", format!("Bearer {}", token))
+            .await
+            .assert_status_ok();
+        send_to_user(&state.chat, &spammer, &receiver, "sorry").await;
+        assert!(receiver_rx.recv().await.unwrap().contains("sorry"));
+
+        server
+            .put("/api/v1/admin/moderation/users/missing/shadow-ban")
+            .add_header("Authorization", format!("Bearer {}", token))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    group::handler::Group,
+    organization::handler::{OWNER_ROLE, member_role},
+    report::handler::{NewReport, ReportReason, ReportTarget, SYSTEM_REPORTER, file},
+    validation::Validated,
+};
+
+/// Filters one group may have
+pub const MAX_FILTERS: i64 = 200;
+
+/// Compiled size a single pattern may reach, keeps pathological regexes out
+const REGEX_SIZE_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterKind {
+    /// Matches the whole word, ignoring case
+    #[default]
+    Word,
+    Regex,
+}
+
+impl FilterKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterKind::Word => "word",
+            FilterKind::Regex => "regex",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "regex" => FilterKind::Regex,
+            _ => FilterKind::Word,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Replace the match with `*`s and deliver the rest
+    #[default]
+    Mask,
+    /// Deliver nothing
+    Block,
+}
+
+impl FilterAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterAction::Mask => "mask",
+            FilterAction::Block => "block",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "block" => FilterAction::Block,
+            _ => FilterAction::Mask,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WordFilter {
+    pub filter_id: String,
+    pub group_id: String,
+    pub pattern: String,
+    pub kind: FilterKind,
+    pub action: FilterAction,
+    /// File a report for the moderators when a message matches
+    pub notify: bool,
+    pub created_by: String,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+fn filter_from_row(row: PgRow) -> WordFilter {
+    WordFilter {
+        filter_id: row.get("filter_id"),
+        group_id: row.get("group_id"),
+        pattern: row.get("pattern"),
+        kind: FilterKind::parse(row.get("kind")),
+        action: FilterAction::parse(row.get("action")),
+        notify: row.get("notify"),
+        created_by: row.get("created_by"),
+        created_at: row
+            .get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            .to_rfc3339(),
+    }
+}
+
+impl WordFilter {
+    fn compile(&self) -> Result<Regex, regex::Error> {
+        compile(&self.pattern, self.kind)
+    }
+}
+
+fn compile(pattern: &str, kind: FilterKind) -> Result<Regex, regex::Error> {
+    let pattern = match kind {
+        FilterKind::Word => format!(r"\b{}\b", regex::escape(pattern)),
+        FilterKind::Regex => pattern.to_string(),
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// What the filters of a group make of a message
+#[derive(Debug, PartialEq)]
+pub enum Filtered {
+    Pass,
+    Masked {
+        message: String,
+        /// Patterns of the matching filters that notify
+        notify: Vec<String>,
+    },
+    Blocked {
+        notify: Vec<String>,
+    },
+}
+
+/// The filters of one group, compiled
+#[derive(Debug, Default)]
+pub struct GroupFilter {
+    rules: Vec<(WordFilter, Regex)>,
+}
+
+impl GroupFilter {
+    /// Filters that don't compile (anymore) are skipped
+    pub fn new(filters: Vec<WordFilter>) -> Self {
+        let rules = filters
+            .into_iter()
+            .filter_map(|filter| match filter.compile() {
+                Ok(regex) => Some((filter, regex)),
+                Err(e) => {
+                    tracing::warn!(filter_id = %filter.filter_id, error = %e, "Skipping word filter");
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Blocks when any matching filter blocks, otherwise masks every match
+    pub fn apply(&self, message: &str) -> Filtered {
+        let matching: Vec<&(WordFilter, Regex)> = self
+            .rules
+            .iter()
+            .filter(|(_, regex)| regex.is_match(message))
+            .collect();
+        if matching.is_empty() {
+            return Filtered::Pass;
+        }
+        let notify = matching
+            .iter()
+            .filter(|(filter, _)| filter.notify)
+            .map(|(filter, _)| filter.pattern.clone())
+            .collect();
+        if matching
+            .iter()
+            .any(|(filter, _)| filter.action == FilterAction::Block)
+        {
+            return Filtered::Blocked { notify };
+        }
+        let message = matching
+            .iter()
+            .fold(message.to_string(), |message, (_, regex)| {
+                regex
+                    .replace_all(&message, |caps: &regex::Captures| {
+                        "*".repeat(caps[0].chars().count())
+                    })
+                    .into_owned()
+            });
+        Filtered::Masked { message, notify }
+    }
+}
+
+/// Compiled filters by group. Connections read it for every message; an
+/// update through the API reloads the group's entry.
+#[derive(Debug, Default)]
+pub struct WordFilterCache(RwLock<HashMap<String, Arc<GroupFilter>>>);
+
+impl WordFilterCache {
+    pub fn get(&self, group_id: &str) -> Option<Arc<GroupFilter>> {
+        self.0.read().unwrap().get(group_id).cloned()
+    }
+
+    /// Reads the group's filters from the database into the cache
+    pub async fn refresh(&self, pool: &Pool<Postgres>, group_id: &str) -> Result<(), Error> {
+        let filter = Arc::new(GroupFilter::new(list(pool, group_id).await?));
+        self.0.write().unwrap().insert(group_id.to_string(), filter);
+        Ok(())
+    }
+
+    /// Loads the group's filters unless they are cached already
+    pub async fn load(&self, pool: &Pool<Postgres>, group_id: &str) -> Result<(), Error> {
+        match self.get(group_id) {
+            Some(_) => Ok(()),
+            None => self.refresh(pool, group_id).await,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "validate_filter"))]
+pub struct NewWordFilter {
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    pub pattern: String,
+    #[serde(default)]
+    pub kind: FilterKind,
+    #[serde(default)]
+    pub action: FilterAction,
+    #[serde(default)]
+    pub notify: bool,
+}
+
+fn validate_filter(filter: &NewWordFilter) -> Result<(), ValidationError> {
+    if compile(&filter.pattern, filter.kind).is_err() {
+        return Err(ValidationError::new("regex")
+            .with_message("pattern must be a valid regular expression".into()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WordFilterResponse {
+    pub meta: MetaResponse,
+    pub data: WordFilter,
+}
+
+impl IntoResponse for WordFilterResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WordFiltersResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<WordFilter>,
+}
+
+impl IntoResponse for WordFiltersResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[tracing::instrument(name = "db.group_word_filters.list", skip(pool))]
+pub async fn list(pool: &Pool<Postgres>, group_id: &str) -> Result<Vec<WordFilter>, Error> {
+    sqlx::query("select * from group_word_filters where group_id = $1 order by created_at")
+        .bind(group_id)
+        .map(filter_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+/// `None` once the group has `MAX_FILTERS`
+#[tracing::instrument(name = "db.group_word_filters.create", skip(pool, filter))]
+pub async fn create(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    filter: &NewWordFilter,
+    created_by: &str,
+) -> Result<Option<WordFilter>, Error> {
+    let sql = "insert into group_word_filters \
+               (filter_id, group_id, pattern, kind, action, notify, created_by) \
+               select $1, $2, $3, $4, $5, $6, $7 \
+               where (select count(*) from group_word_filters where group_id = $2) < $8 \
+               returning *";
+    sqlx::query(sql)
+        .bind(Uuid::new_v4().to_string())
+        .bind(group_id)
+        .bind(&filter.pattern)
+        .bind(filter.kind.as_str())
+        .bind(filter.action.as_str())
+        .bind(filter.notify)
+        .bind(created_by)
+        .bind(MAX_FILTERS)
+        .map(filter_from_row)
+        .fetch_optional(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.group_word_filters.delete", skip(pool))]
+pub async fn delete(pool: &Pool<Postgres>, group_id: &str, filter_id: &str) -> Result<bool, Error> {
+    let result =
+        sqlx::query("delete from group_word_filters where group_id = $1 and filter_id = $2")
+            .bind(group_id)
+            .bind(filter_id)
+            .execute(pool)
+            .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+fn bad_request(e: Error) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+/// Groups have no roles of their own: their filters are managed by admins
+/// and, for a group of an organization, by its owners
+async fn managed_group(
+    state: &AppState,
+    user_id: &str,
+    group_id: &str,
+) -> Result<Group, MetaResponse> {
+    let group = state
+        .groups
+        .get_by_id(group_id)
+        .await
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Group not found".to_string(),
+        })?;
+    if state.users.is_admin(user_id).await.map_err(bad_request)? {
+        return Ok(group);
+    }
+    if let Some(org_id) = &group.org_id
+        && member_role(&state.pool, org_id, user_id)
+            .await
+            .map_err(bad_request)?
+            .as_deref()
+            == Some(OWNER_ROLE)
+    {
+        return Ok(group);
+    }
+    Err(MetaResponse {
+        code: StatusCode::FORBIDDEN.to_i32(),
+        message: "Only group admins can manage the word filter".to_string(),
+    })
+}
+
+pub async fn word_filters_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+) -> Result<WordFiltersResponse, MetaResponse> {
+    managed_group(&state, &user.user_id, &group_id).await?;
+    let data = list(&state.pool, &group_id).await.map_err(bad_request)?;
+    Ok(WordFiltersResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    })
+}
+
+pub async fn create_word_filter_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    Validated(req): Validated<NewWordFilter>,
+) -> Result<WordFilterResponse, MetaResponse> {
+    managed_group(&state, &user.user_id, &group_id).await?;
+    let filter = create(&state.pool, &group_id, &req, &user.user_id)
+        .await
+        .map_err(bad_request)?
+        .ok_or_else(|| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("At most {} word filters per group", MAX_FILTERS),
+        })?;
+    state
+        .group
+        .word_filters
+        .refresh(&state.pool, &group_id)
+        .await
+        .map_err(bad_request)?;
+    Ok(WordFilterResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: filter,
+    })
+}
+
+pub async fn delete_word_filter_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path((group_id, filter_id)): Path<(String, String)>,
+) -> MetaResponse {
+    if let Err(e) = managed_group(&state, &user.user_id, &group_id).await {
+        return e;
+    }
+    match delete(&state.pool, &group_id, &filter_id).await {
+        Ok(true) => match state
+            .group
+            .word_filters
+            .refresh(&state.pool, &group_id)
+            .await
+        {
+            Ok(()) => MetaResponse {
+                code: StatusCode::OK.to_i32(),
+                message: "Success".to_string(),
+            },
+            Err(e) => bad_request(e),
+        },
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Word filter not found".to_string(),
+        },
+        Err(e) => bad_request(e),
+    }
+}
+
+/// Files a report of a filtered message when one of the filters it matched
+/// asks for it, without holding up the chat
+pub fn report_match(
+    state: &Arc<AppState>,
+    user_id: &str,
+    group_id: &str,
+    message: &str,
+    patterns: Vec<String>,
+) {
+    if patterns.is_empty() {
+        return;
+    }
+    let state = state.clone();
+    let report = NewReport {
+        target: ReportTarget::Message,
+        user_id: user_id.to_string(),
+        group_id: Some(group_id.to_string()),
+        message: Some(message.to_string()),
+        reason: ReportReason::Other,
+        details: Some(format!("Matched word filter: {}", patterns.join(", "))),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = file(&state, SYSTEM_REPORTER, report).await {
+            tracing::warn!(error = %e, "Failed to report filtered message");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests_word_filter {
+    use std::time::Duration;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::hash_password,
+        },
+        moderation::word_filter::{
+            FilterAction, FilterKind, Filtered, GroupFilter, WordFilter, WordFilterResponse,
+        },
+        report::handler::SYSTEM_REPORTER,
+        routes::routes,
+        websocket::event::ServerEvent,
+    };
+
+    fn filter(pattern: &str, kind: FilterKind, action: FilterAction, notify: bool) -> WordFilter {
+        WordFilter {
+            filter_id: pattern.to_string(),
+            group_id: "g1".to_string(),
+            pattern: pattern.to_string(),
+            kind,
+            action,
+            notify,
+            created_by: "u1".to_string(),
+            created_at: String::new(),
+        }
+    }
+
+    async fn user(state: &AppState, user_name: &str) -> (User, String) {
+        let hash = hash_password("123456".to_string()).unwrap();
+        let email = format!("{}@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name.to_string(), email, hash),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn test_apply() {
+        let filter = GroupFilter::new(vec![
+            filter("darn", FilterKind::Word, FilterAction::Mask, false),
+            filter("heck", FilterKind::Word, FilterAction::Mask, true),
+            filter(
+                r"buy\s+followers",
+                FilterKind::Regex,
+                FilterAction::Block,
+                true,
+            ),
+        ]);
+        assert_eq!(filter.apply("darning socks"), Filtered::Pass);
+        assert_eq!(
+            filter.apply("Darn it, what the HECK"),
+            Filtered::Masked {
+                message: "**** it, what the ****".to_string(),
+                notify: vec!["heck".to_string()],
+            }
+        );
+        assert_eq!(
+            filter.apply("darn, buy  followers here"),
+            Filtered::Blocked {
+                notify: vec![r"buy\s+followers".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filter_group_chat() {
+        let state = AppState::isolated().await;
+        let server = TestServer::builder()
+            .http_transport()
+            .build(routes((*state).clone()))
+            .unwrap();
+        let (admin, admin_token) = user(&state, "moderator").await;
+        set_role(&admin.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let (_, token) = user(&state, "member").await;
+        let group = state
+            .groups
+            .create("filtered", "", None, &admin.user_id)
+            .await
+            .unwrap();
+        let path = format!("/api/v1/groups/{}/word-filters", group.group_id);
+
+        server
+            .post(&path)
+            .add_header("Authorization", &token)
+            .json(&json!({"pattern": "darn"}))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post(&path)
+            .add_header("Authorization", &admin_token)
+            .json(&json!({"pattern": "(unclosed", "kind": "regex"}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let response = server
+            .post(&path)
+            .add_header("Authorization", &admin_token)
+            .json(&json!({"pattern": "darn", "notify": true}))
+            .await;
+        response.assert_status_ok();
+        let created = response.json::<WordFilterResponse>().data;
+        assert_eq!(created.action, FilterAction::Mask);
+
+        let mut ws = server
+            .get_websocket("/group-chat")
+            .add_header("Authorization", &token)
+            .add_header("group_id", &group.group_id)
+            .await
+            .into_websocket()
+            .await;
+        ws.send_text("darn it").await;
+        loop {
+            match serde_json::from_str(&ws.receive_text().await).unwrap() {
+                ServerEvent::GroupMessage(msg) if msg.message.ends_with(" it") => {
+                    assert_eq!(msg.message, "**** it");
+                    break;
+                }
+                // The welcome message
+                _ => continue,
+            }
+        }
+
+        let mut reported = false;
+        for _ in 0..50 {
+            let count: i64 =
+                sqlx::query_scalar("select count(*) from reports where reporter_id = $1")
+                    .bind(SYSTEM_REPORTER)
+                    .fetch_one(&*state.pool)
+                    .await
+                    .unwrap();
+            if count == 1 {
+                reported = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(reported, "filtered message wasn't reported");
+
+        server
+            .delete(&format!("{}/{}", path, created.filter_id))
+            .add_header("Authorization", &admin_token)
+            .await
+            .assert_status_ok();
+        let cached = state.group.word_filters.get(&group.group_id).unwrap();
+        assert_eq!(cached.apply("darn it"), Filtered::Pass);
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Deserializer, de::Error};
+
+/// Trims a name and collapses every run of whitespace inside it to one
+/// space, so " Jordan " and "Jordan" are one account. Control characters
+/// left over are refused.
+pub fn name(value: &str) -> Result<String, &'static str> {
+    let name = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    refuse_control(name, |_| false)
+}
+
+/// Trims free text. Line breaks and tabs inside are kept, other control
+/// characters refused.
+pub fn text(value: &str) -> Result<String, &'static str> {
+    refuse_control(value.trim().to_string(), |c| {
+        matches!(c, '\n' | '\r' | '\t')
+    })
+}
+
+fn refuse_control(value: String, allowed: fn(char) -> bool) -> Result<String, &'static str> {
+    match value.chars().any(|c| c.is_control() && !allowed(c)) {
+        true => Err("must not contain control characters"),
+        false => Ok(value),
+    }
+}
+
+/// `deserialize_with` of `name`, so validation and lookups only ever see the
+/// normalized value
+pub fn de_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    name(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// `deserialize_with` of `text`
+pub fn de_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    text(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// `de_text` of an optional field; pair it with `#[serde(default)]`
+pub fn de_text_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| text(&value).map_err(D::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests_normalize {
+    use axum::http::{StatusCode, header};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{handler::LoginParam, jwt::create_access_token, user::NewUser, util::random_name},
+        group::handler::GroupResponse,
+        normalize::{name, text},
+        routes::routes,
+    };
+
+    #[test]
+    fn test_name() {
+        assert_eq!(name("  Jordan ").unwrap(), "Jordan");
+        assert_eq!(name("Jordan \t\n Smith").unwrap(), "Jordan Smith");
+        assert!(name("Jor\u{0}dan").is_err());
+        assert!(name("Jordan\u{1b}[31m").is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            text(" line one\n\tline two  ").unwrap(),
+            "line one\n\tline two"
+        );
+        assert!(text("bell\u{7}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login_normalized() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+
+        let user_name = random_name();
+        let body = NewUser::new(
+            format!("  {}  ", user_name),
+            format!(" {}@mail.com ", user_name),
+            "123456".to_string(),
+        );
+        server
+            .post("/api/auth/register")
+            .form(&body)
+            .await
+            .assert_status_ok();
+        let user = state.users.get_by_user_name(&user_name).await.unwrap();
+        assert_eq!(user.user_name, user_name);
+
+        // The padded name is the same account
+        let response = server.post("/api/auth/register").form(&body).await;
+        response.assert_status_bad_request();
+
+        let login = LoginParam {
+            user_name: format!("{} ", user_name),
+            password: "123456".to_string(),
+        };
+        server
+            .post("/api/auth/login")
+            .form(&login)
+            .await
+            .assert_status_ok();
+
+        let body = NewUser::new(
+            format!("{}\u{0}", random_name()),
+            "control@mail.com".to_string(),
+            "123456".to_string(),
+        );
+        let response = server.post("/api/auth/register").json(&body).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_group_name_normalized() {
+        let state = AppState::isolated().await;
+        let user = state.users.get_by_user_name("Jordan").await.unwrap();
+        let token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap()
+        );
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+
+        let name = random_name();
+        let response = server
+            .post("/api/v1/groups")
+            .add_header(header::AUTHORIZATION, &token)
+            .form(&[
+                ("name", format!("  {}  \t team ", name).as_str()),
+                ("description", " first line\nsecond line "),
+            ])
+            .await;
+        response.assert_status_ok();
+        let group = response.json::<GroupResponse>().data;
+        assert_eq!(group.name, format!("{} team", name));
+        assert_eq!(group.description.unwrap(), "first line\nsecond line");
+
+        let response = server
+            .post("/api/v1/groups")
+            .add_header(header::AUTHORIZATION, &token)
+            .form(&[("name", "team\u{8}"), ("description", "")])
+            .await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,1074 @@
+use std::sync::Arc;
+
+use axum::{
+    Form, Json,
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        scope::{Scope, names, parse_all},
+        user::User,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    validation::Validated,
+};
+
+/// OAuth access tokens start with this, which tells them apart from JWTs
+pub const OAUTH_TOKEN_PREFIX: &str = "oat_";
+/// How long an authorization code can be exchanged
+const CODE_TTL_SECS: f64 = 600.0;
+
+fn random_secret() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn secret_hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// A third-party app registered by a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// Confidential clients authenticate with `client_secret` at the token endpoint
+    pub confidential: bool,
+    pub created_at: String,
+    /// Only returned when the client is registered, it is stored hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip)]
+    secret_hash: Option<String>,
+}
+
+fn client_from_row(row: PgRow) -> OAuthClient {
+    let secret_hash: Option<String> = row.get("secret_hash");
+    OAuthClient {
+        client_id: row.get("client_id"),
+        name: row.get("name"),
+        redirect_uris: row.get("redirect_uris"),
+        confidential: secret_hash.is_some(),
+        created_at: row.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+        client_secret: None,
+        secret_hash,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NewClient {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate(
+        length(min = 1, max = 5, message = "must list between 1 and 5 URIs"),
+        custom(function = "validate_redirect_uris")
+    )]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub confidential: bool,
+}
+
+/// Absolute https URIs without a fragment; plain http only for loopback
+/// addresses, which native apps listen on
+fn validate_redirect_uris(uris: &[String]) -> Result<(), ValidationError> {
+    let valid = |uri: &String| {
+        let Ok(url) = Url::parse(uri) else {
+            return false;
+        };
+        let loopback = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+        url.fragment().is_none() && (url.scheme() == "https" || url.scheme() == "http" && loopback)
+    };
+    match uris.iter().all(valid) {
+        true => Ok(()),
+        false => Err(ValidationError::new("redirect_uri").with_message(
+            "must be absolute https URIs without a fragment, or http on localhost".into(),
+        )),
+    }
+}
+
+/// Query of `GET /oauth/authorize` (RFC 6749 section 4.1.1, RFC 7636)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizeParams {
+    pub response_type: String,
+    pub client_id: String,
+    /// May be left out when the client registered a single URI
+    pub redirect_uri: Option<String>,
+    /// Space separated
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: String,
+    /// Only `S256` is supported, and it is the default
+    pub code_challenge_method: Option<String>,
+}
+
+/// Body of `POST /oauth/authorize`: the user's answer to the consent prompt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Consent {
+    #[serde(flatten)]
+    pub params: AuthorizeParams,
+    pub approve: bool,
+}
+
+/// An authorization request that passed every check
+struct Authorization {
+    client: OAuthClient,
+    redirect_uri: String,
+    /// The request named `redirect_uri` rather than relying on the only one registered
+    redirect_uri_explicit: bool,
+    scopes: Vec<Scope>,
+    state: Option<String>,
+    code_challenge: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub name: String,
+}
+
+/// What the consent prompt shows
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthorizeInfo {
+    pub client: ClientInfo,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    /// The user already granted every requested scope to this client
+    pub consented: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Redirect {
+    pub redirect_to: String,
+}
+
+/// An app the user authorized
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Grant {
+    pub client: ClientInfo,
+    pub scopes: Vec<Scope>,
+    pub updated_at: String,
+}
+
+/// Form of `POST /oauth/token` (RFC 6749 section 4.1.3)
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub code_verifier: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: usize,
+    /// Space separated
+    pub scope: String,
+}
+
+impl IntoResponse for TokenResponse {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::OK,
+            [(header::CACHE_CONTROL, "no-store")],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+/// Token endpoint errors, in the shape RFC 6749 section 5.2 requires
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthError {
+    pub error: String,
+    pub error_description: String,
+}
+
+impl OAuthError {
+    fn new(error: &str, description: &str) -> Self {
+        Self {
+            error: error.to_string(),
+            error_description: description.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let status = match self.error.as_str() {
+            "invalid_client" => StatusCode::UNAUTHORIZED,
+            "server_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        (status, [(header::CACHE_CONTROL, "no-store")], Json(self)).into_response()
+    }
+}
+
+impl From<Error> for OAuthError {
+    fn from(error: Error) -> Self {
+        OAuthError::new("server_error", &error.to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientResponse {
+    pub meta: MetaResponse,
+    pub data: OAuthClient,
+}
+
+impl IntoResponse for ClientResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<OAuthClient>,
+}
+
+impl IntoResponse for ClientsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthorizeResponse {
+    pub meta: MetaResponse,
+    pub data: AuthorizeInfo,
+}
+
+impl IntoResponse for AuthorizeResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedirectResponse {
+    pub meta: MetaResponse,
+    pub data: Redirect,
+}
+
+impl IntoResponse for RedirectResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrantsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Grant>,
+}
+
+impl IntoResponse for GrantsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserInfoResponse {
+    pub meta: MetaResponse,
+    pub data: User,
+}
+
+impl IntoResponse for UserInfoResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+#[tracing::instrument(name = "db.oauth_clients.create", skip(pool, client))]
+pub async fn create_client(
+    pool: &Pool<Postgres>,
+    owner_id: &str,
+    client: &NewClient,
+) -> Result<OAuthClient, Error> {
+    let client_id = Uuid::new_v4().simple().to_string();
+    let secret = client.confidential.then(random_secret);
+    let secret_hash = secret.as_deref().map(secret_hash);
+    let created_at: DateTime<Utc> = sqlx::query_scalar(
+        "insert into oauth_clients (client_id, owner_id, name, redirect_uris, secret_hash) \
+         values ($1, $2, $3, $4, $5) returning created_at",
+    )
+    .bind(&client_id)
+    .bind(owner_id)
+    .bind(&client.name)
+    .bind(&client.redirect_uris)
+    .bind(&secret_hash)
+    .fetch_one(pool)
+    .await?;
+    Ok(OAuthClient {
+        client_id,
+        name: client.name.clone(),
+        redirect_uris: client.redirect_uris.clone(),
+        confidential: client.confidential,
+        created_at: created_at.to_rfc3339(),
+        client_secret: secret,
+        secret_hash,
+    })
+}
+
+#[tracing::instrument(name = "db.oauth_clients.get", skip(pool))]
+pub async fn get_client(
+    pool: &Pool<Postgres>,
+    client_id: &str,
+) -> Result<Option<OAuthClient>, Error> {
+    sqlx::query(
+        "select client_id, name, redirect_uris, secret_hash, created_at \
+         from oauth_clients where client_id = $1",
+    )
+    .bind(client_id)
+    .map(client_from_row)
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "db.oauth_clients.owned", skip(pool))]
+pub async fn owned_clients(
+    pool: &Pool<Postgres>,
+    owner_id: &str,
+) -> Result<Vec<OAuthClient>, Error> {
+    sqlx::query(
+        "select client_id, name, redirect_uris, secret_hash, created_at \
+         from oauth_clients where owner_id = $1 order by created_at",
+    )
+    .bind(owner_id)
+    .map(client_from_row)
+    .fetch_all(pool)
+    .await
+}
+
+/// Its codes, consents and tokens go with it. `false` when the owner has no such client.
+#[tracing::instrument(name = "db.oauth_clients.delete", skip(pool))]
+pub async fn delete_client(
+    pool: &Pool<Postgres>,
+    owner_id: &str,
+    client_id: &str,
+) -> Result<bool, Error> {
+    let result = sqlx::query("delete from oauth_clients where owner_id = $1 and client_id = $2")
+        .bind(owner_id)
+        .bind(client_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Scopes the user granted the client, empty when none
+#[tracing::instrument(name = "db.oauth_consents.get", skip(pool))]
+pub async fn granted(
+    pool: &Pool<Postgres>,
+    client_id: &str,
+    user_id: &str,
+) -> Result<Vec<Scope>, Error> {
+    let scopes: Option<Vec<String>> = sqlx::query_scalar(
+        "select scopes from oauth_consents where client_id = $1 and user_id = $2",
+    )
+    .bind(client_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(scopes.as_deref().map(parse_all).unwrap_or_default())
+}
+
+/// Records the consent, adding to what was granted before, and stores a
+/// code for the client to exchange
+#[tracing::instrument(name = "db.oauth_codes.create", skip_all)]
+async fn create_code(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    authorization: &Authorization,
+) -> Result<String, Error> {
+    let client_id = &authorization.client.client_id;
+    let mut scopes = granted(pool, client_id, user_id).await?;
+    for scope in &authorization.scopes {
+        if !scopes.contains(scope) {
+            scopes.push(*scope);
+        }
+    }
+    let code = random_secret();
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "insert into oauth_consents (client_id, user_id, scopes) values ($1, $2, $3) \
+         on conflict (client_id, user_id) do update \
+         set scopes = excluded.scopes, updated_at = current_timestamp",
+    )
+    .bind(client_id)
+    .bind(user_id)
+    .bind(names(&scopes))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "insert into oauth_codes \
+         (code_hash, client_id, user_id, redirect_uri, redirect_uri_explicit, scopes, \
+         code_challenge, expires_at) \
+         values ($1, $2, $3, $4, $5, $6, $7, current_timestamp + make_interval(secs => $8))",
+    )
+    .bind(secret_hash(&code))
+    .bind(client_id)
+    .bind(user_id)
+    .bind(&authorization.redirect_uri)
+    .bind(authorization.redirect_uri_explicit)
+    .bind(names(&authorization.scopes))
+    .bind(&authorization.code_challenge)
+    .bind(CODE_TTL_SECS)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(code)
+}
+
+/// A code taken out of `oauth_codes`
+struct IssuedCode {
+    client_id: String,
+    user_id: String,
+    redirect_uri: String,
+    redirect_uri_explicit: bool,
+    scopes: Vec<Scope>,
+    code_challenge: String,
+    expired: bool,
+}
+
+/// Deletes the code so it can only be exchanged once
+#[tracing::instrument(name = "db.oauth_codes.take", skip_all)]
+async fn take_code(pool: &Pool<Postgres>, code: &str) -> Result<Option<IssuedCode>, Error> {
+    sqlx::query(
+        "delete from oauth_codes where code_hash = $1 \
+         returning client_id, user_id, redirect_uri, redirect_uri_explicit, scopes, code_challenge, \
+         expires_at < current_timestamp as expired",
+    )
+    .bind(secret_hash(code))
+    .map(|row: PgRow| IssuedCode {
+        client_id: row.get("client_id"),
+        user_id: row.get("user_id"),
+        redirect_uri: row.get("redirect_uri"),
+        redirect_uri_explicit: row.get("redirect_uri_explicit"),
+        scopes: parse_all(&row.get::<Vec<String>, _>("scopes")),
+        code_challenge: row.get("code_challenge"),
+        expired: row.get("expired"),
+    })
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "db.oauth_tokens.issue", skip(pool))]
+async fn issue_token(
+    pool: &Pool<Postgres>,
+    client_id: &str,
+    user_id: &str,
+    scopes: &[Scope],
+    ttl_secs: usize,
+) -> Result<String, Error> {
+    let token = format!("{}{}", OAUTH_TOKEN_PREFIX, random_secret());
+    sqlx::query(
+        "insert into oauth_tokens (token_hash, client_id, user_id, scopes, expires_at) \
+         values ($1, $2, $3, $4, current_timestamp + make_interval(secs => $5))",
+    )
+    .bind(secret_hash(&token))
+    .bind(client_id)
+    .bind(user_id)
+    .bind(names(scopes))
+    .bind(ttl_secs as f64)
+    .execute(pool)
+    .await?;
+    Ok(token)
+}
+
+/// The user a live access token acts for: their id, email and the token's scopes
+#[tracing::instrument(name = "db.oauth_tokens.authenticate", skip_all)]
+pub async fn authenticate(
+    pool: &Pool<Postgres>,
+    token: &str,
+) -> Result<Option<(String, String, Vec<Scope>)>, Error> {
+    sqlx::query(
+        "select t.user_id, u.email, t.scopes from oauth_tokens t \
+         join users u on u.user_id = t.user_id \
+         where t.token_hash = $1 and t.expires_at > current_timestamp",
+    )
+    .bind(secret_hash(token))
+    .map(|row: PgRow| {
+        (
+            row.get("user_id"),
+            row.get("email"),
+            parse_all(&row.get::<Vec<String>, _>("scopes")),
+        )
+    })
+    .fetch_optional(pool)
+    .await
+}
+
+#[tracing::instrument(name = "db.oauth_consents.list", skip(pool))]
+pub async fn grants(pool: &Pool<Postgres>, user_id: &str) -> Result<Vec<Grant>, Error> {
+    sqlx::query(
+        "select c.client_id, c.name, g.scopes, g.updated_at from oauth_consents g \
+         join oauth_clients c on c.client_id = g.client_id \
+         where g.user_id = $1 order by g.updated_at desc",
+    )
+    .bind(user_id)
+    .map(|row: PgRow| Grant {
+        client: ClientInfo {
+            client_id: row.get("client_id"),
+            name: row.get("name"),
+        },
+        scopes: parse_all(&row.get::<Vec<String>, _>("scopes")),
+        updated_at: row.get::<DateTime<Utc>, _>("updated_at").to_rfc3339(),
+    })
+    .fetch_all(pool)
+    .await
+}
+
+/// Withdraws the consent and the client's tokens for the user. `false`
+/// when there was no consent.
+#[tracing::instrument(name = "db.oauth_consents.revoke", skip(pool))]
+pub async fn revoke_grant(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    client_id: &str,
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query("delete from oauth_consents where client_id = $1 and user_id = $2")
+        .bind(client_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for table in ["oauth_tokens", "oauth_codes"] {
+        sqlx::query(&format!(
+            "delete from {} where client_id = $1 and user_id = $2",
+            table
+        ))
+        .bind(client_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+fn bad_request(message: &str) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: message.to_string(),
+    }
+}
+
+fn storage_error(e: Error) -> MetaResponse {
+    bad_request(&e.to_string())
+}
+
+fn success() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::OK.to_i32(),
+        message: "Success".to_string(),
+    }
+}
+
+/// Checks an authorization request. Nothing is redirected on failure: the
+/// redirect URI can't be trusted before it's been checked, and the consent
+/// page shows the error instead.
+async fn check(state: &AppState, params: &AuthorizeParams) -> Result<Authorization, MetaResponse> {
+    if params.response_type != "code" {
+        return Err(bad_request("response_type must be code"));
+    }
+    let client = get_client(&state.pool, &params.client_id)
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(|| bad_request("Unknown client_id"))?;
+    let redirect_uri = match &params.redirect_uri {
+        Some(uri) if client.redirect_uris.contains(uri) => uri.clone(),
+        None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+        _ => {
+            return Err(bad_request(
+                "redirect_uri is not registered for this client",
+            ));
+        }
+    };
+    let mut scopes = Vec::new();
+    for name in params.scope.split_whitespace() {
+        let scope = Scope::parse(name).ok_or_else(|| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("Unknown scope {}", name),
+        })?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if scopes.is_empty() {
+        return Err(bad_request("scope is required"));
+    }
+    if params
+        .code_challenge_method
+        .as_deref()
+        .is_some_and(|method| method != "S256")
+    {
+        return Err(bad_request("code_challenge_method must be S256"));
+    }
+    if !(43..=128).contains(&params.code_challenge.len()) {
+        return Err(bad_request("code_challenge must be 43 to 128 characters"));
+    }
+    Ok(Authorization {
+        client,
+        redirect_uri,
+        redirect_uri_explicit: params.redirect_uri.is_some(),
+        scopes,
+        state: params.state.clone(),
+        code_challenge: params.code_challenge.clone(),
+    })
+}
+
+/// `redirect_uri` with the response parameters added to its query
+fn redirect(redirect_uri: &str, pairs: &[(&str, &str)], state: Option<&str>) -> String {
+    let Ok(mut url) = Url::parse(redirect_uri) else {
+        return redirect_uri.to_string();
+    };
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in pairs {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+    url.to_string()
+}
+
+/// PKCE S256: the challenge is the unpadded base64url SHA-256 of the verifier
+fn verifies(code_verifier: &str, code_challenge: &str) -> bool {
+    (43..=128).contains(&code_verifier.len())
+        && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+pub async fn create_client_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<NewClient>,
+) -> Result<ClientResponse, MetaResponse> {
+    let client = create_client(&state.pool, &user.user_id, &req)
+        .await
+        .map_err(storage_error)?;
+    Ok(ClientResponse {
+        meta: success(),
+        data: client,
+    })
+}
+
+pub async fn clients_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<ClientsResponse, MetaResponse> {
+    let clients = owned_clients(&state.pool, &user.user_id)
+        .await
+        .map_err(storage_error)?;
+    Ok(ClientsResponse {
+        meta: success(),
+        data: clients,
+    })
+}
+
+pub async fn delete_client_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(client_id): Path<String>,
+) -> MetaResponse {
+    match delete_client(&state.pool, &user.user_id, &client_id).await {
+        Ok(true) => success(),
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Client not found".to_string(),
+        },
+        Err(e) => storage_error(e),
+    }
+}
+
+/// What to show on the consent page for an authorization request
+pub async fn authorize_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuthorizeParams>,
+) -> Result<AuthorizeResponse, MetaResponse> {
+    let authorization = check(&state, &params).await?;
+    let granted = granted(&state.pool, &authorization.client.client_id, &user.user_id)
+        .await
+        .map_err(storage_error)?;
+    Ok(AuthorizeResponse {
+        meta: success(),
+        data: AuthorizeInfo {
+            consented: authorization
+                .scopes
+                .iter()
+                .all(|scope| granted.contains(scope)),
+            client: ClientInfo {
+                client_id: authorization.client.client_id,
+                name: authorization.client.name,
+            },
+            redirect_uri: authorization.redirect_uri,
+            scopes: authorization.scopes,
+        },
+    })
+}
+
+/// The user's answer; either way the browser is sent to `redirect_to`
+pub async fn consent_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Consent>,
+) -> Result<RedirectResponse, MetaResponse> {
+    let authorization = check(&state, &req.params).await?;
+    let state_param = authorization.state.as_deref();
+    let redirect_to = match req.approve {
+        true => {
+            let code = create_code(&state.pool, &user.user_id, &authorization)
+                .await
+                .map_err(storage_error)?;
+            redirect(&authorization.redirect_uri, &[("code", &code)], state_param)
+        }
+        false => redirect(
+            &authorization.redirect_uri,
+            &[("error", "access_denied")],
+            state_param,
+        ),
+    };
+    Ok(RedirectResponse {
+        meta: success(),
+        data: Redirect { redirect_to },
+    })
+}
+
+/// Exchanges an authorization code for an access token
+pub async fn token_handler(
+    State(state): State<Arc<AppState>>,
+    Form(req): Form<TokenRequest>,
+) -> Result<TokenResponse, OAuthError> {
+    if req.grant_type != "authorization_code" {
+        return Err(OAuthError::new(
+            "unsupported_grant_type",
+            "Only authorization_code is supported",
+        ));
+    }
+    let client = get_client(&state.pool, &req.client_id)
+        .await?
+        .ok_or_else(|| OAuthError::new("invalid_client", "Unknown client_id"))?;
+    if let Some(hash) = &client.secret_hash
+        && req.client_secret.as_deref().map(secret_hash).as_ref() != Some(hash)
+    {
+        return Err(OAuthError::new("invalid_client", "Wrong client_secret"));
+    }
+    let invalid_grant = |description| OAuthError::new("invalid_grant", description);
+    let code = take_code(&state.pool, &req.code)
+        .await?
+        .ok_or_else(|| invalid_grant("Unknown or already used code"))?;
+    if code.expired {
+        return Err(invalid_grant("Code expired"));
+    }
+    if code.client_id != client.client_id {
+        return Err(invalid_grant("Code was issued to another client"));
+    }
+    // Required when the authorization request sent one, checked whenever given
+    let redirect_matches = match &req.redirect_uri {
+        Some(uri) => *uri == code.redirect_uri,
+        None => !code.redirect_uri_explicit,
+    };
+    if !redirect_matches {
+        return Err(invalid_grant(
+            "redirect_uri doesn't match the authorization request",
+        ));
+    }
+    if !verifies(&req.code_verifier, &code.code_challenge) {
+        return Err(invalid_grant("code_verifier doesn't match code_challenge"));
+    }
+    let expires_in = state.jwt_config.access_token_expiry;
+    let access_token = issue_token(
+        &state.pool,
+        &client.client_id,
+        &code.user_id,
+        &code.scopes,
+        expires_in,
+    )
+    .await?;
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        scope: names(&code.scopes).join(" "),
+    })
+}
+
+/// Apps the caller authorized
+pub async fn grants_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<GrantsResponse, MetaResponse> {
+    let grants = grants(&state.pool, &user.user_id)
+        .await
+        .map_err(storage_error)?;
+    Ok(GrantsResponse {
+        meta: success(),
+        data: grants,
+    })
+}
+
+pub async fn revoke_grant_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(client_id): Path<String>,
+) -> MetaResponse {
+    match revoke_grant(&state.pool, &user.user_id, &client_id).await {
+        Ok(true) => success(),
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Client not found".to_string(),
+        },
+        Err(e) => storage_error(e),
+    }
+}
+
+/// The user an access token with `profile:read` acts for
+pub async fn userinfo_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<UserInfoResponse, MetaResponse> {
+    let user = state
+        .user_cache
+        .get_user(&user.user_id, &state.pool)
+        .await
+        .ok_or(MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "User not found".to_string(),
+        })?;
+    Ok(UserInfoResponse {
+        meta: success(),
+        data: user,
+    })
+}
+
+#[cfg(test)]
+mod tests_oauth {
+    use axum_test::TestServer;
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use http::StatusCode;
+    use reqwest::Url;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::hash_password,
+        },
+        oauth::handler::{
+            AuthorizeResponse, ClientResponse, OAuthError, RedirectResponse, TokenResponse,
+            UserInfoResponse,
+        },
+        routes::routes,
+    };
+
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const REDIRECT_URI: &str = "http://localhost:8400/callback";
+
+    /// Approves a request, naming `redirect_uri` when given, and returns the code
+    async fn authorize(
+        server: &TestServer,
+        token: &str,
+        client_id: &str,
+        redirect_uri: Option<&str>,
+    ) -> String {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER.as_bytes()));
+        let mut request = json!({
+            "response_type": "code",
+            "client_id": client_id,
+            "scope": "profile:read",
+            "state": "xyz",
+            "code_challenge": challenge,
+            "code_challenge_method": "S256",
+            "approve": true,
+        });
+        if let Some(redirect_uri) = redirect_uri {
+            request["redirect_uri"] = json!(redirect_uri);
+        }
+        let redirect_to = server
+            .post("/api/v1/oauth/authorize")
+            .add_header("Authorization", token)
+            .json(&request)
+            .await
+            .json::<RedirectResponse>()
+            .data
+            .redirect_to;
+        let url = Url::parse(&redirect_to).unwrap();
+        assert!(redirect_to.starts_with(REDIRECT_URI));
+        assert!(url.query_pairs().any(|(k, v)| k == "state" && v == "xyz"));
+        url.query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, code)| code.into_owned())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(
+            &state.pool,
+            NewUser::new(
+                "oauthuser".to_string(),
+                "oauthuser@mail.com".to_string(),
+                hash,
+            ),
+        )
+        .await
+        .unwrap();
+        let token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap()
+        );
+
+        server
+            .post("/api/v1/oauth/clients")
+            .add_header("Authorization", &token)
+            .json(&json!({"name": "Insecure", "redirect_uris": ["http://example.com/cb"]}))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let client = server
+            .post("/api/v1/oauth/clients")
+            .add_header("Authorization", &token)
+            .json(&json!({"name": "Planner", "redirect_uris": [REDIRECT_URI]}))
+            .await
+            .json::<ClientResponse>()
+            .data;
+        assert!(!client.confidential);
+
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER.as_bytes()));
+        let info = server
+            .get("/api/v1/oauth/authorize")
+            .add_header("Authorization", &token)
+            .add_query_param("response_type", "code")
+            .add_query_param("client_id", &client.client_id)
+            .add_query_param("scope", "profile:read")
+            .add_query_param("code_challenge", &challenge)
+            .await
+            .json::<AuthorizeResponse>()
+            .data;
+        assert_eq!(info.redirect_uri, REDIRECT_URI);
+        assert!(!info.consented);
+
+        // A wrong verifier uses the code up
+        let code = authorize(&server, &token, &client.client_id, None).await;
+        let wrong = "x".repeat(43);
+        let body = [
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("client_id", client.client_id.as_str()),
+            ("code_verifier", wrong.as_str()),
+        ];
+        let response = server.post("/api/v1/oauth/token").form(&body).await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(response.json::<OAuthError>().error, "invalid_grant");
+        let body = [
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("client_id", client.client_id.as_str()),
+            ("code_verifier", VERIFIER),
+        ];
+        server
+            .post("/api/v1/oauth/token")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // A redirect_uri named in the authorization request must be sent again
+        let token_request = |code: &str, redirect_uri: Option<&str>| {
+            let mut body = vec![
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code.to_string()),
+                ("client_id", client.client_id.clone()),
+                ("code_verifier", VERIFIER.to_string()),
+            ];
+            if let Some(redirect_uri) = redirect_uri {
+                body.push(("redirect_uri", redirect_uri.to_string()));
+            }
+            server.post("/api/v1/oauth/token").form(&body)
+        };
+        for redirect_uri in [None, Some("http://localhost:8400/other")] {
+            let code = authorize(&server, &token, &client.client_id, Some(REDIRECT_URI)).await;
+            let response = token_request(&code, redirect_uri).await;
+            response.assert_status(StatusCode::BAD_REQUEST);
+            let error = response.json::<OAuthError>();
+            assert_eq!(error.error, "invalid_grant");
+            assert_eq!(
+                error.error_description,
+                "redirect_uri doesn't match the authorization request"
+            );
+        }
+
+        let code = authorize(&server, &token, &client.client_id, Some(REDIRECT_URI)).await;
+        let body = [
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("client_id", client.client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("code_verifier", VERIFIER),
+        ];
+        let issued = server
+            .post("/api/v1/oauth/token")
+            .form(&body)
+            .await
+            .json::<TokenResponse>();
+        assert_eq!(issued.scope, "profile:read");
+        let app_token = format!("Bearer {}", issued.access_token);
+
+        let me = server
+            .get("/api/v1/oauth/userinfo")
+            .add_header("Authorization", &app_token)
+            .await
+            .json::<UserInfoResponse>()
+            .data;
+        assert_eq!(me.user_id, user.user_id);
+        // Only what the user consented to
+        server
+            .post("/api/v1/messages")
+            .add_header("Authorization", &app_token)
+            .json(&json!({"receiver_id": user.user_id, "message": "hi"}))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .get("/api/v1/users")
+            .add_header("Authorization", &app_token)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .delete(&format!("/api/v1/oauth/consents/{}", client.client_id))
+            .add_header("Authorization", &token)
+            .await
+            .assert_status_ok();
+        server
+            .get("/api/v1/oauth/userinfo")
+            .add_header("Authorization", &app_token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,732 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        handler::AuthResponse,
+        jwt::{Claims, create_org_access_token},
+        user::get_by_user_name,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    extract::JsonOrForm,
+    group::{
+        handler::{GroupFilter, GroupParam, GroupResponse, GroupsResponse},
+        service::GroupService,
+    },
+    pagination::Pagination,
+    validation::Validated,
+};
+
+pub const OWNER_ROLE: &str = "owner";
+pub const MEMBER_ROLE: &str = "member";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Organization {
+    pub org_id: String,
+    pub name: String,
+    /// Role of the caller in this organization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Member {
+    pub user_id: String,
+    pub user_name: String,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invitation {
+    pub invitation_id: String,
+    pub org_id: String,
+    pub org_name: String,
+    pub user_id: String,
+    pub invited_by: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationResponse {
+    pub meta: MetaResponse,
+    pub data: Organization,
+}
+
+impl IntoResponse for OrganizationResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Organization>,
+}
+
+impl IntoResponse for OrganizationsResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MembersResponse {
+    pub meta: MetaResponse,
+    /// Pass as `cursor` to fetch the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    pub data: Vec<Member>,
+}
+
+impl IntoResponse for MembersResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvitationResponse {
+    pub meta: MetaResponse,
+    pub data: Invitation,
+}
+
+impl IntoResponse for InvitationResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvitationsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Invitation>,
+}
+
+impl IntoResponse for InvitationsResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct OrganizationParam {
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteParam {
+    pub user_name: String,
+}
+
+/// The organization a request was scoped to by `org_middleware`
+#[derive(Debug, Clone)]
+pub struct OrgMember {
+    pub org_id: String,
+    pub role: String,
+}
+
+/// Creates the organization with `created_by` as its owner
+#[tracing::instrument(name = "db.organizations.create", skip(pool))]
+pub async fn create(
+    pool: &Pool<Postgres>,
+    name: &str,
+    created_by: &str,
+) -> Result<Organization, Error> {
+    let mut tx = pool.begin().await?;
+    let org_id = Uuid::new_v4().to_string();
+
+    let sql = "insert into organizations (org_id, name, created_by) values ($1, $2, $3)";
+    sqlx::query(sql)
+        .bind(&org_id)
+        .bind(name)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await?;
+    let sql = "insert into organization_members (org_id, user_id, role) values ($1, $2, $3)";
+    sqlx::query(sql)
+        .bind(&org_id)
+        .bind(created_by)
+        .bind(OWNER_ROLE)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Organization {
+        org_id,
+        name: name.to_string(),
+        role: Some(OWNER_ROLE.to_string()),
+    })
+}
+
+#[tracing::instrument(name = "db.organizations.get_by_member", skip(pool))]
+pub async fn get_by_member(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+) -> Result<Vec<Organization>, Error> {
+    let sql = "select o.org_id, o.name, m.role from organizations o \
+        join organization_members m on m.org_id = o.org_id \
+        where m.user_id = $1 order by o.name";
+    sqlx::query(sql)
+        .bind(user_id)
+        .map(|data: PgRow| Organization {
+            org_id: data.get("org_id"),
+            name: data.get("name"),
+            role: data.get("role"),
+        })
+        .fetch_all(pool)
+        .await
+}
+
+/// Role of `user_id` in `org_id`, `None` when not a member
+#[tracing::instrument(name = "db.organizations.member_role", skip(pool))]
+pub async fn member_role(
+    pool: &Pool<Postgres>,
+    org_id: &str,
+    user_id: &str,
+) -> Result<Option<String>, Error> {
+    let sql = "select role from organization_members where org_id = $1 and user_id = $2";
+    sqlx::query(sql)
+        .bind(org_id)
+        .bind(user_id)
+        .map(|data: PgRow| data.get("role"))
+        .fetch_optional(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.organizations.members", skip(pool))]
+pub async fn members(
+    pool: &Pool<Postgres>,
+    org_id: &str,
+    pagination: &Pagination,
+) -> Result<Vec<Member>, Error> {
+    let sql = format!(
+        "select u.user_id, u.user_name, m.role from organization_members m \
+        join users u on u.user_id = m.user_id \
+        where m.org_id = $4 and ($1::text is null or u.user_name {} $1) \
+        order by u.user_name {} limit $2 offset $3",
+        pagination.sort.after(),
+        pagination.sort.sql()
+    );
+    sqlx::query(&sql)
+        .bind(pagination.cursor.as_deref())
+        .bind(pagination.limit())
+        .bind(pagination.offset())
+        .bind(org_id)
+        .map(|data: PgRow| Member {
+            user_id: data.get("user_id"),
+            user_name: data.get("user_name"),
+            role: data.get("role"),
+        })
+        .fetch_all(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.organizations.invite", skip(pool))]
+pub async fn invite(
+    pool: &Pool<Postgres>,
+    org_id: &str,
+    user_id: &str,
+    invited_by: &str,
+) -> Result<Invitation, Error> {
+    let invitation_id = Uuid::new_v4().to_string();
+    let sql = "insert into organization_invitations (invitation_id, org_id, user_id, invited_by) \
+        values ($1, $2, $3, $4)";
+    sqlx::query(sql)
+        .bind(&invitation_id)
+        .bind(org_id)
+        .bind(user_id)
+        .bind(invited_by)
+        .execute(pool)
+        .await?;
+    get_invitation(pool, &invitation_id)
+        .await?
+        .ok_or(Error::RowNotFound)
+}
+
+async fn get_invitation(
+    pool: &Pool<Postgres>,
+    invitation_id: &str,
+) -> Result<Option<Invitation>, Error> {
+    let sql = "select i.invitation_id, i.org_id, o.name, i.user_id, i.invited_by \
+        from organization_invitations i join organizations o on o.org_id = i.org_id \
+        where i.invitation_id = $1";
+    sqlx::query(sql)
+        .bind(invitation_id)
+        .map(invitation_from_row)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Pending invitations addressed to `user_id`
+#[tracing::instrument(name = "db.organizations.invitations", skip(pool))]
+pub async fn invitations(pool: &Pool<Postgres>, user_id: &str) -> Result<Vec<Invitation>, Error> {
+    let sql = "select i.invitation_id, i.org_id, o.name, i.user_id, i.invited_by \
+        from organization_invitations i join organizations o on o.org_id = i.org_id \
+        where i.user_id = $1 order by i.created_at";
+    sqlx::query(sql)
+        .bind(user_id)
+        .map(invitation_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+fn invitation_from_row(data: PgRow) -> Invitation {
+    Invitation {
+        invitation_id: data.get("invitation_id"),
+        org_id: data.get("org_id"),
+        org_name: data.get("name"),
+        user_id: data.get("user_id"),
+        invited_by: data.get("invited_by"),
+    }
+}
+
+/// Turns the invitation into a membership. Only the invited user can accept;
+/// returns the organization joined, `None` when there is no such invitation.
+#[tracing::instrument(name = "db.organizations.accept", skip(pool))]
+pub async fn accept(
+    pool: &Pool<Postgres>,
+    invitation_id: &str,
+    user_id: &str,
+) -> Result<Option<String>, Error> {
+    let mut tx = pool.begin().await?;
+    let sql = "delete from organization_invitations where invitation_id = $1 and user_id = $2 \
+        returning org_id";
+    let org_id: Option<String> = sqlx::query(sql)
+        .bind(invitation_id)
+        .bind(user_id)
+        .map(|data: PgRow| data.get("org_id"))
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(org_id) = org_id else {
+        return Ok(None);
+    };
+
+    let sql = "insert into organization_members (org_id, user_id, role) values ($1, $2, $3) \
+        on conflict do nothing";
+    sqlx::query(sql)
+        .bind(&org_id)
+        .bind(user_id)
+        .bind(MEMBER_ROLE)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(org_id))
+}
+
+fn forbidden(message: &str) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::FORBIDDEN.to_i32(),
+        message: message.to_string(),
+    }
+}
+
+fn bad_request(e: Error) -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::BAD_REQUEST.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+fn success() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::OK.to_i32(),
+        message: "Success".to_string(),
+    }
+}
+
+/// Scopes `/orgs/{org_id}/...` to the organization. The access token must
+/// carry the same `org_id` claim (see `org_token_handler`) and the caller must
+/// still be a member. Must run after `auth_middleware`.
+pub async fn org_middleware(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<Vec<(String, String)>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, MetaResponse> {
+    let org_id = path
+        .into_iter()
+        .find_map(|(key, value)| (key == "org_id").then_some(value))
+        .unwrap_or_default();
+    let claims = req
+        .extensions()
+        .get::<Claims>()
+        .cloned()
+        .ok_or(MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Unauthorized".to_string(),
+        })?;
+    if claims.org_id.as_deref() != Some(org_id.as_str()) {
+        return Err(forbidden("Token is not scoped to this organization"));
+    }
+
+    let role = member_role(&state.pool, &org_id, &claims.user_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        })?
+        .ok_or_else(|| forbidden("Not a member of this organization"))?;
+    req.extensions_mut().insert(OrgMember { org_id, role });
+    Ok(next.run(req).await)
+}
+
+pub async fn create_organization_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<OrganizationParam>,
+) -> Result<OrganizationResponse, MetaResponse> {
+    let result = create(&state.pool, &req.name, &user.user_id)
+        .await
+        .map_err(bad_request)?;
+    Ok(OrganizationResponse {
+        meta: success(),
+        data: result,
+    })
+}
+
+pub async fn organizations_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<OrganizationsResponse, MetaResponse> {
+    let result = get_by_member(&state.pool, &user.user_id)
+        .await
+        .map_err(bad_request)?;
+    Ok(OrganizationsResponse {
+        meta: success(),
+        data: result,
+    })
+}
+
+/// Issues an access token scoped to one of the caller's organizations
+pub async fn org_token_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(org_id): Path<String>,
+) -> Result<AuthResponse, MetaResponse> {
+    member_role(&state.pool, &org_id, &user.user_id)
+        .await
+        .map_err(bad_request)?
+        .ok_or_else(|| forbidden("Not a member of this organization"))?;
+    let access_token =
+        create_org_access_token(&state.jwt_config, &user.user_id, &user.email, &org_id).ok();
+    Ok(AuthResponse {
+        meta: success(),
+        data: None,
+        access_token,
+        refresh_token: None,
+    })
+}
+
+pub async fn invitations_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Result<InvitationsResponse, MetaResponse> {
+    let result = invitations(&state.pool, &user.user_id)
+        .await
+        .map_err(bad_request)?;
+    Ok(InvitationsResponse {
+        meta: success(),
+        data: result,
+    })
+}
+
+pub async fn accept_invitation_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(invitation_id): Path<String>,
+) -> MetaResponse {
+    match accept(&state.pool, &invitation_id, &user.user_id).await {
+        Ok(Some(_)) => success(),
+        Ok(None) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Invitation not found".to_string(),
+        },
+        Err(e) => bad_request(e),
+    }
+}
+
+pub async fn invite_handler(
+    AuthUser(user): AuthUser,
+    Extension(org): Extension<OrgMember>,
+    State(state): State<Arc<AppState>>,
+    JsonOrForm(req): JsonOrForm<InviteParam>,
+) -> Result<InvitationResponse, MetaResponse> {
+    if org.role != OWNER_ROLE {
+        return Err(forbidden("Owner role required"));
+    }
+    let invitee = get_by_user_name(req.user_name, &state.pool)
+        .await
+        .map_err(|_| MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "User not found".to_string(),
+        })?;
+    if member_role(&state.pool, &org.org_id, &invitee.user_id)
+        .await
+        .map_err(bad_request)?
+        .is_some()
+    {
+        return Err(MetaResponse {
+            code: StatusCode::CONFLICT.to_i32(),
+            message: "User is already a member".to_string(),
+        });
+    }
+
+    let result = invite(&state.pool, &org.org_id, &invitee.user_id, &user.user_id)
+        .await
+        .map_err(bad_request)?;
+    Ok(InvitationResponse {
+        meta: success(),
+        data: result,
+    })
+}
+
+pub async fn members_handler(
+    Extension(org): Extension<OrgMember>,
+    State(state): State<Arc<AppState>>,
+    pagination: Pagination,
+) -> Result<MembersResponse, MetaResponse> {
+    let result = members(&state.pool, &org.org_id, &pagination)
+        .await
+        .map_err(bad_request)?;
+    Ok(MembersResponse {
+        meta: success(),
+        next_cursor: pagination.next_cursor(
+            result.len(),
+            result.last().map(|member| member.user_name.as_str()),
+        ),
+        data: result,
+    })
+}
+
+pub async fn org_groups_handler(
+    Extension(org): Extension<OrgMember>,
+    State(state): State<Arc<AppState>>,
+    pagination: Pagination,
+) -> Result<GroupsResponse, MetaResponse> {
+    let page = GroupService::new(&state)
+        .list(Some(&org.org_id), &GroupFilter::default(), &pagination)
+        .await?;
+    Ok(page.into())
+}
+
+pub async fn create_org_group_handler(
+    AuthUser(user): AuthUser,
+    Extension(org): Extension<OrgMember>,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<GroupParam>,
+) -> Result<GroupResponse, MetaResponse> {
+    let group = GroupService::new(&state)
+        .create(&user.user_id, req, Some(&org.org_id))
+        .await?;
+    Ok(group.into())
+}
+
+#[cfg(test)]
+mod tests_organization {
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        app_state::AppState,
+        auth::{
+            jwt::{create_access_token, create_org_access_token},
+            user::{NewUser, User, add},
+            util::random_name,
+        },
+        group::handler::GroupsResponse,
+        organization::handler::{
+            InvitationsResponse, MembersResponse, OrganizationResponse, OrganizationsResponse,
+        },
+        routes::routes,
+    };
+
+    async fn user(state: &AppState) -> (User, String) {
+        let name = random_name();
+        let user = add(
+            &state.pool,
+            NewUser::new(
+                name.clone(),
+                format!("{}@mail.com", name),
+                "123456".to_string(),
+            ),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, token)
+    }
+
+    #[tokio::test]
+    async fn test_invite_and_scope() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (owner, owner_token) = user(&state).await;
+        let (invitee, invitee_token) = user(&state).await;
+
+        let org = server
+            .post("/api/v1/orgs")
+            .add_header("Authorization", format!("Bearer {}", owner_token))
+            .json(&json!({ "name": random_name() }))
+            .await
+            .json::<OrganizationResponse>()
+            .data;
+        let org_token =
+            create_org_access_token(&state.jwt_config, &owner.user_id, &owner.email, &org.org_id)
+                .unwrap();
+
+        // An unscoped token is refused on org routes
+        server
+            .get(&format!("/api/v1/orgs/{}/members", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", owner_token))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .post(&format!("/api/v1/orgs/{}/invitations", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", org_token))
+            .json(&json!({ "user_name": invitee.user_name }))
+            .await
+            .assert_status_ok();
+        let invitations = server
+            .get("/api/v1/invitations")
+            .add_header("Authorization", format!("Bearer {}", invitee_token))
+            .await
+            .json::<InvitationsResponse>()
+            .data;
+        assert_eq!(invitations.len(), 1);
+
+        // Not a member yet, so no scoped token
+        let token_path = format!("/api/v1/orgs/{}/token", org.org_id);
+        server
+            .post(&token_path)
+            .add_header("Authorization", format!("Bearer {}", invitee_token))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post(&format!(
+                "/api/v1/invitations/{}/accept",
+                invitations[0].invitation_id
+            ))
+            .add_header("Authorization", format!("Bearer {}", invitee_token))
+            .await
+            .assert_status_ok();
+        let orgs = server
+            .get("/api/v1/orgs")
+            .add_header("Authorization", format!("Bearer {}", invitee_token))
+            .await
+            .json::<OrganizationsResponse>()
+            .data;
+        assert_eq!(orgs.len(), 1);
+        assert_eq!(orgs[0].role.as_deref(), Some("member"));
+
+        let scoped = server
+            .post(&token_path)
+            .add_header("Authorization", format!("Bearer {}", invitee_token))
+            .await
+            .json::<serde_json::Value>()["access_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let members = server
+            .get(&format!("/api/v1/orgs/{}/members", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", scoped))
+            .await
+            .json::<MembersResponse>()
+            .data;
+        assert_eq!(members.len(), 2);
+
+        // Members can't invite
+        server
+            .post(&format!("/api/v1/orgs/{}/invitations", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", scoped))
+            .json(&json!({ "user_name": owner.user_name }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_org_groups_are_isolated() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let (owner, owner_token) = user(&state).await;
+        let org = server
+            .post("/api/v1/orgs")
+            .add_header("Authorization", format!("Bearer {}", owner_token))
+            .json(&json!({ "name": random_name() }))
+            .await
+            .json::<OrganizationResponse>()
+            .data;
+        let org_token =
+            create_org_access_token(&state.jwt_config, &owner.user_id, &owner.email, &org.org_id)
+                .unwrap();
+        let name = random_name();
+        server
+            .post(&format!("/api/v1/orgs/{}/groups", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", org_token))
+            .json(&json!({ "name": name }))
+            .await
+            .assert_status_ok();
+
+        let groups = server
+            .get(&format!("/api/v1/orgs/{}/groups", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", org_token))
+            .await
+            .json::<GroupsResponse>()
+            .data;
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].org_id.as_deref(), Some(org.org_id.as_str()));
+
+        let global = server
+            .get("/api/v1/groups")
+            .add_query_param("cursor", &name[..name.len() - 1])
+            .add_query_param("per_page", 100)
+            .add_header("Authorization", format!("Bearer {}", owner_token))
+            .await
+            .json::<GroupsResponse>()
+            .data;
+        assert!(global.iter().all(|group| group.name != name));
+
+        // A token for another organization doesn't open this one
+        let (other, other_token) = user(&state).await;
+        let other_org = server
+            .post("/api/v1/orgs")
+            .add_header("Authorization", format!("Bearer {}", other_token))
+            .json(&json!({ "name": random_name() }))
+            .await
+            .json::<OrganizationResponse>()
+            .data;
+        let other_org_token = create_org_access_token(
+            &state.jwt_config,
+            &other.user_id,
+            &other.email,
+            &other_org.org_id,
+        )
+        .unwrap();
+        server
+            .get(&format!("/api/v1/orgs/{}/groups", org.org_id))
+            .add_header("Authorization", format!("Bearer {}", other_org_token))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
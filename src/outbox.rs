@@ -0,0 +1,178 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, PgConnection, Pool, Postgres, Row, postgres::PgListener};
+use tokio::task::JoinHandle;
+
+use crate::{
+    app_state::AppState,
+    event_bus::{DomainEvent, EventBus},
+};
+
+/// `pg_notify` channel raised by `enqueue`, so the relay doesn't wait for its next poll
+const CHANNEL: &str = "outbox";
+
+/// Settings from the `[outbox]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutboxSettings {
+    /// Fallback poll, for notifications missed while the listener reconnects
+    pub poll_ms: u64,
+    /// Events published per transaction
+    pub batch_size: i64,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            poll_ms: 1000,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Queues `event` in the caller's transaction; it is published only if that
+/// transaction commits, and then at least once
+#[tracing::instrument(name = "db.outbox.enqueue", skip(conn, event), fields(event = event.name()))]
+pub async fn enqueue(conn: &mut PgConnection, event: &DomainEvent) -> Result<(), Error> {
+    let event = serde_json::to_string(event).map_err(|e| Error::Encode(e.into()))?;
+    sqlx::query("insert into outbox (event) values ($1::jsonb)")
+        .bind(event)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("select pg_notify($1, '')")
+        .bind(CHANNEL)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Publishes the oldest queued events on the bus and removes them, returning
+/// how many were taken. Rows are locked with `skip locked`, so several
+/// instances can relay side by side without publishing an event twice.
+#[tracing::instrument(name = "db.outbox.relay", skip(pool, events))]
+pub async fn relay_batch(
+    pool: &Pool<Postgres>,
+    events: &EventBus,
+    batch_size: i64,
+) -> Result<usize, Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query(
+        "select outbox_id, event::text as event from outbox \
+         order by outbox_id limit $1 for update skip locked",
+    )
+    .bind(batch_size)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut ids = Vec::with_capacity(rows.len());
+    for row in rows {
+        let outbox_id: i64 = row.get("outbox_id");
+        match serde_json::from_str::<DomainEvent>(row.get("event")) {
+            Ok(event) => events.publish(event),
+            // Dropped rather than retried forever, it would never parse
+            Err(e) => tracing::error!(outbox_id, error = %e, "Unreadable outbox event"),
+        }
+        ids.push(outbox_id);
+    }
+    if !ids.is_empty() {
+        sqlx::query("delete from outbox where outbox_id = any($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(ids.len())
+}
+
+/// Moves queued events to the event bus: right after each commit that
+/// queued some, and every `outbox.poll_ms` in case a notification was missed
+pub fn spawn_relay(state: Arc<AppState>) -> JoinHandle<()> {
+    let settings = state.settings.outbox.clone();
+    let poll = Duration::from_millis(settings.poll_ms);
+    tokio::spawn(async move {
+        let mut listener = match PgListener::connect_with(&state.pool).await {
+            Ok(mut listener) => match listener.listen(CHANNEL).await {
+                Ok(()) => Some(listener),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Outbox listener unavailable, polling only");
+                    None
+                }
+            },
+            Err(e) => {
+                tracing::warn!(error = %e, "Outbox listener unavailable, polling only");
+                None
+            }
+        };
+        loop {
+            match relay_batch(&state.pool, &state.events, settings.batch_size).await {
+                // A full batch means there may be more waiting
+                Ok(count) if count as i64 == settings.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => tracing::error!(error = %e, "Failed to relay outbox events"),
+            }
+            match listener.as_mut() {
+                Some(listener) => {
+                    if let Ok(Err(e)) = tokio::time::timeout(poll, listener.try_recv()).await {
+                        tracing::warn!(error = %e, "Outbox listener failed");
+                        tokio::time::sleep(poll).await;
+                    }
+                }
+                None => tokio::time::sleep(poll).await,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_outbox {
+    use crate::{
+        AppState,
+        auth::user::User,
+        event_bus::DomainEvent,
+        outbox::{enqueue, relay_batch},
+    };
+
+    fn registered(user_name: &str) -> DomainEvent {
+        DomainEvent::UserRegistered {
+            user: User {
+                user_id: format!("{}-id", user_name),
+                user_name: user_name.to_string(),
+                email: format!("{}@mail.com", user_name),
+                is_bot: false,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_committed_events_are_relayed() {
+        let state = AppState::isolated().await;
+        let mut rx = state.events.subscribe();
+
+        let mut tx = state.pool.begin().await.unwrap();
+        enqueue(&mut tx, &registered("rolled-back")).await.unwrap();
+        tx.rollback().await.unwrap();
+        let mut tx = state.pool.begin().await.unwrap();
+        enqueue(&mut tx, &registered("first")).await.unwrap();
+        enqueue(&mut tx, &registered("second")).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(relay_batch(&state.pool, &state.events, 1).await.unwrap(), 1);
+        assert_eq!(
+            relay_batch(&state.pool, &state.events, 10).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            relay_batch(&state.pool, &state.events, 10).await.unwrap(),
+            0
+        );
+        for expected in ["first", "second"] {
+            match rx.recv().await.unwrap() {
+                DomainEvent::UserRegistered { user } => assert_eq!(user.user_name, expected),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert!(rx.try_recv().is_err());
+    }
+}
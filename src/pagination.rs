@@ -0,0 +1,203 @@
+use axum::{
+    extract::{FromRequestParts, Query},
+    http::{StatusCode, request::Parts},
+};
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::auth::util::{MetaResponse, StatusCodeExt};
+
+pub const DEFAULT_PER_PAGE: u32 = 10;
+pub const MAX_PER_PAGE: u32 = 100;
+
+/// Direction of the listing's sort key (user name, group name)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl Sort {
+    pub fn sql(self) -> &'static str {
+        match self {
+            Sort::Asc => "asc",
+            Sort::Desc => "desc",
+        }
+    }
+
+    /// Comparison selecting the rows that come after a cursor in this order
+    pub fn after(self) -> &'static str {
+        match self {
+            Sort::Asc => ">",
+            Sort::Desc => "<",
+        }
+    }
+}
+
+/// `?page=&per_page=&cursor=&sort=` shared by every listing; `limit` is
+/// accepted for `per_page`.
+///
+/// `cursor` is the sort key of the last item already seen; when set, the page
+/// starts right after it and `page` is ignored. Invalid values are rejected
+/// with a 400 `MetaResponse`.
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct Pagination {
+    #[serde(default = "default_page")]
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub page: u32,
+    #[serde(default = "default_per_page", alias = "limit")]
+    #[validate(range(min = 1, max = "MAX_PER_PAGE", message = "must be between 1 and 100"))]
+    pub per_page: u32,
+    #[validate(length(min = 1, max = 50))]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub sort: Sort,
+}
+
+fn default_page() -> u32 {
+    1
+}
+
+fn default_per_page() -> u32 {
+    DEFAULT_PER_PAGE
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            page: default_page(),
+            per_page: default_per_page(),
+            cursor: None,
+            sort: Sort::default(),
+        }
+    }
+}
+
+impl Pagination {
+    /// Default page size at `page`, for callers outside HTTP (GraphQL);
+    /// pages below 1 are treated as the first
+    pub fn page(page: i32) -> Self {
+        Self {
+            page: page.max(1) as u32,
+            ..Self::default()
+        }
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.per_page as i64
+    }
+
+    pub fn offset(&self) -> i64 {
+        if self.cursor.is_some() {
+            return 0;
+        }
+        (self.page.saturating_sub(1) as i64) * self.limit()
+    }
+
+    /// Cursor for the following page, when `fetched` items filled this one
+    pub fn next_cursor(&self, fetched: usize, last_key: Option<&str>) -> Option<String> {
+        (fetched as u32 == self.per_page)
+            .then(|| last_key.map(str::to_string))
+            .flatten()
+    }
+
+    /// The same page taken from items already in memory, for test fakes
+    #[cfg(test)]
+    pub fn slice<T>(&self, mut items: Vec<T>, key: impl Fn(&T) -> &str) -> Vec<T> {
+        items.sort_by(|a, b| key(a).cmp(key(b)));
+        if self.sort == Sort::Desc {
+            items.reverse();
+        }
+        items
+            .into_iter()
+            .filter(|item| match (&self.cursor, self.sort) {
+                (None, _) => true,
+                (Some(cursor), Sort::Asc) => key(item) > cursor.as_str(),
+                (Some(cursor), Sort::Desc) => key(item) < cursor.as_str(),
+            })
+            .skip(self.offset() as usize)
+            .take(self.per_page as usize)
+            .collect()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = MetaResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let bad_request = |message: String| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message,
+        };
+        let Query(pagination) = Query::<Pagination>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| bad_request(e.body_text()))?;
+        pagination
+            .validate()
+            .map_err(|e| bad_request(e.to_string()))?;
+        Ok(pagination)
+    }
+}
+
+#[cfg(test)]
+mod tests_pagination {
+    use axum::{Json, Router, routing::get};
+    use axum_test::TestServer;
+
+    use crate::{
+        error::ErrorResponse,
+        pagination::{Pagination, Sort},
+    };
+
+    fn server() -> TestServer {
+        let app = Router::new().route(
+            "/items",
+            get(|pagination: Pagination| async move {
+                Json((
+                    pagination.limit(),
+                    pagination.offset(),
+                    pagination.sort == Sort::Asc,
+                ))
+            }),
+        );
+        TestServer::new(app).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_defaults() {
+        let response = server().get("/items").await;
+        response.assert_status_ok();
+        response.assert_json(&(10, 0, false));
+    }
+
+    #[tokio::test]
+    async fn test_page_and_sort() {
+        let response = server().get("/items?page=3&per_page=20&sort=asc").await;
+        response.assert_json(&(20, 40, true));
+
+        let response = server().get("/items?page=3&cursor=Jordan").await;
+        response.assert_json(&(10, 0, false));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_values() {
+        for query in ["page=0", "per_page=101", "sort=up", "page=x", "cursor="] {
+            let response = server().get(&format!("/items?{}", query)).await;
+            response.assert_status_bad_request();
+            assert_eq!(response.json::<ErrorResponse>().meta.code, 400);
+        }
+    }
+
+    #[test]
+    fn test_next_cursor() {
+        let pagination = Pagination {
+            per_page: 2,
+            ..Pagination::default()
+        };
+        assert_eq!(pagination.next_cursor(2, Some("b")), Some("b".to_string()));
+        assert_eq!(pagination.next_cursor(1, Some("a")), None);
+        assert_eq!(Pagination::page(0).offset(), 0);
+    }
+}
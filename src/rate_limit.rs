@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        util::{MetaResponse, StatusCodeExt},
+    },
+};
+
+/// `[rate_limits]`, reloadable at runtime
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Messages one incoming hook may post per window
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub hook_limit: u32,
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub hook_window_secs: u64,
+    /// Requests one bot token may make per calendar month (UTC)
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub bot_token_monthly_quota: u32,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            hook_limit: 30,
+            hook_window_secs: 60,
+            bot_token_monthly_quota: 100_000,
+        }
+    }
+}
+
+/// Fixed-window request counter keyed by an arbitrary string (token, ip, ...)
+pub struct RateLimiter {
+    limit: AtomicU32,
+    window_ms: AtomicU64,
+    hits: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit: AtomicU32::new(limit),
+            window_ms: AtomicU64::new(window.as_millis() as u64),
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the limit for the following checks; running windows keep their count
+    pub fn configure(&self, limit: u32, window: Duration) {
+        self.limit.store(limit, Ordering::Relaxed);
+        self.window_ms
+            .store(window.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Records a hit for `key`, returns false once the window's limit is exceeded
+    pub async fn check(&self, key: &str) -> bool {
+        self.hit(key).await.allowed
+    }
+
+    /// Records a hit for `key` and returns where it stands in its window
+    pub async fn hit(&self, key: &str) -> Quota {
+        let window = Duration::from_millis(self.window_ms.load(Ordering::Relaxed));
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut hits = self.hits.lock().await;
+        let now = Instant::now();
+        let entry = hits.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(entry.0) >= window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        Quota {
+            limit,
+            remaining: limit.saturating_sub(entry.1),
+            reset: window.saturating_sub(now.duration_since(entry.0)),
+            allowed: entry.1 <= limit,
+        }
+    }
+}
+
+/// Where a key stands in its window after a hit
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the window starts over
+    pub reset: Duration,
+    pub allowed: bool,
+}
+
+impl Quota {
+    /// `X-RateLimit-*` headers, plus `Retry-After` once the limit is hit.
+    /// Reset and retry are in seconds from now, rounded up.
+    pub fn headers(&self) -> HeaderMap {
+        let reset = self.reset.as_millis().div_ceil(1000).max(1);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(reset as u64));
+        if !self.allowed {
+            headers.insert("retry-after", HeaderValue::from(reset as u64));
+        }
+        headers
+    }
+}
+
+/// `429 Rate limit exceeded` for a rejected hit, with the quota headers
+#[derive(Debug)]
+pub struct Throttled(pub Quota);
+
+impl IntoResponse for Throttled {
+    fn into_response(self) -> Response {
+        let mut response = MetaResponse {
+            code: StatusCode::TOO_MANY_REQUESTS.to_i32(),
+            message: "Rate limit exceeded".to_string(),
+        }
+        .into_response();
+        response.headers_mut().extend(self.0.headers());
+        response
+    }
+}
+
+/// A limit as configured right now
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Limit {
+    pub name: String,
+    /// What is counted
+    pub applies_to: String,
+    pub limit: u32,
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LimitsResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Limit>,
+}
+
+impl IntoResponse for LimitsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// Current limits, after any config reload
+pub async fn limits_handler(
+    AuthUser(_): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> LimitsResponse {
+    let rate_limits = &state.runtime.load().rate_limits;
+    LimitsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: vec![Limit {
+            name: "hooks".to_string(),
+            applies_to: "POST /hooks/{token}, per hook".to_string(),
+            limit: rate_limits.hook_limit,
+            window_secs: rate_limits.hook_window_secs,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests_rate_limit {
+    use std::time::Duration;
+
+    use crate::rate_limit::RateLimiter;
+
+    #[tokio::test]
+    async fn test_limit_exceeded() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check("key").await);
+        assert!(limiter.check("key").await);
+        assert!(!limiter.check("key").await);
+        assert!(limiter.check("other").await);
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        assert!(limiter.check("key").await);
+        assert!(!limiter.check("key").await);
+        limiter.configure(3, Duration::from_secs(60));
+        assert!(limiter.check("key").await);
+        assert!(!limiter.check("key").await);
+    }
+
+    #[tokio::test]
+    async fn test_quota_headers() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let quota = limiter.hit("key").await;
+        assert_eq!((quota.remaining, quota.allowed), (1, true));
+        let headers = quota.headers();
+        assert_eq!(headers["x-ratelimit-limit"], "2");
+        assert_eq!(headers["x-ratelimit-remaining"], "1");
+        assert_eq!(headers["x-ratelimit-reset"], "60");
+        assert!(headers.get("retry-after").is_none());
+
+        limiter.hit("key").await;
+        let headers = limiter.hit("key").await.headers();
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers["retry-after"], "60");
+    }
+
+    #[tokio::test]
+    async fn test_window_reset() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(20));
+        assert!(limiter.check("key").await);
+        assert!(!limiter.check("key").await);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(limiter.check("key").await);
+    }
+}
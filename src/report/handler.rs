@@ -0,0 +1,558 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, PgExecutor, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::admin_emails,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::DomainEvent,
+    mail::{mailer::Email, template::MailTemplate},
+    outbox::enqueue,
+    validation::Validated,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportTarget {
+    User,
+    /// Group messages aren't stored, so the report carries the reported text
+    Message,
+}
+
+impl ReportTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportTarget::User => "user",
+            ReportTarget::Message => "message",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "message" => ReportTarget::Message,
+            _ => ReportTarget::User,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    Spam,
+    Harassment,
+    HateSpeech,
+    Violence,
+    SexualContent,
+    Other,
+}
+
+impl ReportReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportReason::Spam => "spam",
+            ReportReason::Harassment => "harassment",
+            ReportReason::HateSpeech => "hate_speech",
+            ReportReason::Violence => "violence",
+            ReportReason::SexualContent => "sexual_content",
+            ReportReason::Other => "other",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "spam" => ReportReason::Spam,
+            "harassment" => ReportReason::Harassment,
+            "hate_speech" => ReportReason::HateSpeech,
+            "violence" => ReportReason::Violence,
+            "sexual_content" => ReportReason::SexualContent,
+            _ => ReportReason::Other,
+        }
+    }
+}
+
+/// Where a report is in review. Open reports wait in the moderation queue;
+/// resolved and dismissed are final.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    /// A moderator picked it up
+    Reviewing,
+    /// Acted on
+    Resolved,
+    /// Closed without action
+    Dismissed,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Reviewing => "reviewing",
+            ReportStatus::Resolved => "resolved",
+            ReportStatus::Dismissed => "dismissed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "open" => Some(ReportStatus::Open),
+            "reviewing" => Some(ReportStatus::Reviewing),
+            "resolved" => Some(ReportStatus::Resolved),
+            "dismissed" => Some(ReportStatus::Dismissed),
+            _ => None,
+        }
+    }
+
+    /// Statuses a report may move to from this one
+    pub fn next(&self) -> &'static [ReportStatus] {
+        match self {
+            ReportStatus::Open => &[
+                ReportStatus::Reviewing,
+                ReportStatus::Resolved,
+                ReportStatus::Dismissed,
+            ],
+            ReportStatus::Reviewing => &[ReportStatus::Resolved, ReportStatus::Dismissed],
+            ReportStatus::Resolved | ReportStatus::Dismissed => &[],
+        }
+    }
+
+    /// Statuses from which a report may move to this one
+    pub fn previous(&self) -> Vec<&'static str> {
+        [ReportStatus::Open, ReportStatus::Reviewing]
+            .into_iter()
+            .filter(|status| status.next().contains(self))
+            .map(|status| status.as_str())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub report_id: String,
+    pub reporter_id: String,
+    #[serde(rename = "type")]
+    pub target: ReportTarget,
+    /// The reported user, or the author of the reported message
+    pub user_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub reason: ReportReason,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    pub status: ReportStatus,
+    /// RFC 3339
+    pub created_at: String,
+}
+
+pub fn report_from_row(row: PgRow) -> Report {
+    Report {
+        report_id: row.get("report_id"),
+        reporter_id: row.get("reporter_id"),
+        target: ReportTarget::parse(row.get("target_type")),
+        user_id: row.get("user_id"),
+        group_id: row.get("group_id"),
+        message: row.get("message"),
+        reason: ReportReason::parse(row.get("reason")),
+        details: row.get("details"),
+        status: ReportStatus::parse(row.get("status")).unwrap_or(ReportStatus::Open),
+        created_at: row
+            .get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            .to_rfc3339(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "validate_report"))]
+pub struct NewReport {
+    #[serde(rename = "type")]
+    pub target: ReportTarget,
+    /// The reported user, or the author of the reported message
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub user_id: String,
+    /// Group the message was sent in, absent for private chat
+    pub group_id: Option<String>,
+    /// Text of the reported message
+    #[validate(length(min = 1, max = 4000, message = "must be between 1 and 4000 characters"))]
+    pub message: Option<String>,
+    pub reason: ReportReason,
+    #[validate(length(max = 1000, message = "must be at most 1000 characters"))]
+    pub details: Option<String>,
+}
+
+fn validate_report(report: &NewReport) -> Result<(), ValidationError> {
+    if report.target == ReportTarget::Message && report.message.is_none() {
+        return Err(ValidationError::new("message_required")
+            .with_message("message is required when reporting a message".into()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportResponse {
+    pub meta: MetaResponse,
+    pub data: Report,
+}
+
+impl IntoResponse for ReportResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// Stores the report and queues `report.created` in the outbox by the same
+/// transaction
+#[tracing::instrument(name = "db.reports.create", skip(pool, report))]
+pub async fn create(
+    pool: &Pool<Postgres>,
+    reporter_id: &str,
+    report: NewReport,
+) -> Result<Report, Error> {
+    let mut tx = pool.begin().await?;
+    let sql = "insert into reports \
+               (report_id, reporter_id, target_type, user_id, group_id, message, reason, details) \
+               values ($1, $2, $3, $4, $5, $6, $7, $8) returning *";
+    let report = sqlx::query(sql)
+        .bind(Uuid::new_v4().to_string())
+        .bind(reporter_id)
+        .bind(report.target.as_str())
+        .bind(&report.user_id)
+        .bind(&report.group_id)
+        // Only message reports keep the text
+        .bind(
+            report
+                .message
+                .filter(|_| report.target == ReportTarget::Message),
+        )
+        .bind(report.reason.as_str())
+        .bind(&report.details)
+        .map(report_from_row)
+        .fetch_one(&mut *tx)
+        .await?;
+    enqueue(
+        &mut tx,
+        &DomainEvent::ReportCreated {
+            report: report.clone(),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(report)
+}
+
+#[tracing::instrument(name = "db.reports.get", skip(pool))]
+pub async fn get(pool: &Pool<Postgres>, report_id: &str) -> Result<Option<Report>, Error> {
+    sqlx::query("select * from reports where report_id = $1")
+        .bind(report_id)
+        .map(report_from_row)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Reports in `status`, oldest first so the queue is worked in order
+#[tracing::instrument(name = "db.reports.queue", skip(pool))]
+pub async fn queue(
+    pool: &Pool<Postgres>,
+    status: ReportStatus,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Report>, Error> {
+    let sql = "select * from reports where status = $1 \
+               order by created_at, report_id limit $2 offset $3";
+    sqlx::query(sql)
+        .bind(status.as_str())
+        .bind(limit)
+        .bind(offset)
+        .map(report_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+/// The latest reports against `user_id` other than `except`, newest first
+#[tracing::instrument(name = "db.reports.against_user", skip(pool))]
+pub async fn against_user(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    except: &str,
+    limit: i64,
+) -> Result<Vec<Report>, Error> {
+    let sql = "select * from reports where user_id = $1 and report_id <> $2 \
+               order by created_at desc limit $3";
+    sqlx::query(sql)
+        .bind(user_id)
+        .bind(except)
+        .bind(limit)
+        .map(report_from_row)
+        .fetch_all(pool)
+        .await
+}
+
+/// Moves a report to `status`, if the workflow allows it from where it is.
+/// `None` when there is no such report or the move isn't allowed.
+#[tracing::instrument(name = "db.reports.set_status", skip(executor))]
+pub async fn set_status(
+    executor: impl PgExecutor<'_>,
+    report_id: &str,
+    status: ReportStatus,
+) -> Result<Option<Report>, Error> {
+    let sql = "update reports set status = $2, updated_at = current_timestamp \
+               where report_id = $1 and status = any($3) returning *";
+    sqlx::query(sql)
+        .bind(report_id)
+        .bind(status.as_str())
+        .bind(status.previous())
+        .map(report_from_row)
+        .fetch_optional(executor)
+        .await
+}
+
+/// `reporter_id` of the reports the API files itself
+pub const SYSTEM_REPORTER: &str = "system";
+
+/// Stores the report and tells the moderators about it
+pub async fn file(state: &AppState, reporter_id: &str, report: NewReport) -> Result<Report, Error> {
+    let report = create(&state.pool, reporter_id, report).await?;
+    notify_moderators(state, &report);
+    Ok(report)
+}
+
+/// Emails every moderator about the report without holding up the response
+fn notify_moderators(state: &AppState, report: &Report) {
+    let pool = state.pool.clone();
+    let mailer = state.mailer.clone();
+    let template = MailTemplate::ReportFiled {
+        report_id: report.report_id.clone(),
+        target: report.target.as_str().to_string(),
+        reason: report.reason.as_str().replace('_', " "),
+    };
+    tokio::spawn(async move {
+        let emails = match admin_emails(&pool).await {
+            Ok(emails) => emails,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to look up moderators");
+                return;
+            }
+        };
+        for to in emails {
+            if let Err(e) = mailer.send(Email::from_template(&to, &template)).await {
+                tracing::warn!(error = %e, "Failed to notify moderator of report");
+            }
+        }
+    });
+}
+
+pub async fn create_report_handler(
+    AuthUser(claims): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Validated(req): Validated<NewReport>,
+) -> Result<ReportResponse, MetaResponse> {
+    if req.user_id == claims.user_id {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "You cannot report yourself".to_string(),
+        });
+    }
+    if state
+        .user_cache
+        .get_user(&req.user_id, &state.pool)
+        .await
+        .is_none()
+    {
+        return Err(MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "User not found".to_string(),
+        });
+    }
+    if let Some(group_id) = &req.group_id
+        && state.groups.get_by_id(group_id).await.is_none()
+    {
+        return Err(MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Group not found".to_string(),
+        });
+    }
+
+    let report = file(&state, &claims.user_id, req)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    Ok(ReportResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: report,
+    })
+}
+
+#[cfg(test)]
+mod tests_report {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::hash_password,
+        },
+        event_bus::DomainEvent,
+        mail::mailer::{Email, Mailer},
+        outbox::relay_batch,
+        report::handler::{ReportResponse, ReportStatus, set_status},
+        routes::routes,
+    };
+
+    struct ChannelMailer(tokio::sync::mpsc::UnboundedSender<Email>);
+
+    #[async_trait::async_trait]
+    impl Mailer for ChannelMailer {
+        async fn send(&self, email: Email) -> Result<(), String> {
+            self.0.send(email).map_err(|e| e.to_string())
+        }
+    }
+
+    async fn user(state: &AppState, user_name: &str) -> (User, String) {
+        let hash = hash_password("123456".to_string()).unwrap();
+        let email = format!("{}@mail.com", user_name);
+        let user = add(
+            &state.pool,
+            NewUser::new(user_name.to_string(), email, hash),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        (user, format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn test_status_workflow() {
+        assert_eq!(ReportStatus::Reviewing.previous(), ["open"]);
+        assert_eq!(ReportStatus::Dismissed.previous(), ["open", "reviewing"]);
+        assert!(ReportStatus::Open.previous().is_empty());
+        assert!(ReportStatus::Resolved.next().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_report_message() {
+        let isolated = AppState::isolated().await;
+        let (tx, mut mail) = tokio::sync::mpsc::unbounded_channel();
+        let mut state = (**isolated).clone();
+        state.mailer = Arc::new(ChannelMailer(tx));
+        let state = Arc::new(state);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        let mut events = state.events.subscribe();
+
+        let (moderator, _) = user(&state, "moderator").await;
+        set_role(&moderator.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        let (reporter, token) = user(&state, "reporter").await;
+        let (author, _) = user(&state, "author").await;
+
+        let response = server
+            .post("/api/v1/reports")
+            .add_header("Authorization", &token)
+            .json(&json!({
+                "type": "message",
+                "user_id": author.user_id,
+                "message": "buy followers at example.com",
+                "reason": "spam",
+            }))
+            .await;
+        response.assert_status_ok();
+        let report = response.json::<ReportResponse>().data;
+        assert_eq!(report.reporter_id, reporter.user_id);
+        assert_eq!(report.status, ReportStatus::Open);
+        assert_eq!(
+            report.message.as_deref(),
+            Some("buy followers at example.com")
+        );
+
+        let email = mail.recv().await.unwrap();
+        assert_eq!(email.to, moderator.email);
+        assert_eq!(email.subject, "New report: spam");
+        assert!(email.body.contains(&report.report_id));
+
+        relay_batch(&state.pool, &state.events, 10).await.unwrap();
+        loop {
+            match events.recv().await.unwrap() {
+                DomainEvent::ReportCreated { report: created } => {
+                    assert_eq!(created.report_id, report.report_id);
+                    break;
+                }
+                // `user.registered` of the users above
+                _ => continue,
+            }
+        }
+
+        let reviewing = set_status(&*state.pool, &report.report_id, ReportStatus::Reviewing)
+            .await
+            .unwrap();
+        assert_eq!(reviewing.unwrap().status, ReportStatus::Reviewing);
+        // Reviewing can't go back to open
+        let reopened = set_status(&*state.pool, &report.report_id, ReportStatus::Open)
+            .await
+            .unwrap();
+        assert!(reopened.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reports() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let (reporter, token) = user(&state, "reporter").await;
+        let (author, _) = user(&state, "author").await;
+
+        for (body, status) in [
+            (
+                json!({"type": "message", "user_id": author.user_id, "reason": "spam"}),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                json!({"type": "user", "user_id": author.user_id, "reason": "rude"}),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                json!({"type": "user", "user_id": reporter.user_id, "reason": "spam"}),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                json!({"type": "user", "user_id": "missing", "reason": "spam"}),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                json!({"type": "user", "user_id": author.user_id, "group_id": "missing", "reason": "spam"}),
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            server
+                .post("/api/v1/reports")
+                .add_header("Authorization", &token)
+                .json(&body)
+                .await
+                .assert_status(status);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres};
+use tokio::task::JoinHandle;
+
+use crate::{app_state::AppState, audit::handler::record};
+
+/// `actor_id` of the audit entries written for each purge
+pub const RETENTION_ACTOR: &str = "system";
+
+/// Settings from the `[retention]` section of the config file. A policy left
+/// unset keeps its rows forever.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetentionSettings {
+    /// How often the policies run
+    pub interval_secs: u64,
+    /// Count and audit what would be purged, without deleting anything
+    pub dry_run: bool,
+    /// Age of the analytics events to delete
+    pub analytics_days: Option<i32>,
+    /// Phone login codes are deleted this many days after they expired
+    pub expired_otp_days: Option<i32>,
+    /// Revoked tokens are forgotten this many days after they expired
+    pub expired_revocation_days: Option<i32>,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        Self {
+            interval_secs: 3600,
+            dry_run: false,
+            analytics_days: None,
+            expired_otp_days: Some(1),
+            expired_revocation_days: Some(1),
+        }
+    }
+}
+
+/// Rows of `table` matching `condition`, where `$1` is `days`
+struct Policy {
+    table: &'static str,
+    condition: &'static str,
+    days: i32,
+}
+
+impl RetentionSettings {
+    fn policies(&self) -> Vec<Policy> {
+        let mut policies = Vec::new();
+        if let Some(days) = self.analytics_days {
+            policies.push(Policy {
+                table: "analytics_events",
+                condition: "occurred_at < current_timestamp - make_interval(days => $1)",
+                days,
+            });
+        }
+        if let Some(days) = self.expired_otp_days {
+            policies.push(Policy {
+                table: "otp_codes",
+                condition: "expires_at < localtimestamp - make_interval(days => $1)",
+                days,
+            });
+        }
+        if let Some(days) = self.expired_revocation_days {
+            policies.push(Policy {
+                table: "revoked_tokens",
+                condition: "expires_at < current_timestamp - make_interval(days => $1)",
+                days,
+            });
+        }
+        policies
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Purge {
+    pub table: &'static str,
+    /// Deleted, or that would have been on a dry run
+    pub rows: u64,
+}
+
+#[tracing::instrument(name = "db.retention.purge", skip(pool, policy), fields(table = policy.table))]
+async fn purge(pool: &Pool<Postgres>, policy: &Policy, dry_run: bool) -> Result<u64, Error> {
+    if dry_run {
+        let sql = format!(
+            "select count(*) from {} where {}",
+            policy.table, policy.condition
+        );
+        let count: i64 = sqlx::query_scalar(&sql)
+            .bind(policy.days)
+            .fetch_one(pool)
+            .await?;
+        return Ok(count as u64);
+    }
+    let sql = format!("delete from {} where {}", policy.table, policy.condition);
+    let result = sqlx::query(&sql).bind(policy.days).execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+/// Applies every configured policy once. Each one that matched rows leaves
+/// a `retention.purge` (or `retention.dry_run`) entry in the audit log,
+/// targeting `<table> rows=<rows> days=<days>`.
+pub async fn run(pool: &Pool<Postgres>, settings: &RetentionSettings) -> Result<Vec<Purge>, Error> {
+    let action = match settings.dry_run {
+        true => "retention.dry_run",
+        false => "retention.purge",
+    };
+    let mut purges = Vec::new();
+    for policy in settings.policies() {
+        let rows = purge(pool, &policy, settings.dry_run).await?;
+        if rows > 0 {
+            let target = format!("{} rows={} days={}", policy.table, rows, policy.days);
+            record(pool, RETENTION_ACTOR, action, &target, 200, None).await?;
+            tracing::info!(
+                table = policy.table,
+                rows,
+                dry_run = settings.dry_run,
+                "Retention purge"
+            );
+        }
+        purges.push(Purge {
+            table: policy.table,
+            rows,
+        });
+    }
+    Ok(purges)
+}
+
+/// Runs the policies every `retention.interval_secs`
+pub fn spawn_retention(state: Arc<AppState>) -> JoinHandle<()> {
+    let settings = state.settings.retention.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = run(&state.pool, &settings).await {
+                tracing::error!(error = %e, "Retention purge failed");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_retention {
+    use crate::{
+        AppState,
+        retention::{Purge, RETENTION_ACTOR, RetentionSettings, run},
+    };
+
+    async fn count(state: &AppState, table: &str) -> i64 {
+        sqlx::query_scalar(&format!("select count(*) from {}", table))
+            .fetch_one(&*state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_then_purge() {
+        let state = AppState::isolated().await;
+        sqlx::raw_sql(
+            "insert into analytics_events (user_id, name, occurred_at) values \
+                ('u1', 'old', current_timestamp - interval '40 days'), \
+                ('u1', 'new', current_timestamp - interval '1 day'); \
+             insert into otp_codes (phone, code_hash, expires_at) values \
+                ('+6281100000001', 'x', localtimestamp - interval '3 days'), \
+                ('+6281100000002', 'x', localtimestamp + interval '5 minutes'); \
+             insert into revoked_tokens (token_hash, expires_at) values \
+                ('expired', current_timestamp - interval '3 days'), \
+                ('live', current_timestamp + interval '1 hour');",
+        )
+        .execute(&*state.pool)
+        .await
+        .unwrap();
+
+        let mut settings = RetentionSettings {
+            dry_run: true,
+            analytics_days: Some(30),
+            ..Default::default()
+        };
+        let expected = vec![
+            Purge {
+                table: "analytics_events",
+                rows: 1,
+            },
+            Purge {
+                table: "otp_codes",
+                rows: 1,
+            },
+            Purge {
+                table: "revoked_tokens",
+                rows: 1,
+            },
+        ];
+        assert_eq!(run(&state.pool, &settings).await.unwrap(), expected);
+        assert_eq!(count(&state, "analytics_events").await, 2);
+        assert_eq!(count(&state, "otp_codes").await, 2);
+        assert_eq!(count(&state, "revoked_tokens").await, 2);
+
+        settings.dry_run = false;
+        assert_eq!(run(&state.pool, &settings).await.unwrap(), expected);
+        assert_eq!(count(&state, "analytics_events").await, 1);
+        assert_eq!(count(&state, "otp_codes").await, 1);
+        assert_eq!(count(&state, "revoked_tokens").await, 1);
+
+        let actions: Vec<(String, String)> = sqlx::query_as(
+            "select action, target from audit_log where actor_id = $1 order by audit_id",
+        )
+        .bind(RETENTION_ACTOR)
+        .fetch_all(&*state.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            actions,
+            [
+                (
+                    "retention.dry_run".to_string(),
+                    "analytics_events rows=1 days=30".to_string()
+                ),
+                (
+                    "retention.dry_run".to_string(),
+                    "otp_codes rows=1 days=1".to_string()
+                ),
+                (
+                    "retention.dry_run".to_string(),
+                    "revoked_tokens rows=1 days=1".to_string()
+                ),
+                (
+                    "retention.purge".to_string(),
+                    "analytics_events rows=1 days=30".to_string()
+                ),
+                (
+                    "retention.purge".to_string(),
+                    "otp_codes rows=1 days=1".to_string()
+                ),
+                (
+                    "retention.purge".to_string(),
+                    "revoked_tokens rows=1 days=1".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,66 +1,567 @@
 use std::sync::Arc;
 
 use axum::{
-    Router, middleware,
+    Extension, Router,
+    extract::Request,
+    handler::Handler,
+    http::{HeaderValue, header},
+    middleware::{self, Next},
+    response::Response,
     routing::{delete, get, post, put},
 };
 
-use crate::{app_state::AppState, auth::handler::refresh_token_handler};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+
+use crate::{
+    admin::{
+        anonymize::{anonymize_job_handler, bulk_anonymize_handler},
+        handler::{
+            config_handler, log_level_handler, reload_config_handler, set_log_level_handler,
+            stats_handler,
+        },
+    },
+    analytics::{daily::metrics_handler, handler::ingest_handler},
+    app_state::AppState,
+    audit::handler::{Audit, audit_log_handler, audit_middleware},
+    auth::handler::{refresh_handler, refresh_token_handler},
+    bot::{
+        handler::{
+            bots_handler, create_bot_handler, create_bot_token_handler, revoke_bot_token_handler,
+        },
+        usage::key_usage_handler,
+    },
+    calendar::handler::{calendar_events_handler, create_event_handler, rsvp_handler},
+    config::telemetry::{make_span, on_response},
+    error::{
+        json_errors, method_not_allowed_handler, not_found_handler, panic_request_id,
+        panic_response,
+    },
+    etag::etag,
+    export::handler::{export_audit_handler, export_users_handler},
+    i18n::localize,
+    ip_filter::{
+        admin_allow_list, deny_list, ip_lists_handler, reload_ip_lists_handler,
+        replace_ip_lists_handler,
+    },
+    message::handler::{
+        chat_history_handler, send_group_message_handler, send_private_message_handler,
+    },
+    metrics::track_requests,
+    moderation::{
+        handler::{action_handler, queue_handler, report_context_handler},
+        shadow_ban::{lift_shadow_ban_handler, shadow_ban_handler},
+        word_filter::{
+            create_word_filter_handler, delete_word_filter_handler, word_filters_handler,
+        },
+    },
+    oauth::handler::{
+        authorize_handler, clients_handler, consent_handler, create_client_handler,
+        delete_client_handler, grants_handler, revoke_grant_handler, token_handler,
+        userinfo_handler,
+    },
+    organization::handler::{
+        accept_invitation_handler, create_org_group_handler, create_organization_handler,
+        invitations_handler, invite_handler, members_handler, org_groups_handler, org_middleware,
+        org_token_handler, organizations_handler,
+    },
+    rate_limit::limits_handler,
+    report::handler::create_report_handler,
+    saml::handler::{saml_acs_handler, saml_login_handler, saml_metadata_handler},
+};
 use crate::{
     auth::{
         handler::{
-            delete_user_handler, get_users_handler, login_handler, register_handler,
-            update_password_handler,
+            delete_user_handler, get_users_handler, login_handler, logout_handler,
+            register_handler, update_password_handler,
         },
-        middleware::auth_middleware,
+        middleware::{admin_middleware, auth_middleware},
+        otp::{request_otp_handler, verify_otp_handler},
     },
+    body_limit::{AUTH_BODY_LIMIT, DEFAULT_BODY_LIMIT, with_body_limit},
+    graphql::handler::{build_schema, graphql_handler, graphql_ws_handler},
     group::handler::{create_group_handler, groups_handler},
-    websocket::{chat::private_chat_handler, group::group_chat_handler, handler::ws_handler},
+    health::handler::{healthz_handler, livez_handler, readyz_handler, version_handler},
+    hook::handler::{create_hook_handler, incoming_hook_handler, revoke_hook_handler},
+    http_cache::{Cacheable, cache_control},
+    import::handler::{IMPORT_BODY_LIMIT, import_users_handler},
+    storage::handler::{
+        attachment_url_handler, download_attachment_handler, download_avatar_handler,
+        group_files_handler, signed_download_handler, signed_variant_handler,
+        upload_attachment_handler, upload_avatar_handler,
+    },
+    webhooks::handler::{create_webhook_handler, delete_webhook_handler, webhooks_handler},
+    websocket::{
+        chat::private_chat_handler, group::group_chat_handler, handler::ws_handler,
+        socketio::socketio_handler, sse::events_handler,
+    },
 };
 
+/// Mounts each API version under `/api/<version>`. A v2 gets its own builder
+/// and a `.nest("/api/v2", api_v2(state.clone()))` next to v1, so both are
+/// served side by side. Unversioned `/api/...` paths alias v1 and are deprecated.
 pub fn routes(state: Arc<AppState>) -> Router {
+    let api_route = Router::new().nest("/api/v1", api_v1(state.clone())).nest(
+        "/api",
+        api_v1(state.clone()).layer(middleware::from_fn(deprecated_alias)),
+    );
+
+    let health_route = Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/livez", get(livez_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/version", get(version_handler));
+
+    let hook_route = Router::new().route("/hooks/{token}", post(incoming_hook_handler));
+
+    let file_route = Router::new()
+        .route("/files/{attachment_id}", get(signed_download_handler))
+        .route(
+            "/files/{attachment_id}/{variant}",
+            get(signed_variant_handler),
+        );
+
+    let ws_route = Router::new()
+        .route("/ws", get(ws_handler))
+        .route("/chat", get(private_chat_handler))
+        .route("/group-chat", get(group_chat_handler))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Socket.IO clients authenticate in their CONNECT packet, after the upgrade
+    let socketio_route = if state.settings.socketio.enabled {
+        Router::new()
+            .route("/socket.io", get(socketio_handler))
+            .route("/socket.io/", get(socketio_handler))
+    } else {
+        Router::new()
+    };
+
+    let graphql_route = Router::new()
+        .route("/graphql", post(graphql_handler))
+        .route("/graphql/ws", get(graphql_ws_handler))
+        .layer(Extension(build_schema(state.clone())))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    Router::new()
+        .merge(api_route)
+        .merge(health_route)
+        .merge(with_body_limit(hook_route, DEFAULT_BODY_LIMIT))
+        .merge(file_route)
+        .merge(with_body_limit(ws_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(socketio_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(graphql_route, DEFAULT_BODY_LIMIT))
+        .fallback(not_found_handler)
+        .method_not_allowed_fallback(method_not_allowed_handler)
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(middleware::map_response(panic_request_id))
+        .layer(middleware::from_fn_with_state(state.clone(), deny_list))
+        .layer(middleware::map_response(json_errors))
+        .layer(middleware::from_fn(localize))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_span)
+                .on_response(on_response),
+        )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state)
+}
+
+fn api_v1(state: Arc<AppState>) -> Router<Arc<AppState>> {
     let auth_route = Router::new()
-        .route("/api/auth/register", post(register_handler))
-        .route("/api/auth/login", post(login_handler))
-        .route("/api/auth/refresh-token", post(refresh_token_handler));
+        .route("/auth/register", post(register_handler))
+        .route("/auth/login", post(login_handler))
+        .route("/auth/refresh", post(refresh_handler))
+        .route("/auth/refresh-token", post(refresh_token_handler))
+        .route("/auth/otp/request", post(request_otp_handler))
+        .route("/auth/otp/verify", post(verify_otp_handler))
+        .route("/oauth/token", post(token_handler));
+
+    // Identity provider responses are signed XML, often over the auth limit
+    let saml_route = Router::new()
+        .route("/auth/saml/login", get(saml_login_handler))
+        .route("/auth/saml/acs", post(saml_acs_handler))
+        .route("/auth/saml/metadata", get(saml_metadata_handler));
 
     let auth_private_route = Router::new()
-        .route("/api/auth/update-password", put(update_password_handler))
-        .route("/api/auth/delete-account", delete(delete_user_handler))
+        .route("/auth/logout", post(logout_handler))
+        .route("/auth/update-password", put(update_password_handler))
+        .route(
+            "/auth/delete-account",
+            delete(delete_user_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "account.delete"),
+                audit_middleware,
+            )),
+        )
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ));
 
     let user_route = Router::new()
-        .route("/api/users", get(get_users_handler))
+        .route(
+            "/users",
+            get(get_users_handler)
+                .layer(middleware::from_fn(etag))
+                .layer(middleware::from_fn_with_state(
+                    Cacheable::listing(state.clone(), "users"),
+                    cache_control,
+                )),
+        )
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ));
 
     let group_route = Router::new()
-        .route("/api/groups", post(create_group_handler))
-        .route("/api/groups/{page}", get(groups_handler))
+        .route(
+            "/groups",
+            post(create_group_handler).get(groups_handler.layer(middleware::from_fn(etag)).layer(
+                middleware::from_fn_with_state(
+                    Cacheable::listing(state.clone(), "groups"),
+                    cache_control,
+                ),
+            )),
+        )
+        .route(
+            "/groups/{group_id}/messages",
+            post(send_group_message_handler),
+        )
+        .route("/groups/{group_id}/hooks", post(create_hook_handler))
+        .route(
+            "/groups/{group_id}/hooks/{hook_id}",
+            delete(revoke_hook_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "hook.revoke"),
+                audit_middleware,
+            )),
+        )
+        .route(
+            "/groups/{group_id}/webhooks",
+            post(create_webhook_handler).get(webhooks_handler),
+        )
+        .route(
+            "/groups/{group_id}/events",
+            post(create_event_handler.layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "event.create"),
+                audit_middleware,
+            )))
+            .get(calendar_events_handler),
+        )
+        .route(
+            "/groups/{group_id}/events/{event_id}/rsvp",
+            put(rsvp_handler),
+        )
+        .route(
+            "/groups/{group_id}/word-filters",
+            get(word_filters_handler).post(create_word_filter_handler.layer(
+                middleware::from_fn_with_state(
+                    Audit::action(state.clone(), "word_filter.create"),
+                    audit_middleware,
+                ),
+            )),
+        )
+        .route(
+            "/groups/{group_id}/word-filters/{filter_id}",
+            delete(delete_word_filter_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "word_filter.delete"),
+                audit_middleware,
+            )),
+        )
+        .route(
+            "/groups/{group_id}/webhooks/{webhook_id}",
+            delete(delete_webhook_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "webhook.delete"),
+                audit_middleware,
+            )),
+        )
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ));
 
-    let ws_route = Router::new()
-        .route("/ws", get(ws_handler))
-        .route("/chat", get(private_chat_handler))
-        .route("/group-chat", get(group_chat_handler))
+    // Routes under `/orgs/{org_id}` need a token scoped to that organization
+    let org_scoped_route = Router::new()
+        .route("/orgs/{org_id}/invitations", post(invite_handler))
+        .route("/orgs/{org_id}/members", get(members_handler))
+        .route(
+            "/orgs/{org_id}/groups",
+            post(create_org_group_handler).get(org_groups_handler),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            org_middleware,
+        ));
+    let org_route = Router::new()
+        .route(
+            "/orgs",
+            post(create_organization_handler).get(organizations_handler),
+        )
+        .route("/orgs/{org_id}/token", post(org_token_handler))
+        .route("/invitations", get(invitations_handler))
+        .route(
+            "/invitations/{invitation_id}/accept",
+            post(accept_invitation_handler),
+        )
+        .merge(org_scoped_route)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    let upload_route = Router::new()
+        .route("/attachments", post(upload_attachment_handler))
+        .route(
+            "/attachments/{attachment_id}",
+            get(download_attachment_handler),
+        )
+        .route(
+            "/attachments/{attachment_id}/url",
+            get(attachment_url_handler),
+        )
+        .route("/groups/{group_id}/files", get(group_files_handler))
+        .route("/users/avatar", put(upload_avatar_handler))
+        .route("/users/{user_id}/avatar", get(download_avatar_handler))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+    let upload_limit = state.settings.storage.max_upload_bytes;
+
+    // Layers run bottom-up: auth sets the claims, every call (denied ones
+    // included) is audited, then the role is checked
+    let admin = |router: Router<Arc<AppState>>| {
+        router
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                admin_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                Audit::admin(state.clone()),
+                audit_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                admin_allow_list,
+            ))
+    };
+    let admin_route = admin(
+        Router::new()
+            .route("/admin/stats", get(stats_handler))
+            .route("/admin/audit", get(audit_log_handler))
+            .route("/admin/metrics", get(metrics_handler))
+            .route("/admin/moderation/reports", get(queue_handler))
+            .route(
+                "/admin/moderation/reports/{report_id}",
+                get(report_context_handler),
+            )
+            .route(
+                "/admin/moderation/reports/{report_id}/actions",
+                post(action_handler),
+            )
+            .route(
+                "/admin/moderation/users/{user_id}/shadow-ban",
+                put(shadow_ban_handler).delete(lift_shadow_ban_handler),
+            )
+            .route("/admin/export/users", get(export_users_handler))
+            .route("/admin/export/audit", get(export_audit_handler))
+            .route(
+                "/admin/ip-lists",
+                get(ip_lists_handler).put(replace_ip_lists_handler),
+            )
+            .route("/admin/ip-lists/reload", post(reload_ip_lists_handler))
+            .route("/admin/config", get(config_handler))
+            .route("/admin/config/reload", post(reload_config_handler))
+            .route(
+                "/admin/log-level",
+                get(log_level_handler).put(set_log_level_handler),
+            )
+            .route("/admin/users/bulk-anonymize", post(bulk_anonymize_handler))
+            .route(
+                "/admin/users/bulk-anonymize/{job_id}",
+                get(anonymize_job_handler),
+            ),
+    );
+    let import_route =
+        admin(Router::new().route("/admin/users/import", post(import_users_handler)));
+
+    let bot_route = Router::new()
+        .route(
+            "/bots",
+            get(bots_handler).post(create_bot_handler.layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "bot.create"),
+                audit_middleware,
+            ))),
+        )
+        .route(
+            "/bots/{bot_id}/tokens",
+            post(create_bot_token_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "bot.token.create"),
+                audit_middleware,
+            )),
+        )
+        .route(
+            "/bots/{bot_id}/tokens/{token_id}",
+            delete(revoke_bot_token_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "bot.token.revoke"),
+                audit_middleware,
+            )),
+        )
+        .route("/keys/{token_id}/usage", get(key_usage_handler))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ));
 
+    let oauth_route = Router::new()
+        .route(
+            "/oauth/clients",
+            get(clients_handler).post(create_client_handler.layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "oauth.client.create"),
+                audit_middleware,
+            ))),
+        )
+        .route(
+            "/oauth/clients/{client_id}",
+            delete(delete_client_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "oauth.client.delete"),
+                audit_middleware,
+            )),
+        )
+        .route(
+            "/oauth/authorize",
+            get(authorize_handler).post(consent_handler.layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "oauth.authorize"),
+                audit_middleware,
+            ))),
+        )
+        .route("/oauth/consents", get(grants_handler))
+        .route(
+            "/oauth/consents/{client_id}",
+            delete(revoke_grant_handler).layer(middleware::from_fn_with_state(
+                Audit::action(state.clone(), "oauth.consent.revoke"),
+                audit_middleware,
+            )),
+        )
+        .route("/oauth/userinfo", get(userinfo_handler))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    let event_route = Router::new()
+        .route("/events", get(events_handler))
+        .route("/messages", post(send_private_message_handler))
+        .route("/chats/{peer_id}/messages", get(chat_history_handler))
+        .route("/analytics/events", post(ingest_handler))
+        .route("/reports", post(create_report_handler))
+        .route("/limits", get(limits_handler))
+        .layer(middleware::from_fn_with_state(state, auth_middleware));
+
     Router::new()
-        .merge(auth_route)
-        .merge(auth_private_route)
-        .merge(user_route)
-        .merge(group_route)
-        .merge(ws_route)
-        .with_state(state)
+        .merge(with_body_limit(auth_route, AUTH_BODY_LIMIT))
+        .merge(with_body_limit(auth_private_route, AUTH_BODY_LIMIT))
+        .merge(with_body_limit(saml_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(user_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(group_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(org_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(bot_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(oauth_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(event_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(upload_route, upload_limit))
+        .merge(with_body_limit(admin_route, DEFAULT_BODY_LIMIT))
+        .merge(with_body_limit(import_route, IMPORT_BODY_LIMIT))
+}
+
+/// Marks responses served from an unversioned `/api/...` alias as deprecated
+/// and points at the v1 path.
+async fn deprecated_alias(req: Request, next: Next) -> Response {
+    let successor = format!("</api/v1{}>; rel=\"successor-version\"", req.uri().path());
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests_routes {
+    use std::sync::Arc;
+
+    use axum_test::TestServer;
+    use http::StatusCode;
+
+    use crate::{AppState, error::ErrorResponse, routes::routes};
+
+    #[tokio::test]
+    async fn test_v1_route() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = [("user_name", "Jordan"), ("password", "123456")];
+        let response = server.post("/api/v1/auth/login").form(&body).await;
+        response.assert_status_ok();
+        assert!(response.maybe_header("deprecation").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_alias() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        let body = [("user_name", "Jordan"), ("password", "123456")];
+        let response = server.post("/api/auth/login").form(&body).await;
+        response.assert_status_ok();
+        assert_eq!(response.header("deprecation"), "true");
+        assert_eq!(
+            response.header("link"),
+            "</api/v1/auth/login>; rel=\"successor-version\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_route_is_json() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        for path in ["/missing", "/api/v1/missing"] {
+            let response = server.get(path).await;
+            response.assert_status_not_found();
+            let body = response.json::<ErrorResponse>();
+            assert_eq!(body.meta.code, 404);
+            assert_eq!(body.meta.message, "Route not found");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_method_is_json() {
+        let state = Arc::new(AppState::test().await);
+        let server = TestServer::new(routes(state)).unwrap();
+
+        for path in ["/healthz", "/api/v1/auth/login"] {
+            let response = server.delete(path).await;
+            response.assert_status(StatusCode::METHOD_NOT_ALLOWED);
+            let body = response.json::<ErrorResponse>();
+            assert_eq!(body.meta.code, 405);
+            assert_eq!(body.meta.message, "Method not allowed");
+        }
+    }
 }
@@ -0,0 +1,1101 @@
+use std::{io::Write, sync::Arc};
+
+use axum::{
+    Form,
+    extract::{Query, State},
+    http::{HeaderMap, header},
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use flate2::{Compression, write::DeflateEncoder};
+use http::StatusCode;
+use quick_xml::escape::escape;
+use reqwest::Url;
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres};
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        handler::AuthResponse,
+        service::AuthService,
+        user::{NewUser, User, validate_not_reserved},
+        util::{MetaResponse, StatusCodeExt},
+    },
+    saml::{
+        signature::{DSIG_NS, certificate_key, verify},
+        xml::{Element, XmlError, parse},
+    },
+};
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const HTTP_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+/// How long the identity provider has to answer a login request
+const REQUEST_TTL_SECS: f64 = 600.0;
+/// Longest `relay_state` kept for the login page
+const MAX_RELAY_STATE: usize = 512;
+/// Cookie holding the ID of the login request the browser started, so a
+/// response can't sign in a browser that didn't ask for it
+const REQUEST_COOKIE: &str = "saml_request";
+
+/// `[saml]`, sign-in through one SAML 2.0 identity provider per deployment
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[serde(default)]
+#[validate(schema(function = "validate_saml"))]
+pub struct SamlSettings {
+    pub enabled: bool,
+    /// Our entity ID, the audience assertions must be addressed to
+    pub entity_id: String,
+    /// Public URL of `/api/v1/auth/saml/acs`, where the identity provider posts responses
+    pub acs_url: String,
+    pub idp_entity_id: String,
+    /// Single sign-on URL of the identity provider, HTTP-Redirect binding
+    pub idp_sso_url: String,
+    /// Certificate the identity provider signs with, PEM or bare base64
+    pub idp_certificate: String,
+    /// Attribute with the user's email, the NameID when unset
+    pub email_attribute: Option<String>,
+    /// Attribute with the preferred user name, the email's local part when unset
+    pub user_name_attribute: Option<String>,
+    /// Create accounts for people who sign in for the first time, while
+    /// `features.registration` is on
+    pub provision: bool,
+    /// Link a first login to the account with the same email. Only turn this
+    /// on when the identity provider verifies emails, since it hands that
+    /// account to whoever controls the address there.
+    pub link_existing_accounts: bool,
+    /// Tolerated clock difference with the identity provider
+    pub clock_skew_secs: i64,
+    /// Page that receives the tokens in its fragment; the ACS answers with
+    /// JSON like `/auth/login` when unset
+    pub login_redirect: Option<String>,
+}
+
+impl Default for SamlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            entity_id: String::new(),
+            acs_url: String::new(),
+            idp_entity_id: String::new(),
+            idp_sso_url: String::new(),
+            idp_certificate: String::new(),
+            email_attribute: None,
+            user_name_attribute: None,
+            provision: true,
+            link_existing_accounts: false,
+            clock_skew_secs: 60,
+            login_redirect: None,
+        }
+    }
+}
+
+fn validate_saml(saml: &SamlSettings) -> Result<(), ValidationError> {
+    if !saml.enabled {
+        return Ok(());
+    }
+    let required = [
+        ("entity_id", &saml.entity_id),
+        ("acs_url", &saml.acs_url),
+        ("idp_entity_id", &saml.idp_entity_id),
+        ("idp_sso_url", &saml.idp_sso_url),
+    ];
+    for (name, value) in required {
+        if value.is_empty() {
+            return Err(ValidationError::new("saml")
+                .with_message(format!("{} is required when saml is enabled", name).into()));
+        }
+    }
+    let urls = [Some(&saml.idp_sso_url), saml.login_redirect.as_ref()];
+    if urls
+        .into_iter()
+        .flatten()
+        .any(|url| Url::parse(url).is_err())
+    {
+        return Err(ValidationError::new("saml")
+            .with_message("idp_sso_url and login_redirect must be absolute URLs".into()));
+    }
+    certificate_key(&saml.idp_certificate).map_err(|e| {
+        ValidationError::new("saml").with_message(format!("idp_certificate: {}", e).into())
+    })?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SamlError {
+    Disabled,
+    /// The response failed a check, which is named
+    Invalid(String),
+    /// The identity provider answered with something other than success
+    Refused,
+    /// Unknown person and `provision` is off
+    NotProvisioned,
+    /// Unknown person while `features.registration` is off
+    RegistrationDisabled,
+    /// Unknown person whose email has an account, and
+    /// `link_existing_accounts` is off
+    NotLinked,
+    Storage(String),
+}
+
+impl From<XmlError> for SamlError {
+    fn from(error: XmlError) -> Self {
+        SamlError::Invalid(error.0)
+    }
+}
+
+impl From<Error> for SamlError {
+    fn from(error: Error) -> Self {
+        SamlError::Storage(error.to_string())
+    }
+}
+
+impl From<SamlError> for MetaResponse {
+    fn from(error: SamlError) -> Self {
+        let (status, message) = match error {
+            SamlError::Disabled => (StatusCode::NOT_FOUND, "SAML is not enabled".to_string()),
+            SamlError::Invalid(reason) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Invalid SAML response: {}", reason),
+            ),
+            SamlError::Refused => (
+                StatusCode::UNAUTHORIZED,
+                "The identity provider refused the login".to_string(),
+            ),
+            SamlError::NotProvisioned => (
+                StatusCode::FORBIDDEN,
+                "No account for this identity".to_string(),
+            ),
+            SamlError::RegistrationDisabled => (
+                StatusCode::FORBIDDEN,
+                "Registration is disabled".to_string(),
+            ),
+            SamlError::NotLinked => (
+                StatusCode::FORBIDDEN,
+                "An account with this email exists but isn't linked to this identity".to_string(),
+            ),
+            SamlError::Storage(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        MetaResponse {
+            code: status.to_i32(),
+            message,
+        }
+    }
+}
+
+fn invalid(reason: &str) -> SamlError {
+    SamlError::Invalid(reason.to_string())
+}
+
+fn settings(state: &AppState) -> Result<&SamlSettings, SamlError> {
+    match state.settings.saml.enabled {
+        true => Ok(&state.settings.saml),
+        false => Err(SamlError::Disabled),
+    }
+}
+
+/// Who the identity provider vouched for
+#[derive(Debug)]
+struct Identity {
+    name_id: String,
+    email: String,
+    user_name: String,
+    in_response_to: String,
+}
+
+fn instant(element: &Element, name: &str) -> Result<Option<DateTime<Utc>>, SamlError> {
+    element
+        .attribute(name)
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| SamlError::Invalid(format!("{} is not a timestamp", name)))
+        })
+        .transpose()
+}
+
+fn text(element: Option<&Element>) -> Option<String> {
+    element
+        .map(|element| element.text().trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// Checks a `samlp:Response` the way SAML 2.0 Web Browser SSO asks: signed
+/// by the configured certificate, from the configured issuer, addressed to
+/// us, in time, and answering a request we made. Everything is read from
+/// the element whose signature was checked.
+fn check_response(
+    settings: &SamlSettings,
+    key: &RsaPublicKey,
+    response: &Element,
+    now: DateTime<Utc>,
+) -> Result<Identity, SamlError> {
+    if response.ns != PROTOCOL_NS || response.local != "Response" {
+        return Err(invalid("not a samlp:Response"));
+    }
+    if let Some(id) = response.duplicate_id() {
+        return Err(SamlError::Invalid(format!("duplicate ID {}", id)));
+    }
+    if response
+        .attribute("Destination")
+        .is_some_and(|destination| destination != settings.acs_url)
+    {
+        return Err(invalid("Destination is not this service"));
+    }
+    let issuer = |element: &Element| text(element.child(ASSERTION_NS, "Issuer"));
+    if issuer(response).is_some_and(|issuer| issuer != settings.idp_entity_id) {
+        return Err(invalid("unknown Issuer"));
+    }
+    let status = response
+        .child(PROTOCOL_NS, "Status")
+        .and_then(|status| status.child(PROTOCOL_NS, "StatusCode"))
+        .and_then(|code| code.attribute("Value"));
+    if status != Some(SUCCESS) {
+        return Err(SamlError::Refused);
+    }
+    if response.child(ASSERTION_NS, "EncryptedAssertion").is_some() {
+        return Err(invalid("encrypted assertions are not supported"));
+    }
+    let mut assertions = response.children(ASSERTION_NS, "Assertion");
+    let assertion = assertions.next().ok_or_else(|| invalid("no Assertion"))?;
+    if assertions.next().is_some() {
+        return Err(invalid("more than one Assertion"));
+    }
+    let response_signed = response.child(DSIG_NS, "Signature").is_some();
+    if response_signed {
+        verify(response, key)?;
+    }
+    if !response_signed || assertion.child(DSIG_NS, "Signature").is_some() {
+        verify(assertion, key)?;
+    }
+
+    let in_response_to = response
+        .attribute("InResponseTo")
+        .ok_or_else(|| invalid("only logins started at /auth/saml/login are accepted"))?;
+    if issuer(assertion).as_deref() != Some(settings.idp_entity_id.as_str()) {
+        return Err(invalid("unknown Issuer"));
+    }
+    let skew = Duration::seconds(settings.clock_skew_secs);
+    let subject = assertion
+        .child(ASSERTION_NS, "Subject")
+        .ok_or_else(|| invalid("no Subject"))?;
+    let name_id =
+        text(subject.child(ASSERTION_NS, "NameID")).ok_or_else(|| invalid("no NameID"))?;
+    let mut confirmed = false;
+    for confirmation in subject.children(ASSERTION_NS, "SubjectConfirmation") {
+        let Some(data) = confirmation.child(ASSERTION_NS, "SubjectConfirmationData") else {
+            continue;
+        };
+        confirmed |= confirmation.attribute("Method") == Some(BEARER)
+            && data.attribute("Recipient") == Some(settings.acs_url.as_str())
+            && instant(data, "NotOnOrAfter")?.is_some_and(|end| now < end + skew)
+            // Unless the response is signed, only the assertion ties it to our request
+            && data
+                .attribute("InResponseTo")
+                .map_or(response_signed, |id| id == in_response_to);
+    }
+    if !confirmed {
+        return Err(invalid("no bearer SubjectConfirmation for this service"));
+    }
+    let conditions = assertion
+        .child(ASSERTION_NS, "Conditions")
+        .ok_or_else(|| invalid("no Conditions"))?;
+    if instant(conditions, "NotBefore")?.is_some_and(|start| now + skew < start)
+        || instant(conditions, "NotOnOrAfter")?.is_some_and(|end| now >= end + skew)
+    {
+        return Err(invalid("assertion expired or not yet valid"));
+    }
+    let addressed = conditions
+        .children(ASSERTION_NS, "AudienceRestriction")
+        .flat_map(|restriction| restriction.children(ASSERTION_NS, "Audience"))
+        .any(|audience| audience.text().trim() == settings.entity_id);
+    if !addressed {
+        return Err(invalid("Audience is not this service"));
+    }
+
+    let attribute = |name: &str| {
+        assertion
+            .children(ASSERTION_NS, "AttributeStatement")
+            .flat_map(|statement| statement.children(ASSERTION_NS, "Attribute"))
+            .find(|attribute| attribute.attribute("Name") == Some(name))
+            .and_then(|attribute| text(attribute.child(ASSERTION_NS, "AttributeValue")))
+    };
+    let email = match &settings.email_attribute {
+        Some(name) => attribute(name),
+        None => Some(name_id.clone()),
+    }
+    .filter(|email| email.contains('@'))
+    .ok_or_else(|| invalid("no email address"))?;
+    let user_name = settings
+        .user_name_attribute
+        .as_deref()
+        .and_then(attribute)
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+    Ok(Identity {
+        name_id,
+        email,
+        user_name,
+        in_response_to: in_response_to.to_string(),
+    })
+}
+
+/// `samlp:AuthnRequest` asking for a response posted to the ACS
+fn authn_request(settings: &SamlSettings, id: &str, now: DateTime<Utc>) -> String {
+    format!(
+        "<samlp:AuthnRequest xmlns:samlp=\"{PROTOCOL_NS}\" xmlns:saml=\"{ASSERTION_NS}\" \
+         ID=\"{id}\" Version=\"2.0\" IssueInstant=\"{}\" Destination=\"{}\" \
+         AssertionConsumerServiceURL=\"{}\" ProtocolBinding=\"{HTTP_POST}\">\
+         <saml:Issuer>{}</saml:Issuer>\
+         <samlp:NameIDPolicy AllowCreate=\"true\"/></samlp:AuthnRequest>",
+        now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        escape(&settings.idp_sso_url),
+        escape(&settings.acs_url),
+        escape(&settings.entity_id),
+    )
+}
+
+/// Remembers a login request until its response comes back, dropping ones
+/// that were never answered
+#[tracing::instrument(name = "db.saml_requests.create", skip(pool))]
+async fn create_request(
+    pool: &Pool<Postgres>,
+    request_id: &str,
+    relay_state: Option<&str>,
+) -> Result<(), Error> {
+    sqlx::query("delete from saml_requests where expires_at < current_timestamp")
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "insert into saml_requests (request_id, relay_state, expires_at) \
+         values ($1, $2, current_timestamp + make_interval(secs => $3))",
+    )
+    .bind(request_id)
+    .bind(relay_state)
+    .bind(REQUEST_TTL_SECS)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The request's relay state, if it's still open. It can only be answered once.
+#[tracing::instrument(name = "db.saml_requests.take", skip(pool))]
+async fn take_request(
+    pool: &Pool<Postgres>,
+    request_id: &str,
+) -> Result<Option<Option<String>>, Error> {
+    sqlx::query_scalar(
+        "delete from saml_requests where request_id = $1 and expires_at > current_timestamp \
+         returning relay_state",
+    )
+    .bind(request_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// A name nobody has yet, as close to `wanted` as the rules for user names allow
+async fn free_user_name(state: &AppState, wanted: &str) -> Result<String, SamlError> {
+    let base: String = wanted
+        .chars()
+        .filter(|ch| ch.is_alphanumeric() || "._-".contains(*ch))
+        .take(24)
+        .collect();
+    let mut candidate = base.clone();
+    for _ in 0..5 {
+        if candidate.chars().count() >= 6
+            && validate_not_reserved(&candidate).is_ok()
+            && !state.users.name_taken(&candidate).await?
+        {
+            return Ok(candidate);
+        }
+        candidate = format!("{}_{}", base, &Uuid::new_v4().simple().to_string()[..5]);
+    }
+    Err(SamlError::Storage("Could not pick a user name".to_string()))
+}
+
+/// The account an identity signs in to. The first login links the account
+/// with the same email when `link_existing_accounts` is on, or creates one
+/// when `provision` and registration are on.
+async fn provision(
+    state: &AppState,
+    settings: &SamlSettings,
+    identity: &Identity,
+) -> Result<User, SamlError> {
+    let linked: Option<String> = sqlx::query_scalar(
+        "select user_id from saml_identities where idp_entity_id = $1 and name_id = $2",
+    )
+    .bind(&settings.idp_entity_id)
+    .bind(&identity.name_id)
+    .fetch_optional(&*state.pool)
+    .await?;
+    let user_id = match linked {
+        Some(user_id) => user_id,
+        None => {
+            let email = state.runtime.load().email_policy.normalize(&identity.email);
+            let existing: Option<String> = sqlx::query_scalar(
+                "select user_id from users where lower(email) = lower($1) and not is_bot limit 1",
+            )
+            .bind(&email)
+            .fetch_optional(&*state.pool)
+            .await?;
+            let user_id = match existing {
+                Some(user_id) if settings.link_existing_accounts => user_id,
+                Some(_) => return Err(SamlError::NotLinked),
+                None if !settings.provision => return Err(SamlError::NotProvisioned),
+                None if !state.runtime.load().features.registration => {
+                    return Err(SamlError::RegistrationDisabled);
+                }
+                None => {
+                    let user_name = free_user_name(state, &identity.user_name).await?;
+                    // Nobody knows the password, the account signs in through SSO
+                    let password = format!("{}{}", Uuid::new_v4(), Uuid::new_v4());
+                    state
+                        .users
+                        .add(NewUser::new(user_name, email, password))
+                        .await?
+                        .user_id
+                }
+            };
+            sqlx::query(
+                "insert into saml_identities (idp_entity_id, name_id, user_id) values ($1, $2, $3) \
+                 on conflict do nothing",
+            )
+            .bind(&settings.idp_entity_id)
+            .bind(&identity.name_id)
+            .bind(&user_id)
+            .execute(&*state.pool)
+            .await?;
+            user_id
+        }
+    };
+    state
+        .user_cache
+        .get_user(&user_id, &state.pool)
+        .await
+        .ok_or_else(|| SamlError::Storage("User not found".to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginParams {
+    /// Handed back to `login_redirect` as `state`
+    pub relay_state: Option<String>,
+}
+
+/// `Set-Cookie` value of `REQUEST_COOKIE`, scoped to the ACS. The identity
+/// provider posts from its own site, hence `SameSite=None`.
+fn request_cookie(settings: &SamlSettings, request_id: &str, max_age: i64) -> String {
+    let path = Url::parse(&settings.acs_url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| "/".to_string());
+    format!(
+        "{REQUEST_COOKIE}={request_id}; Path={path}; Max-Age={max_age}; HttpOnly; Secure; SameSite=None"
+    )
+}
+
+/// Value of the cookie `name` sent with the request
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Starts a login: sends the browser to the identity provider with an
+/// `AuthnRequest` (HTTP-Redirect binding), and remembers the request in a
+/// cookie the ACS checks
+pub async fn saml_login_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<LoginParams>,
+) -> Result<Response, MetaResponse> {
+    let settings = settings(&state)?;
+    if params
+        .relay_state
+        .as_ref()
+        .is_some_and(|relay_state| relay_state.len() > MAX_RELAY_STATE)
+    {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: format!("relay_state must be at most {} bytes", MAX_RELAY_STATE),
+        });
+    }
+    let request_id = format!("_{}", Uuid::new_v4().simple());
+    create_request(&state.pool, &request_id, params.relay_state.as_deref())
+        .await
+        .map_err(SamlError::from)?;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let request = authn_request(settings, &request_id, Utc::now());
+    let deflated = encoder
+        .write_all(request.as_bytes())
+        .and_then(|_| encoder.finish())
+        .map_err(|e| SamlError::Storage(e.to_string()))?;
+    let mut url =
+        Url::parse(&settings.idp_sso_url).map_err(|e| SamlError::Storage(e.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("SAMLRequest", &STANDARD.encode(deflated));
+    let cookie = request_cookie(settings, &request_id, REQUEST_TTL_SECS as i64);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response())
+}
+
+/// Service provider metadata to register with the identity provider
+pub async fn saml_metadata_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, MetaResponse> {
+    let settings = settings(&state)?;
+    let metadata = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <md:EntityDescriptor xmlns:md=\"urn:oasis:names:tc:SAML:2.0:metadata\" entityID=\"{}\">\
+         <md:SPSSODescriptor AuthnRequestsSigned=\"false\" WantAssertionsSigned=\"true\" \
+         protocolSupportEnumeration=\"{PROTOCOL_NS}\">\
+         <md:NameIDFormat>urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress</md:NameIDFormat>\
+         <md:AssertionConsumerService Binding=\"{HTTP_POST}\" Location=\"{}\" index=\"0\" isDefault=\"true\"/>\
+         </md:SPSSODescriptor></md:EntityDescriptor>\n",
+        escape(&settings.entity_id),
+        escape(&settings.acs_url),
+    );
+    Ok((
+        [(header::CONTENT_TYPE, "application/samlmetadata+xml")],
+        metadata,
+    )
+        .into_response())
+}
+
+/// Form the identity provider posts (HTTP-POST binding)
+#[derive(Debug, Deserialize)]
+pub struct AcsForm {
+    #[serde(rename = "SAMLResponse")]
+    pub saml_response: String,
+}
+
+/// Assertion consumer service: signs in whoever the identity provider
+/// vouched for, in the browser that started the login
+pub async fn saml_acs_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Form(form): Form<AcsForm>,
+) -> Result<Response, MetaResponse> {
+    let settings = settings(&state)?;
+    let key = certificate_key(&settings.idp_certificate).map_err(SamlError::from)?;
+    let encoded: String = form.saml_response.split_whitespace().collect();
+    let xml = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| invalid("SAMLResponse is not base64 encoded XML"))?;
+    let response = parse(&xml).map_err(SamlError::from)?;
+    let identity = check_response(settings, &key, &response, Utc::now())?;
+    if cookie(&headers, REQUEST_COOKIE) != Some(identity.in_response_to.as_str()) {
+        return Err(invalid("the login was not started in this browser").into());
+    }
+    let relay_state = take_request(&state.pool, &identity.in_response_to)
+        .await
+        .map_err(SamlError::from)?
+        .ok_or_else(|| invalid("unknown, expired or already answered request"))?;
+    let user = provision(&state, settings, &identity).await?;
+    let session = AuthService::new(&state).sso_login(user);
+    let clear = [(header::SET_COOKIE, request_cookie(settings, "", 0))];
+
+    let Some(page) = &settings.login_redirect else {
+        return Ok((clear, AuthResponse::from(session)).into_response());
+    };
+    let mut fragment = form_urlencoded::Serializer::new(String::new());
+    for (name, value) in [
+        ("access_token", session.access_token),
+        ("refresh_token", session.refresh_token),
+        ("state", relay_state),
+    ] {
+        if let Some(value) = value {
+            fragment.append_pair(name, &value);
+        }
+    }
+    let mut url = Url::parse(page).map_err(|e| SamlError::Storage(e.to_string()))?;
+    url.set_fragment(Some(&fragment.finish()));
+    Ok((clear, Redirect::to(url.as_str())).into_response())
+}
+
+#[cfg(test)]
+mod tests_saml {
+    use std::{io::Read, sync::Arc};
+
+    use axum_test::TestServer;
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use chrono::{Duration, SecondsFormat, Utc};
+    use flate2::read::DeflateDecoder;
+    use http::StatusCode;
+    use reqwest::Url;
+
+    use crate::{
+        AppState,
+        auth::{handler::AuthResponse, util::random_name},
+        config::settings::Settings,
+        error::ErrorResponse,
+        routes::routes,
+        saml::{
+            handler::{SamlError, SamlSettings, check_response},
+            signature::{certificate_key, sign},
+            xml::parse,
+        },
+    };
+
+    const KEY: &str = include_str!("testdata/idp.key");
+    const CERTIFICATE: &str = include_str!("testdata/idp.crt");
+    const ACS: &str = "https://api.example.com/api/v1/auth/saml/acs";
+
+    fn saml_settings() -> SamlSettings {
+        SamlSettings {
+            enabled: true,
+            entity_id: "https://api.example.com/saml".to_string(),
+            acs_url: ACS.to_string(),
+            idp_entity_id: "https://idp.example.com".to_string(),
+            idp_sso_url: "https://idp.example.com/sso".to_string(),
+            idp_certificate: CERTIFICATE.to_string(),
+            ..SamlSettings::default()
+        }
+    }
+
+    async fn state(saml: SamlSettings) -> AppState {
+        let mut state = AppState::test().await;
+        let mut settings = Settings::clone(&state.settings);
+        settings.saml = saml;
+        state.settings = Arc::new(settings);
+        state
+    }
+
+    /// Keeps cookies like the browser a login happens in
+    async fn server(saml: SamlSettings) -> TestServer {
+        browser(state(saml).await)
+    }
+
+    fn browser(state: AppState) -> TestServer {
+        let mut server = TestServer::new(routes(Arc::new(state))).unwrap();
+        server.save_cookies();
+        server
+    }
+
+    /// Starts a login and returns the ID of the request sent to the identity provider
+    async fn login(server: &TestServer, relay_state: Option<&str>) -> String {
+        let mut request = server.get("/api/v1/auth/saml/login");
+        if let Some(relay_state) = relay_state {
+            request = request.add_query_param("relay_state", relay_state);
+        }
+        let response = request.await;
+        response.assert_status(StatusCode::SEE_OTHER);
+        let location = Url::parse(response.header("location").to_str().unwrap()).unwrap();
+        assert!(
+            location
+                .as_str()
+                .starts_with("https://idp.example.com/sso?")
+        );
+        let (_, request) = location
+            .query_pairs()
+            .find(|(key, _)| key == "SAMLRequest")
+            .unwrap();
+        let mut xml = String::new();
+        DeflateDecoder::new(STANDARD.decode(request.as_ref()).unwrap().as_slice())
+            .read_to_string(&mut xml)
+            .unwrap();
+        let request = parse(&xml).unwrap();
+        assert_eq!(request.attribute("AssertionConsumerServiceURL"), Some(ACS));
+        request.attribute("ID").unwrap().to_string()
+    }
+
+    /// A response signed by the identity provider, with the assertion signed
+    fn response(request_id: &str, email: &str, audience: &str) -> String {
+        let now = Utc::now();
+        let time = |offset: i64| {
+            (now + Duration::minutes(offset)).to_rfc3339_opts(SecondsFormat::Secs, true)
+        };
+        let assertion = format!(
+            r#"<saml:Assertion xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_a{id}" Version="2.0" IssueInstant="{now}"><saml:Issuer>https://idp.example.com</saml:Issuer><saml:Subject><saml:NameID Format="urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress">{email}</saml:NameID><saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer"><saml:SubjectConfirmationData InResponseTo="{id}" NotOnOrAfter="{end}" Recipient="{ACS}"/></saml:SubjectConfirmation></saml:Subject><saml:Conditions NotBefore="{start}" NotOnOrAfter="{end}"><saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction></saml:Conditions><saml:AuthnStatement AuthnInstant="{now}"/></saml:Assertion>"#,
+            id = request_id,
+            now = time(0),
+            start = time(-1),
+            end = time(5),
+        );
+        let assertion = sign(&assertion, "</saml:Issuer>", KEY);
+        let response = format!(
+            r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" ID="_r{request_id}" Version="2.0" InResponseTo="{request_id}" Destination="{ACS}"><saml:Issuer xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion">https://idp.example.com</saml:Issuer><samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status>{assertion}</samlp:Response>"#
+        );
+        STANDARD.encode(response)
+    }
+
+    /// Runs `check_response` on a response built by `response`, after `tamper`
+    fn check(tamper: impl Fn(String) -> String) -> Result<String, String> {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = String::from_utf8(
+            STANDARD
+                .decode(response(
+                    "_req",
+                    "jordan@mail.com",
+                    "https://api.example.com/saml",
+                ))
+                .unwrap(),
+        )
+        .unwrap();
+        match check_response(
+            &saml_settings(),
+            &key,
+            &parse(&tamper(xml)).unwrap(),
+            Utc::now(),
+        ) {
+            Ok(identity) => Ok(identity.email),
+            Err(SamlError::Invalid(reason)) => Err(reason),
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    /// The signed assertion of `xml`, and an unsigned copy for another user
+    /// with an ID of its own
+    fn assertions(xml: &str) -> (String, String) {
+        let start = xml.find("<saml:Assertion").unwrap();
+        let end = xml.rfind("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+        let signed = xml[start..end].to_string();
+        let signature_start = signed.find("<ds:Signature").unwrap();
+        let signature_end = signed.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let evil = format!("{}{}", &signed[..signature_start], &signed[signature_end..])
+            .replace("jordan@mail.com", "admin@mail.com")
+            .replace("ID=\"_a_req\"", "ID=\"_evil\"");
+        (signed, evil)
+    }
+
+    #[test]
+    fn test_signature_wrapping() {
+        assert_eq!(check(|xml| xml), Ok("jordan@mail.com".to_string()));
+
+        // A second, unsigned assertion next to the signed one
+        let result = check(|xml| {
+            let (signed, evil) = assertions(&xml);
+            xml.replace(&signed, &format!("{}{}", evil, signed))
+        });
+        assert_eq!(result, Err("more than one Assertion".to_string()));
+        // Or with the signed one's ID
+        let result = check(|xml| {
+            let (signed, evil) = assertions(&xml);
+            let evil = evil.replace("ID=\"_evil\"", "ID=\"_a_req\"");
+            xml.replace(&signed, &format!("{}{}", evil, signed))
+        });
+        assert_eq!(result, Err("duplicate ID _a_req".to_string()));
+
+        // The signed assertion tucked away in one that isn't
+        let result = check(|xml| {
+            let (signed, evil) = assertions(&xml);
+            let wrapped = evil.replace(
+                "</saml:Issuer>",
+                &format!("</saml:Issuer><saml:Advice>{}</saml:Advice>", signed),
+            );
+            xml.replace(&signed, &wrapped)
+        });
+        assert_eq!(result, Err("Not signed".to_string()));
+
+        // Or moved into the response's extensions
+        let result = check(|xml| {
+            let (signed, evil) = assertions(&xml);
+            xml.replace(
+                &signed,
+                &format!("<samlp:Extensions>{}</samlp:Extensions>{}", signed, evil),
+            )
+        });
+        assert_eq!(result, Err("Not signed".to_string()));
+
+        // The signature moved onto an assertion that took over the signed one's ID
+        let result = check(|xml| {
+            let (signed, _) = assertions(&xml);
+            let moved = signed.replace("jordan@mail.com", "admin@mail.com");
+            xml.replace(&signed, &moved)
+        });
+        assert_eq!(result, Err("Digest doesn't match".to_string()));
+
+        // The ID of the signed assertion used again elsewhere
+        let result = check(|xml| {
+            let (signed, _) = assertions(&xml);
+            let id = parse(&signed).unwrap().attribute("ID").unwrap().to_string();
+            xml.replace(
+                "<samlp:Status>",
+                &format!(
+                    "<samlp:Extensions><x ID=\"{}\"/></samlp:Extensions><samlp:Status>",
+                    id
+                ),
+            )
+        });
+        assert_eq!(result, Err("duplicate ID _a_req".to_string()));
+    }
+
+    /// Responses as Keycloak and Okta send them, signed by the JDK's XML-DSig
+    /// implementation with `testdata/SignFixtures.java`
+    #[test]
+    fn test_identity_provider_responses() {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let now = "2026-10-15T09:00:30Z".parse().unwrap();
+        let check = |settings: &SamlSettings, xml: &str| match check_response(
+            settings,
+            &key,
+            &parse(xml).unwrap(),
+            now,
+        ) {
+            Ok(identity) => Ok((identity.email, identity.user_name)),
+            Err(SamlError::Invalid(reason)) => Err(reason),
+            Err(error) => panic!("{:?}", error),
+        };
+
+        // Both signed, the assertion with a default namespace of its own
+        let keycloak = SamlSettings {
+            idp_entity_id: "https://keycloak.example.com/realms/corp".to_string(),
+            ..saml_settings()
+        };
+        let xml = include_str!("testdata/keycloak_response.xml");
+        assert_eq!(
+            check(&keycloak, xml),
+            Ok((
+                "jordan.keycloak@corp.example.com".to_string(),
+                "jordan.keycloak".to_string()
+            ))
+        );
+        let result = check(&keycloak, &xml.replace(">jordan.keycloak@", ">admin@"));
+        assert_eq!(result, Err("Digest doesn't match".to_string()));
+
+        // Only the assertion signed, pretty-printed, keeping `xs` for `xsi:type`
+        let okta = SamlSettings {
+            idp_entity_id: "http://www.okta.com/exk1fxgw9kcRUbXYZ5d7".to_string(),
+            user_name_attribute: Some("username".to_string()),
+            ..saml_settings()
+        };
+        let xml = include_str!("testdata/okta_response.xml");
+        assert_eq!(
+            check(&okta, xml),
+            Ok((
+                "jordan.okta@corp.example.com".to_string(),
+                "jordan.okta".to_string()
+            ))
+        );
+        for tampered in [
+            xml.replace("        </saml2:Subject>", "</saml2:Subject>"),
+            xml.replace(" PrefixList=\"xs\"", ""),
+            xml.replace(">jordan.okta<", ">admin<"),
+        ] {
+            let result = check(&okta, &tampered);
+            assert_eq!(result, Err("Digest doesn't match".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_saml_login() {
+        let server = server(saml_settings()).await;
+        let email = format!("{}@corp.example.com", random_name().to_lowercase());
+
+        let metadata = server.get("/api/v1/auth/saml/metadata").await;
+        metadata.assert_status_ok();
+        assert!(metadata.text().contains(ACS));
+
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(&request_id, &email, "https://api.example.com/saml"),
+        )];
+        let session = server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .json::<AuthResponse>();
+        let user = session.data.unwrap();
+        assert_eq!(user.email, email);
+        assert!(session.access_token.is_some());
+        // Each request is answered once
+        server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // The next login finds the same account
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(&request_id, &email, "https://api.example.com/saml"),
+        )];
+        let again = server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .json::<AuthResponse>();
+        assert_eq!(again.data.unwrap().user_id, user.user_id);
+
+        // Tampered with, or meant for someone else
+        let request_id = login(&server, None).await;
+        let tampered = response(&request_id, &email, "https://api.example.com/saml");
+        let tampered = String::from_utf8(STANDARD.decode(tampered).unwrap())
+            .unwrap()
+            .replace(&email, "admin@corp.example.com");
+        let body = [("SAMLResponse", STANDARD.encode(tampered))];
+        server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        let body = [(
+            "SAMLResponse",
+            response(&request_id, &email, "https://other.example.com"),
+        )];
+        server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_saml_login_bound_to_browser() {
+        let server = server(saml_settings()).await;
+        let email = format!("{}@corp.example.com", random_name().to_lowercase());
+        let cookie = server.get("/api/v1/auth/saml/login").await;
+        let cookie = cookie.header("set-cookie").to_str().unwrap().to_string();
+        assert!(cookie.starts_with("saml_request=_"));
+        assert!(cookie.contains("Path=/api/v1/auth/saml/acs;"));
+        assert!(cookie.ends_with("HttpOnly; Secure; SameSite=None"));
+
+        // A response posted from a browser that didn't start the login
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(&request_id, &email, "https://api.example.com/saml"),
+        )];
+        let refused = server
+            .post("/api/v1/auth/saml/acs")
+            .clear_cookies()
+            .form(&body)
+            .await;
+        refused.assert_status(StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            refused.json::<ErrorResponse>().meta.message,
+            "Invalid SAML response: the login was not started in this browser"
+        );
+
+        // Or answering a login other than the one this browser started last
+        let other = login(&server, None).await;
+        server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        let body = [(
+            "SAMLResponse",
+            response(&other, &email, "https://api.example.com/saml"),
+        )];
+        let response = server.post("/api/v1/auth/saml/acs").form(&body).await;
+        response.assert_status_ok();
+        assert!(
+            response
+                .header("set-cookie")
+                .to_str()
+                .unwrap()
+                .starts_with("saml_request=; Path=/api/v1/auth/saml/acs; Max-Age=0;")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_saml_redirect_and_provisioning() {
+        let server = server(SamlSettings {
+            provision: false,
+            link_existing_accounts: true,
+            login_redirect: Some("https://app.example.com/sso".to_string()),
+            ..saml_settings()
+        })
+        .await;
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(
+                &request_id,
+                "stranger@corp.example.com",
+                "https://api.example.com/saml",
+            ),
+        )];
+        server
+            .post("/api/v1/auth/saml/acs")
+            .form(&body)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        // Jordan is seeded in the test schema
+        let response_to = |request_id: &str| {
+            [(
+                "SAMLResponse",
+                response(
+                    request_id,
+                    "jordan@mail.com",
+                    "https://api.example.com/saml",
+                ),
+            )]
+        };
+        let request_id = login(&server, Some("/inbox")).await;
+        let response = server
+            .post("/api/v1/auth/saml/acs")
+            .form(&response_to(&request_id))
+            .await;
+        response.assert_status(StatusCode::SEE_OTHER);
+        let location = response.header("location").to_str().unwrap().to_string();
+        assert!(location.starts_with("https://app.example.com/sso#access_token="));
+        assert!(location.ends_with("&state=%2Finbox"));
+    }
+
+    #[tokio::test]
+    async fn test_saml_existing_account_not_linked() {
+        // Jordan is seeded in the test schema, and linking is off by default
+        let server = server(saml_settings()).await;
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(
+                &request_id,
+                "jordan@mail.com",
+                "https://api.example.com/saml",
+            ),
+        )];
+        let response = server.post("/api/v1/auth/saml/acs").form(&body).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "An account with this email exists but isn't linked to this identity"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_saml_provisioning_respects_registration() {
+        let state = state(saml_settings()).await;
+        let mut runtime = (**state.runtime.load()).clone();
+        runtime.features.registration = false;
+        state.runtime.store(Arc::new(runtime));
+        let server = browser(state);
+
+        let email = format!("{}@corp.example.com", random_name().to_lowercase());
+        let request_id = login(&server, None).await;
+        let body = [(
+            "SAMLResponse",
+            response(&request_id, &email, "https://api.example.com/saml"),
+        )];
+        let response = server.post("/api/v1/auth/saml/acs").form(&body).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Registration is disabled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_saml_disabled() {
+        let server = server(SamlSettings::default()).await;
+        server
+            .get("/api/v1/auth/saml/login")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,353 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rsa::{
+    RsaPublicKey,
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    signature::Verifier,
+};
+use sha2::{Digest, Sha256};
+
+use crate::saml::xml::{Element, XmlError, canonicalize};
+
+pub const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+fn invalid(reason: &str) -> XmlError {
+    XmlError(reason.to_string())
+}
+
+/// Splits the DER value at the front of `der` into its whole encoding, its
+/// contents and what follows
+fn tlv(der: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let (_, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        len if len < 0x80 => (len as usize, rest),
+        long => {
+            let count = (long & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, byte| len << 8 | *byte as usize);
+            (len, &rest[count..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = der.len() - rest.len();
+    Some((&der[..header + len], &rest[..len], &rest[len..]))
+}
+
+/// The RSA key of a certificate given as PEM, or as the bare base64 IdP
+/// metadata carries. Nothing else about the certificate is checked: it's
+/// trusted because the deployment configured it.
+pub fn certificate_key(certificate: &str) -> Result<RsaPublicKey, XmlError> {
+    let body: String = certificate
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .flat_map(|line| line.chars().filter(|ch| !ch.is_whitespace()))
+        .collect();
+    let der = STANDARD
+        .decode(body)
+        .map_err(|_| invalid("Certificate is not valid base64"))?;
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version?,
+    // serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo, .. } .. }
+    let spki = (|| {
+        let (_, certificate, _) = tlv(&der)?;
+        let (_, mut fields, _) = tlv(certificate)?;
+        if fields.first() == Some(&0xa0) {
+            fields = tlv(fields)?.2;
+        }
+        for _ in 0..5 {
+            fields = tlv(fields)?.2;
+        }
+        Some(tlv(fields)?.0)
+    })()
+    .ok_or_else(|| invalid("Certificate is not valid DER"))?;
+    RsaPublicKey::from_public_key_der(spki).map_err(|_| invalid("Certificate key is not RSA"))
+}
+
+fn algorithm<'a>(parent: &'a Element, local: &str) -> Result<(&'a Element, &'a str), XmlError> {
+    let element = parent
+        .child(DSIG_NS, local)
+        .ok_or_else(|| XmlError(format!("Signature has no {}", local)))?;
+    let algorithm = element
+        .attribute("Algorithm")
+        .ok_or_else(|| XmlError(format!("{} has no Algorithm", local)))?;
+    Ok((element, algorithm))
+}
+
+/// `PrefixList` of an exclusive canonicalization's `InclusiveNamespaces`
+fn inclusive_prefixes(method: &Element) -> Vec<String> {
+    method
+        .child(EXC_C14N, "InclusiveNamespaces")
+        .and_then(|element| element.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn base64_text(element: &Element) -> Result<Vec<u8>, XmlError> {
+    let text: String = element.text().split_whitespace().collect();
+    STANDARD
+        .decode(text)
+        .map_err(|_| XmlError(format!("{} is not valid base64", element.local)))
+}
+
+/// Checks the enveloped signature that is a direct child of `signed`: it
+/// must reference `signed` itself by ID, so whatever is read from `signed`
+/// afterwards is what was signed, and no other element in it may share an
+/// ID. Only exclusive canonicalization with RSA-SHA256 and SHA-256 digests
+/// is accepted.
+pub fn verify(signed: &Element, key: &RsaPublicKey) -> Result<(), XmlError> {
+    let signature = signed
+        .child(DSIG_NS, "Signature")
+        .ok_or_else(|| invalid("Not signed"))?;
+    let info = signature
+        .child(DSIG_NS, "SignedInfo")
+        .ok_or_else(|| invalid("Signature has no SignedInfo"))?;
+    let (c14n, method) = algorithm(info, "CanonicalizationMethod")?;
+    if method != EXC_C14N {
+        return Err(XmlError(format!("Unsupported canonicalization {}", method)));
+    }
+    let (_, method) = algorithm(info, "SignatureMethod")?;
+    if method != RSA_SHA256 {
+        return Err(XmlError(format!("Unsupported signature method {}", method)));
+    }
+
+    let mut references = info.children(DSIG_NS, "Reference");
+    let reference = references
+        .next()
+        .ok_or_else(|| invalid("Signature has no Reference"))?;
+    if references.next().is_some() {
+        return Err(invalid("Signature has more than one Reference"));
+    }
+    let id = signed
+        .attribute("ID")
+        .ok_or_else(|| invalid("Signed element has no ID"))?;
+    if let Some(duplicate) = signed.duplicate_id() {
+        return Err(XmlError(format!("Duplicate ID {}", duplicate)));
+    }
+    if reference.attribute("URI") != Some(&format!("#{}", id)) {
+        return Err(invalid("Signature doesn't reference the signed element"));
+    }
+    let mut inclusive = Vec::new();
+    let mut enveloped = false;
+    let transforms = reference.child(DSIG_NS, "Transforms");
+    for transform in transforms
+        .into_iter()
+        .flat_map(|transforms| transforms.children(DSIG_NS, "Transform"))
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED) if !enveloped => enveloped = true,
+            Some(EXC_C14N) if inclusive.is_empty() => inclusive = inclusive_prefixes(transform),
+            Some(EXC_C14N) => {}
+            other => {
+                return Err(XmlError(format!(
+                    "Unsupported transform {}",
+                    other.unwrap_or_default()
+                )));
+            }
+        }
+    }
+    if !enveloped {
+        return Err(invalid("Signature is not enveloped"));
+    }
+    let (_, method) = algorithm(reference, "DigestMethod")?;
+    if method != SHA256 {
+        return Err(XmlError(format!("Unsupported digest method {}", method)));
+    }
+    let digest = reference
+        .child(DSIG_NS, "DigestValue")
+        .ok_or_else(|| invalid("Reference has no DigestValue"))?;
+    let content = canonicalize(signed, Some(signature), &inclusive);
+    if Sha256::digest(content.as_bytes())[..] != base64_text(digest)?[..] {
+        return Err(invalid("Digest doesn't match"));
+    }
+
+    let value = signature
+        .child(DSIG_NS, "SignatureValue")
+        .ok_or_else(|| invalid("Signature has no SignatureValue"))?;
+    let value = Signature::try_from(base64_text(value)?.as_slice())
+        .map_err(|_| invalid("Malformed SignatureValue"))?;
+    let info = canonicalize(info, None, &inclusive_prefixes(c14n));
+    VerifyingKey::<Sha256>::new(key.clone())
+        .verify(info.as_bytes(), &value)
+        .map_err(|_| invalid("Signature doesn't match"))
+}
+
+/// Signs the element `xml` like an identity provider would, putting the
+/// signature right after `after`
+#[cfg(test)]
+pub fn sign(xml: &str, after: &str, key: &str) -> String {
+    use rsa::{RsaPrivateKey, pkcs1v15::SigningKey, pkcs8::DecodePrivateKey, signature::Signer};
+
+    use crate::saml::xml::parse;
+
+    let element = parse(xml).unwrap();
+    let id = element.attribute("ID").unwrap();
+    let digest = STANDARD.encode(Sha256::digest(canonicalize(&element, None, &[]).as_bytes()));
+    let info = format!(
+        "<ds:SignedInfo><ds:CanonicalizationMethod Algorithm=\"{EXC_C14N}\"/>\
+         <ds:SignatureMethod Algorithm=\"{RSA_SHA256}\"/><ds:Reference URI=\"#{id}\">\
+         <ds:Transforms><ds:Transform Algorithm=\"{ENVELOPED}\"/>\
+         <ds:Transform Algorithm=\"{EXC_C14N}\"/></ds:Transforms>\
+         <ds:DigestMethod Algorithm=\"{SHA256}\"/><ds:DigestValue>{digest}</ds:DigestValue>\
+         </ds:Reference></ds:SignedInfo>"
+    );
+    let unsigned = format!("<ds:Signature xmlns:ds=\"{DSIG_NS}\">{info}</ds:Signature>");
+    let unsigned = parse(&unsigned).unwrap();
+    let info_element = unsigned.child(DSIG_NS, "SignedInfo").unwrap();
+    let key = SigningKey::<Sha256>::new(RsaPrivateKey::from_pkcs8_pem(key).unwrap());
+    let value: Box<[u8]> = key
+        .sign(canonicalize(info_element, None, &[]).as_bytes())
+        .into();
+    let signature = format!(
+        "<ds:Signature xmlns:ds=\"{DSIG_NS}\">{info}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>",
+        STANDARD.encode(value)
+    );
+    xml.replacen(after, &format!("{after}{signature}"), 1)
+}
+
+#[cfg(test)]
+mod tests_signature {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    use crate::saml::{
+        signature::{DSIG_NS, certificate_key, sign, verify},
+        xml::parse,
+    };
+
+    const KEY: &str = include_str!("testdata/idp.key");
+    const CERTIFICATE: &str = include_str!("testdata/idp.crt");
+
+    #[test]
+    fn test_verify() {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = r#"<a:Doc xmlns:a="urn:a" ID="_1"><a:Issuer>idp</a:Issuer><a:Name>Jordan</a:Name></a:Doc>"#;
+        let signed = sign(xml, "</a:Issuer>", KEY);
+        verify(&parse(&signed).unwrap(), &key).unwrap();
+
+        let tampered = signed.replace("Jordan", "Admin");
+        assert!(verify(&parse(&tampered).unwrap(), &key).is_err());
+        // A signature moved onto another element doesn't reference it
+        let moved = signed.replace("ID=\"_1\"", "ID=\"_2\"");
+        assert!(verify(&parse(&moved).unwrap(), &key).is_err());
+        assert!(verify(&parse(xml).unwrap(), &key).is_err());
+    }
+
+    #[test]
+    fn test_verify_refuses_duplicate_ids() {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = r#"<a:Doc xmlns:a="urn:a" ID="_1"><a:Issuer>idp</a:Issuer><a:Name ID="_1">Jordan</a:Name></a:Doc>"#;
+        let signed = sign(xml, "</a:Issuer>", KEY);
+        let error = verify(&parse(&signed).unwrap(), &key).unwrap_err();
+        assert_eq!(error.0, "Duplicate ID _1");
+        // Nor can an element carry two IDs
+        let twice = signed.replacen("ID=\"_1\"", "ID=\"_1\" ID=\"_2\"", 1);
+        assert!(parse(&twice).is_err());
+    }
+
+    #[test]
+    fn test_verify_namespace_redefinition() {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = r#"<a:Doc xmlns:a="urn:a" ID="_1"><a:Issuer>idp</a:Issuer><a:Name>Jordan</a:Name></a:Doc>"#;
+        let signed = sign(xml, "</a:Issuer>", KEY);
+        // Rebinding a prefix changes what the signed names mean
+        let rebound = signed.replace("<a:Name>", "<a:Name xmlns:a=\"urn:evil\">");
+        assert!(verify(&parse(&rebound).unwrap(), &key).is_err());
+        let rebound = signed.replace(
+            "<ds:SignatureValue>",
+            "<ds:SignatureValue xmlns:ds=\"urn:evil\">",
+        );
+        assert!(verify(&parse(&rebound).unwrap(), &key).is_err());
+        // Binding the default namespace over unprefixed content does too
+        let xml = r#"<Doc ID="_1"><Issuer>idp</Issuer><Name>Jordan</Name></Doc>"#;
+        let signed = sign(xml, "</Issuer>", KEY);
+        verify(&parse(&signed).unwrap(), &key).unwrap();
+        let rebound = signed.replace("<Name>", "<Name xmlns=\"urn:evil\">");
+        assert!(verify(&parse(&rebound).unwrap(), &key).is_err());
+        // A declaration nothing uses is left out of the canonical form
+        let unused = signed.replace("<Name>", "<Name xmlns:x=\"urn:x\">");
+        verify(&parse(&unused).unwrap(), &key).unwrap();
+    }
+
+    #[test]
+    fn test_verify_comments() {
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = r#"<a:Doc xmlns:a="urn:a" ID="_1"><a:Issuer>idp</a:Issuer><a:Name>admin@corp.example.com.evil.com</a:Name></a:Doc>"#;
+        let signed = sign(xml, "</a:Issuer>", KEY);
+        // Comments aren't signed, but can't cut the text they sit in short
+        let commented = signed.replace(".evil", "<!---->.evil");
+        let doc = parse(&commented).unwrap();
+        verify(&doc, &key).unwrap();
+        assert_eq!(
+            doc.child("urn:a", "Name").unwrap().text(),
+            "admin@corp.example.com.evil.com"
+        );
+        let swapped = signed.replace(".evil.com", "<!-- .evil.com -->");
+        assert!(verify(&parse(&swapped).unwrap(), &key).is_err());
+        // Nor hide a signature
+        let hidden = signed.replace("<ds:Signature ", "<!--<ds:Signature ");
+        let hidden = hidden.replace("</ds:Signature>", "</ds:Signature>-->");
+        assert!(
+            parse(&hidden)
+                .unwrap()
+                .child(DSIG_NS, "Signature")
+                .is_none()
+        );
+        assert!(verify(&parse(&hidden).unwrap(), &key).is_err());
+    }
+
+    #[test]
+    fn test_malformed_der() {
+        let body: String = CERTIFICATE
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let der = STANDARD.decode(body).unwrap();
+        for len in 0..der.len() {
+            let truncated = STANDARD.encode(&der[..len]);
+            assert!(certificate_key(&truncated).is_err(), "{} bytes", len);
+        }
+        let garbled = [
+            &[0x30, 0x80, 0x00, 0x00][..],
+            &[0x30, 0x84, 0xff, 0xff, 0xff, 0xff],
+            &[
+                0x30, 0x89, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09,
+            ],
+            &[0x30, 0x03, 0x30, 0x05, 0x02],
+            &[0x30, 0x02, 0xa0, 0x00],
+        ];
+        for der in garbled {
+            assert!(certificate_key(&STANDARD.encode(der)).is_err());
+        }
+        // Any single corrupted byte is refused or still yields some key, never panics
+        for index in 0..der.len() {
+            let mut garbled = der.clone();
+            garbled[index] ^= 0xff;
+            let _ = certificate_key(&STANDARD.encode(&garbled));
+        }
+        assert!(certificate_key("not base64!").is_err());
+
+        let key = certificate_key(CERTIFICATE).unwrap();
+        let xml = r#"<a:Doc xmlns:a="urn:a" ID="_1"><a:Issuer>idp</a:Issuer></a:Doc>"#;
+        let signed = sign(xml, "</a:Issuer>", KEY);
+        let start = signed.find("<ds:SignatureValue>").unwrap() + "<ds:SignatureValue>".len();
+        let end = signed.find("</ds:SignatureValue>").unwrap();
+        let value = STANDARD.decode(&signed[start..end]).unwrap();
+        for replacement in [
+            STANDARD.encode(&value[..value.len() - 1]),
+            STANDARD.encode([]),
+            "@@@".to_string(),
+        ] {
+            let garbled = format!("{}{}{}", &signed[..start], replacement, &signed[end..]);
+            assert!(verify(&parse(&garbled).unwrap(), &key).is_err());
+        }
+    }
+}
@@ -0,0 +1,228 @@
+// Signs the identity provider fixtures next to this file with the JDK's
+// XML-DSig implementation (JSR 105, the Apache Santuario code Java identity
+// providers such as Keycloak sign with), so the tests check our verifier
+// against signatures it didn't make itself. The templates follow what
+// Keycloak and Okta send: a default namespace redeclared on the assertion,
+// `dsig`/`ds` prefixes, pretty-printed content and an `InclusiveNamespaces`
+// prefix list for a namespace only used in `xsi:type`.
+//
+// Run from this directory with `java SignFixtures.java`. Every run makes
+// new signatures, which is fine: only that they verify is checked.
+
+import java.io.ByteArrayInputStream;
+import java.io.StringWriter;
+import java.nio.charset.StandardCharsets;
+import java.nio.file.Files;
+import java.nio.file.Path;
+import java.security.KeyFactory;
+import java.security.PrivateKey;
+import java.security.cert.CertificateFactory;
+import java.security.cert.X509Certificate;
+import java.security.spec.PKCS8EncodedKeySpec;
+import java.util.Base64;
+import java.util.List;
+import java.util.regex.Matcher;
+import java.util.regex.Pattern;
+
+import javax.xml.crypto.dsig.CanonicalizationMethod;
+import javax.xml.crypto.dsig.DigestMethod;
+import javax.xml.crypto.dsig.Reference;
+import javax.xml.crypto.dsig.SignatureMethod;
+import javax.xml.crypto.dsig.SignedInfo;
+import javax.xml.crypto.dsig.Transform;
+import javax.xml.crypto.dsig.XMLSignature;
+import javax.xml.crypto.dsig.XMLSignatureFactory;
+import javax.xml.crypto.dsig.dom.DOMSignContext;
+import javax.xml.crypto.dsig.dom.DOMValidateContext;
+import javax.xml.crypto.dsig.keyinfo.KeyInfo;
+import javax.xml.crypto.dsig.keyinfo.KeyInfoFactory;
+import javax.xml.crypto.dsig.spec.C14NMethodParameterSpec;
+import javax.xml.crypto.dsig.spec.ExcC14NParameterSpec;
+import javax.xml.crypto.dsig.spec.TransformParameterSpec;
+import javax.xml.parsers.DocumentBuilderFactory;
+import javax.xml.transform.OutputKeys;
+import javax.xml.transform.TransformerFactory;
+import javax.xml.transform.dom.DOMSource;
+import javax.xml.transform.stream.StreamResult;
+
+import org.w3c.dom.Document;
+import org.w3c.dom.Element;
+import org.w3c.dom.Node;
+import org.w3c.dom.NodeList;
+
+public class SignFixtures {
+    static final String DSIG = XMLSignature.XMLNS;
+    static final String ASSERTION = "urn:oasis:names:tc:SAML:2.0:assertion";
+    static final String PROTOCOL = "urn:oasis:names:tc:SAML:2.0:protocol";
+    static final String ACS = "https://api.example.com/api/v1/auth/saml/acs";
+    static final String AUDIENCE = "https://api.example.com/saml";
+
+    static final String KEYCLOAK = "<samlp:Response xmlns:samlp=\"" + PROTOCOL + "\" xmlns:saml=\"" + ASSERTION + "\" "
+        + "Destination=\"" + ACS + "\" ID=\"ID_0b6f5e8a-5a4f-4c8e-9d3e-2f0c6b7a9e11\" InResponseTo=\"_kc1\" "
+        + "IssueInstant=\"2026-10-15T09:00:00.120Z\" Version=\"2.0\">"
+        + "<saml:Issuer>https://keycloak.example.com/realms/corp</saml:Issuer>"
+        + "<samlp:Status><samlp:StatusCode Value=\"urn:oasis:names:tc:SAML:2.0:status:Success\"/></samlp:Status>"
+        + "<saml:Assertion xmlns=\"" + ASSERTION + "\" ID=\"ID_5e1c2d44-8b1a-4f7e-a6d2-93c0e1f4b7aa\" "
+        + "IssueInstant=\"2026-10-15T09:00:00.120Z\" Version=\"2.0\">"
+        + "<saml:Issuer>https://keycloak.example.com/realms/corp</saml:Issuer>"
+        + "<saml:Subject><saml:NameID Format=\"urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress\">"
+        + "jordan.keycloak@corp.example.com</saml:NameID>"
+        + "<saml:SubjectConfirmation Method=\"urn:oasis:names:tc:SAML:2.0:cm:bearer\">"
+        + "<saml:SubjectConfirmationData InResponseTo=\"_kc1\" NotOnOrAfter=\"2026-10-15T09:05:00.120Z\" "
+        + "Recipient=\"" + ACS + "\"/></saml:SubjectConfirmation></saml:Subject>"
+        + "<saml:Conditions NotBefore=\"2026-10-15T08:59:58.120Z\" NotOnOrAfter=\"2026-10-15T09:01:00.120Z\">"
+        + "<saml:AudienceRestriction><saml:Audience>" + AUDIENCE + "</saml:Audience></saml:AudienceRestriction>"
+        + "</saml:Conditions>"
+        + "<saml:AuthnStatement AuthnInstant=\"2026-10-15T09:00:00.120Z\" "
+        + "SessionIndex=\"6f1c9a2e-1d3b-4c5a-8e7f-0a1b2c3d4e5f::a1b2c3d4\"><saml:AuthnContext>"
+        + "<saml:AuthnContextClassRef>urn:oasis:names:tc:SAML:2.0:ac:classes:unspecified</saml:AuthnContextClassRef>"
+        + "</saml:AuthnContext></saml:AuthnStatement>"
+        + "<saml:AttributeStatement><saml:Attribute FriendlyName=\"email\" Name=\"email\" "
+        + "NameFormat=\"urn:oasis:names:tc:SAML:2.0:attrname-format:basic\">"
+        + "<saml:AttributeValue xmlns:xs=\"http://www.w3.org/2001/XMLSchema\" "
+        + "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:type=\"xs:string\">"
+        + "jordan.keycloak@corp.example.com</saml:AttributeValue></saml:Attribute></saml:AttributeStatement>"
+        + "</saml:Assertion></samlp:Response>";
+
+    static final String OKTA = String.join("\n",
+        "<saml2p:Response xmlns:saml2p=\"" + PROTOCOL + "\" Destination=\"" + ACS + "\" "
+            + "ID=\"id28972646553180221380530829\" InResponseTo=\"_okta1\" "
+            + "IssueInstant=\"2026-10-15T09:00:00.350Z\" Version=\"2.0\" xmlns:xs=\"http://www.w3.org/2001/XMLSchema\">",
+        "    <saml2:Issuer xmlns:saml2=\"" + ASSERTION + "\" "
+            + "Format=\"urn:oasis:names:tc:SAML:2.0:nameid-format:entity\">http://www.okta.com/exk1fxgw9kcRUbXYZ5d7</saml2:Issuer>",
+        "    <saml2p:Status xmlns:saml2p=\"" + PROTOCOL + "\">",
+        "        <saml2p:StatusCode Value=\"urn:oasis:names:tc:SAML:2.0:status:Success\"/>",
+        "    </saml2p:Status>",
+        "    <saml2:Assertion xmlns:saml2=\"" + ASSERTION + "\" ID=\"id2897264655391651735594318\" "
+            + "IssueInstant=\"2026-10-15T09:00:00.350Z\" Version=\"2.0\" xmlns:xs=\"http://www.w3.org/2001/XMLSchema\">",
+        "        <saml2:Issuer Format=\"urn:oasis:names:tc:SAML:2.0:nameid-format:entity\" "
+            + "xmlns:saml2=\"" + ASSERTION + "\">http://www.okta.com/exk1fxgw9kcRUbXYZ5d7</saml2:Issuer>",
+        "        <saml2:Subject xmlns:saml2=\"" + ASSERTION + "\">",
+        "            <saml2:NameID Format=\"urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress\">jordan.okta@corp.example.com</saml2:NameID>",
+        "            <saml2:SubjectConfirmation Method=\"urn:oasis:names:tc:SAML:2.0:cm:bearer\">",
+        "                <saml2:SubjectConfirmationData InResponseTo=\"_okta1\" "
+            + "NotOnOrAfter=\"2026-10-15T09:05:00.350Z\" Recipient=\"" + ACS + "\"/>",
+        "            </saml2:SubjectConfirmation>",
+        "        </saml2:Subject>",
+        "        <saml2:Conditions NotBefore=\"2026-10-15T08:55:00.350Z\" "
+            + "NotOnOrAfter=\"2026-10-15T09:05:00.350Z\" xmlns:saml2=\"" + ASSERTION + "\">",
+        "            <saml2:AudienceRestriction>",
+        "                <saml2:Audience>" + AUDIENCE + "</saml2:Audience>",
+        "            </saml2:AudienceRestriction>",
+        "        </saml2:Conditions>",
+        "        <saml2:AuthnStatement AuthnInstant=\"2026-10-15T09:00:00.350Z\" "
+            + "SessionIndex=\"_okta1\" xmlns:saml2=\"" + ASSERTION + "\">",
+        "            <saml2:AuthnContext>",
+        "                <saml2:AuthnContextClassRef>urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport</saml2:AuthnContextClassRef>",
+        "            </saml2:AuthnContext>",
+        "        </saml2:AuthnStatement>",
+        "        <saml2:AttributeStatement xmlns:saml2=\"" + ASSERTION + "\">",
+        "            <saml2:Attribute Name=\"username\" "
+            + "NameFormat=\"urn:oasis:names:tc:SAML:2.0:attrname-format:unspecified\">",
+        "                <saml2:AttributeValue xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" "
+            + "xsi:type=\"xs:string\">jordan.okta</saml2:AttributeValue>",
+        "            </saml2:Attribute>",
+        "        </saml2:AttributeStatement>",
+        "    </saml2:Assertion>",
+        "</saml2p:Response>");
+
+    static PrivateKey key;
+    static X509Certificate certificate;
+
+    public static void main(String[] args) throws Exception {
+        String pem = Files.readString(Path.of("idp.key"))
+            .replaceAll("-----[^-]+-----", "").replaceAll("\\s", "");
+        key = KeyFactory.getInstance("RSA")
+            .generatePrivate(new PKCS8EncodedKeySpec(Base64.getDecoder().decode(pem)));
+        certificate = (X509Certificate) CertificateFactory.getInstance("X.509")
+            .generateCertificate(Files.newInputStream(Path.of("idp.crt")));
+
+        // Keycloak signs the assertion and then the response around it
+        Document keycloak = parse(KEYCLOAK);
+        String inner = sign(assertion(keycloak), "dsig", List.of());
+        String outer = sign(keycloak.getDocumentElement(), "dsig", List.of());
+        write(splice(splice(KEYCLOAK, 1, inner), 0, outer), "keycloak_response.xml");
+
+        // Okta only signs the assertion, and keeps `xs` for `xsi:type`
+        Document okta = parse(OKTA);
+        String signature = sign(assertion(okta), "ds", List.of("xs"));
+        write("<?xml version=\"1.0\" encoding=\"UTF-8\"?>" + splice(OKTA, 1, signature), "okta_response.xml");
+    }
+
+    static Document parse(String xml) throws Exception {
+        DocumentBuilderFactory factory = DocumentBuilderFactory.newInstance();
+        factory.setNamespaceAware(true);
+        Document document = factory.newDocumentBuilder()
+            .parse(new ByteArrayInputStream(xml.getBytes(StandardCharsets.UTF_8)));
+        register(document.getDocumentElement());
+        return document;
+    }
+
+    /// Makes every `ID` attribute resolvable as a reference target
+    static void register(Element element) {
+        if (element.hasAttributeNS(null, "ID")) {
+            element.setIdAttributeNS(null, "ID", true);
+        }
+        NodeList children = element.getChildNodes();
+        for (int i = 0; i < children.getLength(); i++) {
+            if (children.item(i) instanceof Element child) {
+                register(child);
+            }
+        }
+    }
+
+    static Element assertion(Document document) {
+        return (Element) document.getElementsByTagNameNS(ASSERTION, "Assertion").item(0);
+    }
+
+    /// Puts an enveloped signature of `element` right after its Issuer and
+    /// returns it serialized
+    static String sign(Element element, String prefix, List<String> inclusive) throws Exception {
+        XMLSignatureFactory factory = XMLSignatureFactory.getInstance("DOM");
+        Transform enveloped = factory.newTransform(Transform.ENVELOPED, (TransformParameterSpec) null);
+        Transform exclusive = factory.newTransform(CanonicalizationMethod.EXCLUSIVE,
+            inclusive.isEmpty() ? null : new ExcC14NParameterSpec(inclusive));
+        Reference reference = factory.newReference("#" + element.getAttribute("ID"),
+            factory.newDigestMethod(DigestMethod.SHA256, null), List.of(enveloped, exclusive), null, null);
+        SignedInfo info = factory.newSignedInfo(
+            factory.newCanonicalizationMethod(CanonicalizationMethod.EXCLUSIVE, (C14NMethodParameterSpec) null),
+            factory.newSignatureMethod(SignatureMethod.RSA_SHA256, null), List.of(reference));
+        KeyInfoFactory keys = factory.getKeyInfoFactory();
+        KeyInfo keyInfo = keys.newKeyInfo(List.of(keys.newX509Data(List.of(certificate))));
+
+        Node issuer = element.getElementsByTagNameNS(ASSERTION, "Issuer").item(0);
+        DOMSignContext context = new DOMSignContext(key, element, issuer.getNextSibling());
+        context.setDefaultNamespacePrefix(prefix);
+        factory.newXMLSignature(info, keyInfo).sign(context);
+
+        var transformer = TransformerFactory.newInstance().newTransformer();
+        transformer.setOutputProperty(OutputKeys.OMIT_XML_DECLARATION, "yes");
+        StringWriter out = new StringWriter();
+        transformer.transform(new DOMSource(issuer.getNextSibling()), new StreamResult(out));
+        return out.toString();
+    }
+
+    /// Inserts `signature` after the `index`th Issuer of the template. Writing
+    /// the whole document out would drop the redundant namespace declarations
+    /// the identity providers send, so the template text is kept as it is
+    static String splice(String xml, int index, String signature) {
+        Matcher issuer = Pattern.compile("</\\w+:Issuer>").matcher(xml);
+        for (int i = 0; i <= index; i++) {
+            issuer.find();
+        }
+        return xml.substring(0, issuer.end()) + signature + xml.substring(issuer.end());
+    }
+
+    static void write(String xml, String name) throws Exception {
+        // What was written must still verify with the JDK before it's a fixture
+        Document written = parse(xml);
+        NodeList signatures = written.getElementsByTagNameNS(DSIG, "Signature");
+        for (int i = 0; i < signatures.getLength(); i++) {
+            DOMValidateContext context = new DOMValidateContext(certificate.getPublicKey(), signatures.item(i));
+            if (!XMLSignatureFactory.getInstance("DOM").unmarshalXMLSignature(context).validate(context)) {
+                throw new IllegalStateException(name + ": signature " + i + " doesn't verify");
+            }
+        }
+        Files.writeString(Path.of(name), xml + "\n");
+    }
+}
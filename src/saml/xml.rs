@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+
+use quick_xml::{
+    XmlVersion,
+    escape::resolve_predefined_entity,
+    events::{BytesStart, Event},
+    name::QName,
+    reader::Reader,
+};
+
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// A parsed element with its namespaces resolved. Comments are dropped, and
+/// documents with a DTD or processing instructions are refused outright.
+#[derive(Debug, Clone)]
+pub struct Element {
+    /// Qualified name as written, e.g. `saml:Assertion`
+    pub name: String,
+    pub ns: String,
+    pub local: String,
+    /// Without the `xmlns` declarations
+    pub attributes: Vec<Attribute>,
+    pub children: Vec<Node>,
+    /// Every binding in scope here, `""` being the default namespace
+    scope: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub name: String,
+    pub ns: String,
+    pub local: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    pub fn attribute(&self, local: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.ns.is_empty() && attr.local == local)
+            .map(|attr| attr.value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// Child elements named `local` in namespace `ns`
+    pub fn children<'a>(
+        &'a self,
+        ns: &'a str,
+        local: &'a str,
+    ) -> impl Iterator<Item = &'a Element> {
+        self.elements()
+            .filter(move |element| element.ns == ns && element.local == local)
+    }
+
+    pub fn child(&self, ns: &str, local: &str) -> Option<&Element> {
+        self.elements()
+            .find(|element| element.ns == ns && element.local == local)
+    }
+
+    /// The first `ID` attribute value that occurs twice in this subtree.
+    /// XML-DSig references resolve by ID, so a repeated one is ambiguous.
+    pub fn duplicate_id(&self) -> Option<&str> {
+        let mut seen = std::collections::HashSet::new();
+        let mut pending = vec![self];
+        while let Some(element) = pending.pop() {
+            if let Some(id) = element.attribute("ID")
+                && !seen.insert(id)
+            {
+                return Some(id);
+            }
+            pending.extend(element.elements());
+        }
+        None
+    }
+
+    /// Text content, child elements included
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            match node {
+                Node::Text(value) => text.push_str(value),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+}
+
+#[derive(Debug)]
+pub struct XmlError(pub String);
+
+impl std::fmt::Display for XmlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<quick_xml::Error> for XmlError {
+    fn from(error: quick_xml::Error) -> Self {
+        XmlError(error.to_string())
+    }
+}
+
+fn split(name: &str) -> (&str, &str) {
+    name.split_once(':').unwrap_or(("", name))
+}
+
+fn open(start: &BytesStart, parent: &BTreeMap<String, String>) -> Result<Element, XmlError> {
+    let name = String::from_utf8_lossy(start.name().as_ref()).into_owned();
+    let mut scope = parent.clone();
+    let mut raw = Vec::new();
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| XmlError(e.to_string()))?;
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let value = attr.normalized_value(XmlVersion::Implicit1_0)?.into_owned();
+        match QName(key.as_bytes()).as_namespace_binding() {
+            Some(_) => {
+                let prefix = key.strip_prefix("xmlns").unwrap_or_default();
+                scope.insert(prefix.trim_start_matches(':').to_string(), value);
+            }
+            None => raw.push((key, value)),
+        }
+    }
+    let resolve = |prefix: &str, default: bool| -> Result<String, XmlError> {
+        match prefix {
+            "" if !default => Ok(String::new()),
+            "xml" => Ok(XML_NS.to_string()),
+            _ => scope
+                .get(prefix)
+                .cloned()
+                .or_else(|| prefix.is_empty().then(String::new))
+                .ok_or_else(|| XmlError(format!("Unbound namespace prefix {}", prefix))),
+        }
+    };
+    let (prefix, local) = split(&name);
+    let ns = resolve(prefix, true)?;
+    let local = local.to_string();
+    let mut attributes = Vec::new();
+    for (key, value) in raw {
+        let (prefix, local) = split(&key);
+        attributes.push(Attribute {
+            ns: resolve(prefix, false)?,
+            local: local.to_string(),
+            name: key.clone(),
+            value,
+        });
+    }
+    Ok(Element {
+        name,
+        ns,
+        local,
+        attributes,
+        children: Vec::new(),
+        scope,
+    })
+}
+
+/// Parses a whole document and returns its root element
+pub fn parse(xml: &str) -> Result<Element, XmlError> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    let empty = BTreeMap::new();
+    loop {
+        let event = reader.read_event()?;
+        let parent_scope = stack.last().map(|element| &element.scope).unwrap_or(&empty);
+        let finished = match event {
+            Event::Start(start) => {
+                stack.push(open(&start, parent_scope)?);
+                None
+            }
+            Event::Empty(start) => Some(open(&start, parent_scope)?),
+            Event::End(_) => stack.pop(),
+            Event::Text(text) => {
+                let text = text.xml10_content().map_err(|e| XmlError(e.to_string()))?;
+                push_text(&mut stack, &text)?;
+                None
+            }
+            Event::CData(data) => {
+                let data = data.decode().map_err(|e| XmlError(e.to_string()))?;
+                push_text(&mut stack, &data)?;
+                None
+            }
+            Event::GeneralRef(reference) => {
+                let text = match reference.resolve_char_ref()? {
+                    Some(ch) => ch.to_string(),
+                    None => {
+                        let name = reference.decode().map_err(|e| XmlError(e.to_string()))?;
+                        resolve_predefined_entity(&name)
+                            .ok_or_else(|| XmlError(format!("Unknown entity {}", name)))?
+                            .to_string()
+                    }
+                };
+                push_text(&mut stack, &text)?;
+                None
+            }
+            Event::DocType(_) => return Err(XmlError("DTDs are not allowed".to_string())),
+            Event::PI(_) => {
+                return Err(XmlError(
+                    "Processing instructions are not allowed".to_string(),
+                ));
+            }
+            Event::Comment(_) | Event::Decl(_) => None,
+            Event::Eof => break,
+        };
+        if let Some(element) = finished {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(Node::Element(element)),
+                None if root.is_none() => root = Some(element),
+                None => return Err(XmlError("More than one root element".to_string())),
+            }
+        }
+    }
+    match (root, stack.is_empty()) {
+        (Some(root), true) => Ok(root),
+        _ => Err(XmlError("Incomplete document".to_string())),
+    }
+}
+
+fn push_text(stack: &mut [Element], text: &str) -> Result<(), XmlError> {
+    let Some(element) = stack.last_mut() else {
+        return match text.trim().is_empty() {
+            true => Ok(()),
+            false => Err(XmlError("Text outside the root element".to_string())),
+        };
+    };
+    match element.children.last_mut() {
+        Some(Node::Text(previous)) => previous.push_str(text),
+        _ => element.children.push(Node::Text(text.to_string())),
+    }
+    Ok(())
+}
+
+/// Exclusive XML Canonicalization 1.0 without comments of the subtree at
+/// `apex`, leaving out `exclude` (the enveloped signature). `inclusive`
+/// is the `InclusiveNamespaces` prefix list, `#default` for the default
+/// namespace.
+pub fn canonicalize(apex: &Element, exclude: Option<&Element>, inclusive: &[String]) -> String {
+    let mut out = String::new();
+    write_element(apex, exclude, inclusive, &BTreeMap::new(), &mut out);
+    out
+}
+
+fn write_element(
+    element: &Element,
+    exclude: Option<&Element>,
+    inclusive: &[String],
+    rendered: &BTreeMap<String, String>,
+    out: &mut String,
+) {
+    // Only the namespaces this element's own names use, unless listed as inclusive
+    let mut used: Vec<&str> = vec![split(&element.name).0];
+    for attr in &element.attributes {
+        let prefix = split(&attr.name).0;
+        if !prefix.is_empty() {
+            used.push(prefix);
+        }
+    }
+    for prefix in inclusive {
+        let prefix = match prefix.as_str() {
+            "#default" => "",
+            prefix => prefix,
+        };
+        if element.scope.contains_key(prefix) {
+            used.push(prefix);
+        }
+    }
+    used.sort();
+    used.dedup();
+
+    let mut rendered = rendered.clone();
+    let mut declarations = Vec::new();
+    for prefix in used {
+        if prefix == "xml" {
+            continue;
+        }
+        let uri = element.scope.get(prefix).map(String::as_str).unwrap_or("");
+        let current = rendered.get(prefix).map(String::as_str).unwrap_or("");
+        // An unset default namespace only needs undeclaring when an ancestor set one
+        let needed = match rendered.contains_key(prefix) {
+            true => current != uri,
+            false => !(prefix.is_empty() && uri.is_empty()),
+        };
+        if needed {
+            declarations.push((prefix, uri));
+            rendered.insert(prefix.to_string(), uri.to_string());
+        }
+    }
+
+    out.push('<');
+    out.push_str(&element.name);
+    for (prefix, uri) in declarations {
+        match prefix {
+            "" => out.push_str(" xmlns=\""),
+            prefix => {
+                out.push_str(" xmlns:");
+                out.push_str(prefix);
+                out.push_str("=\"");
+            }
+        }
+        escape_attribute(uri, out);
+        out.push('"');
+    }
+    let mut attributes: Vec<&Attribute> = element.attributes.iter().collect();
+    attributes.sort_by(|a, b| (&a.ns, &a.local).cmp(&(&b.ns, &b.local)));
+    for attr in attributes {
+        out.push(' ');
+        out.push_str(&attr.name);
+        out.push_str("=\"");
+        escape_attribute(&attr.value, out);
+        out.push('"');
+    }
+    out.push('>');
+    for node in &element.children {
+        match node {
+            Node::Text(text) => escape_text(text, out),
+            Node::Element(child) if exclude.is_some_and(|e| std::ptr::eq(e, child)) => {}
+            Node::Element(child) => write_element(child, exclude, inclusive, &rendered, out),
+        }
+    }
+    out.push_str("</");
+    out.push_str(&element.name);
+    out.push('>');
+}
+
+fn escape_text(text: &str, out: &mut String) {
+    for ch in text.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            ch => out.push(ch),
+        }
+    }
+}
+
+fn escape_attribute(value: &str, out: &mut String) {
+    for ch in value.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            ch => out.push(ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_xml {
+    use crate::saml::xml::{canonicalize, parse};
+
+    #[test]
+    fn test_exclusive_canonicalization() {
+        // Unused namespaces are dropped, used ones declared where first needed,
+        // attributes sorted and empty elements expanded
+        let doc = parse(
+            r#"<?xml version="1.0"?>
+<a:Root xmlns:a="urn:a" xmlns:b="urn:b" xmlns:unused="urn:u" z="1" b:y="2" a:x='3'><!-- gone -->
+  <a:Child b:k="&lt;&#x9;"/><Plain xmlns="urn:d">x &amp; y</Plain></a:Root>"#,
+        )
+        .unwrap();
+        assert_eq!(doc.local, "Root");
+        assert_eq!(doc.ns, "urn:a");
+        assert_eq!(
+            canonicalize(&doc, None, &[]),
+            "<a:Root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" z=\"1\" a:x=\"3\" b:y=\"2\">\n  \
+             <a:Child b:k=\"&lt;&#x9;\"></a:Child><Plain xmlns=\"urn:d\">x &amp; y</Plain></a:Root>"
+        );
+
+        let child = doc.elements().next().unwrap();
+        assert_eq!(
+            canonicalize(child, None, &[]),
+            "<a:Child xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" b:k=\"&lt;&#x9;\"></a:Child>"
+        );
+        assert_eq!(
+            canonicalize(child, None, &["unused".to_string()]),
+            "<a:Child xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" xmlns:unused=\"urn:u\" b:k=\"&lt;&#x9;\"></a:Child>"
+        );
+        assert_eq!(
+            canonicalize(&doc, Some(child), &[]),
+            "<a:Root xmlns:a=\"urn:a\" xmlns:b=\"urn:b\" z=\"1\" a:x=\"3\" b:y=\"2\">\n  \
+             <Plain xmlns=\"urn:d\">x &amp; y</Plain></a:Root>"
+        );
+    }
+
+    #[test]
+    fn test_redefined_prefixes_and_comments() {
+        let doc = parse(
+            r#"<a:Root xmlns:a="urn:a"><a:Child xmlns:a="urn:b">one<!-- x -->two</a:Child><a:Child>three</a:Child></a:Root>"#,
+        )
+        .unwrap();
+        let children: Vec<_> = doc.elements().collect();
+        assert_eq!(children[0].ns, "urn:b");
+        assert_eq!(children[1].ns, "urn:a");
+        // Text around a comment stays one node
+        assert_eq!(children[0].text(), "onetwo");
+        assert_eq!(
+            canonicalize(&doc, None, &[]),
+            "<a:Root xmlns:a=\"urn:a\"><a:Child xmlns:a=\"urn:b\">onetwo</a:Child>\
+             <a:Child>three</a:Child></a:Root>"
+        );
+        assert!(parse(r#"<a:Root xmlns:a="urn:a"><b:Child/></a:Root>"#).is_err());
+        assert!(parse(r#"<Root ID="_1" ID="_2"/>"#).is_err());
+        assert_eq!(
+            parse(r#"<Root ID="_1"><a ID="_2"><b ID="_1"/></a></Root>"#)
+                .unwrap()
+                .duplicate_id(),
+            Some("_1")
+        );
+    }
+
+    #[test]
+    fn test_refuses_dtds() {
+        let doc = r#"<!DOCTYPE r [<!ENTITY e "boom">]><r>&e;</r>"#;
+        assert!(parse(doc).is_err());
+        assert!(parse("<a><b></a>").is_err());
+        assert!(parse("<a/><b/>").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use rand::{Rng, seq::IndexedRandom};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    auth::user::{NewUser, add},
+    group::handler::create,
+};
+
+/// Password of every seeded user, so they can log in during demos
+pub const SEED_PASSWORD: &str = "password123";
+
+const FIRST_NAMES: &[&str] = &[
+    "alice", "bruno", "chen", "dewi", "elena", "farid", "grace", "hiro", "ines", "jamal", "kiran",
+    "lena", "mateo", "nadia", "omar", "priya", "rafael", "sofia", "tomas", "yuki",
+];
+const LAST_NAMES: &[&str] = &[
+    "adams", "baker", "costa", "dubois", "evans", "fischer", "garcia", "haddad", "ito", "jensen",
+    "kowalski", "lopez", "martin", "nguyen", "okafor", "petrov", "rossi", "santos", "tanaka",
+    "wibowo",
+];
+const TOPICS: &[&str] = &[
+    "rust",
+    "hiking",
+    "photography",
+    "cooking",
+    "design",
+    "music",
+    "football",
+    "books",
+    "travel",
+    "gardening",
+    "startups",
+    "gaming",
+];
+const KINDS: &[&str] = &["club", "crew", "lounge", "circle", "hub", "corner"];
+
+/// `seed [--users N] [--groups N]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedOptions {
+    pub users: u32,
+    pub groups: u32,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        Self {
+            users: 50,
+            groups: 5,
+        }
+    }
+}
+
+impl SeedOptions {
+    /// Parses the arguments following `seed`
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let target = match flag.as_str() {
+                "--users" => &mut options.users,
+                "--groups" => &mut options.groups,
+                other => return Err(format!("Unknown argument {}", other)),
+            };
+            *target = args
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| format!("{} expects a number", flag))?;
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SeedReport {
+    pub users: u32,
+    pub groups: u32,
+    /// Generated names that were already taken
+    pub skipped: u32,
+}
+
+/// Inserts `options.users` users and `options.groups` groups through the same
+/// functions the API uses. Names get a random suffix, so seeding twice adds
+/// more rows instead of failing; the rare collision is skipped.
+pub async fn run(pool: &Pool<Postgres>, options: &SeedOptions) -> Result<SeedReport, sqlx::Error> {
+    let mut report = SeedReport::default();
+
+    for _ in 0..options.users {
+        let (user_name, email) = fake_user();
+        match add(
+            pool,
+            NewUser::new(user_name, email, SEED_PASSWORD.to_string()),
+        )
+        .await
+        {
+            Ok(_) => report.users += 1,
+            Err(e) if is_unique_violation(&e) => report.skipped += 1,
+            Err(e) => return Err(e),
+        }
+    }
+
+    for _ in 0..options.groups {
+        let (name, description) = fake_group();
+        match create(pool, &name, &description).await {
+            Ok(_) => report.groups += 1,
+            Err(e) if is_unique_violation(&e) => report.skipped += 1,
+            Err(e) => return Err(e),
+        }
+    }
+
+    tracing::info!(
+        users = report.users,
+        groups = report.groups,
+        skipped = report.skipped,
+        "Seeded database"
+    );
+    Ok(report)
+}
+
+fn fake_user() -> (String, String) {
+    let mut rng = rand::rng();
+    let first = FIRST_NAMES.choose(&mut rng).unwrap_or(&"user");
+    let last = LAST_NAMES.choose(&mut rng).unwrap_or(&"seed");
+    let user_name = format!("{}_{}{}", first, last, rng.random_range(100..10000));
+    let email = format!("{}@example.com", user_name.replace('_', "."));
+    (user_name, email)
+}
+
+fn fake_group() -> (String, String) {
+    let mut rng = rand::rng();
+    let topic = TOPICS.choose(&mut rng).unwrap_or(&"general");
+    let kind = KINDS.choose(&mut rng).unwrap_or(&"group");
+    let name = format!("{} {} {}", topic, kind, rng.random_range(100..10000));
+    let description = format!("A place to talk about {}", topic);
+    (name, description)
+}
+
+fn is_unique_violation(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.is_unique_violation())
+}
+
+#[cfg(test)]
+mod tests_seed {
+    use crate::{
+        app_state::test_config,
+        config::connection::ConnectionBuilder,
+        seed::{SeedOptions, fake_user, run},
+    };
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(SeedOptions::parse(&[]).unwrap(), SeedOptions::default());
+        assert_eq!(
+            SeedOptions::parse(&args(&["--users", "100", "--groups", "10"])).unwrap(),
+            SeedOptions {
+                users: 100,
+                groups: 10
+            }
+        );
+        assert!(SeedOptions::parse(&args(&["--users"])).is_err());
+        assert!(SeedOptions::parse(&args(&["--users", "many"])).is_err());
+        assert!(SeedOptions::parse(&args(&["--messages", "5"])).is_err());
+    }
+
+    #[test]
+    fn test_fake_user_is_valid() {
+        let (user_name, email) = fake_user();
+        assert!((6..=30).contains(&user_name.len()));
+        assert!(email.ends_with("@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = ConnectionBuilder(test_config()).connect().await.unwrap();
+        let report = run(
+            &pool,
+            &SeedOptions {
+                users: 2,
+                groups: 1,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.users + report.groups + report.skipped, 3);
+        pool.close().await;
+    }
+}
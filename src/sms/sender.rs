@@ -0,0 +1,167 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::config::secrets::redact_option;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsProvider {
+    /// Writes messages to the log instead of sending them
+    #[default]
+    Log,
+    /// POSTs each message as JSON to `url`, for SMS gateways with a webhook API
+    Http,
+}
+
+/// Settings from the `[sms]` section of the config file
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SmsSettings {
+    pub provider: SmsProvider,
+    /// Sender id or number passed along to the gateway
+    pub from: String,
+    pub url: Option<String>,
+    /// Sent as `Authorization: Bearer <token>` when set
+    #[serde(serialize_with = "redact_option")]
+    pub token: Option<String>,
+}
+
+impl Default for SmsSettings {
+    fn default() -> Self {
+        Self {
+            provider: SmsProvider::Log,
+            from: "ExampleAPI".to_string(),
+            url: None,
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sms {
+    pub to: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait SmsSender: Send + Sync {
+    async fn send(&self, sms: Sms) -> Result<(), String>;
+}
+
+/// Dev sender: logs each message instead of delivering it
+pub struct LogSms;
+
+#[async_trait]
+impl SmsSender for LogSms {
+    async fn send(&self, sms: Sms) -> Result<(), String> {
+        tracing::info!(to = %sms.to, body = %sms.body, "SMS (not sent)");
+        Ok(())
+    }
+}
+
+/// Body of the request `HttpSms` makes
+#[derive(Serialize)]
+struct HttpSmsRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    body: &'a str,
+}
+
+pub struct HttpSms {
+    client: reqwest::Client,
+    url: String,
+    from: String,
+    token: Option<String>,
+}
+
+impl HttpSms {
+    pub fn new(settings: &SmsSettings) -> Result<Self, String> {
+        let url = settings
+            .url
+            .clone()
+            .ok_or_else(|| "sms.url is required for the http provider".to_string())?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            url,
+            from: settings.from.clone(),
+            token: settings.token.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl SmsSender for HttpSms {
+    async fn send(&self, sms: Sms) -> Result<(), String> {
+        let mut request = self.client.post(&self.url).json(&HttpSmsRequest {
+            from: &self.from,
+            to: &sms.to,
+            body: &sms.body,
+        });
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("SMS gateway answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Builds the configured sender. A broken gateway setup is logged and
+/// replaced by the log sender so the API still starts.
+pub fn build_sms_sender(settings: &SmsSettings) -> Arc<dyn SmsSender> {
+    match settings.provider {
+        SmsProvider::Log => Arc::new(LogSms),
+        SmsProvider::Http => match HttpSms::new(settings) {
+            Ok(sender) => Arc::new(sender),
+            Err(e) => {
+                tracing::error!(error = %e, "Invalid SMS settings, falling back to log sender");
+                Arc::new(LogSms)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests_sms {
+    use crate::sms::sender::{HttpSms, LogSms, Sms, SmsProvider, SmsSender, SmsSettings};
+
+    fn sms() -> Sms {
+        Sms {
+            to: "+628123456789".to_string(),
+            body: "Hello".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_sms() {
+        assert!(LogSms.send(sms()).await.is_ok());
+    }
+
+    #[test]
+    fn test_http_requires_url() {
+        let settings = SmsSettings {
+            provider: SmsProvider::Http,
+            ..Default::default()
+        };
+        assert!(HttpSms::new(&settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_unreachable_gateway() {
+        let sender = HttpSms::new(&SmsSettings {
+            provider: SmsProvider::Http,
+            url: Some("http://127.0.0.1:9/sms".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(sender.send(sms()).await.is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::{error::Error as _, fmt, io, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    body::{Body, Bytes},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, TryStreamExt, stream::BoxStream};
+use http_body_util::LengthLimitError;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    config::secrets::{redact, redact_option},
+    storage::{local::LocalStorage, s3::S3Storage},
+};
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    /// The upload went over the route's body limit
+    TooLarge,
+    /// Keys are relative paths without `..` segments
+    InvalidKey,
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Object not found"),
+            StorageError::TooLarge => write!(f, "Upload is too large"),
+            StorageError::InvalidKey => write!(f, "Invalid object key"),
+            StorageError::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => StorageError::NotFound,
+            io::ErrorKind::FileTooLarge => StorageError::TooLarge,
+            _ => StorageError::Backend(e.to_string()),
+        }
+    }
+}
+
+/// Object storage for uploaded files. Keys look like `attachments/<id>`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Writes the stream to `key`, replacing any existing object, and returns
+    /// the number of bytes stored. Nothing is left behind when the stream fails.
+    async fn put(&self, key: &str, body: ByteStream) -> Result<u64, StorageError>;
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError>;
+    /// Deleting a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files under `dir` on the local disk
+    #[default]
+    Local,
+    /// Any S3-compatible service (AWS, MinIO, R2, ...)
+    S3,
+}
+
+/// Settings from the `[storage]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[serde(default)]
+#[validate(schema(function = "validate_backend"))]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    /// `local`: root directory for stored files
+    pub dir: String,
+    /// Body limit for upload routes
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub max_upload_bytes: usize,
+    /// How long the signed `/files/...` links handed out for attachments work
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub signed_url_ttl_secs: u64,
+    /// Key the `/files/...` links are signed with; a subkey of `jwt.key`
+    /// when unset. Changing it invalidates the links handed out so far.
+    #[serde(serialize_with = "redact")]
+    #[validate(custom(function = "validate_signing_key"))]
+    pub signing_key: String,
+    /// Bounding square of the `thumb` variant of uploaded images
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub thumbnail_px: u32,
+    /// Bounding square of the `web` variant; smaller images keep their size
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub web_px: u32,
+    /// `s3`: bucket name
+    pub bucket: Option<String>,
+    pub region: String,
+    /// `s3`: set for non-AWS services, e.g. `http://minio:9000`
+    pub endpoint: Option<String>,
+    /// `s3`: read from the `AWS_*` environment variables when unset
+    pub access_key_id: Option<String>,
+    #[serde(serialize_with = "redact_option")]
+    pub secret_access_key: Option<String>,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Local,
+            dir: "uploads".to_string(),
+            max_upload_bytes: 5 * 1024 * 1024,
+            signed_url_ttl_secs: 3600,
+            signing_key: String::new(),
+            thumbnail_px: 256,
+            web_px: 1600,
+            bucket: None,
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+        }
+    }
+}
+
+fn validate_signing_key(key: &str) -> Result<(), ValidationError> {
+    match key.is_empty() || key.len() >= 32 {
+        true => Ok(()),
+        false => Err(ValidationError::new("signing_key")
+            .with_message("must be at least 32 characters".into())),
+    }
+}
+
+fn validate_backend(settings: &StorageSettings) -> Result<(), ValidationError> {
+    if settings.backend == StorageBackend::S3 && settings.bucket.is_none() {
+        return Err(ValidationError::new("bucket")
+            .with_message("bucket is required for the s3 backend".into()));
+    }
+    Ok(())
+}
+
+/// Builds the configured backend. An S3 client that can't be built is logged
+/// and replaced by local storage so the API still starts.
+pub fn build_storage(settings: &StorageSettings) -> Arc<dyn Storage> {
+    match settings.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(&settings.dir)),
+        StorageBackend::S3 => match S3Storage::new(settings) {
+            Ok(storage) => Arc::new(storage),
+            Err(e) => {
+                tracing::error!(error = %e, "Invalid S3 settings, falling back to local storage");
+                Arc::new(LocalStorage::new(&settings.dir))
+            }
+        },
+    }
+}
+
+/// Streams a request body into storage without buffering it in memory
+pub async fn upload(storage: &dyn Storage, key: &str, body: Body) -> Result<u64, StorageError> {
+    let stream = body.into_data_stream().map_err(body_error).boxed();
+    storage.put(key, stream).await
+}
+
+/// Streams an object back as the response body
+pub async fn download(
+    storage: &dyn Storage,
+    key: &str,
+    content_type: &str,
+) -> Result<Response, StorageError> {
+    let stream = storage.get(key).await?;
+    Ok((
+        [(header::CONTENT_TYPE, content_type.to_string())],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Surfaces the body limit as `FileTooLarge` so it maps to `StorageError::TooLarge`
+fn body_error(e: axum::Error) -> io::Error {
+    let mut source = e.source();
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return io::Error::new(io::ErrorKind::FileTooLarge, e);
+        }
+        source = error.source();
+    }
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests_backend {
+    use axum::body::{Body, to_bytes};
+    use validator::Validate;
+
+    use crate::storage::{
+        backend::{StorageBackend, StorageError, StorageSettings, download, upload},
+        local::LocalStorage,
+    };
+
+    #[tokio::test]
+    async fn test_upload_and_download() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = LocalStorage::new(&dir);
+
+        let stream = futures::stream::iter(["hello ", "world"].map(Ok::<_, std::io::Error>));
+        let size = upload(&storage, "files/a.txt", Body::from_stream(stream))
+            .await
+            .unwrap();
+        assert_eq!(size, 11);
+
+        let response = download(&storage, "files/a.txt", "text/plain")
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello world");
+
+        assert!(matches!(
+            download(&storage, "files/missing.txt", "text/plain").await,
+            Err(StorageError::NotFound)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_s3_requires_bucket() {
+        let settings = StorageSettings {
+            backend: StorageBackend::S3,
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+        assert!(StorageSettings::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,1380 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, QueryBuilder, Row, postgres::PgRow};
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::is_admin,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    group::handler::Group,
+    moderation::handler::is_banned,
+    organization::handler::member_role,
+    pagination::Pagination,
+    storage::{
+        backend::{StorageError, download, upload},
+        images::{Variant, is_processable, spawn_variants, with_variants},
+        scan::{UploadScanner, Verdict},
+        signed::{signed_url, signing_key, verify},
+    },
+};
+
+/// Where an upload is in the malware scan, see `storage::scan`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus {
+    /// Not scanned yet, not served
+    Pending,
+    Clean,
+    /// Flagged; the object was moved to `quarantine/` and is never served
+    Rejected,
+}
+
+impl ScanStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ScanStatus::Pending => "pending",
+            ScanStatus::Clean => "clean",
+            ScanStatus::Rejected => "rejected",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "pending" => ScanStatus::Pending,
+            "rejected" => ScanStatus::Rejected,
+            _ => ScanStatus::Clean,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Deserialize)]
+pub struct Attachment {
+    pub attachment_id: String,
+    pub user_id: String,
+    #[serde(skip)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub size: i64,
+    pub status: ScanStatus,
+    /// Group the file was shared with, listed in its file library
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    /// RFC 3339
+    pub created_at: String,
+    /// Signed download link, see `storage::signed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Signed links to the resized copies of an image, by variant name.
+    /// They are made in the background and answer 404 until they exist.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variants: BTreeMap<String, String>,
+}
+
+impl Attachment {
+    /// Adds download links, for the file and its image variants, that
+    /// expire after `storage.signed_url_ttl_secs`
+    fn with_url(self, state: &AppState) -> Self {
+        let key = signing_key(&state.settings.storage, &state.jwt_config.secret);
+        let expires = Utc::now().timestamp() + state.settings.storage.signed_url_ttl_secs as i64;
+        let variants = match is_processable(&self.content_type) {
+            true => Variant::ALL
+                .iter()
+                .map(|variant| {
+                    let path = format!("{}/{}", self.attachment_id, variant.name());
+                    (variant.name().to_string(), signed_url(&key, &path, expires))
+                })
+                .collect(),
+            false => BTreeMap::new(),
+        };
+        Self {
+            url: Some(signed_url(&key, &self.attachment_id, expires)),
+            variants,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentResponse {
+    pub meta: MetaResponse,
+    pub data: Attachment,
+}
+
+impl IntoResponse for AttachmentResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+fn from_row(data: PgRow) -> Attachment {
+    Attachment {
+        attachment_id: data.get("attachment_id"),
+        user_id: data.get("user_id"),
+        storage_key: data.get("storage_key"),
+        content_type: data.get("content_type"),
+        size: data.get("size"),
+        status: ScanStatus::parse(data.get("status")),
+        group_id: data.get("group_id"),
+        created_at: data
+            .get::<NaiveDateTime, _>("created_at")
+            .and_utc()
+            .to_rfc3339(),
+        url: None,
+        variants: BTreeMap::new(),
+    }
+}
+
+/// Row written for a stored upload
+#[derive(Debug)]
+pub struct NewAttachment<'a> {
+    pub attachment_id: &'a str,
+    pub user_id: &'a str,
+    pub storage_key: &'a str,
+    pub content_type: &'a str,
+    pub size: i64,
+    pub status: ScanStatus,
+    pub group_id: Option<&'a str>,
+}
+
+#[tracing::instrument(name = "db.attachments.create", skip(pool))]
+pub async fn create(pool: &Pool<Postgres>, new: NewAttachment<'_>) -> Result<Attachment, Error> {
+    let sql = "insert into attachments (attachment_id, user_id, storage_key, content_type, size, status, group_id) values ($1, $2, $3, $4, $5, $6, $7) returning attachment_id, user_id, storage_key, content_type, size, status, group_id, created_at";
+    sqlx::query(sql)
+        .bind(new.attachment_id)
+        .bind(new.user_id)
+        .bind(new.storage_key)
+        .bind(new.content_type)
+        .bind(new.size)
+        .bind(new.status.as_str())
+        .bind(new.group_id)
+        .map(from_row)
+        .fetch_one(pool)
+        .await
+}
+
+#[tracing::instrument(name = "db.attachments.get_by_id", skip(pool))]
+pub async fn get_by_id(pool: &Pool<Postgres>, attachment_id: &str) -> Option<Attachment> {
+    let sql = "select attachment_id, user_id, storage_key, content_type, size, status, group_id, created_at from attachments where attachment_id = $1";
+    sqlx::query(sql)
+        .bind(attachment_id)
+        .map(from_row)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_default()
+}
+
+#[tracing::instrument(name = "db.attachments.get_avatar", skip(pool))]
+pub async fn get_avatar(pool: &Pool<Postgres>, user_id: &str) -> Option<Attachment> {
+    let sql = "select a.attachment_id, a.user_id, a.storage_key, a.content_type, a.size, a.status, a.group_id, a.created_at from users u join attachments a on a.attachment_id = u.avatar_id where u.user_id = $1";
+    sqlx::query(sql)
+        .bind(user_id)
+        .map(from_row)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_default()
+}
+
+/// Points the user's avatar at `attachment_id` and removes the previous
+/// avatar's row, returning it so its object can be deleted too.
+#[tracing::instrument(name = "db.attachments.set_avatar", skip(pool))]
+pub async fn set_avatar(
+    pool: &Pool<Postgres>,
+    user_id: &str,
+    attachment_id: &str,
+) -> Result<Option<Attachment>, Error> {
+    let mut tx = pool.begin().await?;
+    let previous = sqlx::query(
+        "select a.attachment_id, a.user_id, a.storage_key, a.content_type, a.size, a.status, a.group_id, a.created_at from users u join attachments a on a.attachment_id = u.avatar_id where u.user_id = $1 for update of u",
+    )
+    .bind(user_id)
+    .map(from_row)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    sqlx::query("update users set avatar_id = $1 where user_id = $2")
+        .bind(attachment_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    if let Some(previous) = &previous {
+        sqlx::query("delete from attachments where attachment_id = $1")
+            .bind(&previous.attachment_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(previous)
+}
+
+/// Records the scan result; a rejected upload also gets its quarantine key
+#[tracing::instrument(name = "db.attachments.set_status", skip(pool))]
+pub async fn set_status(
+    pool: &Pool<Postgres>,
+    attachment_id: &str,
+    status: ScanStatus,
+    storage_key: Option<&str>,
+) -> Result<(), Error> {
+    sqlx::query(
+        "update attachments set status = $2, storage_key = coalesce($3, storage_key) where attachment_id = $1",
+    )
+    .bind(attachment_id)
+    .bind(status.as_str())
+    .bind(storage_key)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Optional filters of a group's file library; unset ones match every file
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FileFilter {
+    /// A content type (`image/png`) or just its top-level type (`image`)
+    #[serde(rename = "type")]
+    pub content_type: Option<String>,
+    /// User id of the uploader
+    pub uploader: Option<String>,
+    /// Uploaded at or after
+    pub uploaded_after: Option<DateTime<Utc>>,
+    /// Uploaded before
+    pub uploaded_before: Option<DateTime<Utc>>,
+}
+
+/// Files shared with the group that passed the malware scan, newest first
+/// by default. The cursor is the id of the last file seen.
+#[tracing::instrument(name = "db.attachments.get_by_group", skip(pool))]
+pub async fn get_by_group(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    filter: &FileFilter,
+    pagination: &Pagination,
+) -> Result<Vec<Attachment>, Error> {
+    let mut query = QueryBuilder::new(
+        "select attachment_id, user_id, storage_key, content_type, size, status, group_id, created_at \
+        from attachments where status = 'clean' and group_id = ",
+    );
+    query.push_bind(group_id.to_string());
+    if let Some(content_type) = filter.content_type.as_deref().filter(|t| !t.is_empty()) {
+        query
+            .push(" and (content_type = ")
+            .push_bind(content_type.to_string())
+            .push(" or split_part(content_type, '/', 1) = ")
+            .push_bind(content_type.to_string())
+            .push(")");
+    }
+    if let Some(uploader) = filter.uploader.as_deref().filter(|u| !u.is_empty()) {
+        query
+            .push(" and user_id = ")
+            .push_bind(uploader.to_string());
+    }
+    if let Some(after) = filter.uploaded_after {
+        query
+            .push(" and created_at >= ")
+            .push_bind(after.naive_utc());
+    }
+    if let Some(before) = filter.uploaded_before {
+        query
+            .push(" and created_at < ")
+            .push_bind(before.naive_utc());
+    }
+    if let Some(cursor) = pagination.cursor.as_deref() {
+        query
+            .push(" and (created_at, attachment_id) ")
+            .push(pagination.sort.after())
+            .push(" (select created_at, attachment_id from attachments where attachment_id = ")
+            .push_bind(cursor.to_string())
+            .push(")");
+    }
+    query
+        .push(" order by created_at ")
+        .push(pagination.sort.sql())
+        .push(", attachment_id ")
+        .push(pagination.sort.sql())
+        .push(" limit ")
+        .push_bind(pagination.limit())
+        .push(" offset ")
+        .push_bind(pagination.offset());
+    query.build().map(from_row).fetch_all(pool).await
+}
+
+/// Storage keys of everything the user uploaded, for cleanup on account deletion
+#[tracing::instrument(name = "db.attachments.get_keys_by_user", skip(pool))]
+pub async fn get_keys_by_user(pool: &Pool<Postgres>, user_id: &str) -> Result<Vec<String>, Error> {
+    sqlx::query_scalar("select storage_key from attachments where user_id = $1")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Deletes stored objects, and any image variants of them, in the
+/// background; failures are only logged
+pub fn delete_objects(state: &AppState, keys: Vec<String>) {
+    let storage = state.storage.clone();
+    tokio::spawn(async move {
+        for key in keys.into_iter().flat_map(with_variants) {
+            if let Err(e) = storage.delete(&key).await {
+                tracing::warn!(key, error = %e, "Failed to delete stored object");
+            }
+        }
+    });
+}
+
+fn storage_error(e: StorageError) -> MetaResponse {
+    let code = match e {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        StorageError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        StorageError::InvalidKey | StorageError::Backend(_) => {
+            tracing::error!(error = %e, "Storage failure");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    MetaResponse {
+        code: code.to_i32(),
+        message: e.to_string(),
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+/// Streams the body to storage and records it as an attachment
+async fn store(
+    state: &AppState,
+    user_id: &str,
+    content_type: &str,
+    group_id: Option<&str>,
+    body: Body,
+) -> Result<Attachment, MetaResponse> {
+    let attachment_id = uuid::Uuid::new_v4().to_string();
+    let key = format!("attachments/{}", attachment_id);
+    let size = upload(state.storage.as_ref(), &key, body)
+        .await
+        .map_err(storage_error)?;
+    let status = match state.scanner {
+        Some(_) => ScanStatus::Pending,
+        None => ScanStatus::Clean,
+    };
+
+    create(
+        &state.pool,
+        NewAttachment {
+            attachment_id: &attachment_id,
+            user_id,
+            storage_key: &key,
+            content_type,
+            size: size as i64,
+            status,
+            group_id,
+        },
+    )
+    .await
+    .map_err(|e| {
+        delete_objects(state, vec![key]);
+        MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        }
+    })
+}
+
+/// Starts making the variants of an image upload, once it is kept
+fn process_image(state: &AppState, attachment: &Attachment) {
+    if is_processable(&attachment.content_type) {
+        spawn_variants(
+            state.storage.clone(),
+            attachment.storage_key.clone(),
+            state.settings.storage.clone(),
+        );
+    }
+}
+
+/// Scans a stored upload. A flagged file is copied to `quarantine/<id>`,
+/// where only an operator looks at it, and its row is marked rejected.
+async fn scan_upload(
+    state: &AppState,
+    scanner: &dyn UploadScanner,
+    attachment: &Attachment,
+) -> Result<(), String> {
+    let data = state
+        .storage
+        .get(&attachment.storage_key)
+        .await
+        .map_err(|e| e.to_string())?;
+    match scanner.scan(data).await? {
+        Verdict::Clean => {
+            set_status(
+                &state.pool,
+                &attachment.attachment_id,
+                ScanStatus::Clean,
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            process_image(state, attachment);
+        }
+        Verdict::Infected(signature) => {
+            tracing::warn!(
+                attachment_id = attachment.attachment_id,
+                user_id = attachment.user_id,
+                signature,
+                "Upload flagged by malware scan"
+            );
+            let quarantine = format!("quarantine/{}", attachment.attachment_id);
+            let data = state
+                .storage
+                .get(&attachment.storage_key)
+                .await
+                .map_err(|e| e.to_string())?;
+            state
+                .storage
+                .put(&quarantine, data)
+                .await
+                .map_err(|e| e.to_string())?;
+            set_status(
+                &state.pool,
+                &attachment.attachment_id,
+                ScanStatus::Rejected,
+                Some(&quarantine),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            delete_objects(state, vec![attachment.storage_key.clone()]);
+        }
+    }
+    Ok(())
+}
+
+/// Runs what follows a kept upload: the malware scan when one is
+/// configured, then the image variants of clean files. Scan failures leave
+/// the upload pending, so it is never served unscanned.
+fn after_upload(state: &Arc<AppState>, attachment: &Attachment) {
+    let Some(scanner) = state.scanner.clone() else {
+        process_image(state, attachment);
+        return;
+    };
+    let state = state.clone();
+    let attachment = attachment.clone();
+    tokio::spawn(async move {
+        if let Err(e) = scan_upload(&state, scanner.as_ref(), &attachment).await {
+            tracing::error!(
+                attachment_id = attachment.attachment_id,
+                error = %e,
+                "Failed to scan upload"
+            );
+        }
+    });
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadQuery {
+    /// Shares the file with this group, see `group_files_handler`
+    pub group_id: Option<String>,
+}
+
+fn group_not_found() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::NOT_FOUND.to_i32(),
+        message: "Group not found".to_string(),
+    }
+}
+
+pub async fn upload_attachment_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<AttachmentResponse, MetaResponse> {
+    if let Some(group_id) = &query.group_id
+        && state.groups.get_by_id(group_id).await.is_none()
+    {
+        return Err(group_not_found());
+    }
+    let data = store(
+        &state,
+        &user.user_id,
+        &content_type(&headers),
+        query.group_id.as_deref(),
+        body,
+    )
+    .await?
+    .with_url(&state);
+    after_upload(&state, &data);
+    Ok(AttachmentResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    })
+}
+
+fn attachment_not_found() -> MetaResponse {
+    MetaResponse {
+        code: StatusCode::NOT_FOUND.to_i32(),
+        message: "Attachment not found".to_string(),
+    }
+}
+
+/// Whether the user is let into the group: a member of its organization,
+/// if it has one, and not banned from it
+async fn group_readable(state: &AppState, user_id: &str, group: &Group) -> Result<bool, Error> {
+    if let Some(org_id) = &group.org_id
+        && member_role(&state.pool, org_id, user_id).await?.is_none()
+    {
+        return Ok(false);
+    }
+    Ok(!is_banned(&state.pool, user_id, &group.group_id).await?)
+}
+
+/// The attachment, if the user may read it: they uploaded it, it was shared
+/// with a group they are let into, or they are an admin. Others get the
+/// same 404 as for an unknown id.
+async fn readable_attachment(
+    state: &AppState,
+    user_id: &str,
+    attachment_id: &str,
+) -> Result<Attachment, MetaResponse> {
+    let attachment = get_by_id(&state.pool, attachment_id)
+        .await
+        .ok_or_else(attachment_not_found)?;
+    if attachment.user_id == user_id {
+        return Ok(attachment);
+    }
+    let internal = |e: Error| MetaResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+        message: e.to_string(),
+    };
+    if let Some(group_id) = &attachment.group_id
+        && let Some(group) = state.groups.get_by_id(group_id).await
+        && group_readable(state, user_id, &group)
+            .await
+            .map_err(internal)?
+    {
+        return Ok(attachment);
+    }
+    match is_admin(user_id, &state.pool).await.map_err(internal)? {
+        true => Ok(attachment),
+        false => Err(attachment_not_found()),
+    }
+}
+
+pub async fn download_attachment_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(attachment_id): Path<String>,
+) -> Result<Response, MetaResponse> {
+    let attachment = readable_attachment(&state, &user.user_id, &attachment_id).await?;
+    download_variant(&state, &attachment, None).await
+}
+
+/// A fresh signed link for an attachment, to share where a bearer token
+/// can't be sent along (`<img src>`, chat messages)
+pub async fn attachment_url_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(attachment_id): Path<String>,
+) -> Result<AttachmentResponse, MetaResponse> {
+    let data = readable_attachment(&state, &user.user_id, &attachment_id)
+        .await?
+        .with_url(&state);
+    Ok(AttachmentResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentsResponse {
+    pub meta: MetaResponse,
+    /// Pass as `cursor` to fetch the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    pub data: Vec<Attachment>,
+}
+
+impl IntoResponse for AttachmentsResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::OK, Json(self)).into_response()
+    }
+}
+
+/// The group's file library: attachments uploaded with its `group_id`,
+/// each with fresh signed links
+pub async fn group_files_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    Query(filter): Query<FileFilter>,
+    pagination: Pagination,
+) -> Result<AttachmentsResponse, MetaResponse> {
+    let group = state
+        .groups
+        .get_by_id(&group_id)
+        .await
+        .ok_or_else(group_not_found)?;
+    if !group_readable(&state, &user.user_id, &group)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+            message: e.to_string(),
+        })?
+    {
+        return Err(group_not_found());
+    }
+    let files = get_by_group(&state.pool, &group_id, &filter, &pagination)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    let next_cursor = pagination.next_cursor(
+        files.len(),
+        files.last().map(|file| file.attachment_id.as_str()),
+    );
+    Ok(AttachmentsResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        next_cursor,
+        data: files
+            .into_iter()
+            .map(|file| file.with_url(&state))
+            .collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedQuery {
+    pub expires: i64,
+    pub sig: String,
+}
+
+/// The stored object, or one of its image variants, as the response body.
+/// Uploads the malware scan hasn't passed are refused.
+async fn download_variant(
+    state: &AppState,
+    attachment: &Attachment,
+    variant: Option<Variant>,
+) -> Result<Response, MetaResponse> {
+    match attachment.status {
+        ScanStatus::Clean => {}
+        ScanStatus::Pending => {
+            return Err(MetaResponse {
+                code: StatusCode::CONFLICT.to_i32(),
+                message: "Attachment is still being scanned".to_string(),
+            });
+        }
+        ScanStatus::Rejected => {
+            return Err(MetaResponse {
+                code: StatusCode::GONE.to_i32(),
+                message: "Attachment was rejected by the malware scan".to_string(),
+            });
+        }
+    }
+    let (key, content_type) = match variant {
+        Some(variant) => (variant.key(&attachment.storage_key), variant.content_type()),
+        None => (
+            attachment.storage_key.clone(),
+            attachment.content_type.as_str(),
+        ),
+    };
+    download(state.storage.as_ref(), &key, content_type)
+        .await
+        .map_err(storage_error)
+}
+
+/// Checks the link, signed over `path`, and serves the attachment or its
+/// variant. Caches may keep the file until the link expires, not longer.
+async fn signed_download(
+    state: &AppState,
+    path: &str,
+    query: SignedQuery,
+    attachment_id: &str,
+    variant: Option<Variant>,
+) -> Result<Response, MetaResponse> {
+    let now = Utc::now().timestamp();
+    if !verify(
+        &signing_key(&state.settings.storage, &state.jwt_config.secret),
+        path,
+        query.expires,
+        &query.sig,
+        now,
+    ) {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Invalid or expired link".to_string(),
+        });
+    }
+    let attachment = get_by_id(&state.pool, attachment_id)
+        .await
+        .ok_or_else(attachment_not_found)?;
+    let mut response = download_variant(state, &attachment, variant).await?;
+    if let Ok(value) = HeaderValue::from_str(&format!("private, max-age={}", query.expires - now)) {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    Ok(response)
+}
+
+/// Serves `/files/{attachment_id}` to anyone holding a valid signed link
+pub async fn signed_download_handler(
+    State(state): State<Arc<AppState>>,
+    Path(attachment_id): Path<String>,
+    Query(query): Query<SignedQuery>,
+) -> Result<Response, MetaResponse> {
+    signed_download(&state, &attachment_id, query, &attachment_id, None).await
+}
+
+/// `/files/{attachment_id}/{variant}`, an image variant behind a signed link
+pub async fn signed_variant_handler(
+    State(state): State<Arc<AppState>>,
+    Path((attachment_id, variant)): Path<(String, String)>,
+    Query(query): Query<SignedQuery>,
+) -> Result<Response, MetaResponse> {
+    let path = format!("{}/{}", attachment_id, variant);
+    let variant = Variant::parse(&variant).ok_or_else(attachment_not_found)?;
+    signed_download(&state, &path, query, &attachment_id, Some(variant)).await
+}
+
+pub async fn upload_avatar_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<AttachmentResponse, MetaResponse> {
+    let content_type = content_type(&headers);
+    if !content_type.starts_with("image/") {
+        return Err(MetaResponse {
+            code: StatusCode::UNSUPPORTED_MEDIA_TYPE.to_i32(),
+            message: "Avatar must be an image".to_string(),
+        });
+    }
+
+    let data = store(&state, &user.user_id, &content_type, None, body)
+        .await?
+        .with_url(&state);
+    let previous = set_avatar(&state.pool, &user.user_id, &data.attachment_id)
+        .await
+        .map_err(|e| {
+            delete_objects(&state, vec![data.storage_key.clone()]);
+            MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: e.to_string(),
+            }
+        })?;
+    if let Some(previous) = previous {
+        delete_objects(&state, vec![previous.storage_key]);
+    }
+    after_upload(&state, &data);
+
+    Ok(AttachmentResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvatarQuery {
+    /// `thumb` or `web`, the original when absent
+    pub variant: Option<String>,
+}
+
+pub async fn download_avatar_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+    Query(query): Query<AvatarQuery>,
+) -> Result<Response, MetaResponse> {
+    let not_found = || MetaResponse {
+        code: StatusCode::NOT_FOUND.to_i32(),
+        message: "Avatar not found".to_string(),
+    };
+    let avatar = get_avatar(&state.pool, &user_id)
+        .await
+        .ok_or_else(not_found)?;
+    let variant = match query.variant.as_deref() {
+        Some(name) => Some(Variant::parse(name).ok_or_else(not_found)?),
+        None => None,
+    };
+    download_variant(&state, &avatar, variant).await
+}
+
+#[cfg(test)]
+mod tests_storage_handler {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, add, set_role},
+            util::{hash_password, random_name},
+        },
+        error::ErrorResponse,
+        routes::routes,
+        storage::{
+            backend::ByteStream,
+            handler::{ScanStatus, get_by_id, get_keys_by_user},
+            local::LocalStorage,
+            scan::{UploadScanner, Verdict},
+            signed::{signed_url, signing_key},
+        },
+    };
+
+    /// Flags any upload containing `EICAR`
+    struct MarkerScanner;
+
+    #[async_trait::async_trait]
+    impl UploadScanner for MarkerScanner {
+        async fn scan(&self, data: ByteStream) -> Result<Verdict, String> {
+            use futures::TryStreamExt;
+
+            let data = data
+                .try_fold(Vec::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(match data.windows(5).any(|w| w == b"EICAR") {
+                true => Verdict::Infected("Eicar-Test-Signature".to_string()),
+                false => Verdict::Clean,
+            })
+        }
+    }
+
+    async fn setup() -> (
+        TestServer,
+        Arc<AppState>,
+        String,
+        String,
+        std::path::PathBuf,
+    ) {
+        setup_with(None).await
+    }
+
+    async fn setup_with(
+        scanner: Option<Arc<dyn UploadScanner>>,
+    ) -> (
+        TestServer,
+        Arc<AppState>,
+        String,
+        String,
+        std::path::PathBuf,
+    ) {
+        let mut state = AppState::test().await;
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        state.storage = Arc::new(LocalStorage::new(&dir));
+        state.scanner = scanner;
+
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(&state.pool, NewUser::new(user_name, email, hash))
+            .await
+            .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+
+        let state = Arc::new(state);
+        let server = TestServer::new(routes(state.clone())).unwrap();
+        (
+            server,
+            state,
+            user.user_id,
+            format!("Bearer {}", token),
+            dir,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_attachment_round_trip() {
+        let (server, _, _, token, dir) = setup().await;
+
+        let response = server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .content_type("text/plain")
+            .bytes("hello attachment".into())
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        assert_eq!(body["data"]["size"], 16);
+        assert!(body["data"].get("storage_key").is_none());
+        let attachment_id = body["data"]["attachment_id"].as_str().unwrap();
+
+        let response = server
+            .get(&format!("/api/v1/attachments/{}", attachment_id))
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "text/plain");
+        response.assert_text("hello attachment");
+
+        let response = server
+            .get("/api/v1/attachments/unknown")
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_not_found();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signed_link() {
+        let (server, state, _, token, dir) = setup().await;
+
+        let response = server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .content_type("text/plain")
+            .bytes("signed attachment".into())
+            .await;
+        let body = response.json::<serde_json::Value>();
+        let attachment_id = body["data"]["attachment_id"].as_str().unwrap();
+        let url = body["data"]["url"].as_str().unwrap();
+        assert!(url.starts_with(&format!("/files/{}?expires=", attachment_id)));
+
+        let response = server.get(url).await;
+        response.assert_status_ok();
+        response.assert_text("signed attachment");
+        assert!(
+            response
+                .header("cache-control")
+                .to_str()
+                .unwrap()
+                .starts_with("private, max-age=")
+        );
+
+        let response = server
+            .get(&format!("/api/v1/attachments/{}/url", attachment_id))
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        let fresh = response.json::<serde_json::Value>()["data"]["url"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        server.get(&fresh).await.assert_status_ok();
+
+        let tampered = format!("{}0", url);
+        server
+            .get(&tampered)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        let key = signing_key(&state.settings.storage, &state.jwt_config.secret);
+        let expired = signed_url(&key, attachment_id, 1);
+        // Links signed with the JWT key itself are refused
+        let expires = chrono::Utc::now().timestamp() + 60;
+        server
+            .get(&signed_url(
+                state.jwt_config.secret.as_bytes(),
+                attachment_id,
+                expires,
+            ))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        let response = server.get(&expired).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Invalid or expired link"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(width, height, image::Rgb([20, 120, 220]));
+        let mut encoded = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut encoded),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        encoded
+    }
+
+    /// Variants are made in the background, so wait for them a little
+    async fn get_eventually(
+        server: &TestServer,
+        path: &str,
+        token: &str,
+    ) -> axum_test::TestResponse {
+        for _ in 0..100 {
+            let response = server.get(path).add_header("Authorization", token).await;
+            if response.status_code() != StatusCode::NOT_FOUND {
+                return response;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("{} never appeared", path);
+    }
+
+    #[tokio::test]
+    async fn test_image_variants() {
+        let (server, _, user_id, token, dir) = setup().await;
+
+        let response = server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .content_type("image/png")
+            .bytes(png(600, 300).into())
+            .await;
+        response.assert_status_ok();
+        let body = response.json::<serde_json::Value>();
+        let thumb = body["data"]["variants"]["thumb"].as_str().unwrap();
+        assert!(body["data"]["variants"]["web"].is_string());
+
+        let response = get_eventually(&server, thumb, &token).await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "image/png");
+        let image = image::load_from_memory(response.as_bytes()).unwrap();
+        assert_eq!((image.width(), image.height()), (256, 128));
+
+        let response = server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .content_type("text/plain")
+            .bytes("not an image".into())
+            .await;
+        assert!(
+            response.json::<serde_json::Value>()["data"]
+                .get("variants")
+                .is_none()
+        );
+
+        server
+            .put("/api/v1/users/avatar")
+            .add_header("Authorization", &token)
+            .content_type("image/png")
+            .bytes(png(40, 40).into())
+            .await
+            .assert_status_ok();
+        let path = format!("/api/v1/users/{}/avatar?variant=web", user_id);
+        let response = get_eventually(&server, &path, &token).await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "image/jpeg");
+        server
+            .get(&format!("/api/v1/users/{}/avatar?variant=huge", user_id))
+            .add_header("Authorization", &token)
+            .await
+            .assert_status_not_found();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_avatar_replaced() {
+        let (server, state, user_id, token, dir) = setup().await;
+
+        let response = server
+            .put("/api/v1/users/avatar")
+            .add_header("Authorization", &token)
+            .content_type("text/plain")
+            .bytes("not an image".into())
+            .await;
+        response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        for image in ["first", "second"] {
+            server
+                .put("/api/v1/users/avatar")
+                .add_header("Authorization", &token)
+                .content_type("image/png")
+                .bytes(image.into())
+                .await
+                .assert_status_ok();
+        }
+
+        let response = server
+            .get(&format!("/api/v1/users/{}/avatar", user_id))
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        assert_eq!(response.header("content-type"), "image/png");
+        response.assert_text("second");
+        assert_eq!(
+            get_keys_by_user(&state.pool, &user_id).await.unwrap().len(),
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_group_files() {
+        let (server, state, user_id, token, dir) = setup().await;
+        let group = crate::group::handler::create(&state.pool, &random_name(), "")
+            .await
+            .unwrap();
+        let upload = |group_id: &str, content_type: &'static str, content: &'static str| {
+            server
+                .post(&format!("/api/v1/attachments?group_id={}", group_id))
+                .add_header("Authorization", &token)
+                .content_type(content_type)
+                .bytes(content.into())
+        };
+
+        upload("unknown", "text/plain", "nowhere")
+            .await
+            .assert_status_not_found();
+        let mut ids = Vec::new();
+        for (content_type, content) in [
+            ("text/plain", "notes"),
+            ("image/png", "picture"),
+            ("image/jpeg", "photo"),
+        ] {
+            let response = upload(&group.group_id, content_type, content).await;
+            response.assert_status_ok();
+            let body = response.json::<serde_json::Value>();
+            assert_eq!(body["data"]["group_id"], group.group_id.as_str());
+            ids.push(body["data"]["attachment_id"].as_str().unwrap().to_string());
+        }
+        // Not shared with the group
+        server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .content_type("image/png")
+            .bytes("private".into())
+            .await
+            .assert_status_ok();
+
+        let files = |query: String| {
+            let path = format!("/api/v1/groups/{}/files?{}", group.group_id, query);
+            let request = server.get(&path).add_header("Authorization", &token);
+            async move {
+                let response = request.await;
+                response.assert_status_ok();
+                response.json::<serde_json::Value>()
+            }
+        };
+        let listed = |body: &serde_json::Value| -> Vec<String> {
+            body["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|file| file["attachment_id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let body = files("per_page=2".to_string()).await;
+        assert_eq!(listed(&body), vec![ids[2].clone(), ids[1].clone()]);
+        assert!(body["data"][0]["url"].is_string());
+        let cursor = body["next_cursor"].as_str().unwrap();
+        let body = files(format!("per_page=2&cursor={}", cursor)).await;
+        assert_eq!(listed(&body), vec![ids[0].clone()]);
+        assert!(body.get("next_cursor").is_none());
+
+        let body = files("type=image&sort=asc".to_string()).await;
+        assert_eq!(listed(&body), vec![ids[1].clone(), ids[2].clone()]);
+        let body = files("type=text/plain".to_string()).await;
+        assert_eq!(listed(&body), vec![ids[0].clone()]);
+        let body = files(format!("uploader={}", user_id)).await;
+        assert_eq!(listed(&body).len(), 3);
+        let body = files("uploader=someone-else".to_string()).await;
+        assert!(listed(&body).is_empty());
+        let body = files("uploaded_after=2999-01-01T00:00:00Z".to_string()).await;
+        assert!(listed(&body).is_empty());
+        let body = files("uploaded_before=2999-01-01T00:00:00Z".to_string()).await;
+        assert_eq!(listed(&body).len(), 3);
+
+        server
+            .get("/api/v1/groups/unknown/files")
+            .add_header("Authorization", &token)
+            .await
+            .assert_status_not_found();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_attachment_access() {
+        let (server, state, user_id, token, dir) = setup().await;
+        let other_name = random_name();
+        let other = add(
+            &state.pool,
+            NewUser::new(
+                other_name.clone(),
+                format!("{}@mail.com", other_name),
+                hash_password("123456".to_string()).unwrap(),
+            ),
+        )
+        .await
+        .unwrap();
+        let other_token = format!(
+            "Bearer {}",
+            create_access_token(&state.jwt_config, &other.user_id, &other.email).unwrap()
+        );
+        let org = crate::organization::handler::create(&state.pool, &random_name(), &user_id)
+            .await
+            .unwrap();
+        let org_group = crate::group::handler::create_by(
+            &state.pool,
+            &random_name(),
+            "",
+            Some(&org.org_id),
+            &user_id,
+        )
+        .await
+        .unwrap();
+        let open_group = crate::group::handler::create(&state.pool, &random_name(), "")
+            .await
+            .unwrap();
+        let upload = |query: String| {
+            let request = server
+                .post(&format!("/api/v1/attachments{}", query))
+                .add_header("Authorization", &token)
+                .content_type("text/plain")
+                .bytes("private notes".into());
+            async move {
+                let response = request.await;
+                response.assert_status_ok();
+                response.json::<serde_json::Value>()["data"]["attachment_id"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            }
+        };
+        let private = upload(String::new()).await;
+        let in_org = upload(format!("?group_id={}", org_group.group_id)).await;
+        let shared = upload(format!("?group_id={}", open_group.group_id)).await;
+
+        // Someone else can neither download the file nor get a link to it
+        for attachment_id in [&private, &in_org] {
+            for path in [
+                format!("/api/v1/attachments/{}", attachment_id),
+                format!("/api/v1/attachments/{}/url", attachment_id),
+            ] {
+                let response = server
+                    .get(&path)
+                    .add_header("Authorization", &other_token)
+                    .await;
+                response.assert_status_not_found();
+                assert_eq!(
+                    response.json::<ErrorResponse>().meta.message,
+                    "Attachment not found"
+                );
+                server
+                    .get(&path)
+                    .add_header("Authorization", &token)
+                    .await
+                    .assert_status_ok();
+            }
+        }
+        server
+            .get(&format!("/api/v1/groups/{}/files", org_group.group_id))
+            .add_header("Authorization", &other_token)
+            .await
+            .assert_status_not_found();
+        // Files shared with a group they can join are theirs to read
+        server
+            .get(&format!("/api/v1/attachments/{}", shared))
+            .add_header("Authorization", &other_token)
+            .await
+            .assert_status_ok();
+
+        set_role(&other.user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+        server
+            .get(&format!("/api/v1/attachments/{}", private))
+            .add_header("Authorization", &other_token)
+            .await
+            .assert_status_ok();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Scans run in the background, so wait for the status to settle
+    async fn scanned(state: &AppState, attachment_id: &str) -> ScanStatus {
+        for _ in 0..100 {
+            let status = get_by_id(&state.pool, attachment_id).await.unwrap().status;
+            if status != ScanStatus::Pending {
+                return status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("{} was never scanned", attachment_id);
+    }
+
+    #[tokio::test]
+    async fn test_malware_scan() {
+        let (server, state, _, token, dir) = setup_with(Some(Arc::new(MarkerScanner))).await;
+
+        let mut ids = Vec::new();
+        for content in ["harmless", "X5O!P%@AP EICAR test"] {
+            let response = server
+                .post("/api/v1/attachments")
+                .add_header("Authorization", &token)
+                .content_type("text/plain")
+                .bytes(content.into())
+                .await;
+            response.assert_status_ok();
+            let body = response.json::<serde_json::Value>();
+            assert_eq!(body["data"]["status"], "pending");
+            ids.push(body["data"]["attachment_id"].as_str().unwrap().to_string());
+        }
+
+        assert_eq!(scanned(&state, &ids[0]).await, ScanStatus::Clean);
+        let response = server
+            .get(&format!("/api/v1/attachments/{}", ids[0]))
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status_ok();
+        response.assert_text("harmless");
+
+        assert_eq!(scanned(&state, &ids[1]).await, ScanStatus::Rejected);
+        let response = server
+            .get(&format!("/api/v1/attachments/{}", ids[1]))
+            .add_header("Authorization", &token)
+            .await;
+        response.assert_status(StatusCode::GONE);
+        assert_eq!(
+            response.json::<ErrorResponse>().meta.message,
+            "Attachment was rejected by the malware scan"
+        );
+        let quarantined = get_by_id(&state.pool, &ids[1]).await.unwrap();
+        assert_eq!(quarantined.storage_key, format!("quarantine/{}", ids[1]));
+        assert!(dir.join("quarantine").join(&ids[1]).exists());
+        for _ in 0..100 {
+            if !dir.join("attachments").join(&ids[1]).exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(!dir.join("attachments").join(&ids[1]).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_limit() {
+        let (server, state, _, token, dir) = setup().await;
+        let limit = state.settings.storage.max_upload_bytes;
+
+        let response = server
+            .post("/api/v1/attachments")
+            .add_header("Authorization", &token)
+            .bytes(vec![0u8; limit + 1].into())
+            .await;
+        response.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{io::Cursor, sync::Arc};
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt, stream};
+use image::{
+    DynamicImage, ImageFormat, ImageReader, Limits, Rgb, RgbImage, codecs::jpeg::JpegEncoder,
+    imageops::FilterType,
+};
+use tokio::task::JoinHandle;
+
+use crate::storage::backend::{Storage, StorageError, StorageSettings};
+
+/// Largest width or height decoded, against decompression bombs
+const MAX_DIMENSION: u32 = 12_000;
+/// Memory the decoder may allocate for one image
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+const WEB_JPEG_QUALITY: u8 = 80;
+
+/// Images derived from an uploaded one, stored under `<key>.<name>`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    /// Fits `storage.thumbnail_px` square, PNG so transparency survives
+    Thumb,
+    /// Fits `storage.web_px` square, JPEG
+    Web,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 2] = [Variant::Thumb, Variant::Web];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Thumb => "thumb",
+            Variant::Web => "web",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|variant| variant.name() == name)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Variant::Thumb => "image/png",
+            Variant::Web => "image/jpeg",
+        }
+    }
+
+    pub fn key(self, key: &str) -> String {
+        format!("{}.{}", key, self.name())
+    }
+}
+
+/// Uploads variants are made for
+pub fn is_processable(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp"
+    )
+}
+
+/// `key` and the keys its variants would have, for deleting all of them
+pub fn with_variants(key: String) -> Vec<String> {
+    let mut keys: Vec<_> = Variant::ALL
+        .iter()
+        .map(|variant| variant.key(&key))
+        .collect();
+    keys.push(key);
+    keys
+}
+
+fn decode(original: &[u8]) -> Result<DynamicImage, image::ImageError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::new(Cursor::new(original)).with_guessed_format()?;
+    reader.limits(limits);
+    reader.decode()
+}
+
+/// JPEG has no alpha channel, so transparent parts end up white
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// Encoded variants of an image; CPU bound, run it on the blocking pool
+pub fn render(
+    original: &[u8],
+    settings: &StorageSettings,
+) -> Result<Vec<(Variant, Vec<u8>)>, image::ImageError> {
+    let image = decode(original)?;
+    let mut variants = Vec::with_capacity(Variant::ALL.len());
+    for variant in Variant::ALL {
+        let mut encoded = Vec::new();
+        match variant {
+            Variant::Thumb => image
+                .thumbnail(settings.thumbnail_px, settings.thumbnail_px)
+                .write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?,
+            Variant::Web => {
+                let fitted = if image.width() > settings.web_px || image.height() > settings.web_px
+                {
+                    image.resize(settings.web_px, settings.web_px, FilterType::Lanczos3)
+                } else {
+                    image.clone()
+                };
+                JpegEncoder::new_with_quality(&mut encoded, WEB_JPEG_QUALITY)
+                    .encode_image(&flatten(&fitted))?;
+            }
+        }
+        variants.push((variant, encoded));
+    }
+    Ok(variants)
+}
+
+async fn make_variants(
+    storage: &dyn Storage,
+    key: &str,
+    settings: StorageSettings,
+) -> Result<(), String> {
+    let original: Vec<u8> = storage
+        .get(key)
+        .await
+        .map_err(|e| e.to_string())?
+        .try_fold(Vec::new(), |mut data, chunk| async move {
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .await
+        .map_err(|e| StorageError::from(e).to_string())?;
+    let variants = tokio::task::spawn_blocking(move || render(&original, &settings))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    for (variant, data) in variants {
+        let body = stream::once(async move { Ok(Bytes::from(data)) }).boxed();
+        storage
+            .put(&variant.key(key), body)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Makes and stores the variants of an uploaded image in the background.
+/// Until they're there, and if the image can't be decoded, fetching a
+/// variant answers 404; the original is served either way.
+pub fn spawn_variants(
+    storage: Arc<dyn Storage>,
+    key: String,
+    settings: StorageSettings,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = make_variants(storage.as_ref(), &key, settings).await {
+            tracing::warn!(key, error = %e, "Failed to make image variants");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_images {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+    use crate::storage::{
+        backend::StorageSettings,
+        images::{Variant, render, with_variants},
+    };
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(width, height, Rgba([200, 30, 30, 128]));
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)
+            .unwrap();
+        encoded
+    }
+
+    #[test]
+    fn test_render() {
+        let settings = StorageSettings {
+            thumbnail_px: 64,
+            web_px: 200,
+            ..Default::default()
+        };
+        let variants = render(&png(400, 100), &settings).unwrap();
+
+        let (variant, thumb) = &variants[0];
+        assert_eq!(*variant, Variant::Thumb);
+        let thumb = image::load_from_memory_with_format(thumb, ImageFormat::Png).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (64, 16));
+
+        let (variant, web) = &variants[1];
+        assert_eq!(*variant, Variant::Web);
+        let web = image::load_from_memory_with_format(web, ImageFormat::Jpeg).unwrap();
+        assert_eq!((web.width(), web.height()), (200, 50));
+
+        assert!(render(b"not an image", &settings).is_err());
+    }
+
+    #[test]
+    fn test_variant_keys() {
+        assert_eq!(Variant::parse("thumb"), Some(Variant::Thumb));
+        assert_eq!(Variant::parse("original"), None);
+        assert_eq!(
+            with_variants("attachments/a1".to_string()),
+            vec![
+                "attachments/a1.thumb",
+                "attachments/a1.web",
+                "attachments/a1"
+            ]
+        );
+    }
+}
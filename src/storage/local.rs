@@ -0,0 +1,138 @@
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::storage::backend::{ByteStream, Storage, StorageError};
+
+/// Stores each object as a file under `root`, using the key as relative path
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        let key = Path::new(key);
+        let plain = key
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if key.as_os_str().is_empty() || !plain {
+            return Err(StorageError::InvalidKey);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, mut body: ByteStream) -> Result<u64, StorageError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Written next to the target and renamed, so readers never see a partial file
+        let partial = path.with_extension(format!("{}.part", uuid::Uuid::new_v4()));
+        let mut file = fs::File::create(&partial).await?;
+        let mut size = 0;
+        let written = async {
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                size += chunk.len() as u64;
+            }
+            file.flush().await
+        }
+        .await;
+        drop(file);
+
+        match written {
+            Ok(()) => fs::rename(&partial, &path).await?,
+            Err(e) => {
+                let _ = fs::remove_file(&partial).await;
+                return Err(e.into());
+            }
+        }
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let file = fs::File::open(self.path(key)?).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_local {
+    use std::io;
+
+    use futures::{StreamExt, TryStreamExt, stream};
+
+    use crate::storage::{
+        backend::{Storage, StorageError},
+        local::LocalStorage,
+    };
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = LocalStorage::new(&dir);
+
+        let body = stream::iter([Ok("abc".into()), Ok("def".into())]).boxed();
+        assert_eq!(storage.put("a/b.bin", body).await.unwrap(), 6);
+
+        let chunks: Vec<_> = storage
+            .get("a/b.bin")
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"abcdef");
+
+        storage.delete("a/b.bin").await.unwrap();
+        storage.delete("a/b.bin").await.unwrap();
+        assert!(matches!(
+            storage.get("a/b.bin").await,
+            Err(StorageError::NotFound)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_put_leaves_nothing() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = LocalStorage::new(&dir);
+
+        let body = stream::iter([Ok("abc".into()), Err(io::Error::other("client gone"))]).boxed();
+        assert!(storage.put("a/c.bin", body).await.is_err());
+        assert_eq!(std::fs::read_dir(dir.join("a")).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_key() {
+        let storage = LocalStorage::new(std::env::temp_dir());
+        for key in ["", "../etc/passwd", "/etc/passwd", "a/../../b"] {
+            assert!(matches!(
+                storage.get(key).await,
+                Err(StorageError::InvalidKey)
+            ));
+        }
+    }
+}
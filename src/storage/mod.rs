@@ -0,0 +1,7 @@
+pub mod backend;
+pub mod handler;
+pub mod images;
+pub mod local;
+pub mod s3;
+pub mod scan;
+pub mod signed;
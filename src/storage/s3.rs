@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    ObjectStoreExt, WriteMultipart,
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path,
+};
+
+use crate::storage::backend::{ByteStream, Storage, StorageError, StorageSettings};
+
+/// Uploads in parts of this size, at most `MAX_PARTS_IN_FLIGHT` at a time
+const PART_SIZE: usize = 5 * 1024 * 1024;
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+
+/// S3-compatible object storage
+pub struct S3Storage {
+    store: AmazonS3,
+}
+
+impl S3Storage {
+    pub fn new(settings: &StorageSettings) -> Result<Self, String> {
+        let bucket = settings
+            .bucket
+            .as_deref()
+            .ok_or("storage.bucket is required for the s3 backend")?;
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_region(&settings.region);
+        if let Some(endpoint) = &settings.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(key_id) = &settings.access_key_id {
+            builder = builder.with_access_key_id(key_id);
+        }
+        if let Some(secret) = &settings.secret_access_key {
+            builder = builder.with_secret_access_key(secret);
+        }
+        let store = builder.build().map_err(|e| e.to_string())?;
+        Ok(Self { store })
+    }
+}
+
+fn backend_error(e: object_store::Error) -> StorageError {
+    match e {
+        object_store::Error::NotFound { .. } => StorageError::NotFound,
+        e => StorageError::Backend(e.to_string()),
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, mut body: ByteStream) -> Result<u64, StorageError> {
+        let location = Path::parse(key).map_err(|_| StorageError::InvalidKey)?;
+        let upload = self
+            .store
+            .put_multipart(&location)
+            .await
+            .map_err(backend_error)?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // Otherwise the uploaded parts linger in the bucket
+                    let _ = writer.abort().await;
+                    return Err(e.into());
+                }
+            };
+            if let Err(e) = writer.wait_for_capacity(MAX_PARTS_IN_FLIGHT).await {
+                return Err(backend_error(e));
+            }
+            size += chunk.len() as u64;
+            writer.put(chunk);
+        }
+        writer.finish().await.map_err(backend_error)?;
+        Ok(size)
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, StorageError> {
+        let location = Path::parse(key).map_err(|_| StorageError::InvalidKey)?;
+        let result = self.store.get(&location).await.map_err(backend_error)?;
+        Ok(result.into_stream().map_err(std::io::Error::other).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let location = Path::parse(key).map_err(|_| StorageError::InvalidKey)?;
+        match self.store.delete(&location).await {
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            result => result.map_err(backend_error),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+
+use crate::{config::secrets::redact_option, storage::backend::ByteStream};
+
+/// Largest chunk sent to clamd at once, well under its `StreamMaxLength`
+const CLAMD_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanProvider {
+    /// Uploads are served without being scanned
+    #[default]
+    None,
+    /// A clamd daemon, over TCP (`host:port`) or a Unix socket (`/path`)
+    Clamav,
+    /// POSTs the file to `url`, which answers `{"infected": bool, "signature": "..."}`
+    Http,
+}
+
+/// Settings from the `[scan]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScanSettings {
+    pub provider: ScanProvider,
+    /// `clamav`: where clamd listens
+    pub clamav_addr: String,
+    /// `http`: the scanning service
+    pub url: Option<String>,
+    /// `http`: sent as `Authorization: Bearer <token>` when set
+    #[serde(serialize_with = "redact_option")]
+    pub token: Option<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        Self {
+            provider: ScanProvider::None,
+            clamav_addr: "127.0.0.1:3310".to_string(),
+            url: None,
+            token: None,
+            timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// Name of the signature that matched
+    Infected(String),
+}
+
+#[async_trait]
+pub trait UploadScanner: Send + Sync {
+    async fn scan(&self, data: ByteStream) -> Result<Verdict, String>;
+}
+
+pub struct ClamavScanner {
+    addr: String,
+    timeout: Duration,
+}
+
+impl ClamavScanner {
+    pub fn new(settings: &ScanSettings) -> Self {
+        Self {
+            addr: settings.clamav_addr.clone(),
+            timeout: Duration::from_secs(settings.timeout_secs),
+        }
+    }
+
+    /// clamd's `INSTREAM`: length-prefixed chunks ended by an empty one, answered
+    /// with `stream: OK` or `stream: <signature> FOUND`
+    async fn instream<S>(mut conn: S, mut data: ByteStream) -> Result<Verdict, String>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let io = |e: std::io::Error| e.to_string();
+        conn.write_all(b"zINSTREAM\0").await.map_err(io)?;
+        while let Some(chunk) = data.next().await {
+            for part in chunk.map_err(io)?.chunks(CLAMD_CHUNK) {
+                conn.write_all(&(part.len() as u32).to_be_bytes())
+                    .await
+                    .map_err(io)?;
+                conn.write_all(part).await.map_err(io)?;
+            }
+        }
+        conn.write_all(&0u32.to_be_bytes()).await.map_err(io)?;
+
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).await.map_err(io)?;
+        parse_clamd_reply(&reply)
+    }
+}
+
+fn parse_clamd_reply(reply: &str) -> Result<Verdict, String> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(format!("Unexpected clamd reply: {}", reply))
+    }
+}
+
+#[async_trait]
+impl UploadScanner for ClamavScanner {
+    async fn scan(&self, data: ByteStream) -> Result<Verdict, String> {
+        let scan = async {
+            if self.addr.starts_with('/') {
+                let conn = UnixStream::connect(&self.addr)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Self::instream(conn, data).await
+            } else {
+                let conn = TcpStream::connect(&self.addr)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Self::instream(conn, data).await
+            }
+        };
+        tokio::time::timeout(self.timeout, scan)
+            .await
+            .map_err(|_| "clamd timed out".to_string())?
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpScanResult {
+    infected: bool,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+pub struct HttpScanner {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl HttpScanner {
+    pub fn new(settings: &ScanSettings) -> Result<Self, String> {
+        let url = settings
+            .url
+            .clone()
+            .ok_or_else(|| "scan.url is required for the http provider".to_string())?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            url,
+            token: settings.token.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl UploadScanner for HttpScanner {
+    async fn scan(&self, data: ByteStream) -> Result<Verdict, String> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+            .body(reqwest::Body::wrap_stream(data));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Scanner answered {}", response.status()));
+        }
+        let result: HttpScanResult = response.json().await.map_err(|e| e.to_string())?;
+        Ok(match result.infected {
+            true => Verdict::Infected(result.signature.unwrap_or_else(|| "unknown".to_string())),
+            false => Verdict::Clean,
+        })
+    }
+}
+
+/// Builds the configured scanner, `None` when uploads aren't scanned. A
+/// broken setup is logged and leaves scanning off so the API still starts.
+pub fn build_scanner(settings: &ScanSettings) -> Option<Arc<dyn UploadScanner>> {
+    match settings.provider {
+        ScanProvider::None => None,
+        ScanProvider::Clamav => Some(Arc::new(ClamavScanner::new(settings))),
+        ScanProvider::Http => match HttpScanner::new(settings) {
+            Ok(scanner) => Some(Arc::new(scanner)),
+            Err(e) => {
+                tracing::error!(error = %e, "Invalid scan settings, uploads won't be scanned");
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests_scan {
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::storage::scan::{
+        ClamavScanner, HttpScanner, ScanSettings, UploadScanner, Verdict, parse_clamd_reply,
+    };
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0"), Ok(Verdict::Clean));
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Test-Signature FOUND\0"),
+            Ok(Verdict::Infected("Eicar-Test-Signature".to_string()))
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    /// Speaks just enough clamd to check the framing
+    #[tokio::test]
+    async fn test_clamav_instream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clamd = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            conn.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = conn.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                conn.read_exact(&mut chunk).await.unwrap();
+                data.extend(chunk);
+            }
+            let reply: &[u8] = match data.windows(5).any(|w| w == b"EICAR") {
+                true => b"stream: Eicar-Test-Signature FOUND\0",
+                false => b"stream: OK\0",
+            };
+            conn.write_all(reply).await.unwrap();
+            data
+        });
+
+        let scanner = ClamavScanner::new(&ScanSettings {
+            clamav_addr: addr.to_string(),
+            ..Default::default()
+        });
+        let data = futures::stream::iter(["X5O!P%@AP ", "EICAR test"])
+            .map(|part| Ok(Bytes::from(part)))
+            .boxed();
+        assert_eq!(
+            scanner.scan(data).await,
+            Ok(Verdict::Infected("Eicar-Test-Signature".to_string()))
+        );
+        assert_eq!(clamd.await.unwrap(), b"X5O!P%@AP EICAR test");
+    }
+
+    #[test]
+    fn test_http_requires_url() {
+        assert!(HttpScanner::new(&ScanSettings::default()).is_err());
+    }
+}
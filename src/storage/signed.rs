@@ -0,0 +1,97 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::storage::backend::StorageSettings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Label of the subkey derived from the JWT key when `storage.signing_key`
+/// is unset
+const SUBKEY_LABEL: &[u8] = b"example-axum-api files";
+
+/// The key links are signed with: `storage.signing_key`, or else a subkey
+/// of `jwt_key`, so the JWT key itself never signs anything but tokens
+pub fn signing_key(storage: &StorageSettings, jwt_key: &str) -> Vec<u8> {
+    if !storage.signing_key.is_empty() {
+        return storage.signing_key.as_bytes().to_vec();
+    }
+    let mut mac =
+        HmacSha256::new_from_slice(jwt_key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(SUBKEY_LABEL);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn mac(key: &[u8], attachment_id: &str, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(format!("files/{}:{}", attachment_id, expires).as_bytes());
+    mac
+}
+
+/// Hex encoded HMAC-SHA256 of the attachment id and expiry
+pub fn sign(key: &[u8], attachment_id: &str, expires: i64) -> String {
+    hex::encode(mac(key, attachment_id, expires).finalize().into_bytes())
+}
+
+/// `/files/<id>?expires=<unix seconds>&sig=<hex>`, usable without a token
+/// until `expires`
+pub fn signed_url(key: &[u8], attachment_id: &str, expires: i64) -> String {
+    format!(
+        "/files/{}?expires={}&sig={}",
+        attachment_id,
+        expires,
+        sign(key, attachment_id, expires)
+    )
+}
+
+/// Whether `sig` was made by `sign` for these values and `expires` is still
+/// ahead of `now`. The signature is compared in constant time.
+pub fn verify(key: &[u8], attachment_id: &str, expires: i64, sig: &str, now: i64) -> bool {
+    let Ok(sig) = hex::decode(sig) else {
+        return false;
+    };
+    expires > now && mac(key, attachment_id, expires).verify_slice(&sig).is_ok()
+}
+
+#[cfg(test)]
+mod tests_signed {
+    use crate::storage::{
+        backend::StorageSettings,
+        signed::{sign, signed_url, signing_key, verify},
+    };
+
+    #[test]
+    fn test_sign_and_verify() {
+        let sig = sign(b"secret", "a1", 1_000);
+        assert!(verify(b"secret", "a1", 1_000, &sig, 999));
+        assert!(!verify(b"secret", "a1", 1_000, &sig, 1_000));
+        assert!(!verify(b"secret", "a2", 1_000, &sig, 999));
+        assert!(!verify(b"secret", "a1", 2_000, &sig, 999));
+        assert!(!verify(b"other", "a1", 1_000, &sig, 999));
+        assert!(!verify(b"secret", "a1", 1_000, "not-hex", 999));
+        assert_eq!(
+            signed_url(b"secret", "a1", 1_000),
+            format!("/files/a1?expires=1000&sig={}", sig)
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let jwt_key = "a-key-of-at-least-32-characters-long";
+        let derived = signing_key(&StorageSettings::default(), jwt_key);
+        assert_ne!(derived, jwt_key.as_bytes());
+        assert_eq!(derived, signing_key(&StorageSettings::default(), jwt_key));
+        assert_ne!(
+            derived,
+            signing_key(&StorageSettings::default(), "another-key")
+        );
+
+        let storage = StorageSettings {
+            signing_key: "a-dedicated-key-for-file-links-only".to_string(),
+            ..StorageSettings::default()
+        };
+        assert_eq!(
+            signing_key(&storage, jwt_key),
+            b"a-dedicated-key-for-file-links-only"
+        );
+    }
+}
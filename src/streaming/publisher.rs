@@ -0,0 +1,327 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{app_state::AppState, config::secrets::redact_option, event_bus::DomainEvent};
+
+/// Version of the record layout below; bumped when a field changes meaning
+/// or goes away, so consumers can tell old records from new ones
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamProvider {
+    /// Events stay inside the API
+    #[default]
+    None,
+    /// Published to `<subject_prefix>.<event name>`
+    Nats,
+    /// Produced to `topic` through a Kafka REST Proxy (v2 API)
+    Kafka,
+}
+
+/// Settings from the `[streaming]` section of the config file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StreamingSettings {
+    pub provider: StreamProvider,
+    /// `nats`: server address
+    pub nats_url: String,
+    /// `nats`: sent when the server requires token authentication
+    #[serde(serialize_with = "redact_option")]
+    pub nats_token: Option<String>,
+    pub subject_prefix: String,
+    /// `kafka`: base URL of the REST Proxy
+    pub kafka_rest_url: Option<String>,
+    pub topic: String,
+    /// Event names to stream, e.g. `user.registered`; all events when empty
+    pub events: Vec<String>,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            provider: StreamProvider::None,
+            nats_url: "nats://127.0.0.1:4222".to_string(),
+            nats_token: None,
+            subject_prefix: "example".to_string(),
+            kafka_rest_url: None,
+            topic: "example.events".to_string(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl StreamingSettings {
+    fn accepts(&self, event: &DomainEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+/// What downstream consumers receive for each domain event
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamRecord {
+    pub schema_version: u32,
+    pub id: String,
+    /// RFC 3339
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+impl StreamRecord {
+    pub fn new(event: DomainEvent) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            id: Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            event,
+        }
+    }
+
+    /// Partition key, so a group's events stay in order on Kafka
+    pub fn key(&self) -> Option<&str> {
+        self.event.group_id()
+    }
+}
+
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, record: &StreamRecord) -> Result<(), String>;
+}
+
+pub struct NatsPublisher {
+    client: async_nats::Client,
+    subject_prefix: String,
+}
+
+impl NatsPublisher {
+    /// Connects in the background, so a NATS server that is down at startup
+    /// doesn't keep the API from starting; publishes wait for the connection
+    pub async fn connect(settings: &StreamingSettings) -> Result<Self, String> {
+        let mut options = async_nats::ConnectOptions::new()
+            .name(env!("CARGO_PKG_NAME"))
+            .retry_on_initial_connect();
+        if let Some(token) = &settings.nats_token {
+            options = options.token(token.clone());
+        }
+        let client = options
+            .connect(settings.nats_url.as_str())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            subject_prefix: settings.subject_prefix.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EventPublisher for NatsPublisher {
+    async fn publish(&self, record: &StreamRecord) -> Result<(), String> {
+        let subject = format!("{}.{}", self.subject_prefix, record.event.name());
+        let payload = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+        self.client
+            .publish(subject, payload.into())
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+pub struct KafkaRestPublisher {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl KafkaRestPublisher {
+    pub fn new(settings: &StreamingSettings) -> Result<Self, String> {
+        let base = settings
+            .kafka_rest_url
+            .as_deref()
+            .ok_or_else(|| "streaming.kafka_rest_url is required for kafka".to_string())?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            client,
+            url: format!("{}/topics/{}", base.trim_end_matches('/'), settings.topic),
+        })
+    }
+}
+
+#[async_trait]
+impl EventPublisher for KafkaRestPublisher {
+    async fn publish(&self, record: &StreamRecord) -> Result<(), String> {
+        let body = serde_json::json!({
+            "records": [{ "key": record.key(), "value": record }]
+        });
+        let response = self
+            .client
+            .post(&self.url)
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/vnd.kafka.json.v2+json",
+            )
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Kafka REST Proxy answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Builds the configured publisher, `None` when streaming is off. A broken
+/// setup is logged and leaves streaming off so the API still starts.
+pub async fn build_publisher(settings: &StreamingSettings) -> Option<Arc<dyn EventPublisher>> {
+    let publisher: Result<Arc<dyn EventPublisher>, String> = match settings.provider {
+        StreamProvider::None => return None,
+        StreamProvider::Nats => NatsPublisher::connect(settings)
+            .await
+            .map(|publisher| Arc::new(publisher) as _),
+        StreamProvider::Kafka => {
+            KafkaRestPublisher::new(settings).map(|publisher| Arc::new(publisher) as _)
+        }
+    };
+    publisher
+        .inspect_err(
+            |e| tracing::error!(error = %e, "Invalid streaming settings, events won't be streamed"),
+        )
+        .ok()
+}
+
+/// Listens on the event bus and publishes each accepted event. Like webhook
+/// delivery this is best effort: a failed publish is logged, not retried.
+pub fn spawn_streamer(state: Arc<AppState>, publisher: Arc<dyn EventPublisher>) -> JoinHandle<()> {
+    let mut rx = state.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "Event stream fell behind, events were skipped");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if !state.settings.streaming.accepts(&event) {
+                continue;
+            }
+            let record = StreamRecord::new(event);
+            if let Err(e) = publisher.publish(&record).await {
+                tracing::warn!(event = record.event.name(), error = %e, "Failed to stream event");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_publisher {
+    use std::sync::Arc;
+
+    use axum::{Router, http::HeaderMap, routing::post};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        app_state::AppState,
+        auth::user::User,
+        event_bus::DomainEvent,
+        streaming::publisher::{
+            EventPublisher, KafkaRestPublisher, SCHEMA_VERSION, StreamRecord, StreamingSettings,
+            spawn_streamer,
+        },
+    };
+
+    fn user() -> User {
+        User {
+            user_id: "user-1".to_string(),
+            user_name: "Jordan".to_string(),
+            email: "jordan@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        }
+    }
+
+    /// Serves the REST Proxy's `POST /topics/{topic}` on a random port and
+    /// forwards `(content type, body)` of each call
+    async fn rest_proxy() -> (std::net::SocketAddr, mpsc::Receiver<(String, String)>) {
+        let (tx, rx) = mpsc::channel::<(String, String)>(8);
+        let proxy = Router::new().route(
+            "/topics/{topic}",
+            post(move |headers: HeaderMap, body: String| {
+                let tx = tx.clone();
+                async move {
+                    let content_type = headers["content-type"].to_str().unwrap().to_string();
+                    let _ = tx.send((content_type, body)).await;
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, proxy).await.unwrap() });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let record = StreamRecord::new(DomainEvent::MemberJoined {
+            group_id: "g1".to_string(),
+            user: user(),
+        });
+        assert_eq!(record.key(), Some("g1"));
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["event"], "member.joined");
+        assert_eq!(json["data"]["user"]["user_id"], "user-1");
+        assert!(json["id"].is_string());
+        assert!(chrono::DateTime::parse_from_rfc3339(json["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_kafka_requires_url() {
+        assert!(KafkaRestPublisher::new(&StreamingSettings::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_to_kafka_rest_proxy() {
+        let (addr, mut rx) = rest_proxy().await;
+
+        let mut state = AppState::test().await;
+        let mut settings = (*state.settings).clone();
+        settings.streaming = StreamingSettings {
+            kafka_rest_url: Some(format!("http://{}/", addr)),
+            events: vec!["user.registered".to_string()],
+            ..Default::default()
+        };
+        let publisher: Arc<dyn EventPublisher> =
+            Arc::new(KafkaRestPublisher::new(&settings.streaming).unwrap());
+        state.settings = Arc::new(settings);
+        let state = Arc::new(state);
+
+        let streamer = spawn_streamer(state.clone(), publisher);
+        // Filtered out by `events`
+        state.events.publish(DomainEvent::MemberJoined {
+            group_id: "g1".to_string(),
+            user: user(),
+        });
+        state
+            .events
+            .publish(DomainEvent::UserRegistered { user: user() });
+
+        let (content_type, body) = rx.recv().await.unwrap();
+        assert_eq!(content_type, "application/vnd.kafka.json.v2+json");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let record = &json["records"][0];
+        assert!(record["key"].is_null());
+        assert_eq!(record["value"]["event"], "user.registered");
+        assert_eq!(record["value"]["schema_version"], SCHEMA_VERSION);
+        assert!(rx.try_recv().is_err());
+        streamer.abort();
+    }
+}
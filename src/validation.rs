@@ -0,0 +1,198 @@
+use axum::{
+    Form, Json,
+    extract::{FromRequest, Request},
+    http::StatusCode,
+};
+use serde::de::DeserializeOwned;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+use crate::{
+    error::{ErrorDetail, ErrorResponse},
+    extract::is_json,
+};
+
+/// `Form<T>` that also runs `T::validate`, so a handler taking it only ever
+/// sees valid input. Malformed bodies keep the status of the form rejection;
+/// validation failures are a 422 listing every invalid field.
+pub struct ValidatedForm<T>(pub T);
+
+/// `Json<T>` counterpart of `ValidatedForm`
+pub struct ValidatedJson<T>(pub T);
+
+/// JSON or form body (see `JsonOrForm`), validated like `ValidatedForm`
+pub struct Validated<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidatedForm<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Form(value) = Form::<T>::from_request(req, state)
+            .await
+            .map_err(|e| ErrorResponse::new(e.status(), e.body_text()))?;
+        value.validate().map_err(invalid)?;
+        Ok(ValidatedForm(value))
+    }
+}
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|e| ErrorResponse::new(e.status(), e.body_text()))?;
+        value.validate().map_err(invalid)?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+impl<T, S> FromRequest<S> for Validated<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ErrorResponse;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if is_json(req.headers()) {
+            let ValidatedJson(value) = ValidatedJson::<T>::from_request(req, state).await?;
+            return Ok(Validated(value));
+        }
+        let ValidatedForm(value) = ValidatedForm::<T>::from_request(req, state).await?;
+        Ok(Validated(value))
+    }
+}
+
+/// 422 with one `ErrorDetail` per failed rule, fields of nested structs
+/// prefixed by their parent (`address.city`)
+pub fn invalid(errors: ValidationErrors) -> ErrorResponse {
+    let mut response = ErrorResponse::new(StatusCode::UNPROCESSABLE_ENTITY, "Validation failed");
+    collect(&errors, "", &mut response.meta.errors);
+    response.meta.errors.sort_by(|a, b| a.field.cmp(&b.field));
+    response
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, details: &mut Vec<ErrorDetail>) {
+    for (field, kind) in errors.errors() {
+        let field = format!("{}{}", prefix, field);
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                details.extend(errors.iter().map(|error| {
+                    ErrorDetail {
+                        field: field.clone(),
+                        code: error.code.to_string(),
+                        message: error
+                            .message
+                            .as_ref()
+                            .map(|message| message.to_string())
+                            .unwrap_or_else(|| format!("invalid {}", error.code)),
+                    }
+                }));
+            }
+            ValidationErrorsKind::Struct(errors) => {
+                collect(errors, &format!("{}.", field), details);
+            }
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect(errors, &format!("{}[{}].", field, index), details);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_validation {
+    use axum::{Router, http::StatusCode, routing::post};
+    use axum_test::TestServer;
+    use serde::{Deserialize, Serialize};
+    use validator::Validate;
+
+    use crate::{
+        error::ErrorResponse,
+        validation::{ValidatedForm, ValidatedJson},
+    };
+
+    #[derive(Debug, Serialize, Deserialize, Validate)]
+    struct Signup {
+        #[validate(length(min = 3, message = "must be at least 3 characters"))]
+        name: String,
+        #[validate(email)]
+        email: String,
+    }
+
+    fn server() -> TestServer {
+        let app = Router::new()
+            .route(
+                "/form",
+                post(|ValidatedForm(signup): ValidatedForm<Signup>| async move { signup.name }),
+            )
+            .route(
+                "/json",
+                post(|ValidatedJson(signup): ValidatedJson<Signup>| async move { signup.name }),
+            );
+        TestServer::new(app).unwrap()
+    }
+
+    fn signup(name: &str, email: &str) -> Signup {
+        Signup {
+            name: name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_valid_input() {
+        let server = server();
+        let body = signup("Jordan", "jordan@mail.com");
+        server.post("/form").form(&body).await.assert_text("Jordan");
+        server.post("/json").json(&body).await.assert_text("Jordan");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_input_lists_fields() {
+        let server = server();
+        let body = signup("Jo", "not-an-email");
+        for response in [
+            server.post("/form").form(&body).await,
+            server.post("/json").json(&body).await,
+        ] {
+            response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+            let body = response.json::<ErrorResponse>();
+            assert_eq!(body.meta.code, 422);
+            assert_eq!(body.meta.message, "Validation failed");
+            let fields: Vec<_> = body
+                .meta
+                .errors
+                .iter()
+                .map(|e| (e.field.as_str(), e.message.as_str()))
+                .collect();
+            assert_eq!(
+                fields,
+                vec![
+                    ("email", "invalid email"),
+                    ("name", "must be at least 3 characters")
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_keeps_rejection_status() {
+        let response = server()
+            .post("/json")
+            .text("{")
+            .content_type("application/json")
+            .await;
+        response.assert_status_bad_request();
+        assert_eq!(response.json::<ErrorResponse>().meta.code, 400);
+    }
+}
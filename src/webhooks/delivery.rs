@@ -0,0 +1,340 @@
+use std::{sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    app_state::AppState,
+    config::secrets::redact,
+    event_bus::DomainEvent,
+    webhooks::{
+        handler::get_by_group,
+        target::{check_url, public_client},
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Settings from the `[webhooks]` section of the config file
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// Receive every domain event, unlike group webhooks which only get their group's events
+    #[validate(nested)]
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Let group webhooks reach loopback and private addresses, for local
+    /// development. Endpoints configured here always can.
+    pub allow_private_targets: bool,
+}
+
+/// `[[webhooks.endpoints]]`
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct WebhookEndpoint {
+    #[validate(url(message = "must be a URL"))]
+    pub url: String,
+    #[validate(length(min = 16, message = "must be at least 16 characters"))]
+    #[serde(serialize_with = "redact")]
+    pub secret: String,
+    /// Event names to deliver, e.g. `user.registered`; all events when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl WebhookEndpoint {
+    pub fn accepts(&self, event: &DomainEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the raw body, formatted as `sha256=<hex>`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn payload(event: &DomainEvent) -> String {
+    let mut body = serde_json::to_value(event).unwrap_or_default();
+    body["id"] = Uuid::new_v4().to_string().into();
+    body["timestamp"] = chrono::Utc::now().timestamp().into();
+    body.to_string()
+}
+
+/// POSTs the body, retrying with exponential backoff on errors and non-2xx responses
+pub async fn deliver(client: &reqwest::Client, url: &str, secret: &str, body: &str) -> bool {
+    let signature = sign(secret, body.as_bytes());
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client
+            .post(url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header("X-Webhook-Attempt", attempt.to_string())
+            .body(body.to_string())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => return true,
+            Ok(response) => tracing::warn!(
+                url,
+                attempt,
+                status = response.status().as_u16(),
+                "Webhook rejected delivery"
+            ),
+            Err(e) => tracing::warn!(url, attempt, error = %e, "Webhook delivery failed"),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    false
+}
+
+/// Listens on the event bus and delivers each event to the configured
+/// endpoints that accept it and, for group events, to the group's webhooks.
+/// Group webhooks are checked again before each delivery and only reach
+/// public addresses, unless `webhooks.allow_private_targets` is on.
+pub fn spawn_dispatcher(state: Arc<AppState>) -> JoinHandle<()> {
+    let mut rx = state.events.subscribe();
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build webhook client");
+    let guarded = !state.settings.webhooks.allow_private_targets;
+    let group_client = match guarded {
+        true => public_client(Duration::from_secs(10)),
+        false => client.clone(),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            // (url, secret, whether it is a group webhook)
+            let mut targets: Vec<(String, String, bool)> = state
+                .settings
+                .webhooks
+                .endpoints
+                .iter()
+                .filter(|endpoint| endpoint.accepts(&event))
+                .map(|endpoint| (endpoint.url.clone(), endpoint.secret.clone(), false))
+                .collect();
+            if let Some(group_id) = event.group_id() {
+                match get_by_group(&state.pool, group_id).await {
+                    Ok(webhooks) => targets.extend(
+                        webhooks
+                            .into_iter()
+                            .map(|webhook| (webhook.url, webhook.secret.unwrap_or_default(), true)),
+                    ),
+                    Err(e) => tracing::error!(group_id, error = %e, "Failed to load webhooks"),
+                }
+            }
+            if targets.is_empty() {
+                continue;
+            }
+
+            let body = payload(&event);
+            for (url, secret, group) in targets {
+                let client = match group {
+                    true => group_client.clone(),
+                    false => client.clone(),
+                };
+                let body = body.clone();
+                tokio::spawn(async move {
+                    if group
+                        && guarded
+                        && let Err(e) = check_url(&url).await
+                    {
+                        tracing::warn!(url, error = e, "Webhook target refused");
+                        return;
+                    }
+                    deliver(&client, &url, &secret, &body).await;
+                });
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests_delivery {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{Router, http::HeaderMap, routing::post};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        app_state::AppState,
+        auth::{user::User, util::random_name},
+        event_bus::DomainEvent,
+        group::handler,
+        webhooks::{
+            delivery::{SIGNATURE_HEADER, WebhookEndpoint, sign, spawn_dispatcher},
+            handler::create,
+        },
+    };
+
+    /// Serves `POST /hook` on a random port and forwards `(signature, body)` of each call
+    async fn receiver() -> (std::net::SocketAddr, mpsc::Receiver<(String, String)>) {
+        let (tx, rx) = mpsc::channel::<(String, String)>(8);
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| {
+                let tx = tx.clone();
+                async move {
+                    let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+                    let _ = tx.send((signature, body)).await;
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign("secret", b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature, sign("secret", b"{}"));
+        assert_ne!(signature, sign("other", b"{}"));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_signed_event() {
+        let (addr, mut rx) = receiver().await;
+
+        // The receiver is on loopback
+        let mut state = AppState::test().await;
+        let mut settings = (*state.settings).clone();
+        settings.webhooks.allow_private_targets = true;
+        state.settings = Arc::new(settings);
+        let state = Arc::new(state);
+        let group = handler::create(&state.pool, &random_name(), "")
+            .await
+            .unwrap();
+        let webhook = create(
+            &state.pool,
+            &group.group_id,
+            &format!("http://{}/hook", addr),
+            "user-1",
+        )
+        .await
+        .unwrap();
+
+        let dispatcher = spawn_dispatcher(state.clone());
+        state.events.publish(DomainEvent::MemberJoined {
+            group_id: group.group_id.clone(),
+            user: User {
+                user_id: "user-1".to_string(),
+                user_name: "Jordan".to_string(),
+                email: "jordan@mail.com".to_string(),
+                is_bot: false,
+                ..Default::default()
+            },
+        });
+
+        let (signature, body) = rx.recv().await.unwrap();
+        assert_eq!(signature, sign(&webhook.secret.unwrap(), body.as_bytes()));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["event"], "member.joined");
+        assert_eq!(json["data"]["group_id"], group.group_id);
+        dispatcher.abort();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_to_configured_endpoint() {
+        let (addr, mut rx) = receiver().await;
+
+        let mut state = AppState::test().await;
+        let mut settings = (*state.settings).clone();
+        settings.webhooks.endpoints = vec![WebhookEndpoint {
+            url: format!("http://{}/hook", addr),
+            secret: "endpoint-secret-123".to_string(),
+            events: vec!["user.registered".to_string()],
+        }];
+        state.settings = Arc::new(settings);
+        let state = Arc::new(state);
+
+        let dispatcher = spawn_dispatcher(state.clone());
+        let user = User {
+            user_id: "user-1".to_string(),
+            user_name: "Jordan".to_string(),
+            email: "jordan@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        };
+        // Filtered out by the endpoint's event list
+        state.events.publish(DomainEvent::MemberJoined {
+            group_id: "unknown".to_string(),
+            user: user.clone(),
+        });
+        state.events.publish(DomainEvent::UserRegistered { user });
+
+        let (signature, body) = rx.recv().await.unwrap();
+        assert_eq!(signature, sign("endpoint-secret-123", body.as_bytes()));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["event"], "user.registered");
+        assert_eq!(json["data"]["user"]["user_id"], "user-1");
+        dispatcher.abort();
+    }
+
+    #[tokio::test]
+    async fn test_group_webhook_refused_on_private_address() {
+        let (addr, mut rx) = receiver().await;
+
+        let mut state = AppState::test().await;
+        let mut settings = (*state.settings).clone();
+        settings.webhooks.endpoints = vec![WebhookEndpoint {
+            url: format!("http://{}/hook", addr),
+            secret: "endpoint-secret-123".to_string(),
+            events: Vec::new(),
+        }];
+        state.settings = Arc::new(settings);
+        let state = Arc::new(state);
+        let group = handler::create(&state.pool, &random_name(), "")
+            .await
+            .unwrap();
+        // Registered before the check existed, or resolving elsewhere since
+        create(
+            &state.pool,
+            &group.group_id,
+            &format!("http://{}/hook", addr),
+            "user-1",
+        )
+        .await
+        .unwrap();
+
+        let dispatcher = spawn_dispatcher(state.clone());
+        state.events.publish(DomainEvent::MemberJoined {
+            group_id: group.group_id.clone(),
+            user: User {
+                user_id: "user-1".to_string(),
+                ..Default::default()
+            },
+        });
+
+        // Only the configured endpoint is called
+        let (signature, body) = rx.recv().await.unwrap();
+        assert_eq!(signature, sign("endpoint-secret-123", body.as_bytes()));
+        let more = tokio::time::timeout(Duration::from_millis(500), rx.recv()).await;
+        assert!(more.is_err());
+        dispatcher.abort();
+    }
+}
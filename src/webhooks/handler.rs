@@ -0,0 +1,377 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    response::IntoResponse,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, Pool, Postgres, Row, postgres::PgRow};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    auth::{
+        extractors::AuthUser,
+        user::is_admin,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    extract::JsonOrForm,
+    group::handler::owner,
+    webhooks::target::check_url,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub webhook_id: String,
+    pub group_id: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookResponse {
+    pub meta: MetaResponse,
+    pub data: Webhook,
+}
+
+impl IntoResponse for WebhookResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhooksResponse {
+    pub meta: MetaResponse,
+    pub data: Vec<Webhook>,
+}
+
+impl IntoResponse for WebhooksResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::OK;
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookParam {
+    pub url: String,
+}
+
+pub async fn create(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    url: &str,
+    created_by: &str,
+) -> Result<Webhook, Error> {
+    let mut tx = pool.begin().await?;
+    let webhook_id = Uuid::new_v4().to_string();
+    let secret = Uuid::new_v4().simple().to_string();
+
+    let sql = "insert into group_webhooks (webhook_id, group_id, url, secret, created_by) values ($1, $2, $3, $4, $5)";
+    sqlx::query(sql)
+        .bind(&webhook_id)
+        .bind(group_id)
+        .bind(url)
+        .bind(&secret)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Webhook {
+        webhook_id,
+        group_id: group_id.to_string(),
+        url: url.to_string(),
+        secret: Some(secret),
+    })
+}
+
+/// Webhooks of a group including their signing secret, used for delivery
+pub async fn get_by_group(pool: &Pool<Postgres>, group_id: &str) -> Result<Vec<Webhook>, Error> {
+    let sql = "select webhook_id, group_id, url, secret from group_webhooks where group_id = $1";
+    sqlx::query(sql)
+        .bind(group_id)
+        .map(|data: PgRow| Webhook {
+            webhook_id: data.get("webhook_id"),
+            group_id: data.get("group_id"),
+            url: data.get("url"),
+            secret: data.get("secret"),
+        })
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_by_creator(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    created_by: &str,
+) -> Result<Vec<Webhook>, Error> {
+    let sql = "select webhook_id, group_id, url from group_webhooks where group_id = $1 and created_by = $2 order by created_at";
+    sqlx::query(sql)
+        .bind(group_id)
+        .bind(created_by)
+        .map(|data: PgRow| Webhook {
+            webhook_id: data.get("webhook_id"),
+            group_id: data.get("group_id"),
+            url: data.get("url"),
+            secret: None,
+        })
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn delete(
+    pool: &Pool<Postgres>,
+    group_id: &str,
+    webhook_id: &str,
+    created_by: &str,
+) -> Result<bool, Error> {
+    let mut tx = pool.begin().await?;
+    let sql =
+        "delete from group_webhooks where webhook_id = $1 and group_id = $2 and created_by = $3";
+    let result = sqlx::query(sql)
+        .bind(webhook_id)
+        .bind(group_id)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Registers a webhook on a group. Only the group's creator or an admin can,
+/// and the url has to resolve to public addresses unless
+/// `webhooks.allow_private_targets` is on.
+pub async fn create_webhook_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+    JsonOrForm(req): JsonOrForm<WebhookParam>,
+) -> Result<WebhookResponse, MetaResponse> {
+    if !(req.url.starts_with("http://") || req.url.starts_with("https://")) {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: "Webhook url must be http or https".to_string(),
+        });
+    }
+    if state.groups.get_by_id(&group_id).await.is_none() {
+        return Err(MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Group not found".to_string(),
+        });
+    }
+    let storage_error = |e: Error| MetaResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.to_i32(),
+        message: e.to_string(),
+    };
+    let owned =
+        owner(&state.pool, &group_id).await.map_err(storage_error)? == Some(user.user_id.clone());
+    if !owned
+        && !is_admin(&user.user_id, &state.pool)
+            .await
+            .map_err(storage_error)?
+    {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Only the group's creator or an admin can add webhooks".to_string(),
+        });
+    }
+    if !state.settings.webhooks.allow_private_targets
+        && let Err(message) = check_url(&req.url).await
+    {
+        return Err(MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: message.to_string(),
+        });
+    }
+
+    let result = create(&state.pool, &group_id, &req.url, &user.user_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    Ok(WebhookResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: result,
+    })
+}
+
+pub async fn webhooks_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path(group_id): Path<String>,
+) -> Result<WebhooksResponse, MetaResponse> {
+    let result = get_by_creator(&state.pool, &group_id, &user.user_id)
+        .await
+        .map_err(|e| MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        })?;
+    Ok(WebhooksResponse {
+        meta: MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        data: result,
+    })
+}
+
+pub async fn delete_webhook_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+    Path((group_id, webhook_id)): Path<(String, String)>,
+) -> MetaResponse {
+    match delete(&state.pool, &group_id, &webhook_id, &user.user_id).await {
+        Ok(true) => MetaResponse {
+            code: StatusCode::OK.to_i32(),
+            message: "Success".to_string(),
+        },
+        Ok(false) => MetaResponse {
+            code: StatusCode::NOT_FOUND.to_i32(),
+            message: "Webhook not found".to_string(),
+        },
+        Err(e) => MetaResponse {
+            code: StatusCode::BAD_REQUEST.to_i32(),
+            message: e.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests_webhook {
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        app_state::test_config,
+        auth::{
+            jwt::create_access_token,
+            user::{ADMIN_ROLE, NewUser, User, add, set_role},
+            util::random_name,
+        },
+        config::connection::ConnectionBuilder,
+        group::handler::{self, GroupResponse},
+        routes::routes,
+        webhooks::handler::{create, delete, get_by_creator, get_by_group},
+    };
+
+    #[tokio::test]
+    async fn test_create_list_delete() {
+        let builder = ConnectionBuilder(test_config());
+        let pool = builder.connect().await.expect("Failed to connect database");
+        let group = handler::create(&pool, &random_name(), "").await.unwrap();
+
+        let webhook = create(&pool, &group.group_id, "http://localhost/hook", "user-1")
+            .await
+            .unwrap();
+        assert!(webhook.secret.is_some());
+
+        let listed = get_by_creator(&pool, &group.group_id, "user-1")
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].secret.is_none());
+        assert!(
+            get_by_creator(&pool, &group.group_id, "user-2")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(
+            !delete(&pool, &group.group_id, &webhook.webhook_id, "user-2")
+                .await
+                .unwrap()
+        );
+        assert!(
+            delete(&pool, &group.group_id, &webhook.webhook_id, "user-1")
+                .await
+                .unwrap()
+        );
+        assert!(
+            get_by_group(&pool, &group.group_id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_create_webhook_handler() {
+        let state = AppState::isolated().await;
+        let server = TestServer::new(routes((*state).clone())).unwrap();
+        let token = |user: &User| {
+            format!(
+                "Bearer {}",
+                create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap()
+            )
+        };
+        let mut users = Vec::new();
+        for name in ["hook_owner", "hook_other", "hook_admin"] {
+            let user = add(
+                &state.pool,
+                NewUser::new(
+                    name.to_string(),
+                    format!("{}@mail.com", name),
+                    "123456".to_string(),
+                ),
+            )
+            .await
+            .unwrap();
+            users.push(user);
+        }
+        set_role(&users[2].user_id, ADMIN_ROLE, &state.pool)
+            .await
+            .unwrap();
+
+        let group = server
+            .post("/api/v1/groups")
+            .add_header("Authorization", token(&users[0]))
+            .form(&[("name", random_name().as_str()), ("description", "")])
+            .await
+            .json::<GroupResponse>()
+            .data;
+        let path = format!("/api/v1/groups/{}/webhooks", group.group_id);
+        let public = [("url", "https://93.184.215.14/hook")];
+
+        server
+            .post(&path)
+            .add_header("Authorization", token(&users[1]))
+            .form(&public)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        for url in [
+            "http://127.0.0.1:3000/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.5/hook",
+            "http://localhost/hook",
+        ] {
+            server
+                .post(&path)
+                .add_header("Authorization", token(&users[0]))
+                .form(&[("url", url)])
+                .await
+                .assert_status_bad_request();
+        }
+        for user in [&users[0], &users[2]] {
+            server
+                .post(&path)
+                .add_header("Authorization", token(user))
+                .form(&public)
+                .await
+                .assert_status_ok();
+        }
+    }
+}
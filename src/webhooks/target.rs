@@ -0,0 +1,162 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
+
+/// Whether `ip` is on the public internet. Loopback, private (RFC 1918 and
+/// unique local), link-local (with the 169.254.169.254 metadata service),
+/// shared (100.64.0.0/10), unspecified, broadcast, documentation and
+/// multicast addresses are not, so group webhooks can't reach them.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // documentation, 2001:db8::/32
+        || (first == 0x2001 && second == 0x0db8)
+        // NAT64 and IPv4-compatible forms of any v4 address
+        || (first == 0x0064 && second == 0xff9b)
+        || ip.to_ipv4().is_some())
+}
+
+/// Checks a group webhook `url` when it is registered and before each
+/// delivery: http or https, and every address its host resolves to public
+pub async fn check_url(url: &str) -> Result<(), &'static str> {
+    let url = Url::parse(url).map_err(|_| "Webhook url is invalid")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Webhook url must be http or https");
+    }
+    let host = url.host_str().ok_or("Webhook url is invalid")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| "Webhook host doesn't resolve")?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err("Webhook host doesn't resolve");
+    }
+    if !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err("Webhook url must resolve to a public address");
+    }
+    Ok(())
+}
+
+/// Resolves like the system does, minus the addresses that aren't public,
+/// so a name can't point somewhere else between `check_url` and connecting
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Client for group webhooks: public addresses only, no proxy that would
+/// resolve for us and no redirects that could lead back inside
+pub fn public_client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .dns_resolver(PublicResolver)
+        .redirect(Policy::none())
+        .no_proxy()
+        .build()
+        .expect("Failed to build webhook client")
+}
+
+#[cfg(test)]
+mod tests_target {
+    use std::net::IpAddr;
+
+    use crate::webhooks::target::{check_url, is_public};
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["93.184.215.14", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_url() {
+        assert!(check_url("https://93.184.215.14/hook").await.is_ok());
+        for url in [
+            "ftp://example.com/hook",
+            "not a url",
+            "http://127.0.0.1:3000/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/hook",
+            "http://[::ffff:10.0.0.1]/hook",
+            "http://10.0.0.1/hook",
+        ] {
+            assert!(check_url(url).await.is_err(), "{}", url);
+        }
+    }
+}
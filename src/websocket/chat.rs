@@ -1,10 +1,33 @@
-use std::{collections::HashMap, sync::Arc, time};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time,
+};
 
 use crate::{
     AppState,
-    auth::{extractors::AuthUser, user::User},
-    websocket::handler::validate_user,
+    auth::{
+        extractors::AuthUser,
+        user::User,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::{DomainEvent, EventBus},
+    message::repository::MessageRepository,
+    metrics::Channel,
+    moderation::{
+        handler::{Mute, Sanction, mute},
+        shadow_ban::ShadowBans,
+    },
+    websocket::{
+        compression::Compressor,
+        event::ServerEvent,
+        protocol::{ChatProtocol, Framing},
+    },
 };
+use async_graphql::SimpleObject;
 use axum::{
     extract::{
         State, WebSocketUpgrade,
@@ -19,10 +42,10 @@ use axum::{
 use futures::{SinkExt, StreamExt};
 use http::HeaderName;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::{RwLock, broadcast, mpsc};
+use tracing::Instrument;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, SimpleObject)]
 pub struct ChatMessage {
     pub sender_user: User,
     pub receiver_user: User,
@@ -32,12 +55,45 @@ pub struct ChatMessage {
 
 pub struct PrivateChatState {
     pub connections: RwLock<HashMap<String, broadcast::Sender<String>>>,
+    pub shadow_bans: Arc<ShadowBans>,
+    /// Where `send_to_user` keeps every message
+    pub messages: Arc<dyn MessageRepository>,
+    /// Messages `send_to_user` delivered but failed to store, see `/admin/stats`
+    unstored: AtomicU64,
 }
 
 impl PrivateChatState {
-    pub fn new() -> Self {
+    pub fn new(shadow_bans: Arc<ShadowBans>, messages: Arc<dyn MessageRepository>) -> Self {
         Self {
             connections: RwLock::new(HashMap::new()),
+            shadow_bans,
+            messages,
+            unstored: AtomicU64::new(0),
+        }
+    }
+
+    /// Messages missing from the history since startup
+    pub fn unstored(&self) -> u64 {
+        self.unstored.load(Ordering::Relaxed)
+    }
+
+    /// Subscribes to the user's private channel, creating it if needed.
+    /// WebSocket and SSE connections of the same user share one channel.
+    pub async fn subscribe(&self, user_id: &str) -> broadcast::Receiver<String> {
+        let mut connections = self.connections.write().await;
+        connections
+            .entry(user_id.to_string())
+            .or_insert_with(|| broadcast::channel(100).0)
+            .subscribe()
+    }
+
+    /// Drops the user's channel once no connection is listening on it anymore.
+    pub async fn unsubscribe(&self, user_id: &str) {
+        let mut connections = self.connections.write().await;
+        if let Some(tx) = connections.get(user_id)
+            && tx.receiver_count() == 0
+        {
+            connections.remove(user_id);
         }
     }
 }
@@ -54,16 +110,29 @@ pub async fn private_chat_handler(
         Some(v) => match v.to_str() {
             Ok(id) => id.to_string(),
             Err(_) => {
-                return (StatusCode::BAD_REQUEST, "Invalid recevier_id header format")
-                    .into_response();
+                return MetaResponse {
+                    code: StatusCode::BAD_REQUEST.to_i32(),
+                    message: "Invalid recevier_id header format".to_string(),
+                }
+                .into_response();
             }
         },
         None => {
-            return (StatusCode::BAD_REQUEST, "Missing receiver_id header").into_response();
+            return MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Missing receiver_id header".to_string(),
+            }
+            .into_response();
         }
     };
-    let sender_exists = validate_user(&sender_id, &state.pool).await;
-    let receiver_exists = validate_user(&receiver_id, &state.pool).await;
+    let sender_exists = state.user_cache.get_user(&sender_id, &state.pool).await;
+    let receiver_exists = state.user_cache.get_user(&receiver_id, &state.pool).await;
+
+    let (ws, protocol) = match ChatProtocol::negotiate(ws, &headers) {
+        Ok(negotiated) => negotiated,
+        Err(e) => return e.into_response(),
+    };
+    let compressor = Compressor::negotiate(&state.settings.ws_compression, &headers);
 
     let mut headers = HeaderMap::new();
     let token = format!("Bearer {}", sender_id);
@@ -73,15 +142,39 @@ pub async fn private_chat_handler(
     let receiver_header = HeaderValue::from_str(&receiver_id).expect("Invalid header value");
     headers.insert(HeaderName::from_static("receiver_id"), receiver_header);
 
+    let mute = mute(&state.pool, &sender_id).await.unwrap_or_default();
+    if compressor.is_some() {
+        let (name, value) = Compressor::header();
+        headers.insert(name, value);
+    }
+
     match (sender_exists, receiver_exists) {
         (Some(sender), Some(receiver)) => (
             headers.clone(),
-            ws.on_upgrade(move |socket| private_chat(socket, sender, receiver, state.chat.clone())),
+            ws.on_upgrade(move |socket| async move {
+                let _connection = state.metrics.connection(Channel::PrivateChat);
+                private_chat(
+                    socket,
+                    sender,
+                    receiver,
+                    mute,
+                    Framing {
+                        protocol,
+                        compressor,
+                    },
+                    state.chat.clone(),
+                    state.events.clone(),
+                )
+                .await
+            }),
         )
             .into_response(),
         _ => {
-            let mut resp =
-                (StatusCode::BAD_REQUEST, "Invalid user_id or receiver_id").into_response();
+            let mut resp = MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Invalid user_id or receiver_id".to_string(),
+            }
+            .into_response();
             for (k, v) in headers.iter() {
                 resp.headers_mut().append(k, v.clone());
             }
@@ -94,20 +187,32 @@ pub async fn private_chat(
     ws: WebSocket,
     sender_user: User,
     receiver_user: User,
+    mute: Mute,
+    framing: Framing,
     state: Arc<PrivateChatState>,
+    events: Arc<EventBus>,
 ) {
     let (mut sender, mut receiver) = ws.split();
 
-    let (tx, mut rx) = broadcast::channel(100);
-
-    {
-        let mut connections = state.connections.write().await;
-        connections.insert(sender_user.user_id.clone(), tx.clone());
-    }
+    let mut rx = state.subscribe(&sender_user.user_id).await;
+    let (notice_tx, mut notice_rx) = mpsc::unbounded_channel::<String>();
+    let mute = Arc::new(Mutex::new(mute));
+    let send_mute = mute.clone();
 
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg.into())).await.is_err() {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                Some(notice) = notice_rx.recv() => notice,
+            };
+            if let Some(sanction) = sanction(&msg) {
+                let mut mute = send_mute.lock().unwrap();
+                *mute = mute.extend(Mute::from(&sanction));
+            }
+            if sender.send(framing.outgoing(msg)).await.is_err() {
                 break;
             }
         }
@@ -121,8 +226,35 @@ pub async fn private_chat(
             if let Ok(msg) = msg {
                 match msg {
                     Message::Text(text) => {
-                        send_to_user(&state_clone, &sender_clone, &receiver_user, text.as_str())
-                            .await;
+                        let text = match framing.incoming(&text) {
+                            Ok(text) => text,
+                            Err(notice) => {
+                                let _ = notice_tx
+                                    .send(ServerEvent::Notice { message: notice }.to_json());
+                                continue;
+                            }
+                        };
+                        if mute.lock().unwrap().is_active() {
+                            let _ = notice_tx.send(
+                                ServerEvent::Notice {
+                                    message: "You are muted".to_string(),
+                                }
+                                .to_json(),
+                            );
+                            continue;
+                        }
+                        let span = tracing::info_span!(
+                            "ws.chat_message",
+                            from = %sender_clone.user_id,
+                            to = %receiver_user.user_id,
+                        );
+                        let message =
+                            send_to_user(&state_clone, &sender_clone, &receiver_user, &text)
+                                .instrument(span)
+                                .await;
+                        if !state_clone.shadow_bans.contains(&sender_clone.user_id) {
+                            events.publish(DomainEvent::MessageSent { message });
+                        }
                     }
 
                     Message::Close(_) => {
@@ -138,13 +270,13 @@ pub async fn private_chat(
 
     tokio::select! {
         _ = &mut send_task => recv_task.abort(),
-        _ = &mut recv_task => send_task.abort(),
+        _ = &mut recv_task => {
+            send_task.abort();
+            let _ = send_task.await;
+        }
     }
 
-    {
-        let mut connections = state.connections.write().await;
-        connections.remove(&sender_user.user_id.clone());
-    }
+    state.unsubscribe(&sender_user.user_id).await;
 }
 
 pub async fn send_to_user(
@@ -152,37 +284,63 @@ pub async fn send_to_user(
     sender_user: &User,
     receiver_user: &User,
     msg: &str,
-) {
-    let connections = state.connections.read().await;
+) -> ChatMessage {
+    let chat_message = chat_message(sender_user, receiver_user, msg);
+    // A shadow banned sender sees their message sent, the receiver never gets it
+    let delivered = !state.shadow_bans.contains(&sender_user.user_id);
+
+    // Kept for the history even when it can't be delivered now; a message
+    // that fails to store is still delivered, and the sender told so
+    let stored = match state.messages.add(&chat_message, !delivered).await {
+        Ok(_) => true,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to store chat message");
+            state.unstored.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    };
 
-    if let Some(tx) = connections.get(&receiver_user.user_id) {
-        let response = json_msg(sender_user, receiver_user, msg);
+    let connections = state.connections.read().await;
+    if delivered && let Some(tx) = connections.get(&receiver_user.user_id) {
+        let response = ServerEvent::ChatMessage(chat_message.clone()).to_json();
 
         let _ = tx.send(response);
     }
     if let Some(tx) = connections.get(&sender_user.user_id) {
-        let response = json_msg(sender_user, receiver_user, msg);
+        let response = ServerEvent::ChatMessage(chat_message.clone()).to_json();
         let _ = tx.send(response);
+        if !stored {
+            let notice = ServerEvent::Notice {
+                message: "Message sent but not saved to the history".to_string(),
+            };
+            let _ = tx.send(notice.to_json());
+        }
     }
+    chat_message
 }
 
-fn json_msg(sender_user: &User, receiver_user: &User, msg: &str) -> String {
+/// The sanction carried by a private channel payload, if that's what it is
+fn sanction(msg: &str) -> Option<Sanction> {
+    // Skip parsing the chat messages that make up most of the traffic
+    if !msg.starts_with(r#"{"type":"sanction""#) {
+        return None;
+    }
+    match serde_json::from_str::<ServerEvent>(msg) {
+        Ok(ServerEvent::Sanction(sanction)) => Some(sanction),
+        _ => None,
+    }
+}
+
+fn chat_message(sender_user: &User, receiver_user: &User, msg: &str) -> ChatMessage {
     let seconds = time::SystemTime::now()
         .duration_since(time::UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
-    let chat_message = ChatMessage {
+    ChatMessage {
         sender_user: sender_user.clone(),
         receiver_user: receiver_user.clone(),
         message: msg.to_string(),
         timestamp: seconds,
-    };
-
-    match serde_json::to_string(&chat_message) {
-        Ok(json) => json,
-        Err(e) => json!({
-            "error": format!("Failed to serialize message: {}",e.to_string())})
-        .to_string(),
     }
 }
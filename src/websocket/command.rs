@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::auth::user::User;
+
+/// State a command may read or change for the issuing connection
+pub struct CommandContext<'a> {
+    pub user: &'a User,
+    pub args: &'a str,
+    pub muted: &'a mut HashSet<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandOutput {
+    /// Sent to everyone in the group as a regular message from the issuer
+    Broadcast(String),
+    /// Sent only to the issuer
+    Ephemeral(String),
+}
+
+pub trait SlashCommand: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn usage(&self) -> &'static str;
+    fn run(&self, ctx: &mut CommandContext) -> CommandOutput;
+}
+
+pub struct CommandRegistry {
+    commands: HashMap<&'static str, Box<dyn SlashCommand>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+        }
+    }
+
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(MeCommand));
+        registry.register(Box::new(MuteCommand));
+        registry.register(Box::new(UnmuteCommand));
+        registry.register(Box::new(PollCommand));
+        registry
+    }
+
+    pub fn register(&mut self, command: Box<dyn SlashCommand>) {
+        self.commands.insert(command.name(), command);
+    }
+
+    /// Runs the command when `text` starts with `/`, returns None for plain messages
+    pub fn dispatch(
+        &self,
+        text: &str,
+        user: &User,
+        muted: &mut HashSet<String>,
+    ) -> Option<CommandOutput> {
+        let input = text.trim().strip_prefix('/')?;
+        let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+
+        let output = match self.commands.get(name) {
+            Some(command) => command.run(&mut CommandContext {
+                user,
+                args: args.trim(),
+                muted,
+            }),
+            None => {
+                let mut names: Vec<&str> = self.commands.keys().copied().collect();
+                names.sort();
+                CommandOutput::Ephemeral(format!(
+                    "Unknown command /{}. Available: /{}",
+                    name,
+                    names.join(", /")
+                ))
+            }
+        };
+        Some(output)
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct MeCommand;
+
+impl SlashCommand for MeCommand {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+
+    fn run(&self, ctx: &mut CommandContext) -> CommandOutput {
+        if ctx.args.is_empty() {
+            return CommandOutput::Ephemeral(format!("Usage: {}", self.usage()));
+        }
+        CommandOutput::Broadcast(format!("* {} {}", ctx.user.user_name, ctx.args))
+    }
+}
+
+pub struct MuteCommand;
+
+impl SlashCommand for MuteCommand {
+    fn name(&self) -> &'static str {
+        "mute"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/mute <user_name>"
+    }
+
+    fn run(&self, ctx: &mut CommandContext) -> CommandOutput {
+        if ctx.args.is_empty() {
+            return CommandOutput::Ephemeral(format!("Usage: {}", self.usage()));
+        }
+        ctx.muted.insert(ctx.args.to_string());
+        CommandOutput::Ephemeral(format!("Muted {}", ctx.args))
+    }
+}
+
+pub struct UnmuteCommand;
+
+impl SlashCommand for UnmuteCommand {
+    fn name(&self) -> &'static str {
+        "unmute"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/unmute <user_name>"
+    }
+
+    fn run(&self, ctx: &mut CommandContext) -> CommandOutput {
+        if ctx.muted.remove(ctx.args) {
+            CommandOutput::Ephemeral(format!("Unmuted {}", ctx.args))
+        } else {
+            CommandOutput::Ephemeral(format!("{} is not muted", ctx.args))
+        }
+    }
+}
+
+pub struct PollCommand;
+
+impl SlashCommand for PollCommand {
+    fn name(&self) -> &'static str {
+        "poll"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/poll <question> | <option> | <option> ..."
+    }
+
+    fn run(&self, ctx: &mut CommandContext) -> CommandOutput {
+        let parts: Vec<&str> = ctx
+            .args
+            .split('|')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .collect();
+        if parts.len() < 3 {
+            return CommandOutput::Ephemeral(format!("Usage: {}", self.usage()));
+        }
+
+        let options: Vec<String> = parts[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, option)| format!("{}) {}", i + 1, option))
+            .collect();
+        CommandOutput::Broadcast(format!(
+            "Poll by {}: {}\n{}",
+            ctx.user.user_name,
+            parts[0],
+            options.join("\n")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests_command {
+    use std::collections::HashSet;
+
+    use crate::{
+        auth::user::User,
+        websocket::command::{CommandOutput, CommandRegistry},
+    };
+
+    fn user() -> User {
+        User {
+            user_id: "user-1".to_string(),
+            user_name: "Jordan".to_string(),
+            email: "jordan@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plain_message_not_dispatched() {
+        let registry = CommandRegistry::with_defaults();
+        let mut muted = HashSet::new();
+        assert_eq!(registry.dispatch("hello /me", &user(), &mut muted), None);
+    }
+
+    #[test]
+    fn test_me_command() {
+        let registry = CommandRegistry::with_defaults();
+        let mut muted = HashSet::new();
+        assert_eq!(
+            registry.dispatch("/me waves", &user(), &mut muted),
+            Some(CommandOutput::Broadcast("* Jordan waves".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mute_and_unmute() {
+        let registry = CommandRegistry::with_defaults();
+        let mut muted = HashSet::new();
+        registry.dispatch("/mute Alice", &user(), &mut muted);
+        assert!(muted.contains("Alice"));
+        registry.dispatch("/unmute Alice", &user(), &mut muted);
+        assert!(muted.is_empty());
+    }
+
+    #[test]
+    fn test_poll_command() {
+        let registry = CommandRegistry::with_defaults();
+        let mut muted = HashSet::new();
+        assert_eq!(
+            registry.dispatch("/poll Lunch? | Pizza | Sushi", &user(), &mut muted),
+            Some(CommandOutput::Broadcast(
+                "Poll by Jordan: Lunch?\n1) Pizza\n2) Sushi".to_string()
+            ))
+        );
+        assert!(matches!(
+            registry.dispatch("/poll Lunch?", &user(), &mut muted),
+            Some(CommandOutput::Ephemeral(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let registry = CommandRegistry::with_defaults();
+        let mut muted = HashSet::new();
+        match registry.dispatch("/dance", &user(), &mut muted) {
+            Some(CommandOutput::Ephemeral(msg)) => {
+                assert!(msg.starts_with("Unknown command /dance"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
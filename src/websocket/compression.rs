@@ -0,0 +1,158 @@
+use std::io::Write;
+
+use axum::{
+    extract::ws::Message,
+    http::{HeaderMap, HeaderValue},
+};
+use flate2::{Compression, write::DeflateEncoder};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/// Request header a client sends to opt in, echoed back when accepted
+pub const COMPRESSION_HEADER: &str = "x-ws-compression";
+pub const DEFLATE: &str = "deflate";
+
+/// `[ws_compression]`. The WebSocket stack can't negotiate permessage-deflate,
+/// so compression is opted into with `x-ws-compression: deflate` instead.
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[serde(default)]
+pub struct CompressionSettings {
+    pub enabled: bool,
+    /// Messages shorter than this many bytes are sent as plain text
+    pub threshold_bytes: usize,
+    /// zlib level, 1 (fastest) to 9 (smallest)
+    #[validate(range(min = 1, max = 9, message = "must be between 1 and 9"))]
+    pub level: u32,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold_bytes: 1024,
+            level: 6,
+        }
+    }
+}
+
+/// A connection's outgoing compression. Each message is deflated on its own
+/// (no context takeover), so a connection holds no compressor state between
+/// messages and memory stays flat however many sockets are open.
+#[derive(Clone, Copy, Debug)]
+pub struct Compressor {
+    threshold: usize,
+    level: Compression,
+}
+
+impl Compressor {
+    /// The compressor for a client that sent `x-ws-compression: deflate`, if enabled
+    pub fn negotiate(settings: &CompressionSettings, headers: &HeaderMap) -> Option<Self> {
+        let offered = headers
+            .get(COMPRESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|encoding| encoding.trim().eq_ignore_ascii_case(DEFLATE))
+            });
+        (settings.enabled && offered).then(|| Self {
+            threshold: settings.threshold_bytes,
+            level: Compression::new(settings.level),
+        })
+    }
+
+    /// Header to add to the upgrade response once accepted
+    pub fn header() -> (&'static str, HeaderValue) {
+        (COMPRESSION_HEADER, HeaderValue::from_static(DEFLATE))
+    }
+
+    /// `text` as a binary frame of raw DEFLATE when it's long enough and
+    /// compression makes it smaller, as a text frame otherwise
+    pub fn message(&self, text: String) -> Message {
+        if text.len() < self.threshold {
+            return Message::Text(text.into());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+        match encoder
+            .write_all(text.as_bytes())
+            .and_then(|_| encoder.finish())
+        {
+            Ok(compressed) if compressed.len() < text.len() => Message::Binary(compressed.into()),
+            _ => Message::Text(text.into()),
+        }
+    }
+}
+
+/// Outgoing frame for `text` on a connection with or without compression
+pub fn outgoing(compressor: Option<&Compressor>, text: String) -> Message {
+    match compressor {
+        Some(compressor) => compressor.message(text),
+        None => Message::Text(text.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests_compression {
+    use std::io::Read;
+
+    use axum::{
+        extract::ws::Message,
+        http::{HeaderMap, HeaderValue},
+    };
+    use flate2::read::DeflateDecoder;
+
+    use crate::websocket::compression::{
+        COMPRESSION_HEADER, CompressionSettings, Compressor, outgoing,
+    };
+
+    fn headers(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COMPRESSION_HEADER, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let settings = CompressionSettings::default();
+        assert!(Compressor::negotiate(&settings, &headers("deflate")).is_some());
+        assert!(Compressor::negotiate(&settings, &headers("gzip, Deflate")).is_some());
+        assert!(Compressor::negotiate(&settings, &headers("gzip")).is_none());
+        assert!(Compressor::negotiate(&settings, &HeaderMap::new()).is_none());
+        let disabled = CompressionSettings {
+            enabled: false,
+            ..settings
+        };
+        assert!(Compressor::negotiate(&disabled, &headers("deflate")).is_none());
+    }
+
+    #[test]
+    fn test_message() {
+        let settings = CompressionSettings {
+            threshold_bytes: 64,
+            ..CompressionSettings::default()
+        };
+        let compressor = Compressor::negotiate(&settings, &headers("deflate")).unwrap();
+
+        let short = r#"{"type":"notice"}"#.to_string();
+        assert_eq!(
+            compressor.message(short.clone()),
+            Message::Text(short.into())
+        );
+
+        let long = format!(
+            r#"{{"type":"group_message","message":"{}"}}"#,
+            "hi ".repeat(200)
+        );
+        let Message::Binary(compressed) = compressor.message(long.clone()) else {
+            panic!("expected a compressed frame");
+        };
+        assert!(compressed.len() < long.len());
+        let mut inflated = String::new();
+        DeflateDecoder::new(&compressed[..])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, long);
+
+        assert_eq!(outgoing(None, long.clone()), Message::Text(long.into()));
+    }
+}
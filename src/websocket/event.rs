@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth::user::User,
+    moderation::handler::Sanction,
+    report::handler::ReportStatus,
+    websocket::{chat::ChatMessage, group::GroupMessage},
+};
+
+/// Envelope for every frame pushed to clients, over WebSocket or SSE
+///
+/// Serialized with a `type` tag next to the payload fields, e.g.
+/// `{"type":"group_message","id":"...","name":"...","message":"..."}`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Echo {
+        data: User,
+        message: String,
+    },
+    ChatMessage(ChatMessage),
+    GroupMessage(GroupMessage),
+    /// Reply addressed only to the connection that triggered it
+    Notice {
+        message: String,
+    },
+    /// A moderator muted or banned the connected user
+    Sanction(Sanction),
+    /// A report the connected user filed was acted on
+    ReportUpdated {
+        report_id: String,
+        status: ReportStatus,
+    },
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> String {
+        match serde_json::to_string(self) {
+            Ok(json) => json,
+            Err(e) => json!({
+                "error": format!("Failed to serialize message: {}", e),
+            })
+            .to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_event {
+    use crate::{
+        auth::user::User,
+        websocket::{chat::ChatMessage, event::ServerEvent, group::GroupMessage},
+    };
+
+    fn user(id: &str) -> User {
+        User {
+            user_id: id.to_string(),
+            user_name: format!("name-{}", id),
+            email: format!("{}@mail.com", id),
+            is_bot: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_echo_event() {
+        let event = ServerEvent::Echo {
+            data: user("1"),
+            message: "Hello".to_string(),
+        };
+        let json: serde_json::Value = serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json["type"], "echo");
+        assert_eq!(json["data"]["user_id"], "1");
+        assert_eq!(json["message"], "Hello");
+    }
+
+    #[test]
+    fn test_chat_message_event() {
+        let event = ServerEvent::ChatMessage(ChatMessage {
+            sender_user: user("1"),
+            receiver_user: user("2"),
+            message: "Hi".to_string(),
+            timestamp: 1,
+        });
+        let json: serde_json::Value = serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json["type"], "chat_message");
+        assert_eq!(json["sender_user"]["user_id"], "1");
+        assert_eq!(json["receiver_user"]["user_id"], "2");
+        assert_eq!(json["message"], "Hi");
+    }
+
+    #[test]
+    fn test_group_message_event() {
+        let event = ServerEvent::GroupMessage(GroupMessage {
+            id: "g1".to_string(),
+            name: "DevChat".to_string(),
+            message: "Hello group".to_string(),
+        });
+        let parsed: ServerEvent = serde_json::from_str(&event.to_json()).unwrap();
+        match parsed {
+            ServerEvent::GroupMessage(msg) => assert_eq!(msg.id, "g1"),
+            _ => panic!("expected group message"),
+        }
+    }
+}
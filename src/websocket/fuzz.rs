@@ -0,0 +1,291 @@
+//! Property-based fuzzing of the WebSocket protocol. Every case opens a real
+//! connection, throws arbitrary frames at it and then checks that the
+//! connection still answers and that nothing is left behind in the
+//! connection registries once it closes.
+//!
+//! Cases per target default to a small number so `cargo test` stays quick;
+//! set `PROPTEST_CASES` for a longer run.
+
+use std::{collections::HashSet, future::Future, sync::Arc, time::Duration};
+
+use axum_test::{TestServer, TestWebSocket, WsMessage};
+use proptest::{
+    prelude::*,
+    test_runner::{Config, TestRunner},
+};
+use tokio::runtime::Runtime;
+
+use crate::{
+    AppState,
+    auth::{
+        jwt::create_access_token,
+        user::{NewUser, User, add},
+        util::{hash_password, random_name},
+    },
+    routes::routes,
+    websocket::{command::CommandRegistry, event::ServerEvent},
+};
+
+/// Frames per connection stay below the capacity of the broadcast channels
+/// so a well-behaved client is never dropped for lagging
+const MAX_FRAMES: usize = 32;
+const DEFAULT_CASES: u32 = 16;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const SENTINEL: &str = "fuzz-sentinel";
+
+fn frame() -> impl Strategy<Value = WsMessage> {
+    prop_oneof![
+        any::<String>().prop_map(|text| WsMessage::Text(text.into())),
+        envelope().prop_map(|text| WsMessage::Text(text.into())),
+        command().prop_map(|text| WsMessage::Text(text.into())),
+        (0usize..256 * 1024).prop_map(|size| WsMessage::Text("x".repeat(size).into())),
+        prop::collection::vec(any::<u8>(), 0..4096).prop_map(|data| WsMessage::Binary(data.into())),
+        prop::collection::vec(any::<u8>(), 0..125).prop_map(|data| WsMessage::Ping(data.into())),
+        prop::collection::vec(any::<u8>(), 0..125).prop_map(|data| WsMessage::Pong(data.into())),
+    ]
+}
+
+/// `ServerEvent`-shaped JSON with unknown tags, wrong field types and
+/// truncated bodies
+fn envelope() -> impl Strategy<Value = String> {
+    let tag = prop_oneof![
+        Just("echo".to_string()),
+        Just("chat_message".to_string()),
+        Just("group_message".to_string()),
+        Just("notice".to_string()),
+        Just("sanction".to_string()),
+        Just("report_updated".to_string()),
+        "[a-z_]{0,16}",
+    ];
+    let value = prop_oneof![
+        Just("null".to_string()),
+        any::<i64>().prop_map(|n| n.to_string()),
+        any::<String>().prop_map(|s| serde_json::to_string(&s).unwrap()),
+        Just("{}".to_string()),
+        Just("[[[[[[[[".to_string()),
+    ];
+    (tag, "[a-z_]{1,12}", value, 0usize..64).prop_map(|(tag, field, value, cut)| {
+        let json = format!(r#"{{"type":"{}","{}":{}}}"#, tag, field, value);
+        let cut = json.len().saturating_sub(cut);
+        let cut = (0..=cut)
+            .rev()
+            .find(|i| json.is_char_boundary(*i))
+            .unwrap_or(0);
+        json[..cut].to_string()
+    })
+}
+
+fn command() -> impl Strategy<Value = String> {
+    let name = prop_oneof![
+        Just("me".to_string()),
+        Just("mute".to_string()),
+        Just("unmute".to_string()),
+        Just("poll".to_string()),
+        "[a-z]{0,8}",
+    ];
+    (r"\s{0,2}", name, any::<String>())
+        .prop_map(|(lead, name, args)| format!("{}/{} {}", lead, name, args))
+}
+
+fn frames() -> impl Strategy<Value = Vec<WsMessage>> {
+    prop::collection::vec(frame(), 0..MAX_FRAMES)
+}
+
+fn runner() -> TestRunner {
+    let cases = std::env::var("PROPTEST_CASES")
+        .ok()
+        .and_then(|cases| cases.parse().ok())
+        .unwrap_or(DEFAULT_CASES);
+    TestRunner::new(Config::with_cases(cases))
+}
+
+async fn new_user(state: &AppState) -> (User, String) {
+    let user_name = random_name();
+    let email = format!("{}.example.@mail.com", user_name);
+    let hash = hash_password("123456".to_string()).unwrap();
+    let user = add(&state.pool, NewUser::new(user_name, email, hash))
+        .await
+        .unwrap();
+    let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+    (user, token)
+}
+
+async fn send_all(ws: &mut TestWebSocket, frames: Vec<WsMessage>) {
+    for frame in frames {
+        ws.send_message(frame).await;
+    }
+}
+
+/// Reads until a text frame satisfies `done`, failing on a stalled connection
+async fn receive_until(ws: &mut TestWebSocket, done: impl Fn(&ServerEvent) -> bool) {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let WsMessage::Text(text) = ws.receive_message().await
+                && let Ok(event) = serde_json::from_str::<ServerEvent>(&text)
+                && done(&event)
+            {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("connection stopped answering");
+}
+
+/// Waits for the server side of a closed connection to clean up after itself
+async fn eventually(check: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    check()
+}
+
+fn fuzz<S, F, Fut>(setup: impl Future<Output = S>, case: F)
+where
+    F: Fn(Arc<S>, Vec<WsMessage>) -> Fut,
+    Fut: Future<Output = Result<(), TestCaseError>>,
+{
+    let rt = Runtime::new().unwrap();
+    let ctx = Arc::new(rt.block_on(setup));
+    runner()
+        .run(&frames(), |frames| rt.block_on(case(ctx.clone(), frames)))
+        .unwrap();
+}
+
+struct Ctx {
+    state: Arc<AppState>,
+    server: TestServer,
+    user: User,
+    token: String,
+    header: &'static str,
+    target: String,
+}
+
+async fn ctx(header: &'static str) -> Ctx {
+    let state = Arc::new(AppState::test().await);
+    let (user, token) = new_user(&state).await;
+    let target = match header {
+        "receiver_id" => new_user(&state).await.0.user_id,
+        "group_id" => {
+            state
+                .groups
+                .create(&random_name(), "", None, &user.user_id)
+                .await
+                .unwrap()
+                .group_id
+        }
+        _ => String::new(),
+    };
+    let server = TestServer::builder()
+        .http_transport()
+        .build(routes(state.clone()))
+        .expect("Failed start server");
+    Ctx {
+        state,
+        server,
+        user,
+        token,
+        header,
+        target,
+    }
+}
+
+async fn connect(ctx: &Ctx, path: &str) -> TestWebSocket {
+    let mut request = ctx
+        .server
+        .get_websocket(path)
+        .add_header("Authorization", format!("Bearer {}", ctx.token));
+    if !ctx.header.is_empty() {
+        request = request.add_header(ctx.header, ctx.target.clone());
+    }
+    request.await.into_websocket().await
+}
+
+#[test]
+fn fuzz_handle_socket() {
+    fuzz(ctx(""), |ctx, frames| async move {
+        let path = format!("/ws?user_id={}", ctx.user.user_id);
+        let mut ws = connect(&ctx, &path).await;
+        send_all(&mut ws, frames).await;
+        ws.send_text(SENTINEL).await;
+        receive_until(
+            &mut ws,
+            |event| matches!(event, ServerEvent::Echo { message, .. } if message == SENTINEL),
+        )
+        .await;
+        ws.close().await;
+        Ok(())
+    });
+}
+
+#[test]
+fn fuzz_private_chat() {
+    fuzz(ctx("receiver_id"), |ctx, frames| async move {
+        let mut ws = connect(&ctx, "/chat").await;
+        send_all(&mut ws, frames).await;
+        ws.send_text(SENTINEL).await;
+        receive_until(
+            &mut ws,
+            |event| matches!(event, ServerEvent::ChatMessage(chat) if chat.message == SENTINEL),
+        )
+        .await;
+        ws.close().await;
+
+        let chat = ctx.state.chat.clone();
+        let cleaned = eventually(|| {
+            chat.connections
+                .try_read()
+                .is_ok_and(|connections| connections.is_empty())
+        })
+        .await;
+        prop_assert!(cleaned, "private chat channel left registered");
+        Ok(())
+    });
+}
+
+#[test]
+fn fuzz_group_chat() {
+    fuzz(ctx("group_id"), |ctx, frames| async move {
+        let mut ws = connect(&ctx, "/group-chat").await;
+        send_all(&mut ws, frames).await;
+        ws.send_text(SENTINEL).await;
+        receive_until(
+            &mut ws,
+            |event| matches!(event, ServerEvent::GroupMessage(group) if group.message == SENTINEL),
+        )
+        .await;
+        ws.close().await;
+
+        let cleaned = eventually(|| ctx.state.group.tx.receiver_count() == 0).await;
+        prop_assert!(cleaned, "group chat subscriber left behind");
+        Ok(())
+    });
+}
+
+proptest! {
+    #[test]
+    fn fuzz_command_dispatch(text in prop_oneof![any::<String>(), command()]) {
+        let registry = CommandRegistry::with_defaults();
+        let user = User {
+            user_id: "fuzz".to_string(),
+            user_name: "fuzz".to_string(),
+            email: "fuzz@mail.com".to_string(),
+            is_bot: false,
+            ..Default::default()
+        };
+        let mut muted = HashSet::new();
+        let output = registry.dispatch(&text, &user, &mut muted);
+        prop_assert_eq!(output.is_some(), text.trim().starts_with('/'));
+    }
+
+    #[test]
+    fn fuzz_server_event_parsing(text in prop_oneof![any::<String>(), envelope()]) {
+        if let Ok(event) = serde_json::from_str::<ServerEvent>(&text) {
+            let json = event.to_json();
+            prop_assert!(serde_json::from_str::<ServerEvent>(&json).is_ok());
+        }
+    }
+}
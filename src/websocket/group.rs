@@ -1,8 +1,31 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
 use crate::auth::extractors::AuthUser;
-use crate::group::handler::{Group, get_by_id};
-use crate::{AppState, auth::user::User, websocket::handler::validate_user};
+use crate::auth::util::{MetaResponse, StatusCodeExt};
+use crate::group::handler::Group;
+use crate::{
+    AppState,
+    auth::user::User,
+    event_bus::DomainEvent,
+    metrics::Channel,
+    moderation::{
+        self,
+        handler::{Mute, Sanction, SanctionKind, is_banned},
+        shadow_ban::ShadowBans,
+        word_filter::{Filtered, WordFilterCache, report_match},
+    },
+    organization::handler::member_role,
+    websocket::{
+        command::{CommandOutput, CommandRegistry},
+        compression::Compressor,
+        event::ServerEvent,
+        protocol::{ChatProtocol, Framing},
+    },
+};
+use async_graphql::SimpleObject;
 use axum::{
     extract::{
         State, WebSocketUpgrade,
@@ -16,10 +39,9 @@ use axum::{
 };
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, SimpleObject)]
 pub struct GroupMessage {
     pub id: String,
     pub name: String,
@@ -28,12 +50,23 @@ pub struct GroupMessage {
 
 pub struct GroupState {
     pub tx: broadcast::Sender<String>,
+    pub commands: CommandRegistry,
+    /// Sanctions imposed by moderators; each connection picks out its user's
+    pub sanctions: broadcast::Sender<Sanction>,
+    pub shadow_bans: Arc<ShadowBans>,
+    pub word_filters: WordFilterCache,
 }
 
 impl GroupState {
-    pub fn new() -> Self {
+    pub fn new(shadow_bans: Arc<ShadowBans>) -> Self {
         let (tx, _rx) = broadcast::channel(100);
-        Self { tx }
+        Self {
+            tx,
+            commands: CommandRegistry::with_defaults(),
+            sanctions: broadcast::channel(16).0,
+            shadow_bans,
+            word_filters: WordFilterCache::default(),
+        }
     }
 }
 
@@ -47,18 +80,48 @@ pub async fn group_chat_handler(
         Some(v) => match v.to_str() {
             Ok(id) => id.to_string(),
             Err(_) => {
-                return (StatusCode::BAD_REQUEST, "Invalid group_id header").into_response();
+                return MetaResponse {
+                    code: StatusCode::BAD_REQUEST.to_i32(),
+                    message: "Invalid group_id header".to_string(),
+                }
+                .into_response();
             }
         },
         None => {
-            return (StatusCode::BAD_REQUEST, "Missing group_id header").into_response();
+            return MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Missing group_id header".to_string(),
+            }
+            .into_response();
         }
     };
 
-    let user_id_exists = validate_user(&user.user_id, &state.pool).await;
-    let group_id_exists = get_by_id(&state.pool, &group_id).await;
+    let user_id_exists = state.user_cache.get_user(&user.user_id, &state.pool).await;
+    let group_id_exists = state.groups.get_by_id(&group_id).await;
 
+    if let Some(group) = &group_id_exists
+        && let Err(e) = admit(&state, &user.user_id, group).await
+    {
+        return e.into_response();
+    }
+    let mute = moderation::handler::mute(&state.pool, &user.user_id)
+        .await
+        .unwrap_or_default();
+
+    let (ws, protocol) = match ChatProtocol::negotiate(ws, &headers) {
+        Ok(negotiated) => negotiated,
+        Err(e) => return e.into_response(),
+    };
+    let compressor = Compressor::negotiate(&state.settings.ws_compression, &headers);
+    let framing = Framing {
+        protocol,
+        compressor,
+    };
     let mut response_header = HeaderMap::new();
+    if compressor.is_some() {
+        let (name, value) = Compressor::header();
+        response_header.insert(name, value);
+    }
 
     let token = format!("Bearer {}", user.user_id);
     let header_token = HeaderValue::from_str(&token).expect("Invalid header value");
@@ -69,11 +132,18 @@ pub async fn group_chat_handler(
     match (user_id_exists, group_id_exists) {
         (Some(user), Some(group)) => (
             response_header.clone(),
-            ws.on_upgrade(move |socket| group_chat(socket, user, group, state.group.clone())),
+            ws.on_upgrade(move |socket| async move {
+                let _connection = state.metrics.connection(Channel::GroupChat);
+                group_chat(socket, user, group, mute, framing, state.clone()).await
+            }),
         )
             .into_response(),
         _ => {
-            let mut resp = (StatusCode::BAD_REQUEST, "Invalid group_id or user_id").into_response();
+            let mut resp = MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Invalid group_id or user_id".to_string(),
+            }
+            .into_response();
             for (k, v) in response_header.iter() {
                 resp.headers_mut().append(k, v.clone());
             }
@@ -83,45 +153,116 @@ pub async fn group_chat_handler(
     }
 }
 
-pub async fn group_chat(ws: WebSocket, user: User, group: Group, state: Arc<GroupState>) {
+/// Lets a user into a group, unless it belongs to an organization they
+/// aren't a member of or they are banned from it, and loads its word filters
+pub async fn admit(state: &AppState, user_id: &str, group: &Group) -> Result<(), MetaResponse> {
+    if let Some(org_id) = &group.org_id
+        && !matches!(member_role(&state.pool, org_id, user_id).await, Ok(Some(_)))
+    {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "Not a member of this organization".to_string(),
+        });
+    }
+
+    if let Ok(true) = is_banned(&state.pool, user_id, &group.group_id).await {
+        return Err(MetaResponse {
+            code: StatusCode::FORBIDDEN.to_i32(),
+            message: "You are banned from this group".to_string(),
+        });
+    }
+    if let Err(e) = state
+        .group
+        .word_filters
+        .load(&state.pool, &group.group_id)
+        .await
+    {
+        tracing::warn!(group_id = %group.group_id, error = %e, "Failed to load word filters");
+    }
+    Ok(())
+}
+
+pub async fn group_chat(
+    ws: WebSocket,
+    user: User,
+    group: Group,
+    mute: Mute,
+    framing: Framing,
+    app: Arc<AppState>,
+) {
+    let state = app.group.clone();
     let (mut sender, mut receiver) = ws.split();
+    let group_id = group.group_id.clone();
 
     let mut rx = state.tx.subscribe();
-    let msg = format!(
-        "Welcome {} to {}",
-        user.user_name.clone(),
-        group.name.clone(),
-    );
-    let group_msg = GroupMessage {
-        id: group.group_id,
-        name: group.name,
-        message: msg.to_string(),
-    };
-    let response = serde_msg(&group_msg);
-    let _ = state.tx.clone().send(response);
+    let mut sanctions = state.sanctions.subscribe();
+    join(&app, &user, &group);
+
+    let (notice_tx, mut notice_rx) = mpsc::unbounded_channel::<String>();
+    let muted = Arc::new(Mutex::new(HashSet::new()));
+    let send_muted = muted.clone();
+    let mute = Arc::new(Mutex::new(mute));
+    let send_mute = mute.clone();
+    let (user_id, banned_group) = (user.user_id.clone(), group_id.clone());
 
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg.into())).await.is_err() {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) if is_muted(&msg, &send_muted) => continue,
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                Some(notice) = notice_rx.recv() => notice,
+                Ok(sanction) = sanctions.recv() => {
+                    if sanction.user_id != user_id {
+                        continue;
+                    }
+                    let banned = sanction.kind == SanctionKind::GroupBan
+                        && sanction.group_id.as_ref() == Some(&banned_group);
+                    {
+                        let mut mute = send_mute.lock().unwrap();
+                        *mute = mute.extend(Mute::from(&sanction));
+                    }
+                    let msg = ServerEvent::Sanction(sanction).to_json();
+                    if banned {
+                        let _ = sender.send(framing.outgoing(msg)).await;
+                        let _ = sender.send(Message::Close(None)).await;
+                        break;
+                    }
+                    msg
+                },
+            };
+            if sender.send(framing.outgoing(msg)).await.is_err() {
                 break;
             }
         }
     });
 
-    let state_clone = state.tx.clone();
-
     let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
             if let Ok(msg) = msg {
                 match msg {
                     Message::Text(text) => {
-                        let group_msg = GroupMessage {
-                            id: user.user_id.clone(),
-                            name: user.user_name.clone(),
-                            message: text.to_string(),
+                        let text = match framing.incoming(&text) {
+                            Ok(text) => text,
+                            Err(notice) => {
+                                let _ = notice_tx
+                                    .send(ServerEvent::Notice { message: notice }.to_json());
+                                continue;
+                            }
                         };
-                        let response = serde_msg(&group_msg);
-                        let _ = state_clone.send(response);
+                        match post(&app, &user, &group_id, text, &muted, &mute) {
+                            Posted::Notice(notice) => {
+                                let _ = notice_tx
+                                    .send(ServerEvent::Notice { message: notice }.to_json());
+                            }
+                            // Shadow banned users only see their own messages
+                            Posted::Hidden(group_msg) => {
+                                let _ = notice_tx.send(serde_msg(&group_msg));
+                            }
+                            Posted::Sent(_) => {}
+                        }
                     }
 
                     Message::Close(_) => {
@@ -141,14 +282,109 @@ pub async fn group_chat(ws: WebSocket, user: User, group: Group, state: Arc<Grou
     }
 }
 
-pub fn serde_msg(group_msg: &GroupMessage) -> String {
-    let response = match serde_json::to_string(&group_msg) {
-        Ok(json) => json,
-        Err(e) => json!({
-            "error": format!("Failed to serialize message: {}",e.to_string()),
-            "message": group_msg.message.to_string(),
-        })
-        .to_string(),
+/// Announces the user to the group, as a `MemberJoined` event and a
+/// welcome message
+pub fn join(app: &AppState, user: &User, group: &Group) {
+    app.events.publish(DomainEvent::MemberJoined {
+        group_id: group.group_id.clone(),
+        user: user.clone(),
+    });
+    let group_msg = GroupMessage {
+        id: group.group_id.clone(),
+        name: group.name.clone(),
+        message: format!("Welcome {} to {}", user.user_name, group.name),
+    };
+    let _ = app.group.tx.send(serde_msg(&group_msg));
+}
+
+/// What became of a message a member sent to a group
+pub enum Posted {
+    /// Only for the sender: a command's reply, or why the message was refused
+    Notice(String),
+    /// Only shown to the sender, who is shadow banned
+    Hidden(GroupMessage),
+    /// Broadcast to the group
+    Sent(GroupMessage),
+}
+
+/// Runs a member's message through the slash commands, their mute and the
+/// group's word filters, then broadcasts it
+pub fn post(
+    app: &Arc<AppState>,
+    user: &User,
+    group_id: &str,
+    text: String,
+    muted: &Mutex<HashSet<String>>,
+    mute: &Mutex<Mute>,
+) -> Posted {
+    let state = &app.group;
+    let _span = tracing::info_span!(
+        "ws.group_message",
+        group_id = %group_id,
+        user_id = %user.user_id,
+    )
+    .entered();
+    let output = {
+        let mut muted = muted.lock().unwrap();
+        state.commands.dispatch(&text, user, &mut muted)
+    };
+    let message = match output {
+        Some(CommandOutput::Ephemeral(notice)) => return Posted::Notice(notice),
+        Some(CommandOutput::Broadcast(message)) => message,
+        None => text,
+    };
+    if mute.lock().unwrap().is_active() {
+        return Posted::Notice("You are muted".to_string());
+    }
+    let filtered = state
+        .word_filters
+        .get(group_id)
+        .map(|filter| filter.apply(&message));
+    let message = match filtered {
+        Some(Filtered::Blocked { notify }) => {
+            report_match(app, &user.user_id, group_id, &message, notify);
+            return Posted::Notice(
+                "Your message was blocked by the group's word filter".to_string(),
+            );
+        }
+        Some(Filtered::Masked {
+            message: masked,
+            notify,
+        }) => {
+            report_match(app, &user.user_id, group_id, &message, notify);
+            masked
+        }
+        Some(Filtered::Pass) | None => message,
+    };
+
+    let group_msg = GroupMessage {
+        id: user.user_id.clone(),
+        name: user.user_name.clone(),
+        message,
     };
-    response
+    if state.shadow_bans.contains(&user.user_id) {
+        return Posted::Hidden(group_msg);
+    }
+    let _ = state.tx.send(serde_msg(&group_msg));
+    app.events.publish(DomainEvent::GroupMessageCreated {
+        group_id: group_id.to_string(),
+        message: group_msg.clone(),
+    });
+    Posted::Sent(group_msg)
+}
+
+/// True when the payload is a group message from a user the connection muted
+pub fn is_muted(msg: &str, muted: &Mutex<HashSet<String>>) -> bool {
+    let muted = muted.lock().unwrap();
+    if muted.is_empty() {
+        return false;
+    }
+    match serde_json::from_str::<ServerEvent>(msg) {
+        Ok(ServerEvent::GroupMessage(group_msg)) => muted.contains(&group_msg.name),
+        _ => false,
+    }
+}
+
+pub fn serde_msg(group_msg: &GroupMessage) -> String {
+    ServerEvent::GroupMessage(group_msg.clone()).to_json()
 }
@@ -7,20 +7,34 @@
 /// - Message routing and processing for different message types
 /// - Connection lifecycle management (open, process, close)
 use axum::{
-    body::Body,
     extract::{
         Query, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
-    response::{IntoResponse, Response},
+    response::IntoResponse,
 };
 use futures::{SinkExt, StreamExt};
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use serde::Deserialize;
 use sqlx::{Pool, Postgres, Row, postgres::PgRow};
 use std::sync::Arc;
 
-use crate::{AppState, auth::user::User};
+use crate::{
+    AppState,
+    auth::{
+        user::User,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    metrics::Channel,
+    websocket::{
+        compression::Compressor,
+        event::ServerEvent,
+        protocol::{ChatProtocol, Framing},
+    },
+};
+
+/// Largest payload a control frame may carry (RFC 6455, section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
 
 /// Query parameter struct for WebSocket connection
 ///
@@ -55,18 +69,37 @@ pub struct WsQuery {
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Query(query): Query<WsQuery>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    let user_exists = validate_user(&query.user_id, &state.pool).await;
+    let (ws, protocol) = match ChatProtocol::negotiate(ws, &headers) {
+        Ok(negotiated) => negotiated,
+        Err(e) => return e.into_response(),
+    };
+    let user_exists = state.user_cache.get_user(&query.user_id, &state.pool).await;
     match user_exists {
-        Some(user) => ws.on_upgrade(move |socket| handle_socket(socket, query.user_id, user)),
-        None => {
-            return Response::builder()
-                .status(StatusCode::UNAUTHORIZED.as_u16())
-                .body(Body::from("Unauthorized: Invalid user_id"))
-                .unwrap()
-                .into_response();
+        Some(user) => {
+            let metrics = state.metrics.clone();
+            let compressor = Compressor::negotiate(&state.settings.ws_compression, &headers);
+            let framing = Framing {
+                protocol,
+                compressor,
+            };
+            let mut response = ws.on_upgrade(move |socket| async move {
+                let _connection = metrics.connection(Channel::Echo);
+                handle_socket(socket, query.user_id, user, framing).await
+            });
+            if compressor.is_some() {
+                let (name, value) = Compressor::header();
+                response.headers_mut().insert(name, value);
+            }
+            response
+        }
+        None => MetaResponse {
+            code: StatusCode::UNAUTHORIZED.to_i32(),
+            message: "Unauthorized: Invalid user_id".to_string(),
         }
+        .into_response(),
     }
 }
 
@@ -88,28 +121,27 @@ pub async fn ws_handler(
 /// - None: If user is not found or query fails
 ///
 /// Example Usage:
-/// ```rust
+/// ```rust,ignore
 /// let user = validate_user("user-123", &pool).await;
 /// if let Some(user) = user {
 ///     println!("User {} is valid", user.user_name);
 /// }
 /// ```
+#[tracing::instrument(name = "db.users.validate", skip(pool))]
 pub async fn validate_user(user_id: &str, pool: &Pool<Postgres>) -> Option<User> {
-    let sql = "select user_id, user_name, email from users where user_id = $1";
-    let result = sqlx::query(sql)
+    let sql = "select user_id, user_name, email, is_bot from users where user_id = $1";
+    sqlx::query(sql)
         .bind(user_id)
         .map(|data: PgRow| User {
             user_name: data.get("user_name"),
             email: data.get("email"),
             user_id: data.get("user_id"),
+            is_bot: data.get("is_bot"),
+            ..Default::default()
         })
         .fetch_optional(pool)
         .await
-        .unwrap();
-    match result {
-        Some(data) => Some(data.clone()),
-        None => None,
-    }
+        .unwrap()
 }
 
 /// Handles WebSocket communication for a connected user
@@ -122,9 +154,10 @@ pub async fn validate_user(user_id: &str, pool: &Pool<Postgres>) -> Option<User>
 ///
 /// Message Types Handled:
 /// - **Message::Text**: Client sends text data
-///   → Returns JSON response with user data and the received text
+///   → Returns a `ServerEvent::Echo` JSON text frame with user data and the received text
 /// - **Message::Binary**: Client sends binary data
-///   → Responds with Pong frame
+///   → Responds with Pong frame carrying the data, or an empty one when the data
+///   is over the 125 bytes a control frame may hold
 /// - **Message::Ping**: Client sends ping (keep-alive)
 ///   → Responds with pong frame to keep connection alive
 /// - **Message::Close**: Client closes connection
@@ -145,16 +178,16 @@ pub async fn validate_user(user_id: &str, pool: &Pool<Postgres>) -> Option<User>
 /// - `user`: User struct containing user details (user_name, email)
 ///
 /// Example Message Flow:
-/// ```
+/// ```text
 /// Client → Server: "Hello"
-/// Server → Client: {"data":User{...},"message":"Hello"}
+/// Server → Client: {"type":"echo","data":{"user_id":"...",...},"message":"Hello"}
 /// ```
-pub async fn handle_socket(socket: WebSocket, user_id: String, user: User) {
+pub async fn handle_socket(socket: WebSocket, user_id: String, user: User, framing: Framing) {
     // Split the WebSocket into sender (tx) and receiver (rx) halves
     // This allows concurrent sending and receiving of messages
     let (mut sender, mut receiver) = socket.split();
 
-    println!("WebSocket connection established for user_id: {}", user_id);
+    tracing::info!(user_id, "WebSocket connection established");
 
     // Send a welcome message to the client immediately after connection
     // This confirms the connection is active and authenticated
@@ -167,46 +200,123 @@ pub async fn handle_socket(socket: WebSocket, user_id: String, user: User) {
     // - Connection error occurs
     // - Client disconnects
     while let Some(msg) = receiver.next().await {
-        if let Ok(msg) = msg {
-            match msg {
-                // Handle text messages from client
-                // Return a JSON response containing user info and echoed message
-                Message::Text(text) => {
-                    let response = format!(
-                        r#"{{"type":"echo","data":"{:?}","message":"{}"}}"#,
-                        user, text
-                    );
-                    if sender.send(Message::Binary(response.into())).await.is_err() {
-                        break;
-                    }
+        match msg {
+            // Handle text messages from client
+            // Return a JSON response containing user info and echoed message
+            Ok(Message::Text(text)) => {
+                let response = match framing.incoming(&text) {
+                    Ok(message) => ServerEvent::Echo {
+                        data: user.clone(),
+                        message,
+                    },
+                    Err(message) => ServerEvent::Notice { message },
                 }
-                // Handle binary messages from client
-                // Respond with a pong frame to acknowledge receipt
-                Message::Binary(data) => {
-                    if sender.send(Message::Pong(data)).await.is_err() {
-                        break;
-                    }
+                .to_json();
+                if sender.send(framing.outgoing(response)).await.is_err() {
+                    break;
+                }
+            }
+            // Handle binary messages from client
+            // Respond with a pong frame to acknowledge receipt
+            Ok(Message::Binary(mut data)) => {
+                if data.len() > MAX_CONTROL_PAYLOAD {
+                    data.clear();
                 }
-                // Handle explicit close message from client
-                // Log the disconnection and terminate the connection
-                Message::Close(_) => {
-                    println!("User {} disconnected", user_id);
+                if sender.send(Message::Pong(data)).await.is_err() {
                     break;
                 }
-                // Handle ping frames (keep-alive check from client)
-                // Respond with pong to keep connection alive
-                Message::Ping(data) => {
-                    if sender.send(Message::Pong(data)).await.is_err() {
-                        break;
-                    }
+            }
+            // Handle explicit close message from client
+            // Log the disconnection and terminate the connection
+            Ok(Message::Close(_)) => {
+                tracing::info!(user_id, "User disconnected");
+                break;
+            }
+            // Handle ping frames (keep-alive check from client)
+            // Respond with pong to keep connection alive
+            Ok(Message::Ping(data)) => {
+                if sender.send(Message::Pong(data)).await.is_err() {
+                    break;
                 }
-                // Ignore other message types (reserved frames, etc.)
-                _ => {}
             }
-        } else {
+            // Ignore other message types (reserved frames, etc.)
+            Ok(_) => {}
             // If message parsing fails or connection error, break loop
-            break;
+            Err(_) => break,
+        }
+    }
+    tracing::info!(user_id, "WebSocket connection closed");
+}
+
+#[cfg(test)]
+mod tests_ws_handler {
+    use std::sync::Arc;
+
+    use axum::{Router, routing::get};
+    use axum_test::TestServer;
+
+    use crate::{
+        AppState,
+        auth::{
+            user::{NewUser, add},
+            util::{hash_password, random_name},
+        },
+        websocket::{event::ServerEvent, handler::ws_handler},
+    };
+
+    #[tokio::test]
+    async fn test_ws_echo() {
+        let state = Arc::new(AppState::test().await);
+
+        let user_name = random_name();
+        let email = format!("{}.example.@mail.com", user_name);
+        let hash = hash_password("123456".to_string()).unwrap();
+        let new_user = NewUser::new(user_name.clone(), email, hash);
+        let user = add(&state.pool, new_user).await.unwrap();
+
+        let app = Router::new()
+            .route("/ws", get(ws_handler))
+            .with_state(state);
+        let server = TestServer::builder()
+            .http_transport()
+            .build(app)
+            .expect("Failed start server");
+
+        let mut ws = server
+            .get_websocket(&format!("/ws?user_id={}", user.user_id))
+            .await
+            .into_websocket()
+            .await;
+        ws.assert_receive_text(format!("Welcome, user_id: {}!", user.user_id))
+            .await;
+
+        ws.send_text("Hello").await;
+        match ws.receive_json::<ServerEvent>().await {
+            ServerEvent::Echo { data, message } => {
+                assert_eq!(data.user_id, user.user_id);
+                assert_eq!(data.user_name, user_name);
+                assert_eq!(message, "Hello");
+            }
+            _ => panic!("expected echo event"),
         }
     }
-    println!("🔴 WebSocket connection closed for user: {}", user_id);
+
+    #[tokio::test]
+    async fn test_ws_invalid_user() {
+        let state = Arc::new(AppState::test().await);
+
+        let app = Router::new()
+            .route("/ws", get(ws_handler))
+            .with_state(state);
+        let server = TestServer::builder()
+            .http_transport()
+            .build(app)
+            .expect("Failed start server");
+
+        let response = server
+            .get_websocket("/ws?user_id=unknown")
+            .expect_failure()
+            .await;
+        response.assert_status_unauthorized();
+    }
 }
@@ -1,3 +1,12 @@
 pub mod chat;
+pub mod command;
+pub mod compression;
+pub mod event;
 pub mod group;
 pub mod handler;
+pub mod protocol;
+pub mod socketio;
+pub mod sse;
+
+#[cfg(test)]
+mod fuzz;
@@ -0,0 +1,212 @@
+use axum::{
+    extract::ws::{Message, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode, header::SEC_WEBSOCKET_PROTOCOL},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::util::{MetaResponse, StatusCodeExt},
+    websocket::compression::{Compressor, outgoing},
+};
+
+/// Subprotocol carrying the access token, for clients that can't set
+/// `Authorization` on an upgrade (browsers). Never echoed back.
+pub const BEARER_PREFIX: &str = "bearer.";
+pub const CHAT_V1: &str = "chat.v1";
+pub const CHAT_V2: &str = "chat.v2";
+
+/// Version of the chat frames spoken on a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatProtocol {
+    /// Client frames are the raw message text
+    V1,
+    /// Client frames are JSON envelopes, see `ClientFrame`
+    V2,
+}
+
+/// What a `chat.v2` client sends
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Message { message: String },
+}
+
+/// The token of a `bearer.<token>` subprotocol offer
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|protocol| protocol.trim().strip_prefix(BEARER_PREFIX))
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+impl ChatProtocol {
+    /// Picks the newest version the client offers and echoes it. A client
+    /// offering no subprotocol at all gets `chat.v1`, as before versioning;
+    /// one offering only others (or just its token) is refused.
+    pub fn negotiate(
+        ws: WebSocketUpgrade,
+        headers: &HeaderMap,
+    ) -> Result<(WebSocketUpgrade, Self), MetaResponse> {
+        if !headers.contains_key(SEC_WEBSOCKET_PROTOCOL) {
+            return Ok((ws, ChatProtocol::V1));
+        }
+        let ws = ws.protocols([CHAT_V2, CHAT_V1]);
+        let protocol = match ws.selected_protocol().and_then(|value| value.to_str().ok()) {
+            Some(CHAT_V2) => ChatProtocol::V2,
+            Some(CHAT_V1) => ChatProtocol::V1,
+            _ => {
+                return Err(MetaResponse {
+                    code: StatusCode::BAD_REQUEST.to_i32(),
+                    message: "Offer chat.v1 or chat.v2 as a WebSocket subprotocol".to_string(),
+                });
+            }
+        };
+        Ok((ws, protocol))
+    }
+}
+
+/// How frames are read and written on one connection
+#[derive(Clone, Copy, Debug)]
+pub struct Framing {
+    pub protocol: ChatProtocol,
+    pub compressor: Option<Compressor>,
+}
+
+impl Framing {
+    /// The message text of a client frame, or the notice to answer it with
+    pub fn incoming(&self, text: &str) -> Result<String, String> {
+        match self.protocol {
+            ChatProtocol::V1 => Ok(text.to_string()),
+            ChatProtocol::V2 => match serde_json::from_str(text) {
+                Ok(ClientFrame::Message { message }) => Ok(message),
+                Err(_) => Err("Invalid chat.v2 frame".to_string()),
+            },
+        }
+    }
+
+    pub fn outgoing(&self, text: String) -> Message {
+        outgoing(self.compressor.as_ref(), text)
+    }
+}
+
+#[cfg(test)]
+mod tests_protocol {
+    use axum::http::{HeaderMap, HeaderValue, StatusCode, header::SEC_WEBSOCKET_PROTOCOL};
+    use axum_test::TestServer;
+    use serde_json::json;
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::hash_password,
+        },
+        routes::routes,
+        websocket::{
+            event::ServerEvent,
+            protocol::{ChatProtocol, Framing, bearer_token},
+        },
+    };
+
+    fn offer(protocols: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocols));
+        headers
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(
+            bearer_token(&offer("chat.v2, bearer.abc.def")),
+            Some("abc.def".to_string())
+        );
+        assert_eq!(bearer_token(&offer("chat.v2")), None);
+        assert_eq!(bearer_token(&offer("bearer.")), None);
+        assert_eq!(bearer_token(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_incoming() {
+        let v1 = Framing {
+            protocol: ChatProtocol::V1,
+            compressor: None,
+        };
+        let v2 = Framing {
+            protocol: ChatProtocol::V2,
+            ..v1
+        };
+        let frame = r#"{"type":"message","message":"Hi"}"#;
+        assert_eq!(v1.incoming(frame), Ok(frame.to_string()));
+        assert_eq!(v2.incoming(frame), Ok("Hi".to_string()));
+        assert!(v2.incoming("Hi").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_group_chat() {
+        let state = AppState::isolated().await;
+        let server = TestServer::builder()
+            .http_transport()
+            .build(routes((*state).clone()))
+            .unwrap();
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(
+            &state.pool,
+            NewUser::new(
+                "protocoluser".to_string(),
+                "protocoluser@mail.com".to_string(),
+                hash,
+            ),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        let group = state
+            .groups
+            .create("versioned", "", None, &user.user_id)
+            .await
+            .unwrap();
+
+        // Only the version is echoed, the token stays out of the response
+        let response = server
+            .get_websocket("/group-chat")
+            .add_header(
+                SEC_WEBSOCKET_PROTOCOL,
+                format!("chat.v1, chat.v2, bearer.{}", token),
+            )
+            .add_header("group_id", &group.group_id)
+            .await;
+        assert_eq!(response.header(SEC_WEBSOCKET_PROTOCOL), "chat.v2");
+        let mut ws = response.into_websocket().await;
+        ws.send_text("not an envelope").await;
+        ws.send_text(json!({"type": "message", "message": "Hi"}).to_string())
+            .await;
+        let mut rejected = false;
+        loop {
+            match serde_json::from_str(&ws.receive_text().await).unwrap() {
+                ServerEvent::Notice { .. } => rejected = true,
+                ServerEvent::GroupMessage(msg) if msg.message == "Hi" => break,
+                // The welcome message
+                _ => continue,
+            }
+        }
+        assert!(rejected);
+
+        server
+            .get_websocket("/group-chat")
+            .add_header(SEC_WEBSOCKET_PROTOCOL, format!("bearer.{}", token))
+            .add_header("group_id", &group.group_id)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        server
+            .get_websocket("/group-chat")
+            .add_header(SEC_WEBSOCKET_PROTOCOL, "chat.v2, bearer.invalid")
+            .add_header("group_id", &group.group_id)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,773 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade, rejection::WebSocketUpgradeRejection},
+    },
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::{IntoResponse, Response},
+};
+use futures::{SinkExt, StreamExt, stream};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{Instant, interval_at, sleep_until},
+};
+use tracing::Instrument;
+
+use crate::{
+    AppState,
+    auth::{
+        jwt::verify_access_token,
+        user::User,
+        util::{MetaResponse, StatusCodeExt},
+    },
+    event_bus::DomainEvent,
+    metrics::Channel,
+    moderation::handler::{Mute, SanctionKind, mute},
+    websocket::{
+        chat::send_to_user,
+        group::{Posted, admit, is_muted, join, post, serde_msg},
+    },
+};
+
+/// `[socketio]`. Socket.IO clients connect to `/socket.io/` over the
+/// WebSocket transport only; HTTP long-polling isn't served.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SocketIoSettings {
+    pub enabled: bool,
+    /// Milliseconds between the server's pings
+    pub ping_interval_ms: u64,
+    /// Milliseconds a client has to answer a ping before it is dropped
+    pub ping_timeout_ms: u64,
+    /// Largest frame a client may send, in bytes
+    pub max_payload: usize,
+}
+
+impl Default for SocketIoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ping_interval_ms: 25_000,
+            ping_timeout_ms: 20_000,
+            max_payload: 1_000_000,
+        }
+    }
+}
+
+/// Engine.IO packet types, the first character of every frame
+const OPEN: char = '0';
+const CLOSE: char = '1';
+const PING: char = '2';
+const PONG: char = '3';
+const MESSAGE: char = '4';
+
+/// Socket.IO packets, carried in Engine.IO messages
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    Connect {
+        namespace: String,
+        auth: Option<Value>,
+    },
+    Disconnect {
+        namespace: String,
+    },
+    /// `args` starts with the event name
+    Event {
+        namespace: String,
+        id: Option<u64>,
+        args: Vec<Value>,
+    },
+    Ack {
+        namespace: String,
+        id: u64,
+        args: Vec<Value>,
+    },
+    ConnectError {
+        namespace: String,
+        message: String,
+    },
+}
+
+impl Packet {
+    /// Reads the Socket.IO packet of an Engine.IO message (without its `4`).
+    /// Binary packets aren't supported and read as `None`.
+    pub fn decode(text: &str) -> Option<Self> {
+        let mut chars = text.chars();
+        let kind = chars.next()?;
+        let mut rest = chars.as_str();
+
+        let mut namespace = "/";
+        if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or(rest.len());
+            namespace = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or("");
+        }
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let id = match digits {
+            0 => None,
+            _ => Some(rest[..digits].parse().ok()?),
+        };
+        let data: Option<Value> = match &rest[digits..] {
+            "" => None,
+            json => Some(serde_json::from_str(json).ok()?),
+        };
+        let namespace = namespace.to_string();
+        let args = |data: Option<Value>| match data {
+            Some(Value::Array(args)) => Some(args),
+            _ => None,
+        };
+
+        match kind {
+            '0' => Some(Packet::Connect {
+                namespace,
+                auth: data,
+            }),
+            '1' => Some(Packet::Disconnect { namespace }),
+            '2' => Some(Packet::Event {
+                namespace,
+                id,
+                args: args(data).filter(|args| args.first().is_some_and(Value::is_string))?,
+            }),
+            '3' => Some(Packet::Ack {
+                namespace,
+                id: id?,
+                args: args(data)?,
+            }),
+            '4' => Some(Packet::ConnectError {
+                namespace,
+                message: data?.get("message")?.as_str()?.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The packet as an Engine.IO message frame
+    pub fn encode(&self) -> String {
+        let (kind, namespace, id, data) = match self {
+            Packet::Connect { namespace, auth } => ('0', namespace, None, auth.clone()),
+            Packet::Disconnect { namespace } => ('1', namespace, None, None),
+            Packet::Event {
+                namespace,
+                id,
+                args,
+            } => ('2', namespace, *id, Some(Value::from(args.clone()))),
+            Packet::Ack {
+                namespace,
+                id,
+                args,
+            } => ('3', namespace, Some(*id), Some(Value::from(args.clone()))),
+            Packet::ConnectError { namespace, message } => {
+                ('4', namespace, None, Some(json!({ "message": message })))
+            }
+        };
+        let mut frame = format!("{}{}", MESSAGE, kind);
+        if namespace != "/" {
+            frame.push_str(namespace);
+            frame.push(',');
+        }
+        if let Some(id) = id {
+            frame.push_str(&id.to_string());
+        }
+        if let Some(data) = data {
+            frame.push_str(&data.to_string());
+        }
+        frame
+    }
+}
+
+/// A `ServerEvent` payload as a Socket.IO event named after its `type`,
+/// e.g. `42["chat_message",{"sender_user":...}]`
+pub fn event_frame(payload: &str) -> Option<String> {
+    let mut event: Value = serde_json::from_str(payload).ok()?;
+    let name = event.as_object_mut()?.remove("type")?;
+    Some(
+        Packet::Event {
+            namespace: "/".to_string(),
+            id: None,
+            args: vec![name, event],
+        }
+        .encode(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EngineQuery {
+    #[serde(rename = "EIO")]
+    pub eio: Option<String>,
+    pub transport: Option<String>,
+}
+
+/// Socket.IO endpoint bridging to the private and group chats, for clients
+/// built on the socket.io libraries. They must connect with
+/// `transports: ["websocket"]` and pass their access token as
+/// `auth: { token }` (or an `Authorization` header).
+pub async fn socketio_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    Query(query): Query<EngineQuery>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let supported =
+        query.eio.as_deref() == Some("4") && query.transport.as_deref() == Some("websocket");
+    let ws = match ws {
+        Ok(ws) if supported => ws,
+        _ => {
+            return MetaResponse {
+                code: StatusCode::BAD_REQUEST.to_i32(),
+                message: "Only Engine.IO v4 over the websocket transport is supported".to_string(),
+            }
+            .into_response();
+        }
+    };
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    ws.max_message_size(state.settings.socketio.max_payload)
+        .on_upgrade(move |socket| async move {
+            let _connection = state.metrics.connection(Channel::SocketIo);
+            socketio(socket, token, state).await
+        })
+}
+
+/// A connection that sent its Socket.IO `CONNECT` and was let in
+struct Session {
+    user: User,
+    /// Groups joined as rooms
+    rooms: HashSet<String>,
+    /// Users hidden with `/mute`
+    muted: Mutex<HashSet<String>>,
+    mute: Mutex<Mute>,
+    chat_rx: broadcast::Receiver<String>,
+}
+
+pub async fn socketio(socket: WebSocket, header_token: Option<String>, app: Arc<AppState>) {
+    let settings = app.settings.socketio.clone();
+    let (mut sender, mut receiver) = socket.split();
+    let sid = uuid::Uuid::new_v4().simple().to_string();
+
+    let open = json!({
+        "sid": sid,
+        "upgrades": [],
+        "pingInterval": settings.ping_interval_ms,
+        "pingTimeout": settings.ping_timeout_ms,
+        "maxPayload": settings.max_payload,
+    });
+    if sender
+        .send(Message::Text(format!("{}{}", OPEN, open).into()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut session: Option<Session> = None;
+    let mut group_rx = app.group.tx.subscribe();
+    let mut sanctions = app.group.sanctions.subscribe();
+    let period = Duration::from_millis(settings.ping_interval_ms);
+    let mut ping = interval_at(Instant::now() + period, period);
+    let timeout = Duration::from_millis(settings.ping_timeout_ms);
+    let mut pong_deadline: Option<Instant> = None;
+
+    loop {
+        let frames = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let mut chars = text.chars();
+                    match chars.next() {
+                        Some(MESSAGE) => match Packet::decode(chars.as_str()) {
+                            Some(packet) => {
+                                let token = header_token.as_deref();
+                                match receive(&app, &sid, &mut session, token, packet).await {
+                                    Some(frames) => frames,
+                                    None => break,
+                                }
+                            }
+                            None => continue,
+                        },
+                        Some(PONG) => {
+                            pong_deadline = None;
+                            continue;
+                        }
+                        Some(PING) => vec![PONG.to_string()],
+                        Some(CLOSE) => break,
+                        _ => continue,
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            msg = private_channel(&mut session) => match msg {
+                Ok(msg) => event_frame(&msg).into_iter().collect(),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            msg = group_rx.recv() => match msg {
+                Ok(msg) => match &session {
+                    Some(session)
+                        if !session.rooms.is_empty() && !is_muted(&msg, &session.muted) =>
+                    {
+                        event_frame(&msg).into_iter().collect()
+                    }
+                    _ => continue,
+                },
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            // The sanction itself arrives on the private channel
+            Ok(sanction) = sanctions.recv() => {
+                if let Some(session) = &mut session
+                    && sanction.user_id == session.user.user_id
+                {
+                    {
+                        let mut mute = session.mute.lock().unwrap();
+                        *mute = mute.extend(Mute::from(&sanction));
+                    }
+                    if sanction.kind == SanctionKind::GroupBan
+                        && let Some(group_id) = &sanction.group_id
+                    {
+                        session.rooms.remove(group_id);
+                    }
+                }
+                continue;
+            },
+            _ = ping.tick() => {
+                if pong_deadline.is_none() {
+                    pong_deadline = Some(Instant::now() + timeout);
+                }
+                vec![PING.to_string()]
+            },
+            _ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)),
+                if pong_deadline.is_some() =>
+            {
+                tracing::info!(sid, "Socket.IO client missed a ping");
+                break;
+            },
+        };
+        let mut frames =
+            stream::iter(frames).map(|frame| Ok::<_, axum::Error>(Message::Text(frame.into())));
+        if sender.send_all(&mut frames).await.is_err() {
+            break;
+        }
+    }
+
+    if let Some(session) = session {
+        drop(session.chat_rx);
+        app.chat.unsubscribe(&session.user.user_id).await;
+    }
+}
+
+/// Payloads of the user's private channel, once the client has connected
+async fn private_channel(session: &mut Option<Session>) -> Result<String, RecvError> {
+    match session {
+        Some(session) => session.chat_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The frames answering a client packet, `None` to close the connection
+async fn receive(
+    app: &Arc<AppState>,
+    sid: &str,
+    session: &mut Option<Session>,
+    header_token: Option<&str>,
+    packet: Packet,
+) -> Option<Vec<String>> {
+    let frames = match packet {
+        Packet::Connect { namespace, .. } if namespace != "/" => vec![
+            Packet::ConnectError {
+                namespace,
+                message: "Invalid namespace".to_string(),
+            }
+            .encode(),
+        ],
+        Packet::Connect { namespace, auth } if session.is_none() => {
+            let token = auth
+                .as_ref()
+                .and_then(|auth| auth.get("token"))
+                .and_then(Value::as_str)
+                .or(header_token);
+            match connect(app, token).await {
+                Ok(connected) => {
+                    tracing::info!(sid, user_id = %connected.user.user_id, "Socket.IO connected");
+                    *session = Some(connected);
+                    vec![
+                        Packet::Connect {
+                            namespace,
+                            auth: Some(json!({ "sid": sid })),
+                        }
+                        .encode(),
+                    ]
+                }
+                Err(message) => vec![Packet::ConnectError { namespace, message }.encode()],
+            }
+        }
+        Packet::Disconnect { .. } => return None,
+        Packet::Event {
+            namespace,
+            id,
+            mut args,
+        } if namespace == "/" => {
+            let Some(session) = session else {
+                return Some(Vec::new());
+            };
+            let name = args.remove(0);
+            let data = args.into_iter().next().unwrap_or(Value::Null);
+            let mut frames = Vec::new();
+            let result = on_event(
+                app,
+                session,
+                name.as_str().unwrap_or_default(),
+                data,
+                &mut frames,
+            )
+            .await;
+            match (id, result) {
+                (Some(id), result) => frames.push(
+                    Packet::Ack {
+                        namespace: "/".to_string(),
+                        id,
+                        args: vec![result.unwrap_or_else(|error| json!({ "error": error }))],
+                    }
+                    .encode(),
+                ),
+                (None, Err(message)) => {
+                    frames.push(
+                        Packet::Event {
+                            namespace: "/".to_string(),
+                            id: None,
+                            args: vec![json!("notice"), json!({ "message": message })],
+                        }
+                        .encode(),
+                    );
+                }
+                (None, Ok(_)) => {}
+            }
+            frames
+        }
+        // The server never asks for acks, and connects once
+        _ => Vec::new(),
+    };
+    Some(frames)
+}
+
+/// Lets in the user of a valid access token
+async fn connect(app: &AppState, token: Option<&str>) -> Result<Session, String> {
+    let token = token.ok_or("Missing auth token")?;
+    let claims = verify_access_token(&app.jwt_config, token)
+        .map_err(|_| "Invalid or expired token".to_string())?;
+    if app
+        .revoked_tokens
+        .is_revoked(token)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        return Err("Token has been revoked".to_string());
+    }
+    let user = app
+        .user_cache
+        .get_user(&claims.user_id, &app.pool)
+        .await
+        .ok_or("Unknown user")?;
+    let mute = mute(&app.pool, &user.user_id).await.unwrap_or_default();
+    Ok(Session {
+        chat_rx: app.chat.subscribe(&user.user_id).await,
+        user,
+        rooms: HashSet::new(),
+        muted: Mutex::new(HashSet::new()),
+        mute: Mutex::new(mute),
+    })
+}
+
+#[derive(Deserialize)]
+struct PrivateMessage {
+    receiver_id: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct GroupRef {
+    group_id: String,
+}
+
+#[derive(Deserialize)]
+struct GroupPost {
+    group_id: String,
+    message: String,
+}
+
+fn parse<T: DeserializeOwned>(data: Value) -> Result<T, String> {
+    serde_json::from_value(data).map_err(|e| format!("Invalid payload: {}", e))
+}
+
+/// Handles a client event; the result is what an ack gets
+async fn on_event(
+    app: &Arc<AppState>,
+    session: &mut Session,
+    name: &str,
+    data: Value,
+    frames: &mut Vec<String>,
+) -> Result<Value, String> {
+    let user = &session.user;
+    match name {
+        "private_message" => {
+            let PrivateMessage {
+                receiver_id,
+                message,
+            } = parse(data)?;
+            if session.mute.lock().unwrap().is_active() {
+                return Err("You are muted".to_string());
+            }
+            let receiver = app
+                .user_cache
+                .get_user(&receiver_id, &app.pool)
+                .await
+                .ok_or("Invalid receiver_id")?;
+            let span = tracing::info_span!(
+                "ws.chat_message",
+                from = %user.user_id,
+                to = %receiver.user_id,
+            );
+            let message = send_to_user(&app.chat, user, &receiver, &message)
+                .instrument(span)
+                .await;
+            let ack = json!(message);
+            if !app.shadow_bans.contains(&user.user_id) {
+                app.events.publish(DomainEvent::MessageSent { message });
+            }
+            Ok(ack)
+        }
+        "join" => {
+            let GroupRef { group_id } = parse(data)?;
+            let group = app
+                .groups
+                .get_by_id(&group_id)
+                .await
+                .ok_or("Invalid group_id")?;
+            admit(app, &user.user_id, &group)
+                .await
+                .map_err(|e| e.message)?;
+            if session.rooms.insert(group_id) {
+                join(app, user, &group);
+            }
+            Ok(json!(group))
+        }
+        "leave" => {
+            let GroupRef { group_id } = parse(data)?;
+            session.rooms.remove(&group_id);
+            Ok(json!({ "group_id": group_id }))
+        }
+        "group_message" => {
+            let GroupPost { group_id, message } = parse(data)?;
+            if !session.rooms.contains(&group_id) {
+                return Err("Join the group first".to_string());
+            }
+            match post(app, user, &group_id, message, &session.muted, &session.mute) {
+                Posted::Notice(notice) => Err(notice),
+                // Shadow banned users only see their own messages
+                Posted::Hidden(group_msg) => {
+                    frames.extend(event_frame(&serde_msg(&group_msg)));
+                    Ok(json!(group_msg))
+                }
+                Posted::Sent(group_msg) => Ok(json!(group_msg)),
+            }
+        }
+        _ => Err(format!("Unknown event {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests_socketio {
+    use axum::{Router, routing::get};
+    use axum_test::{TestServer, TestWebSocket};
+    use serde_json::{Value, json};
+
+    use crate::{
+        AppState,
+        auth::{
+            jwt::create_access_token,
+            user::{NewUser, add},
+            util::hash_password,
+        },
+        websocket::socketio::{Packet, event_frame, socketio_handler},
+    };
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Packet::decode(r#"0{"token":"abc"}"#),
+            Some(Packet::Connect {
+                namespace: "/".to_string(),
+                auth: Some(json!({"token": "abc"})),
+            })
+        );
+        assert_eq!(
+            Packet::decode(r#"212["join",{"group_id":"g1"}]"#),
+            Some(Packet::Event {
+                namespace: "/".to_string(),
+                id: Some(12),
+                args: vec![json!("join"), json!({"group_id": "g1"})],
+            })
+        );
+        // The namespace comes after the packet type, closed by a comma
+        assert_eq!(
+            Packet::decode("0/admin,"),
+            Some(Packet::Connect {
+                namespace: "/admin".to_string(),
+                auth: None,
+            })
+        );
+        assert_eq!(
+            Packet::decode(r#"0/admin,{"token":"abc"}"#),
+            Some(Packet::Connect {
+                namespace: "/admin".to_string(),
+                auth: Some(json!({"token": "abc"})),
+            })
+        );
+        assert_eq!(
+            Packet::decode(r#"2/admin,7["leave"]"#),
+            Some(Packet::Event {
+                namespace: "/admin".to_string(),
+                id: Some(7),
+                args: vec![json!("leave")],
+            })
+        );
+        assert_eq!(Packet::decode(r#"2{"not":"an array"}"#), None);
+        assert_eq!(
+            Packet::decode(r#"51-["upload",{"_placeholder":true}]"#),
+            None
+        );
+        assert_eq!(Packet::decode(""), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let ack = Packet::Ack {
+            namespace: "/".to_string(),
+            id: 3,
+            args: vec![json!({"ok": true})],
+        };
+        assert_eq!(ack.encode(), r#"433[{"ok":true}]"#);
+        let error = Packet::ConnectError {
+            namespace: "/admin".to_string(),
+            message: "Invalid namespace".to_string(),
+        };
+        assert_eq!(
+            error.encode(),
+            r#"44/admin,{"message":"Invalid namespace"}"#
+        );
+        assert_eq!(
+            event_frame(r#"{"type":"notice","message":"Hi"}"#).unwrap(),
+            r#"42["notice",{"message":"Hi"}]"#
+        );
+    }
+
+    /// Frames until the first one starting with `prefix`
+    async fn receive_until(ws: &mut TestWebSocket, prefix: &str) -> String {
+        loop {
+            let frame = ws.receive_text().await;
+            if frame.starts_with(prefix) {
+                return frame;
+            }
+        }
+    }
+
+    fn ack_args(frame: &str, prefix: &str) -> Value {
+        serde_json::from_str(frame.strip_prefix(prefix).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_socketio_group_chat() {
+        let state = AppState::isolated().await;
+        let app = Router::new()
+            .route("/socket.io/", get(socketio_handler))
+            .with_state((*state).clone());
+        let server = TestServer::builder().http_transport().build(app).unwrap();
+
+        let hash = hash_password("123456".to_string()).unwrap();
+        let user = add(
+            &state.pool,
+            NewUser::new(
+                "socketiouser".to_string(),
+                "socketiouser@mail.com".to_string(),
+                hash,
+            ),
+        )
+        .await
+        .unwrap();
+        let token = create_access_token(&state.jwt_config, &user.user_id, &user.email).unwrap();
+        let group = state
+            .groups
+            .create("sockets", "", None, &user.user_id)
+            .await
+            .unwrap();
+
+        server
+            .get("/socket.io/?EIO=4&transport=polling")
+            .await
+            .assert_status_bad_request();
+
+        let mut ws = server
+            .get_websocket("/socket.io/?EIO=4&transport=websocket")
+            .await
+            .into_websocket()
+            .await;
+        let open = ws.receive_text().await;
+        let handshake: Value = serde_json::from_str(open.strip_prefix('0').unwrap()).unwrap();
+        assert!(handshake["sid"].is_string());
+
+        ws.send_text(r#"40{"token":"invalid"}"#).await;
+        assert_eq!(
+            ws.receive_text().await,
+            r#"44{"message":"Invalid or expired token"}"#
+        );
+        ws.send_text(format!("40{}", json!({ "token": token })))
+            .await;
+        assert!(ws.receive_text().await.starts_with(r#"40{"sid":"#));
+
+        // Posting before joining is refused
+        ws.send_text(format!(
+            "421{}",
+            json!(["group_message", {"group_id": group.group_id, "message": "Hi"}])
+        ))
+        .await;
+        let refused = ack_args(&receive_until(&mut ws, "431").await, "431");
+        assert_eq!(refused[0]["error"], "Join the group first");
+
+        ws.send_text(format!(
+            "422{}",
+            json!(["join", {"group_id": group.group_id}])
+        ))
+        .await;
+        let joined = ack_args(&receive_until(&mut ws, "432").await, "432");
+        assert_eq!(joined[0]["group_id"], group.group_id.as_str());
+
+        ws.send_text(format!(
+            "423{}",
+            json!(["group_message", {"group_id": group.group_id, "message": "Hi"}])
+        ))
+        .await;
+        let sent = ack_args(&receive_until(&mut ws, "433").await, "433");
+        assert_eq!(sent[0]["message"], "Hi");
+        // The welcome message may come first
+        loop {
+            let event = ack_args(&receive_until(&mut ws, r#"42["group_message""#).await, "42");
+            if event[1]["message"] == "Hi" {
+                assert_eq!(event[1]["name"], "socketiouser");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt, stream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    AppState, auth::extractors::AuthUser, metrics::Channel, websocket::chat::PrivateChatState,
+};
+
+/// Keeps the user's private channel registered while the SSE stream is alive
+struct ChatSubscription {
+    state: Arc<PrivateChatState>,
+    user_id: String,
+}
+
+impl Drop for ChatSubscription {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let user_id = self.user_id.clone();
+        tokio::spawn(async move { state.unsubscribe(&user_id).await });
+    }
+}
+
+fn receiver_stream(rx: broadcast::Receiver<String>) -> impl Stream<Item = String> {
+    stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => return Some((msg, rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Merges the user's private chat channel and the group channel into one
+/// stream of `ServerEvent` JSON payloads
+pub async fn event_stream(state: &AppState, user_id: &str) -> impl Stream<Item = String> + use<> {
+    let chat_rx = state.chat.subscribe(user_id).await;
+    let group_rx = state.group.tx.subscribe();
+    let subscription = ChatSubscription {
+        state: state.chat.clone(),
+        user_id: user_id.to_string(),
+    };
+
+    stream::select(receiver_stream(chat_rx), receiver_stream(group_rx)).map(move |msg| {
+        let _ = &subscription;
+        msg
+    })
+}
+
+/// Server-Sent Events fallback for clients that cannot open a WebSocket.
+/// Streams the same `ServerEvent` frames as `/chat` and `/group-chat`.
+pub async fn events_handler(
+    AuthUser(user): AuthUser,
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let connection = state.metrics.connection(Channel::Sse);
+    let stream = event_stream(&state, &user.user_id).await.map(move |msg| {
+        let _ = &connection;
+        Ok(Event::default().data(msg))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests_sse {
+    use futures::StreamExt;
+
+    use crate::{
+        AppState,
+        auth::user::User,
+        websocket::{chat::send_to_user, event::ServerEvent, sse::event_stream},
+    };
+
+    fn user(id: &str) -> User {
+        User {
+            user_id: id.to_string(),
+            user_name: format!("name-{}", id),
+            email: format!("{}@mail.com", id),
+            is_bot: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_receives_chat_message() {
+        let state = AppState::test().await;
+        let sender = user("sse-sender");
+        let receiver = user("sse-receiver");
+
+        let mut events = Box::pin(event_stream(&state, &receiver.user_id).await);
+        send_to_user(&state.chat, &sender, &receiver, "Hello").await;
+
+        let msg = events.next().await.unwrap();
+        match serde_json::from_str::<ServerEvent>(&msg).unwrap() {
+            ServerEvent::ChatMessage(chat) => {
+                assert_eq!(chat.sender_user.user_id, sender.user_id);
+                assert_eq!(chat.message, "Hello");
+            }
+            _ => panic!("expected chat message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_unsubscribes_on_drop() {
+        let state = AppState::test().await;
+        let events = event_stream(&state, "sse-dropped").await;
+        assert!(
+            state
+                .chat
+                .connections
+                .read()
+                .await
+                .contains_key("sse-dropped")
+        );
+
+        drop(events);
+        tokio::task::yield_now().await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(
+            !state
+                .chat
+                .connections
+                .read()
+                .await
+                .contains_key("sse-dropped")
+        );
+    }
+}